@@ -1,5 +1,5 @@
 use glam::{vec3, Mat4};
-use wgpu::{IndexFormat, RenderPass, RenderPipeline, TextureView};
+use wgpu::{IndexFormat, RenderPass, RenderPipeline};
 
 use spark_gap::camera::camera_handler::CAMERA_BIND_GROUP_LAYOUT;
 use spark_gap::gpu_context::GpuContext;
@@ -29,12 +29,11 @@ impl AnimRenderPass {
     }
 
     pub fn render(&self, context: &GpuContext, world: &World) {
-        let frame = context
-            .surface
-            .get_current_texture()
-            .expect("Failed to acquire next swap chain texture");
+        let Some(frame) = context.acquire_frame_or_skip() else {
+            return;
+        };
 
-        let view = frame.texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let view = frame.texture().create_view(&wgpu::TextureViewDescriptor::default());
 
         let pass_description = wgpu::RenderPassDescriptor {
             label: Some("render pass"),
@@ -160,7 +159,7 @@ pub fn create_render_pipeline(context: &GpuContext) -> RenderPipeline {
         source: wgpu::ShaderSource::Wgsl(include_str!("animation_shader.wgsl").into()),
     });
 
-    let swapchain_capabilities = context.surface.get_capabilities(&context.adapter);
+    let swapchain_capabilities = context.get_surface_capabilities();
     let swapchain_format = swapchain_capabilities.formats[0];
 
     let render_pipeline = context.device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
@@ -215,7 +214,7 @@ pub fn create_render_pipeline_2(context: &GpuContext) -> RenderPipeline {
         source: wgpu::ShaderSource::Wgsl(include_str!("animation_shader_2.wgsl").into()),
     });
 
-    let swapchain_capabilities = context.surface.get_capabilities(&context.adapter);
+    let swapchain_capabilities = context.get_surface_capabilities();
     let swapchain_format = swapchain_capabilities.formats[0];
 
     let render_pipeline = context.device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
@@ -253,29 +252,3 @@ pub fn create_render_pipeline_2(context: &GpuContext) -> RenderPipeline {
 
     render_pipeline
 }
-
-pub const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
-
-pub fn create_depth_texture_view(context: &GpuContext) -> TextureView {
-    let size = context.window.inner_size();
-
-    let size = wgpu::Extent3d {
-        width: size.width,
-        height: size.height,
-        depth_or_array_layers: 1,
-    };
-
-    let desc = wgpu::TextureDescriptor {
-        label: Some("depth_texture"),
-        size,
-        mip_level_count: 1,
-        sample_count: 1,
-        dimension: wgpu::TextureDimension::D2,
-        format: DEPTH_FORMAT,
-        usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
-        view_formats: &[DEPTH_FORMAT],
-    };
-
-    let texture = context.device.create_texture(&desc);
-    texture.create_view(&wgpu::TextureViewDescriptor::default())
-}
@@ -1,12 +1,14 @@
-use crate::anim_render::{create_depth_texture_view, AnimRenderPass};
+use crate::anim_render::AnimRenderPass;
 use crate::world::World;
 use glam::{vec3, Mat4, Vec3};
 use spark_gap::camera::camera_handler::CameraHandler;
 use spark_gap::camera::fly_camera_controller::FlyCameraController;
+use spark_gap::camera::projection::get_aspect_ratio;
 use spark_gap::frame_counter::FrameCounter;
 use spark_gap::gpu_context::GpuContext;
 use spark_gap::input::Input;
 use spark_gap::model_builder::ModelBuilder;
+use spark_gap::texture::create_depth_texture;
 use std::sync::Arc;
 use std::time::Instant;
 use winit::event::{Event, WindowEvent};
@@ -23,10 +25,16 @@ pub const BACKGROUND_COLOR: wgpu::Color = wgpu::Color {
 };
 
 pub async fn run(event_loop: EventLoop<()>, window: Arc<Window>) {
-    let mut context = GpuContext::new(window).await;
+    let mut context = match GpuContext::new(window).await {
+        Ok(context) => context,
+        Err(error) => {
+            log::error!("{}", error);
+            return;
+        }
+    };
     let mut frame_counter = FrameCounter::new();
-    let size = context.window.inner_size();
-    let aspect_ratio = size.width as f32 / size.height as f32;
+    let size = context.size;
+    let aspect_ratio = get_aspect_ratio(size.width, size.height);
 
     let camera_position = vec3(0.0, 100.0, 300.0);
     let camera_controller = FlyCameraController::new(aspect_ratio, camera_position, 0.0, 0.0);
@@ -42,7 +50,7 @@ pub async fn run(event_loop: EventLoop<()>, window: Arc<Window>) {
 
     let model_position = Vec3::ZERO;
 
-    let depth_texture_view = create_depth_texture_view(&context);
+    let depth_texture_view = create_depth_texture(&context).view;
 
     let anim_render = AnimRenderPass::new(&mut context);
 
@@ -86,8 +94,8 @@ pub async fn run(event_loop: EventLoop<()>, window: Arc<Window>) {
                             context.resize(new_size);
                             world.camera_controller.resize(&context);
                             world.camera_handler.update_camera(&context, &world.camera_controller);
-                            world.depth_texture_view = create_depth_texture_view(&context);
-                            context.window.request_redraw();
+                            world.depth_texture_view = create_depth_texture(&context).view;
+                            context.request_redraw();
                         }
                         WindowEvent::RedrawRequested => {
                             frame_counter.update();
@@ -102,7 +110,7 @@ pub async fn run(event_loop: EventLoop<()>, window: Arc<Window>) {
 
                             anim_render.render(&context, &world);
 
-                            context.window.request_redraw();
+                            context.request_redraw();
 
                             // println!("Input: {:#?}\n", &world.input);
                         }
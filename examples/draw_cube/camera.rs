@@ -1,5 +1,6 @@
-use crate::context::Context;
 use glam::{Mat4, Vec3};
+use spark_gap::camera::projection::get_aspect_ratio;
+use spark_gap::gpu_context::GpuContext;
 use std::f32::consts;
 use wgpu::util::DeviceExt;
 use wgpu::{BindGroup, BindGroupLayout, Buffer};
@@ -17,8 +18,8 @@ pub struct CameraHandler {
 }
 
 impl Camera {
-    pub fn new(context: &Context) -> Camera {
-        let size = context.window.inner_size();
+    pub fn new(context: &GpuContext) -> Camera {
+        let size = context.size;
 
         Camera {
             window_size: size,
@@ -27,7 +28,7 @@ impl Camera {
     }
 
     pub fn get_projection_view_matrix(size: PhysicalSize<u32>) -> Mat4 {
-        let aspect_ratio = size.width as f32 / size.height as f32;
+        let aspect_ratio = get_aspect_ratio(size.width, size.height);
         let projection = Mat4::perspective_rh(consts::FRAC_PI_4, aspect_ratio, 1.0, 10.0);
         let view = Mat4::look_at_rh(Vec3::new(1.5f32, -5.0, 3.0), Vec3::ZERO, Vec3::Z);
         projection * view
@@ -37,8 +38,8 @@ impl Camera {
         self.perspective_view.to_cols_array()
     }
 
-    pub fn update(&mut self, context: &Context) {
-        let size = context.window.inner_size();
+    pub fn update(&mut self, context: &GpuContext) {
+        let size = context.size;
         if self.window_size != size {
             self.window_size = size;
             self.perspective_view = Self::get_projection_view_matrix(size);
@@ -47,7 +48,7 @@ impl Camera {
 }
 
 impl CameraHandler {
-    pub fn new(context: &Context, camera: &Camera) -> Self {
+    pub fn new(context: &GpuContext, camera: &Camera) -> Self {
         // let camera_uniform = camera.get_camera_uniform();
         let camera_uniform = camera.perspective_view.to_cols_array();
 
@@ -87,7 +88,7 @@ impl CameraHandler {
         }
     }
 
-    pub fn update_camera(&self, context: &Context, camera: &mut Camera) {
+    pub fn update_camera(&self, context: &GpuContext, camera: &mut Camera) {
         camera.update(context);
 
         let camera_uniform = camera.get_camera_uniform();
@@ -1,5 +1,4 @@
 mod camera;
-mod context;
 mod cube;
 mod model;
 mod render;
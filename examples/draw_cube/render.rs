@@ -1,8 +1,8 @@
 use crate::camera::{Camera, CameraHandler};
-use crate::context::Context;
 use crate::model::Model;
-use crate::texture::{create_depth_texture, Texture};
 use spark_gap::frame_counter::FrameCounter;
+use spark_gap::gpu_context::GpuContext;
+use spark_gap::texture::{create_depth_texture, DepthTexture, DEPTH_FORMAT};
 use std::sync::Arc;
 use wgpu::{BindGroupLayout, RenderPipeline};
 use winit::event::{Event, WindowEvent};
@@ -19,7 +19,13 @@ const BACKGROUND_COLOR: wgpu::Color = wgpu::Color {
 };
 
 pub async fn run(event_loop: EventLoop<()>, window: Arc<Window>) {
-    let mut context = Context::new(window).await;
+    let mut context = match GpuContext::new(window).await {
+        Ok(context) => context,
+        Err(error) => {
+            log::error!("{}", error);
+            return;
+        }
+    };
     let mut frame_counter = FrameCounter::new();
 
     let model = Model::new(&context);
@@ -38,14 +44,14 @@ pub async fn run(event_loop: EventLoop<()>, window: Arc<Window>) {
                         context.resize(new_size);
                         camera_handler.update_camera(&context, &mut camera);
                         depth_texture = create_depth_texture(&context);
-                        context.window.request_redraw();
+                        context.request_redraw();
                     }
                     WindowEvent::RedrawRequested => {
                         frame_counter.update();
 
                         draw(&context, &render_pipeline, &camera_handler, &model, &depth_texture);
 
-                        context.window.request_redraw();
+                        context.request_redraw();
                     }
                     WindowEvent::KeyboardInput { event, .. } => {
                         // if event.state == ElementState::Pressed {
@@ -62,13 +68,21 @@ pub async fn run(event_loop: EventLoop<()>, window: Arc<Window>) {
         .unwrap();
 }
 
-pub fn draw(context: &Context, render_pipeline: &RenderPipeline, camera_handler: &CameraHandler, model: &Model, depth_texture: &Texture) {
-    let frame = context
-        .surface
-        .get_current_texture()
-        .expect("Failed to acquire next swap chain texture");
-
-    let view = frame.texture.create_view(&wgpu::TextureViewDescriptor::default());
+pub fn draw(
+    context: &GpuContext,
+    render_pipeline: &RenderPipeline,
+    camera_handler: &CameraHandler,
+    model: &Model,
+    depth_texture: &DepthTexture,
+) {
+    let Some(frame) = context.acquire_frame_or_skip() else {
+        return;
+    };
+
+    let view = frame.texture().create_view(&wgpu::TextureViewDescriptor {
+        format: Some(context.surface_view_format()),
+        ..Default::default()
+    });
 
     let mut encoder = context
         .device
@@ -116,7 +130,7 @@ pub fn draw(context: &Context, render_pipeline: &RenderPipeline, camera_handler:
 }
 
 pub fn create_render_pipeline(
-    context: &Context,
+    context: &GpuContext,
     texture_bind_group_layout: &BindGroupLayout,
     camera_bind_group_layout: &BindGroupLayout,
 ) -> RenderPipeline {
@@ -131,8 +145,7 @@ pub fn create_render_pipeline(
         source: wgpu::ShaderSource::Wgsl(include_str!("shader.wgsl").into()),
     });
 
-    let swapchain_capabilities = context.surface.get_capabilities(&context.adapter);
-    let swapchain_format = swapchain_capabilities.formats[0];
+    let swapchain_format = context.surface_view_format();
 
     let render_pipeline = context.device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
         label: Some("Render Pipeline"),
@@ -157,7 +170,7 @@ pub fn create_render_pipeline(
             conservative: false,
         },
         depth_stencil: Some(wgpu::DepthStencilState {
-            format: wgpu::TextureFormat::Depth32Float,
+            format: DEPTH_FORMAT,
             depth_write_enabled: true,
             depth_compare: wgpu::CompareFunction::Less,
             stencil: wgpu::StencilState::default(),
@@ -1,5 +1,5 @@
-use crate::context::Context;
 use image::GenericImageView;
+use spark_gap::gpu_context::GpuContext;
 use wgpu::{BindGroup, BindGroupLayout};
 
 pub struct Texture {
@@ -8,7 +8,7 @@ pub struct Texture {
     pub sampler: wgpu::Sampler,
 }
 
-pub fn get_texture(context: &Context) -> Texture {
+pub fn get_texture(context: &GpuContext) -> Texture {
     let diffuse_bytes = include_bytes!("container2.png");
     let diffuse_image = image::load_from_memory(diffuse_bytes).unwrap();
     let diffuse_rgba = diffuse_image.to_rgba8();
@@ -70,47 +70,7 @@ pub fn get_texture(context: &Context) -> Texture {
     }
 }
 
-pub const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
-
-pub fn create_depth_texture(context: &Context) -> Texture {
-    let size = context.window.inner_size();
-
-    let size = wgpu::Extent3d {
-        width: size.width,
-        height: size.height,
-        depth_or_array_layers: 1,
-    };
-    let desc = wgpu::TextureDescriptor {
-        label: Some("depth_texture"),
-        size,
-        mip_level_count: 1,
-        sample_count: 1,
-        dimension: wgpu::TextureDimension::D2,
-        format: DEPTH_FORMAT,
-        usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
-        view_formats: &[DEPTH_FORMAT],
-    };
-    let texture = context.device.create_texture(&desc);
-
-    let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
-
-    let sampler = context.device.create_sampler(&wgpu::SamplerDescriptor {
-        address_mode_u: wgpu::AddressMode::ClampToEdge,
-        address_mode_v: wgpu::AddressMode::ClampToEdge,
-        address_mode_w: wgpu::AddressMode::ClampToEdge,
-        mag_filter: wgpu::FilterMode::Linear,
-        min_filter: wgpu::FilterMode::Linear,
-        mipmap_filter: wgpu::FilterMode::Nearest,
-        compare: Some(wgpu::CompareFunction::LessEqual),
-        lod_min_clamp: 0.0,
-        lod_max_clamp: 100.0,
-        ..Default::default()
-    });
-
-    Texture { texture, view, sampler }
-}
-
-pub fn get_texture_bind_group(context: &Context) -> (BindGroupLayout, BindGroup) {
+pub fn get_texture_bind_group(context: &GpuContext) -> (BindGroupLayout, BindGroup) {
     let texture = get_texture(context);
 
     let texture_bind_group_layout = context.device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
@@ -1,11 +1,16 @@
 use crate::model::Model;
-use glam::vec3;
+use glam::{vec3, Vec3};
 use spark_gap::camera::camera_handler::{CameraHandler, CAMERA_BIND_GROUP_LAYOUT};
 use spark_gap::camera::fly_camera_controller::FlyCameraController;
+use spark_gap::camera::projection::get_aspect_ratio;
 use spark_gap::frame_counter::FrameCounter;
-use spark_gap::gpu_context::GpuContext;
+use spark_gap::gpu_context::{GpuContext, GpuContextDescriptor};
 use spark_gap::model_mesh::ModelVertex;
-use spark_gap::texture::{create_depth_texture, Texture};
+use spark_gap::msaa::{create_surface_msaa_target, get_surface_color_attachment, MsaaTarget};
+use spark_gap::skybox::Skybox;
+use spark_gap::texture::{create_cubemap, create_depth_texture, Cubemap, DepthTexture, COLOR_TEXTURE_FORMAT, DEPTH_FORMAT};
+use std::cell::RefCell;
+use std::rc::Rc;
 use std::sync::Arc;
 use wgpu::{BindGroupLayout, RenderPipeline};
 use winit::event::{Event, WindowEvent};
@@ -14,6 +19,9 @@ use winit::keyboard;
 use winit::keyboard::NamedKey::Escape;
 use winit::window::Window;
 
+// falls back to 1x where the surface format doesn't support it
+const SAMPLE_COUNT: u32 = 4;
+
 const BACKGROUND_COLOR: wgpu::Color = wgpu::Color {
     r: 0.1,
     g: 0.2,
@@ -21,12 +29,24 @@ const BACKGROUND_COLOR: wgpu::Color = wgpu::Color {
     a: 1.0,
 };
 
+const SKY_FACE_SIZE: u32 = 64;
+const ZENITH_COLOR: Vec3 = Vec3::new(0.25, 0.45, 0.85);
+const HORIZON_COLOR: Vec3 = Vec3::new(0.8, 0.85, 0.9);
+const GROUND_COLOR: Vec3 = Vec3::new(0.2, 0.25, 0.2);
+
 pub async fn run(event_loop: EventLoop<()>, window: Arc<Window>) {
-    let mut context = GpuContext::new(window).await;
+    let descriptor = GpuContextDescriptor::new().set_sample_count(SAMPLE_COUNT);
+    let mut context = match GpuContext::new_with_descriptor(window, &descriptor).await {
+        Ok(context) => context,
+        Err(error) => {
+            log::error!("{}", error);
+            return;
+        }
+    };
     let mut frame_counter = FrameCounter::new();
 
-    let size = context.window.inner_size();
-    let aspect_ratio = size.width as f32 / size.height as f32;
+    let size = context.size;
+    let aspect_ratio = get_aspect_ratio(size.width, size.height);
 
     let camera_position = vec3(1.5, 1.5, 5.0);
     let camera_controller = FlyCameraController::new(aspect_ratio, camera_position, 15.0, -15.0);
@@ -35,11 +55,17 @@ pub async fn run(event_loop: EventLoop<()>, window: Arc<Window>) {
     let model = Model::new(&context);
 
     let mut depth_texture = create_depth_texture(&context);
+    let msaa_target = create_surface_msaa_target(&mut context).expect("invalid msaa target");
 
     let camera_bind_group_layout = context.bind_layout_cache.get(CAMERA_BIND_GROUP_LAYOUT).unwrap();
 
     let render_pipeline = create_render_pipeline(&context, &model.material.bind_group_layout, &camera_bind_group_layout);
 
+    let sky_cubemap = create_sky_cubemap(&context);
+    let skybox = Skybox::new(&context, sky_cubemap, context.surface_view_format(), Some(DEPTH_FORMAT)).expect("invalid skybox");
+    let projection = camera_controller.get_projection_matrix();
+    skybox.update(&context, &projection, &camera_controller.get_view_matrix());
+
     event_loop
         .run(move |event, target| {
             if let Event::WindowEvent { window_id: _, event } = event {
@@ -48,14 +74,22 @@ pub async fn run(event_loop: EventLoop<()>, window: Arc<Window>) {
                         context.resize(new_size);
                         camera_handler.update_camera(&context, &camera_controller);
                         depth_texture = create_depth_texture(&context);
-                        context.window.request_redraw();
+                        context.request_redraw();
                     }
                     WindowEvent::RedrawRequested => {
                         frame_counter.update();
 
-                        draw(&context, &render_pipeline, &camera_handler, &model, &depth_texture);
-
-                        context.window.request_redraw();
+                        draw(
+                            &context,
+                            &render_pipeline,
+                            &camera_handler,
+                            &model,
+                            &skybox,
+                            &depth_texture,
+                            &msaa_target,
+                        );
+
+                        context.request_redraw();
                     }
                     WindowEvent::KeyboardInput { event, .. } => {
                         // if event.state == ElementState::Pressed {
@@ -77,14 +111,21 @@ pub fn draw(
     render_pipeline: &RenderPipeline,
     camera_handler: &CameraHandler,
     model: &Model,
-    depth_texture: &Texture,
+    skybox: &Skybox,
+    depth_texture: &DepthTexture,
+    msaa_target: &Option<Rc<RefCell<MsaaTarget>>>,
 ) {
-    let frame = context
-        .surface
-        .get_current_texture()
-        .expect("Failed to acquire next swap chain texture");
+    let Some(frame) = context.acquire_frame_or_skip() else {
+        return;
+    };
 
-    let view = frame.texture.create_view(&wgpu::TextureViewDescriptor::default());
+    let view = frame.texture().create_view(&wgpu::TextureViewDescriptor {
+        format: Some(context.config.view_formats[0]),
+        ..Default::default()
+    });
+    let msaa_target = msaa_target.as_ref().map(|target| target.borrow());
+    let color_attachment = get_surface_color_attachment(context, msaa_target.as_deref(), &view, wgpu::LoadOp::Clear(BACKGROUND_COLOR))
+        .expect("msaa target doesn't match the surface");
 
     let mut encoder = context
         .device
@@ -93,14 +134,7 @@ pub fn draw(
     {
         let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
             label: Some("render pass"),
-            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                view: &view,
-                resolve_target: None,
-                ops: wgpu::Operations {
-                    load: wgpu::LoadOp::Clear(BACKGROUND_COLOR),
-                    store: wgpu::StoreOp::Store,
-                },
-            })],
+            color_attachments: &[Some(color_attachment)],
             depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
                 view: &depth_texture.view,
                 depth_ops: Some(wgpu::Operations {
@@ -113,6 +147,9 @@ pub fn draw(
             occlusion_query_set: None,
         });
 
+        // behind the cube, it doesn't write depth
+        skybox.draw(&mut render_pass);
+
         render_pass.set_pipeline(render_pipeline);
 
         // mesh for vertex shader
@@ -147,8 +184,8 @@ pub fn create_render_pipeline(
         source: wgpu::ShaderSource::Wgsl(include_str!("shader.wgsl").into()),
     });
 
-    let swapchain_capabilities = context.surface.get_capabilities(&context.adapter);
-    let swapchain_format = swapchain_capabilities.formats[0];
+    // the frame view and msaa target use the srgb view format
+    let swapchain_format = context.config.view_formats[0];
 
     let render_pipeline = context.device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
         label: Some("Render Pipeline"),
@@ -173,15 +210,35 @@ pub fn create_render_pipeline(
             conservative: false,
         },
         depth_stencil: Some(wgpu::DepthStencilState {
-            format: wgpu::TextureFormat::Depth32Float,
+            format: DEPTH_FORMAT,
             depth_write_enabled: true,
             depth_compare: wgpu::CompareFunction::Less,
             stencil: wgpu::StencilState::default(),
             bias: wgpu::DepthBiasState::default(),
         }),
-        multisample: wgpu::MultisampleState::default(),
+        multisample: context.multisample_state(),
         multiview: None,
     });
 
     render_pipeline
 }
+
+// A vertical gradient from the ground to the zenith. Rows of the side faces run from +y down to -y.
+fn create_sky_cubemap(context: &GpuContext) -> Cubemap {
+    let get_color = |height: f32| {
+        let color = match height >= 0.0 {
+            true => HORIZON_COLOR.lerp(ZENITH_COLOR, height),
+            false => HORIZON_COLOR.lerp(GROUND_COLOR, (-height * 4.0).min(1.0)),
+        };
+        let [r, g, b] = (color * 255.0).to_array().map(|value| value as u8);
+        image::Rgba([r, g, b, 255])
+    };
+    let side = image::RgbaImage::from_fn(SKY_FACE_SIZE, SKY_FACE_SIZE, |_, y| {
+        get_color(1.0 - 2.0 * (y as f32 + 0.5) / SKY_FACE_SIZE as f32)
+    });
+    let top = image::RgbaImage::from_pixel(SKY_FACE_SIZE, SKY_FACE_SIZE, get_color(1.0));
+    let bottom = image::RgbaImage::from_pixel(SKY_FACE_SIZE, SKY_FACE_SIZE, get_color(-1.0));
+
+    let faces = [side.clone(), side.clone(), top, bottom, side.clone(), side];
+    create_cubemap(context, &faces, COLOR_TEXTURE_FORMAT, "sky cubemap").expect("invalid sky cubemap")
+}
@@ -1,10 +1,21 @@
-use gltf::Gltf;
+use spark_gap::gpu_context::GpuContext;
+use spark_gap::model::load_gltf;
 
+// Lists the meshes of a .gltf or .glb file given as the first argument
 fn main() {
-    let gltf = Gltf::open("/Users/john/Dev/Assets/glTF-Sample-Models/2.0/CesiumMan/glTF/CesiumMan.gltf").unwrap();
-    for scene in gltf.scenes() {
-        for node in scene.nodes() {
-            println!("Node #{} has {} children", node.index(), node.children().count(),);
-        }
+    let path = std::env::args().nth(1).expect("usage: gltf_example <file.gltf>");
+
+    let context = pollster::block_on(GpuContext::new_headless(1, 1)).expect("no gpu adapter");
+    let model = load_gltf(&context, &path).unwrap();
+
+    println!("{} has {} meshes", model.name, model.meshes.len());
+    for mesh in model.meshes.iter() {
+        println!(
+            "{}: {} {:?} indices, base color texture: {}",
+            mesh.name,
+            mesh.index_count,
+            mesh.index_format,
+            mesh.base_color_texture.is_some()
+        );
     }
 }
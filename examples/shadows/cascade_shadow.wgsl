@@ -0,0 +1,21 @@
+//!include "spark_gap/shadow_cascades.wgsl"
+
+// The directional light's shadow passes, one per cascade
+
+// Entity in shader.wgsl
+struct Entity {
+    world: mat4x4<f32>,
+    previous_world: mat4x4<f32>,
+    normal: mat3x3<f32>,
+    color: vec4<f32>,
+    tint: vec4<f32>,
+};
+
+@group(0) @binding(0) var<uniform> shadow_cascades: ShadowCascades;
+
+@group(1) @binding(0) var<uniform> entity_data: Entity;
+
+// the instance id is the cascade, like the shadow layer of vs_shadow
+@vertex fn vs_cascade_shadow(@location(0) position: vec4<i32>, @builtin(instance_index) cascade: u32) -> @builtin(position) vec4<f32> {
+    return shadow_cascades.projection_views[cascade] * entity_data.world * vec4<f32>(position);
+}
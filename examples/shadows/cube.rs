@@ -1,9 +1,15 @@
 use bytemuck::{Pod, Zeroable};
 
+use spark_gap::culling::Aabb;
+use spark_gap::VertexLayout;
+
+// Sint8x4 is four signed bytes (i8), vec4<i32> in shaders
 #[repr(C)]
-#[derive(Clone, Copy, Pod, Zeroable)]
+#[derive(Clone, Copy, Pod, Zeroable, VertexLayout)]
 pub struct Vertex {
+    #[vertex(location = 0, format = Sint8x4)]
     pub _pos: [i8; 4],
+    #[vertex(location = 1, format = Sint8x4)]
     pub _normal: [i8; 4],
 }
 
@@ -72,3 +78,12 @@ pub fn create_plane(size: i8) -> (Vec<Vertex>, Vec<u16>) {
 
     (vertex_data.to_vec(), index_data.to_vec())
 }
+
+// box around the vertices in mesh space
+pub fn get_bounding_box(vertices: &[Vertex]) -> Aabb {
+    Aabb::from_points(
+        vertices
+            .iter()
+            .map(|v| glam::vec3(v._pos[0] as f32, v._pos[1] as f32, v._pos[2] as f32)),
+    )
+}
@@ -12,9 +12,10 @@ struct VertexOutput {
 
 @group(0) @binding(0) var<uniform> projection_view: mat4x4<f32>;
 @group(0) @binding(1) var<uniform> model_transform: mat4x4<f32>;
-@group(0) @binding(2) var<uniform> layer_num: u32;
+// uv offset in xy and scale in zw of the displayed shadow map, zero scale when there is none
+@group(0) @binding(2) var<uniform> atlas_rect: vec4<f32>;
 
-@group(0) @binding(3) var texture: texture_depth_2d_array;
+@group(0) @binding(3) var texture: texture_depth_2d;
 @group(0) @binding(4) var texture_sampler: sampler;
 
 
@@ -33,7 +34,8 @@ struct VertexOutput {
     let flip_correction = vec2<f32>(1.0, -1.0);
     let tex_coords = in.tex_coords * flip_correction + vec2<f32>(0.0, 1.0);
 
-    var value = textureSample(texture, texture_sampler, tex_coords, layer_num);
+    let sampled = textureSample(texture, texture_sampler, atlas_rect.xy + tex_coords * atlas_rect.zw);
+    var value = select(1.0, sampled, atlas_rect.z > 0.0);
 
     // expand top range and reverse the range for better grayscale contrast
     value = 1.0 - (value - 0.80) * 5.0;
@@ -9,7 +9,7 @@ use spark_gap::buffers::create_mat4_buffer_init;
 use spark_gap::gpu_context::{get_or_create_bind_group_layout, GpuContext};
 use spark_gap::small_mesh::{create_unit_square, SmallMesh};
 
-use crate::lights::MAX_LIGHTS;
+use crate::lights::{ShadowLayer, SHADOW_ATLAS_SIZE};
 
 pub const SHADOW_WIDTH: u32 = 6 * 1024;
 pub const SHADOW_HEIGHT: u32 = 6 * 1024;
@@ -24,9 +24,12 @@ pub struct ShadowMaterial {
     pub quad_mesh: SmallMesh,
     pub projection_view_buffer: Buffer,
     pub transform_buffer: Buffer,
-    pub layer_num_buffer: Buffer,
+    // uv rect of the light shown by the debug view
+    pub atlas_rect_buffer: Buffer,
     pub shadow_debug_bind_group: BindGroup,
     pub shadow_debug_pipeline: RenderPipeline,
+    // background of the debug view around the shadow map quad
+    pub clear_color: wgpu::Color,
 }
 
 pub fn create_shadow_map_material(context: &mut GpuContext) -> ShadowMaterial {
@@ -40,21 +43,21 @@ pub fn create_shadow_map_material(context: &mut GpuContext) -> ShadowMaterial {
 
     let transform_buffer = create_mat4_buffer_init(context, &model_transform, "shadow debug transform");
 
-    let layer_num = 0_u32;
+    let atlas_rect = [0.0f32; 4];
 
-    let layer_num_buffer = context.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-        label: Some("layer number"),
-        contents: bytemuck::bytes_of(&layer_num),
+    let atlas_rect_buffer = context.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("shadow debug atlas rect"),
+        contents: bytemuck::bytes_of(&atlas_rect),
         usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
     });
 
     let texture_size = wgpu::Extent3d {
-        width: 2048,
-        height: 2048,
-        depth_or_array_layers: MAX_LIGHTS as u32,
+        width: SHADOW_ATLAS_SIZE,
+        height: SHADOW_ATLAS_SIZE,
+        depth_or_array_layers: 1,
     };
 
-    // multi layered depth texture
+    // depth atlas holding the shadow maps of all the lights
     let texture = context.device.create_texture(&wgpu::TextureDescriptor {
         size: texture_size,
         mip_level_count: 1,
@@ -95,7 +98,7 @@ pub fn create_shadow_map_material(context: &mut GpuContext) -> ShadowMaterial {
             },
             wgpu::BindGroupEntry {
                 binding: 2,
-                resource: layer_num_buffer.as_entire_binding(),
+                resource: atlas_rect_buffer.as_entire_binding(),
             },
             wgpu::BindGroupEntry {
                 binding: 3,
@@ -117,9 +120,19 @@ pub fn create_shadow_map_material(context: &mut GpuContext) -> ShadowMaterial {
         quad_mesh,
         projection_view_buffer,
         transform_buffer,
-        layer_num_buffer,
+        atlas_rect_buffer,
         shadow_debug_bind_group,
         shadow_debug_pipeline,
+        clear_color: wgpu::Color::BLACK,
+    }
+}
+
+// The uv rect of the shown shadow layer, an empty rect when there is no such layer so the debug
+// shader shows a cleared map instead of sampling whatever is in the atlas
+pub fn get_debug_atlas_rect(layers: &[ShadowLayer], layer_number: u32) -> [f32; 4] {
+    match layers.get(layer_number as usize) {
+        Some(layer) if layer.atlas_rect.size > 0 => layer.atlas_rect.get_uv_rect(SHADOW_ATLAS_SIZE),
+        _ => [0.0; 4],
     }
 }
 
@@ -148,14 +161,14 @@ fn create_shadow_filter_bind_group_layout(context: &GpuContext, label: &str) ->
                 },
                 count: None,
             },
-            // layer number
+            // atlas rect
             wgpu::BindGroupLayoutEntry {
                 binding: 2,
                 visibility: wgpu::ShaderStages::FRAGMENT,
                 ty: wgpu::BindingType::Buffer {
                     ty: wgpu::BufferBindingType::Uniform,
                     has_dynamic_offset: false,
-                    min_binding_size: wgpu::BufferSize::new(mem::size_of::<u32>() as _),
+                    min_binding_size: wgpu::BufferSize::new(mem::size_of::<[f32; 4]>() as _),
                 },
                 count: None,
             },
@@ -166,7 +179,7 @@ fn create_shadow_filter_bind_group_layout(context: &GpuContext, label: &str) ->
                 ty: wgpu::BindingType::Texture {
                     multisampled: false,
                     sample_type: wgpu::TextureSampleType::Depth,
-                    view_dimension: wgpu::TextureViewDimension::D2Array,
+                    view_dimension: wgpu::TextureViewDimension::D2,
                 },
                 count: None,
             },
@@ -196,7 +209,7 @@ pub fn create_debug_depth_render_pipeline(gpu_context: &GpuContext) -> RenderPip
         push_constant_ranges: &[],
     });
 
-    let swapchain_capabilities = gpu_context.surface.get_capabilities(&gpu_context.adapter);
+    let swapchain_capabilities = gpu_context.get_surface_capabilities();
     let swapchain_format = swapchain_capabilities.formats[0];
 
     let render_pipeline = gpu_context.device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
@@ -0,0 +1,43 @@
+//!include "spark_gap/gbuffer_encoding.wgsl"
+//!include "spark_gap/fullscreen.wgsl"
+//!include "shader.wgsl"
+
+// The deferred path, using the lighting bindings and functions of shader.wgsl
+
+// CLEAR_COLOR in world.rs
+const BACKGROUND_COLOR: vec4<f32> = vec4<f32>(0.1, 0.2, 0.3, 1.0);
+
+@group(2) @binding(0) var gbuffer_albedo: texture_2d<f32>;
+@group(2) @binding(1) var gbuffer_normal: texture_2d<f32>;
+@group(2) @binding(2) var gbuffer_depth: texture_depth_2d;
+@group(2) @binding(3) var<uniform> inverse_projection_view: mat4x4<f32>;
+
+// in the order of GBufferLayout::compact
+struct GBufferOutput {
+    @location(0) albedo: vec4<f32>,
+    @location(1) normal: vec2<f32>,
+    @location(2) material: vec4<f32>,
+};
+
+@fragment fn fs_gbuffer(vertex: VertexOutput) -> GBufferOutput {
+    var result: GBufferOutput;
+    result.albedo = entity_data.color * entity_data.tint;
+    result.normal = encode_octahedral(normalize(vertex.world_normal));
+    result.material = vec4<f32>(0.0);
+    return result;
+}
+
+@fragment fn fs_deferred_lighting(in: FullscreenOutput) -> @location(0) vec4<f32> {
+    let coords = vec2<i32>(in.position.xy);
+    let depth = textureLoad(gbuffer_depth, coords, 0);
+    let albedo = textureLoad(gbuffer_albedo, coords, 0);
+    let normal = decode_octahedral(textureLoad(gbuffer_normal, coords, 0).xy);
+
+    // world position from the depth buffer
+    let ndc = vec4<f32>(in.uv.x * 2.0 - 1.0, 1.0 - in.uv.y * 2.0, depth, 1.0);
+    let world_position = inverse_projection_view * ndc;
+
+    // no discard for the background, shade_lights takes derivatives so it needs uniform control flow
+    let color = vec4<f32>(shade_lights(world_position / world_position.w, normal), 1.0) * albedo;
+    return select(color, BACKGROUND_COLOR, depth >= 1.0);
+}
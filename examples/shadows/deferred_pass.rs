@@ -0,0 +1,248 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::mem;
+use std::rc::Rc;
+
+use glam::Mat4;
+use wgpu::{BindGroup, BindGroupLayout, Buffer, RenderPipeline, TextureView};
+
+use spark_gap::bind_group::create_pipeline_layout;
+use spark_gap::buffers::create_mat4_buffer_init;
+use spark_gap::depth_prepass::{get_depth_prepass_descriptor, get_depth_prepass_layout_entry};
+use spark_gap::gbuffer::{create_surface_gbuffer, GBuffer, GBufferLayout};
+use spark_gap::gpu_context::GpuContext;
+use spark_gap::post::tonemap::HDR_FORMAT;
+use spark_gap::shader_preprocessor::expand_embedded_includes;
+use spark_gap::texture::DEPTH_FORMAT;
+
+use crate::cube::Vertex;
+use crate::forward_pass::ForwardPass;
+use crate::world::get_shader_include;
+
+// Geometry pass writing the gbuffer, then a fullscreen pass lighting it with the forward pass's
+// lights and shadow atlas. Both are recorded once per viewport into its rect of the targets.
+pub struct DeferredPass {
+    // follows the surface size by itself, the bind groups sampling it are rebuilt by resize
+    pub gbuffer: Rc<RefCell<GBuffer>>,
+    pub depth_view: TextureView,
+    pub geometry_pipeline: RenderPipeline,
+    pub lighting_pipeline: RenderPipeline,
+    pub gbuffer_bind_group_layout: BindGroupLayout,
+    pub viewports: Vec<DeferredViewport>,
+}
+
+// The lighting pass reconstructs world positions with the projection of the viewport's camera
+pub struct DeferredViewport {
+    pub inverse_projection_view_buffer: Buffer,
+    pub gbuffer_bind_group: BindGroup,
+}
+
+impl DeferredPass {
+    pub fn resize(&mut self, context: &GpuContext) {
+        self.depth_view = create_gbuffer_depth(context);
+        let gbuffer = self.gbuffer.borrow();
+        for viewport in &mut self.viewports {
+            viewport.gbuffer_bind_group = create_gbuffer_bind_group(
+                context,
+                &self.gbuffer_bind_group_layout,
+                &gbuffer,
+                &self.depth_view,
+                &viewport.inverse_projection_view_buffer,
+            );
+        }
+    }
+
+    // one per camera viewport, at least one
+    pub fn set_viewport_count(&mut self, context: &GpuContext, count: usize) {
+        let count = count.max(1);
+        self.viewports.truncate(count);
+        while self.viewports.len() < count {
+            let viewport = create_deferred_viewport(context, &self.gbuffer_bind_group_layout, &self.gbuffer.borrow(), &self.depth_view);
+            self.viewports.push(viewport);
+        }
+    }
+}
+
+pub fn create_deferred_pass(
+    context: &mut GpuContext,
+    forward_pass: &ForwardPass,
+    entity_bind_group_layout: &BindGroupLayout,
+) -> DeferredPass {
+    let shader_include = get_shader_include();
+    let source = expand_embedded_includes("deferred.wgsl", include_str!("deferred.wgsl"), &[("shader.wgsl", shader_include.as_str())])
+        .expect("invalid deferred.wgsl")
+        .source;
+
+    let shader = context.device.create_shader_module(wgpu::ShaderModuleDescriptor {
+        label: Some("deferred shader"),
+        source: wgpu::ShaderSource::Wgsl(Cow::Owned(source)),
+    });
+
+    let gbuffer = create_surface_gbuffer(context, GBufferLayout::compact(), wgpu::TextureUsages::empty());
+    let depth_view = create_gbuffer_depth(context);
+
+    let gbuffer_texture_entry = |binding| wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::FRAGMENT,
+        ty: wgpu::BindingType::Texture {
+            multisampled: false,
+            view_dimension: wgpu::TextureViewDimension::D2,
+            sample_type: wgpu::TextureSampleType::Float { filterable: false },
+        },
+        count: None,
+    };
+
+    let gbuffer_bind_group_layout = context.device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        entries: &[
+            // albedo
+            gbuffer_texture_entry(0),
+            // normal
+            gbuffer_texture_entry(1),
+            get_depth_prepass_layout_entry(2, wgpu::ShaderStages::FRAGMENT),
+            // inverse projection_view
+            wgpu::BindGroupLayoutEntry {
+                binding: 3,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: wgpu::BufferSize::new(mem::size_of::<Mat4>() as _),
+                },
+                count: None,
+            },
+        ],
+        label: Some("gbuffer bind group layout"),
+    });
+
+    let viewport = create_deferred_viewport(context, &gbuffer_bind_group_layout, &gbuffer.borrow(), &depth_view);
+
+    let geometry_layout = create_pipeline_layout(
+        context,
+        "gbuffer",
+        &[(0, &forward_pass.bind_group_layout), (1, entity_bind_group_layout)],
+        &[],
+    )
+    .expect("invalid gbuffer pipeline layout");
+
+    let geometry_pipeline = context.device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("gbuffer pipeline"),
+        layout: Some(&geometry_layout),
+        vertex: wgpu::VertexState {
+            module: &shader,
+            entry_point: "vs_main",
+            buffers: &[Vertex::vertex_buffer_layout()],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: &shader,
+            entry_point: "fs_gbuffer",
+            targets: &gbuffer.borrow().get_color_targets(),
+        }),
+        primitive: wgpu::PrimitiveState {
+            front_face: wgpu::FrontFace::Ccw,
+            cull_mode: Some(wgpu::Face::Back),
+            ..Default::default()
+        },
+        depth_stencil: Some(wgpu::DepthStencilState {
+            format: DEPTH_FORMAT,
+            depth_write_enabled: true,
+            depth_compare: wgpu::CompareFunction::Less,
+            stencil: wgpu::StencilState::default(),
+            bias: wgpu::DepthBiasState::default(),
+        }),
+        multisample: wgpu::MultisampleState::default(),
+        multiview: None,
+    });
+
+    // the entity group isn't read by the lighting shader, it is bound at offset 0 to keep the group indices of shader.wgsl
+    let lighting_layout = create_pipeline_layout(
+        context,
+        "deferred lighting",
+        &[
+            (0, &forward_pass.bind_group_layout),
+            (1, entity_bind_group_layout),
+            (2, &gbuffer_bind_group_layout),
+        ],
+        &[],
+    )
+    .expect("invalid deferred lighting pipeline layout");
+
+    let lighting_pipeline = context.device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("deferred lighting pipeline"),
+        layout: Some(&lighting_layout),
+        vertex: wgpu::VertexState {
+            module: &shader,
+            entry_point: "vs_fullscreen",
+            buffers: &[],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: &shader,
+            entry_point: "fs_deferred_lighting",
+            targets: &[Some(HDR_FORMAT.into())],
+        }),
+        primitive: wgpu::PrimitiveState::default(),
+        depth_stencil: None,
+        multisample: wgpu::MultisampleState::default(),
+        multiview: None,
+    });
+
+    DeferredPass {
+        gbuffer,
+        depth_view,
+        geometry_pipeline,
+        lighting_pipeline,
+        gbuffer_bind_group_layout,
+        viewports: vec![viewport],
+    }
+}
+
+fn create_deferred_viewport(
+    context: &GpuContext,
+    layout: &BindGroupLayout,
+    gbuffer: &GBuffer,
+    depth_view: &TextureView,
+) -> DeferredViewport {
+    let inverse_projection_view_buffer = create_mat4_buffer_init(context, &Mat4::IDENTITY, "inverse projection view");
+    let gbuffer_bind_group = create_gbuffer_bind_group(context, layout, gbuffer, depth_view, &inverse_projection_view_buffer);
+    DeferredViewport {
+        inverse_projection_view_buffer,
+        gbuffer_bind_group,
+    }
+}
+
+fn create_gbuffer_depth(context: &GpuContext) -> TextureView {
+    let texture = context
+        .device
+        .create_texture(&get_depth_prepass_descriptor(context.config.width, context.config.height));
+    texture.create_view(&wgpu::TextureViewDescriptor::default())
+}
+
+fn create_gbuffer_bind_group(
+    context: &GpuContext,
+    layout: &BindGroupLayout,
+    gbuffer: &GBuffer,
+    depth_view: &TextureView,
+    inverse_projection_view_buffer: &Buffer,
+) -> BindGroup {
+    context.device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(gbuffer.get_view(0)),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::TextureView(gbuffer.get_view(1)),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: wgpu::BindingResource::TextureView(depth_view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 3,
+                resource: inverse_projection_view_buffer.as_entire_binding(),
+            },
+        ],
+        label: Some("gbuffer bind group"),
+    })
+}
@@ -1,32 +1,63 @@
 use std::f32::consts;
-use std::mem;
 use std::sync::Arc;
 
 use bytemuck::{Pod, Zeroable};
 use glam::{Mat4, Quat, Vec3};
-use wgpu::util::{align_to, DeviceExt};
+use wgpu::util::DeviceExt;
 use wgpu::{BindGroup, BindGroupLayout, Buffer};
 
+use spark_gap::buffers::DynamicUniformBuffer;
+use spark_gap::culling::Aabb;
+#[cfg(feature = "serde")]
+use spark_gap::error::Error;
+#[cfg(feature = "serde")]
+use spark_gap::error::Error::SceneError;
 use spark_gap::gpu_context::GpuContext;
+use spark_gap::math::{get_normal_matrix, mat3_to_padded_cols};
+#[cfg(feature = "serde")]
+use spark_gap::scene_file::SceneFile;
 
-use crate::cube::{create_cube, create_plane};
+use crate::cube::{create_cube, create_plane, get_bounding_box, Vertex};
+
+// Entities are drawn in ascending sort_key order, entities with equal keys keep their order.
+// Opaque entities use 0..1000, transparent 1000..2000 and overlays 2000 and up.
+pub const SORT_KEY_OPAQUE: u32 = 0;
+pub const SORT_KEY_TRANSPARENT: u32 = 1000;
+pub const SORT_KEY_OVERLAY: u32 = 2000;
 
 pub struct Entity {
     pub mx_world: Mat4,
+    // world matrix from the previous frame, for motion vectors
+    pub prev_mx_world: Mat4,
     pub rotation_speed: f32,
     pub color: wgpu::Color,
+    // multiplied with color, to tell apart instances sharing a mesh
+    pub tint: wgpu::Color,
     pub vertex_buf: Arc<Buffer>,
     pub index_buf: Arc<Buffer>,
     pub index_format: wgpu::IndexFormat,
     pub index_count: usize,
     pub uniform_offset: wgpu::DynamicOffset,
+    // in mesh space
+    pub bounding_box: Aabb,
+    pub sort_key: u32,
+}
+
+impl Entity {
+    // bounding box in world space
+    pub fn get_bounding_box(&self) -> Aabb {
+        self.bounding_box.transform(&self.mx_world)
+    }
 }
 
 #[repr(C)]
 #[derive(Clone, Copy, Pod, Zeroable)]
 pub struct EntityUniform {
     pub model: [[f32; 4]; 4],
+    pub previous_model: [[f32; 4]; 4],
+    pub normal: [[f32; 4]; 3],
     pub color: [f32; 4],
+    pub tint: [f32; 4],
 }
 
 pub struct CubeDesc {
@@ -37,44 +68,122 @@ pub struct CubeDesc {
 }
 
 pub struct Entities {
-    pub entity_uniform_buf: Buffer,
+    pub entity_uniform_buf: DynamicUniformBuffer<EntityUniform>,
     pub entities: Vec<Entity>,
     pub entity_bind_group_layout: BindGroupLayout,
     pub entity_bind_group: BindGroup,
 }
 
-impl Entities {
-    pub fn new(gpu_context: &mut GpuContext) -> Self {
-        let (plane_vertex_data, plane_index_data) = create_plane(7);
+// Vertex and index buffers shared by the entities drawing the mesh
+pub struct EntityMesh {
+    pub vertex_buf: Arc<Buffer>,
+    pub index_buf: Arc<Buffer>,
+    pub index_count: usize,
+    pub bounding_box: Aabb,
+}
 
-        let plane_vertex_buf = gpu_context.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Plane Vertex Buffer"),
-            contents: bytemuck::cast_slice(&plane_vertex_data),
+impl EntityMesh {
+    pub fn new(gpu_context: &GpuContext, label: &str, vertex_data: &[Vertex], index_data: &[u16]) -> Self {
+        let vertex_buf = gpu_context.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&format!("{} Vertex Buffer", label)),
+            contents: bytemuck::cast_slice(vertex_data),
             usage: wgpu::BufferUsages::VERTEX,
         });
 
-        let plane_index_buf = gpu_context.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Plane Index Buffer"),
-            contents: bytemuck::cast_slice(&plane_index_data),
+        let index_buf = gpu_context.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&format!("{} Index Buffer", label)),
+            contents: bytemuck::cast_slice(index_data),
             usage: wgpu::BufferUsages::INDEX,
         });
 
-        let (cube_vertex_data, cube_index_data) = create_cube();
+        EntityMesh {
+            vertex_buf: Arc::new(vertex_buf),
+            index_buf: Arc::new(index_buf),
+            index_count: index_data.len(),
+            bounding_box: get_bounding_box(vertex_data),
+        }
+    }
 
-        let cube_vertex_buf = Arc::new(gpu_context.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Cubes Vertex Buffer"),
-            contents: bytemuck::cast_slice(&cube_vertex_data),
-            usage: wgpu::BufferUsages::VERTEX,
-        }));
+    pub fn plane(gpu_context: &GpuContext) -> Self {
+        let (vertex_data, index_data) = create_plane(7);
+        EntityMesh::new(gpu_context, "Plane", &vertex_data, &index_data)
+    }
 
-        let cube_index_buf = Arc::new(gpu_context.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Cubes Index Buffer"),
-            contents: bytemuck::cast_slice(&cube_index_data),
-            usage: wgpu::BufferUsages::INDEX,
-        }));
+    pub fn cube(gpu_context: &GpuContext) -> Self {
+        let (vertex_data, index_data) = create_cube();
+        EntityMesh::new(gpu_context, "Cubes", &vertex_data, &index_data)
+    }
+}
+
+// The initial state of an entity, the uniform offsets are assigned when the entities are created
+pub struct EntitySpawn<'a> {
+    pub mesh: &'a EntityMesh,
+    pub mx_world: Mat4,
+    pub rotation_speed: f32,
+    pub color: wgpu::Color,
+}
+
+impl Entities {
+    pub fn new(gpu_context: &mut GpuContext) -> Self {
+        let plane = EntityMesh::plane(gpu_context);
+        let cube = EntityMesh::cube(gpu_context);
+
+        let mut spawns = vec![EntitySpawn {
+            mesh: &plane,
+            mx_world: Mat4::IDENTITY,
+            rotation_speed: 0.0,
+            color: wgpu::Color::WHITE,
+        }];
+
+        for cube_desc in get_cube_descriptions().iter() {
+            spawns.push(EntitySpawn {
+                mesh: &cube,
+                mx_world: Mat4::from_scale_rotation_translation(
+                    Vec3::splat(cube_desc.scale),
+                    Quat::from_axis_angle(cube_desc.offset.normalize(), cube_desc.angle * consts::PI / 180.),
+                    cube_desc.offset,
+                ),
+                rotation_speed: cube_desc.rotation,
+                color: wgpu::Color::GREEN,
+            });
+        }
+
+        Entities::from_spawns(gpu_context, &spawns)
+    }
+
+    // Only the plane and cube meshes of this example can be used in scene files
+    #[cfg(feature = "serde")]
+    pub fn from_scene(gpu_context: &mut GpuContext, scene: &SceneFile) -> Result<Self, Error> {
+        if scene.entities.is_empty() {
+            return Err(SceneError("scene has no entities".to_string()));
+        }
+
+        let plane = EntityMesh::plane(gpu_context);
+        let cube = EntityMesh::cube(gpu_context);
 
-        let entity_uniform_size = mem::size_of::<EntityUniform>() as wgpu::BufferAddress;
+        let spawns = scene
+            .entities
+            .iter()
+            .map(|desc| {
+                let mesh = match desc.mesh.as_str() {
+                    "plane" => &plane,
+                    "cube" => &cube,
+                    other => return Err(SceneError(format!("unknown mesh: {}", other))),
+                };
+                let [r, g, b, a] = desc.material.color.map(|c| c as f64);
+                Ok(EntitySpawn {
+                    mesh,
+                    mx_world: desc.transform.to_transform().compute_matrix(),
+                    rotation_speed: 0.0,
+                    color: wgpu::Color { r, g, b, a },
+                })
+            })
+            .collect::<Result<Vec<EntitySpawn>, Error>>()?;
 
+        Ok(Entities::from_spawns(gpu_context, &spawns))
+    }
+
+    pub fn from_spawns(gpu_context: &mut GpuContext, spawns: &[EntitySpawn]) -> Self {
         let entity_bind_group_layout = gpu_context.device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
             entries: &[wgpu::BindGroupLayoutEntry {
                 binding: 0,
@@ -82,80 +191,42 @@ impl Entities {
                 ty: wgpu::BindingType::Buffer {
                     ty: wgpu::BufferBindingType::Uniform,
                     has_dynamic_offset: true,
-                    min_binding_size: wgpu::BufferSize::new(entity_uniform_size),
+                    min_binding_size: DynamicUniformBuffer::<EntityUniform>::binding_size(),
                 },
                 count: None,
             }],
             label: None,
         });
 
-        let cube_descriptions = get_cube_descriptions();
-
-        let num_entities = 1 + cube_descriptions.len() as wgpu::BufferAddress;
-
-        // Make the `uniform_alignment` >= `entity_uniform_size` and aligned to `min_uniform_buffer_offset_alignment`.
-        let uniform_alignment = {
-            let alignment = gpu_context.device.limits().min_uniform_buffer_offset_alignment as wgpu::BufferAddress;
-            align_to(entity_uniform_size, alignment)
-        };
-
-        // Note: dynamic uniform offsets also have to be aligned to `Limits::min_uniform_buffer_offset_alignment`.
-        let entity_uniform_buf = gpu_context.device.create_buffer(&wgpu::BufferDescriptor {
-            label: None,
-            size: num_entities * uniform_alignment,
-            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-            mapped_at_creation: false,
-        });
+        let entity_uniform_buf = DynamicUniformBuffer::<EntityUniform>::new(gpu_context, spawns.len(), "entity uniforms");
 
         let entity_bind_group = gpu_context.device.create_bind_group(&wgpu::BindGroupDescriptor {
             layout: &entity_bind_group_layout,
             entries: &[wgpu::BindGroupEntry {
                 binding: 0,
-                resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
-                    buffer: &entity_uniform_buf,
-                    offset: 0,
-                    size: wgpu::BufferSize::new(entity_uniform_size),
-                }),
+                resource: entity_uniform_buf.binding(),
             }],
             label: None,
         });
 
-        let index_format = wgpu::IndexFormat::Uint16;
-
-        let mut entities = vec![{
-            Entity {
-                mx_world: Mat4::IDENTITY,
-                rotation_speed: 0.0,
-                color: wgpu::Color::WHITE,
-                vertex_buf: Arc::new(plane_vertex_buf),
-                index_buf: Arc::new(plane_index_buf),
-                index_format,
-                index_count: plane_index_data.len(),
-                uniform_offset: 0,
-            }
-        }];
-
-        for (i, cube) in cube_descriptions.iter().enumerate() {
-            // todo: temp for just one cube
-            // if i > 0 { break; }
-
-            let mx_world = Mat4::from_scale_rotation_translation(
-                Vec3::splat(cube.scale),
-                Quat::from_axis_angle(cube.offset.normalize(), cube.angle * consts::PI / 180.),
-                cube.offset,
-            );
-
-            entities.push(Entity {
-                mx_world,
-                rotation_speed: cube.rotation,
-                color: wgpu::Color::GREEN,
-                vertex_buf: Arc::clone(&cube_vertex_buf),
-                index_buf: Arc::clone(&cube_index_buf),
-                index_format,
-                index_count: cube_index_data.len(),
-                uniform_offset: ((i + 1) * uniform_alignment as usize) as _,
-            });
-        }
+        let entities = spawns
+            .iter()
+            .enumerate()
+            .map(|(i, spawn)| Entity {
+                mx_world: spawn.mx_world,
+                prev_mx_world: spawn.mx_world,
+                rotation_speed: spawn.rotation_speed,
+                color: spawn.color,
+                tint: wgpu::Color::WHITE,
+                vertex_buf: Arc::clone(&spawn.mesh.vertex_buf),
+                index_buf: Arc::clone(&spawn.mesh.index_buf),
+                index_format: wgpu::IndexFormat::Uint16,
+                index_count: spawn.mesh.index_count,
+                uniform_offset: entity_uniform_buf.offset(i),
+                bounding_box: spawn.mesh.bounding_box,
+                sort_key: SORT_KEY_OPAQUE,
+            })
+            .collect();
 
         Entities {
             entity_uniform_buf,
@@ -167,27 +238,38 @@ impl Entities {
 
     pub fn update(&mut self, context: &GpuContext) {
         // update uniforms
-        for entity in self.entities.iter_mut() {
+        for (i, entity) in self.entities.iter_mut().enumerate() {
+            entity.prev_mx_world = entity.mx_world;
             if entity.rotation_speed != 0.0 {
                 let rotation = Mat4::from_rotation_x(entity.rotation_speed * consts::PI / 180.);
                 entity.mx_world *= rotation;
             }
             let data = EntityUniform {
                 model: entity.mx_world.to_cols_array_2d(),
-                color: [
-                    entity.color.r as f32,
-                    entity.color.g as f32,
-                    entity.color.b as f32,
-                    entity.color.a as f32,
-                ],
+                previous_model: entity.prev_mx_world.to_cols_array_2d(),
+                normal: mat3_to_padded_cols(&get_normal_matrix(&entity.mx_world)),
+                color: color_to_array(&entity.color),
+                tint: color_to_array(&entity.tint),
             };
-            context.queue.write_buffer(
-                &self.entity_uniform_buf,
-                entity.uniform_offset as wgpu::BufferAddress,
-                bytemuck::bytes_of(&data),
-            );
+            self.entity_uniform_buf.write(context, i, &data);
         }
     }
+
+    // indices into entities in draw order
+    pub fn get_draw_order(&self) -> Vec<usize> {
+        get_draw_order(self.entities.iter().map(|entity| entity.sort_key))
+    }
+}
+
+pub fn color_to_array(color: &wgpu::Color) -> [f32; 4] {
+    [color.r as f32, color.g as f32, color.b as f32, color.a as f32]
+}
+
+// stable, so entities with the same key are drawn in insertion order
+pub fn get_draw_order(sort_keys: impl Iterator<Item = u32>) -> Vec<usize> {
+    let mut order: Vec<(usize, u32)> = sort_keys.enumerate().collect();
+    order.sort_by_key(|(_, sort_key)| *sort_key);
+    order.into_iter().map(|(index, _)| index).collect()
 }
 
 pub fn get_cube_descriptions() -> [CubeDesc; 4] {
@@ -220,3 +302,53 @@ pub fn get_cube_descriptions() -> [CubeDesc; 4] {
 
     cube_descriptions
 }
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+
+    use crate::entities::{get_draw_order, EntityUniform, SORT_KEY_OPAQUE, SORT_KEY_OVERLAY, SORT_KEY_TRANSPARENT};
+
+    #[test]
+    fn test_draw_order() {
+        let sort_keys = [SORT_KEY_OVERLAY, SORT_KEY_OPAQUE, SORT_KEY_TRANSPARENT, SORT_KEY_OPAQUE, 5];
+        let order = get_draw_order(sort_keys.into_iter());
+
+        assert_eq!(order, vec![1, 3, 4, 2, 0]);
+
+        let drawn_keys: Vec<u32> = order.iter().map(|i| sort_keys[*i]).collect();
+        assert!(drawn_keys.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_scene_file() {
+        let scene = spark_gap::scene_file::parse_scene(include_str!("scene.json")).unwrap();
+        assert_eq!(scene.entities.len(), 3);
+        // two spot lights, a directional and a point light
+        assert_eq!(scene.lights.len(), 4);
+        assert_eq!(scene.lights[3].light_type, spark_gap::scene_file::LightType::Point);
+
+        // only the meshes Entities::from_scene can resolve
+        assert!(scene.entities.iter().all(|entity| entity.mesh == "plane" || entity.mesh == "cube"));
+    }
+
+    #[test]
+    fn test_entity_uniform_tint_offset() {
+        // matches the Entity struct in shader.wgsl
+        assert_eq!(mem::offset_of!(EntityUniform, color), 176);
+        assert_eq!(mem::offset_of!(EntityUniform, tint), 192);
+        assert_eq!(mem::size_of::<EntityUniform>(), 208);
+
+        let uniform = EntityUniform {
+            model: [[0.0; 4]; 4],
+            previous_model: [[0.0; 4]; 4],
+            normal: [[0.0; 4]; 3],
+            color: [1.0; 4],
+            tint: [0.25, 0.5, 0.75, 1.0],
+        };
+        let bytes = bytemuck::bytes_of(&uniform);
+        let tint: &[f32] = bytemuck::cast_slice(&bytes[192..208]);
+        assert_eq!(tint, &[0.25, 0.5, 0.75, 1.0]);
+    }
+}
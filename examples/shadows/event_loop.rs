@@ -1,57 +1,234 @@
 use std::sync::Arc;
 
-use winit::event::{ElementState, Event, WindowEvent};
+use winit::event::{ElementState, WindowEvent};
 use winit::event_loop::EventLoop;
-use winit::keyboard::KeyCode::{Digit1, Digit2, Escape, Space, KeyC};
+use winit::keyboard::KeyCode::{
+    Digit1, Digit2, Digit3, Digit4, Equal, KeyC, KeyF, KeyH, KeyL, KeyM, KeyN, KeyP, KeyR, KeyT, KeyV, Minus, Space,
+};
 use winit::keyboard::PhysicalKey;
 use winit::window::Window;
 
+use spark_gap::app::{App, AppControl, AppSettings};
 use spark_gap::frame_counter::FrameCounter;
 use spark_gap::gpu_context::GpuContext;
+use spark_gap::input::Input;
+use spark_gap::post::tonemap::TonemapOperator;
+#[cfg(feature = "ui")]
+use spark_gap::ui::{egui, EguiLayer};
 
-use crate::world::World;
+use crate::world::{CameraViewport, RenderPath, World};
 
-pub async fn run(event_loop: EventLoop<()>, window: Arc<Window>) {
-    let mut context = GpuContext::new(window).await;
-    let mut frame_counter = FrameCounter::new();
-
-    let mut world = World::new(&mut context);
-
-    event_loop
-        .run(move |event, target| {
-            if let Event::WindowEvent { window_id: _, event } = event {
-                match event {
-                    WindowEvent::Resized(new_size) => {
-                        context.resize(new_size);
-                        world.resize(&context);
-                        context.window.request_redraw();
-                    }
-                    WindowEvent::RedrawRequested => {
-                        frame_counter.update();
+struct ShadowsApp {
+    world: World,
+    frame_counter: FrameCounter,
+    #[cfg(feature = "ui")]
+    ui: EguiLayer,
+}
 
-                        world.render(&context);
+impl App for ShadowsApp {
+    fn resize(&mut self, context: &GpuContext) {
+        self.world.resize(context);
+    }
 
-                        context.window.request_redraw();
-                    }
-                    WindowEvent::KeyboardInput { event, .. } => {
-                        if event.state == ElementState::Pressed {
-                            match event.physical_key {
-                                PhysicalKey::Code(Escape) => target.exit(),
-                                PhysicalKey::Code(Space) => world.show_shadows = !world.show_shadows,
-                                PhysicalKey::Code(Digit1) => world.layer_number = 0,
-                                PhysicalKey::Code(Digit2) => world.layer_number = 1,
-                                PhysicalKey::Code(KeyC) => {
-                                    world.camera_position += 1;
-                                    if world.camera_position > 2 { world.camera_position = 0; }
-                                }
-                                _ => {}
-                            }
-                        }
+    fn window_event(&mut self, context: &mut GpuContext, input: &Input, event: &WindowEvent) -> AppControl {
+        #[cfg(feature = "ui")]
+        if self.ui.handle_window_event(event) {
+            return AppControl::Consumed;
+        }
+
+        let world = &mut self.world;
+        let WindowEvent::KeyboardInput { event, .. } = event else {
+            return AppControl::Continue;
+        };
+        if event.state != ElementState::Pressed {
+            return AppControl::Continue;
+        }
+
+        match event.physical_key {
+            PhysicalKey::Code(Space) => world.show_shadows = !world.show_shadows,
+            PhysicalKey::Code(Digit1) => world.layer_number = 0,
+            PhysicalKey::Code(Digit2) => world.layer_number = 1,
+            PhysicalKey::Code(Digit3) => world.layer_number = 2,
+            PhysicalKey::Code(Digit4) => world.layer_number = 3,
+            PhysicalKey::Code(KeyF) => world.culling_enabled = !world.culling_enabled,
+            PhysicalKey::Code(KeyH) => {
+                let enabled = !world.is_trails_enabled();
+                world.set_trails_enabled(context, enabled);
+            }
+            PhysicalKey::Code(KeyL) => world.lights.animation_enabled = !world.lights.animation_enabled,
+            PhysicalKey::Code(KeyT) => {
+                let settings = &mut world.tonemap_pass.settings;
+                settings.operator = match settings.operator {
+                    TonemapOperator::Reinhard => TonemapOperator::AcesFilmic,
+                    TonemapOperator::AcesFilmic => TonemapOperator::Passthrough,
+                    TonemapOperator::Passthrough => TonemapOperator::Reinhard,
+                };
+                log::info!("tonemap operator {:?}", settings.operator);
+            }
+            PhysicalKey::Code(Minus) => world.tonemap_pass.settings.exposure *= 0.5,
+            PhysicalKey::Code(Equal) => world.tonemap_pass.settings.exposure *= 2.0,
+            PhysicalKey::Code(KeyM) => {
+                world.material.metallic = 1.0 - world.material.metallic;
+                world.material.update(context);
+            }
+            PhysicalKey::Code(KeyN) => world.show_mip_levels = !world.show_mip_levels,
+            PhysicalKey::Code(KeyP) => {
+                if let Some(position) = input.mouse_position() {
+                    match world.pick_entity(context, position.x as u32, position.y as u32) {
+                        Ok(Some(index)) => log::info!("picked entity {}", index),
+                        Ok(None) => log::info!("picked the background"),
+                        Err(error) => log::warn!("picking failed: {:?}", error),
                     }
-                    WindowEvent::CloseRequested => target.exit(),
-                    _ => {}
+                }
+            }
+            PhysicalKey::Code(KeyR) => {
+                let render_path = match world.get_render_path() {
+                    RenderPath::Forward => RenderPath::Deferred,
+                    RenderPath::Deferred => RenderPath::Forward,
+                };
+                world.set_render_path(context, render_path);
+            }
+            PhysicalKey::Code(KeyV) => {
+                let viewports = match world.get_viewports().len() {
+                    1 => vec![
+                        CameraViewport {
+                            camera_position: 0,
+                            rect: [0.0, 0.0, 0.5, 1.0],
+                        },
+                        CameraViewport {
+                            camera_position: 1,
+                            rect: [0.5, 0.0, 0.5, 1.0],
+                        },
+                    ],
+                    _ => vec![],
                 };
+                world.set_viewports(context, viewports);
+            }
+            PhysicalKey::Code(KeyC) => {
+                world.camera_position += 1;
+                if world.camera_position > 2 {
+                    world.camera_position = 0;
+                }
             }
-        })
-        .unwrap();
+            _ => {}
+        }
+        AppControl::Continue
+    }
+
+    fn update(&mut self, context: &mut GpuContext, input: &Input, delta_time: f32) {
+        self.frame_counter.update();
+        self.world.lights.animate(context, delta_time);
+
+        // drag to orbit the camera, scroll to zoom
+        self.world.update_camera(context, input, delta_time);
+
+        #[cfg(feature = "ui")]
+        {
+            let world = &mut self.world;
+            self.ui.run(|ui_context| show_settings(ui_context, context, world));
+        }
+    }
+
+    fn render(&mut self, context: &GpuContext) {
+        #[cfg(feature = "ui")]
+        self.world.render_with_overlay(context, |context, encoder, view| {
+            self.ui.record_overlay_pass(context, encoder, view)
+        });
+        #[cfg(not(feature = "ui"))]
+        self.world.render(context);
+    }
+}
+
+// The keyboard toggles as widgets
+#[cfg(feature = "ui")]
+fn show_settings(ui_context: &egui::Context, context: &mut GpuContext, world: &mut World) {
+    egui::Window::new("settings").show(ui_context, |ui| {
+        ui.checkbox(&mut world.show_shadows, "show shadow maps");
+        ui.checkbox(&mut world.show_mip_levels, "show mip levels");
+        ui.add(egui::Slider::new(&mut world.layer_number, 0..=3).text("shadow map layer"));
+        egui::ComboBox::from_label("camera")
+            .selected_text(get_camera_name(world.camera_position))
+            .show_ui(ui, |ui| {
+                for camera_position in 0..3 {
+                    ui.selectable_value(&mut world.camera_position, camera_position, get_camera_name(camera_position));
+                }
+            });
+        ui.checkbox(&mut world.culling_enabled, "frustum culling");
+        ui.checkbox(&mut world.lights.animation_enabled, "animate lights");
+
+        let mut trails = world.is_trails_enabled();
+        ui.checkbox(&mut trails, "motion trails");
+        if trails != world.is_trails_enabled() {
+            world.set_trails_enabled(context, trails);
+        }
+
+        let mut render_path = world.get_render_path();
+        ui.horizontal(|ui| {
+            ui.radio_value(&mut render_path, RenderPath::Forward, "forward");
+            ui.radio_value(&mut render_path, RenderPath::Deferred, "deferred");
+        });
+        if render_path != world.get_render_path() {
+            world.set_render_path(context, render_path);
+        }
+
+        let mut metallic = world.material.metallic;
+        ui.add(egui::Slider::new(&mut metallic, 0.0..=1.0).text("metallic"));
+        if metallic != world.material.metallic {
+            world.material.metallic = metallic;
+            world.material.update(context);
+        }
+
+        let settings = &mut world.tonemap_pass.settings;
+        ui.add(
+            egui::Slider::new(&mut settings.exposure, 1.0 / 64.0..=64.0)
+                .logarithmic(true)
+                .text("exposure"),
+        );
+        egui::ComboBox::from_label("tonemap operator")
+            .selected_text(format!("{:?}", settings.operator))
+            .show_ui(ui, |ui| {
+                for operator in [TonemapOperator::Reinhard, TonemapOperator::AcesFilmic, TonemapOperator::Passthrough] {
+                    ui.selectable_value(&mut settings.operator, operator, format!("{:?}", operator));
+                }
+            });
+    });
+}
+
+#[cfg(feature = "ui")]
+fn get_camera_name(camera_position: u32) -> &'static str {
+    match camera_position {
+        0 => "camera",
+        1 => "light 1",
+        _ => "light 2",
+    }
+}
+
+pub async fn run(event_loop: EventLoop<()>, window: Arc<Window>) {
+    let mut context = match GpuContext::new(Arc::clone(&window)).await {
+        Ok(context) => context,
+        Err(error) => {
+            log::error!("{}", error);
+            return;
+        }
+    };
+
+    // with the serde feature a scene file can be given as the first argument
+    #[cfg(feature = "serde")]
+    let world = match std::env::args().nth(1) {
+        Some(path) => {
+            let scene = spark_gap::scene_file::load_scene(&path).expect("failed to load scene file");
+            World::from_scene(&mut context, &scene).expect("failed to create scene")
+        }
+        None => World::new(&mut context),
+    };
+    #[cfg(not(feature = "serde"))]
+    let world = World::new(&mut context);
+
+    let app = ShadowsApp {
+        world,
+        frame_counter: FrameCounter::new(),
+        #[cfg(feature = "ui")]
+        ui: EguiLayer::new(&context, window),
+    };
+    spark_gap::app::run(event_loop, context, app, &AppSettings::default()).unwrap();
 }
@@ -0,0 +1,88 @@
+//!include "spark_gap/pbr.wgsl"
+//!include "spark_gap/mip_debug.wgsl"
+//!include "shader.wgsl"
+
+// The forward pass, using the lighting bindings and functions of shader.wgsl
+
+// in the order of get_pbr_material_layout_builder
+@group(2) @binding(0) var<uniform> material: PbrMaterial;
+@group(2) @binding(1) var albedo_map: texture_2d<f32>;
+@group(2) @binding(2) var normal_map: texture_2d<f32>;
+@group(2) @binding(3) var metallic_roughness_map: texture_2d<f32>;
+@group(2) @binding(4) var material_sampler: sampler;
+
+// The cube has no uvs or tangents, so the maps are sampled at one point and the normal map is unused
+const MATERIAL_UV: vec2<f32> = vec2<f32>(0.5, 0.5);
+
+// The cube has no uvs either, so the mip debug view projects world positions onto the plane the
+// face is most aligned with, MIP_DEBUG_UV_SCALE texture repeats per world unit
+const MIP_DEBUG_UV_SCALE: f32 = 0.25;
+
+fn get_mip_debug_color(vertex: VertexOutput) -> vec4<f32> {
+    let position = vertex.world_position.xyz / vertex.world_position.w;
+    let axis = abs(vertex.world_normal);
+    var uv = position.xy;
+    if (axis.x >= axis.y && axis.x >= axis.z) {
+        uv = position.yz;
+    } else if (axis.y >= axis.z) {
+        uv = position.xz;
+    }
+    let level = get_mip_level(albedo_map, uv * MIP_DEBUG_UV_SCALE);
+    return vec4<f32>(get_mip_level_color(level, textureNumLevels(albedo_map)), 1.0);
+}
+
+fn shade(vertex: VertexOutput) -> vec4<f32> {
+#ifdef MIP_DEBUG
+    return get_mip_debug_color(vertex);
+#else
+    return shade_lights_pbr(vertex);
+#endif
+}
+
+fn shade_lights_pbr(vertex: VertexOutput) -> vec4<f32> {
+    let normal = normalize(vertex.world_normal);
+    let world_position = vertex.world_position;
+    let view_dir = normalize(eye_position.xyz - world_position.xyz * eye_position.w);
+
+    let albedo_sample = textureSample(albedo_map, material_sampler, MATERIAL_UV) * entity_data.color * entity_data.tint;
+    let metallic_roughness_sample = textureSample(metallic_roughness_map, material_sampler, MATERIAL_UV);
+    let surface = get_pbr_surface(material, albedo_sample, metallic_roughness_sample);
+
+    var color = get_ambient(normal) * surface.albedo;
+
+    for (var i = 0u; i < min(num_lights, MAX_LIGHTS); i += 1u) {
+        let light = lights_uniform[i];
+        let light_dir = get_light_dir(light, world_position);
+        let shadow = get_light_shadow(light, world_position, normal, light_dir);
+
+        // light colors are what a white diffuse surface facing the light reflects, as in shade_lights
+        let radiance = shadow * light.color.rgb * PI;
+        color += cook_torrance(surface, normal, view_dir, light_dir, radiance);
+    }
+
+    return vec4<f32>(color, albedo_sample.a * material.base_color.a);
+}
+
+@fragment fn fs_main(vertex: VertexOutput) -> @location(0) vec4<f32> {
+    return shade(vertex);
+}
+
+struct MotionOutput {
+    @location(0) color: vec4<f32>,
+    @location(1) motion: vec2<f32>,
+};
+
+// screen space velocity in uv units, pointing from the previous position to the current one.
+// get_motion_vector in forward_pass.rs is the cpu version.
+fn motion_vector(current_clip: vec4<f32>, previous_clip: vec4<f32>) -> vec2<f32> {
+    let current = current_clip.xy / current_clip.w;
+    let previous = previous_clip.xy / previous_clip.w;
+    return (current - previous) * vec2<f32>(0.5, -0.5);
+}
+
+@fragment fn fs_main_motion(vertex: VertexOutput) -> MotionOutput {
+    var result: MotionOutput;
+    result.color = shade(vertex);
+    result.motion = motion_vector(vertex.current_clip, vertex.previous_clip);
+    return result;
+}
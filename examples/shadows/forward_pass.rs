@@ -1,93 +1,150 @@
 use std::mem;
 
-use glam::Mat4;
+use glam::{vec2, vec4, Mat4, Vec2, Vec4};
 use wgpu::util::DeviceExt;
-use wgpu::{BindGroup, BindGroupLayout, Buffer, RenderPipeline, ShaderModule, Texture};
+use wgpu::{BindGroup, BindGroupLayout, Buffer, RenderPipeline, Sampler, ShaderModule, Texture, TextureView};
 
+use spark_gap::bind_group::{create_pipeline_layout, BindGroupBuilder, LayoutBuilder};
+use spark_gap::buffers::create_uniform_buffer_init;
 use spark_gap::gpu_context::GpuContext;
+use spark_gap::point_shadow::PointShadowUniform;
+use spark_gap::post::tonemap::HDR_FORMAT;
+use spark_gap::shader_preprocessor::MIP_DEBUG_DEFINE;
+use spark_gap::shadow_cascades::CascadeUniform;
+use spark_gap::texture::SamplerBuilder;
 
-use crate::lights::{LightUniform, Lights, MAX_LIGHTS};
-use crate::world::{get_projection_view_matrix, get_vertex_buffer_layout};
+use crate::cube::Vertex;
+use crate::lights::{AmbientUniform, LightUniform, Lights, SceneLighting, ShadowLayerUniform, MAX_LIGHTS, MAX_SHADOW_LAYERS};
+use crate::world::{get_forward_shader_source, get_projection_view_matrix};
 
 pub const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
+pub const MOTION_VECTOR_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rg16Float;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ForwardPassOptions {
+    // write screen space motion vectors to a second Rg16Float target
+    pub motion_vectors: bool,
+}
 
 pub struct ForwardPass {
     pub pipeline: RenderPipeline,
+    // the same targets, colored by the albedo mip level
+    pub mip_debug_pipeline: RenderPipeline,
+    // lights, shadows and camera, also used by the deferred passes
+    pub bind_group_layout: BindGroupLayout,
+    pub bind_group: BindGroup,
+    pub projection_view_buffer: Buffer,
+    pub previous_projection_view_buffer: Buffer,
+    pub eye_position_buffer: Buffer,
+    pub ambient_buffer: Buffer,
+    pub options: ForwardPassOptions,
+    shared: SharedBindings,
+}
+
+// Group 0 of an additional camera, the lights and shadow maps are shared with the forward pass
+pub struct CameraBindGroup {
     pub bind_group: BindGroup,
     pub projection_view_buffer: Buffer,
+    pub previous_projection_view_buffer: Buffer,
+    pub eye_position_buffer: Buffer,
+    pub previous_projection_view: Mat4,
+}
+
+// The group 0 resources that are the same for every camera
+struct SharedBindings {
+    num_lights_buffer: Buffer,
+    shadow_view: TextureView,
+    shadow_sampler: Sampler,
+}
+
+impl ForwardPass {
+    // Each camera needs its own uniforms since they are all written before the frame is submitted
+    pub fn create_camera_bind_group(&self, context: &GpuContext, lights: &Lights) -> CameraBindGroup {
+        let previous_projection_view = get_projection_view_matrix(context.config.width as f32 / context.config.height as f32);
+        let (projection_view_buffer, previous_projection_view_buffer) = create_camera_buffers(context, &previous_projection_view);
+        let eye_position_buffer = create_eye_position_buffer(context, &previous_projection_view);
+
+        let bind_group = create_forward_bind_group(
+            context,
+            &self.bind_group_layout,
+            lights,
+            &self.shared,
+            &self.ambient_buffer,
+            &projection_view_buffer,
+            &previous_projection_view_buffer,
+            &eye_position_buffer,
+        );
+
+        CameraBindGroup {
+            bind_group,
+            projection_view_buffer,
+            previous_projection_view_buffer,
+            eye_position_buffer,
+            previous_projection_view,
+        }
+    }
 }
 
 pub fn create_forward_pass(
     context: &mut GpuContext,
     entity_bind_group_layout: &BindGroupLayout,
+    material_bind_group_layout: &BindGroupLayout,
     lights: &Lights,
     shader: &ShaderModule,
-    shadow_texture_array: &Texture,
+    shadow_atlas: &Texture,
+    scene_lighting: &SceneLighting,
+    options: ForwardPassOptions,
 ) -> ForwardPass {
     let light_uniform_size = (MAX_LIGHTS * mem::size_of::<LightUniform>()) as wgpu::BufferAddress;
+    let shadow_layer_size = (MAX_SHADOW_LAYERS * mem::size_of::<ShadowLayerUniform>()) as wgpu::BufferAddress;
 
-    let bind_group_layout = context.device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-        entries: &[
-            // lights
-            wgpu::BindGroupLayoutEntry {
-                binding: 0,
-                visibility: wgpu::ShaderStages::FRAGMENT,
-                ty: wgpu::BindingType::Buffer {
-                    ty: wgpu::BufferBindingType::Uniform,
-                    has_dynamic_offset: false,
-                    min_binding_size: wgpu::BufferSize::new(light_uniform_size),
-                },
-                count: None,
-            },
-            // number of lights
-            wgpu::BindGroupLayoutEntry {
-                binding: 1,
-                visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
-                ty: wgpu::BindingType::Buffer {
-                    ty: wgpu::BufferBindingType::Uniform,
-                    has_dynamic_offset: false,
-                    min_binding_size: wgpu::BufferSize::new(mem::size_of::<u32>() as _),
-                },
-                count: None,
-            },
-            // projection_view
-            wgpu::BindGroupLayoutEntry {
-                binding: 2,
-                visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
-                ty: wgpu::BindingType::Buffer {
-                    ty: wgpu::BufferBindingType::Uniform,
-                    has_dynamic_offset: false,
-                    min_binding_size: wgpu::BufferSize::new(mem::size_of::<Mat4>() as _),
-                },
-                count: None,
-            },
-            // shadow texture
-            wgpu::BindGroupLayoutEntry {
-                binding: 3,
-                visibility: wgpu::ShaderStages::FRAGMENT,
-                ty: wgpu::BindingType::Texture {
-                    multisampled: false,
-                    sample_type: wgpu::TextureSampleType::Depth,
-                    view_dimension: wgpu::TextureViewDimension::D2Array,
-                },
-                count: None,
-            },
-            // shadow sampler
-            wgpu::BindGroupLayoutEntry {
-                binding: 4,
-                visibility: wgpu::ShaderStages::FRAGMENT,
-                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Comparison),
-                count: None,
-            },
-        ],
-        label: None,
-    });
+    let vertex_fragment = wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT;
+    let mat4_size = mem::size_of::<Mat4>() as u64;
+
+    // bindings in the order of shader.wgsl group 0
+    let bind_group_layout = LayoutBuilder::new()
+        // shadow layers, at binding 0 like the shadow pass
+        .sized_uniform(wgpu::ShaderStages::FRAGMENT, shadow_layer_size)
+        // number of lights
+        .sized_uniform(vertex_fragment, mem::size_of::<u32>() as u64)
+        // projection_view
+        .sized_uniform(vertex_fragment, mat4_size)
+        // shadow atlas
+        .texture_depth(wgpu::ShaderStages::FRAGMENT)
+        .sampler_comparison(wgpu::ShaderStages::FRAGMENT)
+        // previous projection_view
+        .sized_uniform(wgpu::ShaderStages::VERTEX, mat4_size)
+        // ambient
+        .sized_uniform(wgpu::ShaderStages::FRAGMENT, mem::size_of::<AmbientUniform>() as u64)
+        // lights
+        .sized_uniform(wgpu::ShaderStages::FRAGMENT, light_uniform_size)
+        // eye position
+        .sized_uniform(wgpu::ShaderStages::FRAGMENT, mem::size_of::<Vec4>() as u64)
+        // point shadow cube and its light
+        .texture(
+            wgpu::ShaderStages::FRAGMENT,
+            wgpu::TextureSampleType::Depth,
+            wgpu::TextureViewDimension::Cube,
+        )
+        .sized_uniform(wgpu::ShaderStages::FRAGMENT, mem::size_of::<PointShadowUniform>() as u64)
+        // directional light cascades and their uniform
+        .texture(
+            wgpu::ShaderStages::FRAGMENT,
+            wgpu::TextureSampleType::Depth,
+            wgpu::TextureViewDimension::D2Array,
+        )
+        .sized_uniform(wgpu::ShaderStages::FRAGMENT, mem::size_of::<CascadeUniform>() as u64)
+        .build(context, "forward")
+        .expect("invalid forward bind group layout");
 
     let project_view_matrix = get_projection_view_matrix(context.config.width as f32 / context.config.height as f32);
 
-    let projection_view_buffer = context.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-        label: Some("projection_view buffer"),
-        contents: bytemuck::cast_slice(&project_view_matrix.to_cols_array()),
+    let (projection_view_buffer, previous_projection_view_buffer) = create_camera_buffers(context, &project_view_matrix);
+    let eye_position_buffer = create_eye_position_buffer(context, &project_view_matrix);
+
+    let ambient_buffer = context.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("ambient buffer"),
+        contents: bytemuck::bytes_of(&scene_lighting.get_ambient_uniform()),
         usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
     });
 
@@ -99,66 +156,87 @@ pub fn create_forward_pass(
         usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
     });
 
-    let shadow_view = shadow_texture_array.create_view(&wgpu::TextureViewDescriptor::default());
-
-    let shadow_sampler = context.device.create_sampler(&wgpu::SamplerDescriptor {
-        label: Some("shadow"),
-        address_mode_u: wgpu::AddressMode::ClampToBorder,
-        address_mode_v: wgpu::AddressMode::ClampToBorder,
-        address_mode_w: wgpu::AddressMode::ClampToBorder,
-        mag_filter: wgpu::FilterMode::Linear,
-        min_filter: wgpu::FilterMode::Linear,
-        mipmap_filter: wgpu::FilterMode::Nearest,
-        compare: Some(wgpu::CompareFunction::LessEqual),
-        ..Default::default()
-    });
+    let shadow_view = shadow_atlas.create_view(&wgpu::TextureViewDescriptor::default());
 
-    let bind_group = context.device.create_bind_group(&wgpu::BindGroupDescriptor {
-        layout: &bind_group_layout,
-        entries: &[
-            wgpu::BindGroupEntry {
-                binding: 0,
-                resource: lights.light_storage_buffer.as_entire_binding(),
-            },
-            wgpu::BindGroupEntry {
-                binding: 1,
-                resource: num_lights_buffer.as_entire_binding(),
-            },
-            wgpu::BindGroupEntry {
-                binding: 2,
-                resource: projection_view_buffer.as_entire_binding(),
-            },
-            wgpu::BindGroupEntry {
-                binding: 3,
-                resource: wgpu::BindingResource::TextureView(&shadow_view),
-            },
-            wgpu::BindGroupEntry {
-                binding: 4,
-                resource: wgpu::BindingResource::Sampler(&shadow_sampler),
-            },
+    let shadow_sampler = SamplerBuilder::new()
+        .label("shadow")
+        .address_mode(wgpu::AddressMode::ClampToBorder)
+        .comparison(wgpu::CompareFunction::LessEqual)
+        .build(context);
+
+    let shared = SharedBindings {
+        num_lights_buffer,
+        shadow_view,
+        shadow_sampler,
+    };
+
+    let bind_group = create_forward_bind_group(
+        context,
+        &bind_group_layout,
+        lights,
+        &shared,
+        &ambient_buffer,
+        &projection_view_buffer,
+        &previous_projection_view_buffer,
+        &eye_position_buffer,
+    );
+
+    let pipeline_layout = create_pipeline_layout(
+        context,
+        "main",
+        &[
+            (0, &bind_group_layout),
+            (1, entity_bind_group_layout),
+            (2, material_bind_group_layout),
         ],
-        label: None,
-    });
+        &[],
+    )
+    .expect("invalid forward pipeline layout");
 
-    let pipeline_layout = context.device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-        label: Some("main"),
-        bind_group_layouts: &[&bind_group_layout, &entity_bind_group_layout],
-        push_constant_ranges: &[],
-    });
+    let pipeline = create_forward_pipeline(context, &pipeline_layout, shader, "forward pipeline", options);
+
+    let mip_debug_shader = context.create_shader_module("forward mip debug", &get_forward_shader_source(&[MIP_DEBUG_DEFINE]));
+    let mip_debug_pipeline = create_forward_pipeline(context, &pipeline_layout, &mip_debug_shader, "forward mip debug pipeline", options);
+
+    ForwardPass {
+        pipeline,
+        mip_debug_pipeline,
+        bind_group_layout,
+        bind_group,
+        projection_view_buffer,
+        previous_projection_view_buffer,
+        eye_position_buffer,
+        ambient_buffer,
+        options,
+        shared,
+    }
+}
+
+fn create_forward_pipeline(
+    context: &GpuContext,
+    pipeline_layout: &wgpu::PipelineLayout,
+    shader: &ShaderModule,
+    label: &str,
+    options: ForwardPassOptions,
+) -> RenderPipeline {
+    let (fragment_entry_point, targets) = if options.motion_vectors {
+        ("fs_main_motion", vec![Some(HDR_FORMAT.into()), Some(MOTION_VECTOR_FORMAT.into())])
+    } else {
+        ("fs_main", vec![Some(HDR_FORMAT.into())])
+    };
 
-    // Create the render pipeline
-    let pipeline = context.device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-        label: Some("forward pipeline"),
-        layout: Some(&pipeline_layout),
+    context.device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some(label),
+        layout: Some(pipeline_layout),
         vertex: wgpu::VertexState {
             module: shader,
             entry_point: "vs_main",
-            buffers: &[get_vertex_buffer_layout()],
+            buffers: &[Vertex::vertex_buffer_layout()],
         },
         fragment: Some(wgpu::FragmentState {
             module: shader,
-            entry_point: "fs_main",
-            targets: &[Some(context.config.view_formats[0].into())],
+            entry_point: fragment_entry_point,
+            targets: &targets,
         }),
         primitive: wgpu::PrimitiveState {
             front_face: wgpu::FrontFace::Ccw,
@@ -174,11 +252,174 @@ pub fn create_forward_pass(
         }),
         multisample: wgpu::MultisampleState::default(),
         multiview: None,
+    })
+}
+
+fn create_camera_buffers(context: &GpuContext, projection_view: &Mat4) -> (Buffer, Buffer) {
+    let projection_view_buffer = context.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("projection_view buffer"),
+        contents: bytemuck::cast_slice(&projection_view.to_cols_array()),
+        usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
     });
 
-    ForwardPass {
-        pipeline,
-        bind_group,
-        projection_view_buffer,
+    let previous_projection_view_buffer = context.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("previous projection_view buffer"),
+        contents: bytemuck::cast_slice(&projection_view.to_cols_array()),
+        usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+    });
+
+    (projection_view_buffer, previous_projection_view_buffer)
+}
+
+fn create_eye_position_buffer(context: &GpuContext, projection_view: &Mat4) -> Buffer {
+    create_uniform_buffer_init(context, &[get_eye_position(projection_view)], "eye position buffer")
+}
+
+fn create_forward_bind_group(
+    context: &GpuContext,
+    layout: &BindGroupLayout,
+    lights: &Lights,
+    shared: &SharedBindings,
+    ambient_buffer: &Buffer,
+    projection_view_buffer: &Buffer,
+    previous_projection_view_buffer: &Buffer,
+    eye_position_buffer: &Buffer,
+) -> BindGroup {
+    BindGroupBuilder::new()
+        .buffer(&lights.shadow_layer_buffer)
+        .buffer(&shared.num_lights_buffer)
+        .buffer(projection_view_buffer)
+        .texture_view(&shared.shadow_view)
+        .sampler(&shared.shadow_sampler)
+        .buffer(previous_projection_view_buffer)
+        .buffer(ambient_buffer)
+        .buffer(&lights.light_storage_buffer)
+        .buffer(eye_position_buffer)
+        .texture_view(&lights.point_shadow.shadow_maps.view)
+        .resource(lights.point_shadow.uniform.as_entire_binding())
+        .texture_view(&lights.cascades.shadow_maps.view)
+        .resource(lights.cascades.uniform.as_entire_binding())
+        .build(context, layout, "forward")
+}
+
+// The eye of a projection_view in homogeneous coordinates, the point every view ray passes through.
+// For orthographic projections w is 0 and xyz the direction towards the eye.
+pub fn get_eye_position(projection_view: &Mat4) -> Vec4 {
+    let eye = projection_view.inverse() * vec4(0.0, 0.0, 1.0, 0.0);
+    match eye.w.abs() > 1e-6 {
+        true => eye / eye.w,
+        false => (-eye.truncate().normalize()).extend(0.0),
+    }
+}
+
+// Cpu version of motion_vector in forward.wgsl, velocity in uv units from the previous to the
+// current position
+pub fn get_motion_vector(current_clip: Vec4, previous_clip: Vec4) -> Vec2 {
+    let current = current_clip.truncate().truncate() / current_clip.w;
+    let previous = previous_clip.truncate().truncate() / previous_clip.w;
+    (current - previous) * vec2(0.5, -0.5)
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{vec2, vec3, vec4, Mat4, Vec2, Vec3, Vec4};
+
+    use spark_gap::gpu_context::GpuContext;
+    #[cfg(feature = "reflection")]
+    use spark_gap::shader::check_wgsl;
+    #[cfg(feature = "reflection")]
+    use spark_gap::shader_preprocessor::MIP_DEBUG_DEFINE;
+    use spark_gap::shader_test::ShaderTest;
+
+    use crate::forward_pass::{get_eye_position, get_motion_vector};
+    use crate::world::{get_forward_shader_source, get_projection_view_matrix};
+
+    // motion_vector for pairs of current and previous clip positions, the forward shader's
+    // bindings take groups 0 to 2
+    const MOTION_VECTOR_WGSL: &str = r"
+@compute @workgroup_size(1) fn get_motion_vectors(@builtin(global_invocation_id) id: vec3<u32>) {
+    let motion = motion_vector(test_inputs[id.x * 2u], test_inputs[id.x * 2u + 1u]);
+    test_outputs[id.x] = vec4<f32>(motion, 0.0, 0.0);
+}
+";
+
+    // current and previous clip positions
+    fn get_motion_cases() -> Vec<(Vec4, Vec4)> {
+        let projection_view = get_projection_view_matrix(1.0);
+        let model = Mat4::from_translation(vec3(1.0, 2.0, 0.5));
+        let previous_model = Mat4::from_translation(vec3(0.0, 2.0, 0.5));
+        let position = vec4(1.0, -1.0, 1.0, 1.0);
+        let clip = projection_view * model * position;
+        vec![
+            (clip, projection_view * model * position),
+            (clip, projection_view * previous_model * position),
+            (vec4(0.5, 0.5, 0.2, 1.0), vec4(0.0, 1.0, 0.4, 2.0)),
+            (vec4(0.0, 0.5, 0.0, 1.0), vec4(0.0, 0.0, 0.0, 1.0)),
+        ]
+    }
+
+    #[test]
+    fn test_motion_vector() {
+        let motions: Vec<Vec2> = get_motion_cases()
+            .into_iter()
+            .map(|(current, previous)| get_motion_vector(current, previous))
+            .collect();
+
+        // static object and static camera
+        assert!(motions[0].length() < 1e-6);
+
+        // the object moved since the previous frame
+        assert!(motions[1].length() > 1e-3);
+
+        // in uv units after the perspective divide, v pointing down
+        assert!(motions[2].abs_diff_eq(vec2(0.25, 0.0), 1e-6), "{}", motions[2]);
+        assert!(motions[3].abs_diff_eq(vec2(0.0, -0.25), 1e-6), "{}", motions[3]);
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "gpu_tests"), ignore = "needs a gpu adapter")]
+    fn test_motion_vector_shader() {
+        let context = pollster::block_on(GpuContext::new_headless(4, 4)).unwrap();
+        let cases = get_motion_cases();
+        let inputs: Vec<[f32; 4]> = cases
+            .iter()
+            .flat_map(|(current, previous)| [current.to_array(), previous.to_array()])
+            .collect();
+
+        let source = get_forward_shader_source(&[]);
+        let outputs = ShaderTest::new(&source, MOTION_VECTOR_WGSL)
+            .group(3)
+            .run(&context, "get_motion_vectors", &inputs, cases.len() as u32, cases.len())
+            .unwrap();
+
+        for ((current, previous), output) in cases.iter().zip(outputs) {
+            let (motion, expected) = (vec2(output[0], output[1]), get_motion_vector(*current, *previous));
+            assert!(motion.abs_diff_eq(expected, 1e-6), "{} instead of {}", motion, expected);
+        }
+    }
+
+    #[test]
+    fn test_eye_position() {
+        let eye = vec3(3.0, -20.0, 6.0);
+        let view = Mat4::look_at_rh(eye, Vec3::ZERO, Vec3::Z);
+
+        let perspective = Mat4::perspective_rh(0.8, 1.5, 1.0, 200.0);
+        let position = get_eye_position(&(perspective * view));
+        assert!(position.abs_diff_eq(eye.extend(1.0), 1e-3), "{}", position);
+
+        // orthographic views look along a direction, the eye is at infinity behind the view
+        let orthographic = Mat4::orthographic_rh(-10.0, 10.0, -10.0, 10.0, 0.1, 100.0);
+        let direction = get_eye_position(&(orthographic * view));
+        assert!(direction.abs_diff_eq(eye.normalize().extend(0.0), 1e-4), "{}", direction);
+    }
+
+    #[test]
+    #[cfg(feature = "reflection")]
+    fn test_mip_debug_shader() {
+        let source = get_forward_shader_source(&[MIP_DEBUG_DEFINE]);
+        assert!(source.contains("return get_mip_debug_color(vertex);"));
+        assert!(!source.contains("return shade_lights_pbr(vertex);"));
+        check_wgsl(&source).unwrap();
+        check_wgsl(&get_forward_shader_source(&[])).unwrap();
     }
 }
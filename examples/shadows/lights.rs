@@ -1,41 +1,151 @@
 use std::f32::consts;
-use std::mem;
 use std::ops::Range;
+use std::{iter, mem};
 
 use bytemuck::{Pod, Zeroable};
-use glam::Mat4;
-use wgpu::{Buffer, Texture, TextureView};
+use glam::{vec3, Mat4, Vec3};
+use wgpu::Buffer;
 
+use spark_gap::error::Error;
+#[cfg(feature = "serde")]
+use spark_gap::error::Error::SceneError;
+use spark_gap::error::Error::ValidationError;
 use spark_gap::gpu_context::GpuContext;
+use spark_gap::point_shadow::PointShadow;
+#[cfg(feature = "serde")]
+use spark_gap::scene_file::{LightType, SceneFile};
+use spark_gap::shadow_atlas::{get_priority_resolution, pack_shadow_atlas, AtlasRect};
+use spark_gap::shadow_cascades::{CascadeCamera, CascadeSettings, CascadedShadowMap, MAX_SHADOW_CASCADES};
+
+use crate::world::get_camera;
 
 pub const MAX_LIGHTS: usize = 10;
 
+// Shadow maps of the spot lights in the atlas, one per light
+pub const MAX_SHADOW_LAYERS: usize = 16;
+pub const MAX_CASCADES: u32 = MAX_SHADOW_CASCADES as u32;
+
+// Directional cascades cover the camera frustum up to this distance
+pub const MAX_SHADOW_DISTANCE: f32 = 60.0;
+// between uniform (0.0) and logarithmic (1.0) cascade splits
+pub const CASCADE_SPLIT_LAMBDA: f32 = 0.5;
+// The directional light's cascades, at most one directional light is supported
+pub const CASCADE_RESOLUTION: u32 = 2048;
+
+// All shadow maps are packed into one depth texture of this size
+pub const SHADOW_ATLAS_SIZE: u32 = 4096;
+
+// The point light's shadow cube, at most one point light is supported
+pub const POINT_SHADOW_RESOLUTION: u32 = 1024;
+pub const POINT_SHADOW_DEPTH: Range<f32> = 0.1..100.0;
+
+pub const DEFAULT_AMBIENT_COLOR: wgpu::Color = wgpu::Color {
+    r: 0.05,
+    g: 0.05,
+    b: 0.05,
+    a: 1.0,
+};
+
+// Fallback lighting so surfaces in shadow aren't fully black. To be replaced by ibl when available.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Ambient {
+    Flat(wgpu::Color),
+    // blended by the normal from ground (facing -z) to sky (facing +z)
+    Hemispheric { sky: wgpu::Color, ground: wgpu::Color },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SceneLighting {
+    pub ambient: Ambient,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Pod, Zeroable)]
+pub struct AmbientUniform {
+    pub sky: [f32; 4],
+    pub ground: [f32; 4],
+}
+
 pub struct Lights {
     pub lights: Vec<Light>,
     pub light_storage_buffer: Buffer,
+    // the combined shadow maps of the lights, each light owns the range in Light::shadow_layers
+    pub shadow_layers: Vec<ShadowLayer>,
+    pub shadow_layer_buffer: Buffer,
+    // the shadow of the point light, a single texel cube when there is none
+    pub point_shadow: PointShadow,
+    // the cascades of the directional light, a single texel layer when there is none
+    pub cascades: CascadedShadowMap,
+    // the cascades of directional lights split the frustum of this camera
+    pub camera: CascadeCamera,
     pub lights_are_dirty: bool,
+    // lights with an animation only move while this is set
+    pub animation_enabled: bool,
+    pub animation_time: f32,
+}
+
+// Moves a light over time so demos can show moving shadows
+#[derive(Debug, Clone, PartialEq)]
+pub enum LightAnimation {
+    // circles the z axis through center, speed in radians per second
+    Orbit { center: Vec3, radius: f32, speed: f32, phase: f32 },
+    // loops through the points and back to the first, speed in units per second
+    Path { points: Vec<Vec3>, speed: f32 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LightKind {
+    // perspective shadow map aimed at the origin
+    Spot,
+    // lit from the direction of the light's position, shadowed by the cascades of Lights::cascades
+    // instead of atlas layers
+    Directional { cascade_count: u32 },
+    // shadowed in every direction by Lights::point_shadow instead of atlas layers
+    Point,
 }
 
 pub struct Light {
+    // the direction towards the light for directional lights
     pub position: glam::Vec3,
     pub color: wgpu::Color,
+    pub kind: LightKind,
     pub fov: f32,
     pub depth: Range<f32>,
+    // 0 is the most important, lower priorities get smaller shadow maps
+    pub shadow_priority: u32,
+    // the light's layer in Lights::shadow_layers, empty for directional and point lights
+    pub shadow_layers: Range<u32>,
+    pub animation: Option<LightAnimation>,
+}
+
+// One shadow map in the atlas and the projection that renders it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShadowLayer {
     pub projection_view: Mat4,
-    pub shadow_view: TextureView,
+    pub atlas_rect: AtlasRect,
 }
 
 #[repr(C)]
 #[derive(Debug, Clone, Copy, Pod, Zeroable)]
 pub struct LightUniform {
-    projection: [[f32; 4]; 4],
+    // w is 0 for a directional light, whose xyz is the direction towards the light
     position: [f32; 4],
     color: [f32; 4],
+    // first shadow layer in x and the layer count in y, z is the ShadowSource
+    shadow_layers: [u32; 4],
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+pub struct ShadowLayerUniform {
+    projection: [[f32; 4]; 4],
+    // shadow map location in the atlas, uv offset in xy and scale in zw
+    atlas_rect: [f32; 4],
 }
 
 impl Lights {
-    pub fn new(gpu_context: &mut GpuContext, shadow_texture_array: &Texture) -> Self {
-        let lights = vec![
+    pub fn new(gpu_context: &mut GpuContext) -> Self {
+        let mut lights = vec![
             Light {
                 position: glam::Vec3::new(7.0, -5.0, 10.0),
                 // position: glam::Vec3::new(7.0, 10.0, -5.0),
@@ -45,10 +155,12 @@ impl Lights {
                     b: 0.5,
                     a: 1.0,
                 },
+                kind: LightKind::Spot,
                 fov: 60.0,
                 depth: 1.0..1000.0,
-                projection_view: Mat4::IDENTITY,
-                shadow_view: create_shadow_texture_view(shadow_texture_array, 0),
+                shadow_priority: 0,
+                shadow_layers: 0..0,
+                animation: Some(LightAnimation::orbit_from(glam::Vec3::new(7.0, -5.0, 10.0), 0.5)),
             },
             Light {
                 position: glam::Vec3::new(-10.0, 7.0, 10.0),
@@ -59,47 +171,390 @@ impl Lights {
                     b: 0.5,
                     a: 1.0,
                 },
+                kind: LightKind::Spot,
                 fov: 45.0,
                 depth: 1.0..1000.0,
-                projection_view: Mat4::IDENTITY,
-                shadow_view: create_shadow_texture_view(shadow_texture_array, 1),
+                shadow_priority: 1,
+                shadow_layers: 0..0,
+                animation: None,
             },
         ];
 
+        let shadow_layers = assign_shadow_layers(&mut lights).expect("the default lights fit the shadow atlas");
+
         let light_storage_buffer = create_light_storage_buffer(gpu_context);
+        let shadow_layer_buffer = create_shadow_layer_buffer(gpu_context);
+        let point_shadow = create_point_shadow(gpu_context, &lights);
+        let cascades = create_cascaded_shadow_map(gpu_context, &lights);
 
         Lights {
             lights,
             light_storage_buffer,
+            shadow_layers,
+            shadow_layer_buffer,
+            point_shadow,
+            cascades,
+            camera: get_camera(gpu_context.config.width as f32 / gpu_context.config.height as f32),
             lights_are_dirty: true,
+            animation_enabled: false,
+            animation_time: 0.0,
         }
     }
 
+    // Spot lights are aimed at the origin, directional lights shine from their position towards it
+    #[cfg(feature = "serde")]
+    pub fn from_scene(gpu_context: &mut GpuContext, scene: &SceneFile) -> Result<Self, Error> {
+        if scene.lights.len() > MAX_LIGHTS {
+            return Err(SceneError(format!(
+                "scene has {} lights, at most {} are supported",
+                scene.lights.len(),
+                MAX_LIGHTS
+            )));
+        }
+
+        let mut lights = scene
+            .lights
+            .iter()
+            .enumerate()
+            .map(|(i, desc)| {
+                let kind = match desc.light_type {
+                    LightType::Spot => LightKind::Spot,
+                    LightType::Directional if (1..=MAX_CASCADES).contains(&desc.cascade_count) => LightKind::Directional {
+                        cascade_count: desc.cascade_count,
+                    },
+                    LightType::Directional => {
+                        return Err(SceneError(format!(
+                            "directional lights have 1 to {} cascades, not {}",
+                            MAX_CASCADES, desc.cascade_count
+                        )))
+                    }
+                    LightType::Point => LightKind::Point,
+                };
+                let radiance = desc.get_radiance();
+                Ok(Light {
+                    position: desc.get_position(),
+                    color: wgpu::Color {
+                        r: radiance.x as f64,
+                        g: radiance.y as f64,
+                        b: radiance.z as f64,
+                        a: 1.0,
+                    },
+                    kind,
+                    fov: desc.spot_angle,
+                    depth: 1.0..1000.0,
+                    // in scene order
+                    shadow_priority: i as u32,
+                    shadow_layers: 0..0,
+                    animation: None,
+                })
+            })
+            .collect::<Result<Vec<Light>, Error>>()?;
+
+        let point_light_count = lights.iter().filter(|light| light.kind == LightKind::Point).count();
+        if point_light_count > 1 {
+            return Err(SceneError(format!(
+                "scene has {} point lights, at most one is supported",
+                point_light_count
+            )));
+        }
+        let directional_light_count = lights.iter().filter(|light| light.get_cascade_count() > 0).count();
+        if directional_light_count > 1 {
+            return Err(SceneError(format!(
+                "scene has {} directional lights, at most one is supported",
+                directional_light_count
+            )));
+        }
+
+        let shadow_layers = assign_shadow_layers(&mut lights)?;
+
+        let light_storage_buffer = create_light_storage_buffer(gpu_context);
+        let shadow_layer_buffer = create_shadow_layer_buffer(gpu_context);
+        let point_shadow = create_point_shadow(gpu_context, &lights);
+        let cascades = create_cascaded_shadow_map(gpu_context, &lights);
+
+        Ok(Lights {
+            lights,
+            light_storage_buffer,
+            shadow_layers,
+            shadow_layer_buffer,
+            point_shadow,
+            cascades,
+            camera: get_camera(gpu_context.config.width as f32 / gpu_context.config.height as f32),
+            lights_are_dirty: true,
+            animation_enabled: false,
+            animation_time: 0.0,
+        })
+    }
+
     pub fn update(&mut self, context: &GpuContext) {
         if self.lights_are_dirty {
             self.lights_are_dirty = false;
+            self.upload_matrices(context);
+        }
+    }
 
-            for (i, light) in self.lights.iter_mut().enumerate() {
-                let view = Mat4::look_at_rh(light.position, glam::Vec3::ZERO, glam::Vec3::Z);
-                let projection = Mat4::perspective_rh(light.fov * consts::PI / 180., 1.0, light.depth.start, light.depth.end);
-                light.projection_view = projection * view;
+    // Advances the animated lights by dt seconds and uploads their new matrices
+    pub fn animate(&mut self, context: &GpuContext, dt: f32) {
+        if self.advance_animations(dt) {
+            self.lights_are_dirty = false;
+            self.upload_matrices(context);
+        }
+    }
 
-                let light_uniform = LightUniform {
-                    projection: light.projection_view.to_cols_array_2d(),
-                    position: [light.position.x, light.position.y, light.position.z, 1.0],
-                    color: [light.color.r as f32, light.color.g as f32, light.color.b as f32, 1.0],
-                };
+    // Returns whether any light moved
+    pub fn advance_animations(&mut self, dt: f32) -> bool {
+        if !self.animation_enabled {
+            return false;
+        }
+        self.animation_time += dt;
+
+        let mut moved = false;
+        for light in self.lights.iter_mut() {
+            if let Some(animation) = &light.animation {
+                light.position = animation.get_position(self.animation_time);
+                moved = true;
+            }
+        }
+        moved
+    }
+
+    // The cascades follow the camera, so they are recomputed with the next upload
+    pub fn set_camera(&mut self, camera: CascadeCamera) {
+        self.camera = camera;
+        self.lights_are_dirty = true;
+    }
+
+    pub fn get_point_light(&self) -> Option<&Light> {
+        self.lights.iter().find(|light| light.kind == LightKind::Point)
+    }
+
+    pub fn get_directional_light(&self) -> Option<&Light> {
+        self.lights.iter().find(|light| light.get_cascade_count() > 0)
+    }
+
+    // The projection_view of the light's shadow layer or nearest cascade, for looking through the light
+    pub fn get_light_view(&self, light_index: usize) -> Mat4 {
+        let Some(light) = self.lights.get(light_index) else {
+            return Mat4::IDENTITY;
+        };
+        let projection_view = match light.kind {
+            LightKind::Directional { .. } => self.cascades.cascades.first().map(|cascade| cascade.projection_view),
+            _ => self
+                .shadow_layers
+                .get(light.shadow_layers.start as usize)
+                .filter(|_| !light.shadow_layers.is_empty())
+                .map(|layer| layer.projection_view),
+        };
+        projection_view.unwrap_or(Mat4::IDENTITY)
+    }
+
+    // Recomputes every shadow layer's projection_view and writes the light and layer arrays with a write each.
+    // The cascades are refit to the camera.
+    pub fn upload_matrices(&mut self, context: &GpuContext) {
+        for light in &self.lights {
+            let layers = &mut self.shadow_layers[light.shadow_layers.start as usize..light.shadow_layers.end as usize];
+            light.update_shadow_layers(layers);
+        }
+        if let Some(position) = self.get_point_light().map(|light| light.position) {
+            self.point_shadow.update(context, position);
+        }
+        if let Some(direction) = self.get_directional_light().map(|light| light.position) {
+            self.cascades.update(context, &self.camera, direction);
+        }
+
+        let light_uniforms: Vec<LightUniform> = self.lights.iter().map(Light::get_light_uniform).collect();
+        let layer_uniforms: Vec<ShadowLayerUniform> = self.shadow_layers.iter().map(ShadowLayer::get_uniform).collect();
+
+        context
+            .queue
+            .write_buffer(&self.light_storage_buffer, 0, bytemuck::cast_slice(&light_uniforms));
+        context
+            .queue
+            .write_buffer(&self.shadow_layer_buffer, 0, bytemuck::cast_slice(&layer_uniforms));
+    }
+}
+
+impl Light {
+    pub fn compute_projection_view(&self) -> Mat4 {
+        get_light_projection_view(self.position, self.fov, &self.depth)
+    }
+
+    // Atlas layers, the directional and point lights render into their own texture arrays
+    pub fn get_layer_count(&self) -> u32 {
+        match self.kind {
+            LightKind::Spot => 1,
+            LightKind::Directional { .. } | LightKind::Point => 0,
+        }
+    }
+
+    pub fn get_cascade_count(&self) -> u32 {
+        match self.kind {
+            LightKind::Directional { cascade_count } => cascade_count,
+            _ => 0,
+        }
+    }
+
+    pub fn update_shadow_layers(&self, layers: &mut [ShadowLayer]) {
+        for layer in layers.iter_mut() {
+            layer.projection_view = self.compute_projection_view();
+        }
+    }
+
+    pub fn get_light_uniform(&self) -> LightUniform {
+        LightUniform::new(self.position, &self.color, self.kind, &self.shadow_layers)
+    }
+}
+
+impl ShadowLayer {
+    pub fn get_uniform(&self) -> ShadowLayerUniform {
+        ShadowLayerUniform::new(&self.projection_view, self.atlas_rect.get_uv_rect(SHADOW_ATLAS_SIZE))
+    }
+}
 
-                context.queue.write_buffer(
-                    &self.light_storage_buffer,
-                    (i * mem::size_of::<LightUniform>()) as wgpu::BufferAddress,
-                    bytemuck::bytes_of(&light_uniform),
-                );
+impl LightAnimation {
+    // An orbit starting at position
+    pub fn orbit_from(position: Vec3, speed: f32) -> Self {
+        LightAnimation::Orbit {
+            center: vec3(0.0, 0.0, position.z),
+            radius: position.truncate().length(),
+            speed,
+            phase: position.y.atan2(position.x),
+        }
+    }
+
+    pub fn get_position(&self, time: f32) -> Vec3 {
+        match self {
+            LightAnimation::Orbit {
+                center,
+                radius,
+                speed,
+                phase,
+            } => {
+                let angle = phase + speed * time;
+                *center + vec3(angle.cos(), angle.sin(), 0.0) * *radius
             }
+            LightAnimation::Path { points, speed } => get_path_position(points, speed * time),
+        }
+    }
+}
+
+// Position at distance along the closed path through points
+fn get_path_position(points: &[Vec3], distance: f32) -> Vec3 {
+    let segments: Vec<(Vec3, Vec3)> = points.iter().zip(points.iter().cycle().skip(1)).map(|(a, b)| (*a, *b)).collect();
+    let length: f32 = segments.iter().map(|(a, b)| a.distance(*b)).sum();
+    if length <= 0.0 {
+        return points.first().copied().unwrap_or(Vec3::ZERO);
+    }
+
+    let mut remaining = distance.rem_euclid(length);
+    for (a, b) in segments {
+        let segment_length = a.distance(b);
+        if remaining <= segment_length && segment_length > 0.0 {
+            return a.lerp(b, remaining / segment_length);
+        }
+        remaining -= segment_length;
+    }
+    points[0]
+}
+
+impl SceneLighting {
+    pub fn new() -> Self {
+        SceneLighting {
+            ambient: Ambient::Flat(DEFAULT_AMBIENT_COLOR),
+        }
+    }
+
+    pub fn get_ambient_uniform(&self) -> AmbientUniform {
+        let (sky, ground) = match self.ambient {
+            Ambient::Flat(color) => (color, color),
+            Ambient::Hemispheric { sky, ground } => (sky, ground),
+        };
+        AmbientUniform {
+            sky: [sky.r as f32, sky.g as f32, sky.b as f32, 1.0],
+            ground: [ground.r as f32, ground.g as f32, ground.b as f32, 1.0],
         }
     }
 }
 
+impl Default for SceneLighting {
+    fn default() -> Self {
+        SceneLighting::new()
+    }
+}
+
+impl LightUniform {
+    pub fn new(position: glam::Vec3, color: &wgpu::Color, kind: LightKind, shadow_layers: &Range<u32>) -> Self {
+        let w = match kind {
+            LightKind::Spot | LightKind::Point => 1.0,
+            LightKind::Directional { .. } => 0.0,
+        };
+        let shadow_source = ShadowSource::from_kind(kind) as u32;
+        LightUniform {
+            position: [position.x, position.y, position.z, w],
+            color: [color.r as f32, color.g as f32, color.b as f32, 1.0],
+            shadow_layers: [shadow_layers.start, shadow_layers.len() as u32, shadow_source, 0],
+        }
+    }
+}
+
+// The shadow map a light is sampled from, SHADOW_SOURCE_* in shader.wgsl
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShadowSource {
+    Atlas = 0,
+    PointShadowCube = 1,
+    Cascades = 2,
+}
+
+impl ShadowSource {
+    pub fn from_kind(kind: LightKind) -> Self {
+        match kind {
+            LightKind::Spot => ShadowSource::Atlas,
+            LightKind::Point => ShadowSource::PointShadowCube,
+            LightKind::Directional { .. } => ShadowSource::Cascades,
+        }
+    }
+}
+
+impl ShadowLayerUniform {
+    pub fn new(projection_view: &Mat4, atlas_rect: [f32; 4]) -> Self {
+        ShadowLayerUniform {
+            projection: projection_view.to_cols_array_2d(),
+            atlas_rect,
+        }
+    }
+}
+
+pub fn get_light_projection_view(position: glam::Vec3, fov: f32, depth: &Range<f32>) -> Mat4 {
+    let view = Mat4::look_at_rh(position, glam::Vec3::ZERO, glam::Vec3::Z);
+    let projection = Mat4::perspective_rh(fov * consts::PI / 180., 1.0, depth.start, depth.end);
+    projection * view
+}
+
+// Full resolution only when a point light renders into it, the forward pass binds it either way
+pub fn create_point_shadow(gpu_context: &GpuContext, lights: &[Light]) -> PointShadow {
+    let resolution = match lights.iter().any(|light| light.kind == LightKind::Point) {
+        true => POINT_SHADOW_RESOLUTION,
+        false => 1,
+    };
+    let depth = POINT_SHADOW_DEPTH;
+    PointShadow::new(gpu_context, resolution, depth.start, depth.end, "point shadow").expect("invalid point shadow settings")
+}
+
+// Like the point shadow, a single texel layer when there is no directional light
+pub fn create_cascaded_shadow_map(gpu_context: &GpuContext, lights: &[Light]) -> CascadedShadowMap {
+    let (cascade_count, resolution) = match lights.iter().map(Light::get_cascade_count).find(|count| *count > 0) {
+        Some(cascade_count) => (cascade_count, CASCADE_RESOLUTION),
+        None => (1, 1),
+    };
+    let settings = CascadeSettings {
+        cascade_count,
+        resolution,
+        lambda: CASCADE_SPLIT_LAMBDA,
+        max_distance: MAX_SHADOW_DISTANCE,
+    };
+    CascadedShadowMap::new(gpu_context, settings).expect("invalid cascade settings")
+}
+
 pub fn create_light_storage_buffer(gpu_context: &mut GpuContext) -> Buffer {
     let light_uniform_size = (MAX_LIGHTS * mem::size_of::<LightUniform>()) as wgpu::BufferAddress;
 
@@ -113,15 +568,213 @@ pub fn create_light_storage_buffer(gpu_context: &mut GpuContext) -> Buffer {
     light_storage_buf
 }
 
-fn create_shadow_texture_view(shadow_texture: &Texture, layer_id: u32) -> TextureView {
-    shadow_texture.create_view(&wgpu::TextureViewDescriptor {
-        label: Some(&format!("shadow id: {}", layer_id)),
-        format: None,
-        dimension: Some(wgpu::TextureViewDimension::D2),
-        aspect: wgpu::TextureAspect::All,
-        base_mip_level: 0,
-        mip_level_count: None,
-        base_array_layer: layer_id,
-        array_layer_count: Some(1),
+pub fn create_shadow_layer_buffer(gpu_context: &mut GpuContext) -> Buffer {
+    gpu_context.device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("shadow layers"),
+        size: (MAX_SHADOW_LAYERS * mem::size_of::<ShadowLayerUniform>()) as wgpu::BufferAddress,
+        usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
     })
 }
+
+// Gives each spot light its shadow layer and packs the layers into the atlas at the resolution of
+// the light's priority
+pub fn assign_shadow_layers(lights: &mut [Light]) -> Result<Vec<ShadowLayer>, Error> {
+    let mut resolutions: Vec<u32> = vec![];
+    for light in lights.iter_mut() {
+        let start = resolutions.len() as u32;
+        let resolution = get_priority_resolution(SHADOW_ATLAS_SIZE, light.shadow_priority);
+        resolutions.extend(iter::repeat(resolution).take(light.get_layer_count() as usize));
+        light.shadow_layers = start..resolutions.len() as u32;
+    }
+
+    if resolutions.len() > MAX_SHADOW_LAYERS {
+        return Err(ValidationError(format!(
+            "lights need {} shadow layers, at most {} are supported",
+            resolutions.len(),
+            MAX_SHADOW_LAYERS
+        )));
+    }
+
+    let rects = pack_shadow_atlas(SHADOW_ATLAS_SIZE, &resolutions)?;
+    Ok(rects
+        .into_iter()
+        .map(|atlas_rect| ShadowLayer {
+            projection_view: Mat4::IDENTITY,
+            atlas_rect,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+
+    use glam::{vec3, Mat4};
+
+    use spark_gap::gpu_context::GpuContext;
+
+    use crate::lights::{
+        assign_shadow_layers, get_light_projection_view, Ambient, AmbientUniform, Light, LightAnimation, LightKind, LightUniform, Lights,
+        SceneLighting, ShadowLayerUniform, ShadowSource, MAX_SHADOW_LAYERS,
+    };
+
+    fn create_light(kind: LightKind, shadow_priority: u32) -> Light {
+        Light {
+            position: vec3(1.0, -1.0, 3.0),
+            color: wgpu::Color::WHITE,
+            kind,
+            fov: 45.0,
+            depth: 1.0..1000.0,
+            shadow_priority,
+            shadow_layers: 0..0,
+            animation: None,
+        }
+    }
+
+    #[test]
+    fn test_packed_light_uniforms() {
+        let positions = [vec3(7.0, -5.0, 10.0), vec3(-10.0, 7.0, 10.0)];
+
+        let layer_uniforms: Vec<ShadowLayerUniform> = positions
+            .iter()
+            .map(|position| {
+                let projection_view = get_light_projection_view(*position, 45.0, &(1.0..1000.0));
+                ShadowLayerUniform::new(&projection_view, [0.0, 0.0, 1.0, 1.0])
+            })
+            .collect();
+
+        let bytes: &[u8] = bytemuck::cast_slice(&layer_uniforms);
+        assert_eq!(bytes.len(), positions.len() * mem::size_of::<ShadowLayerUniform>());
+
+        for (i, position) in positions.iter().enumerate() {
+            let offset = i * mem::size_of::<ShadowLayerUniform>();
+            let matrix_bytes = &bytes[offset..offset + mem::size_of::<Mat4>()];
+            let expected = get_light_projection_view(*position, 45.0, &(1.0..1000.0)).to_cols_array();
+            assert_eq!(matrix_bytes, bytemuck::cast_slice::<f32, u8>(&expected));
+        }
+
+        // a directional light's position is a direction, it's shadowed by the cascades
+        let spot = LightUniform::new(positions[0], &wgpu::Color::WHITE, LightKind::Spot, &(0..1));
+        let sun = LightKind::Directional { cascade_count: 3 };
+        let directional = LightUniform::new(positions[1], &wgpu::Color::WHITE, sun, &(1..1));
+        assert_eq!((spot.position[3], directional.position[3]), (1.0, 0.0));
+        assert_eq!(spot.shadow_layers, [0, 1, ShadowSource::Atlas as u32, 0]);
+        assert_eq!(directional.shadow_layers, [1, 0, ShadowSource::Cascades as u32, 0]);
+        assert_eq!(mem::size_of::<LightUniform>() % 16, 0);
+
+        // a point light is shadowed by the cube instead of its layers
+        let point = LightUniform::new(positions[0], &wgpu::Color::WHITE, LightKind::Point, &(4..4));
+        assert_eq!(point.position[3], 1.0);
+        assert_eq!(point.shadow_layers, [4, 0, ShadowSource::PointShadowCube as u32, 0]);
+    }
+
+    #[test]
+    fn test_shadow_layers() {
+        let mut lights = vec![
+            create_light(LightKind::Directional { cascade_count: 3 }, 1),
+            create_light(LightKind::Spot, 0),
+            create_light(LightKind::Point, 3),
+            create_light(LightKind::Spot, 2),
+        ];
+
+        let layers = assign_shadow_layers(&mut lights).unwrap();
+
+        // only spot lights are in the atlas, the directional light's cascades have their own array
+        assert_eq!(layers.len(), 2);
+        assert!(lights[0].shadow_layers.is_empty());
+        assert_eq!(lights[0].get_cascade_count(), 3);
+        assert_eq!(lights[1].shadow_layers, 0..1);
+        assert!(lights[2].shadow_layers.is_empty());
+        assert_eq!(lights[3].shadow_layers, 1..2);
+        assert!(!layers[0].atlas_rect.overlaps(&layers[1].atlas_rect));
+        assert!(layers[0].atlas_rect.size > layers[1].atlas_rect.size);
+
+        // a spot light per atlas layer fits, in light order
+        let mut lights: Vec<Light> = (0..MAX_SHADOW_LAYERS as u32).map(|i| create_light(LightKind::Spot, i)).collect();
+        let layers = assign_shadow_layers(&mut lights).unwrap();
+        assert_eq!(layers.len(), MAX_SHADOW_LAYERS);
+        for (i, light) in lights.iter().enumerate() {
+            assert_eq!(light.shadow_layers, i as u32..i as u32 + 1);
+        }
+
+        // one more doesn't
+        lights.push(create_light(LightKind::Spot, MAX_SHADOW_LAYERS as u32));
+        assert!(assign_shadow_layers(&mut lights).is_err());
+    }
+
+    #[test]
+    fn test_ambient_uniform() {
+        let uniform = SceneLighting::default().get_ambient_uniform();
+        assert_eq!(uniform.sky, uniform.ground);
+        assert!(uniform.sky[..3].iter().all(|c| *c > 0.0 && *c < 0.2));
+
+        let lighting = SceneLighting {
+            ambient: Ambient::Hemispheric {
+                sky: wgpu::Color::BLUE,
+                ground: wgpu::Color::RED,
+            },
+        };
+        let uniform = lighting.get_ambient_uniform();
+        assert_eq!(uniform.sky, [0.0, 0.0, 1.0, 1.0]);
+        assert_eq!(uniform.ground, [1.0, 0.0, 0.0, 1.0]);
+
+        // the bytes uploaded to the ambient buffer
+        assert_eq!(bytemuck::bytes_of(&uniform).len(), mem::size_of::<AmbientUniform>());
+        assert_eq!(mem::size_of::<AmbientUniform>(), 32);
+    }
+
+    #[test]
+    fn test_light_animation() {
+        let start = vec3(7.0, -5.0, 10.0);
+        let orbit = LightAnimation::orbit_from(start, 0.5);
+        assert!(orbit.get_position(0.0).abs_diff_eq(start, 1e-5));
+
+        // a quarter turn after pi seconds at half a radian per second
+        let moved = orbit.get_position(std::f32::consts::PI);
+        assert!(moved.abs_diff_eq(vec3(5.0, 7.0, 10.0), 1e-4), "{}", moved);
+
+        let path = LightAnimation::Path {
+            points: vec![vec3(0.0, 0.0, 5.0), vec3(4.0, 0.0, 5.0), vec3(4.0, 3.0, 5.0)],
+            speed: 2.0,
+        };
+        assert_eq!(path.get_position(0.0), vec3(0.0, 0.0, 5.0));
+        assert!(path.get_position(1.0).abs_diff_eq(vec3(2.0, 0.0, 5.0), 1e-5));
+        assert!(path.get_position(2.5).abs_diff_eq(vec3(4.0, 1.0, 5.0), 1e-5));
+        // the path closes back to the first point, 12 units long
+        assert!(path.get_position(6.0).abs_diff_eq(vec3(0.0, 0.0, 5.0), 1e-5));
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "gpu_tests"), ignore = "needs a gpu adapter")]
+    fn test_advance_animations() {
+        let mut context = pollster::block_on(GpuContext::new_headless(4, 4)).unwrap();
+        let mut lights = Lights::new(&mut context);
+        let mut path_light = create_light(LightKind::Spot, 0);
+        path_light.animation = Some(LightAnimation::Path {
+            points: vec![vec3(0.0, 0.0, 5.0), vec3(4.0, 0.0, 5.0), vec3(4.0, 3.0, 5.0)],
+            speed: 2.0,
+        });
+        lights.lights = vec![path_light, create_light(LightKind::Spot, 1)];
+
+        // nothing moves until the animation is enabled
+        assert!(!lights.advance_animations(0.5));
+        assert_eq!(lights.animation_time, 0.0);
+        assert_eq!(lights.lights[0].position, vec3(1.0, -1.0, 3.0));
+
+        lights.animation_enabled = true;
+        assert!(lights.advance_animations(0.5));
+        assert!(lights.lights[0].position.abs_diff_eq(vec3(1.0, 0.0, 5.0), 1e-5));
+        assert!(lights.advance_animations(1.0));
+        assert!(lights.lights[0].position.abs_diff_eq(vec3(3.0, 0.0, 5.0), 1e-5));
+
+        // 26 units along the 12 unit closed path after 13 seconds, wrapped around twice
+        for _ in 0..23 {
+            lights.advance_animations(0.5);
+        }
+        assert!((lights.animation_time - 13.0).abs() < 1e-4);
+        let position = lights.lights[0].position;
+        assert!(position.abs_diff_eq(vec3(2.0, 0.0, 5.0), 1e-3), "{}", position);
+        assert_eq!(lights.lights[1].position, vec3(1.0, -1.0, 3.0));
+    }
+}
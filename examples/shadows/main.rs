@@ -2,11 +2,14 @@ extern crate core;
 
 mod cube;
 mod debug_shadow;
+mod deferred_pass;
 mod entities;
 mod event_loop;
 mod forward_pass;
 mod lights;
 mod shadow_pass;
+mod tooling_pass;
+mod trail_pass;
 mod world;
 
 use crate::event_loop::run;
@@ -20,6 +23,14 @@ pub fn main() {
         c : switch camera from normal, light 1 position, light 2 position
         space : toggle between normal display and shadow map display
         0, 1 : select shadow map layer
+        f : toggle frustum culling
+        m : toggle the material between metal and dielectric
+        t : cycle the tonemap operator between reinhard, aces filmic and passthrough
+        -, = : halve or double the exposure
+        r : toggle between forward and deferred rendering
+        p : log the entity under the cursor
+        v : toggle split screen with the normal and light 1 cameras
+        h : toggle motion trails
     ");
 
     env_logger::init();
@@ -0,0 +1,36 @@
+//!include "spark_gap/point_shadow.wgsl"
+
+// The point light's shadow passes, one per cube face
+
+// Entity in shader.wgsl
+struct Entity {
+    world: mat4x4<f32>,
+    previous_world: mat4x4<f32>,
+    normal: mat3x3<f32>,
+    color: vec4<f32>,
+    tint: vec4<f32>,
+};
+
+@group(0) @binding(0) var<uniform> point_shadow_light: PointShadowLight;
+
+@group(1) @binding(0) var<uniform> entity_data: Entity;
+
+struct PointShadowOutput {
+    @builtin(position) position: vec4<f32>,
+    @location(0) world_position: vec3<f32>,
+};
+
+// the instance id is the face, like the shadow layer of vs_shadow
+@vertex fn vs_point_shadow(@location(0) position: vec4<i32>, @builtin(instance_index) face: u32) -> PointShadowOutput {
+    let world_position = entity_data.world * vec4<f32>(position);
+
+    var result: PointShadowOutput;
+    result.position = point_shadow_light.face_projection_views[face] * world_position;
+    result.world_position = world_position.xyz;
+    return result;
+}
+
+// the distance to the light replaces the projection's depth
+@fragment fn fs_point_shadow(vertex: PointShadowOutput) -> @builtin(frag_depth) f32 {
+    return get_point_shadow_depth(point_shadow_light, vertex.world_position);
+}
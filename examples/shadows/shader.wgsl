@@ -1,55 +1,100 @@
+//!include "spark_gap/point_shadow.wgsl"
+//!include "spark_gap/shadow_cascades.wgsl"
 
-const AMBIENT_COLOR: vec3<f32> = vec3<f32>(0.05, 0.05, 0.05);
-const MAX_LIGHTS: u32 = 10u;
+// Included by forward.wgsl and deferred.wgsl, see get_shader_include
+
+// lights::MAX_LIGHTS and lights::MAX_SHADOW_LAYERS
+#const MAX_LIGHTS
+#const MAX_SHADOW_LAYERS
 
 struct Light {
-    projection_view: mat4x4<f32>,
+    // w is 0 for a directional light, whose xyz is the direction towards the light
     position: vec4<f32>,
     color: vec4<f32>,
+    // first shadow layer in x and the layer count in y, z is the SHADOW_SOURCE_* the light is
+    // shadowed by
+    shadow_layers: vec4<u32>,
+};
+
+// lights::ShadowSource
+const SHADOW_SOURCE_ATLAS: u32 = 0u;
+const SHADOW_SOURCE_POINT_SHADOW_CUBE: u32 = 1u;
+const SHADOW_SOURCE_CASCADES: u32 = 2u;
+
+struct ShadowLayer {
+    projection_view: mat4x4<f32>,
+    // the layer's shadow map in the atlas, uv offset in xy and scale in zw
+    atlas_rect: vec4<f32>,
+};
+
+struct Ambient {
+    sky: vec4<f32>,
+    ground: vec4<f32>,
 };
 
 struct Entity {
     world: mat4x4<f32>,
+    previous_world: mat4x4<f32>,
+    normal: mat3x3<f32>,
     color: vec4<f32>,
+    tint: vec4<f32>,
 };
 
-@group(0) @binding(0) var<uniform> lights_uniform: array<Light, MAX_LIGHTS>;
+@group(0) @binding(0) var<uniform> shadow_layers: array<ShadowLayer, MAX_SHADOW_LAYERS>;
 
 @group(0) @binding(1) var<uniform> num_lights: u32;
 @group(0) @binding(2) var<uniform> projection_view: mat4x4<f32>;
 
-@group(0) @binding(3) var shadow_texture_array: texture_depth_2d_array;
+@group(0) @binding(3) var shadow_atlas: texture_depth_2d;
 @group(0) @binding(4) var shadow_sampler: sampler_comparison;
+@group(0) @binding(5) var<uniform> previous_projection_view: mat4x4<f32>;
+@group(0) @binding(6) var<uniform> ambient: Ambient;
+@group(0) @binding(7) var<uniform> lights_uniform: array<Light, MAX_LIGHTS>;
+// w is 0 for the orthographic views of directional lights, whose xyz is the direction towards the eye
+@group(0) @binding(8) var<uniform> eye_position: vec4<f32>;
+@group(0) @binding(9) var point_shadow_cube: texture_depth_cube;
+@group(0) @binding(10) var<uniform> point_shadow_light: PointShadowLight;
+// the directional light's cascades, split by the camera's view depth
+@group(0) @binding(11) var shadow_cascade_maps: texture_depth_2d_array;
+@group(0) @binding(12) var<uniform> shadow_cascades: ShadowCascades;
+
+// in world units, the cube's depth is linear so one bias fits every distance
+const POINT_SHADOW_BIAS: f32 = 0.05;
+// in the cascades' depth, on top of the shadow pipeline's depth bias
+const CASCADE_SHADOW_BIAS: f32 = 0.001;
 
 @group(1) @binding(0) var<uniform> entity_data: Entity;
 
 @vertex fn vs_shadow(@location(0) position: vec4<i32>, @builtin(instance_index) index: u32) -> @builtin(position) vec4<f32> {
-    let light = lights_uniform[index];
-    return light.projection_view * entity_data.world * vec4<f32>(position);
+    let layer = shadow_layers[index];
+    return layer.projection_view * entity_data.world * vec4<f32>(position);
 }
 
 struct VertexOutput {
     @builtin(position) proj_position: vec4<f32>,
     @location(0) world_normal: vec3<f32>,
-    @location(1) world_position: vec4<f32>
+    @location(1) world_position: vec4<f32>,
+    @location(2) current_clip: vec4<f32>,
+    @location(3) previous_clip: vec4<f32>,
 };
 
 @vertex fn vs_main(@location(0) position: vec4<i32>, @location(1) normal: vec4<i32>) -> VertexOutput {
-    let w = entity_data.world;
     let world_pos = entity_data.world * vec4<f32>(position);
 
     var result: VertexOutput;
 
-    result.world_normal = mat3x3<f32>(w[0].xyz, w[1].xyz, w[2].xyz) * vec3<f32>(normal.xyz);
+    result.world_normal = entity_data.normal * vec3<f32>(normal.xyz);
     result.world_position = world_pos;
     result.proj_position = projection_view * world_pos;
+    result.current_clip = result.proj_position;
+    result.previous_clip = previous_projection_view * entity_data.previous_world * vec4<f32>(position);
 
     return result;
 }
 
 // fragment shader
 
-fn fetch_shadow(light_id: u32, homogeneous_coords: vec4<f32>) -> f32 {
+fn fetch_shadow(layer_id: u32, homogeneous_coords: vec4<f32>) -> f32 {
     if (homogeneous_coords.w <= 0.0) {
         return 1.0;
     }
@@ -64,27 +109,39 @@ fn fetch_shadow(light_id: u32, homogeneous_coords: vec4<f32>) -> f32 {
 
     // do the lookup, using HW PCF and comparison
     let shadow_depth = textureSampleCompareLevel(
-        shadow_texture_array,
+        shadow_atlas,
         shadow_sampler,
-        light_local,
-        i32(light_id),
+        get_atlas_coords(layer_id, light_local, vec2<f32>(0.0, 0.0)),
         homogeneous_coords.z * proj_correction);
 
     return shadow_depth;
 }
 
-fn shadow_calculation(light_id: u32, bias: f32, frag_light_space: vec4<f32>, offset: vec2<f32>) -> f32 {
+// Maps a layer's shadow map uv to the atlas. The offset is in atlas uv and the result is kept
+// half a texel inside the layer's rect so filtering never reads a neighbouring shadow map.
+fn get_atlas_coords(layer_id: u32, uv: vec2<f32>, offset: vec2<f32>) -> vec2<f32> {
+    let rect = shadow_layers[layer_id].atlas_rect;
+    let half_texel = 0.5 / vec2<f32>(textureDimensions(shadow_atlas, 0));
+    let coords = rect.xy + uv * rect.zw + offset;
+    return clamp(coords, rect.xy + half_texel, rect.xy + rect.zw - half_texel);
+}
+
+fn shadow_calculation(layer_id: u32, bias: f32, frag_light_space: vec4<f32>, offset: vec2<f32>) -> f32 {
 
   let proj_correction = frag_light_space.xyz / frag_light_space.w;
   let flip_correction = vec2<f32>(0.5, -0.5);
 
   let projCoords = proj_correction.xy * flip_correction + vec2<f32>(0.5, 0.5);
 
+  // outside the light's frustum
+  if (any(projCoords.xy < vec2<f32>(0.0, 0.0)) || any(projCoords.xy > vec2<f32>(1.0, 1.0))) {
+    return 1.0;
+  }
+
   let shadow_depth = textureSampleCompareLevel(
-    shadow_texture_array,
+    shadow_atlas,
     shadow_sampler,
-    projCoords.xy + offset,
-    i32(light_id),
+    get_atlas_coords(layer_id, projCoords.xy, offset),
     proj_correction.z);
 
   return shadow_depth + bias;
@@ -131,44 +188,104 @@ fn shadow_calculation(light_id: u32, bias: f32, frag_light_space: vec4<f32>, off
     return slopeBias;
 }
 
-@fragment fn fs_main(vertex: VertexOutput) -> @location(0) vec4<f32> {
+// The light's first atlas layer whose shadow map covers the point, layers are ordered near to far.
+// A spot light has a single layer.
+fn select_shadow_layer(light: Light, world_position: vec4<f32>) -> u32 {
+    let first = light.shadow_layers.x;
+    let last = first + max(light.shadow_layers.y, 1u) - 1u;
+
+    // from far to near without an early return, shade_lights takes derivatives after this
+    var selected = last;
+    for (var layer = last; layer > first; layer -= 1u) {
+        let coords = shadow_layers[layer - 1u].projection_view * world_position;
+        let ndc = coords.xyz / coords.w;
+        let covered = all(abs(ndc.xy) < vec2<f32>(1.0, 1.0)) && ndc.z <= 1.0;
+        selected = select(selected, layer - 1u, covered);
+    }
+    return selected;
+}
 
-    let normal = normalize(vertex.world_normal);
+// The directional light's shadow from the cascade covering the camera's view depth at the point,
+// with the same 3x3 pcf as the atlas. Points outside the cascade are lit.
+fn sample_cascade_shadow(world_position: vec4<f32>, bias: f32) -> f32 {
+    // the clip w of the camera's perspective projection is the view depth the splits are in
+    let view_depth = (projection_view * world_position).w;
+    let cascade = select_cascade(shadow_cascades, view_depth);
+
+    let coords = shadow_cascades.projection_views[cascade] * world_position;
+    let ndc = coords.xyz / coords.w;
+    let uv = ndc.xy * vec2<f32>(0.5, -0.5) + vec2<f32>(0.5, 0.5);
+    let texel_size = 1.0 / vec2<f32>(textureDimensions(shadow_cascade_maps, 0));
+
+    var shadow = 0.0;
+    for (var x = -1; x <= 1; x += 1) {
+        for (var y = -1; y <= 1; y += 1) {
+            let offset = vec2<f32>(f32(x), f32(y)) * texel_size;
+            shadow += textureSampleCompareLevel(shadow_cascade_maps, shadow_sampler, uv + offset, cascade, ndc.z - bias);
+        }
+    }
 
-    var color: vec3<f32> = AMBIENT_COLOR;
+    let covered = all(uv >= vec2<f32>(0.0, 0.0)) && all(uv <= vec2<f32>(1.0, 1.0)) && ndc.z <= 1.0;
+    return select(1.0, shadow / 9.0, covered);
+}
+
+// The direction towards the light, a directional light's doesn't depend on the point
+fn get_light_dir(light: Light, world_position: vec4<f32>) -> vec3<f32> {
+    return normalize(light.position.xyz - world_position.xyz * light.position.w);
+}
 
-    let dimensions = textureDimensions(shadow_texture_array, 0).xy;
+// How much of the light reaches the point, 0.0 in shadow and 1.0 fully lit
+fn get_light_shadow(light: Light, world_position: vec4<f32>, normal: vec3<f32>, light_dir: vec3<f32>) -> f32 {
+    // pcf offsets step over atlas texels
+    let dimensions = textureDimensions(shadow_atlas, 0).xy;
     let texelSize = vec2<f32>(1.0, 1.0) / vec2<f32>(f32(dimensions.x), f32(dimensions.y));
-    
-    for (var i = 0u; i < min(num_lights, MAX_LIGHTS); i += 1u) {
-//    let i = 1u;
-//    {
-        //let shadow = fetch_shadow(i, light.projection_view * vertex.world_position);
 
-        let light = lights_uniform[i];
-        let light_dir = normalize(light.position.xyz - vertex.world_position.xyz);
-        var shadow_coords = light.projection_view * vertex.world_position;
+    let layer = select_shadow_layer(light, world_position);
+    var shadow_coords = shadow_layers[layer].projection_view * world_position;
 
-        let constant_bias: f32 = 0.005; // A predefined constant bias
-        var bias: f32 = max(0.05 * (1.0 - dot(normal, light_dir)), 0.005);
-        var slope_bias = calculateSlopeBias(shadow_coords.z);
+    let constant_bias: f32 = 0.005; // A predefined constant bias
+    var bias: f32 = max(0.05 * (1.0 - dot(normal, light_dir)), 0.005);
+    var slope_bias = calculateSlopeBias(shadow_coords.z);
 
-        bias = constant_bias + bias + slope_bias;
+    bias = constant_bias + bias + slope_bias;
 
-        var shadow = 0.0;
+    var shadow = 0.0;
 
-        for (var x = -1; x <= 1; x += 1) {
-            for (var y = -1; y <= 1; y += 1) {
-                let offset = vec2<f32>(f32(x), f32(y)) * texelSize;
-                shadow += shadow_calculation(i, bias, shadow_coords, offset);
-            }
+    for (var x = -1; x <= 1; x += 1) {
+        for (var y = -1; y <= 1; y += 1) {
+            let offset = vec2<f32>(f32(x), f32(y)) * texelSize;
+            shadow += shadow_calculation(layer, bias, shadow_coords, offset);
         }
+    }
+
+    // the point and directional lights have no layers and sample their own arrays instead, all are
+    // computed since the slope bias above takes derivatives
+    let point_shadow = sample_point_shadow(point_shadow_cube, shadow_sampler, point_shadow_light, world_position.xyz, POINT_SHADOW_BIAS);
+    let cascade_shadow = sample_cascade_shadow(world_position, CASCADE_SHADOW_BIAS);
 
-        shadow = shadow / 9; // average of neighbors
+    let source = light.shadow_layers.z;
+    let atlas_or_point = select(shadow / 9, point_shadow, source == SHADOW_SOURCE_POINT_SHADOW_CUBE);
+    return select(atlas_or_point, cascade_shadow, source == SHADOW_SOURCE_CASCADES);
+}
+
+// hemispheric ambient with z up, flat ambient has sky equal to ground
+fn get_ambient(normal: vec3<f32>) -> vec3<f32> {
+    return mix(ambient.ground.rgb, ambient.sky.rgb, normal.z * 0.5 + 0.5);
+}
+
+// Ambient and shadowed diffuse light at a surface point for the deferred path, the forward path
+// shades pbr materials in forward.wgsl
+fn shade_lights(world_position: vec4<f32>, normal: vec3<f32>) -> vec3<f32> {
+    var color: vec3<f32> = get_ambient(normal);
+
+    for (var i = 0u; i < min(num_lights, MAX_LIGHTS); i += 1u) {
+        let light = lights_uniform[i];
+        let light_dir = get_light_dir(light, world_position);
+        let shadow = get_light_shadow(light, world_position, normal, light_dir);
 
         let diffuse = max(0.0, dot(normal, light_dir));
         color += shadow * diffuse * light.color.xyz;
     }
 
-    return vec4<f32>(color, 1.0) * entity_data.color;
+    return color;
 }
@@ -2,10 +2,15 @@ use std::mem;
 
 use wgpu::{BindGroup, BindGroupLayout, RenderPipeline, ShaderModule};
 
+use spark_gap::bind_group::{BindGroupBuilder, LayoutBuilder};
 use spark_gap::gpu_context::GpuContext;
+use spark_gap::point_shadow::{get_cube_face_primitive_state, PointShadow, PointShadowUniform};
+use spark_gap::shader_preprocessor::expand_embedded_includes;
+use spark_gap::shadow_cascades::{CascadeUniform, CascadedShadowMap};
+use spark_gap::shadow_pipeline::ShadowPipelineBuilder;
 
-use crate::lights::{LightUniform, Lights, MAX_LIGHTS};
-use crate::world::get_vertex_buffer_layout;
+use crate::cube::Vertex;
+use crate::lights::{Lights, ShadowLayerUniform, MAX_SHADOW_LAYERS};
 
 pub const SHADOW_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
 
@@ -14,76 +19,199 @@ pub struct ShadowPass {
     pub bind_group: BindGroup,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShadowCullMode {
+    Front,
+    Back,
+    None,
+}
+
+impl ShadowCullMode {
+    pub fn to_face(self) -> Option<wgpu::Face> {
+        match self {
+            ShadowCullMode::Front => Some(wgpu::Face::Front),
+            ShadowCullMode::Back => Some(wgpu::Face::Back),
+            ShadowCullMode::None => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ShadowSettings {
+    // Rendering the back faces of casters into the shadow map reduces peter-panning
+    pub cull_mode: ShadowCullMode,
+}
+
+impl Default for ShadowSettings {
+    fn default() -> Self {
+        ShadowSettings {
+            cull_mode: ShadowCullMode::Front,
+        }
+    }
+}
+
+// unclipped depth is set by the pipeline builder when the device supports it
+pub fn get_shadow_primitive_state(settings: &ShadowSettings) -> wgpu::PrimitiveState {
+    wgpu::PrimitiveState {
+        topology: wgpu::PrimitiveTopology::TriangleList,
+        front_face: wgpu::FrontFace::Ccw,
+        cull_mode: settings.cull_mode.to_face(),
+        ..Default::default()
+    }
+}
+
 pub fn create_shadow_pass(
     context: &mut GpuContext,
     lights: &Lights,
     entity_bind_group_layout: &BindGroupLayout,
     shader: &ShaderModule,
+    settings: &ShadowSettings,
 ) -> ShadowPass {
-    let light_uniform_size = (MAX_LIGHTS * mem::size_of::<LightUniform>()) as wgpu::BufferAddress;
-
-    let bind_group_layout = context.device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-        label: None,
-        entries: &[
-            // lights
-            wgpu::BindGroupLayoutEntry {
-                binding: 0,
-                visibility: wgpu::ShaderStages::VERTEX,
-                ty: wgpu::BindingType::Buffer {
-                    ty: wgpu::BufferBindingType::Uniform,
-                    has_dynamic_offset: false,
-                    min_binding_size: wgpu::BufferSize::new(light_uniform_size),
-                },
-                count: None,
-            },
-        ],
-    });
+    let shadow_layer_size = (MAX_SHADOW_LAYERS * mem::size_of::<ShadowLayerUniform>()) as wgpu::BufferAddress;
 
-    let bind_group = context.device.create_bind_group(&wgpu::BindGroupDescriptor {
-        layout: &bind_group_layout,
-        entries: &[wgpu::BindGroupEntry {
-            binding: 0,
-            resource: lights.light_storage_buffer.as_entire_binding(),
-        }],
-        label: None,
-    });
+    let bind_group_layout = LayoutBuilder::new()
+        // shadow layers
+        .sized_uniform(wgpu::ShaderStages::VERTEX, shadow_layer_size)
+        .build(context, "shadow")
+        .expect("invalid shadow bind group layout");
+
+    let bind_group = BindGroupBuilder::new()
+        .buffer(&lights.shadow_layer_buffer)
+        .build(context, &bind_group_layout, "shadow");
+
+    let pipeline = ShadowPipelineBuilder::new("vs_shadow")
+        .vertex_buffer(Vertex::vertex_buffer_layout())
+        .bind_group_layout(&bind_group_layout)
+        .bind_group_layout(entity_bind_group_layout)
+        .depth_format(SHADOW_FORMAT)
+        .primitive(get_shadow_primitive_state(settings))
+        .build(context, shader)
+        .expect("invalid shadow pipeline");
+
+    ShadowPass { pipeline, bind_group }
+}
 
-    let pipeline_layout = context.device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-        label: Some("shadow pipeline layout"),
-        bind_group_layouts: &[&bind_group_layout, &entity_bind_group_layout],
-        push_constant_ranges: &[],
+// The passes rendering the faces of the point light's cube, with their own shader since group 0
+// only holds the point shadow's uniform
+pub fn create_point_shadow_pass(
+    context: &mut GpuContext,
+    point_shadow: &PointShadow,
+    entity_bind_group_layout: &BindGroupLayout,
+    settings: &ShadowSettings,
+) -> ShadowPass {
+    let shader = context.device.create_shader_module(wgpu::ShaderModuleDescriptor {
+        label: Some("point shadow"),
+        source: wgpu::ShaderSource::Wgsl(get_point_shadow_shader_source().into()),
     });
 
-    let pipeline = context.device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-        label: Some("shadow pipeline"),
-        layout: Some(&pipeline_layout),
-        vertex: wgpu::VertexState {
-            module: &shader,
-            entry_point: "vs_shadow",
-            buffers: &[get_vertex_buffer_layout()],
-        },
-        fragment: None,
-        primitive: wgpu::PrimitiveState {
-            topology: wgpu::PrimitiveTopology::TriangleList,
-            front_face: wgpu::FrontFace::Ccw,
-            cull_mode: Some(wgpu::Face::Back),
-            unclipped_depth: context.device.features().contains(wgpu::Features::DEPTH_CLIP_CONTROL),
-            ..Default::default()
-        },
-        depth_stencil: Some(wgpu::DepthStencilState {
-            format: SHADOW_FORMAT,
-            depth_write_enabled: true,
-            depth_compare: wgpu::CompareFunction::LessEqual,
-            stencil: wgpu::StencilState::default(),
-            bias: wgpu::DepthBiasState {
-                constant: 2, // corresponds to bilinear filtering
-                slope_scale: 2.0,
-                clamp: 0.0,
-            },
-        }),
-        multisample: wgpu::MultisampleState::default(),
-        multiview: None,
+    let bind_group_layout = LayoutBuilder::new()
+        .sized_uniform(
+            wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+            mem::size_of::<PointShadowUniform>() as u64,
+        )
+        .build(context, "point shadow")
+        .expect("invalid point shadow bind group layout");
+
+    let bind_group = BindGroupBuilder::new()
+        .buffer(&point_shadow.uniform.buffer)
+        .build(context, &bind_group_layout, "point shadow");
+
+    // the fragment entry writes the distance to the light as depth, which the pipeline's depth
+    // bias doesn't apply to, sample_point_shadow is biased instead
+    let pipeline = ShadowPipelineBuilder::new("vs_point_shadow")
+        .label("point shadow pipeline")
+        .alpha_test("fs_point_shadow")
+        .vertex_buffer(Vertex::vertex_buffer_layout())
+        .bind_group_layout(&bind_group_layout)
+        .bind_group_layout(entity_bind_group_layout)
+        .depth_format(SHADOW_FORMAT)
+        .depth_bias(wgpu::DepthBiasState::default())
+        .primitive(get_cube_face_primitive_state(get_shadow_primitive_state(settings)))
+        .build(context, &shader)
+        .expect("invalid point shadow pipeline");
+
+    ShadowPass { pipeline, bind_group }
+}
+
+// The passes rendering the directional light's cascades, group 0 only holds the cascade uniform
+pub fn create_cascade_shadow_pass(
+    context: &mut GpuContext,
+    cascades: &CascadedShadowMap,
+    entity_bind_group_layout: &BindGroupLayout,
+    settings: &ShadowSettings,
+) -> ShadowPass {
+    let shader = context.device.create_shader_module(wgpu::ShaderModuleDescriptor {
+        label: Some("cascade shadow"),
+        source: wgpu::ShaderSource::Wgsl(get_cascade_shadow_shader_source().into()),
     });
 
+    let bind_group_layout = LayoutBuilder::new()
+        .sized_uniform(wgpu::ShaderStages::VERTEX, mem::size_of::<CascadeUniform>() as u64)
+        .build(context, "cascade shadow")
+        .expect("invalid cascade shadow bind group layout");
+
+    let bind_group = BindGroupBuilder::new()
+        .buffer(&cascades.uniform.buffer)
+        .build(context, &bind_group_layout, "cascade shadow");
+
+    let pipeline = ShadowPipelineBuilder::new("vs_cascade_shadow")
+        .label("cascade shadow pipeline")
+        .vertex_buffer(Vertex::vertex_buffer_layout())
+        .bind_group_layout(&bind_group_layout)
+        .bind_group_layout(entity_bind_group_layout)
+        .depth_format(SHADOW_FORMAT)
+        .primitive(get_shadow_primitive_state(settings))
+        .build(context, &shader)
+        .expect("invalid cascade shadow pipeline");
+
     ShadowPass { pipeline, bind_group }
 }
+
+pub fn get_point_shadow_shader_source() -> String {
+    expand_embedded_includes("point_shadow.wgsl", include_str!("point_shadow.wgsl"), &[])
+        .expect("invalid point_shadow.wgsl")
+        .source
+}
+
+pub fn get_cascade_shadow_shader_source() -> String {
+    expand_embedded_includes("cascade_shadow.wgsl", include_str!("cascade_shadow.wgsl"), &[])
+        .expect("invalid cascade_shadow.wgsl")
+        .source
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::shadow_pass::{
+        get_cascade_shadow_shader_source, get_point_shadow_shader_source, get_shadow_primitive_state, ShadowCullMode, ShadowSettings,
+    };
+
+    #[test]
+    fn test_shadow_cull_mode() {
+        let settings = ShadowSettings::default();
+        assert_eq!(get_shadow_primitive_state(&settings).cull_mode, Some(wgpu::Face::Front));
+
+        let settings = ShadowSettings {
+            cull_mode: ShadowCullMode::Back,
+        };
+        assert_eq!(get_shadow_primitive_state(&settings).cull_mode, Some(wgpu::Face::Back));
+
+        let settings = ShadowSettings {
+            cull_mode: ShadowCullMode::None,
+        };
+        assert_eq!(get_shadow_primitive_state(&settings).cull_mode, None);
+    }
+
+    #[test]
+    fn test_point_shadow_shader() {
+        let source = get_point_shadow_shader_source();
+        assert!(source.contains("struct PointShadowLight") && source.contains("@vertex fn vs_point_shadow("));
+        assert!(source.contains("@fragment fn fs_point_shadow("));
+    }
+
+    #[test]
+    fn test_cascade_shadow_shader() {
+        let source = get_cascade_shadow_shader_source();
+        assert!(source.contains("struct ShadowCascades") && source.contains("@vertex fn vs_cascade_shadow("));
+        assert!(!source.contains("#const"));
+    }
+}
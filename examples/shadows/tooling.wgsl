@@ -0,0 +1,47 @@
+// Entity ids, world normals and depth for editor tooling, see tooling_pass.rs
+
+// EntityUniform in entities.rs
+struct Entity {
+    world: mat4x4<f32>,
+    previous_world: mat4x4<f32>,
+    normal: mat3x3<f32>,
+    color: vec4<f32>,
+    tint: vec4<f32>,
+};
+
+@group(0) @binding(0) var<uniform> projection_view: mat4x4<f32>;
+
+@group(1) @binding(0) var<uniform> entity_data: Entity;
+
+struct ToolingVertex {
+    @builtin(position) proj_position: vec4<f32>,
+    @location(0) world_normal: vec3<f32>,
+    @location(1) @interpolate(flat) entity_id: u32,
+};
+
+// the entity id is passed as the instance index of the single drawn instance
+@vertex fn vs_tooling(
+    @location(0) position: vec4<i32>,
+    @location(1) normal: vec4<i32>,
+    @builtin(instance_index) entity_id: u32,
+) -> ToolingVertex {
+    var result: ToolingVertex;
+    result.proj_position = projection_view * entity_data.world * vec4<f32>(position);
+    result.world_normal = entity_data.normal * vec3<f32>(normal.xyz);
+    result.entity_id = entity_id;
+    return result;
+}
+
+// in the order of GBufferLayout::tooling
+struct ToolingOutput {
+    @location(0) entity_id: u32,
+    @location(1) normal: vec4<f32>,
+};
+
+@fragment fn fs_tooling(vertex: ToolingVertex) -> ToolingOutput {
+    var result: ToolingOutput;
+    result.entity_id = vertex.entity_id;
+    // a is 0 where nothing was drawn
+    result.normal = vec4<f32>(normalize(vertex.world_normal), 1.0);
+    return result;
+}
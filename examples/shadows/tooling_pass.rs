@@ -0,0 +1,266 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::mem;
+use std::rc::Rc;
+
+use glam::Mat4;
+use wgpu::{BindGroup, BindGroupLayout, Buffer, RenderPipeline, TextureView};
+
+use spark_gap::bind_group::create_pipeline_layout;
+use spark_gap::buffers::{create_mat4_buffer_init, update_mat4_buffer};
+use spark_gap::depth_prepass::{get_depth_prepass_descriptor, DEPTH_PREPASS_USAGE};
+use spark_gap::error::Error;
+use spark_gap::gbuffer::{create_surface_gbuffer, GBuffer, GBufferLayout};
+use spark_gap::gpu_context::GpuContext;
+use spark_gap::snapshot::read_texture_region;
+use spark_gap::texture::DEPTH_FORMAT;
+
+use crate::cube::Vertex;
+use crate::entities::Entities;
+
+// Editor pass writing the entity id and world normal of every pixel along with its depth, for
+// picking, gizmo alignment and overlays. It is drawn on demand rather than every frame.
+pub struct ToolingPass {
+    // GBufferLayout::tooling, the targets can be read back
+    pub gbuffer: Rc<RefCell<GBuffer>>,
+    pub depth: (wgpu::Texture, TextureView),
+    pub projection_view_buffer: Buffer,
+    pub bind_group: BindGroup,
+    pub pipeline: RenderPipeline,
+}
+
+impl ToolingPass {
+    pub fn resize(&mut self, context: &GpuContext) {
+        self.depth = create_tooling_depth(context);
+    }
+
+    // Draws all entities in draw order, without culling so every entity can be picked
+    pub fn record(&self, context: &GpuContext, encoder: &mut wgpu::CommandEncoder, entities: &Entities, projection_view: &Mat4) {
+        update_mat4_buffer(context, &self.projection_view_buffer, projection_view);
+
+        encoder.push_debug_group("tooling pass");
+        {
+            // an integer target clears to 0, which is the background id
+            let gbuffer = self.gbuffer.borrow();
+            let mut pass = gbuffer
+                .get_pass_builder("tooling", Some(wgpu::Color::TRANSPARENT))
+                .depth(&self.depth.1, Some(1.0))
+                .keep_depth()
+                .begin(encoder);
+
+            pass.set_pipeline(&self.pipeline);
+            pass.set_bind_group(0, &self.bind_group, &[]);
+
+            for index in entities.get_draw_order() {
+                let entity = &entities.entities[index];
+                pass.set_bind_group(1, &entities.entity_bind_group, &[entity.uniform_offset]);
+
+                pass.set_vertex_buffer(0, entity.vertex_buf.slice(..));
+                pass.set_index_buffer(entity.index_buf.slice(..), entity.index_format);
+
+                let entity_id = get_entity_id(index);
+                pass.draw_indexed(0..entity.index_count as u32, 0, entity_id..entity_id + 1);
+            }
+        }
+        encoder.pop_debug_group();
+    }
+
+    // The index of the entity at the pixel of the last recorded pass, None for the background.
+    // Waits for the gpu.
+    pub fn pick(&self, context: &GpuContext, x: u32, y: u32) -> Result<Option<usize>, Error> {
+        let bytes = read_texture_region(context, self.gbuffer.borrow().get_texture(0), [x, y], 1, 1)?;
+        Ok(get_entity_index(bytemuck::pod_read_unaligned(&bytes)))
+    }
+}
+
+// 0 is left for the background
+pub fn get_entity_id(index: usize) -> u32 {
+    index as u32 + 1
+}
+
+pub fn get_entity_index(entity_id: u32) -> Option<usize> {
+    entity_id.checked_sub(1).map(|index| index as usize)
+}
+
+pub fn create_tooling_pass(context: &mut GpuContext, entity_bind_group_layout: &BindGroupLayout) -> ToolingPass {
+    let shader = context.device.create_shader_module(wgpu::ShaderModuleDescriptor {
+        label: Some("tooling shader"),
+        source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(include_str!("tooling.wgsl"))),
+    });
+
+    let gbuffer = create_surface_gbuffer(context, GBufferLayout::tooling(), wgpu::TextureUsages::COPY_SRC);
+    let depth = create_tooling_depth(context);
+
+    let projection_view_buffer = create_mat4_buffer_init(context, &Mat4::IDENTITY, "tooling projection view");
+
+    let bind_group_layout = context.device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        entries: &[wgpu::BindGroupLayoutEntry {
+            binding: 0,
+            visibility: wgpu::ShaderStages::VERTEX,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: wgpu::BufferSize::new(mem::size_of::<Mat4>() as _),
+            },
+            count: None,
+        }],
+        label: Some("tooling bind group layout"),
+    });
+
+    let bind_group = context.device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout: &bind_group_layout,
+        entries: &[wgpu::BindGroupEntry {
+            binding: 0,
+            resource: projection_view_buffer.as_entire_binding(),
+        }],
+        label: Some("tooling bind group"),
+    });
+
+    let pipeline_layout = create_pipeline_layout(context, "tooling", &[(0, &bind_group_layout), (1, entity_bind_group_layout)], &[])
+        .expect("invalid tooling pipeline layout");
+
+    let pipeline = context.device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("tooling pipeline"),
+        layout: Some(&pipeline_layout),
+        vertex: wgpu::VertexState {
+            module: &shader,
+            entry_point: "vs_tooling",
+            buffers: &[Vertex::vertex_buffer_layout()],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: &shader,
+            entry_point: "fs_tooling",
+            targets: &gbuffer.borrow().get_color_targets(),
+        }),
+        primitive: wgpu::PrimitiveState {
+            front_face: wgpu::FrontFace::Ccw,
+            cull_mode: Some(wgpu::Face::Back),
+            ..Default::default()
+        },
+        depth_stencil: Some(wgpu::DepthStencilState {
+            format: DEPTH_FORMAT,
+            depth_write_enabled: true,
+            depth_compare: wgpu::CompareFunction::Less,
+            stencil: wgpu::StencilState::default(),
+            bias: wgpu::DepthBiasState::default(),
+        }),
+        multisample: wgpu::MultisampleState::default(),
+        multiview: None,
+    });
+
+    ToolingPass {
+        gbuffer,
+        depth,
+        projection_view_buffer,
+        bind_group,
+        pipeline,
+    }
+}
+
+// sampled like the depth prepass for overlays, and read back for picking depth
+fn create_tooling_depth(context: &GpuContext) -> (wgpu::Texture, TextureView) {
+    let texture = context.device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("tooling depth"),
+        usage: DEPTH_PREPASS_USAGE | wgpu::TextureUsages::COPY_SRC,
+        ..get_depth_prepass_descriptor(context.config.width, context.config.height)
+    });
+    let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+    (texture, view)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{iter, mem};
+
+    use glam::{vec3, Mat4, Quat, Vec3};
+
+    use spark_gap::gpu_context::GpuContext;
+    use spark_gap::snapshot::read_texture_region;
+
+    use crate::entities::{Entities, EntityMesh, EntitySpawn};
+    use crate::tooling_pass::{create_tooling_pass, get_entity_id, get_entity_index};
+
+    // the readback isn't aligned for a cast
+    fn read_values<T: bytemuck::Pod>(context: &GpuContext, texture: &wgpu::Texture, origin: [u32; 2], width: u32, height: u32) -> Vec<T> {
+        let bytes = read_texture_region(context, texture, origin, width, height).unwrap();
+        bytes.chunks_exact(mem::size_of::<T>()).map(bytemuck::pod_read_unaligned).collect()
+    }
+
+    #[test]
+    fn test_entity_ids() {
+        assert_eq!(get_entity_index(0), None);
+        for index in [0, 1, 41] {
+            assert_eq!(get_entity_index(get_entity_id(index)), Some(index));
+        }
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "gpu_tests"), ignore = "needs a gpu adapter")]
+    fn test_tooling_pass() {
+        let mut context = pollster::block_on(GpuContext::new_headless(8, 4)).unwrap();
+
+        // two planes facing the camera, the left one further away and both leaving the top and
+        // bottom rows empty
+        let plane = EntityMesh::plane(&context);
+        let half_plane =
+            |x: f32, z: f32| Mat4::from_scale_rotation_translation(vec3(1.0 / 7.0, 0.5 / 7.0, 1.0), Quat::IDENTITY, vec3(x, 0.0, z));
+        let spawns = [
+            EntitySpawn {
+                mesh: &plane,
+                mx_world: half_plane(-1.0, 0.0),
+                rotation_speed: 0.0,
+                color: wgpu::Color::WHITE,
+            },
+            EntitySpawn {
+                mesh: &plane,
+                mx_world: half_plane(1.0, 1.0),
+                rotation_speed: 0.0,
+                color: wgpu::Color::WHITE,
+            },
+        ];
+        let mut entities = Entities::from_spawns(&mut context, &spawns);
+        entities.update(&context);
+
+        let tooling_pass = create_tooling_pass(&mut context, &entities.entity_bind_group_layout);
+        let projection_view =
+            Mat4::orthographic_rh(-2.0, 2.0, -1.0, 1.0, 0.1, 10.0) * Mat4::look_at_rh(vec3(0.0, 0.0, 5.0), Vec3::ZERO, Vec3::Y);
+
+        let mut encoder = context
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        tooling_pass.record(&context, &mut encoder, &entities, &projection_view);
+        context.queue.submit(iter::once(encoder.finish()));
+
+        // the entity id, normal and depth targets at the surface size
+        let gbuffer = tooling_pass.gbuffer.borrow();
+        let formats: Vec<wgpu::TextureFormat> = gbuffer.targets.iter().map(|(texture, _)| texture.format()).collect();
+        assert_eq!(formats, vec![wgpu::TextureFormat::R32Uint, wgpu::TextureFormat::Rgba16Float]);
+        assert_eq!(tooling_pass.depth.0.format(), wgpu::TextureFormat::Depth32Float);
+        assert!(gbuffer
+            .targets
+            .iter()
+            .all(|(texture, _)| texture.size() == tooling_pass.depth.0.size()));
+
+        let ids: Vec<u32> = read_values(&context, gbuffer.get_texture(0), [0, 0], 8, 4);
+        #[rustfmt::skip]
+        let expected_ids: [u32; 32] = [
+            0, 0, 0, 0, 0, 0, 0, 0,
+            1, 1, 1, 1, 2, 2, 2, 2,
+            1, 1, 1, 1, 2, 2, 2, 2,
+            0, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        assert_eq!(ids, expected_ids);
+        assert_eq!(tooling_pass.pick(&context, 1, 1).unwrap(), Some(0));
+        assert_eq!(tooling_pass.pick(&context, 6, 2).unwrap(), Some(1));
+        assert_eq!(tooling_pass.pick(&context, 6, 3).unwrap(), None);
+
+        // half floats, +z normals with full coverage where an entity was drawn
+        let normals: Vec<u16> = read_values(&context, gbuffer.get_texture(1), [0, 1], 8, 1);
+        assert!(normals.chunks(4).all(|normal| normal == [0u16, 0, 0x3c00, 0x3c00]));
+
+        let depths: Vec<f32> = read_values(&context, &tooling_pass.depth.0, [0, 0], 8, 2);
+        assert!(depths[..8].iter().all(|depth| *depth == 1.0));
+        // the right plane is closer to the camera
+        assert!(depths[9] < 1.0 && depths[14] < depths[9]);
+    }
+}
@@ -0,0 +1,19 @@
+//!include "spark_gap/fullscreen.wgsl"
+
+// The previous frame, blended over the current one with the blend constant as its weight
+@group(0) @binding(0) var previous_frame: texture_2d<f32>;
+
+fn srgb_to_linear(color: vec3<f32>) -> vec3<f32> {
+    let low = color / 12.92;
+    let high = pow((color + 0.055) / 1.055, vec3<f32>(2.4));
+    return select(high, low, color <= vec3<f32>(0.04045));
+}
+
+@fragment fn fs_main(@builtin(position) position: vec4<f32>) -> @location(0) vec4<f32> {
+    var color = textureLoad(previous_frame, vec2<i32>(position.xy), 0).rgb;
+#ifdef DECODE_SRGB
+    // the history has the swapchain's non-srgb format, the frame is written through an srgb view
+    color = srgb_to_linear(color);
+#endif
+    return vec4<f32>(color, 1.0);
+}
@@ -0,0 +1,122 @@
+use std::borrow::Cow;
+use std::rc::Rc;
+
+use wgpu::{BindGroupLayout, RenderPipeline, TextureView};
+
+use spark_gap::bind_group::{BindGroupBuilder, LayoutBuilder};
+use spark_gap::error::Error;
+use spark_gap::frame_history::FrameHistory;
+use spark_gap::gpu_context::GpuContext;
+use spark_gap::pipeline_builder::PipelineBuilder;
+use spark_gap::render::RenderPassBuilder;
+use spark_gap::shader_preprocessor::{expand_embedded_includes, preprocess};
+
+// weight of the previous frame, higher leaves longer trails
+pub const DEFAULT_TRAIL_STRENGTH: f64 = 0.8;
+
+// previous * constant + current * (1 - constant)
+pub const TRAIL_BLEND: wgpu::BlendState = wgpu::BlendState {
+    color: wgpu::BlendComponent {
+        src_factor: wgpu::BlendFactor::Constant,
+        dst_factor: wgpu::BlendFactor::OneMinusConstant,
+        operation: wgpu::BlendOperation::Add,
+    },
+    alpha: wgpu::BlendComponent::REPLACE,
+};
+
+// Motion trails from the history of finished frames. Each frame blends the previous one over
+// itself before it is copied into the history, so the trails fade out exponentially.
+pub struct TrailPass {
+    pub strength: f64,
+    history: FrameHistory,
+    bind_group_layout: BindGroupLayout,
+    pipeline: Rc<RenderPipeline>,
+}
+
+impl TrailPass {
+    pub fn new(context: &mut GpuContext) -> Result<Self, Error> {
+        let history = FrameHistory::new(context)?;
+
+        let bind_group_layout = LayoutBuilder::new()
+            .texture(
+                wgpu::ShaderStages::FRAGMENT,
+                wgpu::TextureSampleType::Float { filterable: false },
+                wgpu::TextureViewDimension::D2,
+            )
+            .build(context, "trail bind group layout")?;
+
+        let view_format = context.config.view_formats[0];
+        let defines: &[&str] = match view_format != context.config.format {
+            true => &["DECODE_SRGB"],
+            false => &[],
+        };
+        let source = preprocess(include_str!("trail.wgsl"), defines)?;
+        let shader = context.device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("trail shader"),
+            source: wgpu::ShaderSource::Wgsl(Cow::Owned(expand_embedded_includes("trail.wgsl", &source, &[])?.source)),
+        });
+
+        // drawn straight into the single sampled frame
+        let builder = PipelineBuilder::new("vs_fullscreen", "fs_main")
+            .label("trail pipeline")
+            .bind_group_layout(&bind_group_layout)
+            .color_target(wgpu::ColorTargetState {
+                format: view_format,
+                blend: Some(TRAIL_BLEND),
+                write_mask: wgpu::ColorWrites::ALL,
+            })
+            .primitive(wgpu::PrimitiveState::default());
+        let pipeline = context.get_or_build_pipeline(&builder, &shader)?;
+
+        Ok(TrailPass {
+            strength: DEFAULT_TRAIL_STRENGTH,
+            history,
+            bind_group_layout,
+            pipeline,
+        })
+    }
+
+    // Blends the history over the finished frame, then copies the result into the history.
+    // Nothing is blended on the first frame and after a resize.
+    pub fn record(&mut self, context: &GpuContext, encoder: &mut wgpu::CommandEncoder, frame: &wgpu::Texture, frame_view: &TextureView) {
+        if let Some(previous_view) = self.history.previous_color_view() {
+            let bind_group =
+                BindGroupBuilder::new()
+                    .texture_view(&previous_view)
+                    .build(context, &self.bind_group_layout, "trail bind group");
+
+            let mut pass = RenderPassBuilder::new().label("trail").color(frame_view, None).begin(encoder);
+            pass.set_pipeline(&self.pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            pass.set_blend_constant(get_blend_constant(self.strength));
+            pass.draw(0..3, 0..1);
+        }
+
+        self.history.record_copy(encoder, frame);
+    }
+}
+
+pub fn get_blend_constant(strength: f64) -> wgpu::Color {
+    let strength = strength.clamp(0.0, 1.0);
+    wgpu::Color {
+        r: strength,
+        g: strength,
+        b: strength,
+        a: strength,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::trail_pass::get_blend_constant;
+
+    #[test]
+    fn test_blend_constant() {
+        assert_eq!(get_blend_constant(0.8).r, 0.8);
+        assert_eq!(get_blend_constant(0.8).a, 0.8);
+
+        // a weight above 1 would brighten the trails every frame
+        assert_eq!(get_blend_constant(1.5).g, 1.0);
+        assert_eq!(get_blend_constant(-1.0).b, 0.0);
+    }
+}
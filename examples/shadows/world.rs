@@ -1,166 +1,665 @@
-use std::{borrow::Cow, f32::consts, iter, mem};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::{borrow::Cow, iter};
 
-use glam::{vec3, Mat4, Vec3};
+#[cfg(feature = "text")]
+use glam::vec2;
+use glam::{vec3, Mat4, Vec3, Vec4};
 use wgpu::TextureView;
 
-use spark_gap::buffers::{update_mat4_buffer, update_u32_buffer};
+use spark_gap::buffers::{update_mat4_buffer, update_uniform_buffer};
+use spark_gap::camera::camera::{Camera, CameraController};
+use spark_gap::camera::orbit_controller::OrbitController;
+use spark_gap::camera::projection::get_aspect_ratio;
+use spark_gap::culling::{Aabb, Frustum};
+use spark_gap::error::Error;
+use spark_gap::frame_stats::FrameStats;
 use spark_gap::gpu_context::GpuContext;
-use spark_gap::texture::DEPTH_FORMAT;
+use spark_gap::input::Input;
+use spark_gap::material::{get_pbr_material_bind_group_layout, PbrMaterial};
+use spark_gap::point_shadow::CUBE_FACE_COUNT;
+use spark_gap::post::tonemap::{HdrTarget, TonemapPass, TonemapSettings};
+#[cfg(feature = "profiling")]
+use spark_gap::profiler::GpuTimer;
+use spark_gap::render::RenderPassBuilder;
+#[cfg(feature = "serde")]
+use spark_gap::scene_file::SceneFile;
+use spark_gap::shader_preprocessor::{expand_embedded_includes, preprocess, preprocess_with_constants};
+use spark_gap::shadow_cascades::{CascadeCamera, ShadowTextureArray};
+#[cfg(feature = "text")]
+use spark_gap::text::TextRenderer;
+use spark_gap::texture::{create_depth_texture, create_depth_texture_with_size, DepthTexture};
 
-use crate::cube::Vertex;
-use crate::debug_shadow::{create_shadow_map_material, shadow_render_debug, ShadowMaterial};
+use crate::debug_shadow::{create_shadow_map_material, get_debug_atlas_rect, shadow_render_debug, ShadowMaterial};
+use crate::deferred_pass::{create_deferred_pass, DeferredPass};
 use crate::entities::Entities;
-use crate::forward_pass::{create_forward_pass, ForwardPass};
-use crate::lights::Lights;
-use crate::shadow_pass::{create_shadow_pass, ShadowPass};
+use crate::forward_pass::{create_forward_pass, get_eye_position, CameraBindGroup, ForwardPass, ForwardPassOptions, MOTION_VECTOR_FORMAT};
+use crate::lights::{Lights, SceneLighting, MAX_LIGHTS, MAX_SHADOW_LAYERS};
+use crate::shadow_pass::{create_cascade_shadow_pass, create_point_shadow_pass, create_shadow_pass, ShadowPass, ShadowSettings};
+use crate::tooling_pass::{create_tooling_pass, ToolingPass};
+use crate::trail_pass::TrailPass;
+
+// linear, cleared into the hdr target before tonemapping
+pub const CLEAR_COLOR: wgpu::Color = wgpu::Color {
+    r: 0.1,
+    g: 0.2,
+    b: 0.3,
+    a: 1.0,
+};
+
+// a pass per shadow layer, per point shadow face and per viewport
+#[cfg(feature = "profiling")]
+pub const MAX_TIMED_PASSES: u32 = (MAX_SHADOW_LAYERS + CUBE_FACE_COUNT + 4) as u32;
+
+// hud text in pixels
+#[cfg(feature = "text")]
+pub const HUD_FONT_SIZE: f32 = 16.0;
+#[cfg(feature = "text")]
+pub const HUD_LINE_SPACING: f32 = 20.0;
+
+// x, y, width, height as fractions of the target
+pub const FULL_VIEWPORT: [f32; 4] = [0.0, 0.0, 1.0, 1.0];
+
+// A camera drawn into part of the target, like one side of a split screen
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CameraViewport {
+    pub camera_position: u32,
+    pub rect: [f32; 4],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RenderPath {
+    Forward,
+    // gbuffer geometry pass followed by a fullscreen lighting pass
+    Deferred,
+}
 
 pub struct World {
     pub entities: Entities,
     pub lights: Lights,
+    pub scene_lighting: SceneLighting,
     pub shadow_material: ShadowMaterial,
     pub shadow_pass: ShadowPass,
+    // renders the faces of lights.point_shadow
+    pub point_shadow_pass: ShadowPass,
+    pub cascade_shadow_pass: ShadowPass,
+    // the shadow pipelines are built with them, see set_shadow_settings
+    shadow_settings: ShadowSettings,
+    // the forward shader, whose vs_shadow shadow_pass draws with
+    shader: wgpu::ShaderModule,
     pub forward_pass: ForwardPass,
-    pub forward_depth: TextureView,
+    // the forward path's material for all entities, tinted by their colors
+    pub material: PbrMaterial,
+    // recreated by the context resize registry
+    pub forward_depth: Rc<RefCell<DepthTexture>>,
+    // the forward and deferred paths render into this, recreated by the resize registry
+    pub hdr_target: Rc<RefCell<HdrTarget>>,
+    // from hdr_target to the frame
+    pub tonemap_pass: TonemapPass,
+    // created by the first switch to the deferred path
+    pub deferred_pass: Option<DeferredPass>,
+    render_path: RenderPath,
+    // created by the first pick
+    pub tooling_pass: Option<ToolingPass>,
+    // blends the previous frames over the frame drawn by render(), None when disabled
+    pub trail_pass: Option<TrailPass>,
+    pub motion_vector_view: Option<TextureView>,
+    pub previous_projection_view: Mat4,
     pub show_shadows: bool,
+    // colors the forward path by the sampled albedo mip level
+    pub show_mip_levels: bool,
+    // the atlas has no defined contents until a pass clears it
+    shadow_atlas_cleared: bool,
     pub layer_number: u32,
+    // 0 looks through camera, 1 and 2 through the first two lights
     pub camera_position: u32,
+    pub camera: Camera,
+    pub camera_controller: OrbitController,
+    pub culling_enabled: bool,
+    // empty for a single camera covering the target
+    viewports: Vec<CameraViewport>,
+    // group 0 of each viewport after the first
+    viewport_cameras: Vec<CameraBindGroup>,
+    // times the shadow and forward passes of frames drawn by render()
+    #[cfg(feature = "profiling")]
+    pub gpu_timer: GpuTimer,
+    // draws the previous frame's stats over the frame
+    #[cfg(feature = "text")]
+    pub hud: TextRenderer,
+    #[cfg(feature = "text")]
+    hud_stats: FrameStats,
 }
 
 impl World {
     pub fn new(gpu_context: &mut GpuContext) -> Self {
         let entities = Entities::new(gpu_context);
+        let shadow_material = create_shadow_map_material(gpu_context);
+        let lights = Lights::new(gpu_context);
 
+        World::from_parts(gpu_context, entities, shadow_material, lights)
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn from_scene(gpu_context: &mut GpuContext, scene: &SceneFile) -> Result<Self, Error> {
+        let entities = Entities::from_scene(gpu_context, scene)?;
+        let shadow_material = create_shadow_map_material(gpu_context);
+        let lights = Lights::from_scene(gpu_context, scene)?;
+
+        Ok(World::from_parts(gpu_context, entities, shadow_material, lights))
+    }
+
+    fn from_parts(gpu_context: &mut GpuContext, entities: Entities, shadow_material: ShadowMaterial, lights: Lights) -> Self {
         let shader = gpu_context.device.create_shader_module(wgpu::ShaderModuleDescriptor {
             label: None,
-            source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(include_str!("shader.wgsl"))),
+            source: wgpu::ShaderSource::Wgsl(Cow::Owned(get_forward_shader_source(&[]))),
         });
 
-        let shadow_material = create_shadow_map_material(gpu_context);
+        let forward_depth = Rc::new(RefCell::new(create_depth_texture(gpu_context)));
+        gpu_context
+            .resize_registry
+            .register_resource(&forward_depth, |depth, context, width, height| {
+                *depth = create_depth_texture_with_size(context, width, height)
+            });
 
-        let lights = Lights::new(gpu_context, &shadow_material.texture);
+        let hdr_target = Rc::new(RefCell::new(HdrTarget::new(
+            gpu_context,
+            gpu_context.config.width,
+            gpu_context.config.height,
+        )));
+        gpu_context
+            .resize_registry
+            .register_resource(&hdr_target, |target, context, width, height| {
+                *target = HdrTarget::new(context, width, height)
+            });
+
+        let surface_format = gpu_context.config.view_formats[0];
+        let tonemap_pass = TonemapPass::new(gpu_context, surface_format, &hdr_target.borrow().view, TonemapSettings::default());
 
-        let forward_depth = create_depth_texture(gpu_context);
+        let scene_lighting = SceneLighting::default();
 
-        let shadow_pass = create_shadow_pass(gpu_context, &lights, &entities.entity_bind_group_layout, &shader);
+        let shadow_settings = ShadowSettings::default();
+
+        let shadow_pass = create_shadow_pass(
+            gpu_context,
+            &lights,
+            &entities.entity_bind_group_layout,
+            &shader,
+            &shadow_settings,
+        );
+        let point_shadow_pass = create_point_shadow_pass(
+            gpu_context,
+            &lights.point_shadow,
+            &entities.entity_bind_group_layout,
+            &shadow_settings,
+        );
+        let cascade_shadow_pass =
+            create_cascade_shadow_pass(gpu_context, &lights.cascades, &entities.entity_bind_group_layout, &shadow_settings);
+
+        let material = PbrMaterial::new(gpu_context, Vec4::ONE, 0.0, 0.5);
+        let material_bind_group_layout = get_pbr_material_bind_group_layout(gpu_context);
 
         let forward_pass = create_forward_pass(
             gpu_context,
             &entities.entity_bind_group_layout,
+            &material_bind_group_layout,
             &lights,
             &shader,
             &shadow_material.texture,
+            &scene_lighting,
+            ForwardPassOptions::default(),
         );
 
+        let motion_vector_view = forward_pass
+            .options
+            .motion_vectors
+            .then(|| create_motion_vector_texture(gpu_context));
+
+        let mut camera = get_default_camera();
+        let camera_controller = OrbitController::new(&mut camera, Vec3::ZERO);
+
+        let previous_projection_view =
+            get_projection_view_matrix(gpu_context.config.width as f32 / gpu_context.config.height as f32);
+
         World {
             entities,
             lights,
+            scene_lighting,
             shadow_material,
             shadow_pass,
+            point_shadow_pass,
+            cascade_shadow_pass,
+            shadow_settings,
+            shader,
             forward_pass,
+            material,
             forward_depth,
+            hdr_target,
+            tonemap_pass,
+            deferred_pass: None,
+            render_path: RenderPath::Forward,
+            tooling_pass: None,
+            trail_pass: None,
+            motion_vector_view,
+            previous_projection_view,
             show_shadows: false,
+            show_mip_levels: false,
+            shadow_atlas_cleared: false,
             layer_number: 0,
             camera_position: 0,
+            camera,
+            camera_controller,
+            culling_enabled: true,
+            viewports: vec![],
+            viewport_cameras: vec![],
+            #[cfg(feature = "profiling")]
+            gpu_timer: GpuTimer::new(gpu_context, MAX_TIMED_PASSES),
+            #[cfg(feature = "text")]
+            hud: TextRenderer::new(gpu_context, surface_format),
+            #[cfg(feature = "text")]
+            hud_stats: FrameStats::new(),
         }
     }
 
-    pub fn render(&mut self, context: &GpuContext) {
-        self.entities.update(context);
-        self.lights.update(context);
+    pub fn get_render_path(&self) -> RenderPath {
+        self.render_path
+    }
+
+    pub fn set_render_path(&mut self, context: &mut GpuContext, render_path: RenderPath) {
+        if render_path == RenderPath::Deferred && self.deferred_pass.is_none() {
+            let mut deferred_pass = create_deferred_pass(context, &self.forward_pass, &self.entities.entity_bind_group_layout);
+            deferred_pass.set_viewport_count(context, self.get_viewports().len());
+            self.deferred_pass = Some(deferred_pass);
+        }
+        self.render_path = render_path;
+    }
+
+    pub fn get_shadow_settings(&self) -> ShadowSettings {
+        self.shadow_settings
+    }
+
+    // Rebuilds the shadow passes, their pipelines have the settings baked in
+    pub fn set_shadow_settings(&mut self, context: &mut GpuContext, settings: ShadowSettings) {
+        let entity_layout = &self.entities.entity_bind_group_layout;
+        self.shadow_pass = create_shadow_pass(context, &self.lights, entity_layout, &self.shader, &settings);
+        self.point_shadow_pass = create_point_shadow_pass(context, &self.lights.point_shadow, entity_layout, &settings);
+        self.cascade_shadow_pass = create_cascade_shadow_pass(context, &self.lights.cascades, entity_layout, &settings);
+        self.shadow_settings = settings;
+    }
+
+    pub fn is_trails_enabled(&self) -> bool {
+        self.trail_pass.is_some()
+    }
+
+    // Copying frames needs COPY_SRC on the surface, the trails stay off where it's unsupported
+    pub fn set_trails_enabled(&mut self, context: &mut GpuContext, enabled: bool) {
+        self.trail_pass = match enabled {
+            true => match TrailPass::new(context) {
+                Ok(trail_pass) => Some(trail_pass),
+                Err(error) => {
+                    log::warn!("motion trails unavailable: {:?}", error);
+                    None
+                }
+            },
+            false => None,
+        };
+    }
+
+    // The index of the entity under the pixel, None for the background and an empty target, e.g.
+    // while the window is minimized. Draws the tooling pass with the first viewport's camera over
+    // the whole target and waits for it.
+    pub fn pick_entity(&mut self, context: &mut GpuContext, x: u32, y: u32) -> Result<Option<usize>, Error> {
+        if context.config.width == 0 || context.config.height == 0 {
+            return Ok(None);
+        }
+        let aspect_ratio = get_aspect_ratio(context.config.width, context.config.height);
+        let projection_view = self.get_camera_projection_view(self.get_viewports()[0].camera_position, aspect_ratio);
+
+        let tooling_pass = self
+            .tooling_pass
+            .get_or_insert_with(|| create_tooling_pass(context, &self.entities.entity_bind_group_layout));
+
+        let mut encoder = context
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: Some("picking") });
+        tooling_pass.record(context, &mut encoder, &self.entities, &projection_view);
+        context.queue.submit(iter::once(encoder.finish()));
+
+        let x = x.min(context.config.width - 1);
+        let y = y.min(context.config.height - 1);
+        tooling_pass.pick(context, x, y)
+    }
+
+    // Moves the camera with the frame's input, the cascades follow it
+    pub fn update_camera(&mut self, context: &GpuContext, input: &Input, delta_time: f32) {
+        self.camera_controller.update(&mut self.camera, input, delta_time);
+
+        let aspect_ratio = get_aspect_ratio(context.config.width, context.config.height);
+        let cascade_camera = get_cascade_camera(&self.camera, aspect_ratio);
+        if cascade_camera != self.lights.camera {
+            self.lights.set_camera(cascade_camera);
+        }
+    }
+
+    // None when the frame was skipped because the surface wasn't available
+    pub fn render(&mut self, context: &GpuContext) -> Option<FrameStats> {
+        self.render_with_overlay(context, |_, _, _| {})
+    }
+
+    // Like render, overlay records passes over the finished frame, e.g. a ui
+    pub fn render_with_overlay(
+        &mut self,
+        context: &GpuContext,
+        overlay: impl FnOnce(&GpuContext, &mut wgpu::CommandEncoder, &TextureView),
+    ) -> Option<FrameStats> {
+        let start_instant = web_time::Instant::now();
+
+        let Some(frame) = context.acquire_frame_or_skip() else {
+            return None;
+        };
+
+        let frame_view = frame.texture().create_view(&wgpu::TextureViewDescriptor::default());
 
         let mut encoder = context
             .device
             .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
 
-        // shadow pass
-        encoder.push_debug_group("shadow pass");
-
-        for (i, light) in self.lights.lights.iter().enumerate() {
-            let i = i as u32;
-
-            encoder.push_debug_group(&format!("shadow pass {} (light at position {:?})", i, light.position));
-
-            encoder.insert_debug_marker("render entities");
-            {
-                let depth_stencil_attachment = wgpu::RenderPassDepthStencilAttachment {
-                    view: &light.shadow_view,
-                    depth_ops: Some(wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(1.0),
-                        store: wgpu::StoreOp::Store,
-                    }),
-                    stencil_ops: None,
-                };
-
-                let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                    label: None,
-                    color_attachments: &[],
-                    depth_stencil_attachment: Some(depth_stencil_attachment),
-                    timestamp_writes: None,
-                    occlusion_query_set: None,
-                });
-
-                pass.set_pipeline(&self.shadow_pass.pipeline);
-                pass.set_bind_group(0, &self.shadow_pass.bind_group, &[]);
-
-                for entity in &self.entities.entities {
-                    pass.set_bind_group(1, &self.entities.entity_bind_group, &[entity.uniform_offset]);
-
-                    pass.set_vertex_buffer(0, entity.vertex_buf.slice(..));
-                    pass.set_index_buffer(entity.index_buf.slice(..), entity.index_format);
-
-                    // the instance id is used as an index into the array of lights in the shader to
-                    // get the projection view to use for the current light when writing to the light's shadow_view
-                    pass.draw_indexed(0..entity.index_count as u32, 0, i..(i + 1));
+        let mut stats = self.record(context, &mut encoder, &frame_view);
+
+        // the hud and overlay are drawn after the copy so they leave no trails
+        if let Some(trail_pass) = &mut self.trail_pass {
+            trail_pass.record(context, &mut encoder, frame.texture(), &frame_view);
+        }
+
+        #[cfg(feature = "text")]
+        self.record_hud(context, &mut encoder, &frame_view);
+        overlay(context, &mut encoder, &frame_view);
+
+        #[cfg(feature = "profiling")]
+        self.gpu_timer.resolve(&mut encoder);
+
+        context.queue.submit(iter::once(encoder.finish()));
+        frame.present();
+
+        #[cfg(feature = "profiling")]
+        if self.gpu_timer.is_enabled() {
+            let timings = self.gpu_timer.read_results(context);
+            for (label, ms) in &timings {
+                log::debug!("{}: {:.3}ms", label, ms);
+            }
+            stats.gpu_ms = Some(timings.iter().map(|(_, ms)| ms).sum());
+        }
+
+        stats.cpu_ms = start_instant.elapsed().as_secs_f32() * 1000.0;
+        #[cfg(feature = "text")]
+        {
+            self.hud_stats = stats;
+        }
+        Some(stats)
+    }
+
+    // Records the frame into the caller's encoder for embedding in a larger frame. Nothing is
+    // submitted or presented, the uniforms are written through the queue so they are in place
+    // when the caller submits.
+    pub fn record(&mut self, context: &GpuContext, encoder: &mut wgpu::CommandEncoder, target_view: &TextureView) -> FrameStats {
+        let mut stats = FrameStats::new();
+
+        self.entities.update(context);
+        self.lights.update(context);
+        update_uniform_buffer(
+            context,
+            &self.forward_pass.ambient_buffer,
+            &[self.scene_lighting.get_ambient_uniform()],
+        );
+
+        let frame_passes = self.get_current_frame_passes();
+
+        let hdr_target = self.hdr_target.clone();
+        let hdr_target = hdr_target.borrow();
+        let hdr_view = &hdr_target.view;
+
+        for frame_pass in frame_passes {
+            match frame_pass {
+                FramePass::ClearShadowAtlas => self.record_clear_shadow_atlas(encoder),
+                FramePass::Shadow(layer_index) => self.record_shadow_pass(encoder, layer_index, &mut stats),
+                FramePass::PointShadow(face) => self.record_point_shadow_pass(encoder, face, &mut stats),
+                FramePass::CascadeShadow(cascade) => self.record_cascade_shadow_pass(encoder, cascade, &mut stats),
+                FramePass::Forward(viewport_index) => self.record_forward_pass(context, encoder, hdr_view, viewport_index, &mut stats),
+                FramePass::ShadowMapDebug => self.record_shadow_map_debug(context, encoder, target_view, &mut stats),
+                FramePass::GBuffer(viewport_index) => self.record_gbuffer_pass(context, encoder, viewport_index, &mut stats),
+                FramePass::DeferredLighting(viewport_index) => {
+                    self.record_deferred_lighting_pass(context, encoder, hdr_view, viewport_index, &mut stats)
                 }
+                FramePass::Tonemap => self.tonemap_pass.render(context, encoder, target_view),
             }
-            encoder.pop_debug_group();
+        }
+
+        // either the first shadow pass or the explicit clear pass cleared the whole atlas
+        self.shadow_atlas_cleared |= !self.lights.shadow_layers.is_empty() || self.show_shadows;
+
+        stats
+    }
+
+    // The passes the next record() encodes
+    pub fn get_current_frame_passes(&self) -> Vec<FramePass> {
+        get_frame_passes(
+            self.lights.shadow_layers.len(),
+            self.show_shadows,
+            self.render_path,
+            self.shadow_atlas_cleared,
+            self.get_viewports().len(),
+            self.lights.get_point_light().is_some(),
+            self.get_cascade_count(),
+        )
+    }
+
+    #[cfg(feature = "text")]
+    fn record_hud(&mut self, context: &GpuContext, encoder: &mut wgpu::CommandEncoder, target_view: &TextureView) {
+        for (i, line) in get_hud_lines(&self.hud_stats).iter().enumerate() {
+            self.hud
+                .queue(line, vec2(10.0, 10.0 + i as f32 * HUD_LINE_SPACING), HUD_FONT_SIZE, Vec4::ONE);
+        }
+        if let Err(error) = self.hud.record_overlay_pass(context, encoder, target_view) {
+            log::warn!("hud: {:?}", error);
+        }
+    }
+
+    fn record_clear_shadow_atlas(&self, encoder: &mut wgpu::CommandEncoder) {
+        RenderPassBuilder::new()
+            .label("clear shadow atlas")
+            .depth(&self.shadow_material.texture_view, Some(1.0))
+            .keep_depth()
+            .begin(encoder);
+    }
+
+    fn record_shadow_pass(&self, encoder: &mut wgpu::CommandEncoder, layer_index: u32, stats: &mut FrameStats) {
+        let layer = &self.lights.shadow_layers[layer_index as usize];
+        let i = layer_index;
+
+        encoder.push_debug_group(&format!("shadow pass {} (atlas rect {:?})", i, layer.atlas_rect));
+
+        encoder.insert_debug_marker("render entities");
+        stats.record_shadow_pass();
+        {
+            // every layer renders into its own rect of the atlas, so only the first pass clears
+            let clear = if i == 0 { Some(1.0) } else { None };
+            let builder = RenderPassBuilder::new()
+                .depth(&self.shadow_material.texture_view, clear)
+                .keep_depth();
+
+            #[allow(unused_mut)]
+            let mut descriptor = builder.descriptor();
+            #[cfg(feature = "profiling")]
+            self.gpu_timer.begin(&mut descriptor, &format!("shadow {}", i));
+
+            let mut pass = encoder.begin_render_pass(&descriptor);
+
+            let rect = &layer.atlas_rect;
+            pass.set_viewport(rect.x as f32, rect.y as f32, rect.size as f32, rect.size as f32, 0.0, 1.0);
+            pass.set_scissor_rect(rect.x, rect.y, rect.size, rect.size);
+
+            pass.set_pipeline(&self.shadow_pass.pipeline);
+            pass.set_bind_group(0, &self.shadow_pass.bind_group, &[]);
+
+            // the instance id is used as an index into the array of shadow layers in the shader to
+            // get the projection view to use for the current layer when writing to the layer's atlas rect
+            self.draw_shadow_casters(&mut pass, &layer.projection_view, i, stats);
         }
         encoder.pop_debug_group();
+    }
 
-        // forward pass
-        encoder.push_debug_group("forward rendering pass");
+    fn record_point_shadow_pass(&self, encoder: &mut wgpu::CommandEncoder, face: u32, stats: &mut FrameStats) {
+        let point_shadow = &self.lights.point_shadow;
+        let projection_view = &point_shadow.face_projection_views[face as usize];
+        let target = ShadowLayerTarget {
+            name: "point shadow",
+            shadow_maps: &point_shadow.shadow_maps,
+            shadow_pass: &self.point_shadow_pass,
+        };
+        self.record_shadow_layer_pass(encoder, &target, face, projection_view, stats);
+    }
 
-        let frame = context
-            .surface
-            .get_current_texture()
-            .expect("Failed to acquire next swap chain texture");
+    fn record_cascade_shadow_pass(&self, encoder: &mut wgpu::CommandEncoder, cascade: u32, stats: &mut FrameStats) {
+        let cascades = &self.lights.cascades;
+        let projection_view = &cascades.cascades[cascade as usize].projection_view;
+        let target = ShadowLayerTarget {
+            name: "cascade shadow",
+            shadow_maps: &cascades.shadow_maps,
+            shadow_pass: &self.cascade_shadow_pass,
+        };
+        self.record_shadow_layer_pass(encoder, &target, cascade, projection_view, stats);
+    }
 
-        let frame_view = frame.texture.create_view(&wgpu::TextureViewDescriptor::default());
+    // Like a shadow layer, with the layer's view of the array as the whole target. The instance id
+    // selects the layer's projection view in the shader.
+    fn record_shadow_layer_pass(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        target: &ShadowLayerTarget,
+        layer: u32,
+        projection_view: &Mat4,
+        stats: &mut FrameStats,
+    ) {
+        encoder.push_debug_group(&format!("{} pass {}", target.name, layer));
 
+        encoder.insert_debug_marker("render entities");
+        stats.record_shadow_pass();
         {
-            let color_attachment = wgpu::RenderPassColorAttachment {
-                view: &frame_view,
-                resolve_target: None,
-                ops: wgpu::Operations {
-                    load: wgpu::LoadOp::Clear(wgpu::Color {
-                        r: 0.1,
-                        g: 0.2,
-                        b: 0.3,
-                        a: 1.0,
-                    }),
-                    store: wgpu::StoreOp::Store,
-                },
-            };
+            let builder = RenderPassBuilder::new()
+                .depth(&target.shadow_maps.layer_views[layer as usize], Some(1.0))
+                .keep_depth();
+
+            #[allow(unused_mut)]
+            let mut descriptor = builder.descriptor();
+            #[cfg(feature = "profiling")]
+            self.gpu_timer.begin(&mut descriptor, &format!("{} {}", target.name, layer));
+
+            let mut pass = encoder.begin_render_pass(&descriptor);
+
+            pass.set_pipeline(&target.shadow_pass.pipeline);
+            pass.set_bind_group(0, &target.shadow_pass.bind_group, &[]);
+
+            self.draw_shadow_casters(&mut pass, projection_view, layer, stats);
+        }
+        encoder.pop_debug_group();
+    }
+
+    // The directional light's cascades, none without a directional light
+    fn get_cascade_count(&self) -> usize {
+        match self.lights.get_directional_light() {
+            Some(_) => self.lights.cascades.cascades.len(),
+            None => 0,
+        }
+    }
+
+    // The entities that can cast into the projection, group 0 and the pipeline are already set
+    fn draw_shadow_casters<'a>(&'a self, pass: &mut wgpu::RenderPass<'a>, projection_view: &Mat4, instance: u32, stats: &mut FrameStats) {
+        let frustum = Frustum::from_matrix(projection_view).without_near_plane();
+        for entity in &self.entities.entities {
+            if is_culled(self.culling_enabled, &frustum, &entity.get_bounding_box()) {
+                continue;
+            }
+            pass.set_bind_group(1, &self.entities.entity_bind_group, &[entity.uniform_offset]);
+
+            pass.set_vertex_buffer(0, entity.vertex_buf.slice(..));
+            pass.set_index_buffer(entity.index_buf.slice(..), entity.index_format);
+
+            pass.draw_indexed(0..entity.index_count as u32, 0, instance..(instance + 1));
+            stats.record_draw(entity.index_count as u32, 1);
+        }
+    }
+
+    fn record_forward_pass(
+        &mut self,
+        context: &GpuContext,
+        encoder: &mut wgpu::CommandEncoder,
+        target_view: &TextureView,
+        viewport_index: u32,
+        stats: &mut FrameStats,
+    ) {
+        let viewport = self.get_viewports()[viewport_index as usize];
+        let Some([x, y, width, height]) = get_viewport_pixels(&viewport.rect, context.config.width, context.config.height) else {
+            return;
+        };
 
-            let depth_stencil_attachment = wgpu::RenderPassDepthStencilAttachment {
-                view: &self.forward_depth,
-                depth_ops: Some(wgpu::Operations {
-                    load: wgpu::LoadOp::Clear(1.0),
-                    store: wgpu::StoreOp::Discard,
-                }),
-                stencil_ops: None,
-            }; 
-            let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                label: None,
-                color_attachments: &[Some(color_attachment)],
-                depth_stencil_attachment: Some(depth_stencil_attachment),
-                timestamp_writes: None,
-                occlusion_query_set: None,
+        encoder.push_debug_group(&format!("forward rendering pass {}", viewport_index));
+
+        let pv = self.get_camera_projection_view(viewport.camera_position, width as f32 / height as f32);
+
+        // the first viewport clears the whole target, the others draw over it
+        let first = viewport_index == 0;
+
+        {
+            let forward_depth = self.forward_depth.borrow();
+            let mut builder = RenderPassBuilder::new()
+                .color(target_view, first.then_some(CLEAR_COLOR))
+                .depth(&forward_depth.view, Some(1.0));
+
+            if let Some(motion_vector_view) = &self.motion_vector_view {
+                builder = builder.color(motion_vector_view, first.then_some(wgpu::Color::TRANSPARENT));
+            }
+
+            #[allow(unused_mut)]
+            let mut descriptor = builder.descriptor();
+            #[cfg(feature = "profiling")]
+            self.gpu_timer.begin(&mut descriptor, &format!("forward {}", viewport_index));
+
+            let mut pass = encoder.begin_render_pass(&descriptor);
+
+            pass.set_viewport(x as f32, y as f32, width as f32, height as f32, 0.0, 1.0);
+            pass.set_scissor_rect(x, y, width, height);
+
+            let camera_bind_group = self.update_camera_buffers(context, viewport_index, &pv);
+
+            pass.set_pipeline(match self.show_mip_levels {
+                true => &self.forward_pass.mip_debug_pipeline,
+                false => &self.forward_pass.pipeline,
             });
+            pass.set_bind_group(0, camera_bind_group, &[]);
+            pass.set_bind_group(2, &self.material.bind_group, &[]);
+            self.draw_entities(&mut pass, &pv, stats);
+        }
+        encoder.pop_debug_group();
+
+        self.set_previous_projection_view(viewport_index, pv);
+    }
 
-            let width = context.config.width as f32 / 2.0;
-            let height = context.config.height as f32 / 2.0;
-            let aspect_ratio = width / height;
+    fn record_shadow_map_debug(
+        &self,
+        context: &GpuContext,
+        encoder: &mut wgpu::CommandEncoder,
+        target_view: &TextureView,
+        stats: &mut FrameStats,
+    ) {
+        encoder.push_debug_group("shadow map debug pass");
+        let width = context.config.width as f32 / 2.0;
+        let height = context.config.height as f32 / 2.0;
+
+        {
+            let forward_depth = self.forward_depth.borrow();
+            let mut pass = RenderPassBuilder::new()
+                .color(target_view, Some(self.shadow_material.clear_color))
+                .depth(&forward_depth.view, Some(1.0))
+                .begin(encoder);
 
             let orthographic_projection = Mat4::orthographic_rh(-width, width, -height, height, 0.1, 1000.0);
             let view = Mat4::look_at_rh(vec3(0.0, 0.0001, 200.0), vec3(0.0, 0.0, 0.0), vec3(0.0, 0.0, 1.0));
@@ -168,84 +667,392 @@ impl World {
             let project_view_matrix = orthographic_projection * view;
 
             update_mat4_buffer(context, &self.shadow_material.projection_view_buffer, &project_view_matrix);
-            update_u32_buffer(context, &self.shadow_material.layer_num_buffer, &self.layer_number);
-           
-            let pv = match &self.camera_position {
-                0 => get_projection_view_matrix(aspect_ratio),
-                1 => self.lights.lights[0].projection_view,
-                2 => self.lights.lights[1].projection_view,
-                _ => Mat4::IDENTITY,
-            };
+            let atlas_rect = get_debug_atlas_rect(&self.lights.shadow_layers, self.layer_number);
+            update_uniform_buffer(context, &self.shadow_material.atlas_rect_buffer, &[atlas_rect]);
 
-            update_mat4_buffer(context, &self.forward_pass.projection_view_buffer, &pv);
+            // display shadow map
+            pass.set_pipeline(&self.shadow_material.shadow_debug_pipeline);
+            pass = shadow_render_debug(pass, &self.shadow_material);
+            stats.record_draw(self.shadow_material.quad_mesh.num_elements, 1);
+        }
+        encoder.pop_debug_group();
+    }
 
-            if self.show_shadows == true {
-                // display shadow map
-                pass.set_pipeline(&self.shadow_material.shadow_debug_pipeline);
-                pass = shadow_render_debug(pass, &self.shadow_material);
-            } else {
-                // forward pass
-                pass.set_pipeline(&self.forward_pass.pipeline);
-                pass.set_bind_group(0, &self.forward_pass.bind_group, &[]);
+    // Like the forward passes, the first viewport clears the whole gbuffer and the others draw into their rect
+    fn record_gbuffer_pass(
+        &mut self,
+        context: &GpuContext,
+        encoder: &mut wgpu::CommandEncoder,
+        viewport_index: u32,
+        stats: &mut FrameStats,
+    ) {
+        let viewport = self.get_viewports()[viewport_index as usize];
+        let Some([x, y, width, height]) = get_viewport_pixels(&viewport.rect, context.config.width, context.config.height) else {
+            return;
+        };
 
-                for entity in &self.entities.entities {
-                    pass.set_bind_group(1, &self.entities.entity_bind_group, &[entity.uniform_offset]);
+        let deferred_pass = self.deferred_pass.as_ref().expect("set_render_path creates the deferred pass");
+        let pv = self.get_camera_projection_view(viewport.camera_position, width as f32 / height as f32);
+        let camera_bind_group = self.update_camera_buffers(context, viewport_index, &pv);
+        let deferred_viewport = &deferred_pass.viewports[viewport_index as usize];
+        update_mat4_buffer(context, &deferred_viewport.inverse_projection_view_buffer, &pv.inverse());
 
-                    pass.set_vertex_buffer(0, entity.vertex_buf.slice(..));
-                    pass.set_index_buffer(entity.index_buf.slice(..), entity.index_format);
+        let first = viewport_index == 0;
 
-                    pass.draw_indexed(0..entity.index_count as u32, 0, 0..1);
-                }
-            }
+        encoder.push_debug_group(&format!("gbuffer pass {}", viewport_index));
+        {
+            // depth is read back by the lighting pass
+            let gbuffer = deferred_pass.gbuffer.borrow();
+            let mut pass = gbuffer
+                .get_pass_builder("gbuffer", first.then_some(wgpu::Color::TRANSPARENT))
+                .depth(&deferred_pass.depth_view, first.then_some(1.0))
+                .keep_depth()
+                .begin(encoder);
+
+            pass.set_viewport(x as f32, y as f32, width as f32, height as f32, 0.0, 1.0);
+            pass.set_scissor_rect(x, y, width, height);
+
+            pass.set_pipeline(&deferred_pass.geometry_pipeline);
+            pass.set_bind_group(0, camera_bind_group, &[]);
+            self.draw_entities(&mut pass, &pv, stats);
         }
         encoder.pop_debug_group();
 
-        context.queue.submit(iter::once(encoder.finish()));
-        frame.present();
+        self.set_previous_projection_view(viewport_index, pv);
+    }
+
+    // The fullscreen triangle covers the viewport's rect, so its uvs are relative to the viewport
+    // like the projection used to reconstruct world positions
+    fn record_deferred_lighting_pass(
+        &self,
+        context: &GpuContext,
+        encoder: &mut wgpu::CommandEncoder,
+        target_view: &TextureView,
+        viewport_index: u32,
+        stats: &mut FrameStats,
+    ) {
+        let viewport = self.get_viewports()[viewport_index as usize];
+        let Some([x, y, width, height]) = get_viewport_pixels(&viewport.rect, context.config.width, context.config.height) else {
+            return;
+        };
+
+        let deferred_pass = self.deferred_pass.as_ref().expect("set_render_path creates the deferred pass");
+        let first = viewport_index == 0;
+
+        let mut pass = RenderPassBuilder::new()
+            .label("deferred lighting")
+            .color(target_view, first.then_some(CLEAR_COLOR))
+            .begin(encoder);
+
+        pass.set_viewport(x as f32, y as f32, width as f32, height as f32, 0.0, 1.0);
+        pass.set_scissor_rect(x, y, width, height);
+
+        pass.set_pipeline(&deferred_pass.lighting_pipeline);
+        pass.set_bind_group(0, self.get_camera_bind_group(viewport_index), &[]);
+        pass.set_bind_group(1, &self.entities.entity_bind_group, &[0]);
+        pass.set_bind_group(2, &deferred_pass.viewports[viewport_index as usize].gbuffer_bind_group, &[]);
+        pass.draw(0..3, 0..1);
+        stats.record_draw(3, 1);
+    }
+
+    // The configured viewports, or the full target seen from camera_position
+    pub fn get_viewports(&self) -> Vec<CameraViewport> {
+        match self.viewports.is_empty() {
+            true => vec![CameraViewport {
+                camera_position: self.camera_position,
+                rect: FULL_VIEWPORT,
+            }],
+            false => self.viewports.clone(),
+        }
+    }
+
+    // Every viewport is drawn with the same shadow maps, rendered once per frame
+    pub fn set_viewports(&mut self, context: &GpuContext, viewports: Vec<CameraViewport>) {
+        let camera_count = viewports.len().saturating_sub(1);
+        self.viewport_cameras.truncate(camera_count);
+        while self.viewport_cameras.len() < camera_count {
+            let camera = self.forward_pass.create_camera_bind_group(context, &self.lights);
+            self.viewport_cameras.push(camera);
+        }
+        if let Some(deferred_pass) = &mut self.deferred_pass {
+            deferred_pass.set_viewport_count(context, viewports.len());
+        }
+        self.viewports = viewports;
+    }
+
+    fn get_camera_projection_view(&self, camera_position: u32, aspect_ratio: f32) -> Mat4 {
+        match camera_position {
+            0 => self.camera.projection_view(aspect_ratio),
+            1 => self.lights.get_light_view(0),
+            2 => self.lights.get_light_view(1),
+            _ => Mat4::IDENTITY,
+        }
+    }
+
+    // Writes the camera uniforms of the viewport and returns its group 0. The first viewport uses
+    // the forward pass's own bind group.
+    fn update_camera_buffers(&self, context: &GpuContext, viewport_index: u32, pv: &Mat4) -> &wgpu::BindGroup {
+        let (bind_group, projection_view_buffer, previous_projection_view_buffer, eye_position_buffer, previous_projection_view) =
+            match viewport_index {
+                0 => (
+                    &self.forward_pass.bind_group,
+                    &self.forward_pass.projection_view_buffer,
+                    &self.forward_pass.previous_projection_view_buffer,
+                    &self.forward_pass.eye_position_buffer,
+                    &self.previous_projection_view,
+                ),
+                _ => {
+                    let camera = &self.viewport_cameras[viewport_index as usize - 1];
+                    (
+                        &camera.bind_group,
+                        &camera.projection_view_buffer,
+                        &camera.previous_projection_view_buffer,
+                        &camera.eye_position_buffer,
+                        &camera.previous_projection_view,
+                    )
+                }
+            };
+
+        update_mat4_buffer(context, projection_view_buffer, pv);
+        update_mat4_buffer(context, previous_projection_view_buffer, previous_projection_view);
+        update_uniform_buffer(context, eye_position_buffer, &[get_eye_position(pv)]);
+        bind_group
+    }
+
+    // The viewport's camera uniforms, written by its forward or gbuffer pass earlier in the frame
+    fn get_camera_bind_group(&self, viewport_index: u32) -> &wgpu::BindGroup {
+        match viewport_index {
+            0 => &self.forward_pass.bind_group,
+            _ => &self.viewport_cameras[viewport_index as usize - 1].bind_group,
+        }
+    }
+
+    fn set_previous_projection_view(&mut self, viewport_index: u32, pv: Mat4) {
+        match viewport_index {
+            0 => self.previous_projection_view = pv,
+            _ => self.viewport_cameras[viewport_index as usize - 1].previous_projection_view = pv,
+        }
+    }
+
+    // Frustum culled entities in draw order, the pipeline and group 0 are already set
+    fn draw_entities<'a>(&'a self, pass: &mut wgpu::RenderPass<'a>, pv: &Mat4, stats: &mut FrameStats) {
+        let frustum = Frustum::from_matrix(pv);
+        let draw_order = self.entities.get_draw_order();
+        let visible = cull_entities(
+            self.culling_enabled,
+            &frustum,
+            draw_order,
+            |index| self.entities.entities[index].get_bounding_box(),
+            stats,
+        );
+
+        for index in visible {
+            let entity = &self.entities.entities[index];
+            pass.set_bind_group(1, &self.entities.entity_bind_group, &[entity.uniform_offset]);
+
+            pass.set_vertex_buffer(0, entity.vertex_buf.slice(..));
+            pass.set_index_buffer(entity.index_buf.slice(..), entity.index_format);
+
+            pass.draw_indexed(0..entity.index_count as u32, 0, 0..1);
+            stats.record_draw(entity.index_count as u32, 1);
+        }
     }
 
     pub fn resize(&mut self, gpu_context: &GpuContext) {
-        let mx_total = get_projection_view_matrix(gpu_context.config.width as f32 / gpu_context.config.height as f32);
+        let aspect_ratio = gpu_context.config.width as f32 / gpu_context.config.height as f32;
+        self.lights.set_camera(get_cascade_camera(&self.camera, aspect_ratio));
+
+        let mx_total = self.camera.projection_view(aspect_ratio);
         let mx_ref: &[f32; 16] = mx_total.as_ref();
 
         gpu_context
             .queue
             .write_buffer(&self.forward_pass.projection_view_buffer, 0, bytemuck::cast_slice(mx_ref));
 
-        self.forward_depth = create_depth_texture(gpu_context);
+        if self.motion_vector_view.is_some() {
+            self.motion_vector_view = Some(create_motion_vector_texture(gpu_context));
+        }
+
+        if let Some(deferred_pass) = &mut self.deferred_pass {
+            deferred_pass.resize(gpu_context);
+        }
+
+        if let Some(tooling_pass) = &mut self.tooling_pass {
+            tooling_pass.resize(gpu_context);
+        }
+
+        self.tonemap_pass.set_input(gpu_context, &self.hdr_target.borrow().view);
+
+        #[cfg(feature = "text")]
+        self.hud.resize(gpu_context);
     }
 }
 
-pub fn get_vertex_buffer_layout() -> wgpu::VertexBufferLayout<'static> {
-    wgpu::VertexBufferLayout {
-        array_stride: mem::size_of::<Vertex>() as wgpu::BufferAddress,
-        step_mode: wgpu::VertexStepMode::Vertex,
-        attributes: &[
-            // vertices
-            wgpu::VertexAttribute {
-                shader_location: 0,
-                format: wgpu::VertexFormat::Sint8x4,
-                offset: 0,
-            },
-            // tex coords
-            wgpu::VertexAttribute {
-                shader_location: 1,
-                format: wgpu::VertexFormat::Sint8x4,
-                // Sint8x4 is four signed bytes (i8). vec4<i32> in shaders
-                offset: mem::size_of::<[i8; 4]>() as wgpu::BufferAddress,
-            },
-        ],
+// A shadow map array rendered one layer per pass, the point shadow's faces or the cascades
+struct ShadowLayerTarget<'a> {
+    name: &'a str,
+    shadow_maps: &'a ShadowTextureArray,
+    shadow_pass: &'a ShadowPass,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FramePass {
+    // only needed when no shadow pass clears the atlas before the debug view samples it
+    ClearShadowAtlas,
+    Shadow(u32),
+    // one per face of the point light's shadow cube
+    PointShadow(u32),
+    // one per cascade of the directional light
+    CascadeShadow(u32),
+    // one forward pass per viewport
+    Forward(u32),
+    // the deferred path's passes per viewport, all gbuffer passes before the lighting passes
+    GBuffer(u32),
+    DeferredLighting(u32),
+    // a shadow map layer shown instead of the scene
+    ShadowMapDebug,
+    // the scene from the hdr target to the frame
+    Tonemap,
+}
+
+// shader.wgsl with the constants shared with the Rust side filled in, for //!include "shader.wgsl"
+pub fn get_shader_include() -> String {
+    let constants = [("MAX_LIGHTS", MAX_LIGHTS as u32), ("MAX_SHADOW_LAYERS", MAX_SHADOW_LAYERS as u32)];
+    preprocess_with_constants(include_str!("shader.wgsl"), &[], &constants).expect("invalid shader.wgsl")
+}
+
+// shader.wgsl after the point shadow and cascade helpers it includes
+pub fn get_shader_source() -> String {
+    let source = get_shader_include();
+    expand_embedded_includes("shader.wgsl", &source, &[])
+        .expect("invalid shader.wgsl")
+        .source
+}
+
+// The shadow and forward passes' shader, shading the entities with the pbr material. MIP_DEBUG_DEFINE
+// colors them by the albedo mip level instead.
+pub fn get_forward_shader_source(defines: &[&str]) -> String {
+    let forward_source = preprocess(include_str!("forward.wgsl"), defines).expect("invalid forward.wgsl");
+    let shader_include = get_shader_include();
+    expand_embedded_includes("forward.wgsl", &forward_source, &[("shader.wgsl", shader_include.as_str())])
+        .expect("invalid forward.wgsl")
+        .source
+}
+
+// The pixel rect of a viewport, at least one pixel so the projection stays valid. None for an
+// empty target, e.g. while the window is minimized.
+pub fn get_viewport_pixels(rect: &[f32; 4], width: u32, height: u32) -> Option<[u32; 4]> {
+    if width == 0 || height == 0 {
+        return None;
     }
+    let x = ((rect[0] * width as f32) as u32).min(width - 1);
+    let y = ((rect[1] * height as f32) as u32).min(height - 1);
+    let viewport_width = ((rect[2] * width as f32) as u32).clamp(1, width - x);
+    let viewport_height = ((rect[3] * height as f32) as u32).clamp(1, height - y);
+    Some([x, y, viewport_width, viewport_height])
+}
+
+// The passes record() encodes in order, one shadow pass per shadow layer, point shadow face and cascade
+// before the scene passes. The shadow maps are shared by all viewports. The scene passes draw into the hdr target, which is tonemapped to the frame.
+pub fn get_frame_passes(
+    shadow_layer_count: usize,
+    show_shadows: bool,
+    render_path: RenderPath,
+    shadow_atlas_cleared: bool,
+    viewport_count: usize,
+    point_shadow: bool,
+    cascade_count: usize,
+) -> Vec<FramePass> {
+    let mut passes = vec![];
+    if show_shadows && shadow_layer_count == 0 && !shadow_atlas_cleared {
+        passes.push(FramePass::ClearShadowAtlas);
+    }
+    passes.extend((0..shadow_layer_count as u32).map(FramePass::Shadow));
+    if point_shadow {
+        passes.extend((0..CUBE_FACE_COUNT as u32).map(FramePass::PointShadow));
+    }
+    passes.extend((0..cascade_count as u32).map(FramePass::CascadeShadow));
+    let viewports = 0..viewport_count.max(1) as u32;
+    match (show_shadows, render_path) {
+        (true, _) => passes.push(FramePass::ShadowMapDebug),
+        (false, RenderPath::Forward) => passes.extend(viewports.map(FramePass::Forward)),
+        (false, RenderPath::Deferred) => {
+            passes.extend(viewports.clone().map(FramePass::GBuffer));
+            passes.extend(viewports.map(FramePass::DeferredLighting));
+        }
+    }
+    if !show_shadows {
+        passes.push(FramePass::Tonemap);
+    }
+    passes
+}
+
+#[cfg(feature = "text")]
+pub fn get_hud_lines(stats: &FrameStats) -> Vec<String> {
+    let mut lines = vec![
+        format!("cpu {:.2} ms", stats.cpu_ms),
+        format!("{} draws, {} triangles", stats.draw_calls, stats.triangles),
+        format!("{} entities drawn, {} culled", stats.entities_drawn, stats.entities_culled),
+    ];
+    if let Some(gpu_ms) = stats.gpu_ms {
+        lines.insert(1, format!("gpu {:.2} ms", gpu_ms));
+    }
+    lines
+}
+
+pub fn is_culled(culling_enabled: bool, frustum: &Frustum, bounding_box: &Aabb) -> bool {
+    culling_enabled && !frustum.intersects_aabb(bounding_box)
+}
+
+// The entities of draw_order that aren't culled, in order, each counted as drawn or culled
+pub fn cull_entities(
+    culling_enabled: bool,
+    frustum: &Frustum,
+    draw_order: Vec<usize>,
+    get_bounding_box: impl Fn(usize) -> Aabb,
+    stats: &mut FrameStats,
+) -> Vec<usize> {
+    draw_order
+        .into_iter()
+        .filter(|&index| {
+            let culled = is_culled(culling_enabled, frustum, &get_bounding_box(index));
+            stats.record_entity(culled);
+            !culled
+        })
+        .collect()
 }
 
 pub fn get_projection_view_matrix(aspect_ratio: f32) -> Mat4 {
-    let projection = Mat4::perspective_rh(consts::FRAC_PI_4, aspect_ratio, 1.0, 200.0);
-    let view = Mat4::look_at_rh(Vec3::new(3.0f32, -20.0, 6.0), Vec3::new(0f32, 0.0, 0.0), Vec3::Z);
-    projection * view
+    let camera = get_camera(aspect_ratio);
+    camera.get_slice_projection_view(camera.near, camera.far)
 }
 
-fn create_depth_texture(gpu_context: &GpuContext) -> TextureView {
-    let depth_texture = gpu_context.device.create_texture(&wgpu::TextureDescriptor {
+// The example's starting camera, whose frustum the directional light cascades split
+pub fn get_camera(aspect_ratio: f32) -> CascadeCamera {
+    get_cascade_camera(&get_default_camera(), aspect_ratio)
+}
+
+// z up, looking at the origin with a 45 degree fov
+pub fn get_default_camera() -> Camera {
+    let mut camera = Camera::camera_vec3_up_yaw_pitch(vec3(3.0, -20.0, 6.0), Vec3::Z, 0.0, 0.0);
+    camera.look_at(Vec3::ZERO);
+    camera.near = 1.0;
+    camera.far = 200.0;
+    camera
+}
+
+pub fn get_cascade_camera(camera: &Camera, aspect_ratio: f32) -> CascadeCamera {
+    CascadeCamera {
+        view: camera.get_view_matrix(),
+        fov: camera.zoom.to_radians(),
+        aspect_ratio,
+        near: camera.near,
+        far: camera.far,
+    }
+}
+
+fn create_motion_vector_texture(gpu_context: &GpuContext) -> TextureView {
+    let motion_vector_texture = gpu_context.device.create_texture(&wgpu::TextureDescriptor {
         size: wgpu::Extent3d {
             width: gpu_context.config.width,
             height: gpu_context.config.height,
@@ -254,11 +1061,309 @@ fn create_depth_texture(gpu_context: &GpuContext) -> TextureView {
         mip_level_count: 1,
         sample_count: 1,
         dimension: wgpu::TextureDimension::D2,
-        format: DEPTH_FORMAT,
-        usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
-        label: None,
+        format: MOTION_VECTOR_FORMAT,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+        label: Some("motion vectors"),
         view_formats: &[],
     });
 
-    depth_texture.create_view(&wgpu::TextureViewDescriptor::default())
+    motion_vector_texture.create_view(&wgpu::TextureViewDescriptor::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::iter;
+
+    use glam::{vec3, Mat4};
+
+    use spark_gap::buffers::assert_vertex_layout;
+    use spark_gap::culling::{Aabb, Frustum};
+    use spark_gap::frame_stats::FrameStats;
+    use spark_gap::gpu_context::GpuContext;
+
+    use crate::cube::Vertex;
+    use crate::debug_shadow::get_debug_atlas_rect;
+    use crate::lights::{MAX_LIGHTS, MAX_SHADOW_LAYERS};
+    use crate::shadow_pass::{ShadowCullMode, ShadowSettings};
+    #[cfg(feature = "text")]
+    use crate::world::get_hud_lines;
+    use crate::world::{
+        cull_entities, get_forward_shader_source, get_frame_passes, get_projection_view_matrix, get_shader_source, get_viewport_pixels,
+        is_culled, CameraViewport, FramePass, RenderPath, World,
+    };
+
+    #[test]
+    fn test_vertex_buffer_layout() {
+        assert_vertex_layout::<Vertex>(&Vertex::vertex_buffer_layout());
+    }
+
+    #[test]
+    #[cfg(feature = "text")]
+    fn test_hud_lines() {
+        let mut stats = FrameStats::new();
+        stats.draw_calls = 12;
+        stats.triangles = 144;
+        assert_eq!(get_hud_lines(&stats).len(), 3);
+        assert_eq!(get_hud_lines(&stats)[1], "12 draws, 144 triangles");
+
+        // gpu time follows the cpu time when profiling
+        stats.gpu_ms = Some(1.5);
+        assert_eq!(get_hud_lines(&stats)[1], "gpu 1.50 ms");
+    }
+
+    #[test]
+    fn test_culling_toggle() {
+        let frustum = Frustum::from_matrix(&get_projection_view_matrix(1.0));
+
+        let visible = Aabb {
+            min: vec3(-1.0, -1.0, -1.0),
+            max: vec3(1.0, 1.0, 1.0),
+        };
+        let outside = visible.transform(&Mat4::from_translation(vec3(0.0, -500.0, 0.0)));
+
+        assert!(!is_culled(true, &frustum, &visible));
+        assert!(is_culled(true, &frustum, &outside));
+
+        // everything is drawn when culling is disabled
+        assert!(!is_culled(false, &frustum, &visible));
+        assert!(!is_culled(false, &frustum, &outside));
+    }
+
+    #[test]
+    fn test_culled_entity_stats() {
+        let frustum = Frustum::from_matrix(&get_projection_view_matrix(1.0));
+        let visible = Aabb {
+            min: vec3(-1.0, -1.0, -1.0),
+            max: vec3(1.0, 1.0, 1.0),
+        };
+        let boxes = [
+            visible,
+            visible.transform(&Mat4::from_translation(vec3(0.0, -500.0, 0.0))),
+            visible.transform(&Mat4::from_translation(vec3(1.0, 0.0, 0.0))),
+            visible.transform(&Mat4::from_translation(vec3(500.0, 0.0, 0.0))),
+        ];
+
+        // the draw order is kept, only the ones outside the frustum are dropped
+        let mut stats = FrameStats::new();
+        let drawn = cull_entities(true, &frustum, vec![3, 2, 1, 0], |index| boxes[index], &mut stats);
+        assert_eq!(drawn, vec![2, 0]);
+        assert_eq!((stats.entities_drawn, stats.entities_culled), (2, 2));
+
+        // counts add up over the viewports of a frame
+        let drawn = cull_entities(false, &frustum, vec![3, 2, 1, 0], |index| boxes[index], &mut stats);
+        assert_eq!(drawn, vec![3, 2, 1, 0]);
+        assert_eq!((stats.entities_drawn, stats.entities_culled), (6, 2));
+    }
+
+    #[test]
+    fn test_recorded_passes() {
+        let passes = get_frame_passes(2, false, RenderPath::Forward, true, 1, false, 0);
+        assert_eq!(
+            passes,
+            vec![
+                FramePass::Shadow(0),
+                FramePass::Shadow(1),
+                FramePass::Forward(0),
+                FramePass::Tonemap
+            ]
+        );
+
+        let passes = get_frame_passes(2, true, RenderPath::Forward, true, 1, false, 0);
+        assert_eq!(passes.last(), Some(&FramePass::ShadowMapDebug));
+        assert_eq!(passes.iter().filter(|pass| matches!(pass, FramePass::Shadow(_))).count(), 2);
+
+        // the shadow map view goes straight to the frame
+        assert!(!passes.contains(&FramePass::Tonemap));
+    }
+
+    #[test]
+    fn test_render_path_passes() {
+        let forward = get_frame_passes(1, false, RenderPath::Forward, true, 1, false, 0);
+        let deferred = get_frame_passes(1, false, RenderPath::Deferred, true, 1, false, 0);
+        assert_eq!(
+            deferred,
+            vec![
+                FramePass::Shadow(0),
+                FramePass::GBuffer(0),
+                FramePass::DeferredLighting(0),
+                FramePass::Tonemap
+            ]
+        );
+        assert!(!forward.contains(&FramePass::GBuffer(0)) && !deferred.contains(&FramePass::Forward(0)));
+
+        // the shadow map view is the same on both paths
+        assert_eq!(
+            get_frame_passes(1, true, RenderPath::Deferred, true, 1, false, 0),
+            get_frame_passes(1, true, RenderPath::Forward, true, 1, false, 0)
+        );
+    }
+
+    #[test]
+    fn test_point_shadow_passes() {
+        // the cube faces follow the atlas layers
+        let passes = get_frame_passes(1, false, RenderPath::Forward, true, 1, true, 0);
+        assert_eq!(passes[0], FramePass::Shadow(0));
+        assert_eq!(passes[1..7], (0..6).map(FramePass::PointShadow).collect::<Vec<_>>()[..]);
+        assert_eq!(passes[7..], [FramePass::Forward(0), FramePass::Tonemap]);
+    }
+
+    #[test]
+    fn test_cascade_shadow_passes() {
+        // the directional light's cascades follow the point shadow faces
+        let passes = get_frame_passes(1, false, RenderPath::Forward, true, 1, true, 3);
+        assert_eq!(passes[7..10], (0..3).map(FramePass::CascadeShadow).collect::<Vec<_>>()[..]);
+        assert_eq!(passes[10..], [FramePass::Forward(0), FramePass::Tonemap]);
+
+        // the shader has the cascade helpers before the forward shading that calls them
+        let source = get_shader_source();
+        assert!(source.find("fn select_cascade(").unwrap() < source.find("fn sample_cascade_shadow(").unwrap());
+    }
+
+    #[test]
+    fn test_first_frame_shadow_debug() {
+        // without lights nothing writes the atlas, the debug view clears it before sampling
+        let passes = get_frame_passes(0, true, RenderPath::Forward, false, 1, false, 0);
+        assert_eq!(passes, vec![FramePass::ClearShadowAtlas, FramePass::ShadowMapDebug]);
+        assert_eq!(
+            get_frame_passes(0, true, RenderPath::Forward, true, 1, false, 0),
+            vec![FramePass::ShadowMapDebug]
+        );
+
+        // the first shadow pass clears the atlas itself
+        let passes = get_frame_passes(2, true, RenderPath::Forward, false, 1, false, 0);
+        assert_eq!(passes.first(), Some(&FramePass::Shadow(0)));
+
+        // a missing light shows an empty map
+        assert_eq!(get_debug_atlas_rect(&[], 1), [0.0; 4]);
+    }
+
+    #[test]
+    fn test_shared_shadow_passes() {
+        // a split screen renders the shadow maps once and a forward pass per camera
+        let passes = get_frame_passes(2, false, RenderPath::Forward, true, 2, false, 0);
+        assert_eq!(
+            passes,
+            vec![
+                FramePass::Shadow(0),
+                FramePass::Shadow(1),
+                FramePass::Forward(0),
+                FramePass::Forward(1),
+                FramePass::Tonemap
+            ]
+        );
+
+        assert_eq!(get_viewport_pixels(&[0.0, 0.0, 0.5, 1.0], 1280, 720), Some([0, 0, 640, 720]));
+        assert_eq!(get_viewport_pixels(&[0.5, 0.0, 0.5, 1.0], 1280, 720), Some([640, 0, 640, 720]));
+        assert_eq!(get_viewport_pixels(&[1.0, 1.0, 0.0, 0.0], 1280, 720), Some([1279, 719, 1, 1]));
+
+        // a minimized window has nothing to draw into
+        assert_eq!(get_viewport_pixels(&[0.0, 0.0, 1.0, 1.0], 0, 0), None);
+        assert_eq!(get_viewport_pixels(&[0.5, 0.0, 0.5, 1.0], 1280, 0), None);
+
+        // the deferred path draws every viewport too
+        let passes = get_frame_passes(1, false, RenderPath::Deferred, true, 2, false, 0);
+        assert_eq!(
+            passes[1..],
+            [
+                FramePass::GBuffer(0),
+                FramePass::GBuffer(1),
+                FramePass::DeferredLighting(0),
+                FramePass::DeferredLighting(1),
+                FramePass::Tonemap
+            ]
+        );
+    }
+
+    #[test]
+    fn test_shader_max_lights() {
+        let source = get_shader_source();
+        assert!(source.contains(&format!("const MAX_LIGHTS: u32 = {}u;", MAX_LIGHTS)));
+        assert!(source.contains(&format!("const MAX_SHADOW_LAYERS: u32 = {}u;", MAX_SHADOW_LAYERS)));
+        assert!(!source.contains("#const"));
+
+        let forward_source = get_forward_shader_source(&[]);
+        assert!(forward_source.contains("fn cook_torrance(") && forward_source.contains("@fragment fn fs_main("));
+        assert!(forward_source.contains("return shade_lights_pbr(vertex);") && !forward_source.contains("#ifdef"));
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "gpu_tests"), ignore = "needs a gpu adapter")]
+    fn test_pick_empty_target() {
+        let mut context = pollster::block_on(GpuContext::new_headless(64, 32)).unwrap();
+        let mut world = World::new(&mut context);
+
+        // the config of a minimized window, nothing to pick from
+        context.config.width = 0;
+        assert_eq!(world.pick_entity(&mut context, 10, 10).unwrap(), None);
+    }
+
+    fn record_frame(context: &GpuContext, world: &mut World) -> (Vec<FramePass>, FrameStats) {
+        let frame_passes = world.get_current_frame_passes();
+        let frame_view = context
+            .offscreen_texture
+            .as_ref()
+            .unwrap()
+            .create_view(&wgpu::TextureViewDescriptor::default());
+        let mut encoder = context
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        let stats = world.record(context, &mut encoder, &frame_view);
+        context.queue.submit(iter::once(encoder.finish()));
+        (frame_passes, stats)
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "gpu_tests"), ignore = "needs a gpu adapter")]
+    fn test_record_frame_passes() {
+        let mut context = pollster::block_on(GpuContext::new_headless(64, 32)).unwrap();
+        let mut world = World::new(&mut context);
+        world.set_viewports(
+            &context,
+            vec![
+                CameraViewport {
+                    camera_position: 0,
+                    rect: [0.0, 0.0, 0.5, 1.0],
+                },
+                CameraViewport {
+                    camera_position: 1,
+                    rect: [0.5, 0.0, 0.5, 1.0],
+                },
+            ],
+        );
+
+        let (forward_passes, forward) = record_frame(&context, &mut world);
+        let is_shadow_pass =
+            |pass: &&FramePass| matches!(pass, FramePass::Shadow(_) | FramePass::PointShadow(_) | FramePass::CascadeShadow(_));
+        assert_eq!(forward.shadow_passes as usize, forward_passes.iter().filter(is_shadow_pass).count());
+        assert!(forward_passes.contains(&FramePass::Forward(1)));
+
+        // the same entities, plus one fullscreen lighting draw per viewport
+        world.set_render_path(&mut context, RenderPath::Deferred);
+        let (deferred_passes, deferred) = record_frame(&context, &mut world);
+        assert_eq!(deferred.shadow_passes, forward.shadow_passes);
+        assert!(deferred_passes.contains(&FramePass::DeferredLighting(1)));
+        assert_eq!(deferred.draw_calls, forward.draw_calls + 2);
+        assert_eq!(deferred.entities_drawn, forward.entities_drawn);
+
+        // the mip debug view swaps the forward pipeline only
+        world.set_render_path(&mut context, RenderPath::Forward);
+        world.show_mip_levels = true;
+        let (mip_debug_passes, mip_debug) = record_frame(&context, &mut world);
+        assert_eq!(mip_debug_passes, forward_passes);
+        assert_eq!(mip_debug.draw_calls, forward.draw_calls);
+
+        // new shadow pipelines without culling, drawing the same passes
+        let previous_pipeline = world.shadow_pass.pipeline.global_id();
+        world.set_shadow_settings(
+            &mut context,
+            ShadowSettings {
+                cull_mode: ShadowCullMode::None,
+            },
+        );
+        assert_ne!(world.shadow_pass.pipeline.global_id(), previous_pipeline);
+        assert_eq!(world.get_shadow_settings().cull_mode, ShadowCullMode::None);
+        world.show_mip_levels = false;
+        let (_, unculled) = record_frame(&context, &mut world);
+        assert_eq!(unculled.shadow_passes, forward.shadow_passes);
+    }
 }
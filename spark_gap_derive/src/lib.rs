@@ -0,0 +1,139 @@
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Fields, Ident, LitInt, Member};
+
+// Derives `fn vertex_buffer_layout() -> wgpu::VertexBufferLayout<'static>` for a #[repr(C)] vertex struct.
+// Fields with #[vertex(location = 0, format = Sint8x4)] become attributes at their offset_of! offsets,
+// other fields are padding. #[vertex(step_mode = Instance)] on the struct makes it a per instance buffer.
+// The expansion names wgpu through spark_gap's re-export, so it works without a wgpu dependency.
+#[proc_macro_derive(VertexLayout, attributes(vertex))]
+pub fn derive_vertex_layout(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand_vertex_layout(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(error) => error.to_compile_error().into(),
+    }
+}
+
+struct VertexAttribute {
+    member: Member,
+    location: u32,
+    format: Ident,
+}
+
+fn expand_vertex_layout(input: &DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let name = &input.ident;
+
+    if !input.generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(
+            &input.generics,
+            "VertexLayout can't be derived for generic structs",
+        ));
+    }
+
+    let step_mode = get_step_mode(input)?;
+
+    let fields = match &input.data {
+        Data::Struct(data) => &data.fields,
+        _ => return Err(syn::Error::new_spanned(name, "VertexLayout can only be derived for structs")),
+    };
+
+    let attributes = get_vertex_attributes(fields)?;
+    if attributes.is_empty() {
+        return Err(syn::Error::new_spanned(
+            name,
+            "no fields with #[vertex(location = .., format = ..)]",
+        ));
+    }
+
+    let attribute_tokens = attributes.iter().map(|attribute| {
+        let VertexAttribute { member, location, format } = attribute;
+        quote! {
+            ::spark_gap::wgpu::VertexAttribute {
+                format: ::spark_gap::wgpu::VertexFormat::#format,
+                offset: ::core::mem::offset_of!(#name, #member) as ::spark_gap::wgpu::BufferAddress,
+                shader_location: #location,
+            }
+        }
+    });
+
+    Ok(quote! {
+        impl #name {
+            pub fn vertex_buffer_layout() -> ::spark_gap::wgpu::VertexBufferLayout<'static> {
+                const ATTRIBUTES: &[::spark_gap::wgpu::VertexAttribute] = &[#(#attribute_tokens),*];
+                ::spark_gap::wgpu::VertexBufferLayout {
+                    array_stride: ::core::mem::size_of::<#name>() as ::spark_gap::wgpu::BufferAddress,
+                    step_mode: ::spark_gap::wgpu::VertexStepMode::#step_mode,
+                    attributes: ATTRIBUTES,
+                }
+            }
+        }
+    })
+}
+
+fn get_step_mode(input: &DeriveInput) -> syn::Result<Ident> {
+    let mut step_mode = Ident::new("Vertex", Span::call_site());
+
+    for attr in input.attrs.iter().filter(|attr| attr.path().is_ident("vertex")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("step_mode") {
+                let value: Ident = meta.value()?.parse()?;
+                if value != "Vertex" && value != "Instance" {
+                    return Err(meta.error("step_mode is Vertex or Instance"));
+                }
+                step_mode = value;
+                Ok(())
+            } else {
+                Err(meta.error("unknown vertex attribute, expected step_mode"))
+            }
+        })?;
+    }
+
+    Ok(step_mode)
+}
+
+fn get_vertex_attributes(fields: &Fields) -> syn::Result<Vec<VertexAttribute>> {
+    let mut attributes: Vec<VertexAttribute> = vec![];
+
+    for (index, field) in fields.iter().enumerate() {
+        let member = match &field.ident {
+            Some(ident) => Member::Named(ident.clone()),
+            None => Member::Unnamed(index.into()),
+        };
+
+        for attr in field.attrs.iter().filter(|attr| attr.path().is_ident("vertex")) {
+            let mut location = None;
+            let mut format = None;
+
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("location") {
+                    let value: LitInt = meta.value()?.parse()?;
+                    location = Some(value.base10_parse::<u32>()?);
+                    Ok(())
+                } else if meta.path.is_ident("format") {
+                    format = Some(meta.value()?.parse::<Ident>()?);
+                    Ok(())
+                } else {
+                    Err(meta.error("unknown vertex attribute, expected location or format"))
+                }
+            })?;
+
+            let (Some(location), Some(format)) = (location, format) else {
+                return Err(syn::Error::new_spanned(attr, "vertex fields need both location and format"));
+            };
+
+            if attributes.iter().any(|attribute| attribute.location == location) {
+                return Err(syn::Error::new_spanned(attr, format!("location {} is used twice", location)));
+            }
+
+            attributes.push(VertexAttribute {
+                member: member.clone(),
+                location,
+                format,
+            });
+        }
+    }
+
+    Ok(attributes)
+}
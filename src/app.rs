@@ -0,0 +1,203 @@
+use std::time::Duration;
+
+use web_time::Instant;
+use winit::error::EventLoopError;
+use winit::event::{ElementState, Event, StartCause, WindowEvent};
+use winit::event_loop::{ControlFlow, EventLoop};
+use winit::keyboard::{KeyCode, PhysicalKey};
+
+use crate::gpu_context::GpuContext;
+use crate::input::Input;
+
+// Longer frames, e.g. after a breakpoint or while the window was dragged, are clamped so
+// animations and controllers don't jump
+pub const MAX_DELTA_TIME: f32 = 0.25;
+
+// What the frame loop should do after a callback
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AppControl {
+    Continue,
+    // continue without adding the event to input, e.g. when a ui took it
+    Consumed,
+    Exit,
+}
+
+// Callbacks of the frame loop driven by run. Each frame calls update with the input gathered since
+// the previous frame, then render.
+pub trait App {
+    // After the surface was resized to context.config.width x height
+    fn resize(&mut self, _context: &GpuContext) {}
+
+    // Every window event before it is added to input, for discrete actions like toggles. Consumed
+    // keeps the event out of input.
+    fn window_event(&mut self, _context: &mut GpuContext, _input: &Input, _event: &WindowEvent) -> AppControl {
+        AppControl::Continue
+    }
+
+    // delta_time is in seconds, at most MAX_DELTA_TIME
+    fn update(&mut self, context: &mut GpuContext, input: &Input, delta_time: f32);
+
+    fn render(&mut self, context: &GpuContext);
+
+    // Once before the loop returns
+    fn exit(&mut self, _context: &GpuContext) {}
+}
+
+#[derive(Debug, Clone)]
+pub struct AppSettings {
+    // None renders as fast as the surface's present mode allows
+    pub max_frame_rate: Option<f32>,
+    pub exit_on_escape: bool,
+}
+
+impl Default for AppSettings {
+    fn default() -> Self {
+        AppSettings::new()
+    }
+}
+
+impl AppSettings {
+    pub fn new() -> Self {
+        AppSettings {
+            max_frame_rate: None,
+            exit_on_escape: true,
+        }
+    }
+
+    pub fn set_max_frame_rate(mut self, max_frame_rate: Option<f32>) -> Self {
+        self.max_frame_rate = max_frame_rate;
+        self
+    }
+
+    pub fn set_exit_on_escape(mut self, exit_on_escape: bool) -> Self {
+        self.exit_on_escape = exit_on_escape;
+        self
+    }
+
+    // The shortest time between frame starts
+    pub fn get_frame_interval(&self) -> Option<Duration> {
+        self.max_frame_rate
+            .filter(|rate| *rate > 0.0)
+            .map(|rate| Duration::from_secs_f32(1.0 / rate))
+    }
+}
+
+// Seconds between ticks for update(delta_time)
+#[derive(Debug, Clone)]
+pub struct FrameClock {
+    last_tick: Option<Instant>,
+}
+
+impl Default for FrameClock {
+    fn default() -> Self {
+        FrameClock::new()
+    }
+}
+
+impl FrameClock {
+    pub fn new() -> Self {
+        FrameClock { last_tick: None }
+    }
+
+    // 0.0 on the first tick
+    pub fn tick(&mut self, now: Instant) -> f32 {
+        let delta_time = match self.last_tick {
+            Some(last_tick) => now.saturating_duration_since(last_tick).as_secs_f32(),
+            None => 0.0,
+        };
+        self.last_tick = Some(now);
+        delta_time.min(MAX_DELTA_TIME)
+    }
+}
+
+// Runs the frame loop until the window is closed or a callback returns Exit. The context has to be
+// created for a window of event_loop.
+pub fn run(event_loop: EventLoop<()>, mut context: GpuContext, mut app: impl App, settings: &AppSettings) -> Result<(), EventLoopError> {
+    let mut input = Input::default();
+    let mut clock = FrameClock::new();
+    let frame_interval = settings.get_frame_interval();
+    let exit_on_escape = settings.exit_on_escape;
+
+    event_loop.run(move |event, target| match event {
+        Event::NewEvents(StartCause::ResumeTimeReached { .. }) => context.request_redraw(),
+        Event::DeviceEvent { event, .. } => input.handle_device_event(&event),
+        Event::WindowEvent { event, .. } => {
+            let control = app.window_event(&mut context, &input, &event);
+            if control == AppControl::Exit || is_exit_event(&event, exit_on_escape) {
+                target.exit();
+                return;
+            }
+            if control != AppControl::Consumed {
+                input.handle_window_event(&event);
+            }
+
+            match event {
+                WindowEvent::Resized(new_size) => {
+                    context.resize(new_size);
+                    app.resize(&context);
+                    context.request_redraw();
+                }
+                WindowEvent::RedrawRequested => {
+                    let frame_start = Instant::now();
+                    let delta_time = clock.tick(frame_start);
+
+                    app.update(&mut context, &input, delta_time);
+                    input.prepare_for_update();
+                    app.render(&context);
+
+                    match frame_interval {
+                        Some(interval) => target.set_control_flow(ControlFlow::WaitUntil(frame_start + interval)),
+                        None => context.request_redraw(),
+                    }
+                }
+                _ => {}
+            }
+        }
+        Event::LoopExiting => app.exit(&context),
+        _ => {}
+    })
+}
+
+pub fn is_exit_event(event: &WindowEvent, exit_on_escape: bool) -> bool {
+    match event {
+        WindowEvent::CloseRequested => true,
+        WindowEvent::KeyboardInput { event, .. } => {
+            exit_on_escape && event.state == ElementState::Pressed && event.physical_key == PhysicalKey::Code(KeyCode::Escape)
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use web_time::Instant;
+
+    use crate::app::{AppSettings, FrameClock, MAX_DELTA_TIME};
+
+    #[test]
+    fn test_frame_clock() {
+        let start = Instant::now();
+        let mut clock = FrameClock::new();
+        assert_eq!(clock.tick(start), 0.0);
+
+        let delta_time = clock.tick(start + Duration::from_millis(16));
+        assert!((delta_time - 0.016).abs() < 1e-6);
+
+        // a long stall is clamped
+        assert_eq!(clock.tick(start + Duration::from_secs(5)), MAX_DELTA_TIME);
+
+        // an earlier instant doesn't go negative
+        assert_eq!(clock.tick(start), 0.0);
+    }
+
+    #[test]
+    fn test_frame_interval() {
+        assert_eq!(AppSettings::default().get_frame_interval(), None);
+        assert_eq!(AppSettings::new().set_max_frame_rate(Some(0.0)).get_frame_interval(), None);
+
+        let interval = AppSettings::new().set_max_frame_rate(Some(50.0)).get_frame_interval().unwrap();
+        assert!((interval.as_secs_f32() - 0.02).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,321 @@
+use wgpu::{BindGroup, BindGroupLayout, PipelineLayout};
+
+use crate::error::Error;
+use crate::error::Error::ValidationError;
+use crate::gpu_context::GpuContext;
+
+// Collects bind group layout entries, validating them against the device limits on build.
+// Bindings are numbered in declaration order, matching the resources of a BindGroupBuilder.
+#[derive(Debug, Clone, Default)]
+pub struct LayoutBuilder {
+    pub entries: Vec<wgpu::BindGroupLayoutEntry>,
+}
+
+impl LayoutBuilder {
+    pub fn new() -> Self {
+        LayoutBuilder { entries: vec![] }
+    }
+
+    pub fn entry(mut self, entry: wgpu::BindGroupLayoutEntry) -> Self {
+        self.entries.push(entry);
+        self
+    }
+
+    pub fn uniform(self, visibility: wgpu::ShaderStages) -> Self {
+        self.buffer(visibility, wgpu::BufferBindingType::Uniform, false, None)
+    }
+
+    // min_binding_size lets wgpu check the bound buffer when the bind group is created instead of at draw
+    pub fn sized_uniform(self, visibility: wgpu::ShaderStages, min_binding_size: u64) -> Self {
+        self.buffer(
+            visibility,
+            wgpu::BufferBindingType::Uniform,
+            false,
+            wgpu::BufferSize::new(min_binding_size),
+        )
+    }
+
+    pub fn storage_buffer(self, visibility: wgpu::ShaderStages, read_only: bool) -> Self {
+        self.buffer(visibility, wgpu::BufferBindingType::Storage { read_only }, false, None)
+    }
+
+    // a uniform buffer bound with a dynamic offset, min_binding_size is the size of one element
+    pub fn dynamic_uniform(self, visibility: wgpu::ShaderStages, min_binding_size: u64) -> Self {
+        self.buffer(
+            visibility,
+            wgpu::BufferBindingType::Uniform,
+            true,
+            wgpu::BufferSize::new(min_binding_size),
+        )
+    }
+
+    fn buffer(
+        self,
+        visibility: wgpu::ShaderStages,
+        ty: wgpu::BufferBindingType,
+        has_dynamic_offset: bool,
+        min_binding_size: Option<wgpu::BufferSize>,
+    ) -> Self {
+        self.binding(
+            visibility,
+            wgpu::BindingType::Buffer {
+                ty,
+                has_dynamic_offset,
+                min_binding_size,
+            },
+        )
+    }
+
+    pub fn texture(
+        self,
+        visibility: wgpu::ShaderStages,
+        sample_type: wgpu::TextureSampleType,
+        view_dimension: wgpu::TextureViewDimension,
+    ) -> Self {
+        self.binding(
+            visibility,
+            wgpu::BindingType::Texture {
+                sample_type,
+                view_dimension,
+                multisampled: false,
+            },
+        )
+    }
+
+    // texture_2d<f32> read with a filtering sampler
+    pub fn texture_2d(self, visibility: wgpu::ShaderStages) -> Self {
+        self.texture(
+            visibility,
+            wgpu::TextureSampleType::Float { filterable: true },
+            wgpu::TextureViewDimension::D2,
+        )
+    }
+
+    // texture_depth_2d, e.g. a shadow atlas
+    pub fn texture_depth(self, visibility: wgpu::ShaderStages) -> Self {
+        self.texture(visibility, wgpu::TextureSampleType::Depth, wgpu::TextureViewDimension::D2)
+    }
+
+    pub fn sampler(self, visibility: wgpu::ShaderStages) -> Self {
+        self.binding(visibility, wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering))
+    }
+
+    pub fn sampler_comparison(self, visibility: wgpu::ShaderStages) -> Self {
+        self.binding(visibility, wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Comparison))
+    }
+
+    fn binding(self, visibility: wgpu::ShaderStages, ty: wgpu::BindingType) -> Self {
+        let binding = self.entries.len() as u32;
+        self.entry(wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility,
+            ty,
+            count: None,
+        })
+    }
+
+    pub fn build(&self, context: &GpuContext, label: &str) -> Result<BindGroupLayout, Error> {
+        validate_dynamic_offsets(self.entries.iter(), &context.device.limits())?;
+
+        let layout = context.device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some(label),
+            entries: &self.entries,
+        });
+        context.binding_registry.register_layout(&layout, &self.entries);
+        Ok(layout)
+    }
+}
+
+// Resources of a bind group given in the order of the layout's bindings
+#[derive(Debug, Default)]
+pub struct BindGroupBuilder<'a> {
+    pub entries: Vec<wgpu::BindGroupEntry<'a>>,
+}
+
+impl<'a> BindGroupBuilder<'a> {
+    pub fn new() -> Self {
+        BindGroupBuilder { entries: vec![] }
+    }
+
+    pub fn resource(mut self, resource: wgpu::BindingResource<'a>) -> Self {
+        let binding = self.entries.len() as u32;
+        self.entries.push(wgpu::BindGroupEntry { binding, resource });
+        self
+    }
+
+    pub fn buffer(self, buffer: &'a wgpu::Buffer) -> Self {
+        self.resource(buffer.as_entire_binding())
+    }
+
+    pub fn texture_view(self, view: &'a wgpu::TextureView) -> Self {
+        self.resource(wgpu::BindingResource::TextureView(view))
+    }
+
+    pub fn sampler(self, sampler: &'a wgpu::Sampler) -> Self {
+        self.resource(wgpu::BindingResource::Sampler(sampler))
+    }
+
+    pub fn build(&self, context: &GpuContext, layout: &BindGroupLayout, label: &str) -> BindGroup {
+        context.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some(label),
+            layout,
+            entries: &self.entries,
+        })
+    }
+}
+
+// wgpu only reports exceeding these limits when the pipeline layout is created,
+// so check them where the dynamic bindings are declared.
+pub fn validate_dynamic_offsets<'a>(
+    entries: impl Iterator<Item = &'a wgpu::BindGroupLayoutEntry>,
+    limits: &wgpu::Limits,
+) -> Result<(), Error> {
+    let mut dynamic_uniforms = 0;
+    let mut dynamic_storage = 0;
+
+    for entry in entries {
+        if let wgpu::BindingType::Buffer {
+            ty,
+            has_dynamic_offset: true,
+            ..
+        } = entry.ty
+        {
+            match ty {
+                wgpu::BufferBindingType::Uniform => dynamic_uniforms += 1,
+                wgpu::BufferBindingType::Storage { .. } => dynamic_storage += 1,
+            }
+        }
+    }
+
+    if dynamic_uniforms > limits.max_dynamic_uniform_buffers_per_pipeline_layout {
+        return Err(ValidationError(format!(
+            "{} dynamic uniform buffers exceed max_dynamic_uniform_buffers_per_pipeline_layout ({})",
+            dynamic_uniforms, limits.max_dynamic_uniform_buffers_per_pipeline_layout
+        )));
+    }
+
+    if dynamic_storage > limits.max_dynamic_storage_buffers_per_pipeline_layout {
+        return Err(ValidationError(format!(
+            "{} dynamic storage buffers exceed max_dynamic_storage_buffers_per_pipeline_layout ({})",
+            dynamic_storage, limits.max_dynamic_storage_buffers_per_pipeline_layout
+        )));
+    }
+
+    Ok(())
+}
+
+// Bind group layouts are given with their group index so the order always matches
+// the @group attributes in the shader.
+pub fn create_pipeline_layout(
+    context: &GpuContext,
+    label: &str,
+    groups: &[(u32, &BindGroupLayout)],
+    push_constant_ranges: &[wgpu::PushConstantRange],
+) -> Result<PipelineLayout, Error> {
+    let bind_group_layouts = order_bind_groups(groups, context.device.limits().max_bind_groups)?;
+
+    Ok(context.device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some(label),
+        bind_group_layouts: &bind_group_layouts,
+        push_constant_ranges,
+    }))
+}
+
+// Sorts by group index, requiring the groups to be 0..n without gaps or duplicates
+pub fn order_bind_groups<T: Copy>(groups: &[(u32, T)], max_bind_groups: u32) -> Result<Vec<T>, Error> {
+    if groups.len() > max_bind_groups as usize {
+        return Err(ValidationError(format!(
+            "{} bind groups exceed max_bind_groups ({})",
+            groups.len(),
+            max_bind_groups
+        )));
+    }
+
+    let mut sorted = groups.to_vec();
+    sorted.sort_by_key(|(index, _)| *index);
+
+    for (expected, (index, _)) in sorted.iter().enumerate() {
+        if *index != expected as u32 {
+            return Err(ValidationError(format!(
+                "bind group {} is missing or duplicated, got groups {:?}",
+                expected,
+                groups.iter().map(|(index, _)| *index).collect::<Vec<u32>>()
+            )));
+        }
+    }
+
+    Ok(sorted.into_iter().map(|(_, layout)| layout).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bind_group::{order_bind_groups, validate_dynamic_offsets, LayoutBuilder};
+    use crate::error::Error::ValidationError;
+
+    #[test]
+    fn test_dynamic_offset_limit() {
+        let limits = wgpu::Limits::default();
+
+        let mut builder = LayoutBuilder::new();
+        for _ in 0..limits.max_dynamic_uniform_buffers_per_pipeline_layout {
+            builder = builder.dynamic_uniform(wgpu::ShaderStages::VERTEX, 64);
+        }
+        assert!(validate_dynamic_offsets(builder.entries.iter(), &limits).is_ok());
+
+        builder = builder.dynamic_uniform(wgpu::ShaderStages::VERTEX, 64);
+        match validate_dynamic_offsets(builder.entries.iter(), &limits) {
+            Err(ValidationError(message)) => assert!(message.contains("max_dynamic_uniform_buffers_per_pipeline_layout")),
+            _ => panic!("expected a validation error"),
+        }
+
+        // bindings are numbered in declaration order
+        let bindings: Vec<u32> = builder.entries.iter().map(|entry| entry.binding).collect();
+        assert_eq!(bindings, (0..bindings.len() as u32).collect::<Vec<u32>>());
+    }
+
+    #[test]
+    fn test_layout_bindings() {
+        let fragment = wgpu::ShaderStages::FRAGMENT;
+        let builder = LayoutBuilder::new()
+            .sized_uniform(fragment, 64)
+            .storage_buffer(wgpu::ShaderStages::COMPUTE, true)
+            .texture_depth(fragment)
+            .sampler_comparison(fragment);
+
+        let bindings: Vec<u32> = builder.entries.iter().map(|entry| entry.binding).collect();
+        assert_eq!(bindings, vec![0, 1, 2, 3]);
+
+        assert!(matches!(
+            builder.entries[1].ty,
+            wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only: true },
+                ..
+            }
+        ));
+        assert!(matches!(
+            builder.entries[2].ty,
+            wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Depth,
+                ..
+            }
+        ));
+        assert_eq!(
+            builder.entries[3].ty,
+            wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Comparison)
+        );
+    }
+
+    #[test]
+    fn test_order_bind_groups() {
+        let ordered = order_bind_groups(&[(1, "entity"), (0, "lights"), (2, "material")], 4).unwrap();
+        assert_eq!(ordered, vec!["lights", "entity", "material"]);
+
+        match order_bind_groups(&[(0, "a"), (1, "b"), (2, "c")], 2) {
+            Err(ValidationError(message)) => assert!(message.contains("max_bind_groups")),
+            _ => panic!("expected a validation error"),
+        }
+
+        assert!(order_bind_groups(&[(0, "a"), (2, "c")], 4).is_err());
+        assert!(order_bind_groups(&[(0, "a"), (0, "b")], 4).is_err());
+    }
+}
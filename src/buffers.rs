@@ -1,13 +1,24 @@
+use std::marker::PhantomData;
+use std::mem;
 use std::num::NonZeroU32;
+use std::ops::Range;
 use std::rc::Rc;
 
+use crate::error::Error;
+use crate::error::Error::ValidationError;
 use crate::gpu_context::GpuContext;
 use glam::Mat4;
-use wgpu::util::DeviceExt;
+use wgpu::util::{align_to, DeviceExt};
 use wgpu::{BindGroup, BindGroupLayout, Buffer, BufferAddress};
 
+pub use crate::instance_buffer::{InstanceBuffer, InstanceLayout};
+
 pub const TRANSFORM_BIND_GROUP_LAYOUT: &str = "transform bind group layout";
 
+pub const STORAGE_BUFFER_USAGE: wgpu::BufferUsages = wgpu::BufferUsages::STORAGE
+    .union(wgpu::BufferUsages::COPY_SRC)
+    .union(wgpu::BufferUsages::COPY_DST);
+
 pub fn create_vertex_buffer(context: &GpuContext, size: usize, label: &str) -> Buffer {
     context.device.create_buffer(&wgpu::BufferDescriptor {
         label: Some(label),
@@ -17,6 +28,39 @@ pub fn create_vertex_buffer(context: &GpuContext, size: usize, label: &str) -> B
     })
 }
 
+// Checks that the layout describes V, its stride is the size of V and every attribute lies inside V
+pub fn check_vertex_layout<V: bytemuck::Pod>(layout: &wgpu::VertexBufferLayout) -> Result<(), Error> {
+    let size = mem::size_of::<V>() as BufferAddress;
+    if layout.array_stride != size {
+        return Err(ValidationError(format!(
+            "vertex layout stride {} doesn't match {} ({} bytes)",
+            layout.array_stride,
+            std::any::type_name::<V>(),
+            size
+        )));
+    }
+    check_vertex_attributes(std::any::type_name::<V>(), size, layout.attributes)
+}
+
+// For tests and debug builds, panics when check_vertex_layout fails
+pub fn assert_vertex_layout<V: bytemuck::Pod>(layout: &wgpu::VertexBufferLayout) {
+    if let Err(e) = check_vertex_layout::<V>(layout) {
+        panic!("{}", e);
+    }
+}
+
+pub fn check_vertex_attributes(type_name: &str, size: BufferAddress, attributes: &[wgpu::VertexAttribute]) -> Result<(), Error> {
+    for attribute in attributes {
+        if attribute.offset + attribute.format.size() > size {
+            return Err(ValidationError(format!(
+                "vertex attribute at location {} ({:?} at offset {}) is outside of {} ({} bytes)",
+                attribute.shader_location, attribute.format, attribute.offset, type_name, size
+            )));
+        }
+    }
+    Ok(())
+}
+
 pub fn create_uniform_buffer(context: &GpuContext, size: usize, label: &str) -> Buffer {
     context.device.create_buffer(&wgpu::BufferDescriptor {
         label: Some(label),
@@ -59,11 +103,431 @@ pub fn update_uniform_box_buffer<T: bytemuck::Pod>(context: &GpuContext, buffer:
 }
 
 pub fn update_mat4_buffer(context: &GpuContext, buffer: &Buffer, data: &Mat4) {
-    context.queue.write_buffer(buffer, 0, bytemuck::cast_slice(&data.to_cols_array()));
+    UniformBuffer::<Mat4>::write(context, buffer, data);
 }
 
 pub fn update_u32_buffer(context: &GpuContext, buffer: &Buffer, data: &u32) {
-    context.queue.write_buffer(buffer, 0, bytemuck::bytes_of(data));
+    UniformBuffer::<u32>::write(context, buffer, data);
+}
+
+// Writes data into a buffer of T starting at element first_element, e.g. to update part of an
+// instance array. The buffer needs COPY_DST. Writes past the end or off wgpu's 4 byte copy alignment
+// are errors instead of validation panics.
+pub fn write_slice_at<T: bytemuck::Pod>(context: &GpuContext, buffer: &Buffer, first_element: usize, data: &[T]) -> Result<(), Error> {
+    let range = get_slice_range::<T>(buffer.size(), first_element, data.len())?;
+    context.queue.write_buffer(buffer, range.start, bytemuck::cast_slice(data));
+    Ok(())
+}
+
+// The byte range of len elements of T from element first_element, checked against the buffer size
+pub fn get_slice_range<T>(buffer_size: BufferAddress, first_element: usize, len: usize) -> Result<Range<BufferAddress>, Error> {
+    let element_size = mem::size_of::<T>() as BufferAddress;
+    let start = first_element as BufferAddress * element_size;
+    let end = start + len as BufferAddress * element_size;
+
+    if end > buffer_size {
+        return Err(ValidationError(format!(
+            "writing {} elements of {} at element {} ends at byte {}, past the buffer's {}",
+            len,
+            std::any::type_name::<T>(),
+            first_element,
+            end,
+            buffer_size
+        )));
+    }
+    if start % wgpu::COPY_BUFFER_ALIGNMENT != 0 || end % wgpu::COPY_BUFFER_ALIGNMENT != 0 {
+        return Err(ValidationError(format!(
+            "bytes {} to {} aren't aligned to {}, {} is {} bytes",
+            start,
+            end,
+            wgpu::COPY_BUFFER_ALIGNMENT,
+            std::any::type_name::<T>(),
+            element_size
+        )));
+    }
+    Ok(start..end)
+}
+
+// Elements of T written at any offset, the buffer grows when a write doesn't fit. For data streamed
+// in pieces, e.g. instance transforms appended during a frame.
+//
+// Growing doubles the capacity until the write fits and copies the elements written so far into
+// the new buffer on the gpu, with the same usage. Writes return true when the buffer was replaced,
+// bind groups using it have to be recreated then.
+pub struct GrowableBuffer<T: bytemuck::Pod> {
+    pub buffer: Buffer,
+    usage: wgpu::BufferUsages,
+    capacity: usize,
+    // the end of the furthest write since the last clear
+    len: usize,
+    label: String,
+    _element: PhantomData<T>,
+}
+
+impl<T: bytemuck::Pod> GrowableBuffer<T> {
+    // COPY_SRC and COPY_DST are always added to usage for writing and growing
+    pub fn new(context: &GpuContext, capacity: usize, usage: wgpu::BufferUsages, label: &str) -> Self {
+        let usage = usage | wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::COPY_DST;
+        let capacity = capacity.max(1);
+        GrowableBuffer {
+            buffer: create_growable_buffer::<T>(context, capacity, usage, label),
+            usage,
+            capacity,
+            len: 0,
+            label: label.to_string(),
+            _element: PhantomData,
+        }
+    }
+
+    // Writes data from element first_element, growing when it ends past the capacity. The write
+    // is checked against the grown size first, a misaligned write leaves the buffer as it was.
+    pub fn write(&mut self, context: &GpuContext, first_element: usize, data: &[T]) -> Result<bool, Error> {
+        let end = first_element + data.len();
+        let capacity = get_grown_capacity(self.capacity, end);
+        let range = get_slice_range::<T>(get_growable_buffer_size::<T>(capacity), first_element, data.len())?;
+
+        let grown = capacity > self.capacity;
+        if grown {
+            self.grow(context, capacity);
+        }
+
+        context.queue.write_buffer(&self.buffer, range.start, bytemuck::cast_slice(data));
+        self.len = self.len.max(end);
+        Ok(grown)
+    }
+
+    // Writes data after the elements written so far
+    pub fn push(&mut self, context: &GpuContext, data: &[T]) -> Result<bool, Error> {
+        self.write(context, self.len, data)
+    }
+
+    // Starts the next push at the first element, keeping the allocation
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn usage(&self) -> wgpu::BufferUsages {
+        self.usage
+    }
+
+    // The written elements, e.g. for set_vertex_buffer. Check is_empty first, wgpu has no empty slices.
+    pub fn slice(&self) -> wgpu::BufferSlice<'_> {
+        self.buffer.slice(..(self.len * mem::size_of::<T>()) as BufferAddress)
+    }
+
+    pub fn as_entire_binding(&self) -> wgpu::BindingResource<'_> {
+        self.buffer.as_entire_binding()
+    }
+
+    // The copy is submitted right away, so writes queued for the old buffer land before it and
+    // the write that needed the space lands after it
+    fn grow(&mut self, context: &GpuContext, capacity: usize) {
+        let buffer = create_growable_buffer::<T>(context, capacity, self.usage, &self.label);
+
+        let copy_size = align_to((self.len * mem::size_of::<T>()) as BufferAddress, wgpu::COPY_BUFFER_ALIGNMENT);
+        if copy_size > 0 {
+            let mut encoder = context
+                .device
+                .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: Some(&self.label) });
+            encoder.copy_buffer_to_buffer(&self.buffer, 0, &buffer, 0, copy_size);
+            context.queue.submit(std::iter::once(encoder.finish()));
+        }
+
+        self.buffer = buffer;
+        self.capacity = capacity;
+    }
+}
+
+// The capacity doubled until it holds required elements
+pub fn get_grown_capacity(capacity: usize, required: usize) -> usize {
+    let mut grown = capacity.max(1);
+    while grown < required {
+        grown *= 2;
+    }
+    grown
+}
+
+// Padded to the copy alignment so the used part can always be copied when growing
+fn get_growable_buffer_size<T>(capacity: usize) -> BufferAddress {
+    align_to((capacity * mem::size_of::<T>()) as BufferAddress, wgpu::COPY_BUFFER_ALIGNMENT)
+}
+
+fn create_growable_buffer<T>(context: &GpuContext, capacity: usize, usage: wgpu::BufferUsages, label: &str) -> Buffer {
+    context.device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some(label),
+        size: get_growable_buffer_size::<T>(capacity),
+        usage,
+        mapped_at_creation: false,
+    })
+}
+
+// A uniform buffer holding one T, written at offset 0
+pub struct UniformBuffer<T: bytemuck::Pod> {
+    pub buffer: Buffer,
+    _uniform: PhantomData<T>,
+}
+
+impl<T: bytemuck::Pod> UniformBuffer<T> {
+    // UNIFORM and COPY_DST are always added to usage
+    pub fn new(context: &GpuContext, initial: &T, usage: wgpu::BufferUsages) -> Self {
+        let contents = bytemuck::bytes_of(initial);
+        let buffer = context.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(std::any::type_name::<T>()),
+            contents,
+            usage: usage | wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        UniformBuffer {
+            buffer,
+            _uniform: PhantomData,
+        }
+    }
+
+    // The buffer is public and can be swapped for one created elsewhere, so the value is checked
+    // against the size the buffer was allocated with. create_buffer_init pads to COPY_BUFFER_ALIGNMENT.
+    pub fn update(&self, context: &GpuContext, value: &T) {
+        let data = bytemuck::bytes_of(value);
+        debug_assert_eq!(
+            wgpu::util::align_to(data.len() as BufferAddress, wgpu::COPY_BUFFER_ALIGNMENT),
+            self.buffer.size(),
+            "{} is {} bytes but the uniform buffer has {}",
+            std::any::type_name::<T>(),
+            data.len(),
+            self.buffer.size()
+        );
+        context.queue.write_buffer(&self.buffer, 0, data);
+    }
+
+    pub fn size(&self) -> BufferAddress {
+        self.buffer.size()
+    }
+
+    pub fn as_entire_binding(&self) -> wgpu::BindingResource<'_> {
+        self.buffer.as_entire_binding()
+    }
+
+    // Writes a T into a buffer created elsewhere, which has to be large enough to hold it
+    pub fn write(context: &GpuContext, buffer: &Buffer, value: &T) {
+        let data = bytemuck::bytes_of(value);
+        debug_assert!(
+            data.len() as BufferAddress <= buffer.size(),
+            "{} is {} bytes but the buffer has {}",
+            std::any::type_name::<T>(),
+            data.len(),
+            buffer.size()
+        );
+        context.queue.write_buffer(buffer, 0, data);
+    }
+}
+
+// Slots of T bound with a dynamic offset, e.g. one slot per entity. Each slot starts at a multiple
+// of the device's min_uniform_buffer_offset_alignment.
+pub struct DynamicUniformBuffer<T: bytemuck::Pod> {
+    pub buffer: Buffer,
+    slot_size: BufferAddress,
+    capacity: usize,
+    _uniform: PhantomData<T>,
+}
+
+impl<T: bytemuck::Pod> DynamicUniformBuffer<T> {
+    pub fn new(context: &GpuContext, capacity: usize, label: &str) -> Self {
+        let alignment = context.device.limits().min_uniform_buffer_offset_alignment as BufferAddress;
+        let slot_size = get_dynamic_slot_size(mem::size_of::<T>() as BufferAddress, alignment);
+
+        let buffer = context.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(label),
+            size: slot_size * capacity.max(1) as BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        DynamicUniformBuffer {
+            buffer,
+            slot_size,
+            capacity,
+            _uniform: PhantomData,
+        }
+    }
+
+    // Byte offset of the slot, for set_bind_group
+    pub fn offset(&self, index: usize) -> wgpu::DynamicOffset {
+        assert!(
+            index < self.capacity,
+            "slot {} out of range of a dynamic uniform buffer with {} slots",
+            index,
+            self.capacity
+        );
+        get_dynamic_offset(self.slot_size, index)
+    }
+
+    pub fn write(&self, context: &GpuContext, index: usize, value: &T) {
+        let offset = self.offset(index);
+        context
+            .queue
+            .write_buffer(&self.buffer, offset as BufferAddress, bytemuck::bytes_of(value));
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn slot_size(&self) -> BufferAddress {
+        self.slot_size
+    }
+
+    // min_binding_size of the layout entry, which needs has_dynamic_offset set
+    pub fn binding_size() -> Option<wgpu::BufferSize> {
+        wgpu::BufferSize::new(mem::size_of::<T>() as BufferAddress)
+    }
+
+    // A single T, moved to the slot by the dynamic offset
+    pub fn binding(&self) -> wgpu::BindingResource<'_> {
+        wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+            buffer: &self.buffer,
+            offset: 0,
+            size: Self::binding_size(),
+        })
+    }
+}
+
+pub fn get_dynamic_slot_size(uniform_size: BufferAddress, alignment: BufferAddress) -> BufferAddress {
+    align_to(uniform_size.max(1), alignment.max(1))
+}
+
+pub fn get_dynamic_offset(slot_size: BufferAddress, index: usize) -> wgpu::DynamicOffset {
+    (slot_size * index as BufferAddress) as wgpu::DynamicOffset
+}
+
+// A fixed number of T for compute shaders, always usable as STORAGE, COPY_SRC and COPY_DST.
+//
+// Adding VERTEX to usage lets a simulation write instances that are drawn without a copy, with the
+// layout of an InstanceBuffer<T>:
+//
+//   let particles = StorageBuffer::new_init(context, &initial, wgpu::BufferUsages::VERTEX, "particles")?;
+//   pass.set_vertex_buffer(1, particles.slice());
+//   pass.draw_indexed(0..mesh.index_count, 0, 0..particles.count());
+pub struct StorageBuffer<T: bytemuck::Pod> {
+    pub buffer: Buffer,
+    len: usize,
+    _element: PhantomData<T>,
+}
+
+impl<T: bytemuck::Pod> StorageBuffer<T> {
+    // Zeroed. An UnsupportedError on devices without storage buffers, e.g. WebGL2.
+    pub fn new(context: &GpuContext, len: usize, usage: wgpu::BufferUsages, label: &str) -> Result<Self, Error> {
+        context.capabilities.require_storage_buffers(label)?;
+
+        let buffer = context.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(label),
+            size: (len.max(1) * mem::size_of::<T>()) as BufferAddress,
+            usage: usage | STORAGE_BUFFER_USAGE,
+            mapped_at_creation: false,
+        });
+
+        Ok(StorageBuffer {
+            buffer,
+            len,
+            _element: PhantomData,
+        })
+    }
+
+    pub fn new_init(context: &GpuContext, values: &[T], usage: wgpu::BufferUsages, label: &str) -> Result<Self, Error> {
+        let storage_buffer = StorageBuffer::new(context, values.len(), usage, label)?;
+        storage_buffer.write(context, 0, values)?;
+        Ok(storage_buffer)
+    }
+
+    // Writes values starting at element first, an error when they don't fit in the buffer's len
+    pub fn write(&self, context: &GpuContext, first: usize, values: &[T]) -> Result<(), Error> {
+        let size = (self.len * mem::size_of::<T>()) as BufferAddress;
+        let range = get_slice_range::<T>(size, first, values.len())?;
+        context.queue.write_buffer(&self.buffer, range.start, bytemuck::cast_slice(values));
+        Ok(())
+    }
+
+    // Stalls like read_buffer, so only for debugging and tests
+    pub fn read_back(&self, context: &GpuContext) -> Vec<T> {
+        let data = read_buffer(context, &self.buffer);
+        bytemuck::pod_collect_to_vec(&data[..self.len * mem::size_of::<T>()])
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // instance range for draw calls of a buffer with VERTEX usage
+    pub fn count(&self) -> u32 {
+        self.len as u32
+    }
+
+    pub fn slice(&self) -> wgpu::BufferSlice<'_> {
+        self.buffer.slice(..)
+    }
+
+    pub fn as_entire_binding(&self) -> wgpu::BindingResource<'_> {
+        self.buffer.as_entire_binding()
+    }
+}
+
+// Copies the buffer to a staging buffer and waits for it to be mapped. The buffer needs COPY_SRC usage.
+// Only meant for debugging and tests since it stalls until the gpu is idle.
+pub fn read_buffer(context: &GpuContext, buffer: &Buffer) -> Vec<u8> {
+    let staging_buffer = context.device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("readback buffer"),
+        size: buffer.size(),
+        usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+        mapped_at_creation: false,
+    });
+
+    let mut encoder = context
+        .device
+        .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: Some("readback") });
+    encoder.copy_buffer_to_buffer(buffer, 0, &staging_buffer, 0, buffer.size());
+    context.queue.submit(std::iter::once(encoder.finish()));
+
+    let buffer_slice = staging_buffer.slice(..);
+    buffer_slice.map_async(wgpu::MapMode::Read, |_| ());
+    context.device.poll(wgpu::Maintain::Wait);
+
+    let data = buffer_slice.get_mapped_range().to_vec();
+    staging_buffer.unmap();
+    data
+}
+
+// Typed view of readback data. Fails when the length isn't a multiple of the type size or the data is misaligned.
+pub fn cast_slice<T: bytemuck::Pod>(data: &[u8]) -> Result<&[T], Error> {
+    bytemuck::try_cast_slice(data).map_err(|e| cast_error::<T>(data.len(), e))
+}
+
+pub fn cast_slice_mut<T: bytemuck::Pod>(data: &mut [u8]) -> Result<&mut [T], Error> {
+    let len = data.len();
+    bytemuck::try_cast_slice_mut(data).map_err(|e| cast_error::<T>(len, e))
+}
+
+fn cast_error<T>(len: usize, error: bytemuck::PodCastError) -> Error {
+    ValidationError(format!(
+        "can't cast {} bytes to a slice of {} ({} bytes): {:?}",
+        len,
+        std::any::type_name::<T>(),
+        std::mem::size_of::<T>(),
+        error
+    ))
 }
 
 pub fn get_or_create_bind_group_layout(
@@ -122,3 +586,353 @@ pub fn create_buffer_bind_group(context: &GpuContext, bind_group_layout: &BindGr
         label: Some(label),
     })
 }
+
+// Packs several small uniform structs into one buffer, each at an offset aligned to
+// min_uniform_buffer_offset_alignment, bound as consecutive bindings of a single bind group.
+#[derive(Debug, Clone)]
+pub struct UniformPacker {
+    pub alignment: BufferAddress,
+    pub data: Vec<u8>,
+    // offset and size of each binding
+    pub ranges: Vec<(BufferAddress, BufferAddress)>,
+}
+
+impl UniformPacker {
+    pub fn new(alignment: BufferAddress) -> Self {
+        UniformPacker {
+            alignment: alignment.max(1),
+            data: vec![],
+            ranges: vec![],
+        }
+    }
+
+    pub fn from_limits(limits: &wgpu::Limits) -> Self {
+        Self::new(limits.min_uniform_buffer_offset_alignment as BufferAddress)
+    }
+
+    // Returns the binding of the struct
+    pub fn push<T: bytemuck::Pod>(&mut self, uniform: &T) -> u32 {
+        let offset = align_to(self.data.len() as BufferAddress, self.alignment);
+        let bytes = bytemuck::bytes_of(uniform);
+
+        self.data.resize(offset as usize, 0);
+        self.data.extend_from_slice(bytes);
+        self.ranges.push((offset, bytes.len() as BufferAddress));
+
+        self.ranges.len() as u32 - 1
+    }
+
+    pub fn get_layout_entries(&self, visibility: wgpu::ShaderStages) -> Vec<wgpu::BindGroupLayoutEntry> {
+        self.ranges
+            .iter()
+            .enumerate()
+            .map(|(binding, (_, size))| wgpu::BindGroupLayoutEntry {
+                binding: binding as u32,
+                visibility,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: wgpu::BufferSize::new(*size),
+                },
+                count: None,
+            })
+            .collect()
+    }
+
+    pub fn build(&self, context: &GpuContext, visibility: wgpu::ShaderStages, label: &str) -> PackedUniforms {
+        let buffer = create_uniform_buffer_init(context, &self.data, label);
+
+        let bind_group_layout = context.device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &self.get_layout_entries(visibility),
+            label: Some(label),
+        });
+
+        let entries: Vec<wgpu::BindGroupEntry> = self
+            .ranges
+            .iter()
+            .enumerate()
+            .map(|(binding, (offset, size))| wgpu::BindGroupEntry {
+                binding: binding as u32,
+                resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                    buffer: &buffer,
+                    offset: *offset,
+                    size: wgpu::BufferSize::new(*size),
+                }),
+            })
+            .collect();
+
+        let bind_group = context.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &bind_group_layout,
+            entries: &entries,
+            label: Some(label),
+        });
+
+        PackedUniforms {
+            buffer,
+            bind_group_layout,
+            bind_group,
+            ranges: self.ranges.clone(),
+        }
+    }
+}
+
+pub struct PackedUniforms {
+    pub buffer: Buffer,
+    pub bind_group_layout: BindGroupLayout,
+    pub bind_group: BindGroup,
+    pub ranges: Vec<(BufferAddress, BufferAddress)>,
+}
+
+impl PackedUniforms {
+    pub fn update<T: bytemuck::Pod>(&self, context: &GpuContext, binding: u32, uniform: &T) -> Result<(), Error> {
+        let bytes = bytemuck::bytes_of(uniform);
+        match self.ranges.get(binding as usize) {
+            Some((offset, size)) if *size == bytes.len() as BufferAddress => {
+                context.queue.write_buffer(&self.buffer, *offset, bytes);
+                Ok(())
+            }
+            Some((_, size)) => Err(ValidationError(format!(
+                "packed uniform binding {} is {} bytes, got {}",
+                binding,
+                size,
+                bytes.len()
+            ))),
+            None => Err(ValidationError(format!("no packed uniform at binding {}", binding))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+
+    use glam::{vec3, Mat4};
+
+    use crate::buffers::{
+        assert_vertex_layout, cast_slice, cast_slice_mut, check_vertex_layout, get_dynamic_offset, get_dynamic_slot_size,
+        get_grown_capacity, get_slice_range, read_buffer, GrowableBuffer, UniformBuffer, UniformPacker,
+    };
+    use crate::gpu_context::GpuContext;
+    use crate::VertexLayout;
+
+    #[repr(C)]
+    #[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+    struct PackedVertex {
+        position: [i8; 4],
+        normal: [i8; 4],
+    }
+
+    const PACKED_VERTEX_ATTRIBUTES: [wgpu::VertexAttribute; 2] = [
+        wgpu::VertexAttribute {
+            shader_location: 0,
+            format: wgpu::VertexFormat::Sint8x4,
+            offset: 0,
+        },
+        wgpu::VertexAttribute {
+            shader_location: 1,
+            format: wgpu::VertexFormat::Sint8x4,
+            offset: 4,
+        },
+    ];
+
+    fn packed_vertex_layout(array_stride: u64, attributes: &[wgpu::VertexAttribute]) -> wgpu::VertexBufferLayout<'_> {
+        wgpu::VertexBufferLayout {
+            array_stride,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes,
+        }
+    }
+
+    #[test]
+    fn test_vertex_layout() {
+        assert_vertex_layout::<PackedVertex>(&packed_vertex_layout(8, &PACKED_VERTEX_ATTRIBUTES));
+
+        // an offset counted in i32 instead of i8 puts the normal past the end of the vertex
+        let mut attributes = PACKED_VERTEX_ATTRIBUTES;
+        attributes[1].offset = 16;
+        assert!(check_vertex_layout::<PackedVertex>(&packed_vertex_layout(8, &attributes)).is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "stride")]
+    fn test_vertex_layout_stride_mismatch() {
+        assert_vertex_layout::<PackedVertex>(&packed_vertex_layout(16, &PACKED_VERTEX_ATTRIBUTES));
+    }
+
+    #[repr(C)]
+    #[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable, VertexLayout)]
+    struct DerivedVertex {
+        #[vertex(location = 0, format = Float32x3)]
+        position: [f32; 3],
+        _padding: f32,
+        #[vertex(location = 2, format = Sint8x4)]
+        normal: [i8; 4],
+    }
+
+    #[repr(C)]
+    #[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable, VertexLayout)]
+    #[vertex(step_mode = Instance)]
+    struct DerivedInstance {
+        #[vertex(location = 5, format = Float32x4)]
+        color: [f32; 4],
+    }
+
+    #[test]
+    fn test_derived_vertex_layout() {
+        let layout = DerivedVertex::vertex_buffer_layout();
+        assert_vertex_layout::<DerivedVertex>(&layout);
+        assert_eq!(layout.array_stride, 20);
+        assert_eq!(layout.step_mode, wgpu::VertexStepMode::Vertex);
+
+        // padding fields are skipped, offsets include them
+        let locations: Vec<(u32, u64)> = layout.attributes.iter().map(|a| (a.shader_location, a.offset)).collect();
+        assert_eq!(locations, vec![(0, 0), (2, 16)]);
+
+        let layout = DerivedInstance::vertex_buffer_layout();
+        assert_vertex_layout::<DerivedInstance>(&layout);
+        assert_eq!(layout.step_mode, wgpu::VertexStepMode::Instance);
+        assert_eq!(layout.attributes[0].shader_location, 5);
+    }
+
+    #[test]
+    fn test_dynamic_uniform_offsets() {
+        // a 208 byte entity uniform takes a whole 256 byte slot
+        let slot_size = get_dynamic_slot_size(208, 256);
+        assert_eq!(slot_size, 256);
+        assert_eq!(get_dynamic_offset(slot_size, 3), 768);
+
+        // uniforms larger than the alignment round up to the next multiple
+        assert_eq!(get_dynamic_slot_size(mem::size_of::<[Mat4; 5]>() as u64, 256), 512);
+        assert_eq!(get_dynamic_slot_size(64, 64), 64);
+    }
+
+    #[test]
+    fn test_slice_range() {
+        assert_eq!(get_slice_range::<Mat4>(256, 1, 3).unwrap(), 64..256);
+        assert_eq!(get_slice_range::<u32>(16, 4, 0).unwrap(), 16..16);
+
+        // one element past the end
+        assert!(get_slice_range::<Mat4>(256, 2, 3).is_err());
+
+        // single bytes off the copy alignment
+        assert_eq!(get_slice_range::<u8>(16, 4, 8).unwrap(), 4..12);
+        assert!(get_slice_range::<u8>(16, 1, 4).is_err());
+        assert!(get_slice_range::<u8>(16, 0, 3).is_err());
+    }
+
+    #[test]
+    fn test_grown_capacity() {
+        assert_eq!(get_grown_capacity(4, 3), 4);
+        assert_eq!(get_grown_capacity(4, 5), 8);
+        assert_eq!(get_grown_capacity(4, 33), 64);
+        assert_eq!(get_grown_capacity(0, 3), 4);
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "gpu_tests"), ignore = "needs a gpu adapter")]
+    fn test_growable_buffer() {
+        let context = pollster::block_on(GpuContext::new_headless(4, 4)).unwrap();
+        let mut buffer = GrowableBuffer::<[f32; 4]>::new(&context, 2, wgpu::BufferUsages::VERTEX, "growable");
+
+        assert!(!buffer.push(&context, &[[1.0; 4], [2.0; 4]]).unwrap());
+        assert_eq!(buffer.len(), 2);
+
+        // the third element doesn't fit, the first two are copied over
+        assert!(buffer.push(&context, &[[3.0; 4]]).unwrap());
+        assert_eq!(buffer.capacity(), 4);
+        assert!(buffer.usage().contains(wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_SRC));
+
+        let data = read_buffer(&context, &buffer.buffer);
+        let values: &[[f32; 4]] = cast_slice(&data).unwrap();
+        assert_eq!(values[..3], [[1.0; 4], [2.0; 4], [3.0; 4]]);
+
+        // a write far past the end doubles until it fits
+        assert!(buffer.write(&context, 10, &[[4.0; 4]]).unwrap());
+        assert_eq!((buffer.capacity(), buffer.len()), (16, 11));
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "gpu_tests"), ignore = "needs a gpu adapter")]
+    fn test_growable_buffer_misaligned_write() {
+        let context = pollster::block_on(GpuContext::new_headless(4, 4)).unwrap();
+        let mut buffer = GrowableBuffer::<u8>::new(&context, 4, wgpu::BufferUsages::VERTEX, "growable bytes");
+        assert!(!buffer.push(&context, &[1, 2, 3, 4]).unwrap());
+        let id = buffer.buffer.global_id();
+
+        // would need to grow, but starts off the copy alignment
+        assert!(buffer.write(&context, 5, &[5, 6, 7]).is_err());
+        assert_eq!((buffer.capacity(), buffer.len()), (4, 4));
+        assert_eq!(buffer.buffer.global_id(), id);
+
+        assert!(buffer.write(&context, 4, &[5, 6, 7, 8]).unwrap());
+        assert_eq!(read_buffer(&context, &buffer.buffer)[..8], [1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn test_cast_slice() {
+        let values = [1.0f32, 2.0, 3.0, 4.0];
+        let data = bytemuck::cast_slice::<f32, u8>(&values).to_vec();
+
+        let typed: &[[f32; 4]] = cast_slice(&data).unwrap();
+        assert_eq!(typed, &[[1.0, 2.0, 3.0, 4.0]]);
+
+        assert!(cast_slice::<[f32; 4]>(&data[..15]).is_err());
+
+        let mut data = data;
+        let typed: &mut [f32] = cast_slice_mut(&mut data).unwrap();
+        typed[0] = 5.0;
+        assert_eq!(cast_slice::<f32>(&data).unwrap()[0], 5.0);
+    }
+
+    #[test]
+    fn test_packed_uniforms() {
+        let mut packer = UniformPacker::new(256);
+        let color_binding = packer.push(&[1.0f32, 0.5, 0.25, 1.0]);
+        let transform_binding = packer.push(&Mat4::IDENTITY.to_cols_array());
+        assert_eq!((color_binding, transform_binding), (0, 1));
+
+        assert_eq!(packer.ranges, vec![(0, 16), (256, 64)]);
+        assert_eq!(packer.data.len(), 256 + 64);
+        assert_eq!(bytemuck::pod_read_unaligned::<f32>(&packer.data[256..260]), 1.0);
+
+        let entries = packer.get_layout_entries(wgpu::ShaderStages::VERTEX);
+        let bindings: Vec<(u32, Option<u64>)> = entries
+            .iter()
+            .map(|entry| match entry.ty {
+                wgpu::BindingType::Buffer { min_binding_size, .. } => (entry.binding, min_binding_size.map(|size| size.get())),
+                _ => panic!("expected a buffer binding"),
+            })
+            .collect();
+        assert_eq!(bindings, vec![(0, Some(16)), (1, Some(64))]);
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "gpu_tests"), ignore = "needs a gpu adapter")]
+    fn test_uniform_buffer_update() {
+        let context = pollster::block_on(GpuContext::new_headless(4, 4)).unwrap();
+        let uniform = UniformBuffer::new(&context, &Mat4::IDENTITY, wgpu::BufferUsages::COPY_SRC);
+        assert_eq!(uniform.size(), mem::size_of::<Mat4>() as wgpu::BufferAddress);
+
+        let value = Mat4::from_scale(vec3(2.0, 3.0, 4.0));
+        uniform.update(&context, &value);
+        assert_eq!(read_buffer(&context, &uniform.buffer), bytemuck::bytes_of(&value));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[cfg_attr(not(feature = "gpu_tests"), ignore = "needs a gpu adapter")]
+    #[should_panic(expected = "is 64 bytes but the uniform buffer has 16")]
+    fn test_uniform_buffer_size_mismatch() {
+        let context = pollster::block_on(GpuContext::new_headless(4, 4)).unwrap();
+        let mut uniform = UniformBuffer::new(&context, &Mat4::IDENTITY, wgpu::BufferUsages::empty());
+
+        // a buffer created for a smaller struct
+        uniform.buffer = context.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("vec4 uniform"),
+            size: 16,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        uniform.update(&context, &Mat4::IDENTITY);
+    }
+}
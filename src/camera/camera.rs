@@ -1,11 +1,19 @@
 use glam::*;
 
+use crate::camera::projection::get_perspective_matrix;
+use crate::input::Input;
+
 // Default camera values
 pub const YAW: f32 = -90.0;
 pub const PITCH: f32 = 0.0;
 pub const SPEED: f32 = 100.5;
 pub const SENSITIVITY: f32 = 0.1;
 pub const ZOOM: f32 = 45.0;
+pub const NEAR: f32 = 0.1;
+pub const FAR: f32 = 1000.0;
+
+// pitch limit in degrees, looking straight up or down leaves no right vector
+pub const MAX_PITCH: f32 = 89.0;
 
 // Defines several possible options for camera movement. Used as abstraction
 // to stay away from window-system specific input methods
@@ -32,7 +40,15 @@ pub struct Camera {
     // camera options
     pub movement_speed: f32,
     pub mouse_sensitivity: f32,
+    // vertical field of view in degrees
     pub zoom: f32,
+    pub near: f32,
+    pub far: f32,
+}
+
+// Moves a camera with a frame's input, see OrbitController and FpsController
+pub trait CameraController {
+    fn update(&mut self, camera: &mut Camera, input: &Input, delta_time: f32);
 }
 
 impl Camera {
@@ -48,6 +64,8 @@ impl Camera {
             movement_speed: SPEED,
             mouse_sensitivity: SENSITIVITY,
             zoom: ZOOM,
+            near: NEAR,
+            far: FAR,
         }
     }
 
@@ -79,8 +97,9 @@ impl Camera {
         camera
     }
 
-    // calculates the front vector from the Camera's (updated) Euler Angles
-    fn update_camera_vectors(&mut self) {
+    // calculates the front vector from the Camera's (updated) Euler Angles. Yaw and pitch are
+    // relative to world_up, for a z up world the angles are those of the rotated y up frame.
+    pub fn update_camera_vectors(&mut self) {
         // calculate the new Front vector
         let front = vec3(
             self.yaw.to_radians().cos() * self.pitch.to_radians().cos(),
@@ -88,7 +107,7 @@ impl Camera {
             self.yaw.to_radians().sin() * self.pitch.to_radians().cos(),
         );
 
-        self.front = front.normalize_or_zero();
+        self.front = (self.get_up_rotation() * front).normalize_or_zero();
 
         // also re-calculate the Right and Up vector
         // normalize the vectors, because their length gets closer to 0 the more you look up or down which results in slower movement.
@@ -96,11 +115,40 @@ impl Camera {
         self.up = self.right.cross(self.front).normalize_or_zero();
     }
 
+    // rotation from y up to world_up
+    fn get_up_rotation(&self) -> Quat {
+        let world_up = self.world_up.normalize_or_zero();
+        if world_up == Vec3::ZERO {
+            return Quat::IDENTITY;
+        }
+        Quat::from_rotation_arc(Vec3::Y, world_up)
+    }
+
+    // Sets yaw and pitch to face target, the pitch is limited to MAX_PITCH
+    pub fn look_at(&mut self, target: Vec3) {
+        let direction = self.get_up_rotation().inverse() * (target - self.position).normalize_or_zero();
+        if direction == Vec3::ZERO {
+            return;
+        }
+
+        self.yaw = direction.z.atan2(direction.x).to_degrees();
+        self.pitch = direction.y.clamp(-1.0, 1.0).asin().to_degrees().clamp(-MAX_PITCH, MAX_PITCH);
+        self.update_camera_vectors();
+    }
+
     // returns the view matrix calculated using Euler Angles and the LookAt Matrix
     pub fn get_view_matrix(&self) -> Mat4 {
         Mat4::look_to_rh(self.position, self.front, self.up)
     }
 
+    pub fn get_projection_matrix(&self, aspect_ratio: f32) -> Mat4 {
+        get_perspective_matrix(self.zoom.to_radians(), aspect_ratio, self.near, self.far)
+    }
+
+    pub fn projection_view(&self, aspect_ratio: f32) -> Mat4 {
+        self.get_projection_matrix(aspect_ratio) * self.get_view_matrix()
+    }
+
     // processes input received from any keyboard-like input system. Accepts input parameter
     // in the form of camera defined ENUM (to abstract it from windowing systems)
     pub fn process_keyboard(&mut self, direction: CameraMovement, delta_time: f32) {
@@ -129,12 +177,7 @@ impl Camera {
 
         // make sure that when pitch is out of bounds, screen doesn't get flipped
         if constrain_pitch {
-            if self.pitch > 89.0 {
-                self.pitch = 89.0;
-            }
-            if self.pitch < -89.0 {
-                self.pitch = -89.0;
-            }
+            self.pitch = self.pitch.clamp(-MAX_PITCH, MAX_PITCH);
         }
 
         // update Front, Right and Up Vectors using the updated Euler angles
@@ -154,3 +197,37 @@ impl Camera {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use glam::{vec3, Vec2, Vec3};
+
+    use crate::camera::camera::Camera;
+
+    #[test]
+    fn test_look_at() {
+        // y up keeps the original angles, yaw -90 looks down -z
+        let camera = Camera::camera_vec3(vec3(0.0, 0.0, 3.0));
+        assert!(camera.front.abs_diff_eq(Vec3::NEG_Z, 1e-6));
+
+        let target = vec3(1.0, 2.0, -4.0);
+        for world_up in [Vec3::Y, Vec3::Z] {
+            let mut camera = Camera::camera_vec3_up_yaw_pitch(vec3(3.0, -20.0, 6.0), world_up, 0.0, 0.0);
+            camera.look_at(target);
+
+            let direction = (target - camera.position).normalize();
+            assert!(camera.front.abs_diff_eq(direction, 1e-5), "{} != {}", camera.front, direction);
+            assert!(camera.up.dot(world_up) > 0.0);
+
+            // the target projects to the center of the view
+            let clip = camera.projection_view(1.5) * target.extend(1.0);
+            assert!((clip.truncate() / clip.w).truncate().abs_diff_eq(Vec2::ZERO, 1e-5));
+        }
+
+        // straight down is limited so the view stays defined
+        let mut camera = Camera::camera_vec3(vec3(0.0, 10.0, 0.0));
+        camera.look_at(Vec3::ZERO);
+        assert_eq!(camera.pitch, -89.0);
+        assert!(camera.get_view_matrix().is_finite());
+    }
+}
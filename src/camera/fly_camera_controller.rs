@@ -1,4 +1,5 @@
 use crate::camera::camera_handler::CameraUniform;
+use crate::camera::projection::{get_aspect_ratio, get_perspective_matrix};
 use crate::gpu_context::GpuContext;
 use crate::input::Input;
 use glam::{Mat4, Quat, Vec3};
@@ -90,8 +91,9 @@ impl FlyCameraController {
         rotation.normalize()
     }
 
+    // clamped so a minimized window or bad settings don't give NaNs
     pub fn get_projection_matrix(&self) -> Mat4 {
-        Mat4::perspective_rh(self.fov, self.aspect_ratio, self.near, self.far)
+        get_perspective_matrix(self.fov, self.aspect_ratio, self.near, self.far)
     }
 
     pub fn get_view_matrix(&self) -> Mat4 {
@@ -113,7 +115,7 @@ impl FlyCameraController {
     }
 
     pub fn resize(&mut self, context: &GpuContext) {
-        let size = context.window.inner_size();
-        self.aspect_ratio = size.width as f32 / size.height as f32;
+        let size = context.size;
+        self.aspect_ratio = get_aspect_ratio(size.width, size.height);
     }
 }
@@ -0,0 +1,105 @@
+use glam::{Vec2, Vec3};
+use winit::event::MouseButton;
+use winit::keyboard::KeyCode;
+
+use crate::camera::camera::{Camera, CameraController};
+use crate::input::Input;
+
+// WASD moves along the view at the camera's movement_speed, space and left control along up,
+// left shift speeds up. The mouse looks around scaled by the camera's mouse_sensitivity.
+#[derive(Debug, Clone, Copy)]
+pub struct FpsController {
+    // looks while the button is held, always when None, e.g. with a grabbed cursor
+    pub look_button: Option<MouseButton>,
+    pub sprint_multiplier: f32,
+}
+
+impl Default for FpsController {
+    fn default() -> Self {
+        FpsController {
+            look_button: Some(MouseButton::Right),
+            sprint_multiplier: 2.0,
+        }
+    }
+}
+
+impl FpsController {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl CameraController for FpsController {
+    fn update(&mut self, camera: &mut Camera, input: &Input, delta_time: f32) {
+        let mut direction = Vec3::ZERO;
+        for (key, axis) in [
+            (KeyCode::KeyW, camera.front),
+            (KeyCode::KeyS, -camera.front),
+            (KeyCode::KeyD, camera.right),
+            (KeyCode::KeyA, -camera.right),
+            (KeyCode::Space, camera.up),
+            (KeyCode::ControlLeft, -camera.up),
+        ] {
+            if input.key_pressed(key) {
+                direction += axis;
+            }
+        }
+
+        let mut speed = camera.movement_speed * delta_time;
+        if input.key_pressed(KeyCode::ShiftLeft) {
+            speed *= self.sprint_multiplier;
+        }
+        // diagonals aren't faster
+        camera.position += direction.normalize_or_zero() * speed;
+
+        let looking = match self.look_button {
+            Some(button) => input.mouse_button_pressed(button),
+            None => true,
+        };
+        let mouse_delta = input.mouse_delta();
+        if looking && mouse_delta != Vec2::ZERO {
+            // screen y grows downwards
+            camera.process_mouse_movement(mouse_delta.x, -mouse_delta.y, true);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{vec2, vec3, Vec3};
+    use winit::event::MouseButton;
+    use winit::keyboard::KeyCode;
+
+    use crate::camera::camera::{Camera, CameraController};
+    use crate::camera::fps_controller::FpsController;
+    use crate::input::Input;
+
+    #[test]
+    fn test_fps_movement() {
+        let mut camera = Camera::camera_vec3(Vec3::ZERO);
+        camera.movement_speed = 2.0;
+        let mut controller = FpsController::new();
+
+        let mut input = Input::default();
+        input.keys_held.insert(KeyCode::KeyW);
+        controller.update(&mut camera, &input, 0.5);
+        assert!(camera.position.abs_diff_eq(vec3(0.0, 0.0, -1.0), 1e-6));
+
+        // forward and right together move at the same speed
+        input.keys_held.insert(KeyCode::KeyD);
+        let start = camera.position;
+        controller.update(&mut camera, &input, 0.5);
+        assert!(((camera.position - start).length() - 1.0).abs() < 1e-5);
+
+        // the mouse only looks while the look button is held
+        let mut input = Input::default();
+        input.mouse_delta = vec2(100.0, 0.0);
+        let yaw = camera.yaw;
+        controller.update(&mut camera, &input, 0.5);
+        assert_eq!(camera.yaw, yaw);
+
+        input.mouse_buttons_held.insert(MouseButton::Right);
+        controller.update(&mut camera, &input, 0.5);
+        assert!((camera.yaw - (yaw + 100.0 * camera.mouse_sensitivity)).abs() < 1e-4);
+    }
+}
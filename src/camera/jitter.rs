@@ -0,0 +1,128 @@
+use glam::{vec2, vec3, Mat4, Vec2};
+
+pub const DEFAULT_JITTER_SAMPLES: u32 = 8;
+
+// Radical inverse of index in the given base. Index 0 maps to 0.0 so callers start at 1.
+pub fn halton(mut index: u32, base: u32) -> f32 {
+    let mut result = 0.0;
+    let mut fraction = 1.0;
+    while index > 0 {
+        fraction /= base as f32;
+        result += fraction * (index % base) as f32;
+        index /= base;
+    }
+    result
+}
+
+// Sub-pixel offset for the frame in the range -0.5..0.5 pixels, from the Halton(2,3) sequence
+pub fn get_jitter_offset(frame_index: u32, sample_count: u32) -> Vec2 {
+    let index = frame_index % sample_count.max(1) + 1;
+    vec2(halton(index, 2) - 0.5, halton(index, 3) - 0.5)
+}
+
+// Offsets the projection by a pixel offset. Applied in clip space so it works for
+// both perspective and orthographic projections.
+pub fn apply_jitter(projection: &Mat4, pixel_offset: Vec2, width: u32, height: u32) -> Mat4 {
+    let ndc_offset = ndc_jitter(pixel_offset, width, height);
+    Mat4::from_translation(vec3(ndc_offset.x, ndc_offset.y, 0.0)) * *projection
+}
+
+// One pixel spans 2 / size in ndc
+pub fn ndc_jitter(pixel_offset: Vec2, width: u32, height: u32) -> Vec2 {
+    vec2(
+        2.0 * pixel_offset.x / width.max(1) as f32,
+        2.0 * pixel_offset.y / height.max(1) as f32,
+    )
+}
+
+// Tracks the current and previous frame jitter for temporal reprojection
+#[derive(Debug, Clone)]
+pub struct ProjectionJitter {
+    pub enabled: bool,
+    pub sample_count: u32,
+    pub current: Vec2,
+    pub previous: Vec2,
+}
+
+impl ProjectionJitter {
+    pub fn new() -> Self {
+        ProjectionJitter {
+            enabled: true,
+            sample_count: DEFAULT_JITTER_SAMPLES,
+            current: Vec2::ZERO,
+            previous: Vec2::ZERO,
+        }
+    }
+
+    pub fn set_sample_count(mut self, sample_count: u32) -> Self {
+        self.sample_count = sample_count;
+        self
+    }
+
+    pub fn update(&mut self, frame_index: u32) {
+        self.previous = self.current;
+        self.current = if self.enabled {
+            get_jitter_offset(frame_index, self.sample_count)
+        } else {
+            Vec2::ZERO
+        };
+    }
+
+    pub fn apply(&self, projection: &Mat4, width: u32, height: u32) -> Mat4 {
+        apply_jitter(projection, self.current, width, height)
+    }
+
+    pub fn get_current(&self) -> Vec2 {
+        self.current
+    }
+
+    pub fn get_previous(&self) -> Vec2 {
+        self.previous
+    }
+}
+
+impl Default for ProjectionJitter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::camera::jitter::{apply_jitter, get_jitter_offset, halton, ProjectionJitter};
+    use glam::{vec2, vec4, Mat4};
+
+    #[test]
+    fn test_halton_sequence() {
+        let base_2 = [0.5, 0.25, 0.75, 0.125];
+        let base_3 = [1.0 / 3.0, 2.0 / 3.0, 1.0 / 9.0, 4.0 / 9.0];
+
+        for i in 0..4 {
+            assert!((halton(i as u32 + 1, 2) - base_2[i]).abs() < 1e-6);
+            assert!((halton(i as u32 + 1, 3) - base_3[i]).abs() < 1e-6);
+
+            let offset = get_jitter_offset(i as u32, 8);
+            assert!((offset - vec2(base_2[i] - 0.5, base_3[i] - 0.5)).length() < 1e-6);
+        }
+
+        // the sequence repeats after sample_count frames
+        assert_eq!(get_jitter_offset(8, 8), get_jitter_offset(0, 8));
+    }
+
+    #[test]
+    fn test_jitter_previous_and_apply() {
+        let mut jitter = ProjectionJitter::new();
+        jitter.update(0);
+        jitter.update(1);
+        assert_eq!(jitter.get_previous(), get_jitter_offset(0, 8));
+        assert_eq!(jitter.get_current(), get_jitter_offset(1, 8));
+
+        // a half pixel offset on a 100 pixel wide target moves ndc x by 0.01
+        let projection = Mat4::perspective_rh(45f32.to_radians(), 1.0, 0.1, 100.0);
+        let jittered = apply_jitter(&projection, vec2(0.5, 0.0), 100, 100);
+
+        let clip = projection * vec4(0.0, 0.0, -10.0, 1.0);
+        let jittered_clip = jittered * vec4(0.0, 0.0, -10.0, 1.0);
+        assert!((jittered_clip.x / jittered_clip.w - clip.x / clip.w - 0.01).abs() < 1e-6);
+    }
+}
@@ -1,4 +1,8 @@
 pub mod camera;
 pub mod camera_handler;
 pub mod fly_camera_controller;
+pub mod fps_controller;
+pub mod jitter;
 pub mod orbit_camera;
+pub mod orbit_controller;
+pub mod projection;
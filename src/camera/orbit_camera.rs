@@ -1,6 +1,7 @@
+use crate::camera::projection::get_perspective_matrix;
 use crate::input::Input;
 use crate::transform::Transform;
-use glam::{Mat3, Quat, Vec2, Vec3};
+use glam::{Mat3, Mat4, Quat, Vec2, Vec3};
 use winit::keyboard::KeyCode;
 
 pub struct PerspectiveProjection {
@@ -19,6 +20,10 @@ impl PerspectiveProjection {
             far: 1000.0,
         }
     }
+
+    pub fn get_projection_matrix(&self) -> Mat4 {
+        get_perspective_matrix(self.fov, self.aspect_ratio, self.near, self.far)
+    }
 }
 
 struct PanOrbitCamera {
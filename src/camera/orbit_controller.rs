@@ -0,0 +1,98 @@
+use glam::Vec3;
+use winit::event::MouseButton;
+
+use crate::camera::camera::{Camera, CameraController, MAX_PITCH};
+use crate::input::Input;
+
+pub const MIN_ORBIT_DISTANCE: f32 = 0.1;
+pub const MAX_ORBIT_DISTANCE: f32 = 10000.0;
+
+// Rotates the camera around target while the button is held, the mouse wheel zooms in and out
+#[derive(Debug, Clone, Copy)]
+pub struct OrbitController {
+    pub target: Vec3,
+    pub distance: f32,
+    pub min_distance: f32,
+    pub max_distance: f32,
+    // degrees per pixel of mouse movement
+    pub rotate_sensitivity: f32,
+    // each wheel step scales the distance by exp(-zoom_speed)
+    pub zoom_speed: f32,
+    pub button: MouseButton,
+}
+
+impl OrbitController {
+    // Starts from the camera's position, which is turned towards target
+    pub fn new(camera: &mut Camera, target: Vec3) -> Self {
+        camera.look_at(target);
+        OrbitController {
+            target,
+            distance: (camera.position - target).length().max(MIN_ORBIT_DISTANCE),
+            min_distance: MIN_ORBIT_DISTANCE,
+            max_distance: MAX_ORBIT_DISTANCE,
+            rotate_sensitivity: 0.3,
+            zoom_speed: 0.1,
+            button: MouseButton::Left,
+        }
+    }
+}
+
+impl CameraController for OrbitController {
+    fn update(&mut self, camera: &mut Camera, input: &Input, _delta_time: f32) {
+        if input.mouse_button_pressed(self.button) {
+            let mouse_delta = input.mouse_delta();
+            camera.yaw += mouse_delta.x * self.rotate_sensitivity;
+            camera.pitch = (camera.pitch - mouse_delta.y * self.rotate_sensitivity).clamp(-MAX_PITCH, MAX_PITCH);
+        }
+
+        let zoom = (-input.mouse_wheel_delta() * self.zoom_speed).exp();
+        self.distance = (self.distance * zoom).clamp(self.min_distance, self.max_distance);
+
+        camera.update_camera_vectors();
+        camera.position = self.target - camera.front * self.distance;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{vec2, vec3, Vec3};
+    use winit::event::MouseButton;
+
+    use crate::camera::camera::{Camera, CameraController, MAX_PITCH};
+    use crate::camera::orbit_controller::OrbitController;
+    use crate::input::Input;
+
+    #[test]
+    fn test_orbit() {
+        let target = vec3(1.0, 0.0, 2.0);
+        let mut camera = Camera::camera_vec3_up_yaw_pitch(vec3(1.0, -10.0, 2.0), Vec3::Z, 0.0, 0.0);
+        let mut controller = OrbitController::new(&mut camera, target);
+        assert!((controller.distance - 10.0).abs() < 1e-5);
+
+        // no input leaves the camera in place
+        let start = camera.position;
+        controller.update(&mut camera, &Input::default(), 0.016);
+        assert!(camera.position.abs_diff_eq(start, 1e-4));
+
+        let mut input = Input::default();
+        input.mouse_buttons_held.insert(MouseButton::Left);
+        input.mouse_delta = vec2(150.0, -40.0);
+        controller.update(&mut camera, &input, 0.016);
+
+        assert!(!camera.position.abs_diff_eq(start, 1e-2));
+        assert!(((camera.position - target).length() - 10.0).abs() < 1e-4);
+        assert!(camera.front.abs_diff_eq((target - camera.position).normalize(), 1e-5));
+
+        // dragging far enough stops short of looking straight down
+        input.mouse_delta = vec2(0.0, 10000.0);
+        controller.update(&mut camera, &input, 0.016);
+        assert_eq!(camera.pitch, -MAX_PITCH);
+
+        // zooming in is limited by min_distance
+        let mut input = Input::default();
+        input.mouse_wheel_delta = 1000.0;
+        controller.update(&mut camera, &input, 0.016);
+        assert_eq!(controller.distance, controller.min_distance);
+        assert!(((camera.position - target).length() - controller.min_distance).abs() < 1e-4);
+    }
+}
@@ -0,0 +1,90 @@
+use std::f32::consts;
+
+use glam::Mat4;
+
+pub const MIN_NEAR: f32 = 1e-4;
+// radians, keeps the fov strictly between 0 and pi
+pub const MIN_FOV: f32 = 1e-3;
+
+// Width over height, 1.0 while the window is minimized and either side is 0
+pub fn get_aspect_ratio(width: u32, height: u32) -> f32 {
+    match (width, height) {
+        (0, _) | (_, 0) => 1.0,
+        _ => width as f32 / height as f32,
+    }
+}
+
+// Perspective parameters that give a well defined matrix. NaN or non positive aspect ratios
+// become 1.0, near is kept above 0 and far beyond near. An infinite far is kept.
+pub fn clamp_perspective(fov: f32, aspect_ratio: f32, near: f32, far: f32) -> (f32, f32, f32, f32) {
+    let fov = if fov.is_nan() {
+        consts::FRAC_PI_4
+    } else {
+        fov.clamp(MIN_FOV, consts::PI - MIN_FOV)
+    };
+    let aspect_ratio = if aspect_ratio > 0.0 && aspect_ratio.is_finite() {
+        aspect_ratio
+    } else {
+        1.0
+    };
+    let near = if near > 0.0 && near.is_finite() {
+        near.max(MIN_NEAR)
+    } else {
+        MIN_NEAR
+    };
+    let far = if far > near { far } else { near * 2.0 };
+    (fov, aspect_ratio, near, far)
+}
+
+// Mat4::perspective_rh with clamped parameters, never NaN
+pub fn get_perspective_matrix(fov: f32, aspect_ratio: f32, near: f32, far: f32) -> Mat4 {
+    let (fov, aspect_ratio, near, far) = clamp_perspective(fov, aspect_ratio, near, far);
+    match far.is_finite() {
+        true => Mat4::perspective_rh(fov, aspect_ratio, near, far),
+        false => Mat4::perspective_infinite_rh(fov, aspect_ratio, near),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts;
+
+    use glam::Mat4;
+
+    use crate::camera::projection::{clamp_perspective, get_aspect_ratio, get_perspective_matrix, MIN_FOV, MIN_NEAR};
+
+    fn is_finite(matrix: &Mat4) -> bool {
+        matrix.to_cols_array().iter().all(|value| value.is_finite())
+    }
+
+    #[test]
+    fn test_minimized_window_projection() {
+        // a minimized window reports a 0 height
+        let aspect_ratio = get_aspect_ratio(1280, 0);
+        assert_eq!(aspect_ratio, 1.0);
+        assert_eq!(get_aspect_ratio(1280, 720), 1280.0 / 720.0);
+
+        // dividing by the 0 height directly gives inf, a 0 width gives 0
+        for aspect_ratio in [0.0, 1280.0 / 0.0, 0.0 / 0.0, -1.5] {
+            let projection = get_perspective_matrix(consts::FRAC_PI_4, aspect_ratio, 0.1, 100.0);
+            assert!(is_finite(&projection), "aspect {} gives {}", aspect_ratio, projection);
+        }
+        assert!(!is_finite(&Mat4::perspective_rh(consts::FRAC_PI_4, 0.0, 0.1, 100.0)));
+    }
+
+    #[test]
+    fn test_clamp_perspective() {
+        let (fov, aspect_ratio, near, far) = clamp_perspective(consts::PI, 1.5, 0.0, 100.0);
+        assert_eq!((fov, aspect_ratio, near, far), (consts::PI - MIN_FOV, 1.5, MIN_NEAR, 100.0));
+
+        let (fov, _, near, far) = clamp_perspective(0.0, 1.5, 10.0, 5.0);
+        assert_eq!((fov, near, far), (MIN_FOV, 10.0, 20.0));
+
+        // sane parameters are unchanged
+        assert_eq!(clamp_perspective(1.0, 1.5, 0.1, 1000.0), (1.0, 1.5, 0.1, 1000.0));
+
+        let infinite = get_perspective_matrix(1.0, 1.5, 0.1, f32::INFINITY);
+        assert!(is_finite(&infinite));
+        assert!(is_finite(&get_perspective_matrix(f32::NAN, 1.5, -1.0, f32::NAN)));
+    }
+}
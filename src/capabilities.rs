@@ -0,0 +1,90 @@
+use crate::error::Error;
+use crate::error::Error::UnsupportedError;
+
+// What the device can do beyond WebGL2, taken from its downlevel flags, optional features and limits.
+// Helpers needing compute or storage buffers check these and fail with UnsupportedError.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    pub compute_shaders: bool,
+    pub storage_buffers: bool,
+    pub fragment_writable_storage: bool,
+    pub independent_blend: bool,
+    // AddressMode::ClampToBorder, SamplerBuilder falls back to ClampToEdge without it
+    pub clamp_to_border: bool,
+    pub max_compute_workgroups_per_dimension: u32,
+}
+
+impl Capabilities {
+    // features are the ones the device was created with
+    pub fn new(flags: wgpu::DownlevelFlags, features: wgpu::Features, limits: &wgpu::Limits) -> Self {
+        Capabilities {
+            compute_shaders: flags.contains(wgpu::DownlevelFlags::COMPUTE_SHADERS),
+            storage_buffers: limits.max_storage_buffers_per_shader_stage > 0,
+            fragment_writable_storage: flags.contains(wgpu::DownlevelFlags::FRAGMENT_WRITABLE_STORAGE),
+            independent_blend: flags.contains(wgpu::DownlevelFlags::INDEPENDENT_BLEND),
+            clamp_to_border: features.contains(wgpu::Features::ADDRESS_MODE_CLAMP_TO_BORDER),
+            max_compute_workgroups_per_dimension: limits.max_compute_workgroups_per_dimension,
+        }
+    }
+
+    pub fn from_adapter(adapter: &wgpu::Adapter, features: wgpu::Features, limits: &wgpu::Limits) -> Self {
+        Self::new(adapter.get_downlevel_capabilities().flags, features, limits)
+    }
+
+    // The capabilities of the browser's WebGL2 backend
+    pub fn webgl2() -> Self {
+        Self::new(
+            wgpu::DownlevelCapabilities::lowest_downlevel().flags,
+            wgpu::Features::empty(),
+            &wgpu::Limits::downlevel_webgl2_defaults(),
+        )
+    }
+
+    pub fn require_compute(&self, name: &str) -> Result<(), Error> {
+        if !self.compute_shaders || self.max_compute_workgroups_per_dimension == 0 {
+            return Err(UnsupportedError(format!(
+                "{} needs compute shaders, which the device doesn't support",
+                name
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn require_storage_buffers(&self, name: &str) -> Result<(), Error> {
+        if !self.storage_buffers {
+            return Err(UnsupportedError(format!(
+                "{} needs storage buffers, which the device doesn't support",
+                name
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::capabilities::Capabilities;
+    use crate::depth_reduction::check_depth_reduction_support;
+    use crate::error::Error::UnsupportedError;
+    use crate::prefix_sum::check_prefix_sum_support;
+
+    #[test]
+    fn test_webgl2_compute_unsupported() {
+        let webgl2 = Capabilities::webgl2();
+        assert!(!webgl2.compute_shaders && !webgl2.storage_buffers && !webgl2.clamp_to_border);
+
+        assert!(matches!(check_prefix_sum_support(&webgl2, 1024), Err(UnsupportedError(_))));
+        assert!(matches!(
+            check_depth_reduction_support(&webgl2, 1280, 720),
+            Err(UnsupportedError(_))
+        ));
+        assert!(matches!(webgl2.require_storage_buffers("light grid"), Err(UnsupportedError(_))));
+        // what ComputePipelineBuilder::build and StorageBuffer::new check
+        assert!(matches!(webgl2.require_compute("compute pipeline"), Err(UnsupportedError(_))));
+
+        let full = Capabilities::new(wgpu::DownlevelFlags::all(), wgpu::Features::all(), &wgpu::Limits::default());
+        assert!(check_prefix_sum_support(&full, 1024).is_ok());
+        assert!(check_depth_reduction_support(&full, 1280, 720).is_ok());
+        assert!(full.clamp_to_border);
+    }
+}
@@ -0,0 +1,198 @@
+use wgpu::{BindGroup, BindGroupLayout, ShaderModule};
+
+use crate::error::Error;
+use crate::error::Error::ValidationError;
+use crate::gpu_context::GpuContext;
+#[cfg(feature = "reflection")]
+use crate::shader_bindings::check_shader_bindings;
+
+// Compute pipeline for one entry point of a shader, bind group layouts in group order
+#[derive(Debug, Clone)]
+pub struct ComputePipelineBuilder<'a> {
+    pub label: &'a str,
+    pub entry_point: &'a str,
+    pub bind_group_layouts: Vec<&'a BindGroupLayout>,
+}
+
+impl<'a> ComputePipelineBuilder<'a> {
+    pub fn new(entry_point: &'a str) -> Self {
+        ComputePipelineBuilder {
+            label: "compute pipeline",
+            entry_point,
+            bind_group_layouts: vec![],
+        }
+    }
+
+    pub fn label(mut self, label: &'a str) -> Self {
+        self.label = label;
+        self
+    }
+
+    pub fn bind_group_layout(mut self, layout: &'a BindGroupLayout) -> Self {
+        self.bind_group_layouts.push(layout);
+        self
+    }
+
+    // Like PipelineBuilder::check_bindings, with the entries of each layout in group order
+    #[cfg(feature = "reflection")]
+    pub fn check_bindings(&self, source: &str, layout_entries: &[&[wgpu::BindGroupLayoutEntry]]) -> Result<(), Error> {
+        check_shader_bindings(source, &[self.entry_point], layout_entries)
+    }
+
+    // An UnsupportedError on devices without compute shaders, e.g. WebGL2, and a ValidationError
+    // when the bindings don't match the layouts, see BindingRegistry
+    pub fn build(&self, context: &GpuContext, shader: &ShaderModule) -> Result<ComputePipeline, Error> {
+        context.capabilities.require_compute(self.label)?;
+
+        context
+            .binding_registry
+            .check_pipeline(self.label, shader, &[self.entry_point], &self.bind_group_layouts)?;
+
+        let pipeline_layout = context.device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some(self.label),
+            bind_group_layouts: &self.bind_group_layouts,
+            push_constant_ranges: &[],
+        });
+
+        let pipeline = context.device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some(self.label),
+            layout: Some(&pipeline_layout),
+            module: shader,
+            entry_point: self.entry_point,
+        });
+
+        Ok(ComputePipeline {
+            pipeline,
+            label: self.label.to_string(),
+        })
+    }
+}
+
+pub struct ComputePipeline {
+    pub pipeline: wgpu::ComputePipeline,
+    pub label: String,
+}
+
+impl ComputePipeline {
+    // Records a compute pass with bind_groups set from group 0, for chaining with other passes
+    pub fn record(&self, encoder: &mut wgpu::CommandEncoder, bind_groups: &[&BindGroup], workgroups: [u32; 3]) {
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some(&self.label),
+            timestamp_writes: None,
+        });
+        pass.set_pipeline(&self.pipeline);
+        for (index, bind_group) in bind_groups.iter().enumerate() {
+            pass.set_bind_group(index as u32, bind_group, &[]);
+        }
+        let [x, y, z] = workgroups;
+        pass.dispatch_workgroups(x, y, z);
+    }
+
+    // Encodes and submits a single dispatch of x * y * z workgroups
+    pub fn dispatch(&self, context: &GpuContext, bind_groups: &[&BindGroup], x: u32, y: u32, z: u32) -> Result<(), Error> {
+        check_workgroup_counts(&context.device.limits(), [x, y, z])?;
+
+        let mut encoder = context
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: Some(&self.label) });
+        self.record(&mut encoder, bind_groups, [x, y, z]);
+        context.queue.submit(std::iter::once(encoder.finish()));
+        Ok(())
+    }
+}
+
+// Workgroups covering invocation_count invocations, the shader skips the ones past the end
+pub fn get_workgroup_count(invocation_count: u32, workgroup_size: u32) -> u32 {
+    invocation_count.div_ceil(workgroup_size.max(1))
+}
+
+pub fn check_workgroup_counts(limits: &wgpu::Limits, workgroups: [u32; 3]) -> Result<(), Error> {
+    let max = limits.max_compute_workgroups_per_dimension;
+    if workgroups.iter().any(|count| *count > max) {
+        return Err(ValidationError(format!(
+            "dispatch of {:?} workgroups, at most {} per dimension are supported",
+            workgroups, max
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bind_group::{BindGroupBuilder, LayoutBuilder};
+    use crate::buffers::StorageBuffer;
+    use crate::compute::{check_workgroup_counts, get_workgroup_count, ComputePipelineBuilder};
+    use crate::gpu_context::GpuContext;
+
+    const DOUBLE_WGSL: &str = r"
+@group(0) @binding(0) var<storage, read_write> values: array<u32>;
+
+@compute @workgroup_size(64)
+fn double_values(@builtin(global_invocation_id) id: vec3<u32>) {
+    if (id.x < arrayLength(&values)) {
+        values[id.x] = values[id.x] * 2u;
+    }
+}
+";
+
+    #[test]
+    fn test_workgroup_count() {
+        assert_eq!(get_workgroup_count(0, 64), 0);
+        assert_eq!(get_workgroup_count(1, 64), 1);
+        assert_eq!(get_workgroup_count(64, 64), 1);
+        assert_eq!(get_workgroup_count(65, 64), 2);
+
+        let limits = wgpu::Limits::downlevel_defaults();
+        let max = limits.max_compute_workgroups_per_dimension;
+        assert!(check_workgroup_counts(&limits, [max, 1, 1]).is_ok());
+        assert!(check_workgroup_counts(&limits, [1, max + 1, 1]).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "reflection")]
+    fn test_check_bindings() {
+        let layout = LayoutBuilder::new().storage_buffer(wgpu::ShaderStages::COMPUTE, false);
+        let builder = ComputePipelineBuilder::new("double_values");
+        assert!(builder.check_bindings(DOUBLE_WGSL, &[&layout.entries]).is_ok());
+        assert!(builder.check_bindings(DOUBLE_WGSL, &[]).is_err());
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "gpu_tests"), ignore = "needs a gpu adapter")]
+    fn test_dispatch() {
+        let context = pollster::block_on(GpuContext::new_headless(4, 4)).unwrap();
+        let values: Vec<u32> = (0..100).collect();
+        let storage_buffer = StorageBuffer::new_init(&context, &values, wgpu::BufferUsages::empty(), "values").unwrap();
+
+        let layout = LayoutBuilder::new()
+            .storage_buffer(wgpu::ShaderStages::COMPUTE, false)
+            .build(&context, "values layout")
+            .unwrap();
+        let bind_group = BindGroupBuilder::new()
+            .resource(storage_buffer.as_entire_binding())
+            .build(&context, &layout, "values");
+
+        let shader = context.device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("double"),
+            source: wgpu::ShaderSource::Wgsl(DOUBLE_WGSL.into()),
+        });
+        let pipeline = ComputePipelineBuilder::new("double_values")
+            .bind_group_layout(&layout)
+            .build(&context, &shader)
+            .unwrap();
+
+        let workgroups = get_workgroup_count(values.len() as u32, 64);
+        pipeline.dispatch(&context, &[&bind_group], workgroups, 1, 1).unwrap();
+
+        let doubled = storage_buffer.read_back(&context);
+        assert_eq!(doubled, values.iter().map(|value| value * 2).collect::<Vec<u32>>());
+
+        // write keeps the other elements
+        storage_buffer.write(&context, 98, &[7, 8]).unwrap();
+        assert_eq!(storage_buffer.read_back(&context)[96..], [192, 194, 7, 8]);
+
+        // past the end is an error and leaves the buffer alone
+        assert!(storage_buffer.write(&context, 99, &[9, 9]).is_err());
+        assert_eq!(storage_buffer.read_back(&context)[98..], [7, 8]);
+    }
+}
@@ -0,0 +1,327 @@
+use glam::{vec2, Mat3, Mat4, Vec2, Vec3, Vec4};
+
+// Index of the near plane in Frustum::planes
+pub const NEAR_PLANE: usize = 4;
+
+// Planes are stored as (normal, distance) with the normal pointing into the frustum
+#[derive(Debug, Clone, Copy)]
+pub struct Frustum {
+    pub planes: [Vec4; 6],
+}
+
+impl Frustum {
+    // Gribb/Hartmann plane extraction for the 0..1 depth range used by wgpu and Mat4::perspective_rh
+    pub fn from_matrix(projection_view: &Mat4) -> Self {
+        let row_0 = projection_view.row(0);
+        let row_1 = projection_view.row(1);
+        let row_2 = projection_view.row(2);
+        let row_3 = projection_view.row(3);
+
+        let planes = [
+            row_3 + row_0, // left
+            row_3 - row_0, // right
+            row_3 + row_1, // bottom
+            row_3 - row_1, // top
+            row_2,         // near
+            row_3 - row_2, // far
+        ];
+
+        Frustum {
+            planes: planes.map(normalize_plane),
+        }
+    }
+
+    pub fn intersects_sphere(&self, center: Vec3, radius: f32) -> bool {
+        self.planes.iter().all(|plane| plane.truncate().dot(center) + plane.w >= -radius)
+    }
+
+    // Conservative, a box near a frustum corner can be outside and still pass. Tests the corner
+    // furthest along each plane's normal, the box is outside when that corner is behind the plane.
+    pub fn intersects_aabb(&self, aabb: &Aabb) -> bool {
+        self.planes.iter().all(|plane| {
+            let normal = plane.truncate();
+            let furthest = Vec3::select(normal.cmpge(Vec3::ZERO), aabb.max, aabb.min);
+            normal.dot(furthest) + plane.w >= 0.0
+        })
+    }
+
+    // For culling shadow casters against a light's frustum. Casters between the light and the near
+    // plane still throw shadows into the frustum, so only the near plane doesn't cull.
+    pub fn without_near_plane(&self) -> Frustum {
+        let mut planes = self.planes;
+        planes[NEAR_PLANE] = Vec4::W;
+        Frustum { planes }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    pub fn from_points(points: impl Iterator<Item = Vec3>) -> Self {
+        let (min, max) = points.fold((Vec3::splat(f32::MAX), Vec3::splat(f32::MIN)), |(min, max), point| {
+            (min.min(point), max.max(point))
+        });
+        if min.x > max.x {
+            // no points
+            return Aabb {
+                min: Vec3::ZERO,
+                max: Vec3::ZERO,
+            };
+        }
+        Aabb { min, max }
+    }
+
+    pub fn center(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn extents(&self) -> Vec3 {
+        self.max - self.min
+    }
+
+    // Bounds of the transformed box, using the absolute matrix to transform the half extents
+    pub fn transform(&self, matrix: &Mat4) -> Aabb {
+        let center = matrix.transform_point3(self.center());
+        let half_extents = self.extents() * 0.5;
+        let absolute = Mat3::from_cols(
+            matrix.x_axis.truncate().abs(),
+            matrix.y_axis.truncate().abs(),
+            matrix.z_axis.truncate().abs(),
+        );
+        let transformed_half_extents = absolute * half_extents;
+        Aabb {
+            min: center - transformed_half_extents,
+            max: center + transformed_half_extents,
+        }
+    }
+
+    pub fn corners(&self) -> [Vec3; 8] {
+        [0, 1, 2, 3, 4, 5, 6, 7].map(|i| {
+            Vec3::new(
+                if i & 1 == 0 { self.min.x } else { self.max.x },
+                if i & 2 == 0 { self.min.y } else { self.max.y },
+                if i & 4 == 0 { self.min.z } else { self.max.z },
+            )
+        })
+    }
+
+    // Pixel rect the box covers, origin at the top left, clamped to the viewport. Edges crossing
+    // the camera plane are cut there so boxes around the camera still get a rect. None when
+    // the box is behind the camera or outside the viewport.
+    pub fn get_screen_rect(&self, model_projection_view: &Mat4, viewport_size: Vec2) -> Option<ScreenRect> {
+        let clip_corners = self.corners().map(|corner| *model_projection_view * corner.extend(1.0));
+
+        let mut points: Vec<Vec4> = clip_corners.iter().copied().filter(|corner| corner.w > MIN_CLIP_W).collect();
+        if points.is_empty() {
+            return None;
+        }
+
+        // the 12 edges join corners differing in one axis
+        for i in 0..8 {
+            for axis in [1, 2, 4] {
+                let (a, b) = (clip_corners[i], clip_corners[i ^ axis]);
+                if i & axis == 0 && (a.w > MIN_CLIP_W) != (b.w > MIN_CLIP_W) {
+                    let t = (MIN_CLIP_W - a.w) / (b.w - a.w);
+                    points.push(a.lerp(b, t));
+                }
+            }
+        }
+
+        let (min, max) = points.iter().fold((Vec2::MAX, Vec2::MIN), |(min, max), point| {
+            let ndc = point.truncate().truncate() / point.w;
+            let screen = vec2((ndc.x + 1.0) * 0.5, (1.0 - ndc.y) * 0.5) * viewport_size;
+            (min.min(screen), max.max(screen))
+        });
+
+        let rect = ScreenRect {
+            min: min.clamp(Vec2::ZERO, viewport_size),
+            max: max.clamp(Vec2::ZERO, viewport_size),
+        };
+        rect.size().cmpgt(Vec2::ZERO).all().then_some(rect)
+    }
+}
+
+// distance in front of the camera below which clip space points count as behind it
+const MIN_CLIP_W: f32 = 1e-5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScreenRect {
+    pub min: Vec2,
+    pub max: Vec2,
+}
+
+impl ScreenRect {
+    pub fn size(&self) -> Vec2 {
+        self.max - self.min
+    }
+
+    pub fn center(&self) -> Vec2 {
+        (self.min + self.max) * 0.5
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BoundingSphere {
+    pub center: Vec3,
+    pub radius: f32,
+}
+
+impl BoundingSphere {
+    // encloses the box, radius is half the diagonal
+    pub fn from_aabb(aabb: &Aabb) -> Self {
+        BoundingSphere {
+            center: aabb.center(),
+            radius: aabb.extents().length() * 0.5,
+        }
+    }
+
+    // the radius is scaled by the largest axis scale so the sphere stays conservative
+    pub fn transform(&self, matrix: &Mat4) -> BoundingSphere {
+        let max_scale = matrix
+            .x_axis
+            .truncate()
+            .length()
+            .max(matrix.y_axis.truncate().length())
+            .max(matrix.z_axis.truncate().length());
+        BoundingSphere {
+            center: matrix.transform_point3(self.center),
+            radius: self.radius * max_scale,
+        }
+    }
+}
+
+fn normalize_plane(plane: Vec4) -> Vec4 {
+    let length = plane.truncate().length();
+    if length > f32::EPSILON {
+        plane / length
+    } else {
+        plane
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::culling::{Aabb, Frustum, NEAR_PLANE};
+    use glam::{vec2, vec3, Mat4, Vec3};
+
+    #[test]
+    fn test_frustum_sphere() {
+        let projection = Mat4::perspective_rh(45.0f32.to_radians(), 1.0, 0.1, 100.0);
+        let view = Mat4::look_at_rh(vec3(0.0, 0.0, 10.0), vec3(0.0, 0.0, 0.0), vec3(0.0, 1.0, 0.0));
+        let frustum = Frustum::from_matrix(&(projection * view));
+
+        assert!(frustum.intersects_sphere(vec3(0.0, 0.0, 0.0), 1.0));
+        // behind the camera
+        assert!(!frustum.intersects_sphere(vec3(0.0, 0.0, 20.0), 1.0));
+        // off to the side
+        assert!(!frustum.intersects_sphere(vec3(100.0, 0.0, 0.0), 1.0));
+        // beyond the far plane
+        assert!(!frustum.intersects_sphere(vec3(0.0, 0.0, -200.0), 1.0));
+    }
+
+    #[test]
+    fn test_frustum_planes() {
+        let projection = Mat4::perspective_rh(60.0f32.to_radians(), 1.5, 0.5, 50.0);
+        let view = Mat4::look_at_rh(vec3(2.0, -3.0, 4.0), vec3(0.0, 0.0, 0.0), vec3(0.0, 0.0, 1.0));
+        let frustum = Frustum::from_matrix(&(projection * view));
+
+        // unit normals, all pointing inwards: a point on the view axis is inside every plane
+        let inside = vec3(2.0, -3.0, 4.0).lerp(Vec3::ZERO, 0.5);
+        for plane in frustum.planes {
+            assert!((plane.truncate().length() - 1.0).abs() < 1e-5);
+            assert!(plane.truncate().dot(inside) + plane.w > 0.0, "{}", plane);
+        }
+
+        // the near plane is 0.5 in front of the eye
+        let eye = vec3(2.0, -3.0, 4.0);
+        let forward = -eye.normalize();
+        let near = frustum.planes[NEAR_PLANE];
+        assert!((near.truncate().dot(eye + forward * 0.5) + near.w).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_frustum_aabb() {
+        let projection = Mat4::perspective_rh(45.0f32.to_radians(), 1.0, 0.1, 100.0);
+        let view = Mat4::look_at_rh(vec3(0.0, 0.0, 10.0), vec3(0.0, 0.0, 0.0), vec3(0.0, 1.0, 0.0));
+        let frustum = Frustum::from_matrix(&(projection * view));
+        let unit = Aabb {
+            min: vec3(-1.0, -1.0, -1.0),
+            max: vec3(1.0, 1.0, 1.0),
+        };
+        let moved = |offset| unit.transform(&Mat4::from_translation(offset));
+
+        assert!(frustum.intersects_aabb(&unit));
+        assert!(!frustum.intersects_aabb(&moved(vec3(0.0, 0.0, 20.0))));
+        assert!(!frustum.intersects_aabb(&moved(vec3(100.0, 0.0, 0.0))));
+        assert!(!frustum.intersects_aabb(&moved(vec3(0.0, 0.0, -200.0))));
+
+        // the left plane is 4.14 units off axis at the origin's depth
+        assert!(frustum.intersects_aabb(&moved(vec3(-4.5, 0.0, 0.0))));
+        assert!(!frustum.intersects_aabb(&moved(vec3(-6.0, 0.0, 0.0))));
+
+        // a caster between a directional light and its near plane is kept for shadows
+        let projection = Mat4::orthographic_rh(-5.0, 5.0, -5.0, 5.0, 1.0, 20.0);
+        let light = Frustum::from_matrix(&(projection * view));
+        let behind = moved(vec3(0.0, 0.0, 11.0));
+        assert!(!light.intersects_aabb(&behind));
+        assert!(light.without_near_plane().intersects_aabb(&behind));
+        assert!(!light.without_near_plane().intersects_aabb(&moved(vec3(8.0, 0.0, 11.0))));
+    }
+
+    #[test]
+    fn test_aabb_transform() {
+        let aabb = Aabb {
+            min: vec3(-1.0, -1.0, -1.0),
+            max: vec3(1.0, 1.0, 1.0),
+        };
+
+        let moved = aabb.transform(&Mat4::from_translation(vec3(5.0, 0.0, 0.0)));
+        assert_eq!(moved.min, vec3(4.0, -1.0, -1.0));
+        assert_eq!(moved.max, vec3(6.0, 1.0, 1.0));
+
+        // rotating 45 degrees about z grows the x and y extents to the diagonal
+        let rotated = aabb.transform(&Mat4::from_rotation_z(45f32.to_radians()));
+        assert!((rotated.max.x - 2f32.sqrt()).abs() < 1e-5);
+        assert!((rotated.max.z - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_aabb_screen_rect() {
+        let projection = Mat4::perspective_rh(90.0f32.to_radians(), 1.0, 0.1, 100.0);
+        let view = Mat4::look_at_rh(vec3(0.0, 0.0, 10.0), vec3(0.0, 0.0, 0.0), vec3(0.0, 1.0, 0.0));
+        let projection_view = projection * view;
+        let viewport = vec2(100.0, 100.0);
+
+        // the front face, 9 units from the camera, is the widest
+        let aabb = Aabb {
+            min: vec3(-1.0, -1.0, -1.0),
+            max: vec3(1.0, 1.0, 1.0),
+        };
+        let rect = aabb.get_screen_rect(&projection_view, viewport).unwrap();
+        let half_width = 50.0 / 9.0;
+        assert!(rect.min.abs_diff_eq(vec2(50.0 - half_width, 50.0 - half_width), 1e-3), "{:?}", rect);
+        assert!(rect.max.abs_diff_eq(vec2(50.0 + half_width, 50.0 + half_width), 1e-3), "{:?}", rect);
+
+        // moved right and up on screen, y points down in pixels
+        let moved = aabb.transform(&Mat4::from_translation(vec3(4.0, 4.0, 0.0)));
+        let moved_rect = moved.get_screen_rect(&projection_view, viewport).unwrap();
+        assert!(moved_rect.center().x > 50.0 && moved_rect.center().y < 50.0);
+
+        // behind the camera
+        let behind = aabb.transform(&Mat4::from_translation(vec3(0.0, 0.0, 20.0)));
+        assert_eq!(behind.get_screen_rect(&projection_view, viewport), None);
+
+        // around the camera, clamped to the whole viewport
+        let around = Aabb {
+            min: vec3(-5.0, -5.0, 5.0),
+            max: vec3(5.0, 5.0, 15.0),
+        };
+        let around_rect = around.get_screen_rect(&projection_view, viewport).unwrap();
+        assert_eq!(around_rect.min, vec2(0.0, 0.0));
+        assert_eq!(around_rect.max, viewport);
+    }
+}
@@ -0,0 +1,120 @@
+use std::rc::Rc;
+
+use wgpu::{Texture, TextureView};
+
+use crate::gpu_context::GpuContext;
+
+// 1x1 stand ins for texture maps a material doesn't have
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DefaultTextureKind {
+    White,
+    Black,
+    // tangent space normal pointing straight out of the surface
+    FlatNormal,
+    Transparent,
+}
+
+impl DefaultTextureKind {
+    pub fn get_rgba8(&self) -> [u8; 4] {
+        match self {
+            DefaultTextureKind::White => [255, 255, 255, 255],
+            DefaultTextureKind::Black => [0, 0, 0, 255],
+            DefaultTextureKind::FlatNormal => [128, 128, 255, 255],
+            DefaultTextureKind::Transparent => [0, 0, 0, 0],
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            DefaultTextureKind::White => "default white texture",
+            DefaultTextureKind::Black => "default black texture",
+            DefaultTextureKind::FlatNormal => "default flat normal texture",
+            DefaultTextureKind::Transparent => "default transparent texture",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DefaultTexture {
+    pub kind: DefaultTextureKind,
+    pub texture: Rc<Texture>,
+    pub view: Rc<TextureView>,
+}
+
+// Linear format so the flat normal isn't altered by srgb decoding, the other values are the same either way
+pub const DEFAULT_TEXTURE_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8Unorm;
+
+// Returns the cached texture, creating it on first use
+pub fn get_default_texture(context: &mut GpuContext, kind: DefaultTextureKind) -> Rc<DefaultTexture> {
+    if let Some(default_texture) = context.default_textures.get(&kind) {
+        return default_texture.clone();
+    }
+
+    let default_texture = Rc::new(create_default_texture(context, kind));
+    context.default_textures.insert(kind, default_texture.clone());
+    default_texture
+}
+
+fn create_default_texture(context: &GpuContext, kind: DefaultTextureKind) -> DefaultTexture {
+    let size = wgpu::Extent3d {
+        width: 1,
+        height: 1,
+        depth_or_array_layers: 1,
+    };
+
+    let texture = context.device.create_texture(&wgpu::TextureDescriptor {
+        label: Some(kind.label()),
+        size,
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: DEFAULT_TEXTURE_FORMAT,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        view_formats: &[],
+    });
+
+    context.queue.write_texture(
+        wgpu::ImageCopyTexture {
+            texture: &texture,
+            mip_level: 0,
+            origin: wgpu::Origin3d::ZERO,
+            aspect: wgpu::TextureAspect::All,
+        },
+        &kind.get_rgba8(),
+        wgpu::ImageDataLayout {
+            offset: 0,
+            bytes_per_row: Some(4),
+            rows_per_image: Some(1),
+        },
+        size,
+    );
+
+    let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+    DefaultTexture {
+        kind,
+        texture: texture.into(),
+        view: view.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::default_textures::DefaultTextureKind;
+
+    #[test]
+    fn test_flat_normal() {
+        let rgba = DefaultTextureKind::FlatNormal.get_rgba8().map(|c| c as f32 / 255.0);
+        let expected = [0.5, 0.5, 1.0, 1.0];
+        for (channel, expected) in rgba.iter().zip(expected) {
+            assert!((channel - expected).abs() <= 0.5 / 255.0 + 1e-6);
+        }
+
+        // decodes to the unit z normal within 8 bit precision
+        let normal = glam::vec3(rgba[0], rgba[1], rgba[2]) * 2.0 - 1.0;
+        assert!(normal.abs_diff_eq(glam::Vec3::Z, 1.0 / 127.0));
+
+        assert_eq!(DefaultTextureKind::White.get_rgba8(), [255; 4]);
+        assert_eq!(DefaultTextureKind::Transparent.get_rgba8()[3], 0);
+    }
+}
@@ -0,0 +1,134 @@
+use wgpu::{RenderPipeline, ShaderModule, TextureView};
+
+use crate::error::Error;
+use crate::gpu_context::GpuContext;
+use crate::shadow_pipeline::ShadowPipelineBuilder;
+use crate::texture::DEPTH_FORMAT;
+
+pub const DEPTH_PREPASS_USAGE: wgpu::TextureUsages = wgpu::TextureUsages::RENDER_ATTACHMENT.union(wgpu::TextureUsages::TEXTURE_BINDING);
+
+pub fn get_depth_prepass_descriptor(width: u32, height: u32) -> wgpu::TextureDescriptor<'static> {
+    wgpu::TextureDescriptor {
+        label: Some("depth prepass texture"),
+        size: wgpu::Extent3d {
+            width: width.max(1),
+            height: height.max(1),
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: DEPTH_FORMAT,
+        usage: DEPTH_PREPASS_USAGE,
+        view_formats: &[],
+    }
+}
+
+// Layout entry for reading the prepass depth in a later pass
+pub fn get_depth_prepass_layout_entry(binding: u32, visibility: wgpu::ShaderStages) -> wgpu::BindGroupLayoutEntry {
+    wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility,
+        ty: wgpu::BindingType::Texture {
+            multisampled: false,
+            view_dimension: wgpu::TextureViewDimension::D2,
+            sample_type: wgpu::TextureSampleType::Depth,
+        },
+        count: None,
+    }
+}
+
+// Renders scene depth into its own sampled target, so effects and debug views can use the depth
+// even when the main pass discards its depth buffer.
+pub struct DepthPrepass {
+    pub texture: wgpu::Texture,
+    pub view: TextureView,
+    pipeline: RenderPipeline,
+}
+
+impl DepthPrepass {
+    // The builder supplies the vertex entry, vertex buffers and bind group layouts of the scene geometry.
+    // Depth bias and unclipped depth are shadow map settings so they are turned off here.
+    pub fn new(
+        context: &GpuContext,
+        builder: ShadowPipelineBuilder<'_>,
+        shader: &ShaderModule,
+        width: u32,
+        height: u32,
+    ) -> Result<Self, Error> {
+        let pipeline = builder
+            .label("depth prepass pipeline")
+            .depth_format(DEPTH_FORMAT)
+            .depth_bias(wgpu::DepthBiasState::default())
+            .depth_compare(wgpu::CompareFunction::Less)
+            .unclipped_depth(false)
+            .build(context, shader)?;
+
+        let (texture, view) = create_depth_prepass_texture(context, width, height);
+
+        Ok(DepthPrepass { texture, view, pipeline })
+    }
+
+    pub fn resize(&mut self, context: &GpuContext, width: u32, height: u32) {
+        (self.texture, self.view) = create_depth_prepass_texture(context, width, height);
+    }
+
+    // Clears the target and sets the pipeline, draw binds the geometry and issues the draws
+    pub fn record<'a: 'p, 'p>(
+        &'a self,
+        encoder: &'p mut wgpu::CommandEncoder,
+        draw: impl FnOnce(&mut wgpu::RenderPass<'p>),
+    ) -> &'a TextureView {
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("depth prepass"),
+            color_attachments: &[],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: &self.view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(1.0),
+                    store: wgpu::StoreOp::Store,
+                }),
+                stencil_ops: None,
+            }),
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+
+        pass.set_pipeline(&self.pipeline);
+        draw(&mut pass);
+
+        &self.view
+    }
+}
+
+fn create_depth_prepass_texture(context: &GpuContext, width: u32, height: u32) -> (wgpu::Texture, TextureView) {
+    let texture = context.device.create_texture(&get_depth_prepass_descriptor(width, height));
+    let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+    (texture, view)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::depth_prepass::{get_depth_prepass_descriptor, get_depth_prepass_layout_entry};
+
+    #[test]
+    fn test_depth_prepass_target() {
+        let descriptor = get_depth_prepass_descriptor(1280, 720);
+        assert_eq!(descriptor.format, wgpu::TextureFormat::Depth32Float);
+        assert_eq!(
+            (descriptor.size.width, descriptor.size.height, descriptor.size.depth_or_array_layers),
+            (1280, 720, 1)
+        );
+        assert!(descriptor
+            .usage
+            .contains(wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::RENDER_ATTACHMENT));
+
+        // the depth can be bound as a depth texture for sampling
+        let sample_type = descriptor.format.sample_type(None, None).unwrap();
+        let entry = get_depth_prepass_layout_entry(0, wgpu::ShaderStages::FRAGMENT);
+        assert!(matches!(entry.ty, wgpu::BindingType::Texture { sample_type: entry_type, .. } if entry_type == sample_type));
+
+        // minimized windows still get a valid texture
+        assert_eq!(get_depth_prepass_descriptor(0, 0).size.width, 1);
+    }
+}
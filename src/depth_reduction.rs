@@ -0,0 +1,340 @@
+use std::borrow::Cow;
+use std::rc::Rc;
+
+use glam::Vec4;
+use wgpu::{BindGroupLayout, Buffer, ComputePipeline, TextureView};
+
+use crate::buffers::{cast_slice, create_uniform_buffer_init, read_buffer, update_uniform_buffer};
+use crate::capabilities::Capabilities;
+use crate::depth_prepass::get_depth_prepass_layout_entry;
+use crate::error::Error;
+use crate::error::Error::ValidationError;
+use crate::gpu_context::{get_or_create_bind_group_layout, GpuContext};
+use crate::prefix_sum::{get_scan_levels, SCAN_BLOCK_SIZE};
+
+pub const DEPTH_REDUCTION_BIND_GROUP_LAYOUT: &str = "depth reduction bind group layout";
+pub const BLOCK_REDUCTION_BIND_GROUP_LAYOUT: &str = "block reduction bind group layout";
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct ReductionUniform {
+    pub length: u32,
+    pub width: u32,
+    pub skip_cleared: u32,
+    pub _padding: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DepthRange {
+    pub min: f32,
+    pub max: f32,
+    pub average: f32,
+}
+
+impl DepthRange {
+    // None when every texel was skipped
+    pub fn from_reduction(reduction: Vec4) -> Option<Self> {
+        (reduction.w > 0.0).then(|| DepthRange {
+            min: reduction.x,
+            max: reduction.y,
+            average: reduction.z / reduction.w,
+        })
+    }
+}
+
+struct ReductionLevel {
+    // one (min, max, sum, count) per block of the level
+    output: Buffer,
+    uniform_buffer: Buffer,
+}
+
+// Reduces a depth texture to its min, max and average depth with textureLoad, so depth targets
+// can be read without a comparison sampler. The levels are laid out like the prefix sum levels.
+pub struct DepthReduction {
+    pub width: u32,
+    pub height: u32,
+    depth_pipeline: ComputePipeline,
+    block_pipeline: ComputePipeline,
+    depth_bind_group_layout: Rc<BindGroupLayout>,
+    block_bind_group_layout: Rc<BindGroupLayout>,
+    levels: Vec<ReductionLevel>,
+}
+
+impl DepthReduction {
+    pub fn new(context: &mut GpuContext, width: u32, height: u32) -> Result<Self, Error> {
+        check_depth_reduction_support(&context.capabilities, width, height)?;
+        let length = width * height;
+
+        let depth_bind_group_layout =
+            get_or_create_bind_group_layout(context, DEPTH_REDUCTION_BIND_GROUP_LAYOUT, create_depth_reduction_bind_group_layout);
+        let block_bind_group_layout =
+            get_or_create_bind_group_layout(context, BLOCK_REDUCTION_BIND_GROUP_LAYOUT, create_block_reduction_bind_group_layout);
+
+        let shader = context.device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("depth reduction shader"),
+            source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(include_str!("shaders/depth_reduction.wgsl"))),
+        });
+
+        let create_pipeline = |bind_group_layout: &BindGroupLayout, entry_point: &str| {
+            let pipeline_layout = context.device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("depth reduction pipeline layout"),
+                bind_group_layouts: &[bind_group_layout],
+                push_constant_ranges: &[],
+            });
+
+            context.device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some("depth reduction pipeline"),
+                layout: Some(&pipeline_layout),
+                module: &shader,
+                entry_point,
+            })
+        };
+
+        let depth_pipeline = create_pipeline(&depth_bind_group_layout, "reduce_depth");
+        let block_pipeline = create_pipeline(&block_bind_group_layout, "reduce_blocks");
+
+        let levels = get_reduction_levels(length)
+            .iter()
+            .map(|level_length| ReductionLevel {
+                output: context.device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some("depth reduction output"),
+                    size: (level_length.div_ceil(SCAN_BLOCK_SIZE).max(1) as usize * std::mem::size_of::<Vec4>()) as wgpu::BufferAddress,
+                    usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+                    mapped_at_creation: false,
+                }),
+                uniform_buffer: create_uniform_buffer_init(
+                    &*context,
+                    &[get_reduction_uniform(*level_length, width, false)],
+                    "depth reduction uniform",
+                ),
+            })
+            .collect();
+
+        Ok(DepthReduction {
+            width,
+            height,
+            depth_pipeline,
+            block_pipeline,
+            depth_bind_group_layout,
+            block_bind_group_layout,
+            levels,
+        })
+    }
+
+    // Records the reduction of a width x height depth view, skip_cleared leaves out texels at the far plane.
+    // The level uniforms are written through the queue so only one reduction per submit is supported.
+    pub fn reduce(&self, context: &GpuContext, encoder: &mut wgpu::CommandEncoder, depth_view: &TextureView, skip_cleared: bool) {
+        let lengths = get_reduction_levels(self.width * self.height);
+
+        let bind_groups: Vec<wgpu::BindGroup> = lengths
+            .iter()
+            .enumerate()
+            .map(|(i, level_length)| {
+                let level = &self.levels[i];
+                update_uniform_buffer(
+                    context,
+                    &level.uniform_buffer,
+                    &[get_reduction_uniform(*level_length, self.width, skip_cleared)],
+                );
+
+                let (layout, input) = match i {
+                    0 => (&self.depth_bind_group_layout, wgpu::BindingResource::TextureView(depth_view)),
+                    _ => (&self.block_bind_group_layout, self.levels[i - 1].output.as_entire_binding()),
+                };
+
+                context.device.create_bind_group(&wgpu::BindGroupDescriptor {
+                    layout,
+                    entries: &[
+                        wgpu::BindGroupEntry {
+                            binding: if i == 0 { 0 } else { 1 },
+                            resource: input,
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 2,
+                            resource: level.output.as_entire_binding(),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 3,
+                            resource: level.uniform_buffer.as_entire_binding(),
+                        },
+                    ],
+                    label: Some("depth reduction bind group"),
+                })
+            })
+            .collect();
+
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("depth reduction"),
+            timestamp_writes: None,
+        });
+
+        for (i, (bind_group, level_length)) in bind_groups.iter().zip(&lengths).enumerate() {
+            pass.set_pipeline(if i == 0 { &self.depth_pipeline } else { &self.block_pipeline });
+            pass.set_bind_group(0, bind_group, &[]);
+            pass.dispatch_workgroups(level_length.div_ceil(SCAN_BLOCK_SIZE), 1, 1);
+        }
+    }
+
+    // Blocks until the submitted reduction is done, None when every texel was skipped
+    pub fn read_range(&self, context: &GpuContext) -> Result<Option<DepthRange>, Error> {
+        let last_level = self.levels.last().expect("depth reduction has at least one level");
+        let data = read_buffer(context, &last_level.output);
+        let reduction: &[[f32; 4]] = cast_slice(&data)?;
+        Ok(DepthRange::from_reduction(Vec4::from_array(reduction[0])))
+    }
+}
+
+pub fn check_depth_reduction_support(capabilities: &Capabilities, width: u32, height: u32) -> Result<(), Error> {
+    capabilities.require_compute("depth reduction")?;
+    capabilities.require_storage_buffers("depth reduction")?;
+
+    let max_workgroups = capabilities.max_compute_workgroups_per_dimension;
+    if (width * height).div_ceil(SCAN_BLOCK_SIZE) > max_workgroups {
+        return Err(ValidationError(format!(
+            "depth reduction of {}x{} needs more than max_compute_workgroups_per_dimension ({}) workgroups",
+            width, height, max_workgroups
+        )));
+    }
+    Ok(())
+}
+
+fn get_reduction_uniform(length: u32, width: u32, skip_cleared: bool) -> ReductionUniform {
+    ReductionUniform {
+        length,
+        width,
+        skip_cleared: skip_cleared as u32,
+        _padding: 0,
+    }
+}
+
+// The length reduced at each level, the last level reduces to a single block result
+pub fn get_reduction_levels(length: u32) -> Vec<u32> {
+    get_scan_levels(length)
+}
+
+fn create_depth_reduction_bind_group_layout(context: &GpuContext, label: &str) -> BindGroupLayout {
+    context.device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        entries: &[
+            get_depth_prepass_layout_entry(0, wgpu::ShaderStages::COMPUTE),
+            get_output_layout_entry(),
+            get_uniform_layout_entry(),
+        ],
+        label: Some(label),
+    })
+}
+
+fn create_block_reduction_bind_group_layout(context: &GpuContext, label: &str) -> BindGroupLayout {
+    context.device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        entries: &[
+            // previous level output
+            wgpu::BindGroupLayoutEntry {
+                binding: 1,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            get_output_layout_entry(),
+            get_uniform_layout_entry(),
+        ],
+        label: Some(label),
+    })
+}
+
+fn get_output_layout_entry() -> wgpu::BindGroupLayoutEntry {
+    wgpu::BindGroupLayoutEntry {
+        binding: 2,
+        visibility: wgpu::ShaderStages::COMPUTE,
+        ty: wgpu::BindingType::Buffer {
+            ty: wgpu::BufferBindingType::Storage { read_only: false },
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    }
+}
+
+fn get_uniform_layout_entry() -> wgpu::BindGroupLayoutEntry {
+    wgpu::BindGroupLayoutEntry {
+        binding: 3,
+        visibility: wgpu::ShaderStages::COMPUTE,
+        ty: wgpu::BindingType::Buffer {
+            ty: wgpu::BufferBindingType::Uniform,
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::depth_reduction::{get_reduction_levels, DepthReduction};
+    use crate::gpu_context::GpuContext;
+    use crate::pipeline_builder::PipelineBuilder;
+    use crate::render::RenderPassBuilder;
+    use crate::texture::{create_depth_texture_with_size, DEPTH_FORMAT};
+
+    // A ramp from 0.25 on the left column to 0.75 on the right one, the top four rows keep the clear depth
+    const DEPTH_RAMP: &str = r"
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> @builtin(position) vec4<f32> {
+    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+    return vec4<f32>(uv * 2.0 - 1.0, 0.0, 1.0);
+}
+
+@fragment
+fn fs_main(@builtin(position) position: vec4<f32>) -> @builtin(frag_depth) f32 {
+    if position.y < 4.0 {
+        discard;
+    }
+    return 0.25 + 0.5 * floor(position.x) / 63.0;
+}
+";
+
+    #[test]
+    #[cfg_attr(not(feature = "gpu_tests"), ignore = "needs a gpu adapter")]
+    fn test_depth_min_max() {
+        let (width, height) = (64, 32);
+        let mut context = pollster::block_on(GpuContext::new_headless(width, height)).unwrap();
+        // spans several blocks
+        assert_eq!(get_reduction_levels(width * height), vec![2048, 4]);
+
+        let depth = create_depth_texture_with_size(&context, width, height);
+        let shader = context.device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("depth ramp"),
+            source: wgpu::ShaderSource::Wgsl(DEPTH_RAMP.into()),
+        });
+        let pipeline = PipelineBuilder::new("vs_main", "fs_main")
+            .depth(DEPTH_FORMAT)
+            .depth_compare(wgpu::CompareFunction::Always)
+            .primitive(wgpu::PrimitiveState::default())
+            .build(&context, &shader)
+            .unwrap();
+
+        let reduction = DepthReduction::new(&mut context, width, height).unwrap();
+        let read_range = |skip_cleared: bool| {
+            let mut encoder = context.device.create_command_encoder(&Default::default());
+            {
+                let mut pass = RenderPassBuilder::new().depth(&depth.view, Some(1.0)).begin(&mut encoder);
+                pass.set_pipeline(&pipeline);
+                pass.draw(0..3, 0..1);
+            }
+            reduction.reduce(&context, &mut encoder, &depth.view, skip_cleared);
+            context.queue.submit(std::iter::once(encoder.finish()));
+            reduction.read_range(&context).unwrap().unwrap()
+        };
+
+        let range = read_range(true);
+        assert!((range.min - 0.25).abs() < 1e-5 && (range.max - 0.75).abs() < 1e-5, "{:?}", range);
+        assert!((range.average - 0.5).abs() < 1e-4);
+
+        // the cleared rows are the maximum when they aren't skipped
+        let range = read_range(false);
+        assert!((range.min - 0.25).abs() < 1e-5);
+        assert_eq!(range.max, 1.0);
+    }
+}
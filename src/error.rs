@@ -1,25 +1,57 @@
-#[derive(Debug)]
+// The error of every fallible function of the crate. The messages are meant to be shown to users,
+// e.g. when GpuContext::new finds no gpu.
+#[derive(Debug, thiserror::Error)]
 pub enum Error {
+    // no adapter for the surface, or for headless contexts on any backend
+    #[error("no compatible gpu found")]
+    NoAdapterError,
+    #[error("the gpu device could not be created: {0}")]
+    RequestDeviceError(#[from] wgpu::RequestDeviceError),
+    #[error("the window surface could not be created: {0}")]
+    SurfaceCreationError(#[from] wgpu::CreateSurfaceError),
+    // the surface has no format the adapter can render to
+    #[error("unsupported surface format: {0}")]
+    UnsupportedSurfaceFormatError(String),
+    #[error("invalid path: {0}")]
     PathError(String),
-    FileError(std::io::Error),
+    #[error("file error: {0}")]
+    FileError(#[from] std::io::Error),
+    #[error("shader error: {0}")]
     ShaderError(String),
+    #[error("image error: {0}")]
     ImageError(String),
+    #[error("model error: {0:?}")]
     ModelError(russimp::RussimpError),
+    // parsing a gltf file or reading its buffers
+    #[error("gltf error: {0}")]
+    GltfError(String),
+    #[error("scene error: {0}")]
     SceneError(String),
+    #[error("mesh error: {0}")]
     MeshError(String),
+    #[error("texture error: {0}")]
     TextureError(String),
+    #[error("{0}")]
+    ValidationError(String),
+    // shaping or drawing text with the text feature
+    #[error("text error: {0}")]
+    TextError(String),
+    // the device lacks a capability, e.g. compute on WebGL2
+    #[error("unsupported: {0}")]
+    UnsupportedError(String),
+    // a readback or screenshot of a texture format other than Rgba8 or Bgra8
+    #[error("unsupported texture format {0:?}")]
+    UnsupportedFormatError(wgpu::TextureFormat),
+    #[error("{0}")]
     UnknownError(&'static str),
 }
 
-impl From<std::io::Error> for Error {
-    fn from(s: std::io::Error) -> Self {
-        Error::FileError(s)
-    }
-}
+// The name applications match on, the crate itself uses Error
+pub type SparkGapError = Error;
 
 impl From<image::ImageError> for Error {
     fn from(s: image::ImageError) -> Self {
-        Error::ImageError(format!("{:?}", s))
+        Error::ImageError(s.to_string())
     }
 }
 
@@ -29,14 +61,35 @@ impl From<russimp::RussimpError> for Error {
     }
 }
 
+#[cfg(feature = "gltf")]
+impl From<gltf::Error> for Error {
+    fn from(e: gltf::Error) -> Self {
+        Error::GltfError(e.to_string())
+    }
+}
+
 impl From<&'static str> for Error {
     fn from(s: &'static str) -> Self {
         Error::UnknownError(s)
     }
 }
 
-impl std::fmt::Display for Error {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "error: {:?}", self)
+#[cfg(test)]
+mod tests {
+    use std::error::Error as _;
+
+    use crate::error::Error;
+
+    #[test]
+    fn test_error_messages() {
+        assert_eq!(Error::NoAdapterError.to_string(), "no compatible gpu found");
+        assert!(Error::NoAdapterError.source().is_none());
+
+        let file_error = Error::from(std::io::Error::new(std::io::ErrorKind::NotFound, "missing.png"));
+        assert_eq!(file_error.source().unwrap().to_string(), "missing.png");
+        assert_eq!(file_error.to_string(), "file error: missing.png");
+
+        let shader_error = Error::ShaderError("fs_main not found".to_string());
+        assert_eq!(shader_error.to_string(), "shader error: fs_main not found");
     }
 }
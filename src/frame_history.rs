@@ -0,0 +1,134 @@
+use std::cell::{Ref, RefCell};
+use std::rc::Rc;
+
+use wgpu::TextureView;
+
+use crate::error::Error;
+use crate::gpu_context::GpuContext;
+use crate::texture::{create_surface_matched_target, SurfaceTarget};
+
+// Copy of the previous finished frame for feedback effects like motion trails, temporal
+// denoising or taa. The target follows the surface size and format.
+pub struct FrameHistory {
+    target: Rc<RefCell<SurfaceTarget>>,
+    // size of the target when the last frame was copied into it
+    copied_size: Option<wgpu::Extent3d>,
+}
+
+impl FrameHistory {
+    // Enables COPY_SRC on the surface so frames can be copied, which not every surface supports.
+    // The target can be copied out as well, e.g. to read the history back.
+    pub fn new(context: &mut GpuContext) -> Result<Self, Error> {
+        context.add_surface_usage(wgpu::TextureUsages::COPY_SRC)?;
+        let target = create_surface_matched_target(context, wgpu::TextureUsages::COPY_DST | wgpu::TextureUsages::COPY_SRC);
+
+        Ok(FrameHistory { target, copied_size: None })
+    }
+
+    // Records the copy of the finished frame, called after the last pass and before present.
+    // A frame that doesn't match the target, e.g. during a resize, leaves no history.
+    pub fn record_copy(&mut self, encoder: &mut wgpu::CommandEncoder, frame: &wgpu::Texture) {
+        let target = self.target.borrow();
+        let size = target.texture.size();
+
+        if frame.size() != size || frame.format() != target.texture.format() {
+            self.copied_size = None;
+            return;
+        }
+
+        encoder.copy_texture_to_texture(frame.as_image_copy(), target.texture.as_image_copy(), size);
+        self.copied_size = Some(size);
+    }
+
+    // None before the first copy and after the surface was resized
+    pub fn previous_color_view(&self) -> Option<Ref<'_, TextureView>> {
+        let target = self.target.borrow();
+        let valid = is_history_valid(self.copied_size, target.texture.size());
+        valid.then(|| Ref::map(target, |target| &target.view))
+    }
+
+    pub fn previous_color_texture(&self) -> Option<Ref<'_, wgpu::Texture>> {
+        let target = self.target.borrow();
+        let valid = is_history_valid(self.copied_size, target.texture.size());
+        valid.then(|| Ref::map(target, |target| &target.texture))
+    }
+}
+
+pub fn is_history_valid(copied_size: Option<wgpu::Extent3d>, target_size: wgpu::Extent3d) -> bool {
+    copied_size == Some(target_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::iter;
+
+    use crate::frame_history::{is_history_valid, FrameHistory};
+    use crate::gpu_context::GpuContext;
+    use crate::render::RenderPassBuilder;
+    use crate::snapshot::read_texture_rgba;
+
+    const RED: wgpu::Color = wgpu::Color {
+        r: 1.0,
+        g: 0.0,
+        b: 0.0,
+        a: 1.0,
+    };
+    const BLUE: wgpu::Color = wgpu::Color {
+        r: 0.0,
+        g: 0.0,
+        b: 1.0,
+        a: 1.0,
+    };
+
+    // Clears the offscreen frame and copies it into the history
+    fn render_frame(context: &GpuContext, history: &mut FrameHistory, clear: wgpu::Color) {
+        let frame = context.acquire_frame().unwrap();
+        let view = frame.texture().create_view(&wgpu::TextureViewDescriptor::default());
+
+        let mut encoder = context
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        RenderPassBuilder::new().color(&view, Some(clear)).begin(&mut encoder);
+        history.record_copy(&mut encoder, frame.texture());
+        context.queue.submit(iter::once(encoder.finish()));
+        frame.present();
+    }
+
+    fn read_history(context: &GpuContext, history: &FrameHistory) -> Vec<u8> {
+        let texture = history.previous_color_texture().expect("a frame was copied");
+        read_texture_rgba(context, &texture, 4, 4).unwrap()
+    }
+
+    #[test]
+    fn test_history_validity() {
+        let size = wgpu::Extent3d {
+            width: 800,
+            height: 600,
+            depth_or_array_layers: 1,
+        };
+
+        // nothing to read on the first frame, the second reads the first
+        assert!(!is_history_valid(None, size));
+        assert!(is_history_valid(Some(size), size));
+
+        // a resize recreates the target without the old contents
+        let resized = wgpu::Extent3d { width: 1024, ..size };
+        assert!(!is_history_valid(Some(size), resized));
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "gpu_tests"), ignore = "needs a gpu adapter")]
+    fn test_history_contents() {
+        let mut context = pollster::block_on(GpuContext::new_headless(4, 4)).unwrap();
+        let mut history = FrameHistory::new(&mut context).unwrap();
+        assert!(history.previous_color_view().is_none());
+
+        render_frame(&context, &mut history, RED);
+        assert_eq!(read_history(&context, &history), [255, 0, 0, 255].repeat(16));
+
+        // the second frame replaces the first
+        render_frame(&context, &mut history, BLUE);
+        assert_eq!(read_history(&context, &history), [0, 0, 255, 255].repeat(16));
+        assert!(history.previous_color_view().is_some());
+    }
+}
@@ -0,0 +1,60 @@
+// Counts gathered while recording a frame, for display in overlays or logging
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct FrameStats {
+    pub draw_calls: u32,
+    pub triangles: u32,
+    pub shadow_passes: u32,
+    pub entities_drawn: u32,
+    pub entities_culled: u32,
+    // only available when gpu profiling is enabled
+    pub gpu_ms: Option<f32>,
+    pub cpu_ms: f32,
+}
+
+impl FrameStats {
+    pub fn new() -> Self {
+        FrameStats::default()
+    }
+
+    pub fn record_draw(&mut self, index_count: u32, instance_count: u32) {
+        self.draw_calls += 1;
+        self.triangles += index_count / 3 * instance_count;
+    }
+
+    pub fn record_entity(&mut self, culled: bool) {
+        if culled {
+            self.entities_culled += 1;
+        } else {
+            self.entities_drawn += 1;
+        }
+    }
+
+    pub fn record_shadow_pass(&mut self) {
+        self.shadow_passes += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::frame_stats::FrameStats;
+
+    #[test]
+    fn test_frame_stats() {
+        let mut stats = FrameStats::new();
+
+        // a cube, then a quad drawn 4 times
+        stats.record_draw(36, 1);
+        stats.record_draw(6, 4);
+        assert_eq!((stats.draw_calls, stats.triangles), (2, 20));
+
+        // leftover indices don't make a triangle
+        stats.record_draw(4, 1);
+        assert_eq!((stats.draw_calls, stats.triangles), (3, 21));
+
+        stats.record_entity(true);
+        stats.record_entity(false);
+        stats.record_entity(false);
+        assert_eq!((stats.entities_drawn, stats.entities_culled), (2, 1));
+        assert_eq!(stats.gpu_ms, None);
+    }
+}
@@ -0,0 +1,351 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use glam::{vec2, vec3, Vec2, Vec3};
+use wgpu::TextureView;
+
+use crate::gpu_context::GpuContext;
+use crate::render::RenderPassBuilder;
+
+// Wgsl encode_octahedral and decode_octahedral functions to prepend to gbuffer shaders
+pub const GBUFFER_ENCODING_WGSL: &str = include_str!("shaders/gbuffer_encoding.wgsl");
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NormalEncoding {
+    // xyz stored directly
+    Raw,
+    // packed into two channels with encode_octahedral
+    Octahedral,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GBufferTarget {
+    pub name: &'static str,
+    pub format: wgpu::TextureFormat,
+    // what each channel stores, for documentation and debug views
+    pub channels: &'static str,
+}
+
+// Describes the render targets of the geometry pass in @location order
+#[derive(Debug, Clone, PartialEq)]
+pub struct GBufferLayout {
+    pub targets: Vec<GBufferTarget>,
+    pub normal_encoding: NormalEncoding,
+}
+
+impl GBufferLayout {
+    // 12 bytes per pixel
+    pub fn compact() -> Self {
+        GBufferLayout {
+            targets: vec![
+                GBufferTarget {
+                    name: "albedo",
+                    format: wgpu::TextureFormat::Rgba8UnormSrgb,
+                    channels: "rgb albedo, a occlusion",
+                },
+                GBufferTarget {
+                    name: "normal",
+                    format: wgpu::TextureFormat::Rg16Float,
+                    channels: "rg octahedral normal",
+                },
+                GBufferTarget {
+                    name: "material",
+                    format: wgpu::TextureFormat::Rgba8Unorm,
+                    channels: "r metallic, g roughness, b emissive, a flags",
+                },
+            ],
+            normal_encoding: NormalEncoding::Octahedral,
+        }
+    }
+
+    // 24 bytes per pixel, simpler to debug and no precision loss on normals
+    pub fn fat() -> Self {
+        GBufferLayout {
+            targets: vec![
+                GBufferTarget {
+                    name: "albedo",
+                    format: wgpu::TextureFormat::Rgba16Float,
+                    channels: "rgb albedo, a occlusion",
+                },
+                GBufferTarget {
+                    name: "normal",
+                    format: wgpu::TextureFormat::Rgba16Float,
+                    channels: "rgb normal, a unused",
+                },
+                GBufferTarget {
+                    name: "material",
+                    format: wgpu::TextureFormat::Rgba16Float,
+                    channels: "r metallic, g roughness, b emissive, a flags",
+                },
+            ],
+            normal_encoding: NormalEncoding::Raw,
+        }
+    }
+
+    // 12 bytes per pixel for editor tooling, the entity id for picking and the world normal for
+    // aligning gizmos. Ids are written as the entity index plus one, 0 is the background.
+    pub fn tooling() -> Self {
+        GBufferLayout {
+            targets: vec![
+                GBufferTarget {
+                    name: "entity id",
+                    format: wgpu::TextureFormat::R32Uint,
+                    channels: "r entity id, 0 for the background",
+                },
+                GBufferTarget {
+                    name: "normal",
+                    format: wgpu::TextureFormat::Rgba16Float,
+                    channels: "rgb world normal, a coverage",
+                },
+            ],
+            normal_encoding: NormalEncoding::Raw,
+        }
+    }
+
+    pub fn get_color_targets(&self) -> Vec<Option<wgpu::ColorTargetState>> {
+        self.targets.iter().map(|target| Some(target.format.into())).collect()
+    }
+
+    pub fn get_bytes_per_pixel(&self) -> u32 {
+        self.targets
+            .iter()
+            .map(|target| target.format.block_size(None).unwrap_or(0))
+            .sum()
+    }
+
+    pub fn create_textures(&self, context: &GpuContext) -> Vec<(wgpu::Texture, wgpu::TextureView)> {
+        self.create_textures_with_usage(context, wgpu::TextureUsages::empty())
+    }
+
+    // With extra usages, e.g. COPY_SRC to read the targets back
+    pub fn create_textures_with_usage(&self, context: &GpuContext, usage: wgpu::TextureUsages) -> Vec<(wgpu::Texture, wgpu::TextureView)> {
+        self.create_textures_with_size(context, context.config.width, context.config.height, usage)
+    }
+
+    pub fn create_textures_with_size(
+        &self,
+        context: &GpuContext,
+        width: u32,
+        height: u32,
+        usage: wgpu::TextureUsages,
+    ) -> Vec<(wgpu::Texture, wgpu::TextureView)> {
+        self.targets
+            .iter()
+            .map(|target| {
+                let texture = context.device.create_texture(&wgpu::TextureDescriptor {
+                    label: Some(target.name),
+                    size: wgpu::Extent3d {
+                        width,
+                        height,
+                        depth_or_array_layers: 1,
+                    },
+                    mip_level_count: 1,
+                    sample_count: 1,
+                    dimension: wgpu::TextureDimension::D2,
+                    format: target.format,
+                    usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING | usage,
+                    view_formats: &[],
+                });
+                let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+                (texture, view)
+            })
+            .collect()
+    }
+}
+
+impl Default for GBufferLayout {
+    fn default() -> Self {
+        GBufferLayout::compact()
+    }
+}
+
+// The textures of a GBufferLayout at the surface resolution. All targets are recreated together on
+// resize, so bind groups sampling them have to be rebuilt after that. See create_surface_gbuffer
+// for one that follows the surface size.
+pub struct GBuffer {
+    pub layout: GBufferLayout,
+    // added to RENDER_ATTACHMENT and TEXTURE_BINDING
+    pub usage: wgpu::TextureUsages,
+    // in @location order
+    pub targets: Vec<(wgpu::Texture, TextureView)>,
+}
+
+impl GBuffer {
+    pub fn new(context: &GpuContext, layout: GBufferLayout) -> Self {
+        GBuffer::with_usage(context, layout, wgpu::TextureUsages::empty())
+    }
+
+    pub fn with_usage(context: &GpuContext, layout: GBufferLayout, usage: wgpu::TextureUsages) -> Self {
+        let targets = layout.create_textures_with_usage(context, usage);
+        GBuffer { layout, usage, targets }
+    }
+
+    pub fn resize_to(&mut self, context: &GpuContext, width: u32, height: u32) {
+        self.targets = self.layout.create_textures_with_size(context, width, height, self.usage);
+    }
+
+    pub fn get_texture(&self, index: usize) -> &wgpu::Texture {
+        &self.targets[index].0
+    }
+
+    pub fn get_view(&self, index: usize) -> &TextureView {
+        &self.targets[index].1
+    }
+
+    pub fn get_view_by_name(&self, name: &str) -> Option<&TextureView> {
+        let index = self.layout.targets.iter().position(|target| target.name == name)?;
+        Some(self.get_view(index))
+    }
+
+    pub fn get_views(&self) -> impl Iterator<Item = &TextureView> {
+        self.targets.iter().map(|(_, view)| view)
+    }
+
+    // The fragment targets of geometry pipelines writing this gbuffer
+    pub fn get_color_targets(&self) -> Vec<Option<wgpu::ColorTargetState>> {
+        self.layout.get_color_targets()
+    }
+
+    // A pass writing every target, add the depth attachment before beginning it
+    pub fn get_pass_builder(&self, label: &'static str, clear: Option<wgpu::Color>) -> RenderPassBuilder<'_> {
+        RenderPassBuilder::new().label(label).colors(self.get_views(), clear)
+    }
+}
+
+// The gbuffer is registered with the context and recreated at the new size by GpuContext::resize
+pub fn create_surface_gbuffer(context: &mut GpuContext, layout: GBufferLayout, usage: wgpu::TextureUsages) -> Rc<RefCell<GBuffer>> {
+    let gbuffer = Rc::new(RefCell::new(GBuffer::with_usage(context, layout, usage)));
+    context
+        .resize_registry
+        .register_resource(&gbuffer, |gbuffer, context, width, height| {
+            gbuffer.resize_to(context, width, height)
+        });
+    gbuffer
+}
+
+fn sign_not_zero(v: Vec2) -> Vec2 {
+    vec2(if v.x >= 0.0 { 1.0 } else { -1.0 }, if v.y >= 0.0 { 1.0 } else { -1.0 })
+}
+
+// Cpu versions of the functions in gbuffer_encoding.wgsl
+pub fn encode_octahedral(normal: Vec3) -> Vec2 {
+    let n = normal / (normal.x.abs() + normal.y.abs() + normal.z.abs());
+    if n.z >= 0.0 {
+        return vec2(n.x, n.y);
+    }
+    (Vec2::ONE - vec2(n.y, n.x).abs()) * sign_not_zero(vec2(n.x, n.y))
+}
+
+pub fn decode_octahedral(encoded: Vec2) -> Vec3 {
+    let mut n = vec3(encoded.x, encoded.y, 1.0 - encoded.x.abs() - encoded.y.abs());
+    if n.z < 0.0 {
+        let xy = (Vec2::ONE - vec2(n.y, n.x).abs()) * sign_not_zero(vec2(n.x, n.y));
+        n = vec3(xy.x, xy.y, n.z);
+    }
+    n.normalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{vec3, Vec2, Vec3};
+
+    use crate::gbuffer::{create_surface_gbuffer, decode_octahedral, encode_octahedral, GBuffer, GBufferLayout, GBUFFER_ENCODING_WGSL};
+    use crate::gpu_context::GpuContext;
+    use crate::shader_test::ShaderTest;
+
+    // Each normal encoded into xy and decoded again into the next output
+    const ROUND_TRIP_WGSL: &str = r"
+@compute @workgroup_size(1) fn round_trip(@builtin(global_invocation_id) id: vec3<u32>) {
+    let encoded = encode_octahedral(test_inputs[id.x].xyz);
+    test_outputs[id.x * 2u] = vec4<f32>(encoded, 0.0, 0.0);
+    test_outputs[id.x * 2u + 1u] = vec4<f32>(decode_octahedral(encoded), 0.0);
+}
+";
+
+    fn get_test_normals() -> [Vec3; 7] {
+        [
+            vec3(0.0, 0.0, 1.0),
+            vec3(0.0, 0.0, -1.0),
+            vec3(1.0, 0.0, 0.0),
+            vec3(0.0, -1.0, 0.0),
+            vec3(0.3, -0.5, 0.8).normalize(),
+            vec3(-0.7, 0.2, -0.4).normalize(),
+            vec3(-0.1, -0.9, -0.2).normalize(),
+        ]
+    }
+
+    #[test]
+    fn test_octahedral_round_trip() {
+        for normal in get_test_normals() {
+            let encoded = encode_octahedral(normal);
+            assert!(encoded.x.abs() <= 1.0 && encoded.y.abs() <= 1.0);
+
+            let decoded = decode_octahedral(encoded);
+            assert!((decoded - normal).length() < 1e-5, "{:?} decoded as {:?}", normal, decoded);
+        }
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "gpu_tests"), ignore = "needs a gpu adapter")]
+    fn test_octahedral_shader() {
+        let context = pollster::block_on(GpuContext::new_headless(4, 4)).unwrap();
+        let normals = get_test_normals();
+        let inputs: Vec<[f32; 4]> = normals.iter().map(|normal| normal.extend(0.0).to_array()).collect();
+
+        let outputs = ShaderTest::new(GBUFFER_ENCODING_WGSL, ROUND_TRIP_WGSL)
+            .run(&context, "round_trip", &inputs, normals.len() as u32, normals.len() * 2)
+            .unwrap();
+
+        for (normal, output) in normals.iter().zip(outputs.chunks(2)) {
+            let (encoded, expected) = (Vec2::from_slice(&output[0]), encode_octahedral(*normal));
+            assert!((encoded - expected).length() < 1e-6, "{} instead of {}", encoded, expected);
+
+            let (decoded, expected) = (Vec3::from_slice(&output[1]), decode_octahedral(encoded));
+            assert!((decoded - expected).length() < 1e-6, "{} instead of {}", decoded, expected);
+        }
+    }
+
+    #[test]
+    fn test_gbuffer_layouts() {
+        assert_eq!(GBufferLayout::compact().get_color_targets().len(), 3);
+        assert_eq!(GBufferLayout::compact().get_bytes_per_pixel(), 12);
+        assert_eq!(GBufferLayout::fat().get_bytes_per_pixel(), 24);
+
+        let tooling = GBufferLayout::tooling();
+        assert_eq!(tooling.get_bytes_per_pixel(), 12);
+        assert_eq!(tooling.targets[0].format, wgpu::TextureFormat::R32Uint);
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "gpu_tests"), ignore = "needs a gpu adapter")]
+    fn test_gbuffer_targets() {
+        let context = pollster::block_on(GpuContext::new_headless(8, 4)).unwrap();
+        let mut gbuffer = GBuffer::with_usage(&context, GBufferLayout::compact(), wgpu::TextureUsages::COPY_SRC);
+
+        let builder = gbuffer.get_pass_builder("gbuffer", Some(wgpu::Color::TRANSPARENT));
+        assert_eq!(builder.color_attachments.len(), 3);
+        assert_eq!(gbuffer.get_color_targets().len(), builder.color_attachments.len());
+        assert!(gbuffer.get_view_by_name("normal").is_some());
+        assert!(gbuffer.get_view_by_name("velocity").is_none());
+
+        // every target follows the new size and keeps its format and usage
+        gbuffer.resize_to(&context, 16, 8);
+        for ((texture, _), target) in gbuffer.targets.iter().zip(&gbuffer.layout.targets) {
+            assert_eq!((texture.width(), texture.height()), (16, 8));
+            assert_eq!(texture.format(), target.format);
+            assert!(texture.usage().contains(wgpu::TextureUsages::COPY_SRC));
+        }
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "gpu_tests"), ignore = "needs a gpu adapter")]
+    fn test_surface_gbuffer_resize() {
+        let mut context = pollster::block_on(GpuContext::new_headless(8, 4)).unwrap();
+        let gbuffer = create_surface_gbuffer(&mut context, GBufferLayout::compact(), wgpu::TextureUsages::empty());
+
+        context.resize(winit::dpi::PhysicalSize::new(32, 16));
+        for (texture, _) in &gbuffer.borrow().targets {
+            assert_eq!((texture.width(), texture.height()), (32, 16));
+        }
+    }
+}
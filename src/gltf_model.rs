@@ -0,0 +1,392 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::rc::Rc;
+
+use base64::Engine;
+use glam::{Vec2, Vec3};
+use gltf::mesh::util::ReadIndices;
+use wgpu::util::DeviceExt;
+
+use crate::culling::{Aabb, BoundingSphere};
+use crate::error::Error;
+use crate::error::Error::{MeshError, TextureError};
+use crate::gpu_context::GpuContext;
+use crate::model_mesh::{compute_bounds, interleave, ModelVertex};
+use crate::texture::{load_texture_from_bytes, load_texture_from_path, Texture2D};
+
+// A static model loaded from a .gltf or .glb file, one mesh per primitive. The meshes are in
+// mesh space, node transforms and skins are not applied.
+#[derive(Debug)]
+pub struct GltfModel {
+    pub name: String,
+    pub meshes: Vec<GltfMesh>,
+}
+
+// ModelVertex vertices, so the meshes draw with ModelVertex::vertex_description
+#[derive(Debug)]
+pub struct GltfMesh {
+    pub name: String,
+    pub vertex_buffer: wgpu::Buffer,
+    pub index_buffer: wgpu::Buffer,
+    pub index_count: u32,
+    pub index_format: wgpu::IndexFormat,
+    pub base_color_factor: [f32; 4],
+    // shared by the meshes whose materials use the same image
+    pub base_color_texture: Option<Rc<Texture2D>>,
+    pub aabb: Aabb,
+    pub bounding_sphere: BoundingSphere,
+}
+
+// Indices in the width of the file, u8 indices are widened to u16 since wgpu has no u8 format
+#[derive(Debug, Clone, PartialEq)]
+pub enum IndexData {
+    U16(Vec<u16>),
+    U32(Vec<u32>),
+}
+
+impl IndexData {
+    pub fn from_read_indices(indices: ReadIndices) -> Self {
+        match indices {
+            ReadIndices::U8(indices) => IndexData::U16(indices.map(u16::from).collect()),
+            ReadIndices::U16(indices) => IndexData::U16(indices.collect()),
+            ReadIndices::U32(indices) => IndexData::U32(indices.collect()),
+        }
+    }
+
+    // For primitives without indices, the narrowest format holding every vertex index
+    pub fn sequential(vertex_count: usize) -> Self {
+        match vertex_count <= u16::MAX as usize + 1 {
+            true => IndexData::U16((0..vertex_count).map(|index| index as u16).collect()),
+            false => IndexData::U32((0..vertex_count as u32).collect()),
+        }
+    }
+
+    pub fn format(&self) -> wgpu::IndexFormat {
+        match self {
+            IndexData::U16(_) => wgpu::IndexFormat::Uint16,
+            IndexData::U32(_) => wgpu::IndexFormat::Uint32,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            IndexData::U16(indices) => indices.len(),
+            IndexData::U32(indices) => indices.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            IndexData::U16(indices) => bytemuck::cast_slice(indices),
+            IndexData::U32(indices) => bytemuck::cast_slice(indices),
+        }
+    }
+
+    pub fn to_u32(&self) -> Vec<u32> {
+        match self {
+            IndexData::U16(indices) => indices.iter().map(|index| *index as u32).collect(),
+            IndexData::U32(indices) => indices.clone(),
+        }
+    }
+}
+
+pub fn load_gltf(context: &GpuContext, path: impl AsRef<Path>) -> Result<GltfModel, Error> {
+    let path = path.as_ref();
+    let gltf = gltf::Gltf::open(path)?;
+    let base_path = path.parent().unwrap_or(Path::new(""));
+    let buffers = gltf::import_buffers(&gltf.document, Some(base_path), gltf.blob.clone())?;
+
+    // images by index, loaded the first time a material uses them
+    let mut textures: HashMap<usize, Rc<Texture2D>> = HashMap::new();
+    let mut meshes = vec![];
+
+    for mesh in gltf.document.meshes() {
+        let mesh_name = mesh.name().map_or_else(|| format!("mesh {}", mesh.index()), str::to_string);
+
+        for primitive in mesh.primitives() {
+            let name = format!("{} primitive {}", mesh_name, primitive.index());
+
+            if primitive.mode() != gltf::mesh::Mode::Triangles {
+                return Err(MeshError(format!("{}: {:?} primitives are not supported", name, primitive.mode())));
+            }
+
+            let reader = primitive.reader(|buffer| buffers.get(buffer.index()).map(|data| data.0.as_slice()));
+
+            let positions = reader.read_positions().map(|positions| positions.map(Vec3::from).collect());
+            let normals = reader.read_normals().map(|normals| normals.map(Vec3::from).collect());
+            let uvs = reader.read_tex_coords(0).map(|uvs| uvs.into_f32().map(Vec2::from).collect());
+            let indices = reader.read_indices().map(IndexData::from_read_indices);
+
+            let (vertices, indices) = get_primitive_vertices(&name, positions, normals, uvs, indices)?;
+
+            let material = primitive.material();
+            let pbr = material.pbr_metallic_roughness();
+            let base_color_texture = match pbr.base_color_texture() {
+                Some(info) => {
+                    let image = info.texture().source();
+                    let texture = match textures.get(&image.index()) {
+                        Some(texture) => texture.clone(),
+                        None => {
+                            let texture = Rc::new(load_gltf_image(context, &image, &buffers, base_path)?);
+                            textures.insert(image.index(), texture.clone());
+                            texture
+                        }
+                    };
+                    Some(texture)
+                }
+                None => None,
+            };
+
+            meshes.push(create_gltf_mesh(
+                context,
+                name,
+                &vertices,
+                &indices,
+                pbr.base_color_factor(),
+                base_color_texture,
+            ));
+        }
+    }
+
+    if meshes.is_empty() {
+        return Err(MeshError(format!("{:?} has no meshes", path)));
+    }
+
+    Ok(GltfModel {
+        name: path
+            .file_stem()
+            .map_or_else(String::new, |stem| stem.to_string_lossy().into_owned()),
+        meshes,
+    })
+}
+
+// Interleaved vertices of a primitive. Positions are required, missing normals are computed from
+// the triangles and missing uvs are zero.
+pub fn get_primitive_vertices(
+    name: &str,
+    positions: Option<Vec<Vec3>>,
+    normals: Option<Vec<Vec3>>,
+    uvs: Option<Vec<Vec2>>,
+    indices: Option<IndexData>,
+) -> Result<(Vec<ModelVertex>, IndexData), Error> {
+    let positions = positions.ok_or_else(|| MeshError(format!("{}: missing POSITION attribute", name)))?;
+    let indices = indices.unwrap_or_else(|| IndexData::sequential(positions.len()));
+
+    let triangle_indices = indices.to_u32();
+    if triangle_indices.len() % 3 != 0 {
+        return Err(MeshError(format!(
+            "{}: {} indices is not a whole number of triangles",
+            name,
+            triangle_indices.len()
+        )));
+    }
+    if let Some(index) = triangle_indices.iter().find(|index| **index as usize >= positions.len()) {
+        return Err(MeshError(format!(
+            "{}: index {} out of range of {} vertices",
+            name,
+            index,
+            positions.len()
+        )));
+    }
+
+    let normals = normals.unwrap_or_else(|| compute_normals(&positions, &triangle_indices));
+    let uvs = uvs.unwrap_or_else(|| vec![Vec2::ZERO; positions.len()]);
+
+    let vertices = interleave(&positions, &normals, &uvs).map_err(|e| MeshError(format!("{}: {:?}", name, e)))?;
+    Ok((vertices, indices))
+}
+
+// Area weighted vertex normals, zero for vertices only used by degenerate triangles
+pub fn compute_normals(positions: &[Vec3], indices: &[u32]) -> Vec<Vec3> {
+    let mut normals = vec![Vec3::ZERO; positions.len()];
+    for triangle in indices.chunks_exact(3) {
+        let [a, b, c] = [triangle[0], triangle[1], triangle[2]].map(|index| index as usize);
+        // the cross product length is twice the triangle area
+        let normal = (positions[b] - positions[a]).cross(positions[c] - positions[a]);
+        for index in [a, b, c] {
+            normals[index] += normal;
+        }
+    }
+    normals.iter().map(|normal| normal.normalize_or_zero()).collect()
+}
+
+fn create_gltf_mesh(
+    context: &GpuContext,
+    name: String,
+    vertices: &[ModelVertex],
+    indices: &IndexData,
+    base_color_factor: [f32; 4],
+    base_color_texture: Option<Rc<Texture2D>>,
+) -> GltfMesh {
+    let vertex_buffer = context.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some(&format!("{} vertex buffer", name)),
+        contents: bytemuck::cast_slice(vertices),
+        usage: wgpu::BufferUsages::VERTEX,
+    });
+
+    let index_buffer = context.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some(&format!("{} index buffer", name)),
+        contents: indices.as_bytes(),
+        usage: wgpu::BufferUsages::INDEX,
+    });
+
+    let (aabb, bounding_sphere) = compute_bounds(vertices);
+
+    GltfMesh {
+        name,
+        vertex_buffer,
+        index_buffer,
+        index_count: indices.len() as u32,
+        index_format: indices.format(),
+        base_color_factor,
+        base_color_texture,
+        aabb,
+        bounding_sphere,
+    }
+}
+
+// Base color images are srgb, COLOR_TEXTURE_FORMAT
+fn load_gltf_image(
+    context: &GpuContext,
+    image: &gltf::Image,
+    buffers: &[gltf::buffer::Data],
+    base_path: &Path,
+) -> Result<Texture2D, Error> {
+    match image.source() {
+        gltf::image::Source::View { view, .. } => {
+            let buffer = buffers
+                .get(view.buffer().index())
+                .ok_or_else(|| TextureError(format!("image {} buffer is missing", image.index())))?;
+            let bytes = buffer
+                .0
+                .get(view.offset()..view.offset() + view.length())
+                .ok_or_else(|| TextureError(format!("image {} view is outside of its buffer", image.index())))?;
+            load_texture_from_bytes(context, bytes, None)
+        }
+        gltf::image::Source::Uri { uri, .. } if uri.starts_with("data:") => {
+            let bytes = decode_data_uri(uri).map_err(|e| TextureError(format!("image {}: {}", image.index(), e)))?;
+            load_texture_from_bytes(context, &bytes, None)
+        }
+        gltf::image::Source::Uri { uri, .. } => {
+            let path = decode_uri_path(uri).map_err(|e| TextureError(format!("image {}: {}", image.index(), e)))?;
+            load_texture_from_path(context, base_path.join(path), None)
+        }
+    }
+}
+
+// The payload of an embedded data:<media type>;base64,<data> uri
+pub fn decode_data_uri(uri: &str) -> Result<Vec<u8>, String> {
+    let (header, data) = uri
+        .strip_prefix("data:")
+        .and_then(|rest| rest.split_once(','))
+        .ok_or_else(|| "invalid data uri".to_string())?;
+    if !header.ends_with(";base64") {
+        return Err(format!("data uri {} is not base64 encoded", header));
+    }
+    base64::engine::general_purpose::STANDARD
+        .decode(data)
+        .map_err(|e| format!("invalid base64 data: {}", e))
+}
+
+// Relative uris are percent encoded, e.g. a space in a file name is %20
+pub fn decode_uri_path(uri: &str) -> Result<String, String> {
+    let mut decoded = Vec::with_capacity(uri.len());
+    let mut rest = uri.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte != b'%' {
+            decoded.push(byte);
+            rest = tail;
+            continue;
+        }
+        let value = tail
+            .get(..2)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            .ok_or_else(|| format!("invalid percent encoding in {}", uri))?;
+        decoded.push(value);
+        rest = &tail[2..];
+    }
+    String::from_utf8(decoded).map_err(|_| format!("{} is not utf-8 once decoded", uri))
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{vec3, Vec2, Vec3};
+
+    use crate::error::Error;
+    use crate::gltf_model::{compute_normals, decode_data_uri, decode_uri_path, get_primitive_vertices, IndexData};
+
+    #[test]
+    fn test_index_formats() {
+        let indices = IndexData::U16(vec![0, 1, 2]);
+        assert_eq!(indices.format(), wgpu::IndexFormat::Uint16);
+        assert_eq!(indices.as_bytes().len(), 6);
+
+        let indices = IndexData::U32(vec![0, 1, 2, 70000]);
+        assert_eq!(indices.format(), wgpu::IndexFormat::Uint32);
+        assert_eq!(indices.as_bytes().len(), 16);
+        assert_eq!(indices.len(), 4);
+
+        // unindexed primitives get the narrowest format that fits
+        assert_eq!(IndexData::sequential(3), IndexData::U16(vec![0, 1, 2]));
+        assert_eq!(IndexData::sequential(65536).format(), wgpu::IndexFormat::Uint16);
+        assert_eq!(IndexData::sequential(65537).format(), wgpu::IndexFormat::Uint32);
+    }
+
+    #[test]
+    fn test_primitive_vertices() {
+        let positions = vec![vec3(0.0, 0.0, 0.0), vec3(1.0, 0.0, 0.0), vec3(0.0, 1.0, 0.0)];
+
+        let (vertices, indices) = get_primitive_vertices("triangle", Some(positions.clone()), None, None, None).unwrap();
+        assert_eq!(vertices.len(), 3);
+        assert_eq!(indices, IndexData::U16(vec![0, 1, 2]));
+        // counter clockwise, facing +z
+        let normal = vertices[0].normal;
+        assert_eq!(normal, Vec3::Z);
+        let uv = vertices[2].uv;
+        assert_eq!(uv, Vec2::ZERO);
+
+        // positions are required
+        let result = get_primitive_vertices("no positions", None, Some(vec![Vec3::Z; 3]), None, None);
+        assert!(matches!(result, Err(Error::MeshError(message)) if message.contains("POSITION")));
+
+        let out_of_range = Some(IndexData::U32(vec![0, 1, 3]));
+        assert!(get_primitive_vertices("out of range", Some(positions.clone()), None, None, out_of_range).is_err());
+        let partial_triangle = Some(IndexData::U16(vec![0, 1]));
+        assert!(get_primitive_vertices("partial", Some(positions), None, None, partial_triangle).is_err());
+    }
+
+    #[test]
+    fn test_compute_normals() {
+        // two triangles of a quad in the xy plane sharing an edge, and an unused vertex
+        let positions = [
+            vec3(0.0, 0.0, 0.0),
+            vec3(1.0, 0.0, 0.0),
+            vec3(1.0, 1.0, 0.0),
+            vec3(0.0, 1.0, 0.0),
+            vec3(5.0, 5.0, 5.0),
+        ];
+        let normals = compute_normals(&positions, &[0, 1, 2, 2, 3, 0]);
+        assert!(normals[..4].iter().all(|normal| *normal == Vec3::Z));
+        assert_eq!(normals[4], Vec3::ZERO);
+    }
+
+    #[test]
+    fn test_image_uris() {
+        // embedded images are base64 data uris
+        assert_eq!(decode_data_uri("data:image/png;base64,aGVsbG8=").unwrap(), b"hello");
+        assert!(decode_data_uri("data:image/png,hello").is_err());
+        assert!(decode_data_uri("data:image/png;base64,not base64!").is_err());
+
+        // external images are percent encoded relative paths
+        assert_eq!(decode_uri_path("textures/base%20color.png").unwrap(), "textures/base color.png");
+        assert_eq!(decode_uri_path("caf%C3%A9.png").unwrap(), "café.png");
+        assert_eq!(decode_uri_path("plain.png").unwrap(), "plain.png");
+        assert!(decode_uri_path("broken%2").is_err());
+        assert!(decode_uri_path("broken%zz.png").is_err());
+    }
+}
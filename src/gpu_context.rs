@@ -1,19 +1,138 @@
+use crate::capabilities::Capabilities;
+use crate::default_textures::{DefaultTexture, DefaultTextureKind};
+use crate::error::Error;
+use crate::error::Error::{NoAdapterError, UnsupportedError, UnsupportedSurfaceFormatError, ValidationError};
 use crate::hash_map::HashMap;
-use log::debug;
+use crate::msaa::{check_context_sample_count, get_supported_sample_count};
+use crate::pipeline_builder::PipelineBuilder;
+use crate::pipeline_cache::PipelineCache;
+use crate::post::tonemap::get_output_encodes_srgb;
+use crate::resize_registry::ResizeRegistry;
+use crate::shader_bindings::BindingRegistry;
+use crate::snapshot::{read_texture_rgba, save_texture_png};
+use crate::texture::{surface_target_descriptor, DEPTH_FORMAT};
+use log::{debug, warn};
+use std::cell::RefCell;
+use std::path::Path;
 use std::rc::Rc;
 use std::sync::Arc;
-use wgpu::BindGroupLayout;
+use wgpu::{BindGroupLayout, RenderPipeline, ShaderModule};
 use winit::window::Window;
 
+pub const DEFAULT_FRAME_LATENCY: u32 = 2;
+
+// Headless frames are rendered to a texture of this format, which can be copied out for image comparisons
+pub const HEADLESS_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
+pub const HEADLESS_USAGE: wgpu::TextureUsages = wgpu::TextureUsages::RENDER_ATTACHMENT
+    .union(wgpu::TextureUsages::TEXTURE_BINDING)
+    .union(wgpu::TextureUsages::COPY_SRC);
+
+// Requested when the adapter has them, users check device.features() or Capabilities before relying on them
+pub const OPTIONAL_FEATURES: wgpu::Features = wgpu::Features::DEPTH_CLIP_CONTROL.union(wgpu::Features::ADDRESS_MODE_CLAMP_TO_BORDER);
+
+// How the surface format is picked from the formats the surface supports. The view format in
+// config.view_formats[0], which pipelines and passes target, can differ from the swapchain format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SurfaceFormatPolicy {
+    // an srgb format, or an srgb view of a linear one, so shaders output linear colors
+    #[default]
+    PreferSrgb,
+    // a linear format or a linear view of an srgb one, for shaders that encode colors themselves
+    PreferLinear,
+    // the srgb variant of the format is used when only that is supported, the first format when neither is
+    Explicit(wgpu::TextureFormat),
+}
+
+// Options used when creating the context and configuring the surface
+#[derive(Debug, Clone)]
+pub struct GpuContextDescriptor {
+    pub desired_maximum_frame_latency: u32,
+    // msaa samples of the surface color and depth targets, unsupported counts fall back to 1
+    pub sample_count: u32,
+    pub surface_format_policy: SurfaceFormatPolicy,
+}
+
+impl Default for GpuContextDescriptor {
+    fn default() -> Self {
+        GpuContextDescriptor::new()
+    }
+}
+
+impl GpuContextDescriptor {
+    pub fn new() -> Self {
+        GpuContextDescriptor {
+            desired_maximum_frame_latency: DEFAULT_FRAME_LATENCY,
+            sample_count: 1,
+            surface_format_policy: SurfaceFormatPolicy::PreferSrgb,
+        }
+    }
+
+    pub fn set_frame_latency(mut self, latency: u32) -> Self {
+        self.desired_maximum_frame_latency = latency;
+        self
+    }
+
+    pub fn set_sample_count(mut self, sample_count: u32) -> Self {
+        self.sample_count = sample_count;
+        self
+    }
+
+    pub fn set_surface_format_policy(mut self, policy: SurfaceFormatPolicy) -> Self {
+        self.surface_format_policy = policy;
+        self
+    }
+}
+
+// Headless contexts have no window or surface, frames go to offscreen_texture instead and
+// config describes that texture.
 pub struct GpuContext {
-    pub window: Arc<Window>,
-    pub surface: wgpu::Surface<'static>,
+    pub window: Option<Arc<Window>>,
+    pub surface: Option<wgpu::Surface<'static>>,
+    pub offscreen_texture: Option<wgpu::Texture>,
     pub adapter: wgpu::Adapter,
     pub device: wgpu::Device,
     pub queue: wgpu::Queue,
     pub config: wgpu::SurfaceConfiguration,
     pub size: winit::dpi::PhysicalSize<u32>,
     pub bind_layout_cache: HashMap<String, Rc<BindGroupLayout>>,
+    // recreates size dependent resources like surface matched targets on resize
+    pub resize_registry: ResizeRegistry,
+    // created on first use by default_textures::get_default_texture
+    pub default_textures: HashMap<DefaultTextureKind, Rc<DefaultTexture>>,
+    // created on first use by Texture2D::generate_mipmaps, one per texture format
+    pub mipmap_pipelines: HashMap<wgpu::TextureFormat, Rc<RenderPipeline>>,
+    pub capabilities: Capabilities,
+    // read by create_depth_texture, PipelineBuilder and create_surface_msaa_target
+    pub sample_count: u32,
+    // how config.format and its view format were picked, see set_surface_format
+    pub surface_format_policy: SurfaceFormatPolicy,
+    // layouts and shaders the pipeline builders check their bindings against in debug builds
+    pub binding_registry: BindingRegistry,
+    // render pipelines shared by the passes, see get_or_build_pipeline. HotReloadShader::poll_reload
+    // drops the ones of the module it replaces.
+    pub pipeline_cache: RefCell<PipelineCache>,
+}
+
+// The texture a frame is rendered to, either the swapchain texture or the headless offscreen texture
+pub enum Frame<'a> {
+    Surface(wgpu::SurfaceTexture),
+    Offscreen(&'a wgpu::Texture),
+}
+
+impl Frame<'_> {
+    pub fn texture(&self) -> &wgpu::Texture {
+        match self {
+            Frame::Surface(surface_texture) => &surface_texture.texture,
+            Frame::Offscreen(texture) => texture,
+        }
+    }
+
+    // Nothing to present for offscreen frames, their texture is read back or sampled instead
+    pub fn present(self) {
+        if let Frame::Surface(surface_texture) = self {
+            surface_texture.present();
+        }
+    }
 }
 
 impl Drop for GpuContext {
@@ -23,14 +142,18 @@ impl Drop for GpuContext {
 }
 
 impl GpuContext {
-    pub async fn new(window: Arc<Window>) -> GpuContext {
+    pub async fn new(window: Arc<Window>) -> Result<GpuContext, Error> {
+        Self::new_with_descriptor(window, &GpuContextDescriptor::default()).await
+    }
+
+    pub async fn new_with_descriptor(window: Arc<Window>, descriptor: &GpuContextDescriptor) -> Result<GpuContext, Error> {
         let mut size = window.inner_size();
         size.width = size.width.max(1);
         size.height = size.height.max(1);
 
         let instance = wgpu::Instance::default();
 
-        let surface = instance.create_surface(window.clone()).unwrap();
+        let surface = instance.create_surface(window.clone())?;
 
         let adapter = instance
             .request_adapter(&wgpu::RequestAdapterOptions {
@@ -39,62 +162,192 @@ impl GpuContext {
                 force_fallback_adapter: false,
             })
             .await
-            .expect("Failed to find an appropriate adapter");
-
-        let desired_max_bind_groups = 8;
-
-        let (device, queue) = adapter
-            .request_device(
-                &wgpu::DeviceDescriptor {
-                    label: None,
-                    required_features: wgpu::Features::ADDRESS_MODE_CLAMP_TO_BORDER,
-                    // required_limits: wgpu::Limits::downlevel_webgl2_defaults().using_resolution(adapter.limits()),
-                    required_limits: wgpu::Limits {
-                        max_bind_groups: desired_max_bind_groups,
-                        ..wgpu::Limits::default() // Fill in other limits with default values
-                    },
-                },
-                None,
-            )
-            .await
-            .expect("Failed to create device");
+            .ok_or(NoAdapterError)?;
+
+        let (device, queue, capabilities) = request_device(&adapter).await?;
 
         let surface_caps = surface.get_capabilities(&adapter);
+        if surface_caps.formats.is_empty() {
+            return Err(UnsupportedSurfaceFormatError(format!(
+                "the surface has no formats supported by {}",
+                adapter.get_info().name
+            )));
+        }
 
-        // Shader code in this tutorial assumes an sRGB surface texture. Using a different
-        // one will result in all the colors coming out darker. If you want to support non
-        // sRGB surfaces, you'll need to account for that when drawing to the frame.
-        let surface_format = surface_caps
-            .formats
-            .iter()
-            .copied()
-            .find(|f| f.is_srgb())
-            .unwrap_or(surface_caps.formats[0]);
+        let (surface_format, view_format) = select_surface_format(descriptor.surface_format_policy, &surface_caps.formats);
+        debug!("surface format {:?} viewed as {:?}", surface_format, view_format);
 
         let mut config = wgpu::SurfaceConfiguration {
-            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            // copyable when supported, for screenshots
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | (surface_caps.usages & wgpu::TextureUsages::COPY_SRC),
             format: surface_format,
             width: size.width,
             height: size.height,
             present_mode: surface_caps.present_modes[0],
-            desired_maximum_frame_latency: 2,
+            desired_maximum_frame_latency: DEFAULT_FRAME_LATENCY,
             alpha_mode: surface_caps.alpha_modes[0],
-            view_formats: vec![],
+            view_formats: vec![view_format],
         };
 
-        let view_format = config.format.add_srgb_suffix();
-        config.view_formats.push(view_format);
+        apply_frame_latency(&mut config, descriptor.desired_maximum_frame_latency);
         surface.configure(&device, &config);
 
+        let mut context = Self::from_parts(Some(window), Some(surface), adapter, device, queue, config, capabilities);
+        context.sample_count = context.get_supported_sample_count(descriptor.sample_count);
+        context.surface_format_policy = descriptor.surface_format_policy;
+        Ok(context)
+    }
+
+    // For offscreen rendering and tests, frames are rendered to offscreen_texture
+    pub async fn new_headless(width: u32, height: u32) -> Result<GpuContext, Error> {
+        let instance = wgpu::Instance::default();
+
+        let adapter = instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::default(),
+                compatible_surface: None,
+                force_fallback_adapter: false,
+            })
+            .await
+            .ok_or(NoAdapterError)?;
+
+        let (device, queue, capabilities) = request_device(&adapter).await?;
+
+        let config = get_headless_config(width, height);
+        let offscreen_texture = device.create_texture(&surface_target_descriptor(&config, config.usage));
+
+        let mut context = Self::from_parts(None, None, adapter, device, queue, config, capabilities);
+        context.offscreen_texture = Some(offscreen_texture);
+        Ok(context)
+    }
+
+    fn from_parts(
+        window: Option<Arc<Window>>,
+        surface: Option<wgpu::Surface<'static>>,
+        adapter: wgpu::Adapter,
+        device: wgpu::Device,
+        queue: wgpu::Queue,
+        config: wgpu::SurfaceConfiguration,
+        capabilities: Capabilities,
+    ) -> Self {
         Self {
             window,
             surface,
+            offscreen_texture: None,
             adapter,
             device,
             queue,
+            size: winit::dpi::PhysicalSize::new(config.width, config.height),
             config,
-            size,
             bind_layout_cache: HashMap::new(),
+            resize_registry: ResizeRegistry::new(),
+            default_textures: HashMap::new(),
+            mipmap_pipelines: HashMap::new(),
+            capabilities,
+            sample_count: 1,
+            surface_format_policy: SurfaceFormatPolicy::PreferSrgb,
+            binding_registry: BindingRegistry::new(),
+            pipeline_cache: RefCell::new(PipelineCache::new()),
+        }
+    }
+
+    // A wgsl module whose source is kept for the binding checks of the pipeline builders
+    pub fn create_shader_module(&self, label: &str, source: &str) -> ShaderModule {
+        let module = self.device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some(label),
+            source: wgpu::ShaderSource::Wgsl(source.into()),
+        });
+        self.binding_registry.register_shader(&module, source);
+        module
+    }
+
+    // The cached pipeline for the builder's configuration, built on first use
+    pub fn get_or_build_pipeline(&self, builder: &PipelineBuilder, shader: &ShaderModule) -> Result<Rc<RenderPipeline>, Error> {
+        self.pipeline_cache.borrow_mut().get_or_build(self, builder, shader)
+    }
+
+    pub fn is_headless(&self) -> bool {
+        self.surface.is_none()
+    }
+
+    // The format frames are rendered through, pipelines targeting the surface use it
+    pub fn surface_view_format(&self) -> wgpu::TextureFormat {
+        self.config.view_formats[0]
+    }
+
+    // False when shader outputs and clear colors are written to the surface without srgb encoding
+    pub fn is_srgb(&self) -> bool {
+        self.surface_view_format().is_srgb()
+    }
+
+    // A linear clear color, encoded for 8 and 10 bit linear views so it looks the same either way.
+    // Float surfaces take linear values.
+    pub fn get_clear_color(&self, color: wgpu::Color) -> wgpu::Color {
+        get_surface_clear_color(color, get_output_encodes_srgb(self.surface_view_format()))
+    }
+
+    // Tightly packed rgba bytes of the texture's top left width x height region, see snapshot::capture_texture
+    // for an RgbaImage of the whole texture
+    pub fn read_texture_to_rgba(&self, texture: &wgpu::Texture, width: u32, height: u32) -> Result<Vec<u8>, Error> {
+        read_texture_rgba(self, texture, width, height)
+    }
+
+    // Writes the texture's top left width x height region to a png, see snapshot::save_texture_png.
+    // Swapchain textures can be saved when the surface supports COPY_SRC, before the frame is presented.
+    pub fn save_screenshot(&self, texture: &wgpu::Texture, width: u32, height: u32, path: impl AsRef<Path>) -> Result<(), Error> {
+        save_texture_png(self, texture, width, height, path)
+    }
+
+    // The next swapchain texture, or the offscreen texture of a headless context. A surface that
+    // is lost or outdated, e.g. after a monitor switch or sleep, is reconfigured and tried once more.
+    // Errors are left for the caller to skip the frame, only OutOfMemory is fatal.
+    pub fn acquire_frame(&self) -> Result<Frame<'_>, wgpu::SurfaceError> {
+        let Some(surface) = &self.surface else {
+            return Ok(Frame::Offscreen(
+                self.offscreen_texture
+                    .as_ref()
+                    .expect("headless contexts have an offscreen texture"),
+            ));
+        };
+
+        match surface.get_current_texture() {
+            Err(error) if needs_reconfigure(&error) => {
+                debug!("reconfiguring surface after {:?}", error);
+                surface.configure(&self.device, &self.config);
+                surface.get_current_texture().map(Frame::Surface)
+            }
+            result => result.map(Frame::Surface),
+        }
+    }
+
+    // acquire_frame for render loops, None when the frame should be skipped. Panics when out of memory.
+    pub fn acquire_frame_or_skip(&self) -> Option<Frame<'_>> {
+        match self.acquire_frame() {
+            Ok(frame) => Some(frame),
+            Err(wgpu::SurfaceError::OutOfMemory) => panic!("Out of memory acquiring the swap chain texture"),
+            Err(error) => {
+                warn!("skipping frame: {:?}", error);
+                None
+            }
+        }
+    }
+
+    pub fn request_redraw(&self) {
+        if let Some(window) = &self.window {
+            window.request_redraw();
+        }
+    }
+
+    // The headless capabilities only allow the current configuration
+    pub fn get_surface_capabilities(&self) -> wgpu::SurfaceCapabilities {
+        match &self.surface {
+            Some(surface) => surface.get_capabilities(&self.adapter),
+            None => wgpu::SurfaceCapabilities {
+                formats: vec![self.config.format],
+                present_modes: vec![self.config.present_mode],
+                alpha_modes: vec![self.config.alpha_mode],
+                usages: HEADLESS_USAGE,
+            },
         }
     }
 
@@ -103,10 +356,288 @@ impl GpuContext {
         self.size.height = new_size.height.max(1);
         self.config.width = self.size.width;
         self.config.height = self.size.height;
-        self.surface.configure(&self.device, &self.config);
+        self.configure_surface();
+        self.resize_registered_resources();
+    }
+
+    // Applies config to the surface, or recreates the offscreen texture
+    fn configure_surface(&mut self) {
+        match &self.surface {
+            Some(surface) => surface.configure(&self.device, &self.config),
+            None => {
+                let descriptor = surface_target_descriptor(&self.config, self.config.usage);
+                self.offscreen_texture = Some(self.device.create_texture(&descriptor));
+            }
+        }
+    }
+
+    // The registry is taken out while the callbacks run, so they get the context and can't
+    // register new callbacks into it directly
+    fn resize_registered_resources(&mut self) {
+        let mut registry = std::mem::take(&mut self.resize_registry);
+        registry.resize(self, self.config.width, self.config.height);
+        registry.append(&mut self.resize_registry);
+        self.resize_registry = registry;
+    }
+
+    pub fn adapter_info(&self) -> wgpu::AdapterInfo {
+        self.adapter.get_info()
+    }
+
+    // Summary of the gpu, driver and device capabilities to include in bug reports
+    pub fn report_string(&self) -> String {
+        format_report(&self.adapter_info(), self.device.features(), &self.device.limits())
+    }
+
+    // 2 is double buffering, 3 gives triple buffering at the cost of an extra frame of input latency
+    pub fn set_desired_maximum_frame_latency(&mut self, latency: u32) {
+        apply_frame_latency(&mut self.config, latency);
+        self.configure_surface();
+    }
+
+    // Picks the surface and view formats with the policy, e.g. Explicit(Rgba16Float) to switch to hdr
+    // output. Returns true when either format changed, pipelines targeting the surface then have to
+    // be rebuilt by the caller. An explicit format the surface can't provide is an error here.
+    pub fn set_surface_format(&mut self, policy: SurfaceFormatPolicy) -> Result<bool, Error> {
+        let surface_caps = self.get_surface_capabilities();
+        let changed = apply_surface_format(&mut self.config, &surface_caps.formats, policy)?;
+        self.surface_format_policy = policy;
+        if changed {
+            self.configure_surface();
+            self.resize_registered_resources();
+        }
+        Ok(changed)
+    }
+
+    pub fn check_sample_count(&self, sample_count: u32) -> Result<(), Error> {
+        let format = self.config.view_formats[0];
+        check_context_sample_count(
+            format,
+            self.get_format_flags(format),
+            self.get_format_flags(DEPTH_FORMAT),
+            sample_count,
+        )
+    }
+
+    // sample_count when the surface and depth formats support it, 1 otherwise
+    pub fn get_supported_sample_count(&self, sample_count: u32) -> u32 {
+        let format = self.config.view_formats[0];
+        get_supported_sample_count(
+            format,
+            self.get_format_flags(format),
+            self.get_format_flags(DEPTH_FORMAT),
+            sample_count,
+        )
+    }
+
+    // Returns true when the count changed, depth textures, msaa targets and pipelines then have
+    // to be recreated by the caller. Use get_supported_sample_count to fall back to 1x instead of failing.
+    pub fn set_sample_count(&mut self, sample_count: u32) -> Result<bool, Error> {
+        self.check_sample_count(sample_count)?;
+        let changed = self.sample_count != sample_count;
+        self.sample_count = sample_count;
+        Ok(changed)
+    }
+
+    pub fn multisample_state(&self) -> wgpu::MultisampleState {
+        wgpu::MultisampleState {
+            count: self.sample_count,
+            ..Default::default()
+        }
+    }
+
+    fn get_format_flags(&self, format: wgpu::TextureFormat) -> wgpu::TextureFormatFeatureFlags {
+        self.adapter.get_texture_format_features(format).flags
+    }
+
+    // Adds usage to the surface textures, e.g. COPY_SRC to copy finished frames. Returns true
+    // when the surface was reconfigured.
+    pub fn add_surface_usage(&mut self, usage: wgpu::TextureUsages) -> Result<bool, Error> {
+        let surface_caps = self.get_surface_capabilities();
+        let changed = apply_surface_usage(&mut self.config, surface_caps.usages, usage)?;
+        if changed {
+            self.configure_surface();
+        }
+        Ok(changed)
+    }
+}
+
+async fn request_device(adapter: &wgpu::Adapter) -> Result<(wgpu::Device, wgpu::Queue, Capabilities), Error> {
+    let desired_max_bind_groups = 8;
+
+    #[allow(unused_mut)]
+    let mut required_features = adapter.features() & OPTIONAL_FEATURES;
+    #[cfg(feature = "profiling")]
+    {
+        required_features |= adapter.features() & crate::profiler::PROFILING_FEATURES;
+    }
+
+    // WebGL2 can't provide the default limits, compute and storage helpers report unsupported there
+    let base_limits = match adapter
+        .get_downlevel_capabilities()
+        .flags
+        .contains(wgpu::DownlevelFlags::COMPUTE_SHADERS)
+    {
+        true => wgpu::Limits::default(),
+        false => wgpu::Limits::downlevel_webgl2_defaults().using_resolution(adapter.limits()),
+    };
+    let required_limits = wgpu::Limits {
+        max_bind_groups: desired_max_bind_groups,
+        ..base_limits
+    };
+    let capabilities = Capabilities::from_adapter(adapter, required_features, &required_limits);
+
+    let (device, queue) = adapter
+        .request_device(
+            &wgpu::DeviceDescriptor {
+                label: None,
+                required_features,
+                required_limits: required_limits.clone(),
+            },
+            None,
+        )
+        .await?;
+
+    Ok((device, queue, capabilities))
+}
+
+// Lost and Outdated surfaces work again after configure, Timeout and OutOfMemory don't
+pub fn needs_reconfigure(error: &wgpu::SurfaceError) -> bool {
+    matches!(error, wgpu::SurfaceError::Lost | wgpu::SurfaceError::Outdated)
+}
+
+pub fn get_headless_config(width: u32, height: u32) -> wgpu::SurfaceConfiguration {
+    wgpu::SurfaceConfiguration {
+        usage: HEADLESS_USAGE,
+        format: HEADLESS_FORMAT,
+        width: width.max(1),
+        height: height.max(1),
+        present_mode: wgpu::PresentMode::Fifo,
+        desired_maximum_frame_latency: DEFAULT_FRAME_LATENCY,
+        alpha_mode: wgpu::CompositeAlphaMode::Opaque,
+        view_formats: vec![HEADLESS_FORMAT.add_srgb_suffix()],
+    }
+}
+
+pub fn apply_surface_usage(
+    config: &mut wgpu::SurfaceConfiguration,
+    supported_usages: wgpu::TextureUsages,
+    usage: wgpu::TextureUsages,
+) -> Result<bool, Error> {
+    if !supported_usages.contains(usage) {
+        return Err(UnsupportedError(format!(
+            "surface usage {:?} is not supported, supported usages: {:?}",
+            usage, supported_usages
+        )));
+    }
+
+    if config.usage.contains(usage) {
+        return Ok(false);
+    }
+
+    config.usage |= usage;
+    Ok(true)
+}
+
+pub fn apply_surface_format(
+    config: &mut wgpu::SurfaceConfiguration,
+    supported_formats: &[wgpu::TextureFormat],
+    policy: SurfaceFormatPolicy,
+) -> Result<bool, Error> {
+    if let SurfaceFormatPolicy::Explicit(requested) = policy {
+        let variants = [requested, requested.add_srgb_suffix(), requested.remove_srgb_suffix()];
+        if !variants.iter().any(|format| supported_formats.contains(format)) {
+            return Err(ValidationError(format!(
+                "surface format {:?} is not supported, supported formats: {:?}",
+                requested, supported_formats
+            )));
+        }
+    }
+
+    let (format, view_format) = select_surface_format(policy, supported_formats);
+    if config.format == format && config.view_formats == [view_format] {
+        return Ok(false);
+    }
+
+    config.format = format;
+    config.view_formats = vec![view_format];
+    Ok(true)
+}
+
+// The swapchain format and the view format for config.view_formats[0]. Srgb and linear variants of a
+// format can view each other, so a missing variant is provided by the view. formats is never empty.
+pub fn select_surface_format(policy: SurfaceFormatPolicy, formats: &[wgpu::TextureFormat]) -> (wgpu::TextureFormat, wgpu::TextureFormat) {
+    let first = formats[0];
+    match policy {
+        SurfaceFormatPolicy::PreferSrgb => match formats.iter().find(|format| format.is_srgb()) {
+            Some(format) => (*format, *format),
+            None => (first, first.add_srgb_suffix()),
+        },
+        SurfaceFormatPolicy::PreferLinear => match formats.iter().find(|format| !format.is_srgb()) {
+            Some(format) => (*format, *format),
+            None => (first, first.remove_srgb_suffix()),
+        },
+        SurfaceFormatPolicy::Explicit(requested) => {
+            let variant = match requested.is_srgb() {
+                true => requested.remove_srgb_suffix(),
+                false => requested.add_srgb_suffix(),
+            };
+            if formats.contains(&requested) {
+                (requested, requested)
+            } else if formats.contains(&variant) {
+                (variant, requested)
+            } else {
+                warn!("surface format {:?} is not supported, using {:?}", requested, first);
+                (first, first)
+            }
+        }
     }
 }
 
+pub fn get_surface_clear_color(color: wgpu::Color, encode_srgb: bool) -> wgpu::Color {
+    if !encode_srgb {
+        return color;
+    }
+    wgpu::Color {
+        r: linear_to_srgb(color.r),
+        g: linear_to_srgb(color.g),
+        b: linear_to_srgb(color.b),
+        a: color.a,
+    }
+}
+
+pub fn linear_to_srgb(value: f64) -> f64 {
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+// A latency of zero isn't meaningful to the backends, so it is clamped to one frame
+pub fn apply_frame_latency(config: &mut wgpu::SurfaceConfiguration, latency: u32) {
+    config.desired_maximum_frame_latency = latency.max(1);
+}
+
+pub fn format_report(info: &wgpu::AdapterInfo, features: wgpu::Features, limits: &wgpu::Limits) -> String {
+    let mut report = String::new();
+    report += &format!("backend: {:?}\n", info.backend);
+    report += &format!("device: {} ({:?})\n", info.name, info.device_type);
+    report += &format!("vendor id: {:#06x}  device id: {:#06x}\n", info.vendor, info.device);
+    report += &format!("driver: {} {}\n", info.driver, info.driver_info);
+    report += &format!("features: {:?}\n", features);
+    report += &format!(
+        "limits: max_texture_dimension_2d {}  max_bind_groups {}  max_uniform_buffer_binding_size {}  \
+         max_storage_buffer_binding_size {}  min_uniform_buffer_offset_alignment {}\n",
+        limits.max_texture_dimension_2d,
+        limits.max_bind_groups,
+        limits.max_uniform_buffer_binding_size,
+        limits.max_storage_buffer_binding_size,
+        limits.min_uniform_buffer_offset_alignment
+    );
+    report
+}
+
 pub fn get_or_create_bind_group_layout(
     context: &mut GpuContext,
     layout_name: &str,
@@ -119,3 +650,195 @@ pub fn get_or_create_bind_group_layout(
 
     context.bind_layout_cache.get(layout_name).unwrap().clone()
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::gpu_context::{
+        apply_frame_latency, apply_surface_format, apply_surface_usage, format_report, get_headless_config, get_surface_clear_color,
+        linear_to_srgb, needs_reconfigure, select_surface_format, Frame, GpuContext, GpuContextDescriptor, SurfaceFormatPolicy,
+        DEFAULT_FRAME_LATENCY, HEADLESS_FORMAT, HEADLESS_USAGE,
+    };
+    use crate::post::tonemap::get_output_encodes_srgb;
+
+    fn test_config() -> wgpu::SurfaceConfiguration {
+        wgpu::SurfaceConfiguration {
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            format: wgpu::TextureFormat::Bgra8UnormSrgb,
+            width: 800,
+            height: 600,
+            present_mode: wgpu::PresentMode::Fifo,
+            desired_maximum_frame_latency: DEFAULT_FRAME_LATENCY,
+            alpha_mode: wgpu::CompositeAlphaMode::Auto,
+            view_formats: vec![],
+        }
+    }
+
+    #[test]
+    fn test_frame_latency() {
+        assert_eq!(GpuContextDescriptor::default().desired_maximum_frame_latency, 2);
+        assert_eq!(GpuContextDescriptor::default().sample_count, 1);
+
+        let mut config = test_config();
+        apply_frame_latency(&mut config, 3);
+        assert_eq!(config.desired_maximum_frame_latency, 3);
+
+        apply_frame_latency(&mut config, 0);
+        assert_eq!(config.desired_maximum_frame_latency, 1);
+    }
+
+    #[test]
+    fn test_surface_format() {
+        use wgpu::TextureFormat::{Bgra8Unorm, Bgra8UnormSrgb, Rgba16Float};
+        use SurfaceFormatPolicy::{Explicit, PreferLinear, PreferSrgb};
+
+        let supported = [Bgra8UnormSrgb, Bgra8Unorm];
+        let mut config = test_config();
+
+        let result = apply_surface_format(&mut config, &supported, Explicit(Rgba16Float));
+        assert!(matches!(result, Err(Error::ValidationError(_))));
+        assert_eq!(config.format, Bgra8UnormSrgb);
+
+        // a linear view for shaders that encode themselves
+        assert!(apply_surface_format(&mut config, &supported, Explicit(Bgra8Unorm)).unwrap());
+        assert_eq!(config.format, Bgra8Unorm);
+        assert_eq!(config.view_formats, vec![Bgra8Unorm]);
+
+        // no change, nothing to rebuild
+        assert!(!apply_surface_format(&mut config, &supported, PreferLinear).unwrap());
+
+        // only the view changes
+        assert!(apply_surface_format(&mut config, &[Bgra8Unorm], PreferSrgb).unwrap());
+        assert_eq!((config.format, config.view_formats[0]), (Bgra8Unorm, Bgra8UnormSrgb));
+
+        // the hdr surface keeps its float view
+        assert!(apply_surface_format(&mut config, &[Bgra8Unorm, Rgba16Float], Explicit(Rgba16Float)).unwrap());
+        assert_eq!(config.view_formats, vec![Rgba16Float]);
+    }
+
+    #[test]
+    fn test_select_surface_format() {
+        use wgpu::TextureFormat::{Bgra8Unorm, Bgra8UnormSrgb, Rgba16Float, Rgba8Unorm};
+        use SurfaceFormatPolicy::{Explicit, PreferLinear, PreferSrgb};
+
+        let both = [Bgra8Unorm, Bgra8UnormSrgb];
+        assert_eq!(select_surface_format(PreferSrgb, &both), (Bgra8UnormSrgb, Bgra8UnormSrgb));
+        assert_eq!(select_surface_format(PreferLinear, &both), (Bgra8Unorm, Bgra8Unorm));
+
+        // a missing variant is provided by the view
+        assert_eq!(select_surface_format(PreferSrgb, &[Bgra8Unorm]), (Bgra8Unorm, Bgra8UnormSrgb));
+        assert_eq!(select_surface_format(PreferLinear, &[Bgra8UnormSrgb]), (Bgra8UnormSrgb, Bgra8Unorm));
+        let explicit = Explicit(Bgra8UnormSrgb);
+        assert_eq!(select_surface_format(explicit, &[Bgra8Unorm]), (Bgra8Unorm, Bgra8UnormSrgb));
+
+        // formats without an srgb variant
+        assert_eq!(select_surface_format(PreferSrgb, &[Rgba16Float]), (Rgba16Float, Rgba16Float));
+        let hdr = Explicit(Rgba16Float);
+        assert_eq!(select_surface_format(hdr, &[Bgra8Unorm, Rgba16Float]), (Rgba16Float, Rgba16Float));
+        assert_eq!(select_surface_format(hdr, &[Rgba8Unorm]), (Rgba8Unorm, Rgba8Unorm));
+
+        assert_eq!(GpuContextDescriptor::default().surface_format_policy, PreferSrgb);
+    }
+
+    #[test]
+    fn test_surface_clear_color() {
+        let color = wgpu::Color {
+            r: 0.0,
+            g: 0.2,
+            b: 1.0,
+            a: 0.5,
+        };
+        assert_eq!(get_surface_clear_color(color, false), color);
+
+        let encoded = get_surface_clear_color(color, true);
+        assert_eq!((encoded.r, encoded.b, encoded.a), (0.0, 1.0, 0.5));
+        assert!((encoded.g - 0.4845).abs() < 1e-3, "{}", encoded.g);
+
+        // hdr surfaces take the linear color
+        let encode = get_output_encodes_srgb(wgpu::TextureFormat::Rgba16Float);
+        assert_eq!(get_surface_clear_color(color, encode), color);
+        assert!((linear_to_srgb(0.001) - 0.01292).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_surface_usage() {
+        let mut config = test_config();
+
+        let result = apply_surface_usage(&mut config, wgpu::TextureUsages::RENDER_ATTACHMENT, wgpu::TextureUsages::COPY_SRC);
+        assert!(matches!(result, Err(Error::UnsupportedError(_))));
+
+        let supported = wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC;
+        assert!(apply_surface_usage(&mut config, supported, wgpu::TextureUsages::COPY_SRC).unwrap());
+        assert_eq!(config.usage, supported);
+        assert!(!apply_surface_usage(&mut config, supported, wgpu::TextureUsages::COPY_SRC).unwrap());
+    }
+
+    #[test]
+    fn test_headless_config() {
+        let config = get_headless_config(320, 0);
+        assert_eq!((config.width, config.height), (320, 1));
+        assert_eq!(config.format, HEADLESS_FORMAT);
+        // pipelines built for the surface target view_formats[0]
+        assert_eq!(config.view_formats, vec![HEADLESS_FORMAT]);
+        assert!(config.usage.contains(wgpu::TextureUsages::COPY_SRC));
+
+        // offscreen frames can be copied out without reconfiguring
+        let mut config = config;
+        assert!(!apply_surface_usage(&mut config, HEADLESS_USAGE, wgpu::TextureUsages::COPY_SRC).unwrap());
+    }
+
+    #[test]
+    fn test_needs_reconfigure() {
+        assert!(needs_reconfigure(&wgpu::SurfaceError::Lost));
+        assert!(needs_reconfigure(&wgpu::SurfaceError::Outdated));
+        assert!(!needs_reconfigure(&wgpu::SurfaceError::Timeout));
+        assert!(!needs_reconfigure(&wgpu::SurfaceError::OutOfMemory));
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "gpu_tests"), ignore = "needs a gpu adapter")]
+    fn test_acquire_frame_or_skip() {
+        let context = pollster::block_on(GpuContext::new_headless(4, 2)).unwrap();
+
+        // headless frames are never skipped
+        let frame = context.acquire_frame_or_skip().unwrap();
+        assert!(matches!(frame, Frame::Offscreen(_)));
+        assert_eq!((frame.texture().width(), frame.texture().height()), (4, 2));
+    }
+
+    // Only the formatting, with a made up adapter. test_context_report_string reads a real one.
+    #[test]
+    fn test_report_string() {
+        let info = wgpu::AdapterInfo {
+            name: "Test Gpu".to_string(),
+            vendor: 0x10de,
+            device: 0x2204,
+            device_type: wgpu::DeviceType::DiscreteGpu,
+            driver: "test driver".to_string(),
+            driver_info: "1.2.3".to_string(),
+            backend: wgpu::Backend::Vulkan,
+        };
+
+        let report = format_report(&info, wgpu::Features::ADDRESS_MODE_CLAMP_TO_BORDER, &wgpu::Limits::default());
+
+        assert!(report.contains("backend: Vulkan"));
+        assert!(report.contains("Test Gpu"));
+        assert!(report.contains("test driver 1.2.3"));
+        assert!(report.contains("ADDRESS_MODE_CLAMP_TO_BORDER"));
+        assert!(report.contains("max_bind_groups 4"));
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "gpu_tests"), ignore = "needs a gpu adapter")]
+    fn test_context_report_string() {
+        let context = pollster::block_on(GpuContext::new_headless(4, 4)).unwrap();
+        let info = context.adapter_info();
+        let report = context.report_string();
+
+        // the adapter's own info and the device's limits, not the adapter's
+        assert!(report.contains(&format!("backend: {:?}\n", info.backend)));
+        assert!(report.contains(&format!("device: {} ({:?})\n", info.name, info.device_type)));
+        assert!(report.contains(&format!("max_bind_groups {} ", context.device.limits().max_bind_groups)));
+        assert!(report.contains(&format!("features: {:?}\n", context.device.features())));
+    }
+}
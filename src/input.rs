@@ -4,9 +4,15 @@ use std::sync::Arc;
 use winit::event::MouseButton;
 use winit::keyboard::{KeyCode, PhysicalKey};
 
+// Scroll wheel deltas are in lines, touchpads report pixels which are converted at this rate
+pub const PIXELS_PER_SCROLL_LINE: f32 = 20.0;
+
+// Keyboard and mouse state accumulated from winit events. The pressed and released sets and the
+// deltas cover the events since the last prepare_for_update, call it once per frame after the update.
 #[derive(Debug, Clone)]
 pub struct Input {
     pub keys_pressed: HashSet<KeyCode>,
+    pub keys_released: HashSet<KeyCode>,
     pub keys_held: HashSet<KeyCode>,
     pub mouse_buttons_pressed: HashSet<MouseButton>,
     pub mouse_buttons_held: HashSet<MouseButton>,
@@ -22,6 +28,7 @@ impl Default for Input {
     fn default() -> Self {
         Self {
             keys_pressed: HashSet::default(),
+            keys_released: HashSet::default(),
             keys_held: HashSet::default(),
             mouse_buttons_pressed: HashSet::default(),
             mouse_buttons_held: HashSet::default(),
@@ -48,14 +55,21 @@ impl Input {
         self.enabled.load(std::sync::atomic::Ordering::Relaxed)
     }
 
+    // Only in the frame the key went down, holding it doesn't press it again. Still true when the
+    // key was released again within the same frame.
     pub fn key_just_pressed(&self, key: KeyCode) -> bool {
         self.keys_pressed.contains(&key)
     }
 
+    // True while the key is held
     pub fn key_pressed(&self, key: KeyCode) -> bool {
         self.keys_held.contains(&key)
     }
 
+    pub fn was_key_released(&self, key: KeyCode) -> bool {
+        self.keys_released.contains(&key)
+    }
+
     pub fn mouse_button_just_pressed(&self, button: MouseButton) -> bool {
         self.mouse_buttons_pressed.contains(&button)
     }
@@ -72,12 +86,14 @@ impl Input {
         self.mouse_delta
     }
 
+    // In lines, positive away from the user
     pub fn mouse_wheel_delta(&self) -> f32 {
         self.mouse_wheel_delta
     }
 
     pub fn prepare_for_update(&mut self) {
         self.keys_pressed.clear();
+        self.keys_released.clear();
         self.mouse_buttons_pressed.clear();
         self.mouse_delta = glam::Vec2::ZERO;
         self.mouse_wheel_delta = 0.0;
@@ -116,29 +132,44 @@ impl Input {
                 }
 
                 if let PhysicalKey::Code(code) = event.physical_key {
-                    match event.state {
-                        winit::event::ElementState::Pressed => {
-                            if !self.keys_held.contains(&code) {
-                                self.keys_pressed.insert(code);
-                            }
-                            self.keys_held.insert(code);
-                        }
-                        winit::event::ElementState::Released => {
-                            self.keys_held.remove(&code);
-                        }
-                    }
+                    self.handle_key(code, event.state);
                 }
             }
             winit::event::WindowEvent::MouseWheel { delta, .. } => match delta {
                 winit::event::MouseScrollDelta::LineDelta(_, y) => {
                     self.mouse_wheel_delta += *y;
                 }
-                winit::event::MouseScrollDelta::PixelDelta(_xy) => {}
+                winit::event::MouseScrollDelta::PixelDelta(position) => {
+                    self.mouse_wheel_delta += position.y as f32 / PIXELS_PER_SCROLL_LINE;
+                }
             },
+            // releases while unfocused aren't delivered, so keys would stay held
+            winit::event::WindowEvent::Focused(false) => self.release_all(),
             _ => {}
         }
     }
 
+    // Os key repeats arrive as presses of a held key, they don't press it again
+    pub fn handle_key(&mut self, code: KeyCode, state: winit::event::ElementState) {
+        match state {
+            winit::event::ElementState::Pressed => {
+                if self.keys_held.insert(code) {
+                    self.keys_pressed.insert(code);
+                }
+            }
+            winit::event::ElementState::Released => {
+                if self.keys_held.remove(&code) {
+                    self.keys_released.insert(code);
+                }
+            }
+        }
+    }
+
+    pub fn release_all(&mut self) {
+        self.keys_released.extend(self.keys_held.drain());
+        self.mouse_buttons_held.clear();
+    }
+
     pub fn handle_device_event(&mut self, event: &winit::event::DeviceEvent) {
         if !self.is_input_enabled() {
             return;
@@ -148,3 +179,77 @@ impl Input {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use winit::event::ElementState::{Pressed, Released};
+    use winit::keyboard::KeyCode;
+
+    use crate::input::Input;
+
+    #[test]
+    fn test_key_edges() {
+        let mut input = Input::default();
+
+        input.handle_key(KeyCode::KeyW, Pressed);
+        assert!(input.key_pressed(KeyCode::KeyW));
+        assert!(input.key_just_pressed(KeyCode::KeyW));
+        input.prepare_for_update();
+
+        // held over the next frames, with os key repeats
+        for _ in 0..3 {
+            input.handle_key(KeyCode::KeyW, Pressed);
+            assert!(input.key_pressed(KeyCode::KeyW));
+            assert!(!input.key_just_pressed(KeyCode::KeyW));
+            input.prepare_for_update();
+        }
+
+        input.handle_key(KeyCode::KeyW, Released);
+        assert!(!input.key_pressed(KeyCode::KeyW));
+        assert!(input.was_key_released(KeyCode::KeyW));
+        input.prepare_for_update();
+        assert!(!input.was_key_released(KeyCode::KeyW));
+
+        // a tap within one frame is still seen as a press
+        input.handle_key(KeyCode::Space, Pressed);
+        input.handle_key(KeyCode::Space, Released);
+        assert!(input.key_just_pressed(KeyCode::Space));
+        assert!(input.was_key_released(KeyCode::Space));
+        assert!(!input.key_pressed(KeyCode::Space));
+
+        // a release without a press, e.g. after focusing the window with the key down
+        input.prepare_for_update();
+        input.handle_key(KeyCode::KeyA, Released);
+        assert!(!input.was_key_released(KeyCode::KeyA));
+    }
+
+    #[test]
+    fn test_release_all() {
+        let mut input = Input::default();
+        input.handle_key(KeyCode::KeyA, Pressed);
+        input.prepare_for_update();
+
+        input.release_all();
+        assert!(!input.key_pressed(KeyCode::KeyA));
+        assert!(input.was_key_released(KeyCode::KeyA));
+
+        // pressing again after focusing is a new press
+        input.prepare_for_update();
+        input.handle_key(KeyCode::KeyA, Pressed);
+        assert!(input.key_just_pressed(KeyCode::KeyA));
+    }
+
+    #[test]
+    fn test_frame_deltas() {
+        let mut input = Input::default();
+        input.handle_device_event(&winit::event::DeviceEvent::MouseMotion { delta: (3.0, -1.0) });
+        input.handle_device_event(&winit::event::DeviceEvent::MouseMotion { delta: (1.0, 0.5) });
+        input.mouse_wheel_delta += 2.0;
+        assert_eq!(input.mouse_delta(), glam::vec2(4.0, -0.5));
+        assert_eq!(input.mouse_wheel_delta(), 2.0);
+
+        input.prepare_for_update();
+        assert_eq!(input.mouse_delta(), glam::Vec2::ZERO);
+        assert_eq!(input.mouse_wheel_delta(), 0.0);
+    }
+}
@@ -0,0 +1,263 @@
+use std::marker::PhantomData;
+use std::mem;
+
+use wgpu::BufferAddress;
+
+use crate::buffers::{check_vertex_attributes, check_vertex_layout, GrowableBuffer};
+use crate::error::Error;
+use crate::error::Error::ValidationError;
+use crate::gpu_context::GpuContext;
+
+// Per instance attributes of T, read by the vertex shader with VertexStepMode::Instance
+#[derive(Debug, Clone)]
+pub struct InstanceLayout {
+    pub array_stride: BufferAddress,
+    pub attributes: Vec<wgpu::VertexAttribute>,
+}
+
+impl InstanceLayout {
+    // From the layout of a #[derive(VertexLayout)] struct with #[vertex(step_mode = Instance)]
+    pub fn from_vertex_buffer_layout<T: bytemuck::Pod>(layout: &wgpu::VertexBufferLayout) -> Result<Self, Error> {
+        if layout.step_mode != wgpu::VertexStepMode::Instance {
+            return Err(ValidationError(format!(
+                "{} layout steps per vertex, add #[vertex(step_mode = Instance)]",
+                std::any::type_name::<T>()
+            )));
+        }
+        check_vertex_layout::<T>(layout)?;
+
+        Ok(InstanceLayout {
+            array_stride: layout.array_stride,
+            attributes: layout.attributes.to_vec(),
+        })
+    }
+
+    pub fn get_vertex_buffer_layout(&self) -> wgpu::VertexBufferLayout<'_> {
+        wgpu::VertexBufferLayout {
+            array_stride: self.array_stride,
+            step_mode: wgpu::VertexStepMode::Instance,
+            attributes: &self.attributes,
+        }
+    }
+}
+
+// Shader locations are assigned in the order attributes are added, starting after the
+// locations used by the vertex buffers.
+pub struct InstanceLayoutBuilder<T: bytemuck::Pod> {
+    next_location: u32,
+    attributes: Vec<wgpu::VertexAttribute>,
+    _instance: PhantomData<T>,
+}
+
+impl<T: bytemuck::Pod> InstanceLayoutBuilder<T> {
+    pub fn new(first_location: u32) -> Self {
+        InstanceLayoutBuilder {
+            next_location: first_location,
+            attributes: vec![],
+            _instance: PhantomData,
+        }
+    }
+
+    // offset is usually mem::offset_of!(T, field)
+    pub fn attribute(mut self, offset: usize, format: wgpu::VertexFormat) -> Self {
+        self.attributes.push(wgpu::VertexAttribute {
+            offset: offset as BufferAddress,
+            shader_location: self.next_location,
+            format,
+        });
+        self.next_location += 1;
+        self
+    }
+
+    // A mat4 takes one vec4 location per column
+    pub fn mat4(mut self, offset: usize) -> Self {
+        for column in 0..4 {
+            self = self.attribute(offset + column * mem::size_of::<[f32; 4]>(), wgpu::VertexFormat::Float32x4);
+        }
+        self
+    }
+
+    pub fn build(self) -> Result<InstanceLayout, Error> {
+        let array_stride = mem::size_of::<T>() as BufferAddress;
+        if array_stride % wgpu::VERTEX_STRIDE_ALIGNMENT != 0 {
+            return Err(ValidationError(format!(
+                "instance size of {} ({} bytes) isn't a multiple of {}",
+                std::any::type_name::<T>(),
+                array_stride,
+                wgpu::VERTEX_STRIDE_ALIGNMENT
+            )));
+        }
+
+        check_vertex_attributes(std::any::type_name::<T>(), array_stride, &self.attributes)?;
+
+        Ok(InstanceLayout {
+            array_stride,
+            attributes: self.attributes,
+        })
+    }
+}
+
+// Vertex buffer of T instances, grown when more instances are written than fit.
+//
+// The instance buffer goes after the mesh's vertex buffers in the pipeline's VertexState::buffers,
+// and set_vertex_buffer takes the index in that list as its slot. With one mesh buffer:
+//
+//   buffers: &[Vertex::vertex_buffer_layout(), instances.layout.get_vertex_buffer_layout()]
+//   pass.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+//   pass.set_vertex_buffer(1, instances.slice());
+//   pass.draw_indexed(0..mesh.index_count, 0, 0..instances.count());
+//
+// The instance attribute locations continue after the mesh's, see InstanceLayoutBuilder::new.
+pub struct InstanceBuffer<T: bytemuck::Pod> {
+    pub buffer: GrowableBuffer<T>,
+    pub layout: InstanceLayout,
+}
+
+impl<T: bytemuck::Pod> InstanceBuffer<T> {
+    pub fn new(context: &GpuContext, layout: InstanceLayout, capacity: usize, label: &str) -> Self {
+        InstanceBuffer {
+            buffer: GrowableBuffer::new(context, capacity, wgpu::BufferUsages::VERTEX, label),
+            layout,
+        }
+    }
+
+    pub fn new_init(context: &GpuContext, layout: InstanceLayout, instances: &[T], label: &str) -> Result<Self, Error> {
+        let mut instance_buffer = InstanceBuffer::new(context, layout, instances.len(), label);
+        instance_buffer.write(context, instances)?;
+        Ok(instance_buffer)
+    }
+
+    // Replaces the instances. Keeps the allocation while they fit, so changing transforms every
+    // frame doesn't allocate.
+    pub fn write(&mut self, context: &GpuContext, instances: &[T]) -> Result<(), Error> {
+        // nothing is kept, so growing has nothing to copy
+        self.buffer.clear();
+        self.buffer.write(context, 0, instances)?;
+        Ok(())
+    }
+
+    // instance range for draw calls
+    pub fn count(&self) -> u32 {
+        self.buffer.len() as u32
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buffer.capacity()
+    }
+
+    // At least one instance long, wgpu has no empty slices
+    pub fn slice(&self) -> wgpu::BufferSlice<'_> {
+        self.buffer
+            .buffer
+            .slice(..self.buffer.len().max(1) as BufferAddress * self.layout.array_stride)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+
+    use crate::buffers::read_buffer;
+    use crate::gpu_context::GpuContext;
+    use crate::instance_buffer::{InstanceBuffer, InstanceLayout, InstanceLayoutBuilder};
+    use crate::VertexLayout;
+
+    #[repr(C)]
+    #[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+    struct SpriteInstance {
+        transform: [[f32; 4]; 4],
+        color: [f32; 4],
+        phase: f32,
+        texture_index: u32,
+        _padding: [u32; 2],
+    }
+
+    #[test]
+    fn test_custom_instance_layout() {
+        let layout = InstanceLayoutBuilder::<SpriteInstance>::new(5)
+            .mat4(mem::offset_of!(SpriteInstance, transform))
+            .attribute(mem::offset_of!(SpriteInstance, color), wgpu::VertexFormat::Float32x4)
+            .attribute(mem::offset_of!(SpriteInstance, phase), wgpu::VertexFormat::Float32)
+            .attribute(mem::offset_of!(SpriteInstance, texture_index), wgpu::VertexFormat::Uint32)
+            .build()
+            .unwrap();
+
+        let vertex_buffer_layout = layout.get_vertex_buffer_layout();
+        assert_eq!(vertex_buffer_layout.step_mode, wgpu::VertexStepMode::Instance);
+        assert_eq!(vertex_buffer_layout.array_stride, 96);
+
+        let attributes: Vec<(u64, u32)> = vertex_buffer_layout
+            .attributes
+            .iter()
+            .map(|attribute| (attribute.offset, attribute.shader_location))
+            .collect();
+        assert_eq!(attributes, vec![(0, 5), (16, 6), (32, 7), (48, 8), (64, 9), (80, 10), (84, 11)]);
+
+        // attributes have to fit inside the instance
+        let result = InstanceLayoutBuilder::<SpriteInstance>::new(0)
+            .attribute(mem::offset_of!(SpriteInstance, texture_index), wgpu::VertexFormat::Float32x4)
+            .build();
+        assert!(result.is_err());
+    }
+
+    #[repr(C)]
+    #[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable, VertexLayout)]
+    #[vertex(step_mode = Instance)]
+    struct TransformInstance {
+        #[vertex(location = 2, format = Float32x4)]
+        translation_scale: [f32; 4],
+        #[vertex(location = 3, format = Float32x4)]
+        rotation: [f32; 4],
+    }
+
+    #[repr(C)]
+    #[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable, VertexLayout)]
+    struct MeshVertex {
+        #[vertex(location = 0, format = Float32x4)]
+        position: [f32; 4],
+    }
+
+    #[test]
+    fn test_derived_instance_layout() {
+        let layout = InstanceLayout::from_vertex_buffer_layout::<TransformInstance>(&TransformInstance::vertex_buffer_layout()).unwrap();
+        assert_eq!(layout.array_stride, 32);
+        assert_eq!(layout.get_vertex_buffer_layout().step_mode, wgpu::VertexStepMode::Instance);
+
+        let locations: Vec<u32> = layout.attributes.iter().map(|attribute| attribute.shader_location).collect();
+        assert_eq!(locations, vec![2, 3]);
+
+        // a per vertex layout would advance the transform with every vertex
+        let result = InstanceLayout::from_vertex_buffer_layout::<MeshVertex>(&MeshVertex::vertex_buffer_layout());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "gpu_tests"), ignore = "needs a gpu adapter")]
+    fn test_instance_buffer_write() {
+        let context = pollster::block_on(GpuContext::new_headless(4, 4)).unwrap();
+        let layout = InstanceLayout::from_vertex_buffer_layout::<TransformInstance>(&TransformInstance::vertex_buffer_layout()).unwrap();
+        let instance = |x: f32| TransformInstance {
+            translation_scale: [x, 0.0, 0.0, 1.0],
+            rotation: [0.0, 0.0, 0.0, 1.0],
+        };
+
+        let mut instances = InstanceBuffer::new_init(&context, layout, &[instance(1.0), instance(2.0)], "instances").unwrap();
+        assert_eq!((instances.count(), instances.capacity()), (2, 2));
+
+        // writes replace the instances, growing past the capacity
+        let more: Vec<TransformInstance> = (0..5).map(|i| instance(i as f32)).collect();
+        instances.write(&context, &more).unwrap();
+        assert_eq!((instances.count(), instances.capacity()), (5, 8));
+
+        // and keep the allocation when fewer fit
+        instances.write(&context, &[instance(7.0)]).unwrap();
+        assert_eq!((instances.count(), instances.capacity()), (1, 8));
+        let data = read_buffer(&context, &instances.buffer.buffer);
+        let first: TransformInstance = bytemuck::pod_read_unaligned(&data[..mem::size_of::<TransformInstance>()]);
+        assert_eq!(first.translation_scale, [7.0, 0.0, 0.0, 1.0]);
+    }
+}
@@ -6,27 +6,78 @@ use std::mem;
 use std::os::raw;
 
 pub mod animator;
+pub mod app;
+pub mod bind_group;
 pub mod buffers;
 pub mod camera;
+pub mod capabilities;
+pub mod compute;
+pub mod culling;
+pub mod default_textures;
+pub mod depth_prepass;
+pub mod depth_reduction;
 pub mod error;
 pub mod frame_counter;
+pub mod frame_history;
+pub mod frame_stats;
+pub mod gbuffer;
+#[cfg(feature = "gltf")]
+pub mod gltf_model;
 pub mod gpu_context;
 pub mod hash_any;
 pub mod hash_map;
 pub mod input;
+pub mod instance_buffer;
+pub mod light_grid;
+pub mod line_renderer;
 pub mod material;
 pub mod math;
+pub mod meshlet;
 pub mod model;
 pub mod model_animation;
 pub mod model_builder;
 pub mod model_mesh;
+pub mod msaa;
 pub mod node_animation;
+pub mod pipeline_builder;
+pub mod pipeline_cache;
+pub mod point_shadow;
+pub mod post;
+pub mod prefix_sum;
+#[cfg(feature = "profiling")]
+pub mod profiler;
+pub mod render;
+pub mod resize_registry;
+#[cfg(feature = "serde")]
+pub mod scene_file;
+pub mod shader;
+pub mod shader_bindings;
+pub mod shader_preprocessor;
+pub mod shader_test;
+pub mod shadow_atlas;
+pub mod shadow_cascades;
+pub mod shadow_pipeline;
+pub mod shadow_projection;
+pub mod skybox;
 pub mod small_mesh;
+pub mod snapshot;
+pub mod tangent_generation;
+#[cfg(feature = "text")]
+pub mod text;
 pub mod texture;
 pub mod texture_config;
 pub mod transform;
+#[cfg(feature = "ui")]
+pub mod ui;
 pub mod utils;
 
+pub use spark_gap_derive::VertexLayout;
+// The wgpu the derive macros expand to, so users don't need a matching wgpu dependency
+pub use wgpu;
+
+// Lets the derives' ::spark_gap paths resolve inside this crate too
+extern crate self as spark_gap;
+
 pub const SIZE_OF_FLOAT: usize = mem::size_of::<f32>();
 pub const SIZE_OF_VEC2: usize = mem::size_of::<Vec2>();
 pub const SIZE_OF_VEC3: usize = mem::size_of::<Vec3>();
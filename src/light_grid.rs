@@ -0,0 +1,277 @@
+use glam::Vec3;
+use wgpu::BindGroupLayout;
+
+use crate::bind_group::{BindGroupBuilder, LayoutBuilder};
+use crate::buffers::{read_buffer, StorageBuffer, UniformBuffer};
+use crate::compute::{get_workgroup_count, ComputePipeline, ComputePipelineBuilder};
+use crate::error::Error;
+use crate::error::Error::ValidationError;
+use crate::gpu_context::GpuContext;
+
+pub const LIGHT_GRID_WGSL: &str = include_str!("shaders/light_grid.wgsl");
+
+// must match light_grid.wgsl
+pub const LIGHT_GRID_WORKGROUP_SIZE: u32 = 64;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct ClusterBounds {
+    // view space, w unused
+    pub min: [f32; 4],
+    pub max: [f32; 4],
+}
+
+impl ClusterBounds {
+    pub fn new(min: Vec3, max: Vec3) -> Self {
+        ClusterBounds {
+            min: min.extend(0.0).to_array(),
+            max: max.extend(0.0).to_array(),
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct LightGridUniform {
+    pub cluster_count: u32,
+    pub light_count: u32,
+    pub max_lights_per_cluster: u32,
+    pub _padding: u32,
+}
+
+// Clustered light assignment on the gpu. Each cluster gets a fixed range of the index list, the
+// grid holds one (offset, count) pair of u32 per cluster into it. Lights are a storage buffer
+// of view space (position, radius) vec4s.
+pub struct LightGrid {
+    pub cluster_count: u32,
+    pub max_lights_per_cluster: u32,
+    pub clusters: StorageBuffer<ClusterBounds>,
+    pub grid: StorageBuffer<[u32; 2]>,
+    pub light_indices: StorageBuffer<u32>,
+    uniform: UniformBuffer<LightGridUniform>,
+    bind_group_layout: BindGroupLayout,
+    pipeline: ComputePipeline,
+}
+
+impl LightGrid {
+    pub fn new(context: &GpuContext, clusters: &[ClusterBounds], max_lights_per_cluster: u32) -> Result<Self, Error> {
+        context.capabilities.require_compute("light grid")?;
+        context.capabilities.require_storage_buffers("light grid")?;
+        if clusters.is_empty() || max_lights_per_cluster == 0 {
+            return Err(ValidationError(format!(
+                "light grid of {} clusters with {} lights each is empty",
+                clusters.len(),
+                max_lights_per_cluster
+            )));
+        }
+
+        let cluster_count = clusters.len() as u32;
+        let bind_group_layout = LayoutBuilder::new()
+            .storage_buffer(wgpu::ShaderStages::COMPUTE, true)
+            .storage_buffer(wgpu::ShaderStages::COMPUTE, true)
+            .storage_buffer(wgpu::ShaderStages::COMPUTE, false)
+            .storage_buffer(wgpu::ShaderStages::COMPUTE, false)
+            .uniform(wgpu::ShaderStages::COMPUTE)
+            .build(context, "light grid bind group layout")?;
+
+        let shader = context.device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("light grid shader"),
+            source: wgpu::ShaderSource::Wgsl(LIGHT_GRID_WGSL.into()),
+        });
+        let pipeline = ComputePipelineBuilder::new("assign_lights")
+            .label("light grid")
+            .bind_group_layout(&bind_group_layout)
+            .build(context, &shader)?;
+
+        let uniform = LightGridUniform {
+            cluster_count,
+            light_count: 0,
+            max_lights_per_cluster,
+            _padding: 0,
+        };
+
+        Ok(LightGrid {
+            cluster_count,
+            max_lights_per_cluster,
+            clusters: StorageBuffer::new_init(context, clusters, wgpu::BufferUsages::empty(), "light grid clusters")?,
+            grid: StorageBuffer::new(context, clusters.len(), wgpu::BufferUsages::empty(), "light grid")?,
+            light_indices: StorageBuffer::new(
+                context,
+                clusters.len() * max_lights_per_cluster as usize,
+                wgpu::BufferUsages::empty(),
+                "light grid indices",
+            )?,
+            uniform: UniformBuffer::new(context, &uniform, wgpu::BufferUsages::empty()),
+            bind_group_layout,
+            pipeline,
+        })
+    }
+
+    // Records the assignment of the first light_count lights. The uniform is written through the
+    // queue so only one assignment per submit is supported.
+    pub fn assign(&self, context: &GpuContext, encoder: &mut wgpu::CommandEncoder, lights: &wgpu::Buffer, light_count: u32) {
+        self.uniform.update(
+            context,
+            &LightGridUniform {
+                cluster_count: self.cluster_count,
+                light_count,
+                max_lights_per_cluster: self.max_lights_per_cluster,
+                _padding: 0,
+            },
+        );
+
+        let bind_group = BindGroupBuilder::new()
+            .resource(self.clusters.as_entire_binding())
+            .buffer(lights)
+            .resource(self.grid.as_entire_binding())
+            .resource(self.light_indices.as_entire_binding())
+            .resource(self.uniform.as_entire_binding())
+            .build(context, &self.bind_group_layout, "light grid bind group");
+
+        let workgroups = get_workgroup_count(self.cluster_count, LIGHT_GRID_WORKGROUP_SIZE);
+        self.pipeline.record(encoder, &[&bind_group], [workgroups, 1, 1]);
+    }
+
+    // Stalls until the submitted assignment is done
+    pub fn read_stats(&self, context: &GpuContext) -> LightGridStats {
+        read_light_grid_stats(context, &self.grid.buffer)
+    }
+}
+
+// Debug inspection of the light grid, from its raw (offset, count) pairs
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LightGridStats {
+    pub cluster_count: usize,
+    pub min_lights: u32,
+    pub max_lights: u32,
+    pub average_lights: f32,
+    pub empty_clusters: usize,
+    // the cluster with the most lights, the first to look at for pathological cases
+    pub max_cluster: usize,
+}
+
+// Per cluster light counts from the raw light grid contents
+pub fn get_light_grid_counts(grid: &[u32]) -> Vec<u32> {
+    grid.chunks_exact(2).map(|cluster| cluster[1]).collect()
+}
+
+pub fn get_light_grid_stats(counts: &[u32]) -> LightGridStats {
+    let cluster_count = counts.len();
+    let total: u64 = counts.iter().map(|count| *count as u64).sum();
+
+    let (max_cluster, max_lights) = counts
+        .iter()
+        .copied()
+        .enumerate()
+        .fold((0, 0), |max, (i, count)| if count > max.1 { (i, count) } else { max });
+
+    LightGridStats {
+        cluster_count,
+        min_lights: counts.iter().copied().min().unwrap_or(0),
+        max_lights,
+        average_lights: total as f32 / cluster_count.max(1) as f32,
+        empty_clusters: counts.iter().filter(|count| **count == 0).count(),
+        max_cluster,
+    }
+}
+
+// Maps the light grid buffer, which needs COPY_SRC usage, and summarizes the assignment
+pub fn read_light_grid_stats(context: &GpuContext, light_grid_buffer: &wgpu::Buffer) -> LightGridStats {
+    let data = read_buffer(context, light_grid_buffer);
+    let grid: &[u32] = bytemuck::cast_slice(&data);
+    get_light_grid_stats(&get_light_grid_counts(grid))
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{vec3, Vec3};
+    use wgpu::util::DeviceExt;
+
+    use crate::buffers::STORAGE_BUFFER_USAGE;
+    use crate::gpu_context::GpuContext;
+    use crate::light_grid::{get_light_grid_counts, get_light_grid_stats, ClusterBounds, LightGrid};
+
+    // 4x4x1 grid of unit clusters
+    fn get_clusters() -> Vec<(Vec3, Vec3)> {
+        (0..16)
+            .map(|i| {
+                let min = vec3((i % 4) as f32, (i / 4) as f32, 0.0);
+                (min, min + Vec3::ONE)
+            })
+            .collect()
+    }
+
+    // The expected counts, by the same sphere-box overlap as light_grid.wgsl
+    fn get_expected_counts(clusters: &[(Vec3, Vec3)], lights: &[[f32; 4]]) -> Vec<u32> {
+        clusters
+            .iter()
+            .map(|(min, max)| {
+                lights
+                    .iter()
+                    .filter(|light| {
+                        let position = vec3(light[0], light[1], light[2]);
+                        position.clamp(*min, *max).distance_squared(position) <= light[3] * light[3]
+                    })
+                    .count() as u32
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_light_grid_stats() {
+        let stats = get_light_grid_stats(&[0, 1, 3, 0]);
+        assert_eq!(stats.cluster_count, 4);
+        assert_eq!(stats.min_lights, 0);
+        assert_eq!(stats.max_lights, 3);
+        assert_eq!(stats.empty_clusters, 2);
+        assert_eq!(stats.max_cluster, 2);
+        assert_eq!(stats.average_lights, 1.0);
+    }
+
+    #[test]
+    fn test_light_grid_counts() {
+        let grid = [0, 2, 2, 0, 2, 1];
+        assert_eq!(get_light_grid_counts(&grid), vec![2, 0, 1]);
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "gpu_tests"), ignore = "needs a gpu adapter")]
+    fn test_light_assignment() {
+        let context = pollster::block_on(GpuContext::new_headless(4, 4)).unwrap();
+        let clusters = get_clusters();
+        let bounds: Vec<ClusterBounds> = clusters.iter().map(|(min, max)| ClusterBounds::new(*min, *max)).collect();
+        let light_grid = LightGrid::new(&context, &bounds, 8).unwrap();
+
+        let assign = |lights: &[[f32; 4]]| {
+            let light_buffer = context.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("lights"),
+                contents: bytemuck::cast_slice(lights),
+                usage: STORAGE_BUFFER_USAGE,
+            });
+            let mut encoder = context.device.create_command_encoder(&Default::default());
+            light_grid.assign(&context, &mut encoder, &light_buffer, lights.len() as u32);
+            context.queue.submit(std::iter::once(encoder.finish()));
+            light_grid
+                .grid
+                .read_back(&context)
+                .iter()
+                .map(|cluster| cluster[1])
+                .collect::<Vec<u32>>()
+        };
+
+        // a light at the corner shared by the four center clusters
+        let counts = assign(&[[2.0, 2.0, 0.5, 0.5]]);
+        let assigned: Vec<usize> = (0..16).filter(|i| counts[*i] == 1).collect();
+        assert_eq!(assigned, vec![5, 6, 9, 10]);
+        assert_eq!(light_grid.read_stats(&context).max_cluster, 5);
+
+        let lights = [
+            [0.5, 0.5, 0.5, 0.3],
+            [3.0, 1.0, 0.0, 1.2],
+            [1.5, 3.5, 2.0, 1.6],
+            [2.0, 2.0, 0.5, 4.0],
+        ];
+        assert_eq!(assign(&lights), get_expected_counts(&clusters, &lights));
+    }
+}
@@ -0,0 +1,492 @@
+use std::borrow::Cow;
+use std::mem;
+
+use glam::{vec2, Mat4, Vec2, Vec3};
+use wgpu::util::DeviceExt;
+use wgpu::{BindGroup, BindGroupLayout, Buffer, RenderPass, RenderPipeline};
+
+use crate::buffers::{create_buffer_bind_group, create_uniform_bind_group_layout, create_uniform_buffer_init, update_uniform_buffer};
+use crate::gpu_context::{get_or_create_bind_group_layout, GpuContext};
+
+pub const LINE_BIND_GROUP_LAYOUT: &str = "line bind group layout";
+
+// joints whose miter is longer than this many half widths are beveled instead
+pub const DEFAULT_MITER_LIMIT: f32 = 4.0;
+
+// the quad's two triangles and the bevel triangle of vs_thick
+const SEGMENT_VERTEX_COUNT: u32 = 9;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LineMode {
+    // hardware LineList, always one pixel wide
+    Hairline,
+    // segments expanded to screen space quads of the given width in pixels
+    Thick { width: f32 },
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct LineVertex {
+    pub position: [f32; 3],
+    pub color: [f32; 4],
+}
+
+// One instance per segment. The neighbors are used to compute the miter joints,
+// a neighbor equal to its endpoint means the line ends there.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct LineSegment {
+    pub prev: [f32; 3],
+    pub start: [f32; 3],
+    pub end: [f32; 3],
+    pub next: [f32; 3],
+    pub color: [f32; 4],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct LineUniform {
+    pub projection_view: [[f32; 4]; 4],
+    pub viewport: [f32; 2],
+    pub width: f32,
+    pub miter_limit: f32,
+}
+
+pub struct LineRenderer {
+    pub mode: LineMode,
+    pub miter_limit: f32,
+    hairline_pipeline: RenderPipeline,
+    thick_pipeline: RenderPipeline,
+    uniform_buffer: Buffer,
+    bind_group: BindGroup,
+    vertices: Vec<LineVertex>,
+    segments: Vec<LineSegment>,
+    vertex_buffer: Option<Buffer>,
+    segment_buffer: Option<Buffer>,
+}
+
+impl LineRenderer {
+    pub fn new(context: &mut GpuContext, mode: LineMode, depth_format: Option<wgpu::TextureFormat>) -> Self {
+        let uniform = LineUniform {
+            projection_view: Mat4::IDENTITY.to_cols_array_2d(),
+            viewport: [context.config.width as f32, context.config.height as f32],
+            width: 1.0,
+            miter_limit: DEFAULT_MITER_LIMIT,
+        };
+
+        let uniform_buffer = create_uniform_buffer_init(context, &[uniform], "line uniform");
+
+        let bind_group_layout = get_or_create_bind_group_layout(context, LINE_BIND_GROUP_LAYOUT, create_uniform_bind_group_layout);
+        let bind_group = create_buffer_bind_group(context, &bind_group_layout, &uniform_buffer, "line bind group");
+
+        let shader = context.device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("line shader"),
+            source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(include_str!("shaders/line_shader.wgsl"))),
+        });
+
+        let hairline_pipeline = create_line_pipeline(
+            context,
+            &bind_group_layout,
+            &shader,
+            "vs_hairline",
+            line_vertex_description(),
+            wgpu::PrimitiveTopology::LineList,
+            depth_format,
+        );
+
+        let thick_pipeline = create_line_pipeline(
+            context,
+            &bind_group_layout,
+            &shader,
+            "vs_thick",
+            line_segment_description(),
+            wgpu::PrimitiveTopology::TriangleList,
+            depth_format,
+        );
+
+        LineRenderer {
+            mode,
+            miter_limit: DEFAULT_MITER_LIMIT,
+            hairline_pipeline,
+            thick_pipeline,
+            uniform_buffer,
+            bind_group,
+            vertices: vec![],
+            segments: vec![],
+            vertex_buffer: None,
+            segment_buffer: None,
+        }
+    }
+
+    pub fn add_line(&mut self, start: Vec3, end: Vec3, color: wgpu::Color) {
+        self.add_polyline(&[start, end], color, false);
+    }
+
+    pub fn add_polyline(&mut self, points: &[Vec3], color: wgpu::Color, closed: bool) {
+        let color = [color.r as f32, color.g as f32, color.b as f32, color.a as f32];
+
+        for segment in build_polyline_segments(points, closed) {
+            self.vertices.push(LineVertex {
+                position: segment.start,
+                color,
+            });
+            self.vertices.push(LineVertex {
+                position: segment.end,
+                color,
+            });
+            self.segments.push(LineSegment { color, ..segment });
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.vertices.clear();
+        self.segments.clear();
+    }
+
+    // uploads the queued lines, call before the render pass begins
+    pub fn prepare(&mut self, context: &GpuContext, projection_view: &Mat4) {
+        let width = match self.mode {
+            LineMode::Hairline => 1.0,
+            LineMode::Thick { width } => width,
+        };
+
+        let uniform = LineUniform {
+            projection_view: projection_view.to_cols_array_2d(),
+            viewport: [context.config.width as f32, context.config.height as f32],
+            width,
+            miter_limit: self.miter_limit,
+        };
+
+        update_uniform_buffer(context, &self.uniform_buffer, &[uniform]);
+
+        self.vertex_buffer = create_line_buffer(context, &self.vertices, "line vertices");
+        self.segment_buffer = create_line_buffer(context, &self.segments, "line segments");
+    }
+
+    pub fn render<'a>(&'a self, mut render_pass: RenderPass<'a>) -> RenderPass<'a> {
+        match self.mode {
+            LineMode::Hairline => {
+                if let Some(vertex_buffer) = &self.vertex_buffer {
+                    render_pass.set_pipeline(&self.hairline_pipeline);
+                    render_pass.set_bind_group(0, &self.bind_group, &[]);
+                    render_pass.set_vertex_buffer(0, vertex_buffer.slice(..));
+                    render_pass.draw(0..self.vertices.len() as u32, 0..1);
+                }
+            }
+            LineMode::Thick { .. } => {
+                if let Some(segment_buffer) = &self.segment_buffer {
+                    render_pass.set_pipeline(&self.thick_pipeline);
+                    render_pass.set_bind_group(0, &self.bind_group, &[]);
+                    render_pass.set_vertex_buffer(0, segment_buffer.slice(..));
+                    render_pass.draw(0..SEGMENT_VERTEX_COUNT, 0..self.segments.len() as u32);
+                }
+            }
+        }
+        render_pass
+    }
+}
+
+pub fn build_polyline_segments(points: &[Vec3], closed: bool) -> Vec<LineSegment> {
+    let count = points.len();
+    if count < 2 {
+        return vec![];
+    }
+
+    let num_segments = if closed { count } else { count - 1 };
+
+    (0..num_segments)
+        .map(|i| {
+            let start = points[i];
+            let end = points[(i + 1) % count];
+
+            let prev = if i > 0 {
+                points[i - 1]
+            } else if closed {
+                points[count - 1]
+            } else {
+                start
+            };
+
+            let next = if i + 2 < count {
+                points[i + 2]
+            } else if closed {
+                points[(i + 2) % count]
+            } else {
+                end
+            };
+
+            LineSegment {
+                prev: prev.to_array(),
+                start: start.to_array(),
+                end: end.to_array(),
+                next: next.to_array(),
+                color: [1.0; 4],
+            }
+        })
+        .collect()
+}
+
+// The rest mirrors vs_thick in line_shader.wgsl, in screen space pixels
+
+// The corners of a segment with unjoined ends, in the order of corners in vs_thick
+pub fn expand_segment(start: Vec2, end: Vec2, width: f32) -> [Vec2; 4] {
+    let offset = get_normal(end - start) * width * 0.5;
+    [start - offset, start + offset, end - offset, end + offset]
+}
+
+// The offset direction at a joint, scaled so the line keeps its width along both segments. None
+// past the miter limit, the segments end unjoined there and get_bevel fills the outer corner.
+pub fn get_miter(normal: Vec2, neighbor_normal: Vec2, miter_limit: f32) -> Option<Vec2> {
+    let bisector = normal + neighbor_normal;
+    let cosine = bisector.dot(normal) / bisector.length().max(0.0001);
+    let miter_length = 1.0 / cosine.max(0.0001);
+    (miter_length <= miter_limit).then(|| bisector.normalize() * miter_length)
+}
+
+// The triangle between the unjoined ends of two segments meeting at joint, on the outer side of the turn
+pub fn get_bevel(joint: Vec2, direction: Vec2, next_direction: Vec2, width: f32) -> [Vec2; 3] {
+    let normal = get_normal(direction);
+    let next_normal = get_normal(next_direction);
+    let side = if next_normal.dot(direction) > 0.0 { 1.0 } else { -1.0 };
+    let half_width = width * 0.5 * side;
+    [joint, joint + normal * half_width, joint + next_normal * half_width]
+}
+
+fn get_normal(direction: Vec2) -> Vec2 {
+    let direction = direction.normalize_or_zero();
+    vec2(-direction.y, direction.x)
+}
+
+fn create_line_buffer<T: bytemuck::Pod>(context: &GpuContext, data: &[T], label: &str) -> Option<Buffer> {
+    if data.is_empty() {
+        return None;
+    }
+    Some(context.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some(label),
+        contents: bytemuck::cast_slice(data),
+        usage: wgpu::BufferUsages::VERTEX,
+    }))
+}
+
+fn line_vertex_description() -> wgpu::VertexBufferLayout<'static> {
+    wgpu::VertexBufferLayout {
+        array_stride: mem::size_of::<LineVertex>() as wgpu::BufferAddress,
+        step_mode: wgpu::VertexStepMode::Vertex,
+        attributes: &[
+            // position
+            wgpu::VertexAttribute {
+                offset: 0,
+                shader_location: 0,
+                format: wgpu::VertexFormat::Float32x3,
+            },
+            // color
+            wgpu::VertexAttribute {
+                offset: mem::offset_of!(LineVertex, color) as wgpu::BufferAddress,
+                shader_location: 1,
+                format: wgpu::VertexFormat::Float32x4,
+            },
+        ],
+    }
+}
+
+fn line_segment_description() -> wgpu::VertexBufferLayout<'static> {
+    wgpu::VertexBufferLayout {
+        array_stride: mem::size_of::<LineSegment>() as wgpu::BufferAddress,
+        step_mode: wgpu::VertexStepMode::Instance,
+        attributes: &[
+            // prev
+            wgpu::VertexAttribute {
+                offset: 0,
+                shader_location: 0,
+                format: wgpu::VertexFormat::Float32x3,
+            },
+            // start
+            wgpu::VertexAttribute {
+                offset: mem::offset_of!(LineSegment, start) as wgpu::BufferAddress,
+                shader_location: 1,
+                format: wgpu::VertexFormat::Float32x3,
+            },
+            // end
+            wgpu::VertexAttribute {
+                offset: mem::offset_of!(LineSegment, end) as wgpu::BufferAddress,
+                shader_location: 2,
+                format: wgpu::VertexFormat::Float32x3,
+            },
+            // next
+            wgpu::VertexAttribute {
+                offset: mem::offset_of!(LineSegment, next) as wgpu::BufferAddress,
+                shader_location: 3,
+                format: wgpu::VertexFormat::Float32x3,
+            },
+            // color
+            wgpu::VertexAttribute {
+                offset: mem::offset_of!(LineSegment, color) as wgpu::BufferAddress,
+                shader_location: 4,
+                format: wgpu::VertexFormat::Float32x4,
+            },
+        ],
+    }
+}
+
+fn create_line_pipeline(
+    context: &GpuContext,
+    bind_group_layout: &BindGroupLayout,
+    shader: &wgpu::ShaderModule,
+    vertex_entry_point: &str,
+    vertex_layout: wgpu::VertexBufferLayout,
+    topology: wgpu::PrimitiveTopology,
+    depth_format: Option<wgpu::TextureFormat>,
+) -> RenderPipeline {
+    let pipeline_layout = context.device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some("line pipeline layout"),
+        bind_group_layouts: &[bind_group_layout],
+        push_constant_ranges: &[],
+    });
+
+    context.device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("line pipeline"),
+        layout: Some(&pipeline_layout),
+        vertex: wgpu::VertexState {
+            module: shader,
+            entry_point: vertex_entry_point,
+            buffers: &[vertex_layout],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: shader,
+            entry_point: "fs_main",
+            targets: &[Some(wgpu::ColorTargetState {
+                format: context.config.view_formats[0],
+                blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+        }),
+        primitive: wgpu::PrimitiveState {
+            topology,
+            cull_mode: None,
+            ..Default::default()
+        },
+        depth_stencil: depth_format.map(|format| wgpu::DepthStencilState {
+            format,
+            depth_write_enabled: false,
+            depth_compare: wgpu::CompareFunction::LessEqual,
+            stencil: wgpu::StencilState::default(),
+            bias: wgpu::DepthBiasState::default(),
+        }),
+        // drawn into the main pass
+        multisample: context.multisample_state(),
+        multiview: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{vec2, vec3, Mat4};
+
+    use crate::gpu_context::GpuContext;
+    use crate::line_renderer::{
+        build_polyline_segments, expand_segment, get_bevel, get_miter, LineMode, LineRenderer, DEFAULT_MITER_LIMIT,
+    };
+    use crate::render::RenderPassBuilder;
+    use crate::snapshot::read_texture_rgba;
+
+    const SIZE: u32 = 32;
+
+    // Draws the queued lines with an identity projection, so positions are in ndc
+    fn render_lines(context: &GpuContext, lines: &mut LineRenderer) -> Vec<[u8; 4]> {
+        lines.prepare(context, &Mat4::IDENTITY);
+
+        let frame = context.acquire_frame().unwrap();
+        let view = frame.texture().create_view(&wgpu::TextureViewDescriptor {
+            format: Some(context.surface_view_format()),
+            ..Default::default()
+        });
+        let mut encoder = context.device.create_command_encoder(&Default::default());
+        {
+            let pass = RenderPassBuilder::new().color(&view, Some(wgpu::Color::BLACK)).begin(&mut encoder);
+            lines.render(pass);
+        }
+        context.queue.submit(std::iter::once(encoder.finish()));
+
+        let pixels = read_texture_rgba(context, frame.texture(), SIZE, SIZE).unwrap();
+        bytemuck::cast_slice(&pixels).to_vec()
+    }
+
+    fn is_covered(pixels: &[[u8; 4]], x: u32, y: u32) -> bool {
+        pixels[(y * SIZE + x) as usize][0] > 128
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "gpu_tests"), ignore = "needs a gpu adapter")]
+    fn test_thick_lines() {
+        let mut context = pollster::block_on(GpuContext::new_headless(SIZE, SIZE)).unwrap();
+        let mut lines = LineRenderer::new(&mut context, LineMode::Thick { width: 6.0 }, None);
+
+        // horizontal through the pixel row edge at y 16, vertical through the column edge at x 16
+        lines.add_line(vec3(-0.75, 0.0, 0.0), vec3(-0.25, 0.0, 0.0), wgpu::Color::WHITE);
+        lines.add_line(vec3(0.5, -0.75, 0.0), vec3(0.5, -0.25, 0.0), wgpu::Color::WHITE);
+        let pixels = render_lines(&context, &mut lines);
+
+        // 6 pixels across, centered on the segment
+        let rows: Vec<u32> = (0..SIZE).filter(|y| is_covered(&pixels, 8, *y)).collect();
+        assert_eq!(rows, (13..19).collect::<Vec<u32>>());
+        let columns: Vec<u32> = (0..SIZE).filter(|x| is_covered(&pixels, *x, 24)).collect();
+        assert_eq!(columns, (21..27).collect::<Vec<u32>>());
+
+        // unjoined ends stop at the endpoints
+        assert!(is_covered(&pixels, 4, 16) && !is_covered(&pixels, 3, 16));
+        assert!(is_covered(&pixels, 24, 27) && !is_covered(&pixels, 24, 28));
+
+        // the miter fills the outer corner of a joint
+        lines.clear();
+        lines.add_polyline(
+            &[vec3(-0.5, 0.0, 0.0), vec3(0.0, 0.0, 0.0), vec3(0.0, 0.5, 0.0)],
+            wgpu::Color::WHITE,
+            false,
+        );
+        let pixels = render_lines(&context, &mut lines);
+        assert!(is_covered(&pixels, 18, 18));
+        assert!(!is_covered(&pixels, 20, 20));
+
+        // past the miter limit the bevel cuts the corner off, without a gap between the ends
+        lines.miter_limit = 1.0;
+        let pixels = render_lines(&context, &mut lines);
+        assert!(is_covered(&pixels, 17, 16));
+        assert!(!is_covered(&pixels, 18, 18));
+    }
+
+    #[test]
+    fn test_segment_expansion() {
+        let corners = expand_segment(vec2(0.0, 0.0), vec2(10.0, 0.0), 4.0);
+        assert_eq!(corners, [vec2(0.0, -2.0), vec2(0.0, 2.0), vec2(10.0, -2.0), vec2(10.0, 2.0)]);
+
+        // a right angle turning left from +x to +y, mitered out to where both sides meet
+        let normal = vec2(0.0, 1.0);
+        let next_normal = vec2(-1.0, 0.0);
+        let miter = get_miter(normal, next_normal, DEFAULT_MITER_LIMIT).unwrap();
+        assert!(miter.abs_diff_eq(vec2(-1.0, 1.0), 1e-5), "{}", miter);
+
+        // sharper than the limit or turning back is beveled
+        assert_eq!(get_miter(normal, next_normal, 1.0), None);
+        assert_eq!(get_miter(normal, -normal, DEFAULT_MITER_LIMIT), None);
+
+        // the bevel is on the right, outside the turn
+        let bevel = get_bevel(vec2(10.0, 0.0), vec2(1.0, 0.0), vec2(0.0, 1.0), 4.0);
+        assert_eq!(bevel, [vec2(10.0, 0.0), vec2(10.0, -2.0), vec2(12.0, 0.0)]);
+    }
+
+    #[test]
+    fn test_polyline_neighbors() {
+        let points = [vec3(0.0, 0.0, 0.0), vec3(1.0, 0.0, 0.0), vec3(1.0, 1.0, 0.0)];
+
+        let open = build_polyline_segments(&points, false);
+        assert_eq!(open.len(), 2);
+        assert_eq!(open[0].prev, open[0].start);
+        assert_eq!(open[0].next, points[2].to_array());
+        assert_eq!(open[1].next, open[1].end);
+
+        let closed = build_polyline_segments(&points, true);
+        assert_eq!(closed.len(), 3);
+        assert_eq!(closed[0].prev, points[2].to_array());
+        assert_eq!(closed[2].end, points[0].to_array());
+    }
+}
@@ -1,7 +1,13 @@
+use crate::bind_group::{BindGroupBuilder, LayoutBuilder};
+use crate::buffers::UniformBuffer;
+use crate::default_textures::{get_default_texture, DefaultTextureKind};
 use crate::error::Error;
 use crate::error::Error::ImageError;
-use crate::gpu_context::GpuContext;
+use crate::gpu_context::{get_or_create_bind_group_layout, GpuContext};
+use crate::texture::SamplerBuilder;
 use crate::texture_config::{TextureConfig, TextureFilter, TextureType, TextureWrap};
+use bytemuck::{Pod, Zeroable};
+use glam::{Vec3, Vec4};
 use image::GenericImageView;
 use std::ffi::OsString;
 use std::path::PathBuf;
@@ -9,6 +15,14 @@ use std::rc::Rc;
 use wgpu::{BindGroup, BindGroupLayout, Sampler, Texture, TextureView};
 
 pub const MATERIAL_BIND_GROUP_LAYOUT: &str = "material_bind_group_layout";
+pub const PBR_MATERIAL_BIND_GROUP_LAYOUT: &str = "pbr_material_bind_group_layout";
+
+// Wgsl PbrMaterial struct and cook_torrance brdf to prepend to shaders using PbrMaterial
+pub const PBR_WGSL: &str = include_str!("shaders/pbr.wgsl");
+
+// DIELECTRIC_F0 and MIN_ROUGHNESS in pbr.wgsl
+pub const DIELECTRIC_F0: f32 = 0.04;
+pub const MIN_ROUGHNESS: f32 = 0.045;
 
 #[derive(Debug, Clone)]
 pub struct Material {
@@ -29,6 +43,31 @@ impl Material {
     }
 }
 
+// Material for an absent texture map, binding one of the shared 1x1 default textures
+pub fn get_default_material(context: &mut GpuContext, kind: DefaultTextureKind, texture_type: TextureType) -> Material {
+    let default_texture = get_default_texture(context, kind);
+
+    let texture_sampler = context.device.create_sampler(&wgpu::SamplerDescriptor {
+        label: Some(kind.label()),
+        ..Default::default()
+    });
+
+    let bind_group_layout = get_material_bind_group_layout(context);
+
+    let bind_group = create_texture_bind_group(context, &bind_group_layout, &default_texture.view, &texture_sampler);
+
+    Material {
+        texture_path: OsString::new(),
+        texture_type,
+        texture: default_texture.texture.clone(),
+        view: default_texture.view.clone(),
+        sampler: texture_sampler.into(),
+        bind_group: bind_group.into(),
+        width: 1,
+        height: 1,
+    }
+}
+
 pub fn load_texture(context: &mut GpuContext, texture_path: &PathBuf, texture_config: &TextureConfig) -> Result<Material, Error> {
     let mut img = match image::open(texture_path) {
         Ok(img) => img,
@@ -105,14 +144,7 @@ pub fn load_texture(context: &mut GpuContext, texture_path: &PathBuf, texture_co
         ..Default::default()
     });
 
-    if !context.bind_layout_cache.contains_key(MATERIAL_BIND_GROUP_LAYOUT) {
-        let layout = create_material_bind_group_layout(context);
-        context
-            .bind_layout_cache
-            .insert(String::from(MATERIAL_BIND_GROUP_LAYOUT), layout.into());
-    }
-
-    let bind_group_layout = context.bind_layout_cache.get(MATERIAL_BIND_GROUP_LAYOUT).unwrap();
+    let bind_group_layout = get_material_bind_group_layout(context);
 
     let bind_group = create_texture_bind_group(context, &bind_group_layout, &texture_view, &texture_sampler);
 
@@ -130,6 +162,17 @@ pub fn load_texture(context: &mut GpuContext, texture_path: &PathBuf, texture_co
     Ok(texture)
 }
 
+fn get_material_bind_group_layout(context: &mut GpuContext) -> Rc<BindGroupLayout> {
+    if !context.bind_layout_cache.contains_key(MATERIAL_BIND_GROUP_LAYOUT) {
+        let layout = create_material_bind_group_layout(context);
+        context
+            .bind_layout_cache
+            .insert(String::from(MATERIAL_BIND_GROUP_LAYOUT), layout.into());
+    }
+
+    context.bind_layout_cache.get(MATERIAL_BIND_GROUP_LAYOUT).unwrap().clone()
+}
+
 pub fn create_material_bind_group_layout(context: &GpuContext) -> BindGroupLayout {
     context.device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
         entries: &[
@@ -177,3 +220,296 @@ pub fn create_texture_bind_group(
         label: Some("material_bind_group"),
     })
 }
+
+// PbrMaterial in pbr.wgsl
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Pod, Zeroable)]
+pub struct PbrMaterialUniform {
+    pub base_color: [f32; 4],
+    pub metallic: f32,
+    pub roughness: f32,
+    pub normal_scale: f32,
+    pub _padding: f32,
+}
+
+// Metallic-roughness material. Absent maps bind the 1x1 default textures, white for the albedo and
+// metallic-roughness maps so the factors apply unchanged and a flat normal, so every material has
+// the same bind group layout. After changing the factors call update, after changing a map rebuild_bind_group.
+pub struct PbrMaterial {
+    pub base_color: Vec4,
+    pub metallic: f32,
+    pub roughness: f32,
+    pub normal_scale: f32,
+    pub albedo_map: Option<Rc<TextureView>>,
+    pub normal_map: Option<Rc<TextureView>>,
+    // metallic in blue, roughness in green
+    pub metallic_roughness_map: Option<Rc<TextureView>>,
+    pub sampler: Rc<Sampler>,
+    pub uniform: UniformBuffer<PbrMaterialUniform>,
+    pub bind_group: BindGroup,
+}
+
+impl PbrMaterial {
+    pub fn new(context: &mut GpuContext, base_color: Vec4, metallic: f32, roughness: f32) -> Self {
+        let sampler = SamplerBuilder::new()
+            .label("pbr material")
+            .address_mode(wgpu::AddressMode::Repeat)
+            .build(context);
+
+        let uniform_data = PbrMaterialUniform {
+            base_color: base_color.to_array(),
+            metallic,
+            roughness,
+            normal_scale: 1.0,
+            _padding: 0.0,
+        };
+        let uniform = UniformBuffer::new(context, &uniform_data, wgpu::BufferUsages::empty());
+
+        let bind_group = create_pbr_bind_group(context, [None, None, None], &sampler, &uniform);
+
+        PbrMaterial {
+            base_color,
+            metallic,
+            roughness,
+            normal_scale: 1.0,
+            albedo_map: None,
+            normal_map: None,
+            metallic_roughness_map: None,
+            sampler: sampler.into(),
+            uniform,
+            bind_group,
+        }
+    }
+
+    pub fn get_uniform(&self) -> PbrMaterialUniform {
+        PbrMaterialUniform {
+            base_color: self.base_color.to_array(),
+            metallic: self.metallic.clamp(0.0, 1.0),
+            roughness: self.roughness.clamp(MIN_ROUGHNESS, 1.0),
+            normal_scale: self.normal_scale,
+            _padding: 0.0,
+        }
+    }
+
+    pub fn update(&self, context: &GpuContext) {
+        self.uniform.update(context, &self.get_uniform());
+    }
+
+    pub fn rebuild_bind_group(&mut self, context: &mut GpuContext) {
+        let maps = [
+            self.albedo_map.clone(),
+            self.normal_map.clone(),
+            self.metallic_roughness_map.clone(),
+        ];
+        self.bind_group = create_pbr_bind_group(context, maps, &self.sampler, &self.uniform);
+    }
+}
+
+// Bindings of a pbr material group: PbrMaterial uniform, albedo, normal and metallic-roughness maps, sampler
+pub fn get_pbr_material_layout_builder() -> LayoutBuilder {
+    LayoutBuilder::new()
+        .sized_uniform(wgpu::ShaderStages::FRAGMENT, std::mem::size_of::<PbrMaterialUniform>() as u64)
+        .texture_2d(wgpu::ShaderStages::FRAGMENT)
+        .texture_2d(wgpu::ShaderStages::FRAGMENT)
+        .texture_2d(wgpu::ShaderStages::FRAGMENT)
+        .sampler(wgpu::ShaderStages::FRAGMENT)
+}
+
+// Shared by all pbr materials
+pub fn get_pbr_material_bind_group_layout(context: &mut GpuContext) -> Rc<BindGroupLayout> {
+    get_or_create_bind_group_layout(context, PBR_MATERIAL_BIND_GROUP_LAYOUT, create_pbr_material_bind_group_layout)
+}
+
+fn create_pbr_material_bind_group_layout(context: &GpuContext, label: &str) -> BindGroupLayout {
+    get_pbr_material_layout_builder()
+        .build(context, label)
+        .expect("invalid pbr material bind group layout")
+}
+
+// maps in the order albedo, normal, metallic-roughness
+fn create_pbr_bind_group(
+    context: &mut GpuContext,
+    maps: [Option<Rc<TextureView>>; 3],
+    sampler: &Sampler,
+    uniform: &UniformBuffer<PbrMaterialUniform>,
+) -> BindGroup {
+    let defaults = [DefaultTextureKind::White, DefaultTextureKind::FlatNormal, DefaultTextureKind::White];
+    let views: Vec<Rc<TextureView>> = maps
+        .into_iter()
+        .zip(defaults)
+        .map(|(map, kind)| map.unwrap_or_else(|| get_default_texture(context, kind).view.clone()))
+        .collect();
+
+    let layout = get_pbr_material_bind_group_layout(context);
+    BindGroupBuilder::new()
+        .resource(uniform.buffer.as_entire_binding())
+        .texture_view(&views[0])
+        .texture_view(&views[1])
+        .texture_view(&views[2])
+        .sampler(sampler)
+        .build(context, &layout, "pbr material bind group")
+}
+
+// Cpu version of cook_torrance in pbr.wgsl with unit radiance. The roughness is the surface's,
+// clamped to MIN_ROUGHNESS by get_pbr_surface.
+pub fn cook_torrance(albedo: Vec3, metallic: f32, roughness: f32, normal: Vec3, view_dir: Vec3, light_dir: Vec3) -> Vec3 {
+    let n_dot_l = normal.dot(light_dir).max(0.0);
+    let n_dot_v = normal.dot(view_dir).max(1e-4);
+    let half_dir = (view_dir + light_dir).normalize();
+    let n_dot_h = normal.dot(half_dir).max(0.0);
+    let h_dot_v = half_dir.dot(view_dir).max(0.0);
+
+    let a2 = roughness.powi(4);
+    let d = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+    let distribution = a2 / (std::f32::consts::PI * d * d);
+
+    let k = (roughness + 1.0).powi(2) / 8.0;
+    let geometry = |n_dot_x: f32| n_dot_x / (n_dot_x * (1.0 - k) + k);
+
+    let f0 = Vec3::splat(DIELECTRIC_F0).lerp(albedo, metallic);
+    let fresnel = f0 + (Vec3::ONE - f0) * (1.0 - h_dot_v).clamp(0.0, 1.0).powi(5);
+    let specular = distribution * geometry(n_dot_v) * geometry(n_dot_l) * fresnel / (4.0 * n_dot_v * n_dot_l.max(1e-4));
+
+    let diffuse = (Vec3::ONE - fresnel) * (1.0 - metallic) * albedo / std::f32::consts::PI;
+    (diffuse + specular) * n_dot_l
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{vec3, Vec3};
+
+    use crate::gpu_context::GpuContext;
+    #[cfg(feature = "reflection")]
+    use crate::material::get_pbr_material_layout_builder;
+    use crate::material::{cook_torrance, PbrMaterialUniform, DIELECTRIC_F0, MIN_ROUGHNESS, PBR_WGSL};
+    #[cfg(feature = "reflection")]
+    use crate::shader_bindings::check_shader_bindings;
+    use crate::shader_test::ShaderTest;
+
+    // cook_torrance with a +z normal and unit radiance for each case of three inputs, the surface
+    // is built by get_pbr_surface from the factors with white maps
+    const BRDF_WGSL: &str = r"
+@compute @workgroup_size(1) fn shade_cases(@builtin(global_invocation_id) id: vec3<u32>) {
+    let albedo_metallic = test_inputs[id.x * 3u];
+    let view_dir_roughness = test_inputs[id.x * 3u + 1u];
+    var material: PbrMaterial;
+    material.base_color = vec4<f32>(albedo_metallic.rgb, 1.0);
+    material.metallic = albedo_metallic.w;
+    material.roughness = view_dir_roughness.w;
+    let surface = get_pbr_surface(material, vec4<f32>(1.0), vec4<f32>(1.0));
+
+    let normal = vec3<f32>(0.0, 0.0, 1.0);
+    let light_dir = test_inputs[id.x * 3u + 2u].xyz;
+    let color = cook_torrance(surface, normal, view_dir_roughness.xyz, light_dir, vec3<f32>(1.0));
+    test_outputs[id.x] = vec4<f32>(color, 1.0);
+}
+";
+
+    // albedo, metallic, material roughness, view direction and light direction
+    type BrdfCase = (Vec3, f32, f32, Vec3, Vec3);
+
+    // behind, metal, dielectric, smooth, rough, smooth_off, rough_off and zero_roughness
+    fn get_brdf_cases() -> [BrdfCase; 8] {
+        let normal = Vec3::Z;
+        let red = vec3(1.0, 0.0, 0.0);
+        let view_dir = vec3(1.0, 0.0, 1.0).normalize();
+        let mirror = vec3(-1.0, 0.0, 1.0).normalize();
+        let off_mirror = vec3(-1.0, 0.6, 1.0).normalize();
+        [
+            (red, 0.0, 0.5, normal, -normal),
+            (red, 1.0, 0.5, normal, normal),
+            (red, 0.0, 0.5, normal, normal),
+            (Vec3::ONE, 1.0, 0.2, view_dir, mirror),
+            (Vec3::ONE, 1.0, 0.8, view_dir, mirror),
+            (Vec3::ONE, 1.0, 0.2, view_dir, off_mirror),
+            (Vec3::ONE, 1.0, 0.8, view_dir, off_mirror),
+            (Vec3::ONE, 1.0, 0.0, normal, normal),
+        ]
+    }
+
+    // cook_torrance on the surface get_pbr_surface builds
+    fn shade(&(albedo, metallic, roughness, view_dir, light_dir): &BrdfCase) -> Vec3 {
+        cook_torrance(albedo, metallic, roughness.clamp(MIN_ROUGHNESS, 1.0), Vec3::Z, view_dir, light_dir)
+    }
+
+    #[test]
+    fn test_pbr_material_layout() {
+        assert_eq!(std::mem::size_of::<PbrMaterialUniform>(), 32);
+        assert!(PBR_WGSL.contains(&format!("const DIELECTRIC_F0: f32 = {};", DIELECTRIC_F0)));
+        assert!(PBR_WGSL.contains(&format!("const MIN_ROUGHNESS: f32 = {};", MIN_ROUGHNESS)));
+    }
+
+    #[test]
+    #[cfg(feature = "reflection")]
+    fn test_pbr_material_bindings() {
+        let source = format!(
+            "{}
+@group(0) @binding(0) var<uniform> material: PbrMaterial;
+@group(0) @binding(1) var albedo_map: texture_2d<f32>;
+@group(0) @binding(2) var normal_map: texture_2d<f32>;
+@group(0) @binding(3) var metallic_roughness_map: texture_2d<f32>;
+@group(0) @binding(4) var material_sampler: sampler;
+
+@fragment fn fs_main(@location(0) uv: vec2<f32>) -> @location(0) vec4<f32> {{
+    let surface = get_pbr_surface(material, textureSample(albedo_map, material_sampler, uv), textureSample(metallic_roughness_map, material_sampler, uv));
+    let normal = get_mapped_normal(vec3<f32>(0.0, 0.0, 1.0), vec4<f32>(1.0, 0.0, 0.0, 1.0), textureSample(normal_map, material_sampler, uv).xyz, material.normal_scale);
+    return vec4<f32>(cook_torrance(surface, normal, normal, normal, vec3<f32>(1.0)), 1.0);
+}}",
+            PBR_WGSL
+        );
+        let layout = get_pbr_material_layout_builder();
+        check_shader_bindings(&source, &["fs_main"], &[&layout.entries]).unwrap();
+    }
+
+    #[test]
+    fn test_cook_torrance() {
+        let colors = get_brdf_cases().map(|brdf_case| shade(&brdf_case));
+        let [behind, metal, dielectric, smooth, rough, smooth_off, rough_off, zero_roughness] = colors;
+
+        // lit from behind
+        assert_eq!(behind, Vec3::ZERO);
+
+        // metals reflect tinted by their albedo, without diffuse
+        assert!(metal.x > 0.0);
+        assert_eq!((metal.y, metal.z), (0.0, 0.0));
+
+        // dielectrics have an untinted specular on top of the diffuse
+        assert!(dielectric.y > 0.0 && dielectric.y == dielectric.z);
+        assert!(dielectric.x > dielectric.y);
+
+        // smoother surfaces have a brighter and narrower highlight
+        assert!(smooth.x > rough.x);
+        assert!(smooth_off.x / smooth.x < rough_off.x / rough.x);
+
+        // zero roughness is clamped instead of giving an infinite highlight
+        assert!(zero_roughness.is_finite());
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "gpu_tests"), ignore = "needs a gpu adapter")]
+    fn test_cook_torrance_shader() {
+        let context = pollster::block_on(GpuContext::new_headless(4, 4)).unwrap();
+        let cases = get_brdf_cases();
+        let inputs: Vec<[f32; 4]> = cases
+            .iter()
+            .flat_map(|(albedo, metallic, roughness, view_dir, light_dir)| {
+                [
+                    albedo.extend(*metallic).to_array(),
+                    view_dir.extend(*roughness).to_array(),
+                    light_dir.extend(0.0).to_array(),
+                ]
+            })
+            .collect();
+
+        let outputs = ShaderTest::new(PBR_WGSL, BRDF_WGSL)
+            .run(&context, "shade_cases", &inputs, cases.len() as u32, cases.len())
+            .unwrap();
+
+        // relative to the highlight, which is large for smooth surfaces
+        for (brdf_case, output) in cases.iter().zip(outputs) {
+            let (color, expected) = (Vec3::from_slice(&output), shade(brdf_case));
+            let tolerance = 1e-4 * expected.length().max(1.0);
+            assert!(color.abs_diff_eq(expected, tolerance), "{} instead of {}", color, expected);
+        }
+    }
+}
@@ -1,6 +1,6 @@
 extern crate glam;
 
-use glam::{vec4, Vec3, Vec4Swizzles};
+use glam::{vec4, Mat3, Mat4, Vec3, Vec4Swizzles};
 
 pub fn screen_to_model_glam(
     mouse_x: f32,
@@ -72,10 +72,25 @@ pub fn ray_plane_intersection(ray_origin: Vec3, ray_direction: Vec3, plane_point
     None
 }
 
+// Inverse-transpose of the upper 3x3 of the model matrix. Keeps normals perpendicular to surfaces
+// when the model has a non-uniform scale.
+pub fn get_normal_matrix(model: &Mat4) -> Mat3 {
+    Mat3::from_mat4(*model).inverse().transpose()
+}
+
+// A mat3x3<f32> in a wgsl uniform has each column padded to 16 bytes
+pub fn mat3_to_padded_cols(matrix: &Mat3) -> [[f32; 4]; 3] {
+    [
+        matrix.x_axis.extend(0.0).to_array(),
+        matrix.y_axis.extend(0.0).to_array(),
+        matrix.z_axis.extend(0.0).to_array(),
+    ]
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::math::{get_world_ray_from_mouse, ray_plane_intersection, screen_to_model_glam};
-    use glam::{vec2, vec3, vec4, Mat4, Vec4Swizzles};
+    use crate::math::{get_normal_matrix, get_world_ray_from_mouse, ray_plane_intersection, screen_to_model_glam};
+    use glam::{vec2, vec3, vec4, Mat3, Mat4, Quat, Vec4Swizzles};
     use log::debug;
 
     #[test]
@@ -233,4 +248,26 @@ mod tests {
 
         debug!("intersection: {:?}", intersection);
     }
+
+    #[test]
+    fn test_normal_matrix_non_uniform_scale() {
+        let model = Mat4::from_scale_rotation_translation(
+            vec3(4.0, 1.0, 0.5),
+            Quat::from_rotation_z(30.0f32.to_radians()),
+            vec3(1.0, 2.0, 3.0),
+        );
+
+        // a surface on the plane x = y has this tangent and normal
+        let tangent = vec3(1.0, 1.0, 0.0).normalize();
+        let normal = vec3(1.0, -1.0, 0.0).normalize();
+
+        let transformed_tangent = Mat3::from_mat4(model) * tangent;
+        let transformed_normal = get_normal_matrix(&model) * normal;
+
+        assert!(transformed_tangent.dot(transformed_normal).abs() < 1e-5);
+
+        // using the model matrix directly does not keep them perpendicular
+        let model_normal = Mat3::from_mat4(model) * normal;
+        assert!(transformed_tangent.dot(model_normal).abs() > 1e-2);
+    }
 }
@@ -0,0 +1,622 @@
+use std::borrow::Cow;
+use std::rc::Rc;
+
+use glam::{Mat4, Vec3, Vec4};
+use wgpu::util::DeviceExt;
+use wgpu::{BindGroup, BindGroupLayout, Buffer, ComputePipeline};
+
+use crate::buffers::UniformBuffer;
+use crate::capabilities::Capabilities;
+use crate::culling::{Aabb, BoundingSphere, Frustum};
+use crate::error::Error;
+use crate::error::Error::ValidationError;
+use crate::gpu_context::{get_or_create_bind_group_layout, GpuContext};
+
+pub const MESHLET_CULL_BIND_GROUP_LAYOUT: &str = "meshlet cull bind group layout";
+pub const MESHLET_DRAW_BIND_GROUP_LAYOUT: &str = "meshlet draw bind group layout";
+
+// Common hardware friendly limits, a meshlet fits in one 64 thread workgroup
+pub const MAX_MESHLET_VERTICES: usize = 64;
+pub const MAX_MESHLET_TRIANGLES: usize = 124;
+
+// local vertex indices are packed in bytes
+pub const MESHLET_VERTEX_LIMIT: usize = 256;
+
+// must match meshlet_cull.wgsl
+pub const MESHLET_CULL_WORKGROUP_SIZE: u32 = 64;
+
+// cone cutoff of meshlets whose triangles face too many directions to be backface culled
+pub const NO_CONE_CULLING: f32 = 1.0;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct Meshlet {
+    // into MeshletMesh::vertices
+    pub vertex_offset: u32,
+    pub vertex_count: u32,
+    // into MeshletMesh::triangles
+    pub triangle_offset: u32,
+    pub triangle_count: u32,
+}
+
+// The cone contains the normals of all triangles, the meshlet is back facing when the camera
+// is inside the opposite cone behind it, see cull_meshlets in meshlet_cull.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct MeshletBounds {
+    pub center: Vec3,
+    pub radius: f32,
+    pub cone_axis: Vec3,
+    // sine of the angle between the axis and the furthest normal
+    pub cone_cutoff: f32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct MeshletCullUniform {
+    pub model: Mat4,
+    pub planes: [Vec4; 6],
+    pub camera_position: Vec3,
+    pub meshlet_count: u32,
+}
+
+// A mesh split into meshlets. vertices holds the mesh vertex indices used by each meshlet,
+// triangles the meshlet local indices of each triangle packed into the low 3 bytes of a u32.
+#[derive(Debug, Clone, Default)]
+pub struct MeshletMesh {
+    pub meshlets: Vec<Meshlet>,
+    pub bounds: Vec<MeshletBounds>,
+    pub vertices: Vec<u32>,
+    pub triangles: Vec<u32>,
+    pub max_triangles: u32,
+}
+
+impl MeshletMesh {
+    // Mesh vertex indices of a meshlet's triangles
+    pub fn get_triangle_indices(&self, meshlet: &Meshlet) -> Vec<[u32; 3]> {
+        let vertices = &self.vertices[meshlet.vertex_offset as usize..][..meshlet.vertex_count as usize];
+        let triangles = &self.triangles[meshlet.triangle_offset as usize..][..meshlet.triangle_count as usize];
+        triangles
+            .iter()
+            .map(|packed| [0, 1, 2].map(|corner| vertices[((packed >> (8 * corner)) & 0xff) as usize]))
+            .collect()
+    }
+}
+
+// Splits an indexed triangle list into meshlets of at most max_vertices unique vertices and
+// max_triangles triangles, in index order so meshlets keep the locality of the source mesh
+pub fn build_meshlets(positions: &[Vec3], indices: &[u32], max_vertices: usize, max_triangles: usize) -> Result<MeshletMesh, Error> {
+    if !(3..=MESHLET_VERTEX_LIMIT).contains(&max_vertices) || max_triangles == 0 {
+        return Err(ValidationError(format!(
+            "meshlets need 3 to {} vertices and at least one triangle, got {} and {}",
+            MESHLET_VERTEX_LIMIT, max_vertices, max_triangles
+        )));
+    }
+    if indices.len() % 3 != 0 {
+        return Err(ValidationError(format!(
+            "meshlet index count {} isn't a multiple of 3",
+            indices.len()
+        )));
+    }
+    if let Some(index) = indices.iter().find(|index| **index as usize >= positions.len()) {
+        return Err(ValidationError(format!(
+            "meshlet index {} is out of range of {} vertices",
+            index,
+            positions.len()
+        )));
+    }
+
+    let mut mesh = MeshletMesh {
+        max_triangles: max_triangles as u32,
+        ..Default::default()
+    };
+
+    // local index of each mesh vertex in the current meshlet, u32::MAX when it isn't in it
+    let mut local_indices = vec![u32::MAX; positions.len()];
+    let mut meshlet = Meshlet::default();
+
+    for triangle in indices.chunks_exact(3) {
+        let new_vertices = triangle
+            .iter()
+            .enumerate()
+            .filter(|(i, index)| local_indices[**index as usize] == u32::MAX && !triangle[..*i].contains(*index))
+            .count();
+
+        if meshlet.vertex_count as usize + new_vertices > max_vertices || meshlet.triangle_count as usize == max_triangles {
+            finish_meshlet(&mut mesh, &mut meshlet, &mut local_indices, positions);
+        }
+
+        let mut packed = 0;
+        for (corner, index) in triangle.iter().enumerate() {
+            let local_index = &mut local_indices[*index as usize];
+            if *local_index == u32::MAX {
+                *local_index = meshlet.vertex_count;
+                mesh.vertices.push(*index);
+                meshlet.vertex_count += 1;
+            }
+            packed |= *local_index << (8 * corner);
+        }
+        mesh.triangles.push(packed);
+        meshlet.triangle_count += 1;
+    }
+
+    finish_meshlet(&mut mesh, &mut meshlet, &mut local_indices, positions);
+    Ok(mesh)
+}
+
+// Adds the meshlet with its bounds and starts the next one
+fn finish_meshlet(mesh: &mut MeshletMesh, meshlet: &mut Meshlet, local_indices: &mut [u32], positions: &[Vec3]) {
+    if meshlet.triangle_count == 0 {
+        return;
+    }
+
+    mesh.meshlets.push(*meshlet);
+    let triangles = mesh.get_triangle_indices(meshlet);
+    mesh.bounds.push(get_meshlet_bounds(positions, &triangles));
+
+    for index in &mesh.vertices[meshlet.vertex_offset as usize..] {
+        local_indices[*index as usize] = u32::MAX;
+    }
+    *meshlet = Meshlet {
+        vertex_offset: mesh.vertices.len() as u32,
+        vertex_count: 0,
+        triangle_offset: mesh.triangles.len() as u32,
+        triangle_count: 0,
+    };
+}
+
+pub fn get_meshlet_bounds(positions: &[Vec3], triangles: &[[u32; 3]]) -> MeshletBounds {
+    let corners = triangles.iter().flatten().map(|index| positions[*index as usize]);
+    let sphere = BoundingSphere::from_aabb(&Aabb::from_points(corners));
+
+    // counter clockwise front faces, degenerate triangles have no normal
+    let normals: Vec<Vec3> = triangles
+        .iter()
+        .map(|triangle| triangle.map(|index| positions[index as usize]))
+        .map(|[p0, p1, p2]| (p1 - p0).cross(p2 - p0).normalize_or_zero())
+        .filter(|normal| *normal != Vec3::ZERO)
+        .collect();
+
+    let axis = normals.iter().sum::<Vec3>().normalize_or_zero();
+    let min_dot = normals.iter().map(|normal| normal.dot(axis)).fold(1.0f32, f32::min);
+
+    let (cone_axis, cone_cutoff) = match axis != Vec3::ZERO && min_dot > 0.0 {
+        true => (axis, (1.0 - min_dot * min_dot).max(0.0).sqrt()),
+        false => (Vec3::Z, NO_CONE_CULLING),
+    };
+
+    MeshletBounds {
+        center: sphere.center,
+        radius: sphere.radius,
+        cone_axis,
+        cone_cutoff,
+    }
+}
+
+// Meshlet storage buffers of one mesh and its indirect draw. The draw has an instance per visible
+// meshlet written by MeshletCuller::cull, each with max_triangles * 3 vertices.
+pub struct MeshletBuffers {
+    pub meshlets: Buffer,
+    pub bounds: Buffer,
+    pub vertices: Buffer,
+    pub triangles: Buffer,
+    pub visible_meshlets: Buffer,
+    pub draw_args: Buffer,
+    pub meshlet_count: u32,
+    pub max_triangles: u32,
+    pub draw_bind_group: BindGroup,
+    // copied over draw_args before culling
+    reset_draw_args: Buffer,
+}
+
+impl MeshletBuffers {
+    pub fn new(context: &mut GpuContext, mesh: &MeshletMesh) -> Result<Self, Error> {
+        check_meshlet_culling_support(&context.capabilities, mesh.meshlets.len() as u32)?;
+        if mesh.meshlets.is_empty() {
+            return Err(ValidationError("meshlet buffers need at least one meshlet".to_string()));
+        }
+
+        let create_buffer = |contents: &[u8], usage: wgpu::BufferUsages, label: &str| {
+            context.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some(label),
+                contents,
+                usage,
+            })
+        };
+
+        let storage = wgpu::BufferUsages::STORAGE;
+        // the culling results can be read back for debugging
+        let readable_storage = storage | wgpu::BufferUsages::COPY_SRC;
+        let meshlets = create_buffer(bytemuck::cast_slice(&mesh.meshlets), storage, "meshlets");
+        let bounds = create_buffer(bytemuck::cast_slice(&mesh.bounds), storage, "meshlet bounds");
+        let vertices = create_buffer(bytemuck::cast_slice(&mesh.vertices), storage, "meshlet vertices");
+        let triangles = create_buffer(bytemuck::cast_slice(&mesh.triangles), storage, "meshlet triangles");
+
+        let visible_meshlets = context.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("visible meshlets"),
+            size: (mesh.meshlets.len() * 4) as wgpu::BufferAddress,
+            usage: readable_storage,
+            mapped_at_creation: false,
+        });
+
+        let args = get_meshlet_draw_args(mesh.max_triangles);
+        let draw_args = create_buffer(
+            args.as_bytes(),
+            readable_storage | wgpu::BufferUsages::INDIRECT | wgpu::BufferUsages::COPY_DST,
+            "meshlet draw args",
+        );
+        let reset_draw_args = create_buffer(args.as_bytes(), wgpu::BufferUsages::COPY_SRC, "meshlet reset draw args");
+
+        let layout = get_meshlet_draw_bind_group_layout(context);
+        let draw_bind_group = context.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: meshlets.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: vertices.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: triangles.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: visible_meshlets.as_entire_binding(),
+                },
+            ],
+            label: Some("meshlet draw bind group"),
+        });
+
+        Ok(MeshletBuffers {
+            meshlets,
+            bounds,
+            vertices,
+            triangles,
+            visible_meshlets,
+            draw_args,
+            meshlet_count: mesh.meshlets.len() as u32,
+            max_triangles: mesh.max_triangles,
+            draw_bind_group,
+            reset_draw_args,
+        })
+    }
+
+    // The pipeline's layout has get_meshlet_draw_bind_group_layout at group, matching the group
+    // passed to get_meshlet_draw_wgsl
+    pub fn draw<'a>(&'a self, pass: &mut wgpu::RenderPass<'a>, group: u32) {
+        pass.set_bind_group(group, &self.draw_bind_group, &[]);
+        pass.draw_indirect(&self.draw_args, 0);
+    }
+}
+
+// Instance count is set by culling
+fn get_meshlet_draw_args(max_triangles: u32) -> wgpu::util::DrawIndirectArgs {
+    wgpu::util::DrawIndirectArgs {
+        vertex_count: max_triangles * 3,
+        instance_count: 0,
+        first_vertex: 0,
+        first_instance: 0,
+    }
+}
+
+// Culls meshlets against the frustum and their backface cones on the gpu, writing the indirect
+// draw of the visible ones
+pub struct MeshletCuller {
+    pipeline: ComputePipeline,
+    bind_group_layout: Rc<BindGroupLayout>,
+    uniform_buffer: UniformBuffer<MeshletCullUniform>,
+}
+
+impl MeshletCuller {
+    pub fn new(context: &mut GpuContext) -> Result<Self, Error> {
+        check_meshlet_culling_support(&context.capabilities, 0)?;
+
+        let bind_group_layout =
+            get_or_create_bind_group_layout(context, MESHLET_CULL_BIND_GROUP_LAYOUT, create_meshlet_cull_bind_group_layout);
+
+        let shader = context.device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("meshlet cull shader"),
+            source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(include_str!("shaders/meshlet_cull.wgsl"))),
+        });
+
+        let pipeline_layout = context.device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("meshlet cull pipeline layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let pipeline = context.device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("meshlet cull pipeline"),
+            layout: Some(&pipeline_layout),
+            module: &shader,
+            entry_point: "cull_meshlets",
+        });
+
+        let uniform_buffer = UniformBuffer::new(
+            &*context,
+            &get_meshlet_cull_uniform(&Mat4::IDENTITY, &Mat4::IDENTITY, Vec3::ZERO, 0),
+            wgpu::BufferUsages::empty(),
+        );
+
+        Ok(MeshletCuller {
+            pipeline,
+            bind_group_layout,
+            uniform_buffer,
+        })
+    }
+
+    // Records the culling of mesh drawn with model. The cone test assumes model has no non uniform
+    // scale. The uniform is written through the queue so only one mesh per submit is supported.
+    pub fn cull(
+        &self,
+        context: &GpuContext,
+        encoder: &mut wgpu::CommandEncoder,
+        mesh: &MeshletBuffers,
+        model: &Mat4,
+        projection_view: &Mat4,
+        camera_position: Vec3,
+    ) {
+        let uniform = get_meshlet_cull_uniform(model, projection_view, camera_position, mesh.meshlet_count);
+        self.uniform_buffer.update(context, &uniform);
+
+        let bind_group = context.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: mesh.bounds.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: mesh.visible_meshlets.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: mesh.draw_args.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: self.uniform_buffer.as_entire_binding(),
+                },
+            ],
+            label: Some("meshlet cull bind group"),
+        });
+
+        encoder.copy_buffer_to_buffer(&mesh.reset_draw_args, 0, &mesh.draw_args, 0, mesh.draw_args.size());
+
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("meshlet culling"),
+            timestamp_writes: None,
+        });
+        pass.set_pipeline(&self.pipeline);
+        pass.set_bind_group(0, &bind_group, &[]);
+        pass.dispatch_workgroups(mesh.meshlet_count.div_ceil(MESHLET_CULL_WORKGROUP_SIZE), 1, 1);
+    }
+}
+
+pub fn check_meshlet_culling_support(capabilities: &Capabilities, meshlet_count: u32) -> Result<(), Error> {
+    capabilities.require_compute("meshlet culling")?;
+    capabilities.require_storage_buffers("meshlet culling")?;
+
+    let max_workgroups = capabilities.max_compute_workgroups_per_dimension;
+    if meshlet_count.div_ceil(MESHLET_CULL_WORKGROUP_SIZE) > max_workgroups {
+        return Err(ValidationError(format!(
+            "culling {} meshlets needs more than max_compute_workgroups_per_dimension ({}) workgroups",
+            meshlet_count, max_workgroups
+        )));
+    }
+    Ok(())
+}
+
+fn get_meshlet_cull_uniform(model: &Mat4, projection_view: &Mat4, camera_position: Vec3, meshlet_count: u32) -> MeshletCullUniform {
+    MeshletCullUniform {
+        model: *model,
+        planes: Frustum::from_matrix(projection_view).planes,
+        camera_position,
+        meshlet_count,
+    }
+}
+
+// Declarations and get_meshlet_corner for vertex shaders drawing MeshletBuffers, with the
+// meshlet bindings at group
+pub fn get_meshlet_draw_wgsl(group: u32) -> String {
+    include_str!("shaders/meshlet_draw.wgsl").replace("MESHLET_GROUP", &group.to_string())
+}
+
+pub fn get_meshlet_draw_bind_group_layout(context: &mut GpuContext) -> Rc<BindGroupLayout> {
+    get_or_create_bind_group_layout(context, MESHLET_DRAW_BIND_GROUP_LAYOUT, create_meshlet_draw_bind_group_layout)
+}
+
+fn storage_entry(binding: u32, visibility: wgpu::ShaderStages, read_only: bool) -> wgpu::BindGroupLayoutEntry {
+    wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility,
+        ty: wgpu::BindingType::Buffer {
+            ty: wgpu::BufferBindingType::Storage { read_only },
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    }
+}
+
+fn create_meshlet_cull_bind_group_layout(context: &GpuContext, label: &str) -> BindGroupLayout {
+    let compute = wgpu::ShaderStages::COMPUTE;
+    context.device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        entries: &[
+            // bounds
+            storage_entry(0, compute, true),
+            // visible meshlets
+            storage_entry(1, compute, false),
+            // draw args
+            storage_entry(2, compute, false),
+            wgpu::BindGroupLayoutEntry {
+                binding: 3,
+                visibility: compute,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+        ],
+        label: Some(label),
+    })
+}
+
+fn create_meshlet_draw_bind_group_layout(context: &GpuContext, label: &str) -> BindGroupLayout {
+    let vertex = wgpu::ShaderStages::VERTEX;
+    context.device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        entries: &[
+            // meshlets
+            storage_entry(0, vertex, true),
+            // vertices
+            storage_entry(1, vertex, true),
+            // triangles
+            storage_entry(2, vertex, true),
+            // visible meshlets
+            storage_entry(3, vertex, true),
+        ],
+        label: Some(label),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{vec3, Mat4, Vec3};
+
+    use crate::buffers::read_buffer;
+    use crate::error::Error;
+    use crate::gpu_context::GpuContext;
+    use crate::meshlet::{
+        build_meshlets, MeshletBuffers, MeshletCuller, MeshletMesh, MAX_MESHLET_TRIANGLES, MAX_MESHLET_VERTICES, NO_CONE_CULLING,
+    };
+
+    // size x size quads in the z = 0 plane facing +z
+    fn grid(size: u32) -> (Vec<Vec3>, Vec<u32>) {
+        let positions = (0..=size)
+            .flat_map(|y| (0..=size).map(move |x| vec3(x as f32, y as f32, 0.0)))
+            .collect();
+        let indices = (0..size)
+            .flat_map(|y| (0..size).map(move |x| y * (size + 1) + x))
+            .flat_map(|i| [i, i + 1, i + size + 2, i, i + size + 2, i + size + 1])
+            .collect();
+        (positions, indices)
+    }
+
+    #[test]
+    fn test_meshlet_limits() {
+        let (positions, indices) = grid(24);
+
+        for (max_vertices, max_triangles) in [(MAX_MESHLET_VERTICES, MAX_MESHLET_TRIANGLES), (16, 64), (64, 10), (3, 1)] {
+            let mesh = build_meshlets(&positions, &indices, max_vertices, max_triangles).unwrap();
+
+            for meshlet in &mesh.meshlets {
+                assert!(meshlet.vertex_count as usize <= max_vertices);
+                assert!(meshlet.triangle_count as usize <= max_triangles);
+                assert!(meshlet.triangle_count > 0);
+            }
+
+            // every triangle is kept, in order
+            let triangles: Vec<u32> = mesh
+                .meshlets
+                .iter()
+                .flat_map(|meshlet| mesh.get_triangle_indices(meshlet))
+                .flatten()
+                .collect();
+            assert_eq!(triangles, indices);
+            assert_eq!(mesh.bounds.len(), mesh.meshlets.len());
+        }
+
+        // the default limits fill meshlets instead of splitting early
+        let mesh = build_meshlets(&positions, &indices, MAX_MESHLET_VERTICES, MAX_MESHLET_TRIANGLES).unwrap();
+        assert!(mesh.meshlets.len() < (indices.len() / 3).div_ceil(16));
+
+        let result = build_meshlets(&positions, &indices, 512, MAX_MESHLET_TRIANGLES);
+        assert!(matches!(result, Err(Error::ValidationError(_))));
+        assert!(build_meshlets(&positions, &indices[..4], 64, 64).is_err());
+    }
+
+    // One meshlet of the grid facing +z and one of a flipped copy facing -z at x + 10
+    fn get_two_sided_mesh() -> MeshletMesh {
+        let (mut positions, mut indices) = grid(4);
+        let vertex_count = positions.len() as u32;
+        let shifted: Vec<Vec3> = positions.iter().map(|position| *position + vec3(10.0, 0.0, 0.0)).collect();
+        positions.extend(shifted);
+        let flipped: Vec<u32> = indices
+            .chunks_exact(3)
+            .flat_map(|triangle| [triangle[0], triangle[2], triangle[1]].map(|i| i + vertex_count))
+            .collect();
+        indices.extend(flipped);
+        build_meshlets(&positions, &indices, 64, 32).unwrap()
+    }
+
+    #[test]
+    fn test_meshlet_bounds() {
+        let (positions, indices) = grid(4);
+        let mesh = build_meshlets(&positions, &indices, MAX_MESHLET_VERTICES, MAX_MESHLET_TRIANGLES).unwrap();
+        let bounds = mesh.bounds[0];
+
+        // a flat meshlet has a zero width cone around its normal
+        assert!(bounds.cone_axis.abs_diff_eq(Vec3::Z, 1e-6));
+        assert!(bounds.cone_cutoff.abs() < 1e-3);
+        assert!(bounds.center.abs_diff_eq(vec3(2.0, 2.0, 0.0), 1e-6));
+
+        let mesh = get_two_sided_mesh();
+        assert_eq!(mesh.meshlets.len(), 2);
+        assert!(mesh.bounds[1].cone_axis.abs_diff_eq(Vec3::NEG_Z, 1e-6));
+
+        // a closed box has normals in every direction and is never cone culled
+        let box_positions = (0..8)
+            .map(|i| vec3((i & 1) as f32, ((i >> 1) & 1) as f32, ((i >> 2) & 1) as f32))
+            .collect::<Vec<_>>();
+        let box_indices = [
+            0, 2, 1, 1, 2, 3, 4, 5, 6, 5, 7, 6, 0, 1, 4, 1, 5, 4, 2, 6, 3, 3, 6, 7, 0, 4, 2, 2, 4, 6, 1, 3, 5, 3, 7, 5,
+        ];
+        let mesh = build_meshlets(&box_positions, &box_indices, MAX_MESHLET_VERTICES, MAX_MESHLET_TRIANGLES).unwrap();
+        assert_eq!(mesh.meshlets.len(), 1);
+        assert_eq!(mesh.bounds[0].cone_cutoff, NO_CONE_CULLING);
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "gpu_tests"), ignore = "needs a gpu adapter")]
+    fn test_meshlet_culling() {
+        let mut context = pollster::block_on(GpuContext::new_headless(4, 4)).unwrap();
+        let culler = MeshletCuller::new(&mut context).unwrap();
+        let buffers = MeshletBuffers::new(&mut context, &get_two_sided_mesh()).unwrap();
+        let projection = Mat4::perspective_rh(45f32.to_radians(), 1.0, 0.1, 100.0);
+
+        // the indices of the visible meshlets
+        let cull = |model: Mat4, camera_position: Vec3, target: Vec3| {
+            let projection_view = projection * Mat4::look_at_rh(camera_position, target, Vec3::Y);
+            let mut encoder = context.device.create_command_encoder(&Default::default());
+            culler.cull(&context, &mut encoder, &buffers, &model, &projection_view, camera_position);
+            context.queue.submit(std::iter::once(encoder.finish()));
+
+            let args: [u32; 4] = bytemuck::pod_read_unaligned(&read_buffer(&context, &buffers.draw_args));
+            assert_eq!(args[0], buffers.max_triangles * 3);
+            let visible = read_buffer(&context, &buffers.visible_meshlets);
+            let mut visible: Vec<u32> = bytemuck::pod_collect_to_vec(&visible[..args[1] as usize * 4]);
+            visible.sort();
+            visible
+        };
+
+        // both meshlets are in the frustum, only the one facing the camera is drawn
+        let center = vec3(7.0, 2.0, 0.0);
+        assert_eq!(cull(Mat4::IDENTITY, center + vec3(0.0, 0.0, 20.0), center), vec![0]);
+        assert_eq!(cull(Mat4::IDENTITY, center - vec3(0.0, 0.0, 20.0), center), vec![1]);
+
+        // the cones turn with the model
+        let turned = Mat4::from_rotation_y(std::f32::consts::PI);
+        let turned_center = vec3(-7.0, 2.0, 0.0);
+        assert_eq!(cull(turned, turned_center + vec3(0.0, 0.0, 20.0), turned_center), vec![1]);
+
+        // frustum culled, the draw args are reset every cull
+        assert_eq!(cull(Mat4::IDENTITY, vec3(2.0, 2.0, 20.0), vec3(-20.0, 2.0, 20.0)), vec![]);
+    }
+}
@@ -1,4 +1,6 @@
 use crate::animator::{AnimationClip, Animator, WeightedAnimation};
+#[cfg(feature = "gltf")]
+pub use crate::gltf_model::{load_gltf, GltfMesh, GltfModel};
 use crate::gpu_context::GpuContext;
 use crate::model_mesh::ModelMesh;
 use crate::texture_config::TextureType;
@@ -1,3 +1,6 @@
+use crate::culling::{Aabb, BoundingSphere};
+use crate::error::Error;
+use crate::error::Error::MeshError;
 use crate::gpu_context::GpuContext;
 use crate::material::Material;
 use glam::*;
@@ -108,6 +111,39 @@ impl ModelVertex {
     }
 }
 
+// Builds interleaved vertices from separate attribute arrays, e.g. from a gltf loader.
+// Tangents are left at zero and bone data at the unassigned default.
+pub fn interleave(positions: &[Vec3], normals: &[Vec3], uvs: &[Vec2]) -> Result<Vec<ModelVertex>, Error> {
+    if positions.len() != normals.len() || positions.len() != uvs.len() {
+        return Err(MeshError(format!(
+            "attribute lengths differ: positions {} normals {} uvs {}",
+            positions.len(),
+            normals.len(),
+            uvs.len()
+        )));
+    }
+
+    let vertices = positions
+        .iter()
+        .zip(normals)
+        .zip(uvs)
+        .map(|((position, normal), uv)| ModelVertex {
+            position: *position,
+            normal: *normal,
+            uv: *uv,
+            ..ModelVertex::new()
+        })
+        .collect();
+
+    Ok(vertices)
+}
+
+// Mesh space bounds, transform them by the entity matrix when culling
+pub fn compute_bounds(vertices: &[ModelVertex]) -> (Aabb, BoundingSphere) {
+    let aabb = Aabb::from_points(vertices.iter().map(|vertex| vertex.position));
+    (aabb, BoundingSphere::from_aabb(&aabb))
+}
+
 impl Default for ModelVertex {
     fn default() -> Self {
         Self::new()
@@ -125,6 +161,8 @@ pub struct ModelMesh {
     pub vertex_buffer: wgpu::Buffer,
     pub index_buffer: wgpu::Buffer,
     pub num_elements: u32,
+    pub aabb: Aabb,
+    pub bounding_sphere: BoundingSphere,
 }
 
 impl ModelMesh {
@@ -150,6 +188,8 @@ impl ModelMesh {
 
         let num_elements = indices.len() as u32;
 
+        let (aabb, bounding_sphere) = compute_bounds(&vertices);
+
         ModelMesh {
             id,
             name: name.into(),
@@ -159,6 +199,58 @@ impl ModelMesh {
             vertex_buffer,
             index_buffer,
             num_elements,
+            aabb,
+            bounding_sphere,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::model_mesh::{compute_bounds, interleave, ModelVertex};
+    use glam::{vec2, vec3};
+
+    #[test]
+    fn test_interleave() {
+        let positions = [vec3(1.0, 2.0, 3.0), vec3(4.0, 5.0, 6.0), vec3(7.0, 8.0, 9.0)];
+        let normals = [vec3(0.0, 1.0, 0.0), vec3(1.0, 0.0, 0.0), vec3(0.0, 0.0, 1.0)];
+        let uvs = [vec2(0.0, 0.5), vec2(0.5, 1.0), vec2(1.0, 0.0)];
+
+        let vertices = interleave(&positions, &normals, &uvs).unwrap();
+        assert_eq!(vertices.len(), 3);
+
+        for (i, vertex) in vertices.iter().enumerate() {
+            // copy out of the packed struct before comparing
+            let (position, normal, uv, bone_ids) = (vertex.position, vertex.normal, vertex.uv, vertex.bone_ids);
+            assert_eq!(position, positions[i]);
+            assert_eq!(normal, normals[i]);
+            assert_eq!(uv, uvs[i]);
+            assert_eq!(bone_ids, [-1; 4]);
         }
+
+        assert!(interleave(&positions, &normals, &uvs[..2]).is_err());
+    }
+
+    #[test]
+    fn test_compute_bounds() {
+        let mut vertices = vec![];
+        for x in [-1.0, 3.0] {
+            for y in [-2.0, 2.0] {
+                for z in [0.0, 4.0] {
+                    vertices.push(ModelVertex {
+                        position: vec3(x, y, z),
+                        ..ModelVertex::new()
+                    });
+                }
+            }
+        }
+
+        let (aabb, sphere) = compute_bounds(&vertices);
+        assert_eq!(aabb.min, vec3(-1.0, -2.0, 0.0));
+        assert_eq!(aabb.max, vec3(3.0, 2.0, 4.0));
+        assert_eq!(aabb.extents(), vec3(4.0, 4.0, 4.0));
+
+        assert_eq!(sphere.center, vec3(1.0, 0.0, 2.0));
+        assert!((sphere.radius - vec3(4.0, 4.0, 4.0).length() / 2.0).abs() < 1e-6);
     }
 }
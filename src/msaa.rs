@@ -0,0 +1,371 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use log::warn;
+use wgpu::{BindGroup, BindGroupLayout, RenderPipeline, TextureView};
+
+use crate::error::Error;
+use crate::error::Error::{UnsupportedError, ValidationError};
+use crate::gpu_context::{get_or_create_bind_group_layout, GpuContext};
+use crate::texture::{create_mipmap_bind_group_layout, get_mipmap_pipeline, DEPTH_FORMAT, MIPMAP_BIND_GROUP_LAYOUT};
+
+// Multisampled color target. wgpu only resolves into a texture of the same format, so when the
+// output has a different format, e.g. Rgba16Float rendering shown on an srgb surface, the samples
+// are resolved into a single sampled texture of the msaa format and blitted to the output.
+pub struct MsaaTarget {
+    pub texture: wgpu::Texture,
+    pub view: TextureView,
+    pub format: wgpu::TextureFormat,
+    pub output_format: wgpu::TextureFormat,
+    pub sample_count: u32,
+    conversion: Option<ResolveConversion>,
+}
+
+struct ResolveConversion {
+    view: TextureView,
+    bind_group: BindGroup,
+    bind_group_layout: Rc<BindGroupLayout>,
+    sampler: wgpu::Sampler,
+    pipeline: Rc<RenderPipeline>,
+}
+
+impl MsaaTarget {
+    pub fn new(
+        context: &mut GpuContext,
+        width: u32,
+        height: u32,
+        format: wgpu::TextureFormat,
+        sample_count: u32,
+        output_format: wgpu::TextureFormat,
+    ) -> Result<Self, Error> {
+        let format_flags = context.adapter.get_texture_format_features(format).flags;
+        check_sample_count(format, format_flags, sample_count)?;
+
+        let descriptor = get_msaa_descriptor(width, height, format, sample_count);
+        let texture = context.device.create_texture(&descriptor);
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+        let conversion = match check_resolve_format(format, output_format) {
+            Ok(()) => None,
+            Err(_) => Some(create_resolve_conversion(context, &descriptor, output_format)),
+        };
+
+        Ok(MsaaTarget {
+            texture,
+            view,
+            format,
+            output_format,
+            sample_count,
+            conversion,
+        })
+    }
+
+    // Resolves into output directly when the formats match, otherwise into the intermediate
+    // texture which finish_resolve then converts. output must have the output_format.
+    pub fn color_attachment<'a>(
+        &'a self,
+        output: &'a TextureView,
+        output_format: wgpu::TextureFormat,
+        load: wgpu::LoadOp<wgpu::Color>,
+    ) -> Result<wgpu::RenderPassColorAttachment<'a>, Error> {
+        if output_format != self.output_format {
+            return Err(ValidationError(format!(
+                "msaa target was created for output format {:?}, got {:?}",
+                self.output_format, output_format
+            )));
+        }
+
+        let resolve_target = match &self.conversion {
+            Some(conversion) => &conversion.view,
+            None => output,
+        };
+
+        Ok(wgpu::RenderPassColorAttachment {
+            view: &self.view,
+            resolve_target: Some(resolve_target),
+            ops: wgpu::Operations {
+                load,
+                // the samples aren't needed after the resolve
+                store: wgpu::StoreOp::Discard,
+            },
+        })
+    }
+
+    // Blits the resolved image to the output, called after the pass using color_attachment
+    pub fn finish_resolve(&self, encoder: &mut wgpu::CommandEncoder, output: &TextureView) {
+        let Some(conversion) = &self.conversion else {
+            return;
+        };
+
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("msaa resolve conversion"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: output,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: None,
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+
+        pass.set_pipeline(&conversion.pipeline);
+        pass.set_bind_group(0, &conversion.bind_group, &[]);
+        pass.draw(0..3, 0..1);
+    }
+
+    pub fn needs_conversion(&self) -> bool {
+        self.conversion.is_some()
+    }
+
+    // Recreates the textures at the new size, the formats and sample count are kept
+    pub fn resize(&mut self, context: &GpuContext, width: u32, height: u32) {
+        let descriptor = get_msaa_descriptor(width, height, self.format, self.sample_count);
+        self.texture = context.device.create_texture(&descriptor);
+        self.view = self.texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+        if let Some(conversion) = &mut self.conversion {
+            (conversion.view, conversion.bind_group) =
+                create_resolve_bind_group(context, &descriptor, &conversion.bind_group_layout, &conversion.sampler);
+        }
+    }
+}
+
+// Multisampled target at the context's sample count that resolves into the surface, None at 1x.
+// It follows the surface size, after set_surface_format a new target has to be created.
+pub fn create_surface_msaa_target(context: &mut GpuContext) -> Result<Option<Rc<RefCell<MsaaTarget>>>, Error> {
+    if context.sample_count == 1 {
+        return Ok(None);
+    }
+
+    let (width, height) = (context.config.width, context.config.height);
+    let format = context.config.view_formats[0];
+    let sample_count = context.sample_count;
+
+    let target = Rc::new(RefCell::new(MsaaTarget::new(context, width, height, format, sample_count, format)?));
+    context
+        .resize_registry
+        .register_resource(&target, |target, context, width, height| target.resize(context, width, height));
+    Ok(Some(target))
+}
+
+// Color attachment of a pass drawing to the frame, through the msaa target when there is one
+pub fn get_surface_color_attachment<'a>(
+    context: &GpuContext,
+    msaa_target: Option<&'a MsaaTarget>,
+    frame_view: &'a TextureView,
+    load: wgpu::LoadOp<wgpu::Color>,
+) -> Result<wgpu::RenderPassColorAttachment<'a>, Error> {
+    match msaa_target {
+        Some(target) => target.color_attachment(frame_view, context.config.view_formats[0], load),
+        None => Ok(wgpu::RenderPassColorAttachment {
+            view: frame_view,
+            resolve_target: None,
+            ops: wgpu::Operations {
+                load,
+                store: wgpu::StoreOp::Store,
+            },
+        }),
+    }
+}
+
+fn get_msaa_descriptor(width: u32, height: u32, format: wgpu::TextureFormat, sample_count: u32) -> wgpu::TextureDescriptor<'static> {
+    wgpu::TextureDescriptor {
+        label: Some("msaa target"),
+        size: wgpu::Extent3d {
+            width: width.max(1),
+            height: height.max(1),
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count,
+        dimension: wgpu::TextureDimension::D2,
+        format,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+        view_formats: &[],
+    }
+}
+
+// The resolve texture keeps the msaa format, only the blit changes the format
+fn create_resolve_conversion(
+    context: &mut GpuContext,
+    msaa_descriptor: &wgpu::TextureDescriptor,
+    output_format: wgpu::TextureFormat,
+) -> ResolveConversion {
+    // same size as the output, each pixel reads exactly one texel
+    let sampler = context.device.create_sampler(&wgpu::SamplerDescriptor {
+        label: Some("msaa resolve sampler"),
+        ..Default::default()
+    });
+
+    let pipeline = get_mipmap_pipeline(context, output_format);
+    let bind_group_layout = get_or_create_bind_group_layout(context, MIPMAP_BIND_GROUP_LAYOUT, create_mipmap_bind_group_layout);
+    let (view, bind_group) = create_resolve_bind_group(context, msaa_descriptor, &bind_group_layout, &sampler);
+
+    ResolveConversion {
+        view,
+        bind_group,
+        bind_group_layout,
+        sampler,
+        pipeline,
+    }
+}
+
+fn create_resolve_bind_group(
+    context: &GpuContext,
+    msaa_descriptor: &wgpu::TextureDescriptor,
+    bind_group_layout: &BindGroupLayout,
+    sampler: &wgpu::Sampler,
+) -> (TextureView, BindGroup) {
+    let texture = context.device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("msaa resolve"),
+        sample_count: 1,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+        ..*msaa_descriptor
+    });
+    let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+    let bind_group = context.device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout: bind_group_layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(&view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::Sampler(sampler),
+            },
+        ],
+        label: Some("msaa resolve bind group"),
+    });
+
+    (view, bind_group)
+}
+
+// A resolve target must have the format of the multisampled texture
+pub fn check_resolve_format(msaa_format: wgpu::TextureFormat, resolve_format: wgpu::TextureFormat) -> Result<(), Error> {
+    if msaa_format != resolve_format {
+        return Err(ValidationError(format!(
+            "msaa format {:?} can't be resolved into a {:?} target, resolve into {:?} and blit to the target",
+            msaa_format, resolve_format, msaa_format
+        )));
+    }
+    Ok(())
+}
+
+pub fn check_sample_count(
+    format: wgpu::TextureFormat,
+    format_flags: wgpu::TextureFormatFeatureFlags,
+    sample_count: u32,
+) -> Result<(), Error> {
+    // a single sampled texture can't have a resolve target
+    if sample_count < 2 {
+        return Err(ValidationError(format!("msaa needs at least 2 samples, got {}", sample_count)));
+    }
+    if !format_flags.contains(wgpu::TextureFormatFeatureFlags::MULTISAMPLE_RESOLVE) {
+        return Err(UnsupportedError(format!("format {:?} can't be multisample resolved", format)));
+    }
+    if !format_flags.sample_count_supported(sample_count) {
+        return Err(UnsupportedError(format!(
+            "format {:?} doesn't support {} samples, supported counts are {:?}",
+            format,
+            sample_count,
+            format_flags.supported_sample_counts()
+        )));
+    }
+    Ok(())
+}
+
+// Sample count for the surface color and depth targets, 1 disables msaa
+pub fn check_context_sample_count(
+    color_format: wgpu::TextureFormat,
+    color_flags: wgpu::TextureFormatFeatureFlags,
+    depth_flags: wgpu::TextureFormatFeatureFlags,
+    sample_count: u32,
+) -> Result<(), Error> {
+    if sample_count == 1 {
+        return Ok(());
+    }
+    check_sample_count(color_format, color_flags, sample_count)?;
+    if !depth_flags.sample_count_supported(sample_count) {
+        return Err(UnsupportedError(format!(
+            "depth format {:?} doesn't support {} samples, supported counts are {:?}",
+            DEPTH_FORMAT,
+            sample_count,
+            depth_flags.supported_sample_counts()
+        )));
+    }
+    Ok(())
+}
+
+// The requested count when the formats support it, otherwise 1x
+pub fn get_supported_sample_count(
+    color_format: wgpu::TextureFormat,
+    color_flags: wgpu::TextureFormatFeatureFlags,
+    depth_flags: wgpu::TextureFormatFeatureFlags,
+    sample_count: u32,
+) -> u32 {
+    match check_context_sample_count(color_format, color_flags, depth_flags, sample_count) {
+        Ok(()) => sample_count,
+        Err(error) => {
+            warn!("falling back to 1x msaa: {:?}", error);
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::msaa::{check_context_sample_count, check_resolve_format, check_sample_count, get_supported_sample_count};
+
+    #[test]
+    fn test_resolve_format_mismatch() {
+        assert!(check_resolve_format(wgpu::TextureFormat::Rgba16Float, wgpu::TextureFormat::Rgba16Float).is_ok());
+
+        let result = check_resolve_format(wgpu::TextureFormat::Rgba16Float, wgpu::TextureFormat::Bgra8UnormSrgb);
+        let Err(Error::ValidationError(message)) = result else {
+            panic!("expected a validation error, got {:?}", result);
+        };
+        assert!(message.contains("Rgba16Float") && message.contains("Bgra8UnormSrgb"));
+    }
+
+    #[test]
+    fn test_sample_count() {
+        let flags = wgpu::TextureFormatFeatureFlags::MULTISAMPLE_X4 | wgpu::TextureFormatFeatureFlags::MULTISAMPLE_RESOLVE;
+        let format = wgpu::TextureFormat::Rgba16Float;
+
+        assert!(matches!(check_sample_count(format, flags, 1), Err(Error::ValidationError(_))));
+        assert!(check_sample_count(format, flags, 4).is_ok());
+        assert!(matches!(check_sample_count(format, flags, 8), Err(Error::UnsupportedError(_))));
+
+        let no_resolve = wgpu::TextureFormatFeatureFlags::MULTISAMPLE_X4;
+        assert!(matches!(check_sample_count(format, no_resolve, 4), Err(Error::UnsupportedError(_))));
+    }
+
+    #[test]
+    fn test_context_sample_count_fallback() {
+        let format = wgpu::TextureFormat::Bgra8UnormSrgb;
+        let color_flags = wgpu::TextureFormatFeatureFlags::MULTISAMPLE_X4 | wgpu::TextureFormatFeatureFlags::MULTISAMPLE_RESOLVE;
+        let depth_flags = wgpu::TextureFormatFeatureFlags::MULTISAMPLE_X4;
+
+        // 1x needs no msaa support
+        let no_msaa = wgpu::TextureFormatFeatureFlags::empty();
+        assert!(check_context_sample_count(format, no_msaa, no_msaa, 1).is_ok());
+
+        assert!(check_context_sample_count(format, color_flags, depth_flags, 4).is_ok());
+        assert_eq!(get_supported_sample_count(format, color_flags, depth_flags, 4), 4);
+
+        // the depth target has to support the count too
+        let result = check_context_sample_count(format, color_flags, no_msaa, 4);
+        assert!(matches!(result, Err(Error::UnsupportedError(_))));
+        assert_eq!(get_supported_sample_count(format, color_flags, no_msaa, 4), 1);
+
+        assert_eq!(get_supported_sample_count(format, color_flags, depth_flags, 8), 1);
+        assert_eq!(get_supported_sample_count(format, color_flags, depth_flags, 0), 1);
+    }
+}
@@ -0,0 +1,242 @@
+use wgpu::{BindGroupLayout, RenderPipeline, ShaderModule};
+
+use crate::error::Error;
+use crate::gpu_context::GpuContext;
+#[cfg(feature = "reflection")]
+use crate::shader_bindings::check_shader_bindings;
+
+pub const DEFAULT_DEPTH_COMPARE: wgpu::CompareFunction = wgpu::CompareFunction::Less;
+
+// Render pipeline for custom passes. Depth testing is enabled by setting a depth format.
+#[derive(Debug, Clone)]
+pub struct PipelineBuilder<'a> {
+    pub label: &'a str,
+    pub vertex_entry: &'a str,
+    pub fragment_entry: &'a str,
+    pub vertex_buffers: Vec<wgpu::VertexBufferLayout<'a>>,
+    pub bind_group_layouts: Vec<&'a BindGroupLayout>,
+    pub color_targets: Vec<Option<wgpu::ColorTargetState>>,
+    pub depth_format: Option<wgpu::TextureFormat>,
+    // Greater or GreaterEqual for reverse-z, LessEqual to draw a skybox at the far plane
+    pub depth_compare: wgpu::CompareFunction,
+    pub depth_write_enabled: bool,
+    // only applied when the device has DEPTH_CLIP_CONTROL, see get_primitive_state
+    pub unclipped_depth: bool,
+    pub primitive: wgpu::PrimitiveState,
+    // 1 by default for offscreen targets, passes into the msaa surface target opt in with surface_sample_count
+    pub sample_count: u32,
+    pub surface_samples: bool,
+}
+
+impl<'a> PipelineBuilder<'a> {
+    pub fn new(vertex_entry: &'a str, fragment_entry: &'a str) -> Self {
+        PipelineBuilder {
+            label: "pipeline",
+            vertex_entry,
+            fragment_entry,
+            vertex_buffers: vec![],
+            bind_group_layouts: vec![],
+            color_targets: vec![],
+            depth_format: None,
+            depth_compare: DEFAULT_DEPTH_COMPARE,
+            depth_write_enabled: true,
+            unclipped_depth: false,
+            primitive: wgpu::PrimitiveState {
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: Some(wgpu::Face::Back),
+                ..Default::default()
+            },
+            sample_count: 1,
+            surface_samples: false,
+        }
+    }
+
+    pub fn label(mut self, label: &'a str) -> Self {
+        self.label = label;
+        self
+    }
+
+    pub fn vertex_buffer(mut self, layout: wgpu::VertexBufferLayout<'a>) -> Self {
+        self.vertex_buffers.push(layout);
+        self
+    }
+
+    pub fn bind_group_layout(mut self, layout: &'a BindGroupLayout) -> Self {
+        self.bind_group_layouts.push(layout);
+        self
+    }
+
+    pub fn color_target(mut self, target: impl Into<wgpu::ColorTargetState>) -> Self {
+        self.color_targets.push(Some(target.into()));
+        self
+    }
+
+    pub fn depth(mut self, format: wgpu::TextureFormat) -> Self {
+        self.depth_format = Some(format);
+        self
+    }
+
+    pub fn depth_compare(mut self, compare: wgpu::CompareFunction) -> Self {
+        self.depth_compare = compare;
+        self
+    }
+
+    pub fn depth_write(mut self, enabled: bool) -> Self {
+        self.depth_write_enabled = enabled;
+        self
+    }
+
+    pub fn primitive(mut self, primitive: wgpu::PrimitiveState) -> Self {
+        self.primitive = primitive;
+        self
+    }
+
+    // Depth outside the near and far planes is clamped instead of clipped
+    pub fn unclipped_depth(mut self, enabled: bool) -> Self {
+        self.unclipped_depth = enabled;
+        self
+    }
+
+    pub fn sample_count(mut self, sample_count: u32) -> Self {
+        self.sample_count = sample_count;
+        self.surface_samples = false;
+        self
+    }
+
+    // Uses the context's sample count, for pipelines drawing into the surface or its msaa target
+    pub fn surface_sample_count(mut self) -> Self {
+        self.surface_samples = true;
+        self
+    }
+
+    pub fn get_primitive_state(&self, features: wgpu::Features) -> wgpu::PrimitiveState {
+        get_unclipped_primitive_state(self.primitive, self.unclipped_depth, features)
+    }
+
+    pub fn get_depth_stencil_state(&self) -> Option<wgpu::DepthStencilState> {
+        self.depth_format.map(|format| wgpu::DepthStencilState {
+            format,
+            depth_write_enabled: self.depth_write_enabled,
+            depth_compare: self.depth_compare,
+            stencil: wgpu::StencilState::default(),
+            bias: wgpu::DepthBiasState::default(),
+        })
+    }
+
+    pub fn get_multisample_state(&self, context_sample_count: u32) -> wgpu::MultisampleState {
+        wgpu::MultisampleState {
+            count: match self.surface_samples {
+                true => context_sample_count,
+                false => self.sample_count,
+            },
+            ..Default::default()
+        }
+    }
+
+    // BindGroupLayouts can't be inspected, so the entries each layout was created from are passed
+    // in group order. Mismatches are reported here instead of as validation errors at draw time.
+    // build() does the same check for registered layouts and shaders, see BindingRegistry.
+    #[cfg(feature = "reflection")]
+    pub fn check_bindings(&self, source: &str, layout_entries: &[&[wgpu::BindGroupLayoutEntry]]) -> Result<(), Error> {
+        check_shader_bindings(source, &[self.vertex_entry, self.fragment_entry], layout_entries)
+    }
+
+    // A ValidationError when the bindings don't match the layouts, see BindingRegistry
+    pub fn build(&self, context: &GpuContext, shader: &ShaderModule) -> Result<RenderPipeline, Error> {
+        context.binding_registry.check_pipeline(
+            self.label,
+            shader,
+            &[self.vertex_entry, self.fragment_entry],
+            &self.bind_group_layouts,
+        )?;
+
+        let pipeline_layout = context.device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some(self.label),
+            bind_group_layouts: &self.bind_group_layouts,
+            push_constant_ranges: &[],
+        });
+
+        Ok(context.device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some(self.label),
+            layout: Some(&pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: shader,
+                entry_point: self.vertex_entry,
+                buffers: &self.vertex_buffers,
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: shader,
+                entry_point: self.fragment_entry,
+                targets: &self.color_targets,
+            }),
+            primitive: self.get_primitive_state(context.device.features()),
+            depth_stencil: self.get_depth_stencil_state(),
+            multisample: self.get_multisample_state(context.sample_count),
+            multiview: None,
+        }))
+    }
+}
+
+// Unclipped depth needs DEPTH_CLIP_CONTROL, without it the primitive is clipped as usual
+pub fn get_unclipped_primitive_state(
+    primitive: wgpu::PrimitiveState,
+    unclipped_depth: bool,
+    features: wgpu::Features,
+) -> wgpu::PrimitiveState {
+    wgpu::PrimitiveState {
+        unclipped_depth: unclipped_depth && features.contains(wgpu::Features::DEPTH_CLIP_CONTROL),
+        ..primitive
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pipeline_builder::PipelineBuilder;
+
+    #[test]
+    fn test_depth_compare() {
+        let builder = PipelineBuilder::new("vs_main", "fs_main").color_target(wgpu::TextureFormat::Rgba8Unorm);
+        assert!(builder.get_depth_stencil_state().is_none());
+
+        let depth_state = builder.clone().depth(wgpu::TextureFormat::Depth32Float).get_depth_stencil_state().unwrap();
+        assert_eq!(depth_state.depth_compare, wgpu::CompareFunction::Less);
+
+        let depth_state = builder
+            .depth(wgpu::TextureFormat::Depth32Float)
+            .depth_compare(wgpu::CompareFunction::GreaterEqual)
+            .get_depth_stencil_state()
+            .unwrap();
+        assert_eq!(depth_state.depth_compare, wgpu::CompareFunction::GreaterEqual);
+        assert_eq!(depth_state.format, wgpu::TextureFormat::Depth32Float);
+    }
+
+    #[test]
+    fn test_unclipped_depth() {
+        let builder = PipelineBuilder::new("vs_main", "fs_main");
+        assert!(!builder.get_primitive_state(wgpu::Features::DEPTH_CLIP_CONTROL).unclipped_depth);
+
+        let builder = builder.unclipped_depth(true);
+        assert!(builder.get_primitive_state(wgpu::Features::DEPTH_CLIP_CONTROL).unclipped_depth);
+        // falls back to clipping without the feature
+        assert!(!builder.get_primitive_state(wgpu::Features::empty()).unclipped_depth);
+        assert_eq!(
+            builder.get_primitive_state(wgpu::Features::empty()).cull_mode,
+            Some(wgpu::Face::Back)
+        );
+    }
+
+    #[test]
+    fn test_multisample_state() {
+        // offscreen passes stay single sampled with msaa on
+        let builder = PipelineBuilder::new("vs_main", "fs_main");
+        assert_eq!(builder.get_multisample_state(4).count, 1);
+        assert_eq!(builder.get_multisample_state(4).mask, !0);
+
+        let surface = builder.clone().surface_sample_count();
+        assert_eq!(surface.get_multisample_state(4).count, 4);
+        assert_eq!(surface.get_multisample_state(1).count, 1);
+
+        // an explicit count overrides the surface one
+        assert_eq!(surface.sample_count(2).get_multisample_state(4).count, 2);
+    }
+}
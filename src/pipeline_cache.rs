@@ -0,0 +1,180 @@
+use std::rc::Rc;
+
+use wgpu::{BindGroupLayout, RenderPipeline, ShaderModule};
+
+use crate::error::Error;
+use crate::gpu_context::GpuContext;
+use crate::hash_map::HashMap;
+use crate::pipeline_builder::PipelineBuilder;
+
+// Everything a PipelineBuilder sets that changes the pipeline it builds, except the label.
+// Resources are compared by id, so a recreated shader module or layout is a new key.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PipelineKey {
+    pub shader: wgpu::Id<ShaderModule>,
+    pub vertex_entry: String,
+    pub fragment_entry: String,
+    pub vertex_buffers: Vec<VertexBufferKey>,
+    pub bind_group_layouts: Vec<wgpu::Id<BindGroupLayout>>,
+    pub color_targets: Vec<Option<wgpu::ColorTargetState>>,
+    pub depth_stencil: Option<wgpu::DepthStencilState>,
+    pub primitive: wgpu::PrimitiveState,
+    pub multisample: wgpu::MultisampleState,
+}
+
+// Owned copy of a wgpu::VertexBufferLayout
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct VertexBufferKey {
+    pub array_stride: wgpu::BufferAddress,
+    pub step_mode: wgpu::VertexStepMode,
+    pub attributes: Vec<wgpu::VertexAttribute>,
+}
+
+impl PipelineKey {
+    // The states are resolved the way PipelineBuilder::build resolves them for the context
+    pub fn new(context: &GpuContext, builder: &PipelineBuilder, shader: &ShaderModule) -> Self {
+        PipelineKey {
+            shader: shader.global_id(),
+            vertex_entry: builder.vertex_entry.to_string(),
+            fragment_entry: builder.fragment_entry.to_string(),
+            vertex_buffers: builder
+                .vertex_buffers
+                .iter()
+                .map(|layout| VertexBufferKey {
+                    array_stride: layout.array_stride,
+                    step_mode: layout.step_mode,
+                    attributes: layout.attributes.to_vec(),
+                })
+                .collect(),
+            bind_group_layouts: builder.bind_group_layouts.iter().map(|layout| layout.global_id()).collect(),
+            color_targets: builder.color_targets.clone(),
+            depth_stencil: builder.get_depth_stencil_state(),
+            primitive: builder.get_primitive_state(context.device.features()),
+            multisample: builder.get_multisample_state(context.sample_count),
+        }
+    }
+}
+
+// Render pipelines shared between passes that build the same configuration, e.g. the forward,
+// debug and shadow passes of several views. The first label a configuration is built with is kept.
+// GpuContext holds one, used through GpuContext::get_or_build_pipeline.
+//
+// Pipelines are cached for the lifetime of the cache, drop the ones of a changed shader with
+// invalidate. HotReloadShader::poll_reload does that for the context's cache. wgpu 0.19 has no
+// driver level pipeline cache, so nothing is persisted between runs.
+//
+//   let pipeline = context.get_or_build_pipeline(&PipelineBuilder::new("vs_main", "fs_main").depth(DEPTH_FORMAT), shader.module())?;
+#[derive(Debug, Default)]
+pub struct PipelineCache {
+    pipelines: HashMap<PipelineKey, Rc<RenderPipeline>>,
+}
+
+impl PipelineCache {
+    pub fn new() -> Self {
+        PipelineCache { pipelines: HashMap::new() }
+    }
+
+    pub fn get_or_build(
+        &mut self,
+        context: &GpuContext,
+        builder: &PipelineBuilder,
+        shader: &ShaderModule,
+    ) -> Result<Rc<RenderPipeline>, Error> {
+        let key = PipelineKey::new(context, builder, shader);
+        if let Some(pipeline) = self.pipelines.get(&key) {
+            return Ok(pipeline.clone());
+        }
+        let pipeline = Rc::new(builder.build(context, shader)?);
+        self.pipelines.insert(key, pipeline.clone());
+        Ok(pipeline)
+    }
+
+    pub fn get(&self, key: &PipelineKey) -> Option<Rc<RenderPipeline>> {
+        self.pipelines.get(key).cloned()
+    }
+
+    // Drops the pipelines built from shader, returns how many. Passes still holding one keep it
+    // until they rebuild.
+    pub fn invalidate(&mut self, shader: wgpu::Id<ShaderModule>) -> usize {
+        let count = self.pipelines.len();
+        self.pipelines.retain(|key, _| key.shader != shader);
+        count - self.pipelines.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.pipelines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pipelines.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.pipelines.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use crate::gpu_context::GpuContext;
+    use crate::pipeline_builder::PipelineBuilder;
+    use crate::pipeline_cache::{PipelineCache, PipelineKey};
+    use crate::texture::DEPTH_FORMAT;
+
+    const SHADER: &str = r"
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> @builtin(position) vec4<f32> {
+    return vec4<f32>(f32(index), 0.0, 0.0, 1.0);
+}
+
+@fragment
+fn fs_main() -> @location(0) vec4<f32> {
+    return vec4<f32>(1.0);
+}
+";
+
+    #[test]
+    #[cfg_attr(not(feature = "gpu_tests"), ignore = "needs a gpu adapter")]
+    fn test_pipeline_cache() {
+        let context = pollster::block_on(GpuContext::new_headless(4, 4)).unwrap();
+        let shader = context.device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("cached"),
+            source: wgpu::ShaderSource::Wgsl(SHADER.into()),
+        });
+        let mut cache = PipelineCache::new();
+
+        let builder = PipelineBuilder::new("vs_main", "fs_main").color_target(wgpu::TextureFormat::Rgba8Unorm);
+        let pipeline = cache.get_or_build(&context, &builder, &shader).unwrap();
+
+        // the label isn't part of the key
+        let same = cache.get_or_build(&context, &builder.clone().label("other pass"), &shader).unwrap();
+        assert!(Rc::ptr_eq(&pipeline, &same));
+        assert_eq!(cache.len(), 1);
+
+        let with_depth = builder.clone().depth(DEPTH_FORMAT);
+        assert_ne!(
+            PipelineKey::new(&context, &builder, &shader),
+            PipelineKey::new(&context, &with_depth, &shader)
+        );
+        let depth_pipeline = cache.get_or_build(&context, &with_depth, &shader).unwrap();
+        assert!(!Rc::ptr_eq(&pipeline, &depth_pipeline));
+        assert_eq!(cache.len(), 2);
+
+        // a reloaded shader is a new module
+        let other_shader = context.device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("reloaded"),
+            source: wgpu::ShaderSource::Wgsl(SHADER.into()),
+        });
+        cache.get_or_build(&context, &builder, &other_shader).unwrap();
+        assert_eq!(cache.invalidate(shader.global_id()), 2);
+        assert_eq!(cache.len(), 1);
+        assert!(cache.get(&PipelineKey::new(&context, &builder, &other_shader)).is_some());
+
+        // the context's cache
+        let pipeline = context.get_or_build_pipeline(&builder, &shader).unwrap();
+        assert!(Rc::ptr_eq(&pipeline, &context.get_or_build_pipeline(&builder, &shader).unwrap()));
+        assert_eq!(context.pipeline_cache.borrow().len(), 1);
+    }
+}
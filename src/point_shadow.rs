@@ -0,0 +1,371 @@
+use bytemuck::{Pod, Zeroable};
+use glam::{vec3, Mat4, Vec3};
+
+use crate::buffers::UniformBuffer;
+use crate::error::Error;
+use crate::error::Error::ValidationError;
+use crate::gpu_context::GpuContext;
+use crate::shadow_cascades::ShadowTextureArray;
+
+// Wgsl PointShadowLight struct matching PointShadowUniform, with get_point_shadow_depth for the face
+// passes and sample_point_shadow for shading
+pub const POINT_SHADOW_WGSL: &str = include_str!("shaders/point_shadow.wgsl");
+
+pub const CUBE_FACE_COUNT: usize = 6;
+
+// Forward directions and up vectors of the faces in wgpu's layer order +x, -x, +y, -y, +z, -z
+const CUBE_FACES: [(Vec3, Vec3); CUBE_FACE_COUNT] = [
+    (vec3(1.0, 0.0, 0.0), vec3(0.0, -1.0, 0.0)),
+    (vec3(-1.0, 0.0, 0.0), vec3(0.0, -1.0, 0.0)),
+    (vec3(0.0, 1.0, 0.0), vec3(0.0, 0.0, 1.0)),
+    (vec3(0.0, -1.0, 0.0), vec3(0.0, 0.0, -1.0)),
+    (vec3(0.0, 0.0, 1.0), vec3(0.0, -1.0, 0.0)),
+    (vec3(0.0, 0.0, -1.0), vec3(0.0, -1.0, 0.0)),
+];
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Pod, Zeroable)]
+pub struct PointShadowUniform {
+    pub face_projection_views: [[[f32; 4]; 4]; CUBE_FACE_COUNT],
+    // the light's position in xyz
+    pub position: [f32; 4],
+    // near, far and 1 / (far - near)
+    pub depth_range: [f32; 4],
+}
+
+impl PointShadowUniform {
+    pub fn new(position: Vec3, near: f32, far: f32) -> Self {
+        PointShadowUniform {
+            face_projection_views: get_cube_face_projection_views(position, near, far).map(|matrix| matrix.to_cols_array_2d()),
+            position: position.extend(1.0).to_array(),
+            depth_range: [near, far, 1.0 / (far - near), 0.0],
+        }
+    }
+}
+
+// Omnidirectional shadow of a point light. Each face of a depth cube is rendered in its own pass
+// into shadow_maps.layer_views[i] with face_projection_views[i], then shadow_maps.view, a cube
+// view, is sampled with the vector from the light to the fragment.
+//
+// The face passes write the distance to the light, mapped linearly from near..far to 0..1 by
+// get_point_shadow_depth, instead of the projection's depth. Its precision is the same at every
+// distance, where perspective depth would spend most of it close to the near plane.
+pub struct PointShadow {
+    pub resolution: u32,
+    pub near: f32,
+    pub far: f32,
+    pub shadow_maps: ShadowTextureArray,
+    // of the last update
+    pub face_projection_views: [Mat4; CUBE_FACE_COUNT],
+    pub uniform: UniformBuffer<PointShadowUniform>,
+}
+
+impl PointShadow {
+    pub fn new(context: &GpuContext, resolution: u32, near: f32, far: f32, label: &str) -> Result<Self, Error> {
+        check_point_shadow(resolution, near, far, &context.device.limits())?;
+
+        let shadow_maps = ShadowTextureArray::new(context, resolution, CUBE_FACE_COUNT as u32, wgpu::TextureViewDimension::Cube, label);
+
+        let uniform = PointShadowUniform::new(Vec3::ZERO, near, far);
+
+        Ok(PointShadow {
+            resolution,
+            near,
+            far,
+            shadow_maps,
+            face_projection_views: get_cube_face_projection_views(Vec3::ZERO, near, far),
+            uniform: UniformBuffer::new(context, &uniform, wgpu::BufferUsages::empty()),
+        })
+    }
+
+    // Moves the faces to the light and uploads their matrices
+    pub fn update(&mut self, context: &GpuContext, position: Vec3) {
+        self.face_projection_views = get_cube_face_projection_views(position, self.near, self.far);
+        self.uniform
+            .update(context, &PointShadowUniform::new(position, self.near, self.far));
+    }
+
+    // Clears and stores the face, for the shadow pass rendering it
+    pub fn get_depth_attachment(&self, face: usize) -> wgpu::RenderPassDepthStencilAttachment<'_> {
+        self.shadow_maps.get_depth_attachment(face as u32)
+    }
+}
+
+pub fn check_point_shadow(resolution: u32, near: f32, far: f32, limits: &wgpu::Limits) -> Result<(), Error> {
+    if resolution == 0 || resolution > limits.max_texture_dimension_2d {
+        return Err(ValidationError(format!(
+            "point shadow resolution {} outside of 1 to {}",
+            resolution, limits.max_texture_dimension_2d
+        )));
+    }
+    if !(near > 0.0 && far > near) {
+        return Err(ValidationError(format!(
+            "point shadow depth {} to {} isn't a valid range",
+            near, far
+        )));
+    }
+    Ok(())
+}
+
+// The 90 degree perspective shared by the faces. Y is flipped since wgpu's framebuffer rows go
+// down where the cube face's v goes up, which also flips the winding, see get_cube_face_primitive_state.
+pub fn get_cube_face_projection(near: f32, far: f32) -> Mat4 {
+    Mat4::from_scale(vec3(1.0, -1.0, 1.0)) * Mat4::perspective_rh(std::f32::consts::FRAC_PI_2, 1.0, near, far)
+}
+
+pub fn get_cube_face_projection_views(position: Vec3, near: f32, far: f32) -> [Mat4; CUBE_FACE_COUNT] {
+    let projection = get_cube_face_projection(near, far);
+    CUBE_FACES.map(|(forward, up)| projection * Mat4::look_at_rh(position, position + forward, up))
+}
+
+// The primitive state of a caster pipeline for the faces, whose projection mirrors the winding
+pub fn get_cube_face_primitive_state(primitive: wgpu::PrimitiveState) -> wgpu::PrimitiveState {
+    let front_face = match primitive.front_face {
+        wgpu::FrontFace::Ccw => wgpu::FrontFace::Cw,
+        wgpu::FrontFace::Cw => wgpu::FrontFace::Ccw,
+    };
+    wgpu::PrimitiveState { front_face, ..primitive }
+}
+
+// Cpu version of get_point_shadow_depth in point_shadow.wgsl, for a point at distance from the light
+pub fn get_point_shadow_depth(distance: f32, near: f32, far: f32) -> f32 {
+    ((distance - near) / (far - near)).clamp(0.0, 1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+
+    use glam::{vec2, vec3, Vec2, Vec3};
+
+    use crate::bind_group::{BindGroupBuilder, LayoutBuilder};
+    use crate::gpu_context::GpuContext;
+    use crate::pipeline_builder::PipelineBuilder;
+    use crate::point_shadow::{
+        check_point_shadow, get_cube_face_primitive_state, get_cube_face_projection_views, get_point_shadow_depth, PointShadow,
+        PointShadowUniform, CUBE_FACE_COUNT,
+    };
+    use crate::render::RenderPassBuilder;
+    use crate::shader_preprocessor::expand_embedded_includes;
+    use crate::snapshot::read_texture_region;
+    use crate::texture::{SamplerBuilder, DEPTH_FORMAT};
+
+    // fs_face writes the depth of a caster 5.5 in front of the light on every texel of a face,
+    // fs_sample shades the points at offsets from the light, one per pixel, with the lit amount in
+    // r and the point's own depth in g
+    const TEST_WGSL: &str = r#"
+//!include "spark_gap/point_shadow.wgsl"
+//!include "spark_gap/fullscreen.wgsl"
+
+@group(0) @binding(0) var<uniform> light: PointShadowLight;
+@group(0) @binding(1) var shadow_cube: texture_depth_cube;
+@group(0) @binding(2) var shadow_sampler: sampler_comparison;
+
+@fragment fn fs_face() -> @builtin(frag_depth) f32 {
+    return get_point_shadow_depth(light, light.position.xyz + vec3<f32>(5.5, 0.0, 0.0));
+}
+
+@fragment fn fs_sample(@builtin(position) position: vec4<f32>) -> @location(0) vec4<f32> {
+    var offsets = array<vec3<f32>, 4>(vec3<f32>(3.0, 0.3, 0.1), vec3<f32>(8.0, 0.3, 0.1), vec3<f32>(-8.0, 0.0, 0.0), vec3<f32>(80.0, 0.0, 0.0));
+    let world_position = light.position.xyz + offsets[u32(position.x)];
+    let lit = sample_point_shadow(shadow_cube, shadow_sampler, light, world_position, 0.05);
+    return vec4<f32>(lit, get_point_shadow_depth(light, world_position), 0.0, 1.0);
+}
+"#;
+
+    // offsets in fs_sample
+    const OFFSETS: [Vec3; 4] = [vec3(3.0, 0.3, 0.1), vec3(8.0, 0.3, 0.1), vec3(-8.0, 0.0, 0.0), vec3(80.0, 0.0, 0.0)];
+
+    // The face and uv a direction samples, from the cube map table of the vulkan and webgpu specs
+    fn get_cube_coords(direction: Vec3) -> (usize, Vec2) {
+        let abs = direction.abs();
+        let (face, sc, tc, ma) = if abs.x >= abs.y && abs.x >= abs.z {
+            match direction.x > 0.0 {
+                true => (0, -direction.z, -direction.y, abs.x),
+                false => (1, direction.z, -direction.y, abs.x),
+            }
+        } else if abs.y >= abs.z {
+            match direction.y > 0.0 {
+                true => (2, direction.x, direction.z, abs.y),
+                false => (3, direction.x, -direction.z, abs.y),
+            }
+        } else {
+            match direction.z > 0.0 {
+                true => (4, direction.x, -direction.y, abs.z),
+                false => (5, -direction.x, -direction.y, abs.z),
+            }
+        };
+        (face, (vec2(sc, tc) / ma + 1.0) * 0.5)
+    }
+
+    #[test]
+    fn test_cube_faces() {
+        let light = vec3(1.0, -2.0, 3.0);
+        let faces = get_cube_face_projection_views(light, 0.1, 50.0);
+        assert_eq!(faces.len(), CUBE_FACE_COUNT);
+
+        let directions = [
+            vec3(2.0, 0.5, -0.3),
+            vec3(-3.0, -1.0, 0.8),
+            vec3(0.2, 4.0, 1.0),
+            vec3(-0.7, -2.0, 0.4),
+            vec3(0.4, 0.6, 5.0),
+            vec3(-0.9, 0.1, -3.0),
+        ];
+        for direction in directions {
+            let (face, uv) = get_cube_coords(direction);
+
+            // the face the sample reads renders the point at the same texel
+            let ndc = faces[face].project_point3(light + direction);
+            let rendered_uv = vec2(ndc.x * 0.5 + 0.5, 0.5 - ndc.y * 0.5);
+            assert!(
+                rendered_uv.abs_diff_eq(uv, 1e-4),
+                "face {}: {} rendered at {}",
+                face,
+                uv,
+                rendered_uv
+            );
+            assert!(ndc.z > 0.0 && ndc.z < 1.0);
+        }
+
+        let primitive = get_cube_face_primitive_state(wgpu::PrimitiveState::default());
+        assert_eq!(primitive.front_face, wgpu::FrontFace::Cw);
+    }
+
+    #[test]
+    fn test_point_shadow_depth() {
+        // linear over the depth range
+        assert_eq!(get_point_shadow_depth(0.5, 0.5, 10.5), 0.0);
+        assert_eq!(get_point_shadow_depth(5.5, 0.5, 10.5), 0.5);
+        assert_eq!(get_point_shadow_depth(10.5, 0.5, 10.5), 1.0);
+
+        // casters beyond the range are clamped
+        assert_eq!(get_point_shadow_depth(0.1, 0.5, 10.5), 0.0);
+        assert_eq!(get_point_shadow_depth(80.0, 0.5, 10.5), 1.0);
+    }
+
+    #[test]
+    fn test_point_shadow_uniform() {
+        let uniform = PointShadowUniform::new(vec3(1.0, 2.0, 3.0), 0.5, 10.5);
+        assert_eq!(uniform.position, [1.0, 2.0, 3.0, 1.0]);
+        assert_eq!(uniform.depth_range, [0.5, 10.5, 0.1, 0.0]);
+
+        let limits = wgpu::Limits::downlevel_webgl2_defaults();
+        assert!(check_point_shadow(1024, 0.1, 50.0, &limits).is_ok());
+        assert!(check_point_shadow(0, 0.1, 50.0, &limits).is_err());
+        assert!(check_point_shadow(1024, 0.0, 50.0, &limits).is_err());
+        assert!(check_point_shadow(1024, 5.0, 5.0, &limits).is_err());
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "gpu_tests"), ignore = "needs a gpu adapter")]
+    fn test_point_shadow_sampling() {
+        let context = pollster::block_on(GpuContext::new_headless(4, 4)).unwrap();
+        let light = vec3(1.0, 2.0, 3.0);
+        let mut point_shadow = PointShadow::new(&context, 4, 0.5, 10.5, "point shadow").unwrap();
+        point_shadow.update(&context, light);
+
+        let source = expand_embedded_includes("point shadow test", TEST_WGSL, &[]).unwrap().source;
+        let shader = context.device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("point shadow test"),
+            source: wgpu::ShaderSource::Wgsl(source.into()),
+        });
+        let face_layout = LayoutBuilder::new()
+            .uniform(wgpu::ShaderStages::FRAGMENT)
+            .build(&context, "point shadow face")
+            .unwrap();
+        let sample_layout = LayoutBuilder::new()
+            .uniform(wgpu::ShaderStages::FRAGMENT)
+            .texture(
+                wgpu::ShaderStages::FRAGMENT,
+                wgpu::TextureSampleType::Depth,
+                wgpu::TextureViewDimension::Cube,
+            )
+            .sampler_comparison(wgpu::ShaderStages::FRAGMENT)
+            .build(&context, "point shadow sample")
+            .unwrap();
+        let face_pipeline = PipelineBuilder::new("vs_fullscreen", "fs_face")
+            .bind_group_layout(&face_layout)
+            .depth(DEPTH_FORMAT)
+            .primitive(wgpu::PrimitiveState::default())
+            .build(&context, &shader)
+            .unwrap();
+        let sample_pipeline = PipelineBuilder::new("vs_fullscreen", "fs_sample")
+            .bind_group_layout(&sample_layout)
+            .color_target(wgpu::TextureFormat::Rgba32Float)
+            .primitive(wgpu::PrimitiveState::default())
+            .build(&context, &shader)
+            .unwrap();
+
+        let sampler = SamplerBuilder::new().comparison(wgpu::CompareFunction::LessEqual).build(&context);
+        let uniform = &point_shadow.uniform.buffer;
+        let face_bind_group = BindGroupBuilder::new()
+            .buffer(uniform)
+            .build(&context, &face_layout, "point shadow face");
+        let sample_bind_group = BindGroupBuilder::new()
+            .buffer(uniform)
+            .texture_view(&point_shadow.shadow_maps.view)
+            .sampler(&sampler)
+            .build(&context, &sample_layout, "point shadow sample");
+
+        let output = context.device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("point shadow output"),
+            size: wgpu::Extent3d {
+                width: OFFSETS.len() as u32,
+                height: 1,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba32Float,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            view_formats: &[],
+        });
+        let output_view = output.create_view(&wgpu::TextureViewDescriptor::default());
+
+        // only the +x face has a caster, the others are cleared to the far plane
+        let mut encoder = context.device.create_command_encoder(&Default::default());
+        for face in 0..CUBE_FACE_COUNT {
+            let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                depth_stencil_attachment: Some(point_shadow.get_depth_attachment(face)),
+                ..Default::default()
+            });
+            if face == 0 {
+                pass.set_pipeline(&face_pipeline);
+                pass.set_bind_group(0, &face_bind_group, &[]);
+                pass.draw(0..3, 0..1);
+            }
+        }
+        {
+            let mut pass = RenderPassBuilder::new()
+                .color(&output_view, Some(wgpu::Color::BLACK))
+                .begin(&mut encoder);
+            pass.set_pipeline(&sample_pipeline);
+            pass.set_bind_group(0, &sample_bind_group, &[]);
+            pass.draw(0..3, 0..1);
+        }
+        context.queue.submit(std::iter::once(encoder.finish()));
+
+        let bytes = read_texture_region(&context, &output, [0, 0], OFFSETS.len() as u32, 1).unwrap();
+        let pixels: Vec<[f32; 4]> = bytemuck::pod_collect_to_vec(&bytes);
+
+        // the shader's depth matches the cpu version
+        for (pixel, offset) in pixels.iter().zip(OFFSETS) {
+            let depth = get_point_shadow_depth(offset.length(), 0.5, 10.5);
+            assert!((pixel[1] - depth).abs() < 1e-4, "{} at {} instead of {}", offset, pixel[1], depth);
+        }
+
+        // in front of and behind the caster, on a face without casters and beyond the range
+        let lit: Vec<f32> = pixels.iter().map(|pixel| pixel[0]).collect();
+        assert_eq!(lit, [1.0, 0.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn test_point_shadow_uniform_layout() {
+        // matches PointShadowLight in point_shadow.wgsl
+        assert_eq!(mem::offset_of!(PointShadowUniform, position), 384);
+        assert_eq!(mem::offset_of!(PointShadowUniform, depth_range), 400);
+        assert_eq!(mem::size_of::<PointShadowUniform>(), 416);
+    }
+}
@@ -0,0 +1,421 @@
+use std::borrow::Cow;
+use std::path::Path;
+use std::rc::Rc;
+
+use glam::{vec3, Vec3};
+use wgpu::{BindGroupLayout, Buffer, RenderPipeline, Sampler, TextureView};
+
+use crate::buffers::{create_uniform_buffer_init, update_uniform_buffer};
+use crate::error::Error;
+use crate::error::Error::ImageError;
+use crate::gpu_context::{get_or_create_bind_group_layout, GpuContext};
+use crate::post::create_fullscreen_pipeline;
+use crate::shader_preprocessor::expand_embedded_includes;
+use crate::texture::{create_3d, upload_3d, VolumeTexture};
+
+pub const COLOR_GRADE_BIND_GROUP_LAYOUT: &str = "color grade bind group layout";
+
+pub const MAX_LUT_SIZE: u32 = 256;
+
+// A 3D lookup table in the Adobe .cube format. Entries are stored with red changing fastest,
+// which matches the x, y, z layout of the volume texture.
+#[derive(Debug, Clone)]
+pub struct CubeLut {
+    pub title: Option<String>,
+    pub size: u32,
+    pub domain_min: Vec3,
+    pub domain_max: Vec3,
+    pub data: Vec<Vec3>,
+}
+
+impl CubeLut {
+    // maps every color to itself
+    pub fn neutral(size: u32) -> Self {
+        let size = size.clamp(2, MAX_LUT_SIZE);
+        let max = (size - 1) as f32;
+        let mut data = Vec::with_capacity((size * size * size) as usize);
+        for b in 0..size {
+            for g in 0..size {
+                for r in 0..size {
+                    data.push(vec3(r as f32 / max, g as f32 / max, b as f32 / max));
+                }
+            }
+        }
+
+        CubeLut {
+            title: None,
+            size,
+            domain_min: Vec3::ZERO,
+            domain_max: Vec3::ONE,
+            data,
+        }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let text = std::fs::read_to_string(path.as_ref())?;
+        parse_cube(&text)
+    }
+
+    // Rgba8 voxel data for upload_3d
+    pub fn to_rgba8(&self) -> Vec<u8> {
+        self.data
+            .iter()
+            .flat_map(|color| {
+                let color = (color.clamp(Vec3::ZERO, Vec3::ONE) * 255.0).round();
+                [color.x as u8, color.y as u8, color.z as u8, 255]
+            })
+            .collect()
+    }
+}
+
+pub fn parse_cube(text: &str) -> Result<CubeLut, Error> {
+    let mut title = None;
+    let mut size = None;
+    let mut domain_min = Vec3::ZERO;
+    let mut domain_max = Vec3::ONE;
+    let mut data = vec![];
+
+    let parse_vec3 = |values: &[&str], line: &str| -> Result<Vec3, Error> {
+        let values: Result<Vec<f32>, _> = values.iter().map(|v| v.parse::<f32>()).collect();
+        match values {
+            Ok(values) if values.len() == 3 => Ok(vec3(values[0], values[1], values[2])),
+            _ => Err(ImageError(format!("invalid cube lut line: {}", line))),
+        }
+    };
+
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let parts: Vec<&str> = line.split_whitespace().collect();
+
+        match parts[0] {
+            "TITLE" => title = Some(line["TITLE".len()..].trim().trim_matches('"').to_string()),
+            "LUT_3D_SIZE" => {
+                let value = parts
+                    .get(1)
+                    .and_then(|v| v.parse::<u32>().ok())
+                    .ok_or(ImageError(format!("invalid cube lut size: {}", line)))?;
+                size = Some(value);
+            }
+            "DOMAIN_MIN" => domain_min = parse_vec3(&parts[1..], line)?,
+            "DOMAIN_MAX" => domain_max = parse_vec3(&parts[1..], line)?,
+            "LUT_1D_SIZE" => return Err(ImageError("1D cube luts are not supported".to_string())),
+            _ => data.push(parse_vec3(&parts, line)?),
+        }
+    }
+
+    let size = size.ok_or(ImageError("cube lut is missing LUT_3D_SIZE".to_string()))?;
+
+    if !(2..=MAX_LUT_SIZE).contains(&size) {
+        return Err(ImageError(format!("cube lut size {} not supported", size)));
+    }
+
+    if data.len() != (size * size * size) as usize {
+        return Err(ImageError(format!(
+            "cube lut has {} entries, expected {}",
+            data.len(),
+            size * size * size
+        )));
+    }
+
+    Ok(CubeLut {
+        title,
+        size,
+        domain_min,
+        domain_max,
+        data,
+    })
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct ColorGradeUniform {
+    pub domain_min: [f32; 4],
+    pub domain_max: [f32; 4],
+    pub lut_size: f32,
+    pub intensity: f32,
+    pub _padding: [f32; 2],
+}
+
+// Applies a 3D lut to a tonemapped (ldr) image, blended with the original by intensity
+pub struct ColorGradePass {
+    pub intensity: f32,
+    pipeline: RenderPipeline,
+    bind_group_layout: Rc<BindGroupLayout>,
+    sampler: Sampler,
+    uniform_buffer: Buffer,
+    lut: CubeLut,
+    lut_texture: VolumeTexture,
+}
+
+impl ColorGradePass {
+    pub fn new(context: &mut GpuContext, lut: CubeLut, format: wgpu::TextureFormat) -> Result<Self, Error> {
+        let bind_group_layout = get_or_create_bind_group_layout(context, COLOR_GRADE_BIND_GROUP_LAYOUT, create_color_grade_bind_group_layout);
+
+        let shader = context.device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("color grade shader"),
+            source: wgpu::ShaderSource::Wgsl(Cow::Owned(
+                expand_embedded_includes("color_grade.wgsl", include_str!("../shaders/color_grade.wgsl"), &[])
+                    .expect("invalid color_grade.wgsl")
+                    .source,
+            )),
+        });
+
+        let pipeline = create_fullscreen_pipeline(context, "color grade pipeline", &bind_group_layout, &shader, format);
+
+        let sampler = context.device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("color grade sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+
+        let lut_texture = create_lut_texture(context, &lut)?;
+
+        let uniform_buffer = create_uniform_buffer_init(context, &[get_color_grade_uniform(&lut, 1.0)], "color grade uniform");
+
+        Ok(ColorGradePass {
+            intensity: 1.0,
+            pipeline,
+            bind_group_layout,
+            sampler,
+            uniform_buffer,
+            lut,
+            lut_texture,
+        })
+    }
+
+    pub fn set_lut(&mut self, context: &GpuContext, lut: CubeLut) -> Result<(), Error> {
+        self.lut_texture = create_lut_texture(context, &lut)?;
+        self.lut = lut;
+        Ok(())
+    }
+
+    pub fn render(&self, context: &GpuContext, encoder: &mut wgpu::CommandEncoder, input_view: &TextureView, output_view: &TextureView) {
+        update_uniform_buffer(context, &self.uniform_buffer, &[get_color_grade_uniform(&self.lut, self.intensity)]);
+
+        let bind_group = context.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(input_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&self.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(&self.lut_texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::Sampler(&self.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: self.uniform_buffer.as_entire_binding(),
+                },
+            ],
+            label: Some("color grade bind group"),
+        });
+
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("color grade"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: output_view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: None,
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+
+        pass.set_pipeline(&self.pipeline);
+        pass.set_bind_group(0, &bind_group, &[]);
+        pass.draw(0..3, 0..1);
+    }
+}
+
+fn get_color_grade_uniform(lut: &CubeLut, intensity: f32) -> ColorGradeUniform {
+    ColorGradeUniform {
+        domain_min: lut.domain_min.extend(0.0).to_array(),
+        domain_max: lut.domain_max.extend(0.0).to_array(),
+        lut_size: lut.size as f32,
+        intensity: intensity.clamp(0.0, 1.0),
+        _padding: [0.0; 2],
+    }
+}
+
+fn create_lut_texture(context: &GpuContext, lut: &CubeLut) -> Result<VolumeTexture, Error> {
+    let lut_texture = create_3d(
+        context,
+        [lut.size, lut.size, lut.size],
+        wgpu::TextureFormat::Rgba8Unorm,
+        wgpu::TextureUsages::TEXTURE_BINDING,
+    );
+    upload_3d(context, &lut_texture, &lut.to_rgba8())?;
+    Ok(lut_texture)
+}
+
+fn create_color_grade_bind_group_layout(context: &GpuContext, label: &str) -> BindGroupLayout {
+    let sampler_entry = |binding| wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::FRAGMENT,
+        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+        count: None,
+    };
+
+    context.device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        entries: &[
+            // input image
+            wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    multisampled: false,
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                },
+                count: None,
+            },
+            sampler_entry(1),
+            // lut
+            wgpu::BindGroupLayoutEntry {
+                binding: 2,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    multisampled: false,
+                    view_dimension: wgpu::TextureViewDimension::D3,
+                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                },
+                count: None,
+            },
+            sampler_entry(3),
+            wgpu::BindGroupLayoutEntry {
+                binding: 4,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+        ],
+        label: Some(label),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{vec3, Vec3};
+
+    use crate::gpu_context::GpuContext;
+    use crate::post::color_grade::{parse_cube, ColorGradePass, CubeLut};
+    use crate::post::tonemap::HdrTarget;
+    use crate::render::RenderPassBuilder;
+    use crate::snapshot::read_texture_region;
+
+    fn neutral_cube_text(size: u32) -> String {
+        let mut text = format!("# neutral\nTITLE \"neutral\"\nLUT_3D_SIZE {}\n", size);
+        for color in CubeLut::neutral(size).data {
+            text += &format!("{:.6} {:.6} {:.6}\n", color.x, color.y, color.z);
+        }
+        text
+    }
+
+    // fs_main on a 1x1 input cleared to color, read back from an Rgba32Float output
+    fn grade_on_gpu(context: &mut GpuContext, lut: CubeLut, intensity: f32, color: Vec3) -> Vec3 {
+        let format = wgpu::TextureFormat::Rgba32Float;
+        let mut pass = ColorGradePass::new(context, lut, format).unwrap();
+        pass.intensity = intensity;
+
+        let input = HdrTarget::new(context, 1, 1);
+        let output = context.device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("color grade output"),
+            size: wgpu::Extent3d {
+                width: 1,
+                height: 1,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            view_formats: &[],
+        });
+        let output_view = output.create_view(&wgpu::TextureViewDescriptor::default());
+
+        let clear = wgpu::Color {
+            r: color.x as f64,
+            g: color.y as f64,
+            b: color.z as f64,
+            a: 1.0,
+        };
+        let mut encoder = context.device.create_command_encoder(&Default::default());
+        RenderPassBuilder::new().color(&input.view, Some(clear)).begin(&mut encoder);
+        pass.render(context, &mut encoder, &input.view, &output_view);
+        context.queue.submit(std::iter::once(encoder.finish()));
+
+        let bytes = read_texture_region(context, &output, [0, 0], 1, 1).unwrap();
+        Vec3::from_slice(&bytemuck::pod_read_unaligned::<[f32; 4]>(&bytes))
+    }
+
+    #[test]
+    fn test_neutral_lut() {
+        for size in [16, 32] {
+            let lut = parse_cube(&neutral_cube_text(size)).unwrap();
+            assert_eq!(lut.size, size);
+            assert_eq!(lut.title.as_deref(), Some("neutral"));
+            assert_eq!(lut.data.len(), (size * size * size) as usize);
+
+            // red changes fastest
+            assert!(lut.data[1].abs_diff_eq(vec3(1.0 / (size - 1) as f32, 0.0, 0.0), 1e-5));
+            assert!(lut.data[size as usize].abs_diff_eq(vec3(0.0, 1.0 / (size - 1) as f32, 0.0), 1e-5));
+        }
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "gpu_tests"), ignore = "needs a gpu adapter")]
+    fn test_color_grade_pass() {
+        let mut context = pollster::block_on(GpuContext::new_headless(4, 4)).unwrap();
+        // the lut is stored as rgba8 and filtered with limited precision
+        let tolerance = 3.0 / 255.0;
+
+        let neutral = parse_cube(&neutral_cube_text(16)).unwrap();
+        for color in [Vec3::ZERO, Vec3::ONE, vec3(0.25, 0.5, 0.75), vec3(0.9, 0.1, 0.33)] {
+            let graded = grade_on_gpu(&mut context, neutral.clone(), 1.0, color);
+            assert!(graded.abs_diff_eq(color, tolerance), "{} != {}", graded, color);
+        }
+
+        let mut inverted = CubeLut::neutral(16);
+        inverted.data.iter_mut().for_each(|entry| *entry = Vec3::ONE - *entry);
+        let color = vec3(0.25, 0.5, 0.75);
+        let graded = grade_on_gpu(&mut context, inverted.clone(), 1.0, color);
+        assert!(graded.abs_diff_eq(vec3(0.75, 0.5, 0.25), tolerance), "{}", graded);
+
+        // intensity blends with the input
+        let graded = grade_on_gpu(&mut context, inverted, 0.5, color);
+        assert!(graded.abs_diff_eq(Vec3::splat(0.5), tolerance), "{}", graded);
+    }
+
+    #[test]
+    fn test_invalid_cube() {
+        assert!(parse_cube("LUT_3D_SIZE 2\n0 0 0\n").is_err());
+        assert!(parse_cube("0 0 0\n").is_err());
+    }
+}
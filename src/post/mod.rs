@@ -0,0 +1,44 @@
+pub mod color_grade;
+pub mod ssr;
+pub mod taa;
+pub mod tonemap;
+
+use wgpu::{BindGroupLayout, RenderPipeline, ShaderModule};
+
+use crate::gpu_context::GpuContext;
+
+pub const FULLSCREEN_WGSL: &str = include_str!("../shaders/fullscreen.wgsl");
+
+// Post effects draw a single fullscreen triangle generated from the vertex index in vs_fullscreen
+pub fn create_fullscreen_pipeline(
+    context: &GpuContext,
+    label: &str,
+    bind_group_layout: &BindGroupLayout,
+    shader: &ShaderModule,
+    target_format: wgpu::TextureFormat,
+) -> RenderPipeline {
+    let pipeline_layout = context.device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some(label),
+        bind_group_layouts: &[bind_group_layout],
+        push_constant_ranges: &[],
+    });
+
+    context.device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some(label),
+        layout: Some(&pipeline_layout),
+        vertex: wgpu::VertexState {
+            module: shader,
+            entry_point: "vs_fullscreen",
+            buffers: &[],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: shader,
+            entry_point: "fs_main",
+            targets: &[Some(target_format.into())],
+        }),
+        primitive: wgpu::PrimitiveState::default(),
+        depth_stencil: None,
+        multisample: wgpu::MultisampleState::default(),
+        multiview: None,
+    })
+}
@@ -0,0 +1,283 @@
+use std::borrow::Cow;
+use std::rc::Rc;
+
+use glam::Mat4;
+use wgpu::{BindGroupLayout, Buffer, RenderPipeline, Sampler, TextureView};
+
+use crate::buffers::{create_uniform_buffer_init, update_uniform_buffer};
+use crate::gpu_context::{get_or_create_bind_group_layout, GpuContext};
+use crate::post::create_fullscreen_pipeline;
+use crate::shader_preprocessor::expand_embedded_includes;
+
+pub const SSR_BIND_GROUP_LAYOUT: &str = "ssr bind group layout";
+
+#[derive(Debug, Clone, Copy)]
+pub struct SsrSettings {
+    pub max_steps: u32,
+    // how far behind the depth buffer a ray can be and still count as a hit, in view space units
+    pub thickness: f32,
+    pub max_distance: f32,
+    // fraction of the screen over which reflections fade out towards the edges
+    pub edge_fade: f32,
+    // surfaces this rough or rougher get no reflections
+    pub max_roughness: f32,
+    pub intensity: f32,
+}
+
+impl SsrSettings {
+    pub fn new() -> Self {
+        SsrSettings {
+            max_steps: 64,
+            thickness: 0.5,
+            max_distance: 50.0,
+            edge_fade: 0.1,
+            max_roughness: 0.6,
+            intensity: 1.0,
+        }
+    }
+
+    pub fn set_max_steps(mut self, max_steps: u32) -> Self {
+        self.max_steps = max_steps;
+        self
+    }
+
+    pub fn set_thickness(mut self, thickness: f32) -> Self {
+        self.thickness = thickness;
+        self
+    }
+
+    pub fn set_max_distance(mut self, max_distance: f32) -> Self {
+        self.max_distance = max_distance;
+        self
+    }
+
+    pub fn set_intensity(mut self, intensity: f32) -> Self {
+        self.intensity = intensity;
+        self
+    }
+}
+
+impl Default for SsrSettings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct SsrUniform {
+    pub projection: [[f32; 4]; 4],
+    pub inverse_projection: [[f32; 4]; 4],
+    pub max_steps: u32,
+    pub thickness: f32,
+    pub max_distance: f32,
+    pub edge_fade: f32,
+    pub max_roughness: f32,
+    pub intensity: f32,
+    pub _padding: [f32; 2],
+}
+
+impl SsrUniform {
+    pub fn new(settings: &SsrSettings, projection: &Mat4) -> Self {
+        SsrUniform {
+            projection: projection.to_cols_array_2d(),
+            inverse_projection: projection.inverse().to_cols_array_2d(),
+            max_steps: settings.max_steps,
+            thickness: settings.thickness,
+            max_distance: settings.max_distance,
+            edge_fade: settings.edge_fade,
+            max_roughness: settings.max_roughness,
+            intensity: settings.intensity,
+            _padding: [0.0; 2],
+        }
+    }
+}
+
+// Ray marches the depth buffer in view space along the reflected view direction and
+// blends the hdr color found at the hit into the output.
+pub struct SsrPass {
+    pub settings: SsrSettings,
+    pipeline: RenderPipeline,
+    bind_group_layout: Rc<BindGroupLayout>,
+    sampler: Sampler,
+    uniform_buffer: Buffer,
+}
+
+impl SsrPass {
+    pub fn new(context: &mut GpuContext, format: wgpu::TextureFormat, settings: SsrSettings) -> Self {
+        let bind_group_layout = get_or_create_bind_group_layout(context, SSR_BIND_GROUP_LAYOUT, create_ssr_bind_group_layout);
+
+        let shader = context.device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("ssr shader"),
+            source: wgpu::ShaderSource::Wgsl(Cow::Owned(
+                expand_embedded_includes("ssr.wgsl", include_str!("../shaders/ssr.wgsl"), &[])
+                    .expect("invalid ssr.wgsl")
+                    .source,
+            )),
+        });
+
+        let pipeline = create_fullscreen_pipeline(context, "ssr pipeline", &bind_group_layout, &shader, format);
+
+        let sampler = context.device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("ssr sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+
+        let uniform_buffer = create_uniform_buffer_init(context, &[SsrUniform::new(&settings, &Mat4::IDENTITY)], "ssr uniform");
+
+        SsrPass {
+            settings,
+            pipeline,
+            bind_group_layout,
+            sampler,
+            uniform_buffer,
+        }
+    }
+
+    // The normal view holds view space normals with roughness in alpha, the depth view
+    // is the scene depth rendered with the same projection.
+    #[allow(clippy::too_many_arguments)]
+    pub fn render(
+        &self,
+        context: &GpuContext,
+        encoder: &mut wgpu::CommandEncoder,
+        projection: &Mat4,
+        color_view: &TextureView,
+        depth_view: &TextureView,
+        normal_view: &TextureView,
+        output_view: &TextureView,
+    ) {
+        update_uniform_buffer(context, &self.uniform_buffer, &[SsrUniform::new(&self.settings, projection)]);
+
+        let bind_group = context.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(color_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(depth_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(normal_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::Sampler(&self.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: self.uniform_buffer.as_entire_binding(),
+                },
+            ],
+            label: Some("ssr bind group"),
+        });
+
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("ssr"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: output_view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: None,
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+
+        pass.set_pipeline(&self.pipeline);
+        pass.set_bind_group(0, &bind_group, &[]);
+        pass.draw(0..3, 0..1);
+    }
+}
+
+fn create_ssr_bind_group_layout(context: &GpuContext, label: &str) -> BindGroupLayout {
+    let float_texture_entry = |binding| wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::FRAGMENT,
+        ty: wgpu::BindingType::Texture {
+            multisampled: false,
+            view_dimension: wgpu::TextureViewDimension::D2,
+            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+        },
+        count: None,
+    };
+
+    context.device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        entries: &[
+            // hdr color
+            float_texture_entry(0),
+            // depth
+            wgpu::BindGroupLayoutEntry {
+                binding: 1,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    multisampled: false,
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    sample_type: wgpu::TextureSampleType::Depth,
+                },
+                count: None,
+            },
+            // normals and roughness
+            float_texture_entry(2),
+            wgpu::BindGroupLayoutEntry {
+                binding: 3,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 4,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+        ],
+        label: Some(label),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+
+    use glam::Mat4;
+
+    use crate::post::ssr::{SsrSettings, SsrUniform};
+
+    #[test]
+    fn test_ssr_uniform() {
+        let settings = SsrSettings::new().set_max_steps(32).set_thickness(0.25);
+        let projection = Mat4::perspective_rh(45f32.to_radians(), 1.5, 0.1, 100.0);
+
+        let uniform = SsrUniform::new(&settings, &projection);
+        assert_eq!(uniform.max_steps, 32);
+        assert_eq!(uniform.thickness, 0.25);
+
+        // the uploaded bytes match the SsrUniform layout in ssr.wgsl
+        let bytes = bytemuck::bytes_of(&uniform);
+        assert_eq!(bytes.len(), 160);
+        assert_eq!(mem::offset_of!(SsrUniform, max_steps), 128);
+        assert_eq!(bytemuck::pod_read_unaligned::<u32>(&bytes[128..132]), 32);
+        assert_eq!(bytemuck::pod_read_unaligned::<f32>(&bytes[132..136]), 0.25);
+
+        let inverse = Mat4::from_cols_array_2d(&uniform.inverse_projection);
+        assert!((inverse * projection).abs_diff_eq(Mat4::IDENTITY, 1e-5));
+    }
+}
@@ -0,0 +1,432 @@
+use std::borrow::Cow;
+use std::rc::Rc;
+
+use glam::Vec3;
+use wgpu::{BindGroupLayout, Buffer, RenderPipeline, Sampler, TextureView};
+
+use crate::buffers::{create_uniform_buffer_init, update_uniform_buffer};
+use crate::gpu_context::{get_or_create_bind_group_layout, GpuContext};
+use crate::post::create_fullscreen_pipeline;
+use crate::shader_preprocessor::expand_embedded_includes;
+
+pub const TAA_BIND_GROUP_LAYOUT: &str = "taa bind group layout";
+
+pub const DEFAULT_BLEND_FACTOR: f32 = 0.9;
+
+#[derive(Debug, Clone, Copy)]
+pub struct TaaSettings {
+    // weight of the reprojected history, higher is smoother but slower to converge
+    pub blend_factor: f32,
+}
+
+impl TaaSettings {
+    pub fn new() -> Self {
+        TaaSettings {
+            blend_factor: DEFAULT_BLEND_FACTOR,
+        }
+    }
+
+    pub fn set_blend_factor(mut self, blend_factor: f32) -> Self {
+        self.blend_factor = blend_factor.clamp(0.0, 1.0);
+        self
+    }
+}
+
+impl Default for TaaSettings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct TaaUniform {
+    pub texel_size: [f32; 2],
+    pub blend_factor: f32,
+    pub history_valid: f32,
+}
+
+struct HistoryTarget {
+    texture: wgpu::Texture,
+    view: TextureView,
+}
+
+// Accumulates jittered frames into a ping-pong history. Each resolve reads the previous
+// history and writes the new one, which is also the anti-aliased output.
+pub struct TaaPass {
+    pub settings: TaaSettings,
+    pub format: wgpu::TextureFormat,
+    pipeline: RenderPipeline,
+    bind_group_layout: Rc<BindGroupLayout>,
+    sampler: Sampler,
+    uniform_buffer: Buffer,
+    history: [HistoryTarget; 2],
+    write_index: usize,
+    history_valid: bool,
+}
+
+impl TaaPass {
+    pub fn new(context: &mut GpuContext, format: wgpu::TextureFormat, settings: TaaSettings) -> Self {
+        let bind_group_layout = get_or_create_bind_group_layout(context, TAA_BIND_GROUP_LAYOUT, create_taa_bind_group_layout);
+
+        let shader = context.device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("taa shader"),
+            source: wgpu::ShaderSource::Wgsl(Cow::Owned(
+                expand_embedded_includes("taa.wgsl", include_str!("../shaders/taa.wgsl"), &[])
+                    .expect("invalid taa.wgsl")
+                    .source,
+            )),
+        });
+
+        let pipeline = create_fullscreen_pipeline(context, "taa pipeline", &bind_group_layout, &shader, format);
+
+        let sampler = context.device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("taa sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+
+        let uniform_buffer = create_uniform_buffer_init(context, &[get_taa_uniform(context, &settings, false)], "taa uniform");
+
+        let history = [
+            create_history_target(context, format),
+            create_history_target(context, format),
+        ];
+
+        TaaPass {
+            settings,
+            format,
+            pipeline,
+            bind_group_layout,
+            sampler,
+            uniform_buffer,
+            history,
+            write_index: 0,
+            history_valid: false,
+        }
+    }
+
+    // discards the history, e.g. after a camera cut
+    pub fn reset(&mut self) {
+        self.history_valid = false;
+    }
+
+    pub fn resize(&mut self, context: &GpuContext) {
+        self.history = [
+            create_history_target(context, self.format),
+            create_history_target(context, self.format),
+        ];
+        self.reset();
+    }
+
+    // The current view is the jittered hdr frame and the motion view the Rg16Float motion vectors.
+    // Returns the view holding the resolved frame.
+    pub fn resolve(
+        &mut self,
+        context: &GpuContext,
+        encoder: &mut wgpu::CommandEncoder,
+        current_view: &TextureView,
+        motion_view: &TextureView,
+    ) -> &TextureView {
+        let read_index = 1 - self.write_index;
+
+        update_uniform_buffer(
+            context,
+            &self.uniform_buffer,
+            &[get_taa_uniform(context, &self.settings, self.history_valid)],
+        );
+
+        let bind_group = context.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(current_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&self.history[read_index].view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(motion_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::Sampler(&self.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: self.uniform_buffer.as_entire_binding(),
+                },
+            ],
+            label: Some("taa bind group"),
+        });
+
+        {
+            let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("taa resolve"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &self.history[self.write_index].view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        store: wgpu::StoreOp::Store,
+                    },
+                })],
+                depth_stencil_attachment: None,
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            });
+
+            pass.set_pipeline(&self.pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            pass.draw(0..3, 0..1);
+        }
+
+        let output_index = self.write_index;
+        self.write_index = read_index;
+        self.history_valid = true;
+
+        &self.history[output_index].view
+    }
+
+    // the most recently resolved frame
+    pub fn output_view(&self) -> &TextureView {
+        &self.history[1 - self.write_index].view
+    }
+
+    // The texture of output_view, it can be copied from, e.g. into a screenshot
+    pub fn output_texture(&self) -> &wgpu::Texture {
+        &self.history[1 - self.write_index].texture
+    }
+}
+
+// The history clamped to the bounds of the current frame's neighborhood, which includes current,
+// and blended in, like fs_main in taa.wgsl does for each pixel
+pub fn resolve_taa_color(current: Vec3, neighborhood: &[Vec3], history: Vec3, blend: f32) -> Vec3 {
+    let (color_min, color_max) = neighborhood.iter().fold((current, current), |(color_min, color_max), color| {
+        (color_min.min(*color), color_max.max(*color))
+    });
+    current.lerp(history.clamp(color_min, color_max), blend)
+}
+
+fn get_taa_uniform(context: &GpuContext, settings: &TaaSettings, history_valid: bool) -> TaaUniform {
+    TaaUniform {
+        texel_size: [1.0 / context.config.width as f32, 1.0 / context.config.height as f32],
+        blend_factor: settings.blend_factor,
+        history_valid: if history_valid { 1.0 } else { 0.0 },
+    }
+}
+
+fn create_history_target(context: &GpuContext, format: wgpu::TextureFormat) -> HistoryTarget {
+    let texture = context.device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("taa history"),
+        size: wgpu::Extent3d {
+            width: context.config.width,
+            height: context.config.height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_SRC,
+        view_formats: &[],
+    });
+
+    let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+    HistoryTarget { texture, view }
+}
+
+fn create_taa_bind_group_layout(context: &GpuContext, label: &str) -> BindGroupLayout {
+    let texture_entry = |binding| wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::FRAGMENT,
+        ty: wgpu::BindingType::Texture {
+            multisampled: false,
+            view_dimension: wgpu::TextureViewDimension::D2,
+            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+        },
+        count: None,
+    };
+
+    context.device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        entries: &[
+            // current frame
+            texture_entry(0),
+            // history
+            texture_entry(1),
+            // motion vectors
+            texture_entry(2),
+            wgpu::BindGroupLayoutEntry {
+                binding: 3,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 4,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+        ],
+        label: Some(label),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{vec3, Vec3};
+    use wgpu::util::DeviceExt;
+
+    use crate::gpu_context::GpuContext;
+    use crate::post::taa::{resolve_taa_color, TaaPass, TaaSettings, DEFAULT_BLEND_FACTOR};
+    use crate::snapshot::read_texture_rgba;
+
+    const SIZE: u32 = 8;
+
+    fn create_input(context: &GpuContext, pixels: &[[u8; 4]]) -> wgpu::TextureView {
+        let texture = context.device.create_texture_with_data(
+            &context.queue,
+            &wgpu::TextureDescriptor {
+                label: Some("taa input"),
+                size: wgpu::Extent3d {
+                    width: SIZE,
+                    height: SIZE,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: wgpu::TextureFormat::Rgba8Unorm,
+                usage: wgpu::TextureUsages::TEXTURE_BINDING,
+                view_formats: &[],
+            },
+            wgpu::util::TextureDataOrder::LayerMajor,
+            bytemuck::cast_slice(pixels),
+        );
+        texture.create_view(&wgpu::TextureViewDescriptor::default())
+    }
+
+    // red and green ramps along x and y in steps of 32, blue constant
+    fn get_ramp(offset: u8) -> Vec<[u8; 4]> {
+        (0..SIZE * SIZE)
+            .map(|i| {
+                let (x, y) = ((i % SIZE * 32 + 16) as u8, (i / SIZE * 32 + 16) as u8);
+                [x + offset, y + offset, 64 + offset, 255]
+            })
+            .collect()
+    }
+
+    fn resolve(context: &GpuContext, taa: &mut TaaPass, current: &wgpu::TextureView, motion: &wgpu::TextureView) -> Vec<[u8; 4]> {
+        let mut encoder = context.device.create_command_encoder(&Default::default());
+        taa.resolve(context, &mut encoder, current, motion);
+        context.queue.submit(std::iter::once(encoder.finish()));
+        let pixels = read_texture_rgba(context, taa.output_texture(), SIZE, SIZE).unwrap();
+        bytemuck::cast_slice(&pixels).to_vec()
+    }
+
+    #[test]
+    fn test_resolve_taa_color() {
+        let current = vec3(0.5, 0.5, 0.5);
+        let neighborhood = [vec3(0.25, 0.5, 0.5), vec3(0.75, 0.5, 0.5), vec3(0.5, 0.25, 0.75)];
+
+        assert_eq!(resolve_taa_color(current, &neighborhood, Vec3::ONE, 0.0), current);
+        // outside the neighborhood the history is clamped to its bounds first
+        assert_eq!(resolve_taa_color(current, &neighborhood, Vec3::ONE, 1.0), vec3(0.75, 0.5, 0.75));
+        assert_eq!(resolve_taa_color(current, &neighborhood, Vec3::ZERO, 1.0), vec3(0.25, 0.25, 0.5));
+
+        // a static frame resolved into its own history stays put
+        let mut history = current;
+        for _ in 0..64 {
+            history = resolve_taa_color(current, &neighborhood, history, DEFAULT_BLEND_FACTOR);
+            assert!(history.abs_diff_eq(current, 1e-6), "{}", history);
+        }
+
+        // and a different history converges to it
+        let mut history = vec3(0.7, 0.3, 0.6);
+        let mut error = history.distance(current);
+        for _ in 0..64 {
+            history = resolve_taa_color(current, &neighborhood, history, DEFAULT_BLEND_FACTOR);
+            let next_error = history.distance(current);
+            assert!(next_error < error || next_error < 1e-6);
+            error = next_error;
+        }
+        assert!(error < 1e-2, "{}", error);
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "gpu_tests"), ignore = "needs a gpu adapter")]
+    fn test_taa_convergence() {
+        let mut context = pollster::block_on(GpuContext::new_headless(SIZE, SIZE)).unwrap();
+        let mut taa = TaaPass::new(&mut context, wgpu::TextureFormat::Rgba8Unorm, TaaSettings::new());
+
+        let ramp = get_ramp(0);
+        let ramp_view = create_input(&context, &ramp);
+        let still = create_input(&context, &vec![[0; 4]; (SIZE * SIZE) as usize]);
+
+        // through both history targets several times, within a step of the 8 bit history
+        for frame in 0..9 {
+            let resolved = resolve(&context, &mut taa, &ramp_view, &still);
+            for (i, (pixel, input)) in resolved.iter().zip(&ramp).enumerate() {
+                for channel in 0..4 {
+                    assert!(
+                        pixel[channel].abs_diff(input[channel]) <= 1,
+                        "frame {}: {:?} at {}, {}",
+                        frame,
+                        pixel,
+                        i as u32 % SIZE,
+                        i as u32 / SIZE
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "gpu_tests"), ignore = "needs a gpu adapter")]
+    fn test_taa_resolve() {
+        let mut context = pollster::block_on(GpuContext::new_headless(SIZE, SIZE)).unwrap();
+        let mut taa = TaaPass::new(&mut context, wgpu::TextureFormat::Rgba8Unorm, TaaSettings::new());
+
+        let ramp = get_ramp(0);
+        let brighter = get_ramp(8);
+        let (ramp_view, brighter_view) = (create_input(&context, &ramp), create_input(&context, &brighter));
+        let still = create_input(&context, &vec![[0; 4]; (SIZE * SIZE) as usize]);
+        // moved by a whole frame, the history is off screen
+        let moved = create_input(&context, &vec![[255, 255, 0, 255]; (SIZE * SIZE) as usize]);
+
+        // without a history the frame is passed through
+        assert_eq!(resolve(&context, &mut taa, &ramp_view, &still), ramp);
+
+        // inside the ramp neighborhoods the history is blended in with 0.9, the constant blue is
+        // clamped to the new neighborhood
+        let resolved = resolve(&context, &mut taa, &brighter_view, &still);
+        for y in 1..SIZE {
+            for x in 1..SIZE {
+                let i = (y * SIZE + x) as usize;
+                assert!(resolved[i][0].abs_diff(ramp[i][0] + 1) <= 1, "{:?} at {}, {}", resolved[i], x, y);
+                assert!(resolved[i][1].abs_diff(ramp[i][1] + 1) <= 1, "{:?} at {}, {}", resolved[i], x, y);
+                assert_eq!(resolved[i][2], brighter[i][2]);
+            }
+        }
+
+        assert_eq!(resolve(&context, &mut taa, &ramp_view, &moved), ramp);
+
+        // a reset discards the ramp history
+        taa.reset();
+        assert_eq!(resolve(&context, &mut taa, &brighter_view, &still), brighter);
+    }
+}
@@ -0,0 +1,497 @@
+use std::borrow::Cow;
+use std::rc::Rc;
+
+use glam::Mat3;
+use wgpu::{BindGroup, BindGroupLayout, Buffer, RenderPipeline, Sampler, Texture, TextureView};
+
+use crate::bind_group::BindGroupBuilder;
+use crate::buffers::{create_uniform_buffer_init, update_uniform_buffer};
+use crate::gpu_context::{get_or_create_bind_group_layout, GpuContext};
+use crate::post::create_fullscreen_pipeline;
+use crate::render::RenderPassBuilder;
+use crate::shader_preprocessor::expand_embedded_includes;
+
+pub const TONEMAP_BIND_GROUP_LAYOUT: &str = "tonemap bind group layout";
+
+// The scene is rendered into a target of this format and tonemapped to the surface
+pub const HDR_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+// Linear Rec.709 to linear Rec.2020 (ITU-R BT.2087), columns for glam
+pub const REC709_TO_REC2020: Mat3 = Mat3::from_cols_array(&[
+    0.6274, 0.0691, 0.0164, //
+    0.3293, 0.9195, 0.0880, //
+    0.0433, 0.0114, 0.8956,
+]);
+
+// The luminance 1.0 is shown at on a PQ output, the hdr reference white of ITU-R BT.2408.
+// PQ_PAPER_WHITE_NITS in tonemap.wgsl.
+pub const PQ_PAPER_WHITE_NITS: f32 = 203.0;
+
+// Primaries and encoding of the tonemapped output. Scene colors are rendered with Rec.709 primaries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputColorSpace {
+    // sdr, clamped to 0..1
+    Rec709,
+    // scRGB, linear Rec.709 primaries with values above 1.0 kept as hdr headroom
+    ExtendedLinearRec709,
+    // HDR10, Rec.2020 primaries encoded with the PQ curve
+    Rec2020Pq,
+}
+
+impl OutputColorSpace {
+    // Float surfaces are scRGB on every wgpu backend. wgpu can't configure an HDR10 swapchain, so
+    // 10 bit surfaces are sdr and Rec2020Pq is only set explicitly for an output known to be HDR10.
+    pub fn from_surface_format(format: wgpu::TextureFormat) -> Self {
+        match format {
+            wgpu::TextureFormat::Rgba16Float => OutputColorSpace::ExtendedLinearRec709,
+            _ => OutputColorSpace::Rec709,
+        }
+    }
+
+    pub fn get_primaries_matrix(&self) -> Mat3 {
+        match self {
+            OutputColorSpace::Rec709 | OutputColorSpace::ExtendedLinearRec709 => Mat3::IDENTITY,
+            OutputColorSpace::Rec2020Pq => REC709_TO_REC2020,
+        }
+    }
+
+    // hdr outputs keep what the operator maps above 1.0
+    pub fn is_clamped(&self) -> bool {
+        matches!(self, OutputColorSpace::Rec709)
+    }
+}
+
+// How fs_main in tonemap.wgsl encodes its output, OUTPUT_* in tonemap.wgsl
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputEncoding {
+    // srgb views and float outputs
+    Linear,
+    // 8 and 10 bit formats without srgb encoding on write
+    Srgb,
+    Pq,
+}
+
+impl OutputEncoding {
+    pub fn new(color_space: OutputColorSpace, encode_srgb: bool) -> Self {
+        match (color_space, encode_srgb) {
+            (OutputColorSpace::Rec2020Pq, _) => OutputEncoding::Pq,
+            (_, true) => OutputEncoding::Srgb,
+            (_, false) => OutputEncoding::Linear,
+        }
+    }
+
+    pub fn get_index(&self) -> u32 {
+        match self {
+            OutputEncoding::Linear => 0,
+            OutputEncoding::Srgb => 1,
+            OutputEncoding::Pq => 2,
+        }
+    }
+}
+
+// The curve mapping exposed scene colors to 0..1, TONEMAP_* in tonemap.wgsl
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TonemapOperator {
+    // extended Reinhard up to the white point
+    Reinhard,
+    // Narkowicz's fit of the ACES filmic curve, more contrast and a softer shoulder
+    AcesFilmic,
+    // only exposure and clamping, for comparing against the untonemapped image
+    Passthrough,
+}
+
+impl TonemapOperator {
+    pub fn get_index(&self) -> u32 {
+        match self {
+            TonemapOperator::Reinhard => 0,
+            TonemapOperator::AcesFilmic => 1,
+            TonemapOperator::Passthrough => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TonemapSettings {
+    pub operator: TonemapOperator,
+    pub exposure: f32,
+    // the scene luminance mapped to 1.0 by Reinhard, everything brighter is clipped
+    pub white_point: f32,
+    pub color_space: OutputColorSpace,
+}
+
+impl TonemapSettings {
+    pub fn new() -> Self {
+        TonemapSettings {
+            operator: TonemapOperator::Reinhard,
+            exposure: 1.0,
+            white_point: 4.0,
+            color_space: OutputColorSpace::Rec709,
+        }
+    }
+
+    pub fn set_operator(mut self, operator: TonemapOperator) -> Self {
+        self.operator = operator;
+        self
+    }
+
+    pub fn set_exposure(mut self, exposure: f32) -> Self {
+        self.exposure = exposure;
+        self
+    }
+
+    pub fn set_white_point(mut self, white_point: f32) -> Self {
+        self.white_point = white_point;
+        self
+    }
+
+    pub fn set_color_space(mut self, color_space: OutputColorSpace) -> Self {
+        self.color_space = color_space;
+        self
+    }
+}
+
+impl Default for TonemapSettings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct TonemapUniform {
+    // mat3x3 columns are padded to vec4 in wgsl uniforms
+    pub primaries: [[f32; 4]; 3],
+    pub exposure: f32,
+    pub white_point: f32,
+    pub operator: u32,
+    // OutputEncoding::get_index
+    pub output_encoding: u32,
+}
+
+impl TonemapUniform {
+    pub fn new(settings: &TonemapSettings, encode_srgb: bool) -> Self {
+        let primaries = settings.color_space.get_primaries_matrix();
+        TonemapUniform {
+            primaries: [
+                primaries.x_axis.extend(0.0).to_array(),
+                primaries.y_axis.extend(0.0).to_array(),
+                primaries.z_axis.extend(0.0).to_array(),
+            ],
+            exposure: settings.exposure,
+            white_point: settings.white_point.max(1e-4),
+            operator: settings.operator.get_index(),
+            output_encoding: OutputEncoding::new(settings.color_space, encode_srgb).get_index(),
+        }
+    }
+}
+
+// Srgb formats encode on write. The other 8 and 10 bit formats are displayed as srgb without
+// encoding, so the shader encodes. Float outputs are scRGB, which takes linear values.
+pub fn get_output_encodes_srgb(format: wgpu::TextureFormat) -> bool {
+    matches!(
+        format,
+        wgpu::TextureFormat::Rgba8Unorm | wgpu::TextureFormat::Bgra8Unorm | wgpu::TextureFormat::Rgb10a2Unorm
+    )
+}
+
+// An offscreen color target the scene is rendered into before tonemapping
+pub struct HdrTarget {
+    pub texture: Texture,
+    pub view: TextureView,
+}
+
+impl HdrTarget {
+    pub fn new(context: &GpuContext, width: u32, height: u32) -> Self {
+        let texture = context.device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("hdr target"),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: HDR_FORMAT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        HdrTarget { texture, view }
+    }
+}
+
+// Maps an hdr image to the output range with the settings' operator, then converts to the
+// primaries of the output color space. The settings are written every render so they can be
+// changed at runtime. The input is bound once, call set_input after recreating it, e.g. on resize.
+pub struct TonemapPass {
+    pub settings: TonemapSettings,
+    pipeline: RenderPipeline,
+    bind_group_layout: Rc<BindGroupLayout>,
+    bind_group: BindGroup,
+    sampler: Sampler,
+    uniform_buffer: Buffer,
+    encode_srgb: bool,
+}
+
+impl TonemapPass {
+    // The color space is picked from the output format, settings.color_space can override it afterwards
+    pub fn new(context: &mut GpuContext, format: wgpu::TextureFormat, input_view: &TextureView, settings: TonemapSettings) -> Self {
+        let settings = settings.set_color_space(OutputColorSpace::from_surface_format(format));
+
+        let bind_group_layout = get_or_create_bind_group_layout(context, TONEMAP_BIND_GROUP_LAYOUT, create_tonemap_bind_group_layout);
+
+        let shader = context.device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("tonemap shader"),
+            source: wgpu::ShaderSource::Wgsl(Cow::Owned(
+                expand_embedded_includes("tonemap.wgsl", include_str!("../shaders/tonemap.wgsl"), &[])
+                    .expect("invalid tonemap.wgsl")
+                    .source,
+            )),
+        });
+
+        let pipeline = create_fullscreen_pipeline(context, "tonemap pipeline", &bind_group_layout, &shader, format);
+
+        let sampler = context.device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("tonemap sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+
+        let encode_srgb = get_output_encodes_srgb(format);
+        let uniform_buffer = create_uniform_buffer_init(context, &[TonemapUniform::new(&settings, encode_srgb)], "tonemap uniform");
+
+        let bind_group = create_tonemap_bind_group(context, &bind_group_layout, input_view, &sampler, &uniform_buffer);
+
+        TonemapPass {
+            settings,
+            pipeline,
+            bind_group_layout,
+            bind_group,
+            sampler,
+            uniform_buffer,
+            encode_srgb,
+        }
+    }
+
+    pub fn set_input(&mut self, context: &GpuContext, input_view: &TextureView) {
+        self.bind_group = create_tonemap_bind_group(context, &self.bind_group_layout, input_view, &self.sampler, &self.uniform_buffer);
+    }
+
+    pub fn render(&self, context: &GpuContext, encoder: &mut wgpu::CommandEncoder, output_view: &TextureView) {
+        update_uniform_buffer(
+            context,
+            &self.uniform_buffer,
+            &[TonemapUniform::new(&self.settings, self.encode_srgb)],
+        );
+
+        let mut pass = RenderPassBuilder::new()
+            .label("tonemap")
+            .color(output_view, Some(wgpu::Color::BLACK))
+            .begin(encoder);
+
+        pass.set_pipeline(&self.pipeline);
+        pass.set_bind_group(0, &self.bind_group, &[]);
+        pass.draw(0..3, 0..1);
+    }
+}
+
+fn create_tonemap_bind_group(
+    context: &GpuContext,
+    layout: &BindGroupLayout,
+    input_view: &TextureView,
+    sampler: &Sampler,
+    uniform_buffer: &Buffer,
+) -> BindGroup {
+    BindGroupBuilder::new()
+        .texture_view(input_view)
+        .sampler(sampler)
+        .buffer(uniform_buffer)
+        .build(context, layout, "tonemap bind group")
+}
+
+fn create_tonemap_bind_group_layout(context: &GpuContext, label: &str) -> BindGroupLayout {
+    context.device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        entries: &[
+            // hdr input
+            wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    multisampled: false,
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 1,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 2,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+        ],
+        label: Some(label),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{vec3, Mat3, Vec3};
+
+    use crate::gpu_context::GpuContext;
+    use crate::post::tonemap::{
+        get_output_encodes_srgb, HdrTarget, OutputColorSpace, OutputEncoding, TonemapOperator, TonemapPass, TonemapSettings, TonemapUniform,
+    };
+    use crate::render::RenderPassBuilder;
+    use crate::snapshot::read_texture_region;
+
+    // fs_main on a 1x1 input cleared to color, read back from an Rgba32Float or Rgba8Unorm output
+    fn tonemap_on_gpu(context: &mut GpuContext, settings: TonemapSettings, format: wgpu::TextureFormat, color: Vec3) -> Vec3 {
+        let input = HdrTarget::new(context, 1, 1);
+        let mut pass = TonemapPass::new(context, format, &input.view, settings);
+        pass.settings = settings;
+
+        let output = context.device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("tonemap output"),
+            size: wgpu::Extent3d {
+                width: 1,
+                height: 1,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            view_formats: &[],
+        });
+        let output_view = output.create_view(&wgpu::TextureViewDescriptor::default());
+
+        let clear = wgpu::Color {
+            r: color.x as f64,
+            g: color.y as f64,
+            b: color.z as f64,
+            a: 1.0,
+        };
+        let mut encoder = context.device.create_command_encoder(&Default::default());
+        RenderPassBuilder::new().color(&input.view, Some(clear)).begin(&mut encoder);
+        pass.render(context, &mut encoder, &output_view);
+        context.queue.submit(std::iter::once(encoder.finish()));
+
+        let bytes = read_texture_region(context, &output, [0, 0], 1, 1).unwrap();
+        match format {
+            wgpu::TextureFormat::Rgba32Float => Vec3::from_slice(&bytemuck::pod_read_unaligned::<[f32; 4]>(&bytes)),
+            _ => vec3(bytes[0] as f32, bytes[1] as f32, bytes[2] as f32) / 255.0,
+        }
+    }
+
+    #[test]
+    fn test_rec2020_primaries() {
+        assert_eq!(
+            OutputColorSpace::from_surface_format(wgpu::TextureFormat::Bgra8UnormSrgb),
+            OutputColorSpace::Rec709
+        );
+        // 10 bit surfaces are sdr, HDR10 is opted into
+        assert_eq!(
+            OutputColorSpace::from_surface_format(wgpu::TextureFormat::Rgb10a2Unorm),
+            OutputColorSpace::Rec709
+        );
+
+        let settings = TonemapSettings::new().set_color_space(OutputColorSpace::Rec2020Pq);
+        let uniform = TonemapUniform::new(&settings, true);
+        assert_eq!(uniform.output_encoding, OutputEncoding::Pq.get_index());
+        assert_eq!(uniform.primaries[0], [0.6274, 0.0691, 0.0164, 0.0]);
+        assert_eq!(uniform.primaries[2], [0.0433, 0.0114, 0.8956, 0.0]);
+
+        let uniform = TonemapUniform::new(&TonemapSettings::new(), false);
+        assert_eq!(
+            Mat3::from_cols_array_2d(&uniform.primaries.map(|c| [c[0], c[1], c[2]])),
+            Mat3::IDENTITY
+        );
+    }
+
+    #[test]
+    fn test_output_formats() {
+        assert_eq!(std::mem::size_of::<TonemapUniform>(), 64);
+        let aces = TonemapSettings::new().set_operator(TonemapOperator::AcesFilmic);
+        assert_eq!(TonemapUniform::new(&aces, false).operator, 1);
+
+        assert!(get_output_encodes_srgb(wgpu::TextureFormat::Bgra8Unorm));
+        assert!(!get_output_encodes_srgb(wgpu::TextureFormat::Bgra8UnormSrgb));
+        assert!(get_output_encodes_srgb(wgpu::TextureFormat::Rgb10a2Unorm));
+
+        // float surfaces keep rec.709 primaries and are encoded linearly
+        let color_space = OutputColorSpace::from_surface_format(wgpu::TextureFormat::Rgba16Float);
+        assert_eq!(color_space, OutputColorSpace::ExtendedLinearRec709);
+        let uniform = TonemapUniform::new(
+            &TonemapSettings::new().set_color_space(color_space),
+            get_output_encodes_srgb(wgpu::TextureFormat::Rgba16Float),
+        );
+        assert_eq!(uniform.output_encoding, OutputEncoding::Linear.get_index());
+        assert_eq!(uniform.primaries, TonemapUniform::new(&TonemapSettings::new(), false).primaries);
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "gpu_tests"), ignore = "needs a gpu adapter")]
+    fn test_tonemap_pass() {
+        let mut context = pollster::block_on(GpuContext::new_headless(4, 4)).unwrap();
+        let float = wgpu::TextureFormat::Rgba32Float;
+        let mut tonemap = |settings: TonemapSettings, color: Vec3| tonemap_on_gpu(&mut context, settings, float, color);
+
+        // reinhard maps the white point to 1.0
+        let reinhard = TonemapSettings::new().set_white_point(8.0);
+        assert!(tonemap(reinhard, Vec3::splat(8.0)).abs_diff_eq(Vec3::ONE, 1e-4));
+        assert!(tonemap(reinhard, Vec3::splat(2.0)).x < 1.0);
+        assert_eq!(tonemap(reinhard, Vec3::ZERO), Vec3::ZERO);
+        assert!(tonemap(TonemapSettings::new(), Vec3::splat(0.5)).x < 0.5);
+
+        // aces keeps black, rises monotonically and saturates towards 1.0
+        let aces = TonemapSettings::new().set_operator(TonemapOperator::AcesFilmic);
+        assert!(tonemap(aces, Vec3::ZERO).max_element() < 1e-4);
+        let values: Vec<f32> = [0.1, 0.5, 1.0, 4.0, 16.0]
+            .iter()
+            .map(|v| tonemap(aces, Vec3::splat(*v)).x)
+            .collect();
+        assert!(values.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", values);
+        assert!(values[4] > 0.95 && values[4] <= 1.0);
+
+        // passthrough only applies exposure
+        let passthrough = TonemapSettings::new().set_operator(TonemapOperator::Passthrough);
+        let color = tonemap(passthrough.set_exposure(2.0), vec3(0.25, 0.4, 0.45));
+        assert!(color.abs_diff_eq(vec3(0.5, 0.8, 0.9), 1e-3), "{}", color);
+
+        // scRGB keeps values above 1.0 as headroom
+        let scrgb = passthrough.set_color_space(OutputColorSpace::ExtendedLinearRec709);
+        let color = tonemap(scrgb, vec3(0.5, 2.0, 6.0));
+        assert!(color.abs_diff_eq(vec3(0.5, 2.0, 6.0), 1e-2), "{}", color);
+
+        // pq white is the paper white, pure rec.709 red moves inwards in the wider gamut
+        let pq = TonemapSettings::new()
+            .set_white_point(1.0)
+            .set_color_space(OutputColorSpace::Rec2020Pq);
+        let white = tonemap(pq, Vec3::ONE);
+        assert!(white.abs_diff_eq(Vec3::splat(0.5807), 2e-3), "{}", white);
+        let red = tonemap(pq, vec3(1.0, 0.0, 0.0));
+        assert!(red.x > red.y && red.y > 0.0 && red.z > 0.0, "{}", red);
+
+        // 8 bit outputs without srgb views are clamped and encoded in the shader
+        let srgb = tonemap_on_gpu(&mut context, passthrough, wgpu::TextureFormat::Rgba8Unorm, vec3(0.0, 0.214, 6.0));
+        assert!(srgb.abs_diff_eq(vec3(0.0, 0.5, 1.0), 3e-3), "{}", srgb);
+    }
+}
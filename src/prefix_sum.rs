@@ -0,0 +1,270 @@
+use std::borrow::Cow;
+use std::rc::Rc;
+
+use wgpu::{BindGroupLayout, Buffer, ComputePipeline};
+
+use crate::buffers::{create_uniform_buffer_init, update_uniform_buffer};
+use crate::capabilities::Capabilities;
+use crate::error::Error;
+use crate::error::Error::ValidationError;
+use crate::gpu_context::{get_or_create_bind_group_layout, GpuContext};
+
+pub const PREFIX_SUM_BIND_GROUP_LAYOUT: &str = "prefix sum bind group layout";
+
+// must match prefix_sum.wgsl, each thread scans two values
+pub const SCAN_WORKGROUP_SIZE: u32 = 256;
+pub const SCAN_BLOCK_SIZE: u32 = 2 * SCAN_WORKGROUP_SIZE;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct ScanUniform {
+    pub length: u32,
+    pub _padding: [u32; 3],
+}
+
+struct ScanLevel {
+    // totals of each block scanned at this level, scanned in turn by the next level
+    block_sums: Buffer,
+    uniform_buffer: Buffer,
+}
+
+// In place exclusive scan of a u32 storage buffer. Lengths over one block are handled
+// by scanning the block totals in further levels and adding them back.
+pub struct PrefixSum {
+    pub max_length: u32,
+    scan_pipeline: ComputePipeline,
+    add_pipeline: ComputePipeline,
+    bind_group_layout: Rc<BindGroupLayout>,
+    levels: Vec<ScanLevel>,
+}
+
+impl PrefixSum {
+    pub fn new(context: &mut GpuContext, max_length: u32) -> Result<Self, Error> {
+        check_prefix_sum_support(&context.capabilities, max_length)?;
+
+        let bind_group_layout =
+            get_or_create_bind_group_layout(context, PREFIX_SUM_BIND_GROUP_LAYOUT, create_prefix_sum_bind_group_layout);
+
+        let shader = context.device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("prefix sum shader"),
+            source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(include_str!("shaders/prefix_sum.wgsl"))),
+        });
+
+        let pipeline_layout = context.device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("prefix sum pipeline layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let scan_pipeline = context.device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("prefix sum scan pipeline"),
+            layout: Some(&pipeline_layout),
+            module: &shader,
+            entry_point: "scan_blocks",
+        });
+
+        let add_pipeline = context.device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("prefix sum add pipeline"),
+            layout: Some(&pipeline_layout),
+            module: &shader,
+            entry_point: "add_block_sums",
+        });
+
+        let levels = get_scan_levels(max_length)
+            .iter()
+            .map(|length| ScanLevel {
+                block_sums: context.device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some("prefix sum block sums"),
+                    size: (length.div_ceil(SCAN_BLOCK_SIZE).max(1) * 4) as wgpu::BufferAddress,
+                    usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+                    mapped_at_creation: false,
+                }),
+                uniform_buffer: create_uniform_buffer_init(&*context, &[get_scan_uniform(*length)], "prefix sum uniform"),
+            })
+            .collect();
+
+        Ok(PrefixSum {
+            max_length,
+            scan_pipeline,
+            add_pipeline,
+            bind_group_layout,
+            levels,
+        })
+    }
+
+    // Records the scan of the first length values of data, which needs STORAGE usage.
+    // The level uniforms are written through the queue so only one scan per submit is supported.
+    pub fn scan(&self, context: &GpuContext, encoder: &mut wgpu::CommandEncoder, data: &Buffer, length: u32) -> Result<(), Error> {
+        if length > self.max_length {
+            return Err(ValidationError(format!(
+                "prefix sum length {} exceeds max_length {}",
+                length, self.max_length
+            )));
+        }
+
+        if length == 0 {
+            return Ok(());
+        }
+
+        let lengths = get_scan_levels(length);
+
+        let bind_groups: Vec<wgpu::BindGroup> = lengths
+            .iter()
+            .enumerate()
+            .map(|(i, level_length)| {
+                let level = &self.levels[i];
+                update_uniform_buffer(context, &level.uniform_buffer, &[get_scan_uniform(*level_length)]);
+
+                let level_data = if i == 0 { data } else { &self.levels[i - 1].block_sums };
+
+                context.device.create_bind_group(&wgpu::BindGroupDescriptor {
+                    layout: &self.bind_group_layout,
+                    entries: &[
+                        wgpu::BindGroupEntry {
+                            binding: 0,
+                            resource: level_data.as_entire_binding(),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 1,
+                            resource: level.block_sums.as_entire_binding(),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 2,
+                            resource: level.uniform_buffer.as_entire_binding(),
+                        },
+                    ],
+                    label: Some("prefix sum bind group"),
+                })
+            })
+            .collect();
+
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("prefix sum"),
+            timestamp_writes: None,
+        });
+
+        // scan down to a single block, then add the scanned totals back up the levels
+        pass.set_pipeline(&self.scan_pipeline);
+        for (bind_group, level_length) in bind_groups.iter().zip(&lengths) {
+            pass.set_bind_group(0, bind_group, &[]);
+            pass.dispatch_workgroups(level_length.div_ceil(SCAN_BLOCK_SIZE), 1, 1);
+        }
+
+        pass.set_pipeline(&self.add_pipeline);
+        for (bind_group, level_length) in bind_groups.iter().zip(&lengths).rev().skip(1) {
+            pass.set_bind_group(0, bind_group, &[]);
+            pass.dispatch_workgroups(level_length.div_ceil(SCAN_BLOCK_SIZE), 1, 1);
+        }
+
+        Ok(())
+    }
+}
+
+pub fn check_prefix_sum_support(capabilities: &Capabilities, max_length: u32) -> Result<(), Error> {
+    capabilities.require_compute("prefix sum")?;
+    capabilities.require_storage_buffers("prefix sum")?;
+
+    let max_workgroups = capabilities.max_compute_workgroups_per_dimension;
+    if max_length.div_ceil(SCAN_BLOCK_SIZE) > max_workgroups {
+        return Err(ValidationError(format!(
+            "prefix sum length {} needs more than max_compute_workgroups_per_dimension ({}) workgroups",
+            max_length, max_workgroups
+        )));
+    }
+    Ok(())
+}
+
+fn get_scan_uniform(length: u32) -> ScanUniform {
+    ScanUniform {
+        length,
+        _padding: [0; 3],
+    }
+}
+
+// The length scanned at each level, every level after the first holds the block totals of the one before
+pub fn get_scan_levels(length: u32) -> Vec<u32> {
+    let mut levels = vec![length];
+    let mut length = length;
+    while length > SCAN_BLOCK_SIZE {
+        length = length.div_ceil(SCAN_BLOCK_SIZE);
+        levels.push(length);
+    }
+    levels
+}
+
+fn create_prefix_sum_bind_group_layout(context: &GpuContext, label: &str) -> BindGroupLayout {
+    let storage_entry = |binding| wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::COMPUTE,
+        ty: wgpu::BindingType::Buffer {
+            ty: wgpu::BufferBindingType::Storage { read_only: false },
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    };
+
+    context.device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        entries: &[
+            // data
+            storage_entry(0),
+            // block sums
+            storage_entry(1),
+            wgpu::BindGroupLayoutEntry {
+                binding: 2,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+        ],
+        label: Some(label),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use wgpu::util::DeviceExt;
+
+    use crate::buffers::{cast_slice, read_buffer, STORAGE_BUFFER_USAGE};
+    use crate::gpu_context::GpuContext;
+    use crate::prefix_sum::{get_scan_levels, PrefixSum, SCAN_BLOCK_SIZE};
+
+    fn scan_on_gpu(context: &mut GpuContext, values: &[u32]) -> Vec<u32> {
+        let prefix_sum = PrefixSum::new(context, values.len() as u32).unwrap();
+        let data = context.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("scanned values"),
+            contents: bytemuck::cast_slice(values),
+            usage: STORAGE_BUFFER_USAGE,
+        });
+
+        let mut encoder = context.device.create_command_encoder(&Default::default());
+        prefix_sum.scan(context, &mut encoder, &data, values.len() as u32).unwrap();
+        context.queue.submit(std::iter::once(encoder.finish()));
+
+        cast_slice::<u32>(&read_buffer(context, &data)).unwrap().to_vec()
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "gpu_tests"), ignore = "needs a gpu adapter")]
+    fn test_exclusive_scan() {
+        let mut context = pollster::block_on(GpuContext::new_headless(4, 4)).unwrap();
+        assert_eq!(scan_on_gpu(&mut context, &[1, 1, 1, 1]), vec![0, 1, 2, 3]);
+        assert_eq!(scan_on_gpu(&mut context, &[3, 0, 2, 5]), vec![0, 3, 3, 5]);
+
+        // several blocks need a second level
+        let scanned = scan_on_gpu(&mut context, &[1; 1500]);
+        assert!(scanned.iter().enumerate().all(|(i, value)| *value == i as u32));
+    }
+
+    #[test]
+    fn test_scan_levels() {
+        assert_eq!(get_scan_levels(4), vec![4]);
+        assert_eq!(get_scan_levels(SCAN_BLOCK_SIZE), vec![SCAN_BLOCK_SIZE]);
+        assert_eq!(get_scan_levels(1000), vec![1000, 2]);
+        assert_eq!(get_scan_levels(300_000), vec![300_000, 586, 2]);
+    }
+}
@@ -0,0 +1,363 @@
+use std::cell::RefCell;
+
+use wgpu::{Buffer, QuerySet};
+
+use crate::gpu_context::GpuContext;
+
+// Optional device features requested by GpuContext when the profiling feature is enabled
+pub const PROFILING_FEATURES: wgpu::Features = wgpu::Features::PIPELINE_STATISTICS_QUERY.union(wgpu::Features::TIMESTAMP_QUERY);
+
+// Each statistic is resolved as a u64, in the bit order of PipelineStatisticsTypes
+pub fn get_statistics_count(types: wgpu::PipelineStatisticsTypes) -> u32 {
+    types.bits().count_ones()
+}
+
+pub fn get_statistics_buffer_size(types: wgpu::PipelineStatisticsTypes, max_scopes: u32) -> wgpu::BufferAddress {
+    (get_statistics_count(types) * max_scopes) as wgpu::BufferAddress * std::mem::size_of::<u64>() as wgpu::BufferAddress
+}
+
+// Invocation counts of one scope, statistics that weren't requested are None
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PipelineStatistics {
+    pub vertex_shader_invocations: Option<u64>,
+    pub clipper_invocations: Option<u64>,
+    pub clipper_primitives_out: Option<u64>,
+    pub fragment_shader_invocations: Option<u64>,
+    pub compute_shader_invocations: Option<u64>,
+}
+
+impl PipelineStatistics {
+    pub fn from_results(types: wgpu::PipelineStatisticsTypes, results: &[u64]) -> Self {
+        let mut values = results.iter().copied();
+        let mut next = |statistic: wgpu::PipelineStatisticsTypes| if types.contains(statistic) { values.next() } else { None };
+
+        PipelineStatistics {
+            vertex_shader_invocations: next(wgpu::PipelineStatisticsTypes::VERTEX_SHADER_INVOCATIONS),
+            clipper_invocations: next(wgpu::PipelineStatisticsTypes::CLIPPER_INVOCATIONS),
+            clipper_primitives_out: next(wgpu::PipelineStatisticsTypes::CLIPPER_PRIMITIVES_OUT),
+            fragment_shader_invocations: next(wgpu::PipelineStatisticsTypes::FRAGMENT_SHADER_INVOCATIONS),
+            compute_shader_invocations: next(wgpu::PipelineStatisticsTypes::COMPUTE_SHADER_INVOCATIONS),
+        }
+    }
+}
+
+struct StatisticsQueries {
+    query_set: QuerySet,
+    resolve_buffer: Buffer,
+    readback_buffer: Buffer,
+}
+
+// Wraps passes in pipeline statistics queries and reads the counts of each labelled scope back.
+// Does nothing when the device doesn't support PIPELINE_STATISTICS_QUERY or all scopes are used.
+pub struct PipelineStatisticsProfiler {
+    pub types: wgpu::PipelineStatisticsTypes,
+    pub max_scopes: u32,
+    queries: Option<StatisticsQueries>,
+    scopes: RefCell<Vec<String>>,
+}
+
+impl PipelineStatisticsProfiler {
+    pub fn new(context: &GpuContext, types: wgpu::PipelineStatisticsTypes, max_scopes: u32) -> Self {
+        let supported = context.device.features().contains(wgpu::Features::PIPELINE_STATISTICS_QUERY);
+
+        let queries = (supported && max_scopes > 0 && !types.is_empty()).then(|| {
+            let size = get_statistics_buffer_size(types, max_scopes);
+            StatisticsQueries {
+                query_set: context.device.create_query_set(&wgpu::QuerySetDescriptor {
+                    label: Some("pipeline statistics"),
+                    ty: wgpu::QueryType::PipelineStatistics(types),
+                    count: max_scopes,
+                }),
+                resolve_buffer: context.device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some("pipeline statistics resolve"),
+                    size,
+                    usage: wgpu::BufferUsages::QUERY_RESOLVE | wgpu::BufferUsages::COPY_SRC,
+                    mapped_at_creation: false,
+                }),
+                readback_buffer: context.device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some("pipeline statistics readback"),
+                    size,
+                    usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+                    mapped_at_creation: false,
+                }),
+            }
+        });
+
+        PipelineStatisticsProfiler {
+            types,
+            max_scopes,
+            queries,
+            scopes: RefCell::new(vec![]),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.queries.is_some()
+    }
+
+    fn begin_scope(&self, label: &str) -> Option<(&QuerySet, u32)> {
+        let queries = self.queries.as_ref()?;
+        let mut scopes = self.scopes.borrow_mut();
+        if scopes.len() as u32 >= self.max_scopes {
+            return None;
+        }
+        scopes.push(label.to_string());
+        Some((&queries.query_set, scopes.len() as u32 - 1))
+    }
+
+    pub fn scope_render_pass<'a>(&'a self, label: &str, pass: &mut wgpu::RenderPass<'a>, record: impl FnOnce(&mut wgpu::RenderPass<'a>)) {
+        match self.begin_scope(label) {
+            Some((query_set, index)) => {
+                pass.begin_pipeline_statistics_query(query_set, index);
+                record(pass);
+                pass.end_pipeline_statistics_query();
+            }
+            None => record(pass),
+        }
+    }
+
+    pub fn scope_compute_pass<'a>(
+        &'a self,
+        label: &str,
+        pass: &mut wgpu::ComputePass<'a>,
+        record: impl FnOnce(&mut wgpu::ComputePass<'a>),
+    ) {
+        match self.begin_scope(label) {
+            Some((query_set, index)) => {
+                pass.begin_pipeline_statistics_query(query_set, index);
+                record(pass);
+                pass.end_pipeline_statistics_query();
+            }
+            None => record(pass),
+        }
+    }
+
+    // Call once after the last scope of the frame is recorded
+    pub fn resolve(&self, encoder: &mut wgpu::CommandEncoder) {
+        let Some(queries) = &self.queries else {
+            return;
+        };
+        let count = self.scopes.borrow().len() as u32;
+        if count == 0 {
+            return;
+        }
+
+        encoder.resolve_query_set(&queries.query_set, 0..count, &queries.resolve_buffer, 0);
+        encoder.copy_buffer_to_buffer(
+            &queries.resolve_buffer,
+            0,
+            &queries.readback_buffer,
+            0,
+            get_statistics_buffer_size(self.types, count),
+        );
+    }
+
+    // Blocks until the resolved frame is available, then starts collecting scopes for the next one
+    pub fn read_results(&self, context: &GpuContext) -> Vec<(String, PipelineStatistics)> {
+        let scopes = std::mem::take(&mut *self.scopes.borrow_mut());
+        let Some(queries) = &self.queries else {
+            return vec![];
+        };
+        if scopes.is_empty() {
+            return vec![];
+        }
+
+        let size = get_statistics_buffer_size(self.types, scopes.len() as u32);
+        let buffer_slice = queries.readback_buffer.slice(..size);
+        buffer_slice.map_async(wgpu::MapMode::Read, |_| ());
+        context.device.poll(wgpu::Maintain::Wait);
+
+        let results: Vec<u64> = bytemuck::cast_slice(&buffer_slice.get_mapped_range()).to_vec();
+        queries.readback_buffer.unmap();
+
+        let count = get_statistics_count(self.types) as usize;
+        scopes
+            .into_iter()
+            .zip(results.chunks_exact(count))
+            .map(|(label, values)| (label, PipelineStatistics::from_results(self.types, values)))
+            .collect()
+    }
+}
+
+// A begin and an end timestamp per scope, each resolved as a u64
+pub fn get_timestamp_buffer_size(max_scopes: u32) -> wgpu::BufferAddress {
+    (2 * max_scopes) as wgpu::BufferAddress * std::mem::size_of::<u64>() as wgpu::BufferAddress
+}
+
+// Milliseconds from the begin to the end timestamp of each scope, period is nanoseconds per tick
+pub fn get_scope_durations_ms(timestamps: &[u64], period: f32) -> Vec<f32> {
+    timestamps
+        .chunks_exact(2)
+        .map(|pair| (pair[1].saturating_sub(pair[0]) as f64 * period as f64 / 1_000_000.0) as f32)
+        .collect()
+}
+
+struct TimestampQueries {
+    query_set: QuerySet,
+    resolve_buffer: Buffer,
+    readback_buffer: Buffer,
+}
+
+// Times labelled passes with timestamps written at the beginning and end of each pass, and reads
+// the durations back in milliseconds. Does nothing when the device doesn't support TIMESTAMP_QUERY
+// or all scopes are used.
+pub struct GpuTimer {
+    pub max_scopes: u32,
+    queries: Option<TimestampQueries>,
+    scopes: RefCell<Vec<String>>,
+    // nanoseconds per timestamp tick
+    period: f32,
+}
+
+impl GpuTimer {
+    pub fn new(context: &GpuContext, max_scopes: u32) -> Self {
+        let supported = context.device.features().contains(wgpu::Features::TIMESTAMP_QUERY);
+
+        let queries = (supported && max_scopes > 0).then(|| {
+            let size = get_timestamp_buffer_size(max_scopes);
+            TimestampQueries {
+                query_set: context.device.create_query_set(&wgpu::QuerySetDescriptor {
+                    label: Some("gpu timer"),
+                    ty: wgpu::QueryType::Timestamp,
+                    count: 2 * max_scopes,
+                }),
+                resolve_buffer: context.device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some("gpu timer resolve"),
+                    size,
+                    usage: wgpu::BufferUsages::QUERY_RESOLVE | wgpu::BufferUsages::COPY_SRC,
+                    mapped_at_creation: false,
+                }),
+                readback_buffer: context.device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some("gpu timer readback"),
+                    size,
+                    usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+                    mapped_at_creation: false,
+                }),
+            }
+        });
+
+        GpuTimer {
+            max_scopes,
+            queries,
+            scopes: RefCell::new(vec![]),
+            period: context.queue.get_timestamp_period(),
+        }
+    }
+
+    // False when the device has no timestamp queries, the passes are then recorded untimed
+    pub fn is_enabled(&self) -> bool {
+        self.queries.is_some()
+    }
+
+    // The query set and the index of the scope's begin timestamp, the end is the next index
+    fn begin_scope(&self, label: &str) -> Option<(&QuerySet, u32)> {
+        let queries = self.queries.as_ref()?;
+        let mut scopes = self.scopes.borrow_mut();
+        if scopes.len() as u32 >= self.max_scopes {
+            return None;
+        }
+        scopes.push(label.to_string());
+        Some((&queries.query_set, 2 * (scopes.len() as u32 - 1)))
+    }
+
+    // Fills in the timestamp_writes of the descriptor so the pass created from it is timed
+    pub fn begin<'desc>(&'desc self, descriptor: &mut wgpu::RenderPassDescriptor<'_, 'desc>, label: &str) {
+        if let Some((query_set, index)) = self.begin_scope(label) {
+            descriptor.timestamp_writes = Some(wgpu::RenderPassTimestampWrites {
+                query_set,
+                beginning_of_pass_write_index: Some(index),
+                end_of_pass_write_index: Some(index + 1),
+            });
+        }
+    }
+
+    pub fn begin_compute<'desc>(&'desc self, descriptor: &mut wgpu::ComputePassDescriptor<'desc>, label: &str) {
+        if let Some((query_set, index)) = self.begin_scope(label) {
+            descriptor.timestamp_writes = Some(wgpu::ComputePassTimestampWrites {
+                query_set,
+                beginning_of_pass_write_index: Some(index),
+                end_of_pass_write_index: Some(index + 1),
+            });
+        }
+    }
+
+    // Call once after the last timed pass of the frame is recorded, before the encoder is submitted
+    pub fn resolve(&self, encoder: &mut wgpu::CommandEncoder) {
+        let Some(queries) = &self.queries else {
+            return;
+        };
+        let count = self.scopes.borrow().len() as u32;
+        if count == 0 {
+            return;
+        }
+
+        encoder.resolve_query_set(&queries.query_set, 0..2 * count, &queries.resolve_buffer, 0);
+        encoder.copy_buffer_to_buffer(
+            &queries.resolve_buffer,
+            0,
+            &queries.readback_buffer,
+            0,
+            get_timestamp_buffer_size(count),
+        );
+    }
+
+    // Blocks until the submitted frame is done, then returns the milliseconds of each scope in
+    // recording order and starts collecting scopes for the next frame
+    pub fn read_results(&self, context: &GpuContext) -> Vec<(String, f32)> {
+        let scopes = std::mem::take(&mut *self.scopes.borrow_mut());
+        let Some(queries) = &self.queries else {
+            return vec![];
+        };
+        if scopes.is_empty() {
+            return vec![];
+        }
+
+        let size = get_timestamp_buffer_size(scopes.len() as u32);
+        let buffer_slice = queries.readback_buffer.slice(..size);
+        buffer_slice.map_async(wgpu::MapMode::Read, |_| ());
+        context.device.poll(wgpu::Maintain::Wait);
+
+        let timestamps: Vec<u64> = bytemuck::cast_slice(&buffer_slice.get_mapped_range()).to_vec();
+        queries.readback_buffer.unmap();
+
+        scopes.into_iter().zip(get_scope_durations_ms(&timestamps, self.period)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wgpu::PipelineStatisticsTypes;
+
+    use crate::profiler::{
+        get_scope_durations_ms, get_statistics_buffer_size, get_statistics_count, get_timestamp_buffer_size, PipelineStatistics,
+    };
+
+    #[test]
+    fn test_statistics_query_size() {
+        let types = PipelineStatisticsTypes::VERTEX_SHADER_INVOCATIONS | PipelineStatisticsTypes::FRAGMENT_SHADER_INVOCATIONS;
+        assert_eq!(get_statistics_count(types), 2);
+        assert_eq!(get_statistics_buffer_size(types, 4), 2 * 4 * 8);
+        assert_eq!(get_statistics_count(PipelineStatisticsTypes::all()), 5);
+        assert_eq!(get_statistics_buffer_size(PipelineStatisticsTypes::empty(), 4), 0);
+
+        let statistics = PipelineStatistics::from_results(types, &[300, 1920 * 1080]);
+        assert_eq!(statistics.vertex_shader_invocations, Some(300));
+        assert_eq!(statistics.fragment_shader_invocations, Some(1920 * 1080));
+        assert_eq!(statistics.clipper_invocations, None);
+    }
+
+    #[test]
+    fn test_timestamp_durations() {
+        assert_eq!(get_timestamp_buffer_size(3), 3 * 2 * 8);
+
+        // a 2ms shadow pass and a 0.5ms forward pass with one nanosecond ticks
+        let timestamps = [1_000, 2_001_000, 3_000_000, 3_500_000];
+        assert_eq!(get_scope_durations_ms(&timestamps, 1.0), vec![2.0, 0.5]);
+
+        // the period converts ticks to nanoseconds
+        assert_eq!(get_scope_durations_ms(&timestamps[..2], 40.0), vec![80.0]);
+
+        // timestamps can go backwards across power state changes, those scopes read as zero
+        assert_eq!(get_scope_durations_ms(&[500, 100], 1.0), vec![0.0]);
+    }
+}
@@ -0,0 +1,155 @@
+use wgpu::TextureView;
+
+// Attachments of a render pass. Color is stored, depth is discarded unless keep_depth is set, and
+// a None clear loads the previous contents. Without color attachments the pass is depth only, e.g.
+// for shadow maps.
+//
+//   let mut pass = RenderPassBuilder::new()
+//       .label("forward")
+//       .color(&target_view, Some(wgpu::Color::BLACK))
+//       .depth(&depth_view, Some(1.0))
+//       .begin(&mut encoder);
+#[derive(Debug, Clone, Default)]
+pub struct RenderPassBuilder<'a> {
+    pub label: Option<&'a str>,
+    pub color_attachments: Vec<Option<wgpu::RenderPassColorAttachment<'a>>>,
+    pub depth_view: Option<&'a TextureView>,
+    pub depth_clear: Option<f32>,
+    pub keep_depth: bool,
+}
+
+impl<'a> RenderPassBuilder<'a> {
+    pub fn new() -> Self {
+        RenderPassBuilder::default()
+    }
+
+    pub fn label(mut self, label: &'a str) -> Self {
+        self.label = Some(label);
+        self
+    }
+
+    pub fn color(self, view: &'a TextureView, clear: Option<wgpu::Color>) -> Self {
+        self.color_attachment(wgpu::RenderPassColorAttachment {
+            view,
+            resolve_target: None,
+            ops: get_color_ops(clear),
+        })
+    }
+
+    // One attachment per view in @location order, e.g. the targets of a gbuffer
+    pub fn colors(self, views: impl IntoIterator<Item = &'a TextureView>, clear: Option<wgpu::Color>) -> Self {
+        views.into_iter().fold(self, |builder, view| builder.color(view, clear))
+    }
+
+    // For attachments with a resolve target or other store ops
+    pub fn color_attachment(mut self, attachment: wgpu::RenderPassColorAttachment<'a>) -> Self {
+        self.color_attachments.push(Some(attachment));
+        self
+    }
+
+    pub fn depth(mut self, view: &'a TextureView, clear: Option<f32>) -> Self {
+        self.depth_view = Some(view);
+        self.depth_clear = clear;
+        self
+    }
+
+    // Stores depth for later passes, e.g. shadow maps or a depth buffer read by the lighting pass
+    pub fn keep_depth(mut self) -> Self {
+        self.keep_depth = true;
+        self
+    }
+
+    // For filling in timestamp_writes, e.g. GpuTimer::begin, before the pass is begun from it
+    pub fn descriptor(&self) -> wgpu::RenderPassDescriptor<'a, '_> {
+        wgpu::RenderPassDescriptor {
+            label: self.label,
+            color_attachments: &self.color_attachments,
+            depth_stencil_attachment: self.depth_view.map(|view| wgpu::RenderPassDepthStencilAttachment {
+                view,
+                depth_ops: Some(get_depth_ops(self.depth_clear, self.keep_depth)),
+                stencil_ops: None,
+            }),
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        }
+    }
+
+    pub fn begin<'pass>(&self, encoder: &'pass mut wgpu::CommandEncoder) -> wgpu::RenderPass<'pass>
+    where
+        'a: 'pass,
+    {
+        encoder.begin_render_pass(&self.descriptor())
+    }
+}
+
+pub fn get_color_ops(clear: Option<wgpu::Color>) -> wgpu::Operations<wgpu::Color> {
+    wgpu::Operations {
+        load: clear.map_or(wgpu::LoadOp::Load, wgpu::LoadOp::Clear),
+        store: wgpu::StoreOp::Store,
+    }
+}
+
+pub fn get_depth_ops(clear: Option<f32>, keep: bool) -> wgpu::Operations<f32> {
+    wgpu::Operations {
+        load: clear.map_or(wgpu::LoadOp::Load, wgpu::LoadOp::Clear),
+        store: match keep {
+            true => wgpu::StoreOp::Store,
+            false => wgpu::StoreOp::Discard,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::gpu_context::GpuContext;
+    use crate::render::{get_color_ops, get_depth_ops, RenderPassBuilder};
+    use crate::texture::create_depth_texture;
+
+    #[test]
+    fn test_attachment_ops() {
+        let clear = get_color_ops(Some(wgpu::Color::RED));
+        assert_eq!(clear.load, wgpu::LoadOp::Clear(wgpu::Color::RED));
+        assert_eq!(clear.store, wgpu::StoreOp::Store);
+        assert_eq!(get_color_ops(None).load, wgpu::LoadOp::Load);
+
+        let forward_depth = get_depth_ops(Some(1.0), false);
+        assert_eq!(forward_depth.load, wgpu::LoadOp::Clear(1.0));
+        assert_eq!(forward_depth.store, wgpu::StoreOp::Discard);
+
+        let shadow_depth = get_depth_ops(None, true);
+        assert_eq!(shadow_depth.load, wgpu::LoadOp::Load);
+        assert_eq!(shadow_depth.store, wgpu::StoreOp::Store);
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "gpu_tests"), ignore = "needs a gpu adapter")]
+    fn test_render_pass_builder() {
+        let context = pollster::block_on(GpuContext::new_headless(4, 2)).unwrap();
+        let texture = context.offscreen_texture.as_ref().unwrap();
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let depth = create_depth_texture(&context);
+
+        // depth only
+        let builder = RenderPassBuilder::new()
+            .label("depth only")
+            .depth(&depth.view, Some(1.0))
+            .keep_depth();
+        let descriptor = builder.descriptor();
+        assert!(descriptor.color_attachments.is_empty());
+        assert_eq!(
+            descriptor.depth_stencil_attachment.unwrap().depth_ops.unwrap().store,
+            wgpu::StoreOp::Store
+        );
+
+        let mut encoder = context.device.create_command_encoder(&Default::default());
+        builder.begin(&mut encoder);
+        RenderPassBuilder::new()
+            .color(&view, Some(wgpu::Color::WHITE))
+            .depth(&depth.view, None)
+            .begin(&mut encoder);
+        context.queue.submit(std::iter::once(encoder.finish()));
+
+        let pixels = context.read_texture_to_rgba(texture, 4, 2).unwrap();
+        assert!(pixels.iter().all(|value| *value == 255));
+    }
+}
@@ -0,0 +1,93 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::gpu_context::GpuContext;
+
+// Returns false once the resource is gone so the callback can be dropped
+type ResizeCallback<C> = Box<dyn FnMut(&C, u32, u32) -> bool>;
+
+// Recreate callbacks of size dependent resources, called by GpuContext::resize with the new
+// surface size. Generic over the context so the callbacks can be tested without a device.
+pub struct ResizeRegistry<C = GpuContext> {
+    callbacks: Vec<ResizeCallback<C>>,
+}
+
+impl<C> Default for ResizeRegistry<C> {
+    fn default() -> Self {
+        ResizeRegistry { callbacks: vec![] }
+    }
+}
+
+impl<C> ResizeRegistry<C> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The callback stays registered for the lifetime of the registry
+    pub fn register(&mut self, mut callback: impl FnMut(&C, u32, u32) + 'static) {
+        self.callbacks.push(Box::new(move |context: &C, width, height| {
+            callback(context, width, height);
+            true
+        }));
+    }
+
+    // Only a weak reference is kept, the callback is dropped with the resource
+    pub fn register_resource<T: 'static>(&mut self, resource: &Rc<RefCell<T>>, recreate: impl Fn(&mut T, &C, u32, u32) + 'static) {
+        let resource = Rc::downgrade(resource);
+        self.callbacks
+            .push(Box::new(move |context: &C, width, height| match resource.upgrade() {
+                Some(resource) => {
+                    recreate(&mut resource.borrow_mut(), context, width, height);
+                    true
+                }
+                None => false,
+            }));
+    }
+
+    pub fn resize(&mut self, context: &C, width: u32, height: u32) {
+        self.callbacks.retain_mut(|callback| callback(context, width, height));
+    }
+
+    // Moves the callbacks of other, e.g. ones registered while resizing, into this registry
+    pub fn append(&mut self, other: &mut ResizeRegistry<C>) {
+        self.callbacks.append(&mut other.callbacks);
+    }
+
+    pub fn len(&self) -> usize {
+        self.callbacks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.callbacks.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+
+    use crate::resize_registry::ResizeRegistry;
+
+    #[test]
+    fn test_resize_callbacks() {
+        let mut registry = ResizeRegistry::<()>::new();
+
+        let size = Rc::new(Cell::new((0, 0)));
+        let size_ref = size.clone();
+        registry.register(move |_, width, height| size_ref.set((width, height)));
+
+        let target = Rc::new(RefCell::new(vec![0u8; 4]));
+        registry.register_resource(&target, |target, _, width, height| target.resize((width * height) as usize, 0));
+
+        registry.resize(&(), 1280, 720);
+        assert_eq!(size.get(), (1280, 720));
+        assert_eq!(target.borrow().len(), 1280 * 720);
+
+        // dropped resources are removed at the next resize
+        drop(target);
+        registry.resize(&(), 640, 480);
+        assert_eq!(size.get(), (640, 480));
+        assert_eq!(registry.len(), 1);
+    }
+}
@@ -0,0 +1,186 @@
+use std::path::Path;
+
+use glam::{EulerRot, Quat, Vec3};
+use serde::Deserialize;
+
+use crate::error::Error;
+use crate::error::Error::SceneError;
+use crate::transform::Transform;
+
+// Data driven scene description loaded from json. The application decides how meshes
+// are resolved, a mesh is either a model path or the name of a mesh it builds itself.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SceneFile {
+    #[serde(default)]
+    pub entities: Vec<EntityDesc>,
+    #[serde(default)]
+    pub lights: Vec<LightDesc>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct EntityDesc {
+    pub mesh: String,
+    #[serde(default)]
+    pub transform: TransformDesc,
+    #[serde(default)]
+    pub material: MaterialDesc,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct TransformDesc {
+    pub translation: [f32; 3],
+    // euler angles in degrees, applied in x y z order
+    pub rotation: [f32; 3],
+    pub scale: [f32; 3],
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct MaterialDesc {
+    pub color: [f32; 4],
+    pub diffuse_texture: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LightType {
+    Point,
+    Directional,
+    Spot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct LightDesc {
+    #[serde(rename = "type")]
+    pub light_type: LightType,
+    #[serde(default)]
+    pub position: [f32; 3],
+    #[serde(default = "default_light_color")]
+    pub color: [f32; 3],
+    #[serde(default = "default_light_intensity")]
+    pub intensity: f32,
+    // full cone angle in degrees, only used by spot lights
+    #[serde(default = "default_spot_angle")]
+    pub spot_angle: f32,
+    // shadow cascades splitting the camera frustum, only used by directional lights
+    #[serde(default = "default_cascade_count")]
+    pub cascade_count: u32,
+}
+
+impl Default for TransformDesc {
+    fn default() -> Self {
+        TransformDesc {
+            translation: [0.0; 3],
+            rotation: [0.0; 3],
+            scale: [1.0; 3],
+        }
+    }
+}
+
+impl TransformDesc {
+    pub fn to_transform(&self) -> Transform {
+        let [x, y, z] = self.rotation;
+        Transform {
+            translation: Vec3::from(self.translation),
+            rotation: Quat::from_euler(EulerRot::XYZ, x.to_radians(), y.to_radians(), z.to_radians()),
+            scale: Vec3::from(self.scale),
+        }
+    }
+}
+
+impl Default for MaterialDesc {
+    fn default() -> Self {
+        MaterialDesc {
+            color: [1.0; 4],
+            diffuse_texture: None,
+        }
+    }
+}
+
+impl LightDesc {
+    pub fn get_position(&self) -> Vec3 {
+        Vec3::from(self.position)
+    }
+
+    // color scaled by intensity
+    pub fn get_radiance(&self) -> Vec3 {
+        Vec3::from(self.color) * self.intensity
+    }
+}
+
+fn default_light_color() -> [f32; 3] {
+    [1.0; 3]
+}
+
+fn default_light_intensity() -> f32 {
+    1.0
+}
+
+fn default_spot_angle() -> f32 {
+    45.0
+}
+
+fn default_cascade_count() -> u32 {
+    3
+}
+
+pub fn parse_scene(text: &str) -> Result<SceneFile, Error> {
+    serde_json::from_str(text).map_err(|e| SceneError(format!("invalid scene file: {}", e)))
+}
+
+pub fn load_scene(path: impl AsRef<Path>) -> Result<SceneFile, Error> {
+    let text = std::fs::read_to_string(path.as_ref())?;
+    parse_scene(&text)
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{vec3, Vec3};
+
+    use crate::error::Error;
+    use crate::scene_file::{parse_scene, LightType};
+
+    const SCENE: &str = r#"{
+        "entities": [
+            { "mesh": "plane" },
+            {
+                "mesh": "cube",
+                "transform": { "translation": [2.0, -2.0, 2.0], "scale": [1.5, 1.5, 1.5] },
+                "material": { "color": [0.0, 1.0, 0.0, 1.0] }
+            }
+        ],
+        "lights": [
+            { "type": "spot", "position": [7.0, -5.0, 10.0], "color": [0.5, 1.0, 0.5], "intensity": 2.0 },
+            { "type": "directional", "position": [1.0, -1.0, 3.0], "cascade_count": 4 }
+        ]
+    }"#;
+
+    #[test]
+    fn test_parse_scene() {
+        let scene = parse_scene(SCENE).unwrap();
+        assert_eq!(scene.entities.len(), 2);
+        assert_eq!(scene.lights.len(), 2);
+
+        // defaults for omitted fields
+        let plane = &scene.entities[0];
+        assert_eq!(plane.transform.to_transform().compute_matrix(), glam::Mat4::IDENTITY);
+        assert_eq!(plane.material.color, [1.0; 4]);
+
+        let cube = scene.entities[1].transform.to_transform();
+        assert_eq!(cube.translation, vec3(2.0, -2.0, 2.0));
+        assert_eq!(cube.scale, Vec3::splat(1.5));
+
+        let light = &scene.lights[0];
+        assert_eq!(light.light_type, LightType::Spot);
+        assert_eq!(light.get_radiance(), vec3(1.0, 2.0, 1.0));
+        assert_eq!(light.spot_angle, 45.0);
+        assert_eq!(light.cascade_count, 3);
+
+        let sun = &scene.lights[1];
+        assert_eq!(sun.light_type, LightType::Directional);
+        assert_eq!(sun.cascade_count, 4);
+
+        assert!(matches!(parse_scene(r#"{ "entities": [{ "transform": {} }] }"#), Err(Error::SceneError(_))));
+    }
+}
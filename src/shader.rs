@@ -0,0 +1,319 @@
+use std::path::Path;
+#[cfg(feature = "hot_reload")]
+use std::path::PathBuf;
+#[cfg(feature = "hot_reload")]
+use std::sync::mpsc::{channel, Receiver};
+
+#[cfg(feature = "hot_reload")]
+use log::{error, info, warn};
+use wgpu::ShaderModule;
+
+use crate::error::Error;
+#[cfg(feature = "hot_reload")]
+use crate::error::Error::ShaderError;
+use crate::gpu_context::GpuContext;
+use crate::shader_preprocessor::expand_includes_from_path;
+#[cfg(feature = "reflection")]
+use crate::shader_preprocessor::ExpandedSource;
+
+// A shader module that is recompiled when its wgsl file changes, so shaders can be edited while
+// the app runs. Watching needs the hot_reload feature. Without it, or for shaders created from a
+// string, poll_reload never reports a reload.
+pub struct HotReloadShader {
+    pub label: String,
+    module: ShaderModule,
+    #[cfg(feature = "hot_reload")]
+    watch: Option<ShaderWatch>,
+}
+
+#[cfg(feature = "hot_reload")]
+struct ShaderWatch {
+    path: PathBuf,
+    // the included files of shaders from from_path_with_includes, None for shaders without includes
+    includes: Option<Vec<PathBuf>>,
+    // the parent directories of the shader and its includes are watched since editors often save by
+    // replacing the file
+    watcher: notify::RecommendedWatcher,
+    directories: Vec<PathBuf>,
+    events: Receiver<notify::Result<notify::Event>>,
+}
+
+#[cfg(feature = "hot_reload")]
+impl ShaderWatch {
+    // Adds the directories of includes added since the last call, a failed watch is logged and
+    // retried after the next reload
+    fn watch_directories(&mut self) {
+        use notify::Watcher;
+
+        let paths = std::iter::once(&self.path).chain(self.includes.iter().flatten());
+        for directory in paths.filter_map(|path| path.parent()) {
+            if self.directories.iter().any(|watched| watched == directory) {
+                continue;
+            }
+            match self.watcher.watch(directory, notify::RecursiveMode::NonRecursive) {
+                Ok(()) => self.directories.push(directory.to_path_buf()),
+                Err(error) => warn!("not watching {}: {}", directory.display(), error),
+            }
+        }
+    }
+}
+
+impl HotReloadShader {
+    pub fn from_path(context: &GpuContext, path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let source = std::fs::read_to_string(path)?;
+        let label = path.display().to_string();
+
+        #[cfg(feature = "hot_reload")]
+        check_wgsl(&source).map_err(|message| ShaderError(format!("{}: {}", label, message)))?;
+
+        let module = context.create_shader_module(&label, &source);
+
+        Ok(HotReloadShader {
+            #[cfg(feature = "hot_reload")]
+            watch: watch_shader(path, None),
+            label,
+            module,
+        })
+    }
+
+    // Expands //!include directives relative to the including file, see expand_includes. Errors
+    // name the file and line the failing code came from, and edits to the included files reload
+    // the shader too.
+    pub fn from_path_with_includes(context: &GpuContext, path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let expanded = expand_includes_from_path(path)?;
+        let label = path.display().to_string();
+
+        #[cfg(feature = "hot_reload")]
+        check_expanded_wgsl(&expanded).map_err(ShaderError)?;
+
+        let module = context.create_shader_module(&label, &expanded.source);
+
+        Ok(HotReloadShader {
+            #[cfg(feature = "hot_reload")]
+            watch: watch_shader(path, Some(get_include_paths(&expanded))),
+            label,
+            module,
+        })
+    }
+
+    // Nothing to watch, e.g. for shaders embedded with include_str! in release builds
+    pub fn from_str(context: &GpuContext, label: &str, source: &str) -> Self {
+        HotReloadShader {
+            label: label.to_string(),
+            module: context.create_shader_module(label, source),
+            #[cfg(feature = "hot_reload")]
+            watch: None,
+        }
+    }
+
+    pub fn module(&self) -> &ShaderModule {
+        &self.module
+    }
+
+    // Called once per frame. Returns true when the module was recreated and the pipelines using
+    // it have to be rebuilt, the context's pipeline cache drops them. A shader that fails to compile
+    // is logged and the last good module kept.
+    #[cfg(feature = "hot_reload")]
+    pub fn poll_reload(&mut self, context: &GpuContext) -> bool {
+        let Some(watch) = &mut self.watch else {
+            return false;
+        };
+
+        let changed = watch.events.try_iter().fold(false, |changed, event| match event {
+            Ok(event) => {
+                changed
+                    || is_shader_change(&event, &watch.path)
+                    || watch.includes.iter().flatten().any(|include| is_shader_change(&event, include))
+            }
+            Err(error) => {
+                warn!("watching {}: {}", self.label, error);
+                changed
+            }
+        });
+        if !changed {
+            return false;
+        }
+
+        let source = match &watch.includes {
+            Some(_) => expand_includes_from_path(&watch.path),
+            None => std::fs::read_to_string(&watch.path)
+                .map(|source| ExpandedSource::without_includes(&self.label, &source))
+                .map_err(Error::from),
+        };
+        let source = match source {
+            Ok(source) => source,
+            Err(error) => {
+                error!("reading {}: {:?}", self.label, error);
+                return false;
+            }
+        };
+
+        if let Err(message) = check_expanded_wgsl(&source) {
+            error!("{} failed to compile, keeping the previous module\n{}", self.label, message);
+            return false;
+        }
+
+        if let Some(includes) = &mut watch.includes {
+            *includes = get_include_paths(&source);
+            watch.watch_directories();
+        }
+        context.binding_registry.remove_shader(self.module.global_id());
+        context.pipeline_cache.borrow_mut().invalidate(self.module.global_id());
+        self.module = context.create_shader_module(&self.label, &source.source);
+        info!("reloaded {}", self.label);
+        true
+    }
+
+    #[cfg(not(feature = "hot_reload"))]
+    pub fn poll_reload(&mut self, _context: &GpuContext) -> bool {
+        false
+    }
+}
+
+// The included files, canonical like the watched shader path to compare with event paths
+#[cfg(feature = "hot_reload")]
+fn get_include_paths(expanded: &ExpandedSource) -> Vec<PathBuf> {
+    expanded.files[1..]
+        .iter()
+        .filter_map(|file| Path::new(file).canonicalize().ok())
+        .collect()
+}
+
+// A failed watch leaves the shader working, just without reloads
+#[cfg(feature = "hot_reload")]
+fn watch_shader(path: &Path, includes: Option<Vec<PathBuf>>) -> Option<ShaderWatch> {
+    let path = match path.canonicalize() {
+        Ok(path) => path,
+        Err(error) => {
+            warn!("not watching {}: {}", path.display(), error);
+            return None;
+        }
+    };
+
+    let (sender, events) = channel();
+    let watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        let _ = sender.send(event);
+    });
+    let watcher = match watcher {
+        Ok(watcher) => watcher,
+        Err(error) => {
+            warn!("not watching {}: {}", path.display(), error);
+            return None;
+        }
+    };
+
+    let mut watch = ShaderWatch {
+        path,
+        includes,
+        watcher,
+        directories: vec![],
+        events,
+    };
+    watch.watch_directories();
+    match watch.directories.is_empty() {
+        true => None,
+        false => Some(watch),
+    }
+}
+
+#[cfg(feature = "hot_reload")]
+pub fn is_shader_change(event: &notify::Event, path: &Path) -> bool {
+    matches!(event.kind, notify::EventKind::Create(_) | notify::EventKind::Modify(_)) && event.paths.iter().any(|p| p == path)
+}
+
+// Parses and validates with naga so errors are reported here with their source location,
+// instead of by wgpu's error handler, which panics by default.
+#[cfg(feature = "reflection")]
+pub fn check_wgsl(source: &str) -> Result<(), String> {
+    validate_wgsl(source).map_err(|(message, _)| message)
+}
+
+// check_wgsl for an expanded source, the message starts with the file and line of the error
+#[cfg(feature = "reflection")]
+pub fn check_expanded_wgsl(expanded: &ExpandedSource) -> Result<(), String> {
+    validate_wgsl(&expanded.source).map_err(|(message, line)| match line.and_then(|line| expanded.get_origin(line as usize)) {
+        Some((file, file_line)) => format!("{}:{}: {}", file, file_line, message),
+        None => format!("{}: {}", expanded.files[0], message),
+    })
+}
+
+// The emitted error and the line it points to
+#[cfg(feature = "reflection")]
+fn validate_wgsl(source: &str) -> Result<(), (String, Option<u32>)> {
+    let module = naga::front::wgsl::parse_str(source).map_err(|error| {
+        let line = error.location(source).map(|location| location.line_number);
+        (error.emit_to_string(source), line)
+    })?;
+
+    naga::valid::Validator::new(naga::valid::ValidationFlags::all(), naga::valid::Capabilities::all())
+        .validate(&module)
+        .map_err(|error| {
+            let line = error.location(source).map(|location| location.line_number);
+            (error.emit_to_string(source), line)
+        })?;
+
+    Ok(())
+}
+
+#[cfg(all(test, feature = "hot_reload"))]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::shader::{check_expanded_wgsl, check_wgsl, get_include_paths, is_shader_change, watch_shader};
+    use crate::shader_preprocessor::{expand_includes, expand_includes_from_path};
+
+    #[test]
+    fn test_check_wgsl() {
+        let source = "@fragment fn fs_main() -> @location(0) vec4<f32> { return vec4<f32>(1.0); }";
+        assert!(check_wgsl(source).is_ok());
+
+        // the error names the undefined identifier
+        let message = check_wgsl(&source.replace("vec4<f32>(1.0)", "color")).unwrap_err();
+        assert!(message.contains("color"));
+    }
+
+    #[test]
+    fn test_expanded_error_origin() {
+        let common = "fn get_color() -> vec4<f32> {\n    return color;\n}";
+        let source = "//!include \"common.wgsl\"\n@fragment fn fs_main() -> @location(0) vec4<f32> { return get_color(); }";
+        let expanded = expand_includes("main.wgsl", source, |_, _| Ok(("common.wgsl".to_string(), common.to_string()))).unwrap();
+
+        // the undefined identifier is on the second line of the included file
+        let message = check_expanded_wgsl(&expanded).unwrap_err();
+        assert!(message.starts_with("common.wgsl:2: "), "{}", message);
+    }
+
+    #[test]
+    fn test_is_shader_change() {
+        let path = PathBuf::from("/shaders/shader.wgsl");
+        let modify = notify::EventKind::Modify(notify::event::ModifyKind::Any);
+
+        let event = notify::Event::new(modify).add_path(path.clone());
+        assert!(is_shader_change(&event, &path));
+
+        let other_file = notify::Event::new(modify).add_path(PathBuf::from("/shaders/other.wgsl"));
+        assert!(!is_shader_change(&other_file, &path));
+
+        let access = notify::Event::new(notify::EventKind::Access(notify::event::AccessKind::Any)).add_path(path.clone());
+        assert!(!is_shader_change(&access, &path));
+    }
+
+    #[test]
+    fn test_watch_include_directories() {
+        let root = std::env::temp_dir().join("spark_gap_test_watch");
+        std::fs::create_dir_all(root.join("shaders")).unwrap();
+        std::fs::create_dir_all(root.join("common")).unwrap();
+        std::fs::write(root.join("common/color.wgsl"), "const COLOR: f32 = 1.0;").unwrap();
+        let path = root.join("shaders/main.wgsl");
+        let source = "//!include \"../common/color.wgsl\"\n//!include \"spark_gap/fullscreen.wgsl\"";
+        std::fs::write(&path, source).unwrap();
+
+        // the library include is embedded, so only the shader's and common's directories are watched
+        let expanded = expand_includes_from_path(&path).unwrap();
+        let watch = watch_shader(&path, Some(get_include_paths(&expanded))).unwrap();
+        let root = root.canonicalize().unwrap();
+        assert_eq!(watch.directories, [root.join("shaders"), root.join("common")]);
+    }
+}
@@ -0,0 +1,511 @@
+use std::cell::RefCell;
+use std::fmt;
+
+use wgpu::{BindGroupLayout, ShaderModule};
+
+use crate::error::Error;
+#[cfg(feature = "reflection")]
+use crate::error::Error::ShaderError;
+use crate::error::Error::ValidationError;
+use crate::hash_map::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShaderBindingKind {
+    Uniform,
+    Storage {
+        read_only: bool,
+    },
+    Sampler {
+        comparison: bool,
+    },
+    Texture {
+        sample_type: ShaderSampleType,
+        view_dimension: wgpu::TextureViewDimension,
+        multisampled: bool,
+    },
+    StorageTexture {
+        view_dimension: wgpu::TextureViewDimension,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShaderSampleType {
+    Float,
+    Sint,
+    Uint,
+    Depth,
+}
+
+// A resource variable declared with @group and @binding in wgsl
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShaderBinding {
+    pub group: u32,
+    pub binding: u32,
+    pub name: String,
+    pub kind: ShaderBindingKind,
+}
+
+// The wgsl spelling of the declaration, for error messages
+impl fmt::Display for ShaderBindingKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShaderBindingKind::Uniform => write!(f, "var<uniform>"),
+            ShaderBindingKind::Storage { read_only: true } => write!(f, "var<storage, read>"),
+            ShaderBindingKind::Storage { read_only: false } => write!(f, "var<storage, read_write>"),
+            ShaderBindingKind::Sampler { comparison: false } => write!(f, "sampler"),
+            ShaderBindingKind::Sampler { comparison: true } => write!(f, "sampler_comparison"),
+            ShaderBindingKind::Texture {
+                sample_type,
+                view_dimension,
+                multisampled,
+            } => {
+                let multisampled = if *multisampled { "multisampled_" } else { "" };
+                let dimension = get_dimension_name(*view_dimension);
+                match sample_type {
+                    ShaderSampleType::Depth => write!(f, "texture_depth_{}{}", multisampled, dimension),
+                    ShaderSampleType::Float => write!(f, "texture_{}{}<f32>", multisampled, dimension),
+                    ShaderSampleType::Sint => write!(f, "texture_{}{}<i32>", multisampled, dimension),
+                    ShaderSampleType::Uint => write!(f, "texture_{}{}<u32>", multisampled, dimension),
+                }
+            }
+            ShaderBindingKind::StorageTexture { view_dimension } => write!(f, "texture_storage_{}", get_dimension_name(*view_dimension)),
+        }
+    }
+}
+
+// The resource declarations of a wgsl source, as naga parses them. Needs the reflection feature,
+// which brings in naga.
+#[cfg(feature = "reflection")]
+pub fn reflect_bindings(source: &str) -> Result<Vec<ShaderBinding>, Error> {
+    let module = parse_module(source)?;
+    Ok(get_module_bindings(&module)?.into_iter().map(|(_, binding)| binding).collect())
+}
+
+// The bindings referenced from the entry points, directly or through the functions they call.
+// Several pipelines can share a module, each only needs layouts for the resources it uses.
+#[cfg(feature = "reflection")]
+pub fn get_used_bindings(source: &str, entry_points: &[&str]) -> Result<Vec<ShaderBinding>, Error> {
+    let module = parse_module(source)?;
+    let info = naga::valid::Validator::new(naga::valid::ValidationFlags::all(), naga::valid::Capabilities::all())
+        .validate(&module)
+        .map_err(|error| ShaderError(error.emit_to_string(source)))?;
+
+    let mut functions = vec![];
+    for entry_point in entry_points {
+        let index = module
+            .entry_points
+            .iter()
+            .position(|candidate| candidate.name == *entry_point)
+            .ok_or_else(|| ShaderError(format!("entry point {} not found", entry_point)))?;
+        functions.push(info.get_entry_point(index));
+    }
+
+    // the uses of an entry point include the ones of the functions it calls
+    Ok(get_module_bindings(&module)?
+        .into_iter()
+        .filter(|(handle, _)| functions.iter().any(|function| !function[*handle].is_empty()))
+        .map(|(_, binding)| binding)
+        .collect())
+}
+
+// Compares shader bindings against the entries of the bind group layouts of a pipeline, indexed by group
+pub fn check_bindings(bindings: &[ShaderBinding], layouts: &[&[wgpu::BindGroupLayoutEntry]]) -> Result<(), Error> {
+    for shader_binding in bindings {
+        let location = format!(
+            "group {} binding {} ({})",
+            shader_binding.group, shader_binding.binding, shader_binding.name
+        );
+
+        let entries = layouts
+            .get(shader_binding.group as usize)
+            .ok_or_else(|| ValidationError(format!("{}: the pipeline layout only has {} bind groups", location, layouts.len())))?;
+
+        let entry = entries
+            .iter()
+            .find(|entry| entry.binding == shader_binding.binding)
+            .ok_or_else(|| ValidationError(format!("{}: missing from bind group layout {}", location, shader_binding.group)))?;
+
+        if !is_compatible(&shader_binding.kind, &entry.ty) {
+            return Err(ValidationError(format!(
+                "{}: shader declares {} but the layout has {:?}",
+                location, shader_binding.kind, entry.ty
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "reflection")]
+pub fn check_shader_bindings(source: &str, entry_points: &[&str], layouts: &[&[wgpu::BindGroupLayoutEntry]]) -> Result<(), Error> {
+    check_bindings(&get_used_bindings(source, entry_points)?, layouts)
+}
+
+fn is_compatible(kind: &ShaderBindingKind, ty: &wgpu::BindingType) -> bool {
+    match (kind, ty) {
+        (
+            ShaderBindingKind::Uniform,
+            wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                ..
+            },
+        ) => true,
+        // a read only shader variable can use a read write binding
+        (
+            ShaderBindingKind::Storage { read_only },
+            wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage {
+                    read_only: layout_read_only,
+                },
+                ..
+            },
+        ) => *read_only || !layout_read_only,
+        (ShaderBindingKind::Sampler { comparison }, wgpu::BindingType::Sampler(sampler_type)) => {
+            *comparison == (*sampler_type == wgpu::SamplerBindingType::Comparison)
+        }
+        (
+            ShaderBindingKind::Texture {
+                sample_type,
+                view_dimension,
+                multisampled,
+            },
+            wgpu::BindingType::Texture {
+                sample_type: layout_sample_type,
+                view_dimension: layout_view_dimension,
+                multisampled: layout_multisampled,
+            },
+        ) => {
+            let sample_type_matches = matches!(
+                (sample_type, layout_sample_type),
+                (ShaderSampleType::Float, wgpu::TextureSampleType::Float { .. })
+                    | (ShaderSampleType::Sint, wgpu::TextureSampleType::Sint)
+                    | (ShaderSampleType::Uint, wgpu::TextureSampleType::Uint)
+                    | (ShaderSampleType::Depth, wgpu::TextureSampleType::Depth)
+            );
+            sample_type_matches && view_dimension == layout_view_dimension && multisampled == layout_multisampled
+        }
+        (
+            ShaderBindingKind::StorageTexture { view_dimension },
+            wgpu::BindingType::StorageTexture {
+                view_dimension: layout_view_dimension,
+                ..
+            },
+        ) => view_dimension == layout_view_dimension,
+        _ => false,
+    }
+}
+
+// The entries of the layouts created by LayoutBuilder and the sources of the modules created by
+// GpuContext::create_shader_module, so the pipeline builders can check their bindings on build.
+// Only filled with the reflection feature, on by default. Resources created directly through the
+// device aren't known and their pipelines aren't checked.
+#[derive(Debug, Default)]
+pub struct BindingRegistry {
+    layouts: RefCell<HashMap<wgpu::Id<BindGroupLayout>, Vec<wgpu::BindGroupLayoutEntry>>>,
+    shaders: RefCell<HashMap<wgpu::Id<ShaderModule>, String>>,
+}
+
+impl BindingRegistry {
+    pub fn new() -> Self {
+        BindingRegistry::default()
+    }
+
+    pub fn register_layout(&self, layout: &BindGroupLayout, entries: &[wgpu::BindGroupLayoutEntry]) {
+        if cfg!(feature = "reflection") {
+            self.layouts.borrow_mut().insert(layout.global_id(), entries.to_vec());
+        }
+    }
+
+    pub fn register_shader(&self, shader: &ShaderModule, source: &str) {
+        if cfg!(feature = "reflection") {
+            self.shaders.borrow_mut().insert(shader.global_id(), source.to_string());
+        }
+    }
+
+    // e.g. the previous module of a reloaded shader
+    pub fn remove_shader(&self, shader: wgpu::Id<ShaderModule>) {
+        self.shaders.borrow_mut().remove(&shader);
+    }
+
+    // Called by the pipeline builders, the error names the pipeline by its label. Ok without
+    // checking when the shader or one of the layouts wasn't registered.
+    #[cfg(feature = "reflection")]
+    pub fn check_pipeline(
+        &self,
+        label: &str,
+        shader: &ShaderModule,
+        entry_points: &[&str],
+        layouts: &[&BindGroupLayout],
+    ) -> Result<(), Error> {
+        let shaders = self.shaders.borrow();
+        let Some(source) = shaders.get(&shader.global_id()) else {
+            return Ok(());
+        };
+        let registered = self.layouts.borrow();
+        let Some(layout_entries) = layouts
+            .iter()
+            .map(|layout| registered.get(&layout.global_id()).map(Vec::as_slice))
+            .collect::<Option<Vec<_>>>()
+        else {
+            return Ok(());
+        };
+        check_shader_bindings(source, entry_points, &layout_entries).map_err(|error| match error {
+            ValidationError(message) => ValidationError(format!("pipeline {}: {}", label, message)),
+            ShaderError(message) => ShaderError(format!("pipeline {}: {}", label, message)),
+            error => error,
+        })
+    }
+
+    // Nothing is registered without naga
+    #[cfg(not(feature = "reflection"))]
+    pub fn check_pipeline(
+        &self,
+        _label: &str,
+        _shader: &ShaderModule,
+        _entry_points: &[&str],
+        _layouts: &[&BindGroupLayout],
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "reflection")]
+fn parse_module(source: &str) -> Result<naga::Module, Error> {
+    naga::front::wgsl::parse_str(source).map_err(|error| ShaderError(error.emit_to_string(source)))
+}
+
+#[cfg(feature = "reflection")]
+fn get_module_bindings(module: &naga::Module) -> Result<Vec<(naga::Handle<naga::GlobalVariable>, ShaderBinding)>, Error> {
+    let mut bindings = vec![];
+    for (handle, variable) in module.global_variables.iter() {
+        let Some(resource) = &variable.binding else {
+            continue;
+        };
+        let name = variable.name.clone().unwrap_or_default();
+        let unsupported = || {
+            ShaderError(format!(
+                "group {} binding {} ({}): unsupported resource type",
+                resource.group, resource.binding, name
+            ))
+        };
+
+        let kind = match variable.space {
+            naga::AddressSpace::Uniform => ShaderBindingKind::Uniform,
+            naga::AddressSpace::Storage { access } => ShaderBindingKind::Storage {
+                read_only: !access.contains(naga::StorageAccess::STORE),
+            },
+            naga::AddressSpace::Handle => get_handle_kind(module, variable.ty).ok_or_else(unsupported)?,
+            _ => return Err(unsupported()),
+        };
+
+        bindings.push((
+            handle,
+            ShaderBinding {
+                group: resource.group,
+                binding: resource.binding,
+                name,
+                kind,
+            },
+        ));
+    }
+    Ok(bindings)
+}
+
+#[cfg(feature = "reflection")]
+fn get_handle_kind(module: &naga::Module, ty: naga::Handle<naga::Type>) -> Option<ShaderBindingKind> {
+    match module.types[ty].inner {
+        naga::TypeInner::Sampler { comparison } => Some(ShaderBindingKind::Sampler { comparison }),
+        // binding arrays are checked like a single element
+        naga::TypeInner::BindingArray { base, .. } => get_handle_kind(module, base),
+        naga::TypeInner::Image { dim, arrayed, class } => {
+            let view_dimension = match (dim, arrayed) {
+                (naga::ImageDimension::D1, false) => wgpu::TextureViewDimension::D1,
+                (naga::ImageDimension::D2, false) => wgpu::TextureViewDimension::D2,
+                (naga::ImageDimension::D2, true) => wgpu::TextureViewDimension::D2Array,
+                (naga::ImageDimension::D3, false) => wgpu::TextureViewDimension::D3,
+                (naga::ImageDimension::Cube, false) => wgpu::TextureViewDimension::Cube,
+                (naga::ImageDimension::Cube, true) => wgpu::TextureViewDimension::CubeArray,
+                _ => return None,
+            };
+            let (sample_type, multisampled) = match class {
+                naga::ImageClass::Storage { .. } => return Some(ShaderBindingKind::StorageTexture { view_dimension }),
+                naga::ImageClass::Depth { multi } => (ShaderSampleType::Depth, multi),
+                naga::ImageClass::Sampled { kind, multi } => {
+                    let sample_type = match kind {
+                        naga::ScalarKind::Float => ShaderSampleType::Float,
+                        naga::ScalarKind::Sint => ShaderSampleType::Sint,
+                        naga::ScalarKind::Uint => ShaderSampleType::Uint,
+                        _ => return None,
+                    };
+                    (sample_type, multi)
+                }
+            };
+            Some(ShaderBindingKind::Texture {
+                sample_type,
+                view_dimension,
+                multisampled,
+            })
+        }
+        _ => None,
+    }
+}
+
+fn get_dimension_name(view_dimension: wgpu::TextureViewDimension) -> &'static str {
+    match view_dimension {
+        wgpu::TextureViewDimension::D1 => "1d",
+        wgpu::TextureViewDimension::D2 => "2d",
+        wgpu::TextureViewDimension::D2Array => "2d_array",
+        wgpu::TextureViewDimension::D3 => "3d",
+        wgpu::TextureViewDimension::Cube => "cube",
+        wgpu::TextureViewDimension::CubeArray => "cube_array",
+    }
+}
+
+#[cfg(all(test, feature = "reflection"))]
+mod tests {
+    use crate::bind_group::LayoutBuilder;
+    use crate::error::Error;
+    use crate::gpu_context::GpuContext;
+    use crate::shader_bindings::{check_shader_bindings, get_used_bindings, reflect_bindings, ShaderBindingKind, ShaderSampleType};
+
+    const SOURCE: &str = "
+        struct Light { position: vec4<f32>, };
+
+        @group(0) @binding(0) var<uniform> lights: array<Light, 10u>;
+        @group(0) @binding(1) var shadow_atlas: texture_depth_2d; // the shadow maps
+        @group(0) @binding(2) var shadow_sampler: sampler_comparison;
+        @binding(0) @group(1) var<storage, read_write> counts: array<u32>;
+
+        fn get_light(i: u32) -> Light {
+            return lights[i];
+        }
+
+        @vertex fn vs_shadow(@builtin(instance_index) index: u32) -> @builtin(position) vec4<f32> {
+            return get_light(index).position;
+        }
+
+        @fragment fn fs_main(@builtin(position) position: vec4<f32>) -> @location(0) vec4<f32> {
+            /* counts[0] += 1u; */
+            let shadow = textureSampleCompareLevel(shadow_atlas, shadow_sampler, position.xy, position.z);
+            return vec4<f32>(shadow);
+        }
+    ";
+
+    fn entry(binding: u32, ty: wgpu::BindingType) -> wgpu::BindGroupLayoutEntry {
+        wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::VERTEX_FRAGMENT,
+            ty,
+            count: None,
+        }
+    }
+
+    fn uniform() -> wgpu::BindingType {
+        wgpu::BindingType::Buffer {
+            ty: wgpu::BufferBindingType::Uniform,
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        }
+    }
+
+    fn depth_texture(view_dimension: wgpu::TextureViewDimension) -> wgpu::BindingType {
+        wgpu::BindingType::Texture {
+            sample_type: wgpu::TextureSampleType::Depth,
+            view_dimension,
+            multisampled: false,
+        }
+    }
+
+    #[test]
+    fn test_reflect_bindings() {
+        let bindings = reflect_bindings(SOURCE).unwrap();
+        assert_eq!(bindings.len(), 4);
+        assert_eq!(bindings[1].name, "shadow_atlas");
+        assert_eq!(
+            bindings[1].kind,
+            ShaderBindingKind::Texture {
+                sample_type: ShaderSampleType::Depth,
+                view_dimension: wgpu::TextureViewDimension::D2,
+                multisampled: false,
+            }
+        );
+        assert_eq!((bindings[3].group, bindings[3].binding), (1, 0));
+        assert_eq!(bindings[3].kind, ShaderBindingKind::Storage { read_only: false });
+
+        let names = |entry_points: &[&str]| -> Vec<String> {
+            get_used_bindings(SOURCE, entry_points)
+                .unwrap()
+                .into_iter()
+                .map(|b| b.name)
+                .collect()
+        };
+        assert_eq!(names(&["vs_shadow"]), ["lights"]);
+        assert_eq!(names(&["fs_main"]), ["shadow_atlas", "shadow_sampler"]);
+        assert!(get_used_bindings(SOURCE, &["vs_main"]).is_err());
+    }
+
+    #[test]
+    fn test_binding_mismatch() {
+        let sampler = wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Comparison);
+
+        // the shadow pass only needs the lights
+        let shadow_layout = [entry(0, uniform())];
+        assert!(check_shader_bindings(SOURCE, &["vs_shadow"], &[&shadow_layout]).is_ok());
+
+        let forward_layout = [
+            entry(0, uniform()),
+            entry(1, depth_texture(wgpu::TextureViewDimension::D2)),
+            entry(2, sampler),
+        ];
+        assert!(check_shader_bindings(SOURCE, &["vs_shadow", "fs_main"], &[&forward_layout]).is_ok());
+
+        // layout still declaring the old shadow texture array
+        let array_layout = [
+            entry(0, uniform()),
+            entry(1, depth_texture(wgpu::TextureViewDimension::D2Array)),
+            entry(2, sampler),
+        ];
+        let Err(Error::ValidationError(message)) = check_shader_bindings(SOURCE, &["fs_main"], &[&array_layout]) else {
+            panic!("expected a binding mismatch");
+        };
+        assert!(
+            message.starts_with("group 0 binding 1 (shadow_atlas): shader declares texture_depth_2d"),
+            "{}",
+            message
+        );
+
+        let Err(Error::ValidationError(message)) = check_shader_bindings(SOURCE, &["fs_main"], &[&shadow_layout]) else {
+            panic!("expected a missing binding");
+        };
+        assert_eq!(message, "group 0 binding 1 (shadow_atlas): missing from bind group layout 0");
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "gpu_tests"), ignore = "needs a gpu adapter")]
+    fn test_binding_registry() {
+        let context = pollster::block_on(GpuContext::new_headless(4, 4)).unwrap();
+        let registry = &context.binding_registry;
+        let shader = context.create_shader_module("bindings", SOURCE);
+        let shadow_layout = LayoutBuilder::new()
+            .uniform(wgpu::ShaderStages::VERTEX)
+            .build(&context, "shadow")
+            .unwrap();
+
+        assert!(registry
+            .check_pipeline("shadow", &shader, &["vs_shadow"], &[&shadow_layout])
+            .is_ok());
+        let Err(Error::ValidationError(message)) = registry.check_pipeline("forward", &shader, &["fs_main"], &[&shadow_layout]) else {
+            panic!("expected a missing binding");
+        };
+        assert_eq!(
+            message,
+            "pipeline forward: group 0 binding 1 (shadow_atlas): missing from bind group layout 0"
+        );
+
+        // layouts created through the device aren't known, so the pipeline isn't checked
+        let unknown = context
+            .device
+            .create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor { label: None, entries: &[] });
+        assert!(registry.check_pipeline("forward", &shader, &["fs_main"], &[&unknown]).is_ok());
+    }
+}
@@ -0,0 +1,384 @@
+use std::path::{Component, Path, PathBuf};
+
+use crate::error::Error;
+use crate::error::Error::ShaderError;
+use crate::gbuffer::GBUFFER_ENCODING_WGSL;
+use crate::material::PBR_WGSL;
+use crate::point_shadow::POINT_SHADOW_WGSL;
+use crate::post::FULLSCREEN_WGSL;
+use crate::shadow_cascades::get_shadow_cascades_wgsl;
+use crate::texture::MIP_DEBUG_WGSL;
+
+// Debug views selectable by define in shaders that support them
+pub const MIP_DEBUG_DEFINE: &str = "MIP_DEBUG";
+
+// //!include "path" on its own line, a wgsl comment so unexpanded sources still parse
+pub const INCLUDE_DIRECTIVE: &str = "//!include";
+
+// Include paths starting with it name the library's shared wgsl, see get_library_include
+pub const LIBRARY_INCLUDE_PREFIX: &str = "spark_gap/";
+
+struct Block {
+    parent_active: bool,
+    condition: bool,
+    in_else: bool,
+}
+
+impl Block {
+    fn is_active(&self) -> bool {
+        self.parent_active && self.condition != self.in_else
+    }
+}
+
+// Handles #ifdef NAME, #ifndef NAME, #else and #endif on their own lines, which may be nested.
+// Removed lines are left empty so shader compile errors keep the line numbers of the source.
+pub fn preprocess(source: &str, defines: &[&str]) -> Result<String, Error> {
+    preprocess_with_constants(source, defines, &[])
+}
+
+// Like preprocess, also replacing #const NAME lines with a u32 const declaration of the named
+// value, so array sizes can come from the same constant as the Rust side.
+pub fn preprocess_with_constants(source: &str, defines: &[&str], constants: &[(&str, u32)]) -> Result<String, Error> {
+    let mut output = String::with_capacity(source.len());
+    let mut blocks: Vec<Block> = vec![];
+
+    for (line_index, line) in source.lines().enumerate() {
+        let trimmed = line.trim();
+        let active = blocks.last().map_or(true, Block::is_active);
+        let directive_error = |message: &str| ShaderError(format!("line {}: {}", line_index + 1, message));
+
+        if let Some(name) = trimmed.strip_prefix("#ifdef ") {
+            blocks.push(Block {
+                parent_active: active,
+                condition: defines.contains(&name.trim()),
+                in_else: false,
+            });
+        } else if let Some(name) = trimmed.strip_prefix("#ifndef ") {
+            blocks.push(Block {
+                parent_active: active,
+                condition: !defines.contains(&name.trim()),
+                in_else: false,
+            });
+        } else if trimmed == "#else" {
+            let block = blocks.last_mut().ok_or_else(|| directive_error("#else without #ifdef"))?;
+            if block.in_else {
+                return Err(directive_error("duplicate #else"));
+            }
+            block.in_else = true;
+        } else if trimmed == "#endif" {
+            blocks.pop().ok_or_else(|| directive_error("#endif without #ifdef"))?;
+        } else if let Some(name) = trimmed.strip_prefix("#const ") {
+            let name = name.trim();
+            let (_, value) = constants
+                .iter()
+                .find(|(constant, _)| *constant == name)
+                .ok_or_else(|| directive_error(&format!("no value for #const {}", name)))?;
+            if active {
+                output.push_str(&format!("const {}: u32 = {}u;", name, value));
+            }
+        } else if active {
+            output.push_str(line);
+        }
+
+        output.push('\n');
+    }
+
+    if !blocks.is_empty() {
+        return Err(ShaderError(format!("{} unterminated #ifdef", blocks.len())));
+    }
+
+    Ok(output)
+}
+
+// Source after expand_includes, with the file and line every output line came from
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExpandedSource {
+    pub source: String,
+    // the including file first, then the included files in the order they were first included
+    pub files: Vec<String>,
+    // per output line, the index of its file and its line in that file
+    pub line_origins: Vec<(usize, usize)>,
+}
+
+impl ExpandedSource {
+    // A source without includes, every line is its own origin
+    pub fn without_includes(name: &str, source: &str) -> Self {
+        ExpandedSource {
+            source: source.to_string(),
+            files: vec![name.to_string()],
+            line_origins: (1..=source.lines().count()).map(|line| (0, line)).collect(),
+        }
+    }
+
+    // Lines are numbered from 1 like in naga's errors
+    pub fn get_origin(&self, line: usize) -> Option<(&str, usize)> {
+        let (file, file_line) = *self.line_origins.get(line.checked_sub(1)?)?;
+        Some((&self.files[file], file_line))
+    }
+
+    fn push_line(&mut self, line: &str, file: usize, file_line: usize) {
+        self.source.push_str(line);
+        self.source.push('\n');
+        self.line_origins.push((file, file_line));
+    }
+}
+
+// Replaces //!include "path" lines with the included source, recursively. Every file is included
+// once and later includes of it are dropped, an include cycle is an error. resolve gets the path
+// and the name of the including file and returns the included file's name and source. Names are
+// compared to find repeated includes, so they have to be unique, e.g. normalized paths.
+pub fn expand_includes(
+    name: &str,
+    source: &str,
+    mut resolve: impl FnMut(&str, &str) -> Result<(String, String), Error>,
+) -> Result<ExpandedSource, Error> {
+    let mut expanded = ExpandedSource {
+        source: String::with_capacity(source.len()),
+        files: vec![name.to_string()],
+        line_origins: vec![],
+    };
+    expand_file(&mut expanded, 0, source, &mut vec![], &mut resolve)?;
+    Ok(expanded)
+}
+
+fn expand_file<F>(expanded: &mut ExpandedSource, file: usize, source: &str, stack: &mut Vec<usize>, resolve: &mut F) -> Result<(), Error>
+where
+    F: FnMut(&str, &str) -> Result<(String, String), Error>,
+{
+    stack.push(file);
+
+    for (line_index, line) in source.lines().enumerate() {
+        let Some(argument) = line.trim().strip_prefix(INCLUDE_DIRECTIVE) else {
+            expanded.push_line(line, file, line_index + 1);
+            continue;
+        };
+
+        let location = format!("{}:{}", expanded.files[file], line_index + 1);
+        let path = argument
+            .trim()
+            .strip_prefix('"')
+            .and_then(|argument| argument.strip_suffix('"'))
+            .ok_or_else(|| ShaderError(format!("{}: expected {} \"path\"", location, INCLUDE_DIRECTIVE)))?;
+
+        let (name, included_source) =
+            resolve(path, &expanded.files[file]).map_err(|error| ShaderError(format!("{}: including {}: {:?}", location, path, error)))?;
+
+        match expanded.files.iter().position(|included| *included == name) {
+            Some(index) if stack.contains(&index) => {
+                return Err(ShaderError(format!("{}: include cycle through {}", location, name)));
+            }
+            // already included, the line is kept empty
+            Some(_) => expanded.push_line("", file, line_index + 1),
+            None => {
+                expanded.files.push(name);
+                expand_file(expanded, expanded.files.len() - 1, &included_source, stack, resolve)?;
+            }
+        }
+    }
+
+    stack.pop();
+    Ok(())
+}
+
+// expand_includes for a wgsl file, include paths are relative to the including file
+pub fn expand_includes_from_path(path: impl AsRef<Path>) -> Result<ExpandedSource, Error> {
+    let path = normalize_path(path.as_ref());
+    let source = std::fs::read_to_string(&path)?;
+
+    expand_includes(&path.display().to_string(), &source, |include, including| {
+        if let Some(source) = get_library_include(include) {
+            return Ok((include.to_string(), source));
+        }
+        let directory = Path::new(including).parent().unwrap_or(Path::new(""));
+        let path = normalize_path(&directory.join(include));
+        let source = std::fs::read_to_string(&path)?;
+        Ok((path.display().to_string(), source))
+    })
+}
+
+// expand_includes for sources embedded with include_str!, include paths are looked up in files and
+// then in the library's shared wgsl
+pub fn expand_embedded_includes(name: &str, source: &str, files: &[(&str, &str)]) -> Result<ExpandedSource, Error> {
+    expand_includes(name, source, |include, _including| {
+        if let Some((name, source)) = files.iter().find(|(name, _)| *name == include) {
+            return Ok((name.to_string(), source.to_string()));
+        }
+        let source = get_library_include(include).ok_or_else(|| ShaderError(format!("no embedded file {}", include)))?;
+        Ok((include.to_string(), source))
+    })
+}
+
+// The library's wgsl shared between shaders, e.g. //!include "spark_gap/fullscreen.wgsl" for
+// vs_fullscreen. Embedded, so the files don't have to be next to the including shader.
+pub fn get_library_include(path: &str) -> Option<String> {
+    let source = match path.strip_prefix(LIBRARY_INCLUDE_PREFIX)? {
+        "fullscreen.wgsl" => FULLSCREEN_WGSL.to_string(),
+        "gbuffer_encoding.wgsl" => GBUFFER_ENCODING_WGSL.to_string(),
+        "mip_debug.wgsl" => MIP_DEBUG_WGSL.to_string(),
+        "pbr.wgsl" => PBR_WGSL.to_string(),
+        "point_shadow.wgsl" => POINT_SHADOW_WGSL.to_string(),
+        "shadow_cascades.wgsl" => get_shadow_cascades_wgsl(),
+        _ => return None,
+    };
+    Some(source)
+}
+
+// Removes . and resolves .. lexically, so different spellings of a path compare equal
+pub fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    normalized.push("..");
+                }
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use crate::error::Error;
+    use crate::post::FULLSCREEN_WGSL;
+    use crate::shader_preprocessor::{
+        expand_embedded_includes, expand_includes, get_library_include, normalize_path, preprocess, preprocess_with_constants,
+        MIP_DEBUG_DEFINE,
+    };
+    use crate::texture::MIP_DEBUG_WGSL;
+
+    const FRAGMENT: &str = "
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+#ifdef MIP_DEBUG
+    let level = get_mip_level(diffuse_texture, in.tex_coords);
+    return vec4<f32>(get_mip_level_color(level, textureNumLevels(diffuse_texture)), 1.0);
+#else
+    return textureSample(diffuse_texture, diffuse_sampler, in.tex_coords);
+#endif
+}
+";
+
+    #[test]
+    fn test_mip_debug_define() {
+        let debug = preprocess(FRAGMENT, &[MIP_DEBUG_DEFINE]).unwrap();
+        assert!(debug.contains("get_mip_level_color"));
+        assert!(!debug.contains("textureSample("));
+        assert!(!debug.contains('#'));
+
+        let normal = preprocess(FRAGMENT, &[]).unwrap();
+        assert!(normal.contains("textureSample("));
+        assert!(!normal.contains("get_mip_level"));
+
+        // line numbers are kept
+        assert_eq!(debug.lines().count(), FRAGMENT.lines().count());
+
+        // the library functions used by the debug branch
+        assert!(MIP_DEBUG_WGSL.contains("fn get_mip_level("));
+        assert!(MIP_DEBUG_WGSL.contains("fn get_mip_level_color("));
+    }
+
+    #[test]
+    fn test_nested_and_unbalanced() {
+        let source = "a\n#ifdef A\nb\n#ifndef B\nc\n#endif\n#endif\n";
+        assert_eq!(preprocess(source, &["A"]).unwrap().split_whitespace().collect::<Vec<_>>(), ["a", "b", "c"]);
+        assert_eq!(preprocess(source, &["A", "B"]).unwrap().split_whitespace().collect::<Vec<_>>(), ["a", "b"]);
+        assert_eq!(preprocess(source, &["B"]).unwrap().split_whitespace().collect::<Vec<_>>(), ["a"]);
+
+        assert!(matches!(preprocess("#ifdef A\n", &[]), Err(Error::ShaderError(_))));
+        assert!(matches!(preprocess("#endif\n", &[]), Err(Error::ShaderError(_))));
+    }
+
+    #[test]
+    fn test_const_values() {
+        let source = "#const MAX_LIGHTS\nvar<uniform> lights: array<Light, MAX_LIGHTS>;\n";
+        let output = preprocess_with_constants(source, &[], &[("MAX_LIGHTS", 16)]).unwrap();
+        assert_eq!(output.lines().next(), Some("const MAX_LIGHTS: u32 = 16u;"));
+        assert_eq!(output.lines().count(), source.lines().count());
+
+        assert!(matches!(preprocess(source, &[]), Err(Error::ShaderError(_))));
+    }
+
+    fn resolve_from(files: &'static [(&'static str, &'static str)]) -> impl FnMut(&str, &str) -> Result<(String, String), Error> {
+        |path, _including| {
+            let (name, source) = files.iter().find(|(name, _)| *name == path).ok_or("no such file")?;
+            Ok((name.to_string(), source.to_string()))
+        }
+    }
+
+    #[test]
+    fn test_expand_embedded_includes() {
+        let files = &[("shared.wgsl", "//!include \"spark_gap/fullscreen.wgsl\"\nfn shared() {}")];
+        let source = "//!include \"spark_gap/fullscreen.wgsl\"\n//!include \"shared.wgsl\"\nfn main() {}";
+        let expanded = expand_embedded_includes("main.wgsl", source, files).unwrap();
+
+        // the library file is included once, before shared
+        assert_eq!(expanded.source.matches("fn vs_fullscreen(").count(), 1);
+        assert!(expanded.source.starts_with(FULLSCREEN_WGSL));
+        assert_eq!(expanded.files, ["main.wgsl", "spark_gap/fullscreen.wgsl", "shared.wgsl"]);
+
+        assert!(get_library_include("spark_gap/shadow_cascades.wgsl").is_some());
+        assert_eq!(get_library_include("fullscreen.wgsl"), None);
+        assert!(matches!(
+            expand_embedded_includes("main.wgsl", "//!include \"missing.wgsl\"", files),
+            Err(Error::ShaderError(_))
+        ));
+    }
+
+    #[test]
+    fn test_expand_includes() {
+        let files = &[
+            ("math.wgsl", "const PI: f32 = 3.14159;"),
+            ("lighting.wgsl", "//!include \"math.wgsl\"\nfn lambert() {}"),
+        ];
+        let source = "//!include \"lighting.wgsl\"\n  //!include \"math.wgsl\"\nfn main() {}";
+        let expanded = expand_includes("main.wgsl", source, resolve_from(files)).unwrap();
+
+        // math is included once, by lighting
+        assert_eq!(expanded.source, "const PI: f32 = 3.14159;\nfn lambert() {}\n\nfn main() {}\n");
+        assert_eq!(expanded.files, ["main.wgsl", "lighting.wgsl", "math.wgsl"]);
+
+        assert_eq!(expanded.get_origin(1), Some(("math.wgsl", 1)));
+        assert_eq!(expanded.get_origin(2), Some(("lighting.wgsl", 2)));
+        assert_eq!(expanded.get_origin(4), Some(("main.wgsl", 3)));
+        assert_eq!(expanded.get_origin(0), None);
+        assert_eq!(expanded.get_origin(5), None);
+    }
+
+    #[test]
+    fn test_include_errors() {
+        let cycle = &[("a.wgsl", "fn a() {}\n//!include \"b.wgsl\""), ("b.wgsl", "//!include \"a.wgsl\"")];
+        let Err(Error::ShaderError(message)) = expand_includes("main.wgsl", "//!include \"a.wgsl\"", resolve_from(cycle)) else {
+            panic!("expected an include cycle error");
+        };
+        assert!(message.starts_with("b.wgsl:1: include cycle"), "{}", message);
+
+        // errors name the including file and line
+        let Err(Error::ShaderError(message)) = expand_includes("main.wgsl", "\n//!include \"missing.wgsl\"", resolve_from(cycle)) else {
+            panic!("expected a missing include error");
+        };
+        assert!(message.starts_with("main.wgsl:2: including missing.wgsl"), "{}", message);
+
+        assert!(matches!(
+            expand_includes("main.wgsl", "//!include math.wgsl", resolve_from(cycle)),
+            Err(Error::ShaderError(_))
+        ));
+    }
+
+    #[test]
+    fn test_normalize_path() {
+        assert_eq!(
+            normalize_path(Path::new("shaders/./pbr/../common.wgsl")),
+            PathBuf::from("shaders/common.wgsl")
+        );
+        assert_eq!(
+            normalize_path(Path::new("../shaders/common.wgsl")),
+            PathBuf::from("../shaders/common.wgsl")
+        );
+    }
+}
@@ -0,0 +1,117 @@
+use crate::bind_group::{BindGroupBuilder, LayoutBuilder};
+use crate::buffers::StorageBuffer;
+use crate::compute::ComputePipelineBuilder;
+use crate::error::Error;
+use crate::gpu_context::GpuContext;
+
+// Runs a compute entry point appended to a wgsl library, for tests that check the library's
+// functions on the gpu against the cpu functions they mirror. The snippet reads test_inputs and
+// writes test_outputs, both array<vec4<f32>>, declared in the group after the library's own.
+//
+//   let outputs = ShaderTest::new(GBUFFER_ENCODING_WGSL, ROUND_TRIP_WGSL).run(&context, "round_trip", &inputs, 2, 4)?;
+#[derive(Debug, Clone)]
+pub struct ShaderTest<'a> {
+    pub library: &'a str,
+    pub snippet: &'a str,
+    pub group: u32,
+}
+
+impl<'a> ShaderTest<'a> {
+    pub fn new(library: &'a str, snippet: &'a str) -> Self {
+        ShaderTest {
+            library,
+            snippet,
+            group: 0,
+        }
+    }
+
+    // For libraries with bindings of their own, the groups before are bound empty
+    pub fn group(mut self, group: u32) -> Self {
+        self.group = group;
+        self
+    }
+
+    pub fn get_source(&self) -> String {
+        let bindings = format!(
+            "@group({0}) @binding(0) var<storage, read> test_inputs: array<vec4<f32>>;\n\
+             @group({0}) @binding(1) var<storage, read_write> test_outputs: array<vec4<f32>>;",
+            self.group
+        );
+        [self.library, &bindings, self.snippet].join("\n")
+    }
+
+    // Dispatches invocations workgroups of entry_point and reads back output_len outputs
+    pub fn run(
+        &self,
+        context: &GpuContext,
+        entry_point: &str,
+        inputs: &[[f32; 4]],
+        invocations: u32,
+        output_len: usize,
+    ) -> Result<Vec<[f32; 4]>, Error> {
+        let inputs = StorageBuffer::new_init(context, inputs, wgpu::BufferUsages::empty(), "test inputs")?;
+        let outputs = StorageBuffer::<[f32; 4]>::new(context, output_len, wgpu::BufferUsages::empty(), "test outputs")?;
+
+        let empty_layout = LayoutBuilder::new().build(context, "empty")?;
+        let empty = BindGroupBuilder::new().build(context, &empty_layout, "empty");
+        let layout = LayoutBuilder::new()
+            .storage_buffer(wgpu::ShaderStages::COMPUTE, true)
+            .storage_buffer(wgpu::ShaderStages::COMPUTE, false)
+            .build(context, "shader test layout")?;
+        let bind_group = BindGroupBuilder::new()
+            .resource(inputs.as_entire_binding())
+            .resource(outputs.as_entire_binding())
+            .build(context, &layout, "shader test");
+
+        let shader = context.create_shader_module(entry_point, &self.get_source());
+        let mut builder = ComputePipelineBuilder::new(entry_point).label(entry_point);
+        let mut bind_groups = vec![];
+        for _ in 0..self.group {
+            builder = builder.bind_group_layout(&empty_layout);
+            bind_groups.push(&empty);
+        }
+        let pipeline = builder.bind_group_layout(&layout).build(context, &shader)?;
+        bind_groups.push(&bind_group);
+
+        pipeline.dispatch(context, &bind_groups, invocations, 1, 1)?;
+        Ok(outputs.read_back(context))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::gpu_context::GpuContext;
+    use crate::shader_test::ShaderTest;
+
+    const LIBRARY: &str = "fn double(value: vec4<f32>) -> vec4<f32> { return value * 2.0; }";
+
+    const SNIPPET: &str = r"
+@compute @workgroup_size(1) fn double_inputs(@builtin(global_invocation_id) id: vec3<u32>) {
+    test_outputs[id.x] = double(test_inputs[id.x]);
+}
+";
+
+    #[test]
+    fn test_shader_test_source() {
+        let source = ShaderTest::new(LIBRARY, SNIPPET).group(3).get_source();
+        assert!(source.starts_with(LIBRARY));
+        assert!(source.contains("@group(3) @binding(0) var<storage, read> test_inputs"));
+        assert!(source.contains("@group(3) @binding(1) var<storage, read_write> test_outputs"));
+        assert!(source.ends_with(SNIPPET));
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "gpu_tests"), ignore = "needs a gpu adapter")]
+    fn test_shader_test_run() {
+        let context = pollster::block_on(GpuContext::new_headless(4, 4)).unwrap();
+        let inputs = [[1.0, 2.0, 3.0, 4.0], [-1.0, 0.5, 0.0, 8.0]];
+
+        for group in [0, 2] {
+            let outputs = ShaderTest::new(LIBRARY, SNIPPET)
+                .group(group)
+                .run(&context, "double_inputs", &inputs, 2, 2)
+                .unwrap();
+            assert_eq!(outputs, [[2.0, 4.0, 6.0, 8.0], [-2.0, 1.0, 0.0, 16.0]]);
+        }
+    }
+}
@@ -0,0 +1,26 @@
+//!include "spark_gap/fullscreen.wgsl"
+
+struct ColorGradeUniform {
+    domain_min: vec4<f32>,
+    domain_max: vec4<f32>,
+    lut_size: f32,
+    intensity: f32,
+};
+
+@group(0) @binding(0) var input_texture: texture_2d<f32>;
+@group(0) @binding(1) var input_sampler: sampler;
+@group(0) @binding(2) var lut_texture: texture_3d<f32>;
+@group(0) @binding(3) var lut_sampler: sampler;
+@group(0) @binding(4) var<uniform> grade: ColorGradeUniform;
+
+@fragment fn fs_main(vertex: FullscreenOutput) -> @location(0) vec4<f32> {
+    let color = textureSampleLevel(input_texture, input_sampler, vertex.uv, 0.0);
+
+    let normalized = clamp((color.rgb - grade.domain_min.xyz) / (grade.domain_max.xyz - grade.domain_min.xyz), vec3<f32>(0.0), vec3<f32>(1.0));
+
+    // sample at texel centers so the ends of the domain map to the first and last entries
+    let uvw = normalized * ((grade.lut_size - 1.0) / grade.lut_size) + 0.5 / grade.lut_size;
+    let graded = textureSampleLevel(lut_texture, lut_sampler, uvw, 0.0).rgb;
+
+    return vec4<f32>(mix(color.rgb, graded, grade.intensity), color.a);
+}
@@ -0,0 +1,72 @@
+// Min, max and sum of a depth texture. Each workgroup reduces a block of 2 * WORKGROUP_SIZE values
+// to one (min, max, sum, count), the block results are reduced again in the next level.
+
+struct ReductionUniform {
+    length: u32,
+    width: u32,
+    skip_cleared: u32,
+    _padding: u32,
+}
+
+// reduce_depth reads the texture, reduce_blocks the results of the previous level
+@group(0) @binding(0) var depth_texture: texture_depth_2d;
+@group(0) @binding(1) var<storage, read> input: array<vec4<f32>>;
+@group(0) @binding(2) var<storage, read_write> output: array<vec4<f32>>;
+@group(0) @binding(3) var<uniform> params: ReductionUniform;
+
+const WORKGROUP_SIZE: u32 = 256u;
+const BLOCK_SIZE: u32 = 512u;
+
+// depth is in 0..1 so these are the identities of min and max
+const EMPTY: vec4<f32> = vec4<f32>(1.0, 0.0, 0.0, 0.0);
+
+var<workgroup> temp: array<vec4<f32>, WORKGROUP_SIZE>;
+
+fn combine(a: vec4<f32>, b: vec4<f32>) -> vec4<f32> {
+    return vec4<f32>(min(a.x, b.x), max(a.y, b.y), a.z + b.z, a.w + b.w);
+}
+
+fn load_depth(index: u32) -> vec4<f32> {
+    if (index >= params.length) {
+        return EMPTY;
+    }
+    let depth = textureLoad(depth_texture, vec2<u32>(index % params.width, index / params.width), 0);
+    if (params.skip_cleared != 0u && depth >= 1.0) {
+        return EMPTY;
+    }
+    return vec4<f32>(depth, depth, depth, 1.0);
+}
+
+fn load_block(index: u32) -> vec4<f32> {
+    if (index >= params.length) {
+        return EMPTY;
+    }
+    return input[index];
+}
+
+fn reduce_workgroup(thread: u32, group: u32, value: vec4<f32>) {
+    temp[thread] = value;
+
+    for (var d = WORKGROUP_SIZE >> 1u; d > 0u; d = d >> 1u) {
+        workgroupBarrier();
+        if (thread < d) {
+            temp[thread] = combine(temp[thread], temp[thread + d]);
+        }
+    }
+
+    if (thread == 0u) {
+        output[group] = temp[0];
+    }
+}
+
+@compute @workgroup_size(WORKGROUP_SIZE)
+fn reduce_depth(@builtin(local_invocation_id) local_id: vec3<u32>, @builtin(workgroup_id) group_id: vec3<u32>) {
+    let a = group_id.x * BLOCK_SIZE + local_id.x;
+    reduce_workgroup(local_id.x, group_id.x, combine(load_depth(a), load_depth(a + WORKGROUP_SIZE)));
+}
+
+@compute @workgroup_size(WORKGROUP_SIZE)
+fn reduce_blocks(@builtin(local_invocation_id) local_id: vec3<u32>, @builtin(workgroup_id) group_id: vec3<u32>) {
+    let a = group_id.x * BLOCK_SIZE + local_id.x;
+    reduce_workgroup(local_id.x, group_id.x, combine(load_block(a), load_block(a + WORKGROUP_SIZE)));
+}
@@ -0,0 +1,16 @@
+// A triangle covering the target, for //!include "spark_gap/fullscreen.wgsl". The uv is 0,0 at the
+// top left like texture coordinates.
+
+struct FullscreenOutput {
+    @builtin(position) position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+};
+
+@vertex fn vs_fullscreen(@builtin(vertex_index) index: u32) -> FullscreenOutput {
+    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+
+    var result: FullscreenOutput;
+    result.position = vec4<f32>(uv * vec2<f32>(2.0, -2.0) + vec2<f32>(-1.0, 1.0), 0.0, 1.0);
+    result.uv = uv;
+    return result;
+}
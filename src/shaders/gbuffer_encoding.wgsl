@@ -0,0 +1,24 @@
+// Normal encodings for the gbuffer. Prepend to shaders that read or write the gbuffer.
+// encode_octahedral and decode_octahedral in gbuffer.rs are the cpu versions.
+
+fn sign_not_zero(v: vec2<f32>) -> vec2<f32> {
+    return select(vec2<f32>(-1.0), vec2<f32>(1.0), v >= vec2<f32>(0.0));
+}
+
+// unit normal to two components in -1..1
+fn encode_octahedral(normal: vec3<f32>) -> vec2<f32> {
+    let n = normal / (abs(normal.x) + abs(normal.y) + abs(normal.z));
+    if (n.z >= 0.0) {
+        return n.xy;
+    }
+    return (vec2<f32>(1.0) - abs(n.yx)) * sign_not_zero(n.xy);
+}
+
+fn decode_octahedral(encoded: vec2<f32>) -> vec3<f32> {
+    var n = vec3<f32>(encoded, 1.0 - abs(encoded.x) - abs(encoded.y));
+    if (n.z < 0.0) {
+        let xy = (vec2<f32>(1.0) - abs(n.yx)) * sign_not_zero(n.xy);
+        n = vec3<f32>(xy, n.z);
+    }
+    return normalize(n);
+}
@@ -0,0 +1,49 @@
+// Assigns lights to clusters by sphere-box overlap. Each cluster owns max_lights_per_cluster
+// slots of the index list, lights past that are dropped.
+
+struct Cluster {
+    // view space bounds in xyz
+    min: vec4<f32>,
+    max: vec4<f32>,
+}
+
+struct LightGridUniform {
+    cluster_count: u32,
+    light_count: u32,
+    max_lights_per_cluster: u32,
+    _padding: u32,
+}
+
+@group(0) @binding(0) var<storage, read> clusters: array<Cluster>;
+// view space position in xyz, radius in w
+@group(0) @binding(1) var<storage, read> lights: array<vec4<f32>>;
+// (offset, count) per cluster into light_indices
+@group(0) @binding(2) var<storage, read_write> light_grid: array<vec2<u32>>;
+@group(0) @binding(3) var<storage, read_write> light_indices: array<u32>;
+@group(0) @binding(4) var<uniform> params: LightGridUniform;
+
+const WORKGROUP_SIZE: u32 = 64u;
+
+@compute @workgroup_size(WORKGROUP_SIZE)
+fn assign_lights(@builtin(global_invocation_id) id: vec3<u32>) {
+    let cluster_index = id.x;
+    if (cluster_index >= params.cluster_count) {
+        return;
+    }
+
+    let cluster = clusters[cluster_index];
+    let offset = cluster_index * params.max_lights_per_cluster;
+    var count = 0u;
+
+    for (var i = 0u; i < params.light_count && count < params.max_lights_per_cluster; i++) {
+        let light = lights[i];
+        let closest = clamp(light.xyz, cluster.min.xyz, cluster.max.xyz);
+        let delta = closest - light.xyz;
+        if (dot(delta, delta) <= light.w * light.w) {
+            light_indices[offset + count] = i;
+            count += 1u;
+        }
+    }
+
+    light_grid[cluster_index] = vec2<u32>(offset, count);
+}
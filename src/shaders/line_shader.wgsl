@@ -0,0 +1,123 @@
+struct LineUniform {
+    projection_view: mat4x4<f32>,
+    viewport: vec2<f32>,
+    width: f32,
+    miter_limit: f32,
+};
+
+@group(0) @binding(0) var<uniform> line_uniform: LineUniform;
+
+struct VertexOutput {
+    @builtin(position) position: vec4<f32>,
+    @location(0) color: vec4<f32>,
+};
+
+// hairline mode, one pixel wide LineList
+
+@vertex fn vs_hairline(@location(0) position: vec3<f32>, @location(1) color: vec4<f32>) -> VertexOutput {
+    var result: VertexOutput;
+    result.position = line_uniform.projection_view * vec4<f32>(position, 1.0);
+    result.color = color;
+    return result;
+}
+
+// thick mode, each segment instance is expanded into the two triangles of a quad and a bevel
+// triangle at its end
+
+struct SegmentInput {
+    @location(0) prev: vec3<f32>,
+    @location(1) start: vec3<f32>,
+    @location(2) end: vec3<f32>,
+    @location(3) next: vec3<f32>,
+    @location(4) color: vec4<f32>,
+};
+
+fn to_screen(clip: vec4<f32>) -> vec2<f32> {
+    return clip.xy / clip.w * 0.5 * line_uniform.viewport;
+}
+
+fn perpendicular(direction: vec2<f32>) -> vec2<f32> {
+    return vec2<f32>(-direction.y, direction.x);
+}
+
+// in half widths, very long for joints that turn back
+fn get_miter_length(normal: vec2<f32>, neighbor_normal: vec2<f32>) -> f32 {
+    let bisector = normal + neighbor_normal;
+    let cosine = dot(bisector, normal) / max(length(bisector), 0.0001);
+    return 1.0 / max(cosine, 0.0001);
+}
+
+// offset direction at a joint, scaled so the line keeps its width along both segments. Past the
+// miter limit the segment ends unjoined and the bevel triangle fills the outer corner.
+fn miter(normal: vec2<f32>, neighbor_normal: vec2<f32>) -> vec2<f32> {
+    let miter_length = get_miter_length(normal, neighbor_normal);
+    if (miter_length > line_uniform.miter_limit) {
+        return normal;
+    }
+    return normalize(normal + neighbor_normal) * miter_length;
+}
+
+@vertex fn vs_thick(@builtin(vertex_index) vertex_index: u32, segment: SegmentInput) -> VertexOutput {
+    // x selects the start or end of the segment, y selects the side. The bevel triangle after the
+    // quad has no area unless the joint at the end is past the miter limit.
+    var corners = array<vec2<f32>, 9>(
+        vec2<f32>(0.0, -1.0),
+        vec2<f32>(0.0, 1.0),
+        vec2<f32>(1.0, -1.0),
+        vec2<f32>(0.0, 1.0),
+        vec2<f32>(1.0, 1.0),
+        vec2<f32>(1.0, -1.0),
+        vec2<f32>(1.0, 0.0),
+        vec2<f32>(1.0, 1.0),
+        vec2<f32>(1.0, 1.0),
+    );
+    let corner = corners[vertex_index];
+
+    let clip_start = line_uniform.projection_view * vec4<f32>(segment.start, 1.0);
+    let clip_end = line_uniform.projection_view * vec4<f32>(segment.end, 1.0);
+
+    let screen_start = to_screen(clip_start);
+    let screen_end = to_screen(clip_end);
+
+    let direction = normalize(screen_end - screen_start);
+    let normal = perpendicular(direction);
+
+    var offset = normal;
+    var clip = clip_start;
+
+    if (corner.x < 0.5) {
+        let screen_prev = to_screen(line_uniform.projection_view * vec4<f32>(segment.prev, 1.0));
+        if (distance(screen_prev, screen_start) > 0.0001) {
+            offset = miter(normal, perpendicular(normalize(screen_start - screen_prev)));
+        }
+    } else {
+        clip = clip_end;
+        if (vertex_index >= 6u) {
+            offset = vec2<f32>(0.0);
+        }
+        let screen_next = to_screen(line_uniform.projection_view * vec4<f32>(segment.next, 1.0));
+        if (distance(screen_next, screen_end) > 0.0001) {
+            let next_normal = perpendicular(normalize(screen_next - screen_end));
+            if (vertex_index < 6u) {
+                offset = miter(normal, next_normal);
+            } else if (get_miter_length(normal, next_normal) > line_uniform.miter_limit) {
+                // on the outer side of the turn, from this segment's corner to the next one's
+                let side = select(-1.0, 1.0, dot(next_normal, direction) > 0.0);
+                offset = select(normal, next_normal, vertex_index == 8u) * side;
+            }
+        }
+    }
+
+    // pixels to clip space
+    let pixel_offset = offset * corner.y * line_uniform.width * 0.5;
+    clip = vec4<f32>(clip.xy + pixel_offset / (0.5 * line_uniform.viewport) * clip.w, clip.zw);
+
+    var result: VertexOutput;
+    result.position = clip;
+    result.color = segment.color;
+    return result;
+}
+
+@fragment fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    return in.color;
+}
@@ -0,0 +1,62 @@
+// Frustum and backface cone culling of meshlets. Each visible meshlet appends its index to
+// visible_meshlets and adds an instance to the indirect draw, see meshlet_draw.wgsl.
+
+struct MeshletBounds {
+    center: vec3<f32>,
+    radius: f32,
+    cone_axis: vec3<f32>,
+    cone_cutoff: f32,
+}
+
+struct MeshletCullUniform {
+    model: mat4x4<f32>,
+    // world space, normals pointing into the frustum
+    planes: array<vec4<f32>, 6>,
+    camera_position: vec3<f32>,
+    meshlet_count: u32,
+}
+
+struct DrawArgs {
+    vertex_count: u32,
+    instance_count: atomic<u32>,
+    first_vertex: u32,
+    first_instance: u32,
+}
+
+@group(0) @binding(0) var<storage, read> bounds: array<MeshletBounds>;
+@group(0) @binding(1) var<storage, read_write> visible_meshlets: array<u32>;
+@group(0) @binding(2) var<storage, read_write> draw_args: DrawArgs;
+@group(0) @binding(3) var<uniform> params: MeshletCullUniform;
+
+const WORKGROUP_SIZE: u32 = 64u;
+
+@compute @workgroup_size(WORKGROUP_SIZE)
+fn cull_meshlets(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let index = global_id.x;
+    if (index >= params.meshlet_count) {
+        return;
+    }
+
+    let meshlet_bounds = bounds[index];
+    let model = params.model;
+    let center = (model * vec4<f32>(meshlet_bounds.center, 1.0)).xyz;
+    let scale = max(length(model[0].xyz), max(length(model[1].xyz), length(model[2].xyz)));
+    let radius = meshlet_bounds.radius * scale;
+
+    for (var i = 0u; i < 6u; i++) {
+        let plane = params.planes[i];
+        if (dot(plane.xyz, center) + plane.w < -radius) {
+            return;
+        }
+    }
+
+    // every triangle faces away when the camera is inside the cone behind the meshlet
+    let cone_axis = normalize((model * vec4<f32>(meshlet_bounds.cone_axis, 0.0)).xyz);
+    let to_center = center - params.camera_position;
+    if (dot(to_center, cone_axis) > meshlet_bounds.cone_cutoff * length(to_center) + radius) {
+        return;
+    }
+
+    let slot = atomicAdd(&draw_args.instance_count, 1u);
+    visible_meshlets[slot] = index;
+}
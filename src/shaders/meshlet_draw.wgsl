@@ -0,0 +1,34 @@
+// Vertex fetch of meshlet draws. The instance index selects a visible meshlet and the vertex index
+// a corner of one of its triangles, corners past the meshlet's triangle count aren't valid and
+// the vertex shader should output vec4(0.0) for them so their triangles are dropped.
+
+struct Meshlet {
+    vertex_offset: u32,
+    vertex_count: u32,
+    triangle_offset: u32,
+    triangle_count: u32,
+}
+
+struct MeshletCorner {
+    // index into the mesh's vertices
+    vertex: u32,
+    valid: bool,
+}
+
+@group(MESHLET_GROUP) @binding(0) var<storage, read> meshlets: array<Meshlet>;
+@group(MESHLET_GROUP) @binding(1) var<storage, read> meshlet_vertices: array<u32>;
+// three local vertex indices packed in the low bytes
+@group(MESHLET_GROUP) @binding(2) var<storage, read> meshlet_triangles: array<u32>;
+@group(MESHLET_GROUP) @binding(3) var<storage, read> visible_meshlets: array<u32>;
+
+fn get_meshlet_corner(instance_index: u32, vertex_index: u32) -> MeshletCorner {
+    let meshlet = meshlets[visible_meshlets[instance_index]];
+    let triangle = vertex_index / 3u;
+    if (triangle >= meshlet.triangle_count) {
+        return MeshletCorner(0u, false);
+    }
+
+    let packed = meshlet_triangles[meshlet.triangle_offset + triangle];
+    let local_vertex = (packed >> (8u * (vertex_index % 3u))) & 0xffu;
+    return MeshletCorner(meshlet_vertices[meshlet.vertex_offset + local_vertex], true);
+}
@@ -0,0 +1,29 @@
+// Mip level selected for uv from the screen space derivatives. Not clamped to the mip chain,
+// below 0 the texture is magnified and past the last level it is undersampled.
+fn get_mip_level(t: texture_2d<f32>, uv: vec2<f32>) -> f32 {
+    let texel_uv = uv * vec2<f32>(textureDimensions(t, 0));
+    let dx = dpdx(texel_uv);
+    let dy = dpdy(texel_uv);
+    let max_length_squared = max(dot(dx, dx), dot(dy, dy));
+    return 0.5 * log2(max(max_length_squared, 1e-8));
+}
+
+// Blue when magnified, green at level 0 through yellow to red at the last level,
+// magenta when past the end of the mip chain
+fn get_mip_level_color(level: f32, num_levels: u32) -> vec3<f32> {
+    let last_level = f32(max(num_levels, 1u) - 1u);
+
+    if (level < 0.0) {
+        return mix(vec3<f32>(0.0, 1.0, 0.0), vec3<f32>(0.0, 0.0, 1.0), clamp(-level, 0.0, 1.0));
+    }
+
+    if (level > last_level + 0.5) {
+        return vec3<f32>(1.0, 0.0, 1.0);
+    }
+
+    let t = clamp(level / max(last_level, 1.0), 0.0, 1.0);
+    if (t < 0.5) {
+        return mix(vec3<f32>(0.0, 1.0, 0.0), vec3<f32>(1.0, 1.0, 0.0), t * 2.0);
+    }
+    return mix(vec3<f32>(1.0, 1.0, 0.0), vec3<f32>(1.0, 0.0, 0.0), t * 2.0 - 1.0);
+}
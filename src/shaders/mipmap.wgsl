@@ -0,0 +1,9 @@
+//!include "spark_gap/fullscreen.wgsl"
+
+// Each mip level is drawn from the level above with a linear sampler, averaging 2x2 texels
+@group(0) @binding(0) var source_texture: texture_2d<f32>;
+@group(0) @binding(1) var source_sampler: sampler;
+
+@fragment fn fs_main(vertex: FullscreenOutput) -> @location(0) vec4<f32> {
+    return textureSampleLevel(source_texture, source_sampler, vertex.uv, 0.0);
+}
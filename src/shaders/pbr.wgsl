@@ -0,0 +1,82 @@
+// Cook-Torrance brdf for PbrMaterial in material.rs. Prepend to shaders that shade pbr materials, the
+// including shader declares the material bindings in the order of get_pbr_material_layout_builder.
+
+const PI: f32 = 3.14159265359;
+
+// reflectance at normal incidence of non metals
+const DIELECTRIC_F0: f32 = 0.04;
+
+// rougher than zero so the highlight of a point light doesn't collapse to nothing
+const MIN_ROUGHNESS: f32 = 0.045;
+
+// PbrMaterialUniform in material.rs
+struct PbrMaterial {
+    base_color: vec4<f32>,
+    metallic: f32,
+    roughness: f32,
+    normal_scale: f32,
+};
+
+struct PbrSurface {
+    albedo: vec3<f32>,
+    metallic: f32,
+    roughness: f32,
+};
+
+// The factors times the maps, metallic in blue and roughness in green like gltf
+fn get_pbr_surface(material: PbrMaterial, albedo_sample: vec4<f32>, metallic_roughness_sample: vec4<f32>) -> PbrSurface {
+    var surface: PbrSurface;
+    surface.albedo = material.base_color.rgb * albedo_sample.rgb;
+    surface.metallic = clamp(material.metallic * metallic_roughness_sample.b, 0.0, 1.0);
+    surface.roughness = clamp(material.roughness * metallic_roughness_sample.g, MIN_ROUGHNESS, 1.0);
+    return surface;
+}
+
+// A tangent space normal map sample in world space, tangent.w is the sign of the bitangent
+fn get_mapped_normal(normal: vec3<f32>, tangent: vec4<f32>, normal_sample: vec3<f32>, normal_scale: f32) -> vec3<f32> {
+    let n = normalize(normal);
+    let t = normalize(tangent.xyz - n * dot(n, tangent.xyz));
+    let b = cross(n, t) * tangent.w;
+    let mapped = (normal_sample * 2.0 - 1.0) * vec3<f32>(normal_scale, normal_scale, 1.0);
+    return normalize(mat3x3<f32>(t, b, n) * mapped);
+}
+
+fn distribution_ggx(n_dot_h: f32, roughness: f32) -> f32 {
+    let a = roughness * roughness;
+    let a2 = a * a;
+    let d = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+    return a2 / (PI * d * d);
+}
+
+fn geometry_schlick_ggx(n_dot_x: f32, roughness: f32) -> f32 {
+    let r = roughness + 1.0;
+    let k = r * r / 8.0;
+    return n_dot_x / (n_dot_x * (1.0 - k) + k);
+}
+
+fn geometry_smith(n_dot_v: f32, n_dot_l: f32, roughness: f32) -> f32 {
+    return geometry_schlick_ggx(n_dot_v, roughness) * geometry_schlick_ggx(n_dot_l, roughness);
+}
+
+fn fresnel_schlick(cos_theta: f32, f0: vec3<f32>) -> vec3<f32> {
+    return f0 + (vec3<f32>(1.0) - f0) * pow(clamp(1.0 - cos_theta, 0.0, 1.0), 5.0);
+}
+
+// Light reflected towards the viewer from one light, the directions are unit vectors pointing away
+// from the surface. cook_torrance in material.rs is the cpu version.
+fn cook_torrance(surface: PbrSurface, normal: vec3<f32>, view_dir: vec3<f32>, light_dir: vec3<f32>, radiance: vec3<f32>) -> vec3<f32> {
+    let n_dot_l = max(dot(normal, light_dir), 0.0);
+    let n_dot_v = max(dot(normal, view_dir), 1e-4);
+    let half_dir = normalize(view_dir + light_dir);
+    let n_dot_h = max(dot(normal, half_dir), 0.0);
+    let h_dot_v = max(dot(half_dir, view_dir), 0.0);
+
+    let f0 = mix(vec3<f32>(DIELECTRIC_F0), surface.albedo, surface.metallic);
+    let fresnel = fresnel_schlick(h_dot_v, f0);
+    let specular = distribution_ggx(n_dot_h, surface.roughness) * geometry_smith(n_dot_v, n_dot_l, surface.roughness) * fresnel
+        / (4.0 * n_dot_v * max(n_dot_l, 1e-4));
+
+    // metals have no diffuse
+    let diffuse = (vec3<f32>(1.0) - fresnel) * (1.0 - surface.metallic) * surface.albedo / PI;
+    return (diffuse + specular) * radiance * n_dot_l;
+}
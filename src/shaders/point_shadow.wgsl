@@ -0,0 +1,31 @@
+// PointShadowUniform in point_shadow.rs
+struct PointShadowLight {
+    // in the order of PointShadow::shadow_maps.layer_views
+    face_projection_views: array<mat4x4<f32>, 6>,
+    // the light's position in xyz
+    position: vec4<f32>,
+    // near, far and 1 / (far - near)
+    depth_range: vec4<f32>,
+};
+
+// The depth the face passes write instead of the projection's, the distance to the light mapped
+// linearly from near..far to 0..1. get_point_shadow_depth in point_shadow.rs is the cpu version.
+fn get_point_shadow_depth(light: PointShadowLight, world_position: vec3<f32>) -> f32 {
+    let distance = length(world_position - light.position.xyz);
+    return clamp((distance - light.depth_range.x) * light.depth_range.z, 0.0, 1.0);
+}
+
+// How much of the light reaches the point, 0.0 in shadow and 1.0 fully lit. The cube is sampled in
+// the direction from the light to the point, the opposite of the fragment to light vector, and bias
+// is a distance in world units.
+fn sample_point_shadow(
+    shadow_cube: texture_depth_cube,
+    shadow_sampler: sampler_comparison,
+    light: PointShadowLight,
+    world_position: vec3<f32>,
+    bias: f32,
+) -> f32 {
+    let light_to_point = world_position - light.position.xyz;
+    let depth = get_point_shadow_depth(light, world_position) - bias * light.depth_range.z;
+    return textureSampleCompareLevel(shadow_cube, shadow_sampler, light_to_point, depth);
+}
@@ -0,0 +1,84 @@
+// Blelloch exclusive scan. Each workgroup scans a block of 2 * WORKGROUP_SIZE values in place
+// and writes the block total, the block totals are scanned in the next level and added back.
+
+struct ScanUniform {
+    length: u32,
+    _padding: vec3<u32>,
+}
+
+@group(0) @binding(0) var<storage, read_write> data: array<u32>;
+@group(0) @binding(1) var<storage, read_write> block_sums: array<u32>;
+@group(0) @binding(2) var<uniform> params: ScanUniform;
+
+const WORKGROUP_SIZE: u32 = 256u;
+const BLOCK_SIZE: u32 = 512u;
+
+var<workgroup> temp: array<u32, BLOCK_SIZE>;
+
+@compute @workgroup_size(WORKGROUP_SIZE)
+fn scan_blocks(@builtin(local_invocation_id) local_id: vec3<u32>, @builtin(workgroup_id) group_id: vec3<u32>) {
+    let thread = local_id.x;
+    let a = group_id.x * BLOCK_SIZE + 2u * thread;
+    let b = a + 1u;
+
+    temp[2u * thread] = 0u;
+    temp[2u * thread + 1u] = 0u;
+    if (a < params.length) {
+        temp[2u * thread] = data[a];
+    }
+    if (b < params.length) {
+        temp[2u * thread + 1u] = data[b];
+    }
+
+    // up sweep, builds partial sums in place
+    var offset = 1u;
+    for (var d = BLOCK_SIZE >> 1u; d > 0u; d = d >> 1u) {
+        workgroupBarrier();
+        if (thread < d) {
+            let ai = offset * (2u * thread + 1u) - 1u;
+            let bi = offset * (2u * thread + 2u) - 1u;
+            temp[bi] += temp[ai];
+        }
+        offset = offset * 2u;
+    }
+
+    if (thread == 0u) {
+        block_sums[group_id.x] = temp[BLOCK_SIZE - 1u];
+        temp[BLOCK_SIZE - 1u] = 0u;
+    }
+
+    // down sweep
+    for (var d = 1u; d < BLOCK_SIZE; d = d * 2u) {
+        offset = offset >> 1u;
+        workgroupBarrier();
+        if (thread < d) {
+            let ai = offset * (2u * thread + 1u) - 1u;
+            let bi = offset * (2u * thread + 2u) - 1u;
+            let t = temp[ai];
+            temp[ai] = temp[bi];
+            temp[bi] += t;
+        }
+    }
+    workgroupBarrier();
+
+    if (a < params.length) {
+        data[a] = temp[2u * thread];
+    }
+    if (b < params.length) {
+        data[b] = temp[2u * thread + 1u];
+    }
+}
+
+@compute @workgroup_size(WORKGROUP_SIZE)
+fn add_block_sums(@builtin(local_invocation_id) local_id: vec3<u32>, @builtin(workgroup_id) group_id: vec3<u32>) {
+    let sum = block_sums[group_id.x];
+    let a = group_id.x * BLOCK_SIZE + 2u * local_id.x;
+    let b = a + 1u;
+
+    if (a < params.length) {
+        data[a] += sum;
+    }
+    if (b < params.length) {
+        data[b] += sum;
+    }
+}
@@ -0,0 +1,22 @@
+// CascadeUniform in shadow_cascades.rs, see get_shadow_cascades_wgsl
+#const MAX_SHADOW_CASCADES
+
+struct ShadowCascades {
+    projection_views: array<mat4x4<f32>, MAX_SHADOW_CASCADES>,
+    // view distance where each cascade ends in x, nearest first
+    splits: array<vec4<f32>, MAX_SHADOW_CASCADES>,
+    cascade_count: u32,
+};
+
+// The first cascade reaching past view_distance, the last one beyond all splits
+fn select_cascade(cascades: ShadowCascades, view_distance: f32) -> u32 {
+    // a local copy can be indexed dynamically
+    var splits = cascades.splits;
+    var cascade = 0u;
+    for (var i = 0u; i + 1u < cascades.cascade_count; i += 1u) {
+        if (view_distance > splits[i].x) {
+            cascade = i + 1u;
+        }
+    }
+    return cascade;
+}
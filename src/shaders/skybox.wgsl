@@ -0,0 +1,33 @@
+// SkyboxUniform in skybox.rs
+struct SkyboxUniform {
+    inverse_projection: mat4x4<f32>,
+    inverse_view_rotation: mat4x4<f32>,
+};
+
+@group(0) @binding(0) var sky_texture: texture_cube<f32>;
+@group(0) @binding(1) var sky_sampler: sampler;
+@group(0) @binding(2) var<uniform> sky: SkyboxUniform;
+
+struct VertexOutput {
+    @builtin(position) position: vec4<f32>,
+    @location(0) ndc: vec2<f32>,
+};
+
+// A fullscreen triangle on the far plane
+@vertex fn vs_skybox(@builtin(vertex_index) index: u32) -> VertexOutput {
+    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+    let ndc = uv * vec2<f32>(2.0, -2.0) + vec2<f32>(-1.0, 1.0);
+
+    var result: VertexOutput;
+    result.position = vec4<f32>(ndc, 1.0, 1.0);
+    result.ndc = ndc;
+    return result;
+}
+
+@fragment fn fs_skybox(vertex: VertexOutput) -> @location(0) vec4<f32> {
+    // The xyz of an unprojected perspective clip position is the view ray through the pixel for
+    // any depth. It isn't divided by w, which is 0 at the far plane of an infinite projection.
+    let view_ray = sky.inverse_projection * vec4<f32>(vertex.ndc, 1.0, 1.0);
+    let direction = sky.inverse_view_rotation * vec4<f32>(view_ray.xyz, 0.0);
+    return textureSampleLevel(sky_texture, sky_sampler, normalize(direction.xyz), 0.0);
+}
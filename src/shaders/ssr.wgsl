@@ -0,0 +1,96 @@
+//!include "spark_gap/fullscreen.wgsl"
+
+struct SsrUniform {
+    projection: mat4x4<f32>,
+    inverse_projection: mat4x4<f32>,
+    max_steps: u32,
+    thickness: f32,
+    max_distance: f32,
+    edge_fade: f32,
+    max_roughness: f32,
+    intensity: f32,
+};
+
+@group(0) @binding(0) var color_texture: texture_2d<f32>;
+@group(0) @binding(1) var depth_texture: texture_depth_2d;
+// view space normal in xyz and roughness in w
+@group(0) @binding(2) var normal_texture: texture_2d<f32>;
+@group(0) @binding(3) var color_sampler: sampler;
+@group(0) @binding(4) var<uniform> ssr: SsrUniform;
+
+fn view_position(uv: vec2<f32>, depth: f32) -> vec3<f32> {
+    let ndc = vec4<f32>(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, depth, 1.0);
+    let view = ssr.inverse_projection * ndc;
+    return view.xyz / view.w;
+}
+
+fn project_to_uv(position: vec3<f32>) -> vec2<f32> {
+    let clip = ssr.projection * vec4<f32>(position, 1.0);
+    let ndc = clip.xy / clip.w;
+    return vec2<f32>(ndc.x * 0.5 + 0.5, 0.5 - ndc.y * 0.5);
+}
+
+fn load_depth(uv: vec2<f32>) -> f32 {
+    let dimensions = vec2<f32>(textureDimensions(depth_texture));
+    let coords = vec2<i32>(clamp(uv * dimensions, vec2<f32>(0.0), dimensions - 1.0));
+    return textureLoad(depth_texture, coords, 0);
+}
+
+@fragment fn fs_main(vertex: FullscreenOutput) -> @location(0) vec4<f32> {
+    let color = textureSampleLevel(color_texture, color_sampler, vertex.uv, 0.0);
+    let normal_roughness = textureSampleLevel(normal_texture, color_sampler, vertex.uv, 0.0);
+
+    let depth = load_depth(vertex.uv);
+    let roughness = normal_roughness.w;
+
+    // nothing to reflect for the background or rough surfaces
+    if (depth >= 1.0 || roughness >= ssr.max_roughness) {
+        return color;
+    }
+
+    let origin = view_position(vertex.uv, depth);
+    let normal = normalize(normal_roughness.xyz);
+    let direction = normalize(reflect(normalize(origin), normal));
+
+    let step_length = ssr.max_distance / f32(max(ssr.max_steps, 1u));
+
+    var hit_uv = vec2<f32>(0.0);
+    var hit_distance = 0.0;
+    var hit = false;
+
+    for (var i = 1u; i <= ssr.max_steps; i += 1u) {
+        let position = origin + direction * step_length * f32(i);
+        let uv = project_to_uv(position);
+
+        if (any(uv < vec2<f32>(0.0)) || any(uv > vec2<f32>(1.0))) {
+            break;
+        }
+
+        let scene = view_position(uv, load_depth(uv));
+
+        // view space looks down -z so the ray is behind the surface when it is further away
+        let behind = scene.z - position.z;
+        if (behind > 0.0 && behind < ssr.thickness) {
+            hit_uv = uv;
+            hit_distance = step_length * f32(i);
+            hit = true;
+            break;
+        }
+    }
+
+    if (!hit) {
+        return color;
+    }
+
+    let reflected = textureSampleLevel(color_texture, color_sampler, hit_uv, 0.0).rgb;
+
+    // fade out near the screen edges, with distance and with roughness to hide missing information
+    let edge = min(hit_uv, vec2<f32>(1.0) - hit_uv);
+    let edge_fade = clamp(min(edge.x, edge.y) / max(ssr.edge_fade, 0.0001), 0.0, 1.0);
+    let distance_fade = 1.0 - clamp(hit_distance / ssr.max_distance, 0.0, 1.0);
+    let roughness_fade = 1.0 - clamp(roughness / ssr.max_roughness, 0.0, 1.0);
+
+    let weight = edge_fade * distance_fade * roughness_fade * ssr.intensity;
+
+    return vec4<f32>(mix(color.rgb, reflected, weight), color.a);
+}
@@ -0,0 +1,48 @@
+//!include "spark_gap/fullscreen.wgsl"
+
+struct TaaUniform {
+    texel_size: vec2<f32>,
+    blend_factor: f32,
+    history_valid: f32,
+};
+
+@group(0) @binding(0) var current_texture: texture_2d<f32>;
+@group(0) @binding(1) var history_texture: texture_2d<f32>;
+@group(0) @binding(2) var motion_texture: texture_2d<f32>;
+@group(0) @binding(3) var taa_sampler: sampler;
+@group(0) @binding(4) var<uniform> taa: TaaUniform;
+
+// the history clamped to the neighborhood bounds and blended in, TaaPass's resolve_taa_color
+fn resolve_history(current: vec3<f32>, history: vec3<f32>, color_min: vec3<f32>, color_max: vec3<f32>, blend: f32) -> vec3<f32> {
+    return mix(current, clamp(history, color_min, color_max), blend);
+}
+
+@fragment fn fs_main(vertex: FullscreenOutput) -> @location(0) vec4<f32> {
+    let current = textureSampleLevel(current_texture, taa_sampler, vertex.uv, 0.0).rgb;
+
+    // the 3x3 neighborhood of the current frame bounds the history color to reject ghosting
+    var color_min = current;
+    var color_max = current;
+
+    for (var x = -1; x <= 1; x += 1) {
+        for (var y = -1; y <= 1; y += 1) {
+            let offset = vec2<f32>(f32(x), f32(y)) * taa.texel_size;
+            let neighbor = textureSampleLevel(current_texture, taa_sampler, vertex.uv + offset, 0.0).rgb;
+            color_min = min(color_min, neighbor);
+            color_max = max(color_max, neighbor);
+        }
+    }
+
+    // motion vectors point from the previous position to the current one
+    let motion = textureSampleLevel(motion_texture, taa_sampler, vertex.uv, 0.0).xy;
+    let history_uv = vertex.uv - motion;
+
+    let history = textureSampleLevel(history_texture, taa_sampler, history_uv, 0.0).rgb;
+
+    var blend = taa.blend_factor * taa.history_valid;
+    if (any(history_uv < vec2<f32>(0.0)) || any(history_uv > vec2<f32>(1.0))) {
+        blend = 0.0;
+    }
+
+    return vec4<f32>(resolve_history(current, history, color_min, color_max, blend), 1.0);
+}
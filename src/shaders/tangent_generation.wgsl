@@ -0,0 +1,97 @@
+// Per vertex tangents of an indexed mesh. accumulate_tangents adds the unit tangent of each triangle
+// to its three vertices in fixed point, normalize_tangents turns the sums into unit tangents.
+
+struct TangentUniform {
+    vertex_count: u32,
+    triangle_count: u32,
+    _padding: vec2<u32>,
+}
+
+// positions and tangents are packed vec3s, uvs packed vec2s
+@group(0) @binding(0) var<storage, read> positions: array<f32>;
+@group(0) @binding(1) var<storage, read> uvs: array<f32>;
+@group(0) @binding(2) var<storage, read> indices: array<u32>;
+@group(0) @binding(3) var<storage, read_write> accumulated: array<atomic<i32>>;
+@group(0) @binding(4) var<storage, read_write> tangents: array<f32>;
+@group(0) @binding(5) var<uniform> params: TangentUniform;
+
+const WORKGROUP_SIZE: u32 = 64u;
+const FIXED_POINT_SCALE: f32 = 65536.0;
+
+fn load_position(index: u32) -> vec3<f32> {
+    return vec3<f32>(positions[3u * index], positions[3u * index + 1u], positions[3u * index + 2u]);
+}
+
+fn load_uv(index: u32) -> vec2<f32> {
+    return vec2<f32>(uvs[2u * index], uvs[2u * index + 1u]);
+}
+
+// zero for triangles with degenerate uvs
+fn face_tangent(i0: u32, i1: u32, i2: u32) -> vec3<f32> {
+    let p0 = load_position(i0);
+    let edge1 = load_position(i1) - p0;
+    let edge2 = load_position(i2) - p0;
+
+    let uv0 = load_uv(i0);
+    let delta_uv1 = load_uv(i1) - uv0;
+    let delta_uv2 = load_uv(i2) - uv0;
+
+    let det = delta_uv1.x * delta_uv2.y - delta_uv2.x * delta_uv1.y;
+    if (abs(det) < 1e-12) {
+        return vec3<f32>(0.0);
+    }
+
+    let tangent = (edge1 * delta_uv2.y - edge2 * delta_uv1.y) / det;
+    let length_squared = dot(tangent, tangent);
+    if (length_squared == 0.0) {
+        return vec3<f32>(0.0);
+    }
+    return tangent * inverseSqrt(length_squared);
+}
+
+fn add_tangent(vertex: u32, tangent: vec3<i32>) {
+    atomicAdd(&accumulated[3u * vertex], tangent.x);
+    atomicAdd(&accumulated[3u * vertex + 1u], tangent.y);
+    atomicAdd(&accumulated[3u * vertex + 2u], tangent.z);
+}
+
+@compute @workgroup_size(WORKGROUP_SIZE)
+fn accumulate_tangents(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let triangle = global_id.x;
+    if (triangle >= params.triangle_count) {
+        return;
+    }
+
+    let i0 = indices[3u * triangle];
+    let i1 = indices[3u * triangle + 1u];
+    let i2 = indices[3u * triangle + 2u];
+
+    let tangent = vec3<i32>(round(face_tangent(i0, i1, i2) * FIXED_POINT_SCALE));
+    add_tangent(i0, tangent);
+    add_tangent(i1, tangent);
+    add_tangent(i2, tangent);
+}
+
+@compute @workgroup_size(WORKGROUP_SIZE)
+fn normalize_tangents(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let vertex = global_id.x;
+    if (vertex >= params.vertex_count) {
+        return;
+    }
+
+    let sum = vec3<f32>(
+        f32(atomicLoad(&accumulated[3u * vertex])),
+        f32(atomicLoad(&accumulated[3u * vertex + 1u])),
+        f32(atomicLoad(&accumulated[3u * vertex + 2u])),
+    );
+
+    var tangent = vec3<f32>(0.0);
+    let length_squared = dot(sum, sum);
+    if (length_squared > 0.0) {
+        tangent = sum * inverseSqrt(length_squared);
+    }
+
+    tangents[3u * vertex] = tangent.x;
+    tangents[3u * vertex + 1u] = tangent.y;
+    tangents[3u * vertex + 2u] = tangent.z;
+}
@@ -0,0 +1,72 @@
+//!include "spark_gap/fullscreen.wgsl"
+
+// TonemapOperator::get_index in tonemap.rs
+const TONEMAP_REINHARD: u32 = 0u;
+const TONEMAP_ACES_FILMIC: u32 = 1u;
+const TONEMAP_PASSTHROUGH: u32 = 2u;
+
+// OutputEncoding::get_index in tonemap.rs
+const OUTPUT_LINEAR: u32 = 0u;
+const OUTPUT_SRGB: u32 = 1u;
+const OUTPUT_PQ: u32 = 2u;
+
+// PQ_PAPER_WHITE_NITS in tonemap.rs
+const PQ_PAPER_WHITE_NITS: f32 = 203.0;
+
+struct TonemapUniform {
+    primaries: mat3x3<f32>,
+    exposure: f32,
+    white_point: f32,
+    operator: u32,
+    output_encoding: u32,
+};
+
+@group(0) @binding(0) var input_texture: texture_2d<f32>;
+@group(0) @binding(1) var input_sampler: sampler;
+@group(0) @binding(2) var<uniform> tonemap: TonemapUniform;
+
+fn apply_operator(color: vec3<f32>) -> vec3<f32> {
+    if (tonemap.operator == TONEMAP_ACES_FILMIC) {
+        return color * (2.51 * color + 0.03) / (color * (2.43 * color + 0.59) + 0.14);
+    }
+    if (tonemap.operator == TONEMAP_PASSTHROUGH) {
+        return color;
+    }
+
+    // extended reinhard, the white point maps to 1.0
+    let white_squared = tonemap.white_point * tonemap.white_point;
+    return color * (1.0 + color / white_squared) / (1.0 + color);
+}
+
+fn linear_to_srgb(color: vec3<f32>) -> vec3<f32> {
+    let low = color * 12.92;
+    let high = 1.055 * pow(color, vec3<f32>(1.0 / 2.4)) - 0.055;
+    return select(high, low, color <= vec3<f32>(0.0031308));
+}
+
+// SMPTE ST 2084, 1.0 is shown at the paper white
+fn linear_to_pq(color: vec3<f32>) -> vec3<f32> {
+    let m1 = 0.1593017578125;
+    let m2 = 78.84375;
+    let c1 = 0.8359375;
+    let c2 = 18.8515625;
+    let c3 = 18.6875;
+    let y = pow(clamp(color * PQ_PAPER_WHITE_NITS / 10000.0, vec3<f32>(0.0), vec3<f32>(1.0)), vec3<f32>(m1));
+    return pow((c1 + c2 * y) / (1.0 + c3 * y), vec3<f32>(m2));
+}
+
+@fragment fn fs_main(vertex: FullscreenOutput) -> @location(0) vec4<f32> {
+    let input = textureSampleLevel(input_texture, input_sampler, vertex.uv, 0.0);
+    let color = max(input.rgb * tonemap.exposure, vec3<f32>(0.0));
+    let mapped = apply_operator(color);
+
+    // rec.709 to the output primaries, only sdr outputs are clamped to 1.0
+    var output = max(tonemap.primaries * mapped, vec3<f32>(0.0));
+    if (tonemap.output_encoding == OUTPUT_SRGB) {
+        output = linear_to_srgb(min(output, vec3<f32>(1.0)));
+    } else if (tonemap.output_encoding == OUTPUT_PQ) {
+        output = linear_to_pq(output);
+    }
+
+    return vec4<f32>(output, input.a);
+}
@@ -0,0 +1,132 @@
+use crate::error::Error;
+use crate::error::Error::ValidationError;
+
+pub const MIN_SHADOW_RESOLUTION: u32 = 64;
+
+// Square region of a shadow atlas in texels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AtlasRect {
+    pub x: u32,
+    pub y: u32,
+    pub size: u32,
+}
+
+impl AtlasRect {
+    pub fn overlaps(&self, other: &AtlasRect) -> bool {
+        self.x < other.x + other.size && other.x < self.x + self.size && self.y < other.y + other.size && other.y < self.y + self.size
+    }
+
+    // uv offset in xy and uv scale in zw, for mapping a shadow map uv into the atlas
+    pub fn get_uv_rect(&self, atlas_size: u32) -> [f32; 4] {
+        let atlas_size = atlas_size as f32;
+        [
+            self.x as f32 / atlas_size,
+            self.y as f32 / atlas_size,
+            self.size as f32 / atlas_size,
+            self.size as f32 / atlas_size,
+        ]
+    }
+}
+
+// Priority 0 is the most important light and gets half the atlas width, each step halves again
+pub fn get_priority_resolution(atlas_size: u32, priority: u32) -> u32 {
+    atlas_size
+        .checked_shr(priority + 1)
+        .unwrap_or(0)
+        .max(MIN_SHADOW_RESOLUTION)
+        .min(atlas_size)
+}
+
+// Packs one square per requested resolution, rounded up to powers of two, by splitting the atlas
+// into quadrants. Returns the rects in request order, or an error when they don't all fit.
+pub fn pack_shadow_atlas(atlas_size: u32, resolutions: &[u32]) -> Result<Vec<AtlasRect>, Error> {
+    if !atlas_size.is_power_of_two() {
+        return Err(ValidationError(format!("shadow atlas size {} is not a power of two", atlas_size)));
+    }
+
+    // largest first, so every free square is at least as large as the remaining requests
+    let mut order: Vec<usize> = (0..resolutions.len()).collect();
+    order.sort_by_key(|i| std::cmp::Reverse(resolutions[*i]));
+
+    let mut free = vec![AtlasRect {
+        x: 0,
+        y: 0,
+        size: atlas_size,
+    }];
+    let mut rects = vec![AtlasRect { x: 0, y: 0, size: 0 }; resolutions.len()];
+
+    for index in order {
+        let size = resolutions[index].max(1).next_power_of_two();
+
+        let free_index = free
+            .iter()
+            .enumerate()
+            .filter(|(_, rect)| rect.size >= size)
+            .min_by_key(|(_, rect)| rect.size)
+            .map(|(i, _)| i)
+            .ok_or_else(|| {
+                ValidationError(format!(
+                    "shadow atlas {}x{} has no space for a {} shadow map",
+                    atlas_size, atlas_size, size
+                ))
+            })?;
+
+        let mut rect = free.swap_remove(free_index);
+        while rect.size > size {
+            let half = rect.size / 2;
+            free.push(AtlasRect {
+                x: rect.x + half,
+                y: rect.y,
+                size: half,
+            });
+            free.push(AtlasRect {
+                x: rect.x,
+                y: rect.y + half,
+                size: half,
+            });
+            free.push(AtlasRect {
+                x: rect.x + half,
+                y: rect.y + half,
+                size: half,
+            });
+            rect.size = half;
+        }
+
+        rects[index] = rect;
+    }
+
+    Ok(rects)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::shadow_atlas::{get_priority_resolution, pack_shadow_atlas, AtlasRect};
+
+    #[test]
+    fn test_pack_shadow_atlas() {
+        let atlas_size = 4096;
+        let priorities = [2, 0, 1, 3, 3, 9];
+        let resolutions: Vec<u32> = priorities.iter().map(|p| get_priority_resolution(atlas_size, *p)).collect();
+        assert_eq!(resolutions, vec![512, 2048, 1024, 256, 256, 64]);
+
+        let rects = pack_shadow_atlas(atlas_size, &resolutions).unwrap();
+
+        for (i, rect) in rects.iter().enumerate() {
+            assert_eq!(rect.size, resolutions[i]);
+            assert!(rect.x + rect.size <= atlas_size && rect.y + rect.size <= atlas_size);
+            for other in &rects[i + 1..] {
+                assert!(!rect.overlaps(other), "{:?} overlaps {:?}", rect, other);
+            }
+        }
+
+        // more important lights get larger rects
+        assert!(rects[1].size > rects[2].size && rects[2].size > rects[0].size);
+
+        let uv_rect = AtlasRect { x: 2048, y: 0, size: 1024 }.get_uv_rect(atlas_size);
+        assert_eq!(uv_rect, [0.5, 0.0, 0.25, 0.25]);
+
+        // five half size maps don't fit
+        assert!(matches!(pack_shadow_atlas(atlas_size, &[2048; 5]), Err(Error::ValidationError(_))));
+    }
+}
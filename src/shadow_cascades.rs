@@ -0,0 +1,402 @@
+use std::ops::Range;
+
+use bytemuck::{Pod, Zeroable};
+use glam::{Mat4, Vec3};
+use wgpu::{BindGroup, BindGroupLayout, TextureView};
+
+use crate::bind_group::{BindGroupBuilder, LayoutBuilder};
+use crate::buffers::UniformBuffer;
+use crate::camera::projection::get_perspective_matrix;
+use crate::error::Error;
+use crate::error::Error::ValidationError;
+use crate::gpu_context::GpuContext;
+use crate::shader_preprocessor::preprocess_with_constants;
+use crate::shadow_projection::{get_cascade_projection_view, get_cascade_splits};
+use crate::texture::{SamplerBuilder, DEPTH_FORMAT};
+
+pub const MAX_SHADOW_CASCADES: usize = 4;
+
+// Wgsl ShadowCascades struct and select_cascade function matching CascadeUniform, with the
+// arrays sized by MAX_SHADOW_CASCADES
+pub fn get_shadow_cascades_wgsl() -> String {
+    let constants = [("MAX_SHADOW_CASCADES", MAX_SHADOW_CASCADES as u32)];
+    preprocess_with_constants(include_str!("shaders/shadow_cascades.wgsl"), &[], &constants).expect("invalid shadow_cascades.wgsl")
+}
+
+// Perspective camera whose frustum the cascades split by view distance
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CascadeCamera {
+    pub view: Mat4,
+    // vertical, in radians
+    pub fov: f32,
+    pub aspect_ratio: f32,
+    pub near: f32,
+    pub far: f32,
+}
+
+impl CascadeCamera {
+    pub fn get_slice_projection_view(&self, near: f32, far: f32) -> Mat4 {
+        get_perspective_matrix(self.fov, self.aspect_ratio, near, far) * self.view
+    }
+
+    // View distance ranges of the cascades, nearest first, ending at max_distance or the far plane.
+    // lambda blends uniform (0.0) and logarithmic (1.0) splits.
+    pub fn get_cascade_ranges(&self, cascade_count: u32, lambda: f32, max_distance: f32) -> Vec<Range<f32>> {
+        let far = self.far.min(max_distance);
+        let mut near = self.near;
+        get_cascade_splits(self.near, far, cascade_count, lambda)
+            .into_iter()
+            .map(|split| {
+                let range = near..split;
+                near = split;
+                range
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CascadeSettings {
+    pub cascade_count: u32,
+    // width and height of each cascade's shadow map
+    pub resolution: u32,
+    pub lambda: f32,
+    // cascades cover the camera frustum up to this distance
+    pub max_distance: f32,
+}
+
+impl Default for CascadeSettings {
+    fn default() -> Self {
+        CascadeSettings {
+            cascade_count: 4,
+            resolution: 2048,
+            lambda: 0.5,
+            max_distance: 100.0,
+        }
+    }
+}
+
+impl CascadeSettings {
+    pub fn check(&self, limits: &wgpu::Limits) -> Result<(), Error> {
+        if !(1..=MAX_SHADOW_CASCADES as u32).contains(&self.cascade_count) {
+            return Err(ValidationError(format!(
+                "{} cascades, 1 to {} are supported",
+                self.cascade_count, MAX_SHADOW_CASCADES
+            )));
+        }
+        if self.resolution == 0 || self.resolution > limits.max_texture_dimension_2d {
+            return Err(ValidationError(format!(
+                "cascade resolution {} outside of 1 to {}",
+                self.resolution, limits.max_texture_dimension_2d
+            )));
+        }
+        if self.cascade_count > limits.max_texture_array_layers {
+            return Err(ValidationError(format!(
+                "{} cascades but only {} texture array layers",
+                self.cascade_count, limits.max_texture_array_layers
+            )));
+        }
+        Ok(())
+    }
+}
+
+// One depth layer per shadow map, shared by the cascades of a directional light and the faces of
+// a point light's cube. Rendered through the layer views and sampled through view, a D2Array or
+// Cube view of all layers.
+pub struct ShadowTextureArray {
+    pub texture: wgpu::Texture,
+    pub view: TextureView,
+    pub layer_views: Vec<TextureView>,
+}
+
+impl ShadowTextureArray {
+    pub fn new(context: &GpuContext, resolution: u32, layer_count: u32, view_dimension: wgpu::TextureViewDimension, label: &str) -> Self {
+        let texture = context.device.create_texture(&wgpu::TextureDescriptor {
+            label: Some(label),
+            size: wgpu::Extent3d {
+                width: resolution,
+                height: resolution,
+                depth_or_array_layers: layer_count,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: DEPTH_FORMAT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        });
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            label: Some(label),
+            dimension: Some(view_dimension),
+            ..Default::default()
+        });
+
+        let layer_views = (0..layer_count)
+            .map(|layer| {
+                texture.create_view(&wgpu::TextureViewDescriptor {
+                    label: Some(label),
+                    dimension: Some(wgpu::TextureViewDimension::D2),
+                    base_array_layer: layer,
+                    array_layer_count: Some(1),
+                    ..Default::default()
+                })
+            })
+            .collect();
+
+        ShadowTextureArray {
+            texture,
+            view,
+            layer_views,
+        }
+    }
+
+    pub fn layer_count(&self) -> u32 {
+        self.layer_views.len() as u32
+    }
+
+    // Clears and stores the layer, for the shadow pass rendering it
+    pub fn get_depth_attachment(&self, layer: u32) -> wgpu::RenderPassDepthStencilAttachment<'_> {
+        wgpu::RenderPassDepthStencilAttachment {
+            view: &self.layer_views[layer as usize],
+            depth_ops: Some(wgpu::Operations {
+                load: wgpu::LoadOp::Clear(1.0),
+                store: wgpu::StoreOp::Store,
+            }),
+            stencil_ops: None,
+        }
+    }
+}
+
+// A cascade of the last update, covering the camera frustum between the view distances
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cascade {
+    pub projection_view: Mat4,
+    pub near: f32,
+    pub far: f32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Pod, Zeroable)]
+pub struct CascadeUniform {
+    pub projection_views: [[[f32; 4]; 4]; MAX_SHADOW_CASCADES],
+    // far distance of each cascade in x, unused cascades are 0. Uniform arrays have a 16 byte
+    // stride, so each split takes a vec4.
+    pub splits: [[f32; 4]; MAX_SHADOW_CASCADES],
+    pub cascade_count: u32,
+    pub _padding: [u32; 3],
+}
+
+impl CascadeUniform {
+    pub fn new(cascades: &[Cascade]) -> Self {
+        let mut uniform = CascadeUniform::zeroed();
+        for (i, cascade) in cascades.iter().take(MAX_SHADOW_CASCADES).enumerate() {
+            uniform.projection_views[i] = cascade.projection_view.to_cols_array_2d();
+            uniform.splits[i][0] = cascade.far;
+        }
+        uniform.cascade_count = cascades.len().min(MAX_SHADOW_CASCADES) as u32;
+        uniform
+    }
+}
+
+// Shadow maps of a directional light split over the camera frustum. Render each cascade into
+// shadow_maps.layer_views[i] with cascades[i].projection_view, then bind the group to sample them.
+pub struct CascadedShadowMap {
+    pub settings: CascadeSettings,
+    pub shadow_maps: ShadowTextureArray,
+    pub cascades: Vec<Cascade>,
+    pub uniform: UniformBuffer<CascadeUniform>,
+    pub sampler: wgpu::Sampler,
+    pub bind_group_layout: BindGroupLayout,
+    pub bind_group: BindGroup,
+}
+
+impl CascadedShadowMap {
+    pub fn new(context: &GpuContext, settings: CascadeSettings) -> Result<Self, Error> {
+        settings.check(&context.device.limits())?;
+
+        let shadow_maps = ShadowTextureArray::new(
+            context,
+            settings.resolution,
+            settings.cascade_count,
+            wgpu::TextureViewDimension::D2Array,
+            "shadow cascades",
+        );
+        let uniform = UniformBuffer::new(context, &CascadeUniform::zeroed(), wgpu::BufferUsages::empty());
+
+        let sampler = SamplerBuilder::new()
+            .label("shadow cascade sampler")
+            .comparison(wgpu::CompareFunction::LessEqual)
+            .build(context);
+
+        let bind_group_layout = get_cascade_layout_builder().build(context, "shadow cascades bind group layout")?;
+
+        let bind_group = BindGroupBuilder::new()
+            .texture_view(&shadow_maps.view)
+            .sampler(&sampler)
+            .resource(uniform.as_entire_binding())
+            .build(context, &bind_group_layout, "shadow cascades bind group");
+
+        Ok(CascadedShadowMap {
+            settings,
+            shadow_maps,
+            cascades: vec![],
+            uniform,
+            sampler,
+            bind_group_layout,
+            bind_group,
+        })
+    }
+
+    // Refits the cascades to the camera and uploads their matrices and splits
+    pub fn update(&mut self, context: &GpuContext, camera: &CascadeCamera, to_light: Vec3) {
+        self.cascades = get_cascades(camera, to_light, &self.settings);
+        self.uniform.update(context, &CascadeUniform::new(&self.cascades));
+    }
+
+    // View distance where each cascade ends, nearest first
+    pub fn get_split_depths(&self) -> Vec<f32> {
+        self.cascades.iter().map(|cascade| cascade.far).collect()
+    }
+}
+
+pub fn get_cascades(camera: &CascadeCamera, to_light: Vec3, settings: &CascadeSettings) -> Vec<Cascade> {
+    camera
+        .get_cascade_ranges(settings.cascade_count, settings.lambda, settings.max_distance)
+        .into_iter()
+        .map(|range| {
+            let slice = camera.get_slice_projection_view(range.start, range.end);
+            Cascade {
+                projection_view: get_cascade_projection_view(&slice, to_light, settings.resolution),
+                near: range.start,
+                far: range.end,
+            }
+        })
+        .collect()
+}
+
+// texture_depth_2d_array, sampler_comparison and the ShadowCascades uniform
+pub fn get_cascade_layout_builder() -> LayoutBuilder {
+    let visibility = wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT;
+    LayoutBuilder::new()
+        .texture(
+            wgpu::ShaderStages::FRAGMENT,
+            wgpu::TextureSampleType::Depth,
+            wgpu::TextureViewDimension::D2Array,
+        )
+        .sampler_comparison(wgpu::ShaderStages::FRAGMENT)
+        .sized_uniform(visibility, std::mem::size_of::<CascadeUniform>() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+
+    use glam::{vec3, Mat4, Vec3};
+
+    #[cfg(feature = "reflection")]
+    use crate::shader::check_wgsl;
+    #[cfg(feature = "reflection")]
+    use crate::shadow_cascades::get_shadow_cascades_wgsl;
+    use crate::shadow_cascades::{get_cascades, CascadeCamera, CascadeSettings, CascadeUniform, MAX_SHADOW_CASCADES};
+    use crate::shadow_projection::get_frustum_corners;
+
+    fn get_test_camera() -> CascadeCamera {
+        CascadeCamera {
+            view: Mat4::look_at_rh(vec3(3.0, -20.0, 6.0), Vec3::ZERO, Vec3::Z),
+            fov: std::f32::consts::FRAC_PI_4,
+            aspect_ratio: 1.5,
+            near: 1.0,
+            far: 200.0,
+        }
+    }
+
+    #[test]
+    fn test_cascade_ranges() {
+        let camera = get_test_camera();
+        let ranges = camera.get_cascade_ranges(3, 0.5, 60.0);
+
+        // contiguous from the near plane to the shadow distance
+        assert_eq!(ranges.len(), 3);
+        assert_eq!(ranges[0].start, 1.0);
+        assert!(ranges.windows(2).all(|pair| pair[0].end == pair[1].start));
+        assert!((ranges[2].end - 60.0).abs() < 1e-3);
+
+        // a camera with a closer far plane isn't extended
+        let close = CascadeCamera { far: 30.0, ..camera };
+        assert!((close.get_cascade_ranges(3, 0.5, 60.0)[2].end - 30.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_cascades_cover_slices() {
+        let camera = get_test_camera();
+        let settings = CascadeSettings {
+            cascade_count: 3,
+            resolution: 1024,
+            ..Default::default()
+        };
+        let cascades = get_cascades(&camera, vec3(1.0, -1.0, 3.0), &settings);
+        assert_eq!(cascades.len(), 3);
+
+        for cascade in &cascades {
+            let slice = camera.get_slice_projection_view(cascade.near, cascade.far);
+            for corner in get_frustum_corners(&slice) {
+                let ndc = cascade.projection_view.project_point3(corner);
+                // snapping moves the slice by up to a texel
+                let margin = 1.0 + 2.0 / settings.resolution as f32;
+                assert!(ndc.x.abs() <= margin && ndc.y.abs() <= margin, "{} is outside", corner);
+                assert!(ndc.z >= 0.0 && ndc.z <= 1.0);
+            }
+        }
+
+        let uniform = CascadeUniform::new(&cascades);
+        assert_eq!(uniform.cascade_count, 3);
+        for (split, cascade) in uniform.splits.iter().zip(&cascades) {
+            assert_eq!(split[0], cascade.far);
+        }
+        assert_eq!(uniform.splits[3], [0.0; 4]);
+    }
+
+    #[test]
+    fn test_cascade_settings() {
+        let limits = wgpu::Limits::downlevel_webgl2_defaults();
+        assert!(CascadeSettings::default().check(&limits).is_ok());
+
+        let too_many = CascadeSettings {
+            cascade_count: MAX_SHADOW_CASCADES as u32 + 1,
+            ..Default::default()
+        };
+        assert!(too_many.check(&limits).is_err());
+
+        let too_large = CascadeSettings {
+            resolution: limits.max_texture_dimension_2d * 2,
+            ..Default::default()
+        };
+        assert!(too_large.check(&limits).is_err());
+    }
+
+    #[test]
+    fn test_cascade_uniform_layout() {
+        // matches ShadowCascades in shadow_cascades.wgsl
+        assert_eq!(mem::offset_of!(CascadeUniform, splits), 256);
+        assert_eq!(mem::offset_of!(CascadeUniform, cascade_count), 320);
+        assert_eq!(mem::size_of::<CascadeUniform>(), 336);
+    }
+
+    #[test]
+    #[cfg(feature = "reflection")]
+    fn test_cascades_wgsl() {
+        let source = get_shadow_cascades_wgsl();
+        assert!(source.contains(&format!("const MAX_SHADOW_CASCADES: u32 = {}u;", MAX_SHADOW_CASCADES)));
+
+        let entry = r"
+@group(0) @binding(0) var<uniform> shadow_cascades: ShadowCascades;
+
+@fragment fn fs_main(@builtin(position) position: vec4<f32>) -> @location(0) vec4<f32> {
+    let cascade = select_cascade(shadow_cascades, position.z);
+    return shadow_cascades.projection_views[cascade] * vec4<f32>(position.xyz, 1.0);
+}
+";
+        check_wgsl(&format!("{}\n{}", source, entry)).unwrap();
+    }
+}
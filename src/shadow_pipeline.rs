@@ -0,0 +1,198 @@
+use wgpu::{BindGroupLayout, RenderPipeline, ShaderModule};
+
+use crate::error::Error;
+use crate::gpu_context::GpuContext;
+use crate::pipeline_builder::get_unclipped_primitive_state;
+#[cfg(feature = "reflection")]
+use crate::shader_bindings::check_shader_bindings;
+
+pub const DEFAULT_SHADOW_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
+
+pub const DEFAULT_SHADOW_DEPTH_BIAS: wgpu::DepthBiasState = wgpu::DepthBiasState {
+    constant: 2, // corresponds to bilinear filtering
+    slope_scale: 2.0,
+    clamp: 0.0,
+};
+
+// Builds depth only pipelines for rendering shadow casters. The fragment entry is
+// only needed for alpha tested casters that discard, it has no color targets.
+#[derive(Debug, Clone)]
+pub struct ShadowPipelineBuilder<'a> {
+    pub label: &'a str,
+    pub vertex_entry: &'a str,
+    pub alpha_test_entry: Option<&'a str>,
+    pub vertex_buffers: Vec<wgpu::VertexBufferLayout<'a>>,
+    pub bind_group_layouts: Vec<&'a BindGroupLayout>,
+    pub depth_format: wgpu::TextureFormat,
+    pub depth_bias: wgpu::DepthBiasState,
+    pub depth_compare: wgpu::CompareFunction,
+    // casters in front of the light's near plane still write depth, clamped to 0, when the
+    // device supports DEPTH_CLIP_CONTROL
+    pub unclipped_depth: bool,
+    pub primitive: wgpu::PrimitiveState,
+}
+
+impl<'a> ShadowPipelineBuilder<'a> {
+    pub fn new(vertex_entry: &'a str) -> Self {
+        ShadowPipelineBuilder {
+            label: "shadow pipeline",
+            vertex_entry,
+            alpha_test_entry: None,
+            vertex_buffers: vec![],
+            bind_group_layouts: vec![],
+            depth_format: DEFAULT_SHADOW_FORMAT,
+            depth_bias: DEFAULT_SHADOW_DEPTH_BIAS,
+            depth_compare: wgpu::CompareFunction::LessEqual,
+            unclipped_depth: true,
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: Some(wgpu::Face::Back),
+                ..Default::default()
+            },
+        }
+    }
+
+    pub fn label(mut self, label: &'a str) -> Self {
+        self.label = label;
+        self
+    }
+
+    pub fn alpha_test(mut self, fragment_entry: &'a str) -> Self {
+        self.alpha_test_entry = Some(fragment_entry);
+        self
+    }
+
+    pub fn vertex_buffer(mut self, layout: wgpu::VertexBufferLayout<'a>) -> Self {
+        self.vertex_buffers.push(layout);
+        self
+    }
+
+    pub fn bind_group_layout(mut self, layout: &'a BindGroupLayout) -> Self {
+        self.bind_group_layouts.push(layout);
+        self
+    }
+
+    pub fn depth_format(mut self, format: wgpu::TextureFormat) -> Self {
+        self.depth_format = format;
+        self
+    }
+
+    pub fn depth_bias(mut self, bias: wgpu::DepthBiasState) -> Self {
+        self.depth_bias = bias;
+        self
+    }
+
+    pub fn depth_compare(mut self, compare: wgpu::CompareFunction) -> Self {
+        self.depth_compare = compare;
+        self
+    }
+
+    pub fn primitive(mut self, primitive: wgpu::PrimitiveState) -> Self {
+        self.primitive = primitive;
+        self
+    }
+
+    pub fn cull_mode(mut self, cull_mode: Option<wgpu::Face>) -> Self {
+        self.primitive.cull_mode = cull_mode;
+        self
+    }
+
+    pub fn unclipped_depth(mut self, enabled: bool) -> Self {
+        self.unclipped_depth = enabled;
+        self
+    }
+
+    pub fn get_primitive_state(&self, features: wgpu::Features) -> wgpu::PrimitiveState {
+        get_unclipped_primitive_state(self.primitive, self.unclipped_depth, features)
+    }
+
+    pub fn get_depth_stencil_state(&self) -> wgpu::DepthStencilState {
+        wgpu::DepthStencilState {
+            format: self.depth_format,
+            depth_write_enabled: true,
+            depth_compare: self.depth_compare,
+            stencil: wgpu::StencilState::default(),
+            bias: self.depth_bias,
+        }
+    }
+
+    // shadow pipelines never write color
+    pub fn get_color_targets(&self) -> &'static [Option<wgpu::ColorTargetState>] {
+        &[]
+    }
+
+    // Checks the layout entries, in group order, against the bindings the shadow entry points use
+    #[cfg(feature = "reflection")]
+    pub fn check_bindings(&self, source: &str, layout_entries: &[&[wgpu::BindGroupLayoutEntry]]) -> Result<(), Error> {
+        check_shader_bindings(source, &self.get_entry_points(), layout_entries)
+    }
+
+    fn get_entry_points(&self) -> Vec<&str> {
+        [Some(self.vertex_entry), self.alpha_test_entry].into_iter().flatten().collect()
+    }
+
+    // A ValidationError when the bindings don't match the layouts, see BindingRegistry
+    pub fn build(&self, context: &GpuContext, shader: &ShaderModule) -> Result<RenderPipeline, Error> {
+        context
+            .binding_registry
+            .check_pipeline(self.label, shader, &self.get_entry_points(), &self.bind_group_layouts)?;
+
+        let pipeline_layout = context.device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some(self.label),
+            bind_group_layouts: &self.bind_group_layouts,
+            push_constant_ranges: &[],
+        });
+
+        Ok(context.device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some(self.label),
+            layout: Some(&pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: shader,
+                entry_point: self.vertex_entry,
+                buffers: &self.vertex_buffers,
+            },
+            fragment: self.alpha_test_entry.map(|entry_point| wgpu::FragmentState {
+                module: shader,
+                entry_point,
+                targets: self.get_color_targets(),
+            }),
+            primitive: self.get_primitive_state(context.device.features()),
+            depth_stencil: Some(self.get_depth_stencil_state()),
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::shadow_pipeline::{ShadowPipelineBuilder, DEFAULT_SHADOW_FORMAT};
+
+    #[test]
+    fn test_shadow_pipeline_builder() {
+        let builder = ShadowPipelineBuilder::new("vs_shadow");
+        assert!(builder.get_color_targets().is_empty());
+        assert_eq!(builder.get_depth_stencil_state().format, DEFAULT_SHADOW_FORMAT);
+        assert!(builder.alpha_test_entry.is_none());
+
+        let builder = builder
+            .alpha_test("fs_alpha_test")
+            .depth_format(wgpu::TextureFormat::Depth16Unorm)
+            .cull_mode(None);
+        assert!(builder.get_color_targets().is_empty());
+        assert_eq!(builder.get_depth_stencil_state().format, wgpu::TextureFormat::Depth16Unorm);
+        assert_eq!(builder.alpha_test_entry, Some("fs_alpha_test"));
+        assert_eq!(builder.primitive.cull_mode, None);
+    }
+
+    #[test]
+    fn test_shadow_unclipped_depth() {
+        let builder = ShadowPipelineBuilder::new("vs_shadow");
+        assert!(builder.get_primitive_state(wgpu::Features::DEPTH_CLIP_CONTROL).unclipped_depth);
+        assert!(!builder.get_primitive_state(wgpu::Features::empty()).unclipped_depth);
+
+        let builder = builder.unclipped_depth(false);
+        assert!(!builder.get_primitive_state(wgpu::Features::DEPTH_CLIP_CONTROL).unclipped_depth);
+    }
+}
@@ -0,0 +1,195 @@
+use glam::{vec2, vec3, Mat4, Vec2, Vec3};
+
+// Orthographic shadow projection extents in light view space. Looking down -z, so near and far
+// are distances in front of the light.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OrthoBounds {
+    pub left: f32,
+    pub right: f32,
+    pub bottom: f32,
+    pub top: f32,
+    pub near: f32,
+    pub far: f32,
+}
+
+impl OrthoBounds {
+    // tightest bounds around the points, e.g. the corners of a camera frustum slice
+    pub fn fit_to_points(light_view: &Mat4, points: impl Iterator<Item = Vec3>) -> Self {
+        let mut min = Vec3::splat(f32::MAX);
+        let mut max = Vec3::splat(f32::MIN);
+        for point in points {
+            let light_point = light_view.transform_point3(point);
+            min = min.min(light_point);
+            max = max.max(light_point);
+        }
+        OrthoBounds {
+            left: min.x,
+            right: max.x,
+            bottom: min.y,
+            top: max.y,
+            near: -max.z,
+            far: -min.z,
+        }
+    }
+
+    pub fn to_projection(&self) -> Mat4 {
+        Mat4::orthographic_rh(self.left, self.right, self.bottom, self.top, self.near, self.far)
+    }
+
+    // size of one shadow map texel in light view space
+    pub fn get_texel_size(&self, resolution: u32) -> Vec2 {
+        vec2(self.right - self.left, self.top - self.bottom) / resolution as f32
+    }
+}
+
+// Moves the light view by less than a texel so its translation is a whole number of texels.
+// Static geometry then always rasterizes to the same texels as the fitted bounds follow the
+// camera, which stops shadow edges shimmering. The bounds extents should stay constant for
+// this to hold, e.g. by fitting to a bounding sphere.
+pub fn snap_light_view_to_texels(light_view: &Mat4, bounds: &OrthoBounds, resolution: u32) -> Mat4 {
+    let texel_size = bounds.get_texel_size(resolution);
+    let translation = light_view.w_axis.truncate().truncate();
+
+    let snapped = (translation / texel_size).round() * texel_size;
+    let offset = snapped - translation;
+
+    Mat4::from_translation(offset.extend(0.0)) * *light_view
+}
+
+// View distance where each cascade ends, nearest first. Blends logarithmic splits, which keep the
+// texel density even over distance, with uniform splits by lambda.
+pub fn get_cascade_splits(near: f32, far: f32, cascade_count: u32, lambda: f32) -> Vec<f32> {
+    (1..=cascade_count)
+        .map(|i| {
+            let t = i as f32 / cascade_count as f32;
+            let logarithmic = near * (far / near).powf(t);
+            let uniform = near + (far - near) * t;
+            lambda * logarithmic + (1.0 - lambda) * uniform
+        })
+        .collect()
+}
+
+// World space corners of the frustum of projection_view, the near plane first
+pub fn get_frustum_corners(projection_view: &Mat4) -> [Vec3; 8] {
+    let inverse = projection_view.inverse();
+    let mut corners = [Vec3::ZERO; 8];
+    for (i, corner) in corners.iter_mut().enumerate() {
+        let x = if i & 1 == 0 { -1.0 } else { 1.0 };
+        let y = if i & 2 == 0 { -1.0 } else { 1.0 };
+        let z = if i & 4 == 0 { 0.0 } else { 1.0 };
+        *corner = inverse.project_point3(vec3(x, y, z));
+    }
+    corners
+}
+
+// Orthographic projection_view of a directional light covering a slice of the camera frustum.
+// Fitted to the bounding sphere of the slice so the extents stay constant as the camera turns,
+// then snapped to texels. The depth range reaches one radius past the sphere towards the light
+// for casters outside the slice.
+pub fn get_cascade_projection_view(slice_projection_view: &Mat4, to_light: Vec3, resolution: u32) -> Mat4 {
+    let corners = get_frustum_corners(slice_projection_view);
+    let center = corners.iter().sum::<Vec3>() / 8.0;
+    let radius = corners.iter().map(|corner| corner.distance(center)).fold(0.0, f32::max);
+    // rounded up so float noise in the corners doesn't change the texel size
+    let radius = (radius * 16.0).ceil() / 16.0;
+
+    let to_light = to_light.normalize();
+    let up = if to_light.dot(Vec3::Z).abs() > 0.99 { Vec3::Y } else { Vec3::Z };
+    let light_view = Mat4::look_at_rh(center + to_light * 2.0 * radius, center, up);
+
+    let bounds = OrthoBounds {
+        left: -radius,
+        right: radius,
+        bottom: -radius,
+        top: radius,
+        near: 0.0,
+        far: 3.0 * radius,
+    };
+    bounds.to_projection() * snap_light_view_to_texels(&light_view, &bounds, resolution)
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{vec3, Mat4, Vec3};
+
+    use crate::shadow_projection::{
+        get_cascade_projection_view, get_cascade_splits, get_frustum_corners, snap_light_view_to_texels, OrthoBounds,
+    };
+
+    #[test]
+    fn test_snap_light_view_to_texels() {
+        let resolution = 2048;
+        let bounds = OrthoBounds {
+            left: -20.0,
+            right: 20.0,
+            bottom: -15.0,
+            top: 15.0,
+            near: 0.1,
+            far: 100.0,
+        };
+        let texel_size = bounds.get_texel_size(resolution);
+
+        // the light follows a slowly moving camera
+        for i in 0..20 {
+            let target = vec3(i as f32 * 0.0137, 0.5, i as f32 * -0.021);
+            let light_view = Mat4::look_at_rh(target + vec3(-10.0, 30.0, 10.0), target, Vec3::Y);
+
+            let snapped = snap_light_view_to_texels(&light_view, &bounds, resolution);
+
+            let texels = snapped.w_axis.truncate().truncate() / texel_size;
+            assert!((texels - texels.round()).abs().max_element() < 1e-2);
+
+            // only translated, by under a texel
+            assert!(snapped.x_axis.abs_diff_eq(light_view.x_axis, 1e-6));
+            let offset = (snapped.w_axis - light_view.w_axis).truncate();
+            assert!(offset.x.abs() <= texel_size.x * 0.5 + 1e-5);
+            assert!(offset.y.abs() <= texel_size.y * 0.5 + 1e-5);
+            assert_eq!(offset.z, 0.0);
+        }
+    }
+
+    #[test]
+    fn test_fit_to_points() {
+        let light_view = Mat4::look_at_rh(vec3(0.0, 0.0, 10.0), Vec3::ZERO, Vec3::Y);
+        let points = [vec3(-1.0, -2.0, 1.0), vec3(3.0, 4.0, -1.0)];
+        let bounds = OrthoBounds::fit_to_points(&light_view, points.into_iter());
+
+        assert_eq!((bounds.left, bounds.right, bounds.bottom, bounds.top), (-1.0, 3.0, -2.0, 4.0));
+        assert_eq!((bounds.near, bounds.far), (9.0, 11.0));
+    }
+
+    #[test]
+    fn test_cascade_splits() {
+        let splits = get_cascade_splits(1.0, 100.0, 4, 0.5);
+        assert_eq!(splits.len(), 4);
+        assert!(splits.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!((splits[3] - 100.0).abs() < 1e-3);
+
+        // logarithmic splits put more cascades near the camera than uniform ones
+        assert_eq!(get_cascade_splits(1.0, 101.0, 2, 0.0), vec![51.0, 101.0]);
+        assert!((get_cascade_splits(1.0, 100.0, 2, 1.0)[0] - 10.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_cascade_projection_view() {
+        let view = Mat4::look_at_rh(vec3(3.0, -20.0, 6.0), Vec3::ZERO, Vec3::Z);
+        let slice = Mat4::perspective_rh(std::f32::consts::FRAC_PI_4, 1.5, 1.0, 15.0) * view;
+        let to_light = vec3(1.0, -1.0, 3.0);
+
+        let projection_view = get_cascade_projection_view(&slice, to_light, 1024);
+
+        // the whole slice is inside the shadow map
+        for corner in get_frustum_corners(&slice) {
+            let ndc = projection_view.project_point3(corner);
+            // give or take the half texel of snapping
+            let margin = 1.0 + 1.0 / 1024.0;
+            assert!(ndc.x.abs() <= margin && ndc.y.abs() <= margin, "{} is outside", corner);
+            assert!(ndc.z >= 0.0 && ndc.z <= 1.0, "{} is outside the depth range", corner);
+        }
+
+        // depth increases away from the light
+        let near = projection_view.project_point3(to_light.normalize());
+        let far = projection_view.project_point3(-to_light.normalize());
+        assert!(near.z < far.z);
+    }
+}
@@ -0,0 +1,206 @@
+use std::rc::Rc;
+
+use bytemuck::{Pod, Zeroable};
+use glam::{Mat3, Mat4};
+use wgpu::{BindGroup, BindGroupLayout, RenderPipeline};
+
+use crate::bind_group::{BindGroupBuilder, LayoutBuilder};
+use crate::buffers::UniformBuffer;
+use crate::error::Error;
+use crate::gpu_context::GpuContext;
+use crate::pipeline_builder::PipelineBuilder;
+use crate::texture::Cubemap;
+
+pub const SKYBOX_WGSL: &str = include_str!("shaders/skybox.wgsl");
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Pod, Zeroable)]
+pub struct SkyboxUniform {
+    pub inverse_projection: [[f32; 4]; 4],
+    // without the view's translation, the sky doesn't move with the camera
+    pub inverse_view_rotation: [[f32; 4]; 4],
+}
+
+impl SkyboxUniform {
+    pub fn new(projection: &Mat4, view: &Mat4) -> Self {
+        SkyboxUniform {
+            inverse_projection: projection.inverse().to_cols_array_2d(),
+            inverse_view_rotation: Mat4::from_mat3(Mat3::from_mat4(*view).inverse()).to_cols_array_2d(),
+        }
+    }
+}
+
+// Draws a cubemap behind the scene from a fullscreen triangle at the far plane. Draw it first in the
+// opaque pass after clearing depth to 1.0, it doesn't write depth so the geometry drawn after covers it.
+pub struct Skybox {
+    pub cubemap: Cubemap,
+    pipeline: Rc<RenderPipeline>,
+    bind_group_layout: BindGroupLayout,
+    bind_group: BindGroup,
+    uniform: UniformBuffer<SkyboxUniform>,
+}
+
+impl Skybox {
+    // depth_format is None for passes without a depth attachment
+    pub fn new(
+        context: &GpuContext,
+        cubemap: Cubemap,
+        color_format: wgpu::TextureFormat,
+        depth_format: Option<wgpu::TextureFormat>,
+    ) -> Result<Self, Error> {
+        let bind_group_layout = get_skybox_layout_builder().build(context, "skybox bind group layout")?;
+
+        let mut builder = PipelineBuilder::new("vs_skybox", "fs_skybox")
+            .label("skybox pipeline")
+            .bind_group_layout(&bind_group_layout)
+            .color_target(color_format)
+            .depth_compare(wgpu::CompareFunction::LessEqual)
+            .depth_write(false)
+            .primitive(wgpu::PrimitiveState::default())
+            .surface_sample_count();
+        if let Some(format) = depth_format {
+            builder = builder.depth(format);
+        }
+
+        let shader = context.create_shader_module("skybox shader", SKYBOX_WGSL);
+        let pipeline = context.get_or_build_pipeline(&builder, &shader)?;
+
+        let uniform = UniformBuffer::new(
+            context,
+            &SkyboxUniform::new(&Mat4::IDENTITY, &Mat4::IDENTITY),
+            wgpu::BufferUsages::empty(),
+        );
+        let bind_group = create_skybox_bind_group(context, &bind_group_layout, &cubemap, &uniform);
+
+        Ok(Skybox {
+            cubemap,
+            pipeline,
+            bind_group_layout,
+            bind_group,
+            uniform,
+        })
+    }
+
+    pub fn set_cubemap(&mut self, context: &GpuContext, cubemap: Cubemap) {
+        self.cubemap = cubemap;
+        self.bind_group = create_skybox_bind_group(context, &self.bind_group_layout, &self.cubemap, &self.uniform);
+    }
+
+    // The projection and view written to the camera buffer, finite or infinite far perspectives
+    pub fn update(&self, context: &GpuContext, projection: &Mat4, view: &Mat4) {
+        self.uniform.update(context, &SkyboxUniform::new(projection, view));
+    }
+
+    pub fn draw<'a>(&'a self, pass: &mut wgpu::RenderPass<'a>) {
+        pass.set_pipeline(&self.pipeline);
+        pass.set_bind_group(0, &self.bind_group, &[]);
+        pass.draw(0..3, 0..1);
+    }
+}
+
+pub fn get_skybox_layout_builder() -> LayoutBuilder {
+    LayoutBuilder::new()
+        .texture(
+            wgpu::ShaderStages::FRAGMENT,
+            wgpu::TextureSampleType::Float { filterable: true },
+            wgpu::TextureViewDimension::Cube,
+        )
+        .sampler(wgpu::ShaderStages::FRAGMENT)
+        .sized_uniform(wgpu::ShaderStages::FRAGMENT, std::mem::size_of::<SkyboxUniform>() as u64)
+}
+
+fn create_skybox_bind_group(
+    context: &GpuContext,
+    layout: &BindGroupLayout,
+    cubemap: &Cubemap,
+    uniform: &UniformBuffer<SkyboxUniform>,
+) -> BindGroup {
+    BindGroupBuilder::new()
+        .texture_view(&cubemap.view)
+        .sampler(&cubemap.sampler)
+        .resource(uniform.as_entire_binding())
+        .build(context, layout, "skybox bind group")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::iter;
+
+    use glam::{vec3, Mat4, Vec3};
+
+    use crate::camera::projection::get_perspective_matrix;
+    use crate::gpu_context::GpuContext;
+    #[cfg(feature = "reflection")]
+    use crate::pipeline_builder::PipelineBuilder;
+    use crate::render::RenderPassBuilder;
+    use crate::skybox::Skybox;
+    #[cfg(feature = "reflection")]
+    use crate::skybox::{get_skybox_layout_builder, SKYBOX_WGSL};
+    use crate::snapshot::read_texture_rgba;
+    use crate::texture::{create_cubemap, COLOR_TEXTURE_FORMAT};
+
+    // +x, -x, +y, -y, +z, -z
+    const FACE_COLORS: [[u8; 4]; 6] = [
+        [255, 0, 0, 255],
+        [0, 255, 0, 255],
+        [0, 0, 255, 255],
+        [255, 255, 0, 255],
+        [255, 0, 255, 255],
+        [0, 255, 255, 255],
+    ];
+
+    #[test]
+    #[cfg(feature = "reflection")]
+    fn test_skybox_bindings() {
+        let layout = get_skybox_layout_builder();
+        let builder = PipelineBuilder::new("vs_skybox", "fs_skybox");
+        builder.check_bindings(SKYBOX_WGSL, &[&layout.entries]).unwrap();
+    }
+
+    fn render_sky(context: &GpuContext, skybox: &Skybox, projection: &Mat4, view: &Mat4) -> Vec<u8> {
+        skybox.update(context, projection, view);
+
+        let frame = context.acquire_frame().unwrap();
+        let frame_view = frame.texture().create_view(&wgpu::TextureViewDescriptor {
+            format: Some(context.surface_view_format()),
+            ..Default::default()
+        });
+        let mut encoder = context
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        {
+            let mut pass = RenderPassBuilder::new()
+                .color(&frame_view, Some(wgpu::Color::BLACK))
+                .begin(&mut encoder);
+            skybox.draw(&mut pass);
+        }
+        context.queue.submit(iter::once(encoder.finish()));
+        read_texture_rgba(context, frame.texture(), 4, 4).unwrap()
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "gpu_tests"), ignore = "needs a gpu adapter")]
+    fn test_skybox_render() {
+        let context = pollster::block_on(GpuContext::new_headless(4, 4)).unwrap();
+        let faces = FACE_COLORS.map(|color| image::RgbaImage::from_pixel(2, 2, image::Rgba(color)));
+        let cubemap = create_cubemap(&context, &faces, COLOR_TEXTURE_FORMAT, "test sky").unwrap();
+        let skybox = Skybox::new(&context, cubemap, context.surface_view_format(), None).unwrap();
+
+        // the whole frame shows the face the camera looks at, wherever the camera is
+        let position = vec3(5.0, -3.0, 2.0);
+        let finite = get_perspective_matrix(1.0, 1.0, 0.1, 100.0);
+        let infinite = get_perspective_matrix(1.0, 1.0, 0.1, f32::INFINITY);
+        let views = [
+            (Vec3::X, Vec3::Z, FACE_COLORS[0]),
+            (Vec3::NEG_Y, Vec3::Z, FACE_COLORS[3]),
+            (Vec3::NEG_Z, Vec3::Y, FACE_COLORS[5]),
+        ];
+        for projection in [finite, infinite] {
+            for (forward, up, color) in views {
+                let view = Mat4::look_at_rh(position, position + forward, up);
+                let pixels = render_sky(&context, &skybox, &projection, &view);
+                assert_eq!(pixels, color.repeat(16), "looking along {}", forward);
+            }
+        }
+    }
+}
@@ -0,0 +1,460 @@
+use std::path::Path;
+
+use image::RgbaImage;
+use wgpu::util::align_to;
+
+use crate::error::Error;
+use crate::error::Error::{ImageError, TextureError, UnsupportedFormatError};
+use crate::gpu_context::GpuContext;
+use crate::texture::get_mip_size;
+
+// Result of comparing a captured frame against a reference image
+#[derive(Debug)]
+pub struct ImageDiff {
+    pub max_difference: u8,
+    pub mean_difference: f32,
+    // number of pixels with a channel differing by more than the tolerance
+    pub pixels_over_tolerance: usize,
+    pub diff_image: Option<RgbaImage>,
+}
+
+impl ImageDiff {
+    pub fn is_match(&self) -> bool {
+        self.pixels_over_tolerance == 0
+    }
+}
+
+// Copies an Rgba8 or Bgra8 texture back to the cpu. The texture needs COPY_SRC usage.
+pub fn capture_texture(context: &GpuContext, texture: &wgpu::Texture) -> Result<RgbaImage, Error> {
+    capture_texture_mip(context, texture, 0)
+}
+
+// Copies one mip level, e.g. to check generated mipmaps
+pub fn capture_texture_mip(context: &GpuContext, texture: &wgpu::Texture, mip_level: u32) -> Result<RgbaImage, Error> {
+    if mip_level >= texture.mip_level_count() {
+        return Err(TextureError(format!(
+            "mip level {} of a texture with {} levels",
+            mip_level,
+            texture.mip_level_count()
+        )));
+    }
+
+    let (width, height) = get_mip_size(texture.width(), texture.height(), mip_level);
+    let pixels = read_mip_region_rgba(context, texture, mip_level, 0, width, height)?;
+
+    RgbaImage::from_raw(width, height, pixels).ok_or(TextureError("capture buffer size mismatch".to_string()))
+}
+
+// Reads the top left width x height region of an Rgba8 or Bgra8 texture as tightly packed rgba rows.
+// Waits for the gpu, so it is meant for tests and screenshots rather than every frame.
+pub fn read_texture_rgba(context: &GpuContext, texture: &wgpu::Texture, width: u32, height: u32) -> Result<Vec<u8>, Error> {
+    read_mip_region_rgba(context, texture, 0, 0, width, height)
+}
+
+// Reads a region of any uncompressed color or depth format as tightly packed texels in the
+// texture's own layout, e.g. u32 ids of an R32Uint target or f32 depths
+pub fn read_texture_region(
+    context: &GpuContext,
+    texture: &wgpu::Texture,
+    origin: [u32; 2],
+    width: u32,
+    height: u32,
+) -> Result<Vec<u8>, Error> {
+    let bytes_per_texel = texture
+        .format()
+        .block_size(Some(wgpu::TextureAspect::All))
+        .ok_or(TextureError(format!("read of format {:?} not supported", texture.format())))?;
+    if texture.format().block_dimensions() != (1, 1) {
+        return Err(TextureError(format!(
+            "read of compressed format {:?} not supported",
+            texture.format()
+        )));
+    }
+    read_mip_region(context, texture, 0, [origin[0], origin[1], 0], width, height, bytes_per_texel)
+}
+
+// Like read_texture_rgba for one slice of a 3d texture or one layer of an array texture
+pub fn read_texture_slice_rgba(
+    context: &GpuContext,
+    texture: &wgpu::Texture,
+    slice: u32,
+    width: u32,
+    height: u32,
+) -> Result<Vec<u8>, Error> {
+    if slice >= texture.depth_or_array_layers() {
+        return Err(TextureError(format!(
+            "read of slice {} of a texture with {}",
+            slice,
+            texture.depth_or_array_layers()
+        )));
+    }
+    read_mip_region_rgba(context, texture, 0, slice, width, height)
+}
+
+// Saves the top left width x height region of an Rgba8 or Bgra8 texture as a png, e.g. the
+// swapchain texture after rendering and before present, or a headless offscreen texture. wgpu copies
+// rows top to bottom on every backend, so the image is upright without flipping.
+pub fn save_texture_png(
+    context: &GpuContext,
+    texture: &wgpu::Texture,
+    width: u32,
+    height: u32,
+    path: impl AsRef<Path>,
+) -> Result<(), Error> {
+    if !texture.usage().contains(wgpu::TextureUsages::COPY_SRC) {
+        return Err(TextureError(format!(
+            "screenshot of a {:?} texture without COPY_SRC usage",
+            texture.format()
+        )));
+    }
+
+    let pixels = read_texture_rgba(context, texture, width, height)?;
+    let image = RgbaImage::from_raw(width, height, pixels).ok_or(TextureError("capture buffer size mismatch".to_string()))?;
+    image.save_with_format(path, image::ImageFormat::Png)?;
+    Ok(())
+}
+
+// Whether the texels need their red and blue swapped for rgba
+pub fn get_rgba_swizzle(format: wgpu::TextureFormat) -> Result<bool, Error> {
+    match format {
+        wgpu::TextureFormat::Rgba8Unorm | wgpu::TextureFormat::Rgba8UnormSrgb => Ok(false),
+        wgpu::TextureFormat::Bgra8Unorm | wgpu::TextureFormat::Bgra8UnormSrgb => Ok(true),
+        format => Err(UnsupportedFormatError(format)),
+    }
+}
+
+fn read_mip_region_rgba(
+    context: &GpuContext,
+    texture: &wgpu::Texture,
+    mip_level: u32,
+    slice: u32,
+    width: u32,
+    height: u32,
+) -> Result<Vec<u8>, Error> {
+    let is_bgra = get_rgba_swizzle(texture.format())?;
+
+    let mut pixels = read_mip_region(context, texture, mip_level, [0, 0, slice], width, height, 4)?;
+
+    if is_bgra {
+        pixels.chunks_exact_mut(4).for_each(|pixel| pixel.swap(0, 2));
+    }
+
+    Ok(pixels)
+}
+
+fn read_mip_region(
+    context: &GpuContext,
+    texture: &wgpu::Texture,
+    mip_level: u32,
+    origin: [u32; 3],
+    width: u32,
+    height: u32,
+    bytes_per_texel: u32,
+) -> Result<Vec<u8>, Error> {
+    let (level_width, level_height) = get_mip_size(texture.width(), texture.height(), mip_level);
+    if width == 0 || height == 0 || origin[0] + width > level_width || origin[1] + height > level_height {
+        return Err(TextureError(format!(
+            "read region {}x{} at {:?} outside of mip level {} size {}x{}",
+            width, height, origin, mip_level, level_width, level_height
+        )));
+    }
+
+    let (unpadded_bytes_per_row, padded_bytes_per_row) = get_texel_bytes_per_row(width, bytes_per_texel);
+
+    let buffer = context.device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("capture buffer"),
+        size: (padded_bytes_per_row * height) as wgpu::BufferAddress,
+        usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+        mapped_at_creation: false,
+    });
+
+    let mut encoder = context
+        .device
+        .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: Some("capture") });
+
+    encoder.copy_texture_to_buffer(
+        wgpu::ImageCopyTexture {
+            texture,
+            mip_level,
+            origin: wgpu::Origin3d {
+                x: origin[0],
+                y: origin[1],
+                z: origin[2],
+            },
+            aspect: wgpu::TextureAspect::All,
+        },
+        wgpu::ImageCopyBuffer {
+            buffer: &buffer,
+            layout: wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(padded_bytes_per_row),
+                rows_per_image: Some(height),
+            },
+        },
+        wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+    );
+
+    context.queue.submit(std::iter::once(encoder.finish()));
+
+    let buffer_slice = buffer.slice(..);
+    buffer_slice.map_async(wgpu::MapMode::Read, |_| ());
+    context.device.poll(wgpu::Maintain::Wait);
+
+    let pixels = unpad_rows(
+        &buffer_slice.get_mapped_range(),
+        unpadded_bytes_per_row,
+        padded_bytes_per_row,
+        height,
+    );
+    buffer.unmap();
+
+    Ok(pixels)
+}
+
+// Rows copied into a buffer start at multiples of COPY_BYTES_PER_ROW_ALIGNMENT
+pub fn get_readback_bytes_per_row(width: u32) -> (u32, u32) {
+    get_texel_bytes_per_row(width, 4)
+}
+
+pub fn get_texel_bytes_per_row(width: u32, bytes_per_texel: u32) -> (u32, u32) {
+    let unpadded_bytes_per_row = width * bytes_per_texel;
+    let padded_bytes_per_row = align_to(unpadded_bytes_per_row, wgpu::COPY_BYTES_PER_ROW_ALIGNMENT);
+    (unpadded_bytes_per_row, padded_bytes_per_row)
+}
+
+// Removes the row padding required by copy_texture_to_buffer
+pub fn unpad_rows(data: &[u8], unpadded_bytes_per_row: u32, padded_bytes_per_row: u32, height: u32) -> Vec<u8> {
+    let mut pixels = Vec::with_capacity((unpadded_bytes_per_row * height) as usize);
+    for row in data.chunks(padded_bytes_per_row as usize).take(height as usize) {
+        pixels.extend_from_slice(&row[..unpadded_bytes_per_row as usize]);
+    }
+    pixels
+}
+
+pub fn compare_images(image: &RgbaImage, reference: &RgbaImage, tolerance: u8, create_diff_image: bool) -> Result<ImageDiff, Error> {
+    if image.dimensions() != reference.dimensions() {
+        return Err(ImageError(format!(
+            "image size {:?} does not match reference size {:?}",
+            image.dimensions(),
+            reference.dimensions()
+        )));
+    }
+
+    let mut max_difference = 0u8;
+    let mut total_difference = 0u64;
+    let mut pixels_over_tolerance = 0;
+
+    let mut diff_image = create_diff_image.then(|| RgbaImage::new(image.width(), image.height()));
+
+    for (x, y, pixel) in image.enumerate_pixels() {
+        let reference_pixel = reference.get_pixel(x, y);
+
+        let mut pixel_difference = 0u8;
+        for channel in 0..4 {
+            let difference = pixel[channel].abs_diff(reference_pixel[channel]);
+            pixel_difference = pixel_difference.max(difference);
+            total_difference += difference as u64;
+        }
+
+        max_difference = max_difference.max(pixel_difference);
+
+        if pixel_difference > tolerance {
+            pixels_over_tolerance += 1;
+        }
+
+        if let Some(diff_image) = diff_image.as_mut() {
+            // differences over the tolerance are shown in red, the rest in grayscale
+            let diff_pixel = if pixel_difference > tolerance {
+                image::Rgba([255, 0, 0, 255])
+            } else {
+                image::Rgba([pixel_difference, pixel_difference, pixel_difference, 255])
+            };
+            diff_image.put_pixel(x, y, diff_pixel);
+        }
+    }
+
+    let channel_count = (image.width() * image.height() * 4).max(1) as f32;
+
+    Ok(ImageDiff {
+        max_difference,
+        mean_difference: total_difference as f32 / channel_count,
+        pixels_over_tolerance,
+        diff_image,
+    })
+}
+
+// Captures the texture and compares it to the reference png, optionally saving the diff image
+pub fn compare_to_reference(
+    context: &GpuContext,
+    texture: &wgpu::Texture,
+    reference_path: impl AsRef<Path>,
+    tolerance: u8,
+    diff_path: Option<&Path>,
+) -> Result<ImageDiff, Error> {
+    let image = capture_texture(context, texture)?;
+    let reference = image::open(reference_path.as_ref())?.to_rgba8();
+
+    let diff = compare_images(&image, &reference, tolerance, diff_path.is_some())?;
+
+    if let (Some(path), Some(diff_image)) = (diff_path, &diff.diff_image) {
+        diff_image.save(path)?;
+    }
+
+    Ok(diff)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::gpu_context::GpuContext;
+    use crate::render::RenderPassBuilder;
+    use crate::snapshot::{
+        capture_texture_mip, compare_images, get_readback_bytes_per_row, get_rgba_swizzle, get_texel_bytes_per_row, save_texture_png,
+        unpad_rows,
+    };
+    use crate::texture::load_texture_from_bytes;
+    use image::{Rgba, RgbaImage};
+
+    fn test_image(shift: u32) -> RgbaImage {
+        RgbaImage::from_fn(16, 16, |x, _y| {
+            if (x + shift) % 4 < 2 {
+                Rgba([255, 255, 255, 255])
+            } else {
+                Rgba([0, 0, 0, 255])
+            }
+        })
+    }
+
+    #[test]
+    fn test_compare_same_image() {
+        let image = test_image(0);
+        let diff = compare_images(&image, &image, 0, true).unwrap();
+
+        assert_eq!(diff.max_difference, 0);
+        assert_eq!(diff.mean_difference, 0.0);
+        assert!(diff.is_match());
+    }
+
+    #[test]
+    fn test_compare_shifted_image() {
+        let diff = compare_images(&test_image(1), &test_image(0), 2, false).unwrap();
+
+        assert_eq!(diff.max_difference, 255);
+        assert!(diff.mean_difference > 0.0);
+        assert!(!diff.is_match());
+        assert!(diff.diff_image.is_none());
+    }
+
+    #[test]
+    fn test_unpad_rows() {
+        let data = [1, 2, 0, 0, 3, 4, 0, 0];
+        assert_eq!(unpad_rows(&data, 2, 4, 2), vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_readback_bytes_per_row() {
+        assert_eq!(get_readback_bytes_per_row(64), (256, 256));
+        assert_eq!(get_readback_bytes_per_row(65), (260, 512));
+        // 8 byte Rgba16Float texels
+        assert_eq!(get_texel_bytes_per_row(40, 8), (320, 512));
+
+        // a 3 pixel wide frame is copied as 256 byte rows and comes back as 12 byte rows
+        let (unpadded, padded) = get_readback_bytes_per_row(3);
+        let data: Vec<u8> = (0..padded * 2).map(|i| (i % padded) as u8).collect();
+        let pixels = unpad_rows(&data, unpadded, padded, 2);
+        assert_eq!(pixels.len(), 3 * 2 * 4);
+        assert_eq!(&pixels[12..16], &[0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_rgba_swizzle() {
+        assert!(!get_rgba_swizzle(wgpu::TextureFormat::Rgba8UnormSrgb).unwrap());
+        assert!(get_rgba_swizzle(wgpu::TextureFormat::Bgra8Unorm).unwrap());
+
+        // hdr targets need tonemapping into an 8 bit target first
+        let result = get_rgba_swizzle(wgpu::TextureFormat::Rgba16Float);
+        assert!(matches!(
+            result,
+            Err(Error::UnsupportedFormatError(wgpu::TextureFormat::Rgba16Float))
+        ));
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "gpu_tests"), ignore = "needs a gpu adapter")]
+    fn test_save_screenshot() {
+        let context = pollster::block_on(GpuContext::new_headless(6, 4)).unwrap();
+        let frame = context.acquire_frame().unwrap();
+        let view = frame.texture().create_view(&wgpu::TextureViewDescriptor::default());
+
+        let mut encoder = context.device.create_command_encoder(&Default::default());
+        RenderPassBuilder::new().color(&view, Some(wgpu::Color::GREEN)).begin(&mut encoder);
+        context.queue.submit(std::iter::once(encoder.finish()));
+
+        let path = std::env::temp_dir().join("spark_gap_test_screenshot.png");
+        context.save_screenshot(frame.texture(), 6, 4, &path).unwrap();
+
+        let image = image::open(&path).unwrap().to_rgba8();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(image.dimensions(), (6, 4));
+        assert_eq!(image.get_pixel(5, 3), &Rgba([0, 255, 0, 255]));
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "gpu_tests"), ignore = "needs a gpu adapter")]
+    fn test_headless_readback() {
+        let context = pollster::block_on(GpuContext::new_headless(5, 3)).unwrap();
+        let frame = context.acquire_frame().unwrap();
+        let view = frame.texture().create_view(&wgpu::TextureViewDescriptor::default());
+
+        let mut encoder = context
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: None,
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: &view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::RED),
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: None,
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+        context.queue.submit(std::iter::once(encoder.finish()));
+
+        let pixels = context.read_texture_to_rgba(frame.texture(), 5, 3).unwrap();
+        assert_eq!(pixels.len(), 5 * 3 * 4);
+        assert_eq!(&pixels[4 * 7..4 * 8], &[255, 0, 0, 255]);
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "gpu_tests"), ignore = "needs a gpu adapter")]
+    fn test_mip_readback() {
+        let mut context = pollster::block_on(GpuContext::new_headless(4, 4)).unwrap();
+
+        // a linear format, so the 2x2 blocks average without srgb conversion
+        let image = RgbaImage::from_fn(4, 4, |x, y| Rgba([(x * 60) as u8, (y * 60) as u8, 100, 255]));
+        let mut png = std::io::Cursor::new(vec![]);
+        image.write_to(&mut png, image::ImageOutputFormat::Png).unwrap();
+
+        let mut texture = load_texture_from_bytes(&context, png.get_ref(), Some(wgpu::TextureFormat::Rgba8Unorm)).unwrap();
+        texture.generate_mipmaps(&mut context);
+
+        let mip = capture_texture_mip(&context, &texture.texture, 1).unwrap();
+        assert_eq!(mip.dimensions(), (2, 2));
+
+        for (x, y, pixel) in mip.enumerate_pixels() {
+            let expected = [(x * 120 + 30) as u8, (y * 120 + 30) as u8, 100, 255];
+            for channel in 0..4 {
+                assert!(pixel[channel].abs_diff(expected[channel]) <= 1, "{:?} != {:?}", pixel, expected);
+            }
+        }
+    }
+}
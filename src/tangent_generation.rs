@@ -0,0 +1,375 @@
+use std::borrow::Cow;
+use std::rc::Rc;
+
+use glam::{Vec2, Vec3};
+use wgpu::util::DeviceExt;
+use wgpu::{BindGroupLayout, Buffer, BufferAddress, ComputePipeline};
+
+use crate::buffers::{cast_slice, read_buffer, UniformBuffer};
+use crate::capabilities::Capabilities;
+use crate::error::Error;
+use crate::error::Error::ValidationError;
+use crate::gpu_context::{get_or_create_bind_group_layout, GpuContext};
+
+pub const TANGENT_GENERATION_BIND_GROUP_LAYOUT: &str = "tangent generation bind group layout";
+
+// must match tangent_generation.wgsl
+pub const TANGENT_WORKGROUP_SIZE: u32 = 64;
+pub const TANGENT_FIXED_POINT_SCALE: f32 = 65536.0;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct TangentUniform {
+    pub vertex_count: u32,
+    pub triangle_count: u32,
+    pub _padding: [u32; 2],
+}
+
+// Storage buffers of one mesh. Tangents are packed vec3s, one per vertex.
+pub struct TangentBuffers {
+    pub positions: Buffer,
+    pub uvs: Buffer,
+    pub indices: Buffer,
+    pub tangents: Buffer,
+    pub vertex_count: u32,
+    pub triangle_count: u32,
+}
+
+impl TangentBuffers {
+    pub fn new(context: &GpuContext, positions: &[Vec3], uvs: &[Vec2], indices: &[u32]) -> Result<Self, Error> {
+        if positions.is_empty() || indices.is_empty() {
+            return Err(ValidationError("tangent generation needs at least one triangle".to_string()));
+        }
+        if positions.len() != uvs.len() {
+            return Err(ValidationError(format!(
+                "tangent generation has {} positions but {} uvs",
+                positions.len(),
+                uvs.len()
+            )));
+        }
+        if indices.len() % 3 != 0 {
+            return Err(ValidationError(format!(
+                "tangent generation index count {} isn't a multiple of 3",
+                indices.len()
+            )));
+        }
+        if let Some(index) = indices.iter().find(|index| **index as usize >= positions.len()) {
+            return Err(ValidationError(format!(
+                "tangent generation index {} is out of range of {} vertices",
+                index,
+                positions.len()
+            )));
+        }
+
+        let create_storage_buffer = |contents: &[u8], label: &str| {
+            context.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some(label),
+                contents,
+                usage: wgpu::BufferUsages::STORAGE,
+            })
+        };
+
+        let tangents = context.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("tangent generation tangents"),
+            size: (positions.len() * 3 * 4) as BufferAddress,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+
+        Ok(TangentBuffers {
+            positions: create_storage_buffer(bytemuck::cast_slice(positions), "tangent generation positions"),
+            uvs: create_storage_buffer(bytemuck::cast_slice(uvs), "tangent generation uvs"),
+            indices: create_storage_buffer(bytemuck::cast_slice(indices), "tangent generation indices"),
+            tangents,
+            vertex_count: positions.len() as u32,
+            triangle_count: (indices.len() / 3) as u32,
+        })
+    }
+
+    // Blocks until the generated tangents are copied back
+    pub fn read_tangents(&self, context: &GpuContext) -> Result<Vec<Vec3>, Error> {
+        let data = read_buffer(context, &self.tangents);
+        Ok(cast_slice::<Vec3>(&data)?.to_vec())
+    }
+}
+
+// Computes per vertex tangents on the gpu for meshes too large to do on the cpu. Each triangle's
+// unit tangent is added to its vertices with atomics, then the sums are normalized.
+pub struct TangentGenerator {
+    pub max_vertices: u32,
+    pub max_triangles: u32,
+    accumulate_pipeline: ComputePipeline,
+    normalize_pipeline: ComputePipeline,
+    bind_group_layout: Rc<BindGroupLayout>,
+    // fixed point tangent sums, three i32 per vertex
+    accumulation_buffer: Buffer,
+    uniform_buffer: UniformBuffer<TangentUniform>,
+}
+
+impl TangentGenerator {
+    pub fn new(context: &mut GpuContext, max_vertices: u32, max_triangles: u32) -> Result<Self, Error> {
+        check_tangent_generation_support(&context.capabilities, max_vertices, max_triangles)?;
+
+        let bind_group_layout = get_or_create_bind_group_layout(
+            context,
+            TANGENT_GENERATION_BIND_GROUP_LAYOUT,
+            create_tangent_generation_bind_group_layout,
+        );
+
+        let shader = context.device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("tangent generation shader"),
+            source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(include_str!("shaders/tangent_generation.wgsl"))),
+        });
+
+        let pipeline_layout = context.device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("tangent generation pipeline layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let accumulate_pipeline = context.device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("tangent accumulate pipeline"),
+            layout: Some(&pipeline_layout),
+            module: &shader,
+            entry_point: "accumulate_tangents",
+        });
+
+        let normalize_pipeline = context.device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("tangent normalize pipeline"),
+            layout: Some(&pipeline_layout),
+            module: &shader,
+            entry_point: "normalize_tangents",
+        });
+
+        let accumulation_buffer = context.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("tangent accumulation"),
+            size: (max_vertices.max(1) * 3 * 4) as BufferAddress,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let uniform_buffer = UniformBuffer::new(&*context, &get_tangent_uniform(0, 0), wgpu::BufferUsages::empty());
+
+        Ok(TangentGenerator {
+            max_vertices,
+            max_triangles,
+            accumulate_pipeline,
+            normalize_pipeline,
+            bind_group_layout,
+            accumulation_buffer,
+            uniform_buffer,
+        })
+    }
+
+    // Records both passes writing mesh.tangents. The uniform is written through the queue
+    // so only one mesh per submit is supported.
+    pub fn generate(&self, context: &GpuContext, encoder: &mut wgpu::CommandEncoder, mesh: &TangentBuffers) -> Result<(), Error> {
+        if mesh.vertex_count > self.max_vertices || mesh.triangle_count > self.max_triangles {
+            return Err(ValidationError(format!(
+                "tangent generation of {} vertices and {} triangles exceeds the maximum of {} and {}",
+                mesh.vertex_count, mesh.triangle_count, self.max_vertices, self.max_triangles
+            )));
+        }
+
+        self.uniform_buffer
+            .update(context, &get_tangent_uniform(mesh.vertex_count, mesh.triangle_count));
+
+        let bind_group = context.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: mesh.positions.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: mesh.uvs.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: mesh.indices.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: self.accumulation_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: mesh.tangents.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: self.uniform_buffer.as_entire_binding(),
+                },
+            ],
+            label: Some("tangent generation bind group"),
+        });
+
+        encoder.clear_buffer(&self.accumulation_buffer, 0, None);
+
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("tangent generation"),
+            timestamp_writes: None,
+        });
+        pass.set_bind_group(0, &bind_group, &[]);
+
+        pass.set_pipeline(&self.accumulate_pipeline);
+        pass.dispatch_workgroups(mesh.triangle_count.div_ceil(TANGENT_WORKGROUP_SIZE), 1, 1);
+
+        pass.set_pipeline(&self.normalize_pipeline);
+        pass.dispatch_workgroups(mesh.vertex_count.div_ceil(TANGENT_WORKGROUP_SIZE), 1, 1);
+
+        Ok(())
+    }
+}
+
+pub fn check_tangent_generation_support(capabilities: &Capabilities, max_vertices: u32, max_triangles: u32) -> Result<(), Error> {
+    capabilities.require_compute("tangent generation")?;
+    capabilities.require_storage_buffers("tangent generation")?;
+
+    let max_workgroups = capabilities.max_compute_workgroups_per_dimension;
+    if max_vertices.max(max_triangles).div_ceil(TANGENT_WORKGROUP_SIZE) > max_workgroups {
+        return Err(ValidationError(format!(
+            "tangent generation of {} vertices and {} triangles needs more than max_compute_workgroups_per_dimension ({}) workgroups",
+            max_vertices, max_triangles, max_workgroups
+        )));
+    }
+    Ok(())
+}
+
+fn get_tangent_uniform(vertex_count: u32, triangle_count: u32) -> TangentUniform {
+    TangentUniform {
+        vertex_count,
+        triangle_count,
+        _padding: [0; 2],
+    }
+}
+
+fn create_tangent_generation_bind_group_layout(context: &GpuContext, label: &str) -> BindGroupLayout {
+    let storage_entry = |binding, read_only| wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::COMPUTE,
+        ty: wgpu::BindingType::Buffer {
+            ty: wgpu::BufferBindingType::Storage { read_only },
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    };
+
+    context.device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        entries: &[
+            // positions
+            storage_entry(0, true),
+            // uvs
+            storage_entry(1, true),
+            // indices
+            storage_entry(2, true),
+            // accumulated tangents
+            storage_entry(3, false),
+            // tangents
+            storage_entry(4, false),
+            wgpu::BindGroupLayoutEntry {
+                binding: 5,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+        ],
+        label: Some(label),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{vec2, vec3, Mat3, Vec2, Vec3};
+
+    use crate::gpu_context::GpuContext;
+    use crate::tangent_generation::{TangentBuffers, TangentGenerator};
+
+    const QUAD_INDICES: [u32; 6] = [0, 1, 2, 0, 2, 3];
+
+    fn get_quad() -> (Vec<Vec3>, Vec<Vec2>) {
+        let positions = vec![
+            vec3(-1.0, -1.0, 0.0),
+            vec3(1.0, -1.0, 0.0),
+            vec3(1.0, 1.0, 0.0),
+            vec3(-1.0, 1.0, 0.0),
+        ];
+        let uvs = vec![vec2(0.0, 0.0), vec2(1.0, 0.0), vec2(1.0, 1.0), vec2(0.0, 1.0)];
+        (positions, uvs)
+    }
+
+    // rotated and sheared with skewed uvs, so the two triangles have different face tangents
+    fn get_skewed_quad() -> (Vec<Vec3>, Vec<Vec2>) {
+        let transform = Mat3::from_rotation_y(0.7) * Mat3::from_cols(Vec3::X, vec3(0.4, 1.0, 0.0), Vec3::Z);
+        let positions = get_quad().0.iter().map(|position| transform * *position).collect();
+        let uvs = vec![vec2(0.0, 0.0), vec2(1.0, 0.2), vec2(0.9, 1.0), vec2(0.1, 0.7)];
+        (positions, uvs)
+    }
+
+    // Unit tangent of a triangle along increasing u, zero when the uvs are degenerate
+    fn get_face_tangent(positions: [Vec3; 3], uvs: [Vec2; 3]) -> Vec3 {
+        let edge1 = positions[1] - positions[0];
+        let edge2 = positions[2] - positions[0];
+        let delta_uv1 = uvs[1] - uvs[0];
+        let delta_uv2 = uvs[2] - uvs[0];
+
+        let det = delta_uv1.x * delta_uv2.y - delta_uv2.x * delta_uv1.y;
+        if det.abs() < 1e-12 {
+            return Vec3::ZERO;
+        }
+
+        ((edge1 * delta_uv2.y - edge2 * delta_uv1.y) / det).normalize_or_zero()
+    }
+
+    // The float reference, the normalized sum of the face tangents around each vertex
+    fn calculate_tangents(positions: &[Vec3], uvs: &[Vec2], indices: &[u32]) -> Vec<Vec3> {
+        let mut sums = vec![Vec3::ZERO; positions.len()];
+
+        for triangle in indices.chunks_exact(3) {
+            let [i0, i1, i2] = [triangle[0] as usize, triangle[1] as usize, triangle[2] as usize];
+            let tangent = get_face_tangent([positions[i0], positions[i1], positions[i2]], [uvs[i0], uvs[i1], uvs[i2]]);
+            for i in [i0, i1, i2] {
+                sums[i] += tangent;
+            }
+        }
+
+        sums.iter().map(|sum| sum.normalize_or_zero()).collect()
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "gpu_tests"), ignore = "needs a gpu adapter")]
+    fn test_gpu_tangents() {
+        let mut context = pollster::block_on(GpuContext::new_headless(4, 4)).unwrap();
+        let generator = TangentGenerator::new(&mut context, 16, 16).unwrap();
+        let generate = |positions: &[Vec3], uvs: &[Vec2]| {
+            let mesh = TangentBuffers::new(&context, positions, uvs, &QUAD_INDICES).unwrap();
+            let mut encoder = context.device.create_command_encoder(&Default::default());
+            generator.generate(&context, &mut encoder, &mesh).unwrap();
+            context.queue.submit(std::iter::once(encoder.finish()));
+            mesh.read_tangents(&context).unwrap()
+        };
+
+        let (positions, uvs) = get_quad();
+        let tangents = generate(&positions, &uvs);
+        assert!(tangents.iter().all(|tangent| tangent.abs_diff_eq(Vec3::X, 1e-4)), "{:?}", tangents);
+
+        // degenerate uvs leave the tangent at zero
+        let flat_uvs = vec![vec2(0.5, 0.5); 4];
+        assert!(generate(&positions, &flat_uvs).iter().all(|tangent| *tangent == Vec3::ZERO));
+
+        // the fixed point sums stay close to the float reference
+        let (positions, uvs) = get_skewed_quad();
+        let tangents = generate(&positions, &uvs);
+        let reference = calculate_tangents(&positions, &uvs, &QUAD_INDICES);
+        assert_eq!(tangents.len(), reference.len());
+        for (expected, actual) in reference.iter().zip(&tangents) {
+            assert!(expected.abs_diff_eq(*actual, 1e-4), "{} != {}", expected, actual);
+        }
+    }
+}
@@ -0,0 +1,221 @@
+use glam::{Vec2, Vec4};
+use glyphon::{Attrs, Buffer, Color, Family, FontSystem, Metrics, Resolution, Shaping, SwashCache, TextArea, TextAtlas, TextBounds};
+use wgpu::TextureView;
+
+use crate::error::Error;
+use crate::error::Error::TextError;
+use crate::gpu_context::GpuContext;
+use crate::render::RenderPassBuilder;
+
+// Line height as a multiple of the font size
+pub const LINE_HEIGHT_SCALE: f32 = 1.2;
+
+// A string queued for the next frame. position is the top left corner in pixels from the top left
+// of the target, color is srgb encoded like css colors.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextSection {
+    pub text: String,
+    pub position: Vec2,
+    pub size: f32,
+    pub color: Vec4,
+}
+
+// Draws hud and debug text over a finished frame with glyphon. Queue the frame's strings, then
+// either record_overlay_pass after the scene or prepare and render into a pass of your own.
+//
+//   text.queue("16.6 ms", vec2(10.0, 10.0), 16.0, Vec4::ONE);
+//   text.record_overlay_pass(context, &mut encoder, &frame_view)?;
+pub struct TextRenderer {
+    font_system: FontSystem,
+    swash_cache: SwashCache,
+    atlas: TextAtlas,
+    renderer: glyphon::TextRenderer,
+    resolution: Resolution,
+    sections: Vec<TextSection>,
+    // one shaped buffer per queued section, kept between frames
+    buffers: Vec<Buffer>,
+}
+
+impl TextRenderer {
+    // format is the format of the views rendered into, usually context.surface_view_format().
+    // Text is drawn into the surface pass, so the pipeline uses the context's sample count.
+    pub fn new(context: &GpuContext, format: wgpu::TextureFormat) -> Self {
+        let mut atlas = TextAtlas::new(&context.device, &context.queue, format);
+        let renderer = glyphon::TextRenderer::new(&mut atlas, &context.device, context.multisample_state(), None);
+
+        TextRenderer {
+            font_system: FontSystem::new(),
+            swash_cache: SwashCache::new(),
+            atlas,
+            renderer,
+            resolution: Resolution {
+                width: context.config.width,
+                height: context.config.height,
+            },
+            sections: vec![],
+            buffers: vec![],
+        }
+    }
+
+    // After the surface was resized to context.config.width x height
+    pub fn resize(&mut self, context: &GpuContext) {
+        self.resolution = Resolution {
+            width: context.config.width,
+            height: context.config.height,
+        };
+    }
+
+    pub fn queue(&mut self, text: &str, position: Vec2, size: f32, color: Vec4) {
+        self.queue_section(TextSection {
+            text: text.to_string(),
+            position,
+            size,
+            color,
+        });
+    }
+
+    pub fn queue_section(&mut self, section: TextSection) {
+        self.sections.push(section);
+    }
+
+    pub fn get_queued(&self) -> &[TextSection] {
+        &self.sections
+    }
+
+    // Shapes the queued sections and uploads their glyphs, then clears the queue. Call once per
+    // frame before render.
+    pub fn prepare(&mut self, context: &GpuContext) -> Result<(), Error> {
+        // glyphs not used since the last prepare can be evicted
+        self.atlas.trim();
+
+        let sections = std::mem::take(&mut self.sections);
+        while self.buffers.len() < sections.len() {
+            self.buffers
+                .push(Buffer::new(&mut self.font_system, Metrics::new(16.0, get_line_height(16.0))));
+        }
+
+        for (buffer, section) in self.buffers.iter_mut().zip(&sections) {
+            buffer.set_metrics(&mut self.font_system, Metrics::new(section.size, get_line_height(section.size)));
+            buffer.set_size(
+                &mut self.font_system,
+                (self.resolution.width as f32 - section.position.x).max(0.0),
+                (self.resolution.height as f32 - section.position.y).max(0.0),
+            );
+            buffer.set_text(
+                &mut self.font_system,
+                &section.text,
+                Attrs::new().family(Family::SansSerif),
+                Shaping::Advanced,
+            );
+        }
+
+        let bounds = TextBounds {
+            left: 0,
+            top: 0,
+            right: self.resolution.width as i32,
+            bottom: self.resolution.height as i32,
+        };
+        let text_areas = self.buffers.iter().zip(&sections).map(|(buffer, section)| TextArea {
+            buffer,
+            left: section.position.x,
+            top: section.position.y,
+            scale: 1.0,
+            bounds,
+            default_color: get_text_color(section.color),
+        });
+
+        self.renderer
+            .prepare(
+                &context.device,
+                &context.queue,
+                &mut self.font_system,
+                &mut self.atlas,
+                self.resolution,
+                text_areas,
+                &mut self.swash_cache,
+            )
+            .map_err(|error| TextError(format!("preparing text: {:?}", error)))
+    }
+
+    // Draws the sections of the last prepare, the pass targets a view of the renderer's format
+    pub fn render<'a>(&'a self, pass: &mut wgpu::RenderPass<'a>) -> Result<(), Error> {
+        self.renderer
+            .render(&self.atlas, pass)
+            .map_err(|error| TextError(format!("rendering text: {:?}", error)))
+    }
+
+    // Prepares the queued text and draws it over target_view in its own pass, which loads the
+    // frame's contents. Record it after the scene and tonemap passes.
+    pub fn record_overlay_pass(
+        &mut self,
+        context: &GpuContext,
+        encoder: &mut wgpu::CommandEncoder,
+        target_view: &TextureView,
+    ) -> Result<(), Error> {
+        self.prepare(context)?;
+
+        let mut pass = RenderPassBuilder::new()
+            .label("text overlay")
+            .color(target_view, None)
+            .begin(encoder);
+        self.render(&mut pass)
+    }
+}
+
+pub fn get_line_height(size: f32) -> f32 {
+    (size * LINE_HEIGHT_SCALE).ceil()
+}
+
+// glyphon colors are srgb bytes, linear targets are converted by its shader
+pub fn get_text_color(color: Vec4) -> Color {
+    let [r, g, b, a] = (color.clamp(Vec4::ZERO, Vec4::ONE) * 255.0)
+        .round()
+        .to_array()
+        .map(|value| value as u8);
+    Color::rgba(r, g, b, a)
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{vec2, vec4, Vec4};
+    use glyphon::Color;
+
+    use crate::gpu_context::GpuContext;
+    use crate::render::RenderPassBuilder;
+    use crate::text::{get_line_height, get_text_color, TextRenderer};
+
+    #[test]
+    fn test_text_color() {
+        assert_eq!(get_text_color(Vec4::ONE), Color::rgba(255, 255, 255, 255));
+        assert_eq!(get_text_color(vec4(1.0, 0.5, 0.0, 0.25)), Color::rgba(255, 128, 0, 64));
+
+        // out of range values are clamped
+        assert_eq!(get_text_color(vec4(2.0, -1.0, 0.0, 1.0)), Color::rgba(255, 0, 0, 255));
+
+        // whole pixel lines
+        assert_eq!(get_line_height(16.0), 20.0);
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "gpu_tests"), ignore = "needs a gpu adapter")]
+    fn test_text_overlay() {
+        let context = pollster::block_on(GpuContext::new_headless(64, 32)).unwrap();
+        let texture = context.offscreen_texture.as_ref().unwrap();
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+        let mut text = TextRenderer::new(&context, context.surface_view_format());
+        text.queue("Hi", vec2(4.0, 4.0), 20.0, Vec4::ONE);
+        assert_eq!(text.get_queued().len(), 1);
+
+        let mut encoder = context.device.create_command_encoder(&Default::default());
+        RenderPassBuilder::new().color(&view, Some(wgpu::Color::BLACK)).begin(&mut encoder);
+        text.record_overlay_pass(&context, &mut encoder, &view).unwrap();
+        context.queue.submit(std::iter::once(encoder.finish()));
+
+        // the queue is cleared for the next frame
+        assert!(text.get_queued().is_empty());
+
+        let pixels = context.read_texture_to_rgba(texture, 64, 32).unwrap();
+        assert!(pixels.chunks(4).any(|pixel| pixel[0] > 128), "no text was drawn");
+    }
+}
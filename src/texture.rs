@@ -1,9 +1,18 @@
 use crate::error::Error;
-use crate::error::Error::ImageError;
-use crate::gpu_context::GpuContext;
+use crate::error::Error::{ImageError, TextureError};
+use crate::gpu_context::{get_or_create_bind_group_layout, GpuContext};
+use crate::post::create_fullscreen_pipeline;
+use crate::shader_preprocessor::expand_embedded_includes;
 use image::GenericImageView;
-use std::path::PathBuf;
-use wgpu::{BindGroup, BindGroupLayout};
+use log::warn;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use wgpu::{BindGroup, BindGroupLayout, RenderPipeline};
+
+// Wgsl get_mip_level and get_mip_level_color functions for visualizing texture lod
+pub const MIP_DEBUG_WGSL: &str = include_str!("shaders/mip_debug.wgsl");
 
 #[derive(Debug)]
 pub struct Texture {
@@ -77,44 +86,544 @@ pub fn get_texture(context: &GpuContext, file_path: impl Into<PathBuf>) -> Resul
     })
 }
 
-pub const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
+pub const MIPMAP_BIND_GROUP_LAYOUT: &str = "mipmap bind group layout";
 
-pub fn create_depth_texture(context: &GpuContext) -> Texture {
-    let size = context.window.inner_size();
+// copy source so generate_mipmaps can move the image into a texture with more levels
+const TEXTURE_2D_USAGE: wgpu::TextureUsages = wgpu::TextureUsages::TEXTURE_BINDING
+    .union(wgpu::TextureUsages::COPY_DST)
+    .union(wgpu::TextureUsages::COPY_SRC);
 
-    let size = wgpu::Extent3d {
-        width: size.width,
-        height: size.height,
+// Default format of loaded color images, Rgba8Unorm can be requested for data like normal maps
+pub const COLOR_TEXTURE_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
+
+#[derive(Debug)]
+pub struct Texture2D {
+    pub texture: wgpu::Texture,
+    pub view: wgpu::TextureView,
+    pub sampler: wgpu::Sampler,
+    pub width: u32,
+    pub height: u32,
+    pub format: wgpu::TextureFormat,
+    // 1 until generate_mipmaps is called
+    pub mip_level_count: u32,
+}
+
+impl Texture2D {
+    // Reallocates the texture with a full mip chain, copies the image into level 0 and draws
+    // every further level from the one above. wgpu has no built in mipmap generation.
+    pub fn generate_mipmaps(&mut self, context: &mut GpuContext) {
+        let mip_level_count = get_mip_level_count(self.width, self.height);
+        if mip_level_count <= self.mip_level_count {
+            return;
+        }
+
+        let pipeline = get_mipmap_pipeline(context, self.format);
+        let bind_group_layout = get_or_create_bind_group_layout(context, MIPMAP_BIND_GROUP_LAYOUT, create_mipmap_bind_group_layout);
+
+        let size = wgpu::Extent3d {
+            width: self.width,
+            height: self.height,
+            depth_or_array_layers: 1,
+        };
+
+        let texture = context.device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("mipmapped texture"),
+            size,
+            mip_level_count,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: self.format,
+            usage: TEXTURE_2D_USAGE | wgpu::TextureUsages::RENDER_ATTACHMENT,
+            view_formats: &[],
+        });
+
+        let level_views: Vec<wgpu::TextureView> = (0..mip_level_count)
+            .map(|level| {
+                texture.create_view(&wgpu::TextureViewDescriptor {
+                    base_mip_level: level,
+                    mip_level_count: Some(1),
+                    ..Default::default()
+                })
+            })
+            .collect();
+
+        let sampler = context.device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("mipmap sampler"),
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+
+        let mut encoder = context
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: Some("mipmaps") });
+
+        encoder.copy_texture_to_texture(self.texture.as_image_copy(), texture.as_image_copy(), size);
+
+        for level in 1..mip_level_count as usize {
+            let bind_group = context.device.create_bind_group(&wgpu::BindGroupDescriptor {
+                layout: &bind_group_layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(&level_views[level - 1]),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::Sampler(&sampler),
+                    },
+                ],
+                label: Some("mipmap bind group"),
+            });
+
+            let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("mipmap"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &level_views[level],
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                        store: wgpu::StoreOp::Store,
+                    },
+                })],
+                depth_stencil_attachment: None,
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            });
+
+            pass.set_pipeline(&pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            pass.draw(0..3, 0..1);
+        }
+
+        context.queue.submit(std::iter::once(encoder.finish()));
+
+        self.view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        self.sampler = create_texture_2d_sampler(context, mip_level_count);
+        self.texture = texture;
+        self.mip_level_count = mip_level_count;
+    }
+
+    // Samplers of textures without mipmaps should keep lod_max_clamp at 0
+    pub fn has_mipmaps(&self) -> bool {
+        self.mip_level_count > 1
+    }
+}
+
+// floor(log2(max(width, height))) + 1, down to a 1x1 level
+pub fn get_mip_level_count(width: u32, height: u32) -> u32 {
+    u32::BITS - width.max(height).max(1).leading_zeros()
+}
+
+// Size of a mip level, halved per level and rounded down to at least 1x1
+pub fn get_mip_size(width: u32, height: u32, level: u32) -> (u32, u32) {
+    ((width >> level).max(1), (height >> level).max(1))
+}
+
+// Created on first use for each format and kept on the context. The pipeline draws a full
+// screen copy of the bound texture, which also converts between formats.
+pub(crate) fn get_mipmap_pipeline(context: &mut GpuContext, format: wgpu::TextureFormat) -> Rc<RenderPipeline> {
+    if let Some(pipeline) = context.mipmap_pipelines.get(&format) {
+        return pipeline.clone();
+    }
+
+    let bind_group_layout = get_or_create_bind_group_layout(context, MIPMAP_BIND_GROUP_LAYOUT, create_mipmap_bind_group_layout);
+
+    let shader = context.device.create_shader_module(wgpu::ShaderModuleDescriptor {
+        label: Some("mipmap shader"),
+        source: wgpu::ShaderSource::Wgsl(Cow::Owned(
+            expand_embedded_includes("mipmap.wgsl", include_str!("shaders/mipmap.wgsl"), &[])
+                .expect("invalid mipmap.wgsl")
+                .source,
+        )),
+    });
+
+    let pipeline = Rc::new(create_fullscreen_pipeline(
+        context,
+        "mipmap pipeline",
+        &bind_group_layout,
+        &shader,
+        format,
+    ));
+    context.mipmap_pipelines.insert(format, pipeline.clone());
+    pipeline
+}
+
+pub(crate) fn create_mipmap_bind_group_layout(context: &GpuContext, label: &str) -> BindGroupLayout {
+    context.device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        entries: &[
+            wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    multisampled: false,
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 1,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                count: None,
+            },
+        ],
+        label: Some(label),
+    })
+}
+
+// Any size is supported, rows are uploaded unpadded through the queue
+pub fn load_texture_from_path(
+    context: &GpuContext,
+    path: impl AsRef<Path>,
+    format: Option<wgpu::TextureFormat>,
+) -> Result<Texture2D, Error> {
+    let path = path.as_ref();
+    let bytes = std::fs::read(path)?;
+    let image = decode_rgba8(&bytes).map_err(|e| ImageError(format!("{}  file: {:?}", e, path)))?;
+    create_texture_2d(context, &image, format.unwrap_or(COLOR_TEXTURE_FORMAT), &path.to_string_lossy())
+}
+
+// Bytes of any format the image crate was built with, detected from the data
+pub fn load_texture_from_bytes(context: &GpuContext, bytes: &[u8], format: Option<wgpu::TextureFormat>) -> Result<Texture2D, Error> {
+    let image = decode_rgba8(bytes)?;
+    create_texture_2d(context, &image, format.unwrap_or(COLOR_TEXTURE_FORMAT), "texture from bytes")
+}
+
+// Checkerboard of cells x cells squares for checking uv mapping without loading assets
+pub fn debug_checker(context: &GpuContext, size: u32, color_a: [u8; 4], color_b: [u8; 4], cells: u32) -> Texture2D {
+    let image = get_checker_image(size, color_a, color_b, cells);
+    create_texture_2d(context, &image, COLOR_TEXTURE_FORMAT, "debug checker").expect("debug textures are rgba8")
+}
+
+// Cells shaded by their uv, red along u and green along v, separated by white lines.
+// Numbered cells show their index, counted from the top left row by row.
+pub fn debug_uv_grid(context: &GpuContext, size: u32, cells: u32, numbered: bool) -> Texture2D {
+    let image = get_uv_grid_image(size, cells, numbered);
+    create_texture_2d(context, &image, COLOR_TEXTURE_FORMAT, "debug uv grid").expect("debug textures are rgba8")
+}
+
+pub fn get_checker_image(size: u32, color_a: [u8; 4], color_b: [u8; 4], cells: u32) -> image::RgbaImage {
+    let size = size.max(1);
+    let cells = cells.clamp(1, size);
+
+    image::RgbaImage::from_fn(size, size, |x, y| {
+        let cell_x = x * cells / size;
+        let cell_y = y * cells / size;
+        match (cell_x + cell_y) % 2 {
+            0 => image::Rgba(color_a),
+            _ => image::Rgba(color_b),
+        }
+    })
+}
+
+const GRID_LINE_COLOR: [u8; 4] = [255, 255, 255, 255];
+
+pub fn get_uv_grid_image(size: u32, cells: u32, numbered: bool) -> image::RgbaImage {
+    let size = size.max(1);
+    let cells = cells.clamp(1, size);
+    let line_width = (size / 256).max(1);
+
+    let mut image = image::RgbaImage::from_fn(size, size, |x, y| {
+        let cell_x = x * cells / size;
+        let cell_y = y * cells / size;
+
+        // the first pixels of each cell and the last of the texture form the lines
+        let is_line = |position: u32, cell: u32| position - cell * size / cells < line_width || position >= size - line_width;
+        if is_line(x, cell_x) || is_line(y, cell_y) {
+            return image::Rgba(GRID_LINE_COLOR);
+        }
+
+        let u = (x as f32 + 0.5) / size as f32;
+        let v = (y as f32 + 0.5) / size as f32;
+        image::Rgba([(u * 255.0) as u8, (v * 255.0) as u8, 64, 255])
+    });
+
+    if numbered {
+        let cell_size = size / cells;
+        let scale = (cell_size / 16).max(1);
+        for cell_y in 0..cells {
+            for cell_x in 0..cells {
+                let x = cell_x * size / cells + line_width + scale;
+                let y = cell_y * size / cells + line_width + scale;
+                draw_number(&mut image, cell_y * cells + cell_x, x, y, scale, cell_size);
+            }
+        }
+    }
+
+    image
+}
+
+// 3x5 pixel digits, one row per entry with the leftmost pixel in the high bit
+const DIGIT_FONT: [[u8; 5]; 10] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111],
+    [0b010, 0b110, 0b010, 0b010, 0b111],
+    [0b111, 0b001, 0b111, 0b100, 0b111],
+    [0b111, 0b001, 0b111, 0b001, 0b111],
+    [0b101, 0b101, 0b111, 0b001, 0b001],
+    [0b111, 0b100, 0b111, 0b001, 0b111],
+    [0b111, 0b100, 0b111, 0b101, 0b111],
+    [0b111, 0b001, 0b001, 0b001, 0b001],
+    [0b111, 0b101, 0b111, 0b101, 0b111],
+    [0b111, 0b101, 0b111, 0b001, 0b111],
+];
+
+// Numbers that don't fit into the cell are left out
+fn draw_number(image: &mut image::RgbaImage, number: u32, x: u32, y: u32, scale: u32, cell_size: u32) {
+    let digits = number.to_string();
+    let text_width = (digits.len() as u32 * 4 - 1) * scale;
+    if text_width + 2 * scale > cell_size || 7 * scale > cell_size {
+        return;
+    }
+
+    for (index, digit) in digits.bytes().enumerate() {
+        let glyph = DIGIT_FONT[(digit - b'0') as usize];
+        let glyph_x = x + index as u32 * 4 * scale;
+
+        for (row, bits) in glyph.iter().enumerate() {
+            for column in 0..3 {
+                if bits & (0b100 >> column) == 0 {
+                    continue;
+                }
+                for dy in 0..scale {
+                    for dx in 0..scale {
+                        let pixel_x = glyph_x + column * scale + dx;
+                        let pixel_y = y + row as u32 * scale + dy;
+                        if pixel_x < image.width() && pixel_y < image.height() {
+                            image.put_pixel(pixel_x, pixel_y, image::Rgba(GRID_LINE_COLOR));
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub fn decode_rgba8(bytes: &[u8]) -> Result<image::RgbaImage, Error> {
+    let img = image::load_from_memory(bytes).map_err(|e| ImageError(format!("image decode error: {:?}", e)))?;
+    Ok(img.to_rgba8())
+}
+
+// Loaded images are 8 bit rgba, only the srgb and linear variants match the data
+pub fn check_rgba8_format(format: wgpu::TextureFormat) -> Result<(), Error> {
+    match format {
+        wgpu::TextureFormat::Rgba8UnormSrgb | wgpu::TextureFormat::Rgba8Unorm => Ok(()),
+        _ => Err(TextureError(format!(
+            "images can't be loaded as {:?}, use Rgba8UnormSrgb or Rgba8Unorm",
+            format
+        ))),
+    }
+}
+
+fn create_texture_2d(context: &GpuContext, image: &image::RgbaImage, format: wgpu::TextureFormat, label: &str) -> Result<Texture2D, Error> {
+    check_rgba8_format(format)?;
+    let (width, height) = image.dimensions();
+
+    let texture_size = wgpu::Extent3d {
+        width,
+        height,
         depth_or_array_layers: 1,
     };
-    let desc = wgpu::TextureDescriptor {
-        label: Some("depth_texture"),
-        size,
+
+    let texture = context.device.create_texture(&wgpu::TextureDescriptor {
+        label: Some(label),
+        size: texture_size,
         mip_level_count: 1,
         sample_count: 1,
         dimension: wgpu::TextureDimension::D2,
-        format: DEPTH_FORMAT,
-        usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
-        view_formats: &[DEPTH_FORMAT],
-    };
-    let texture = context.device.create_texture(&desc);
+        format,
+        usage: TEXTURE_2D_USAGE,
+        view_formats: &[],
+    });
+
+    context.queue.write_texture(
+        texture.as_image_copy(),
+        image.as_raw(),
+        wgpu::ImageDataLayout {
+            offset: 0,
+            bytes_per_row: Some(4 * width),
+            rows_per_image: Some(height),
+        },
+        texture_size,
+    );
 
     let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+    let sampler = create_texture_2d_sampler(context, 1);
 
-    let sampler = context.device.create_sampler(&wgpu::SamplerDescriptor {
-        address_mode_u: wgpu::AddressMode::ClampToEdge,
-        address_mode_v: wgpu::AddressMode::ClampToEdge,
-        address_mode_w: wgpu::AddressMode::ClampToEdge,
-        mag_filter: wgpu::FilterMode::Linear,
-        min_filter: wgpu::FilterMode::Linear,
-        mipmap_filter: wgpu::FilterMode::Nearest,
-        compare: Some(wgpu::CompareFunction::LessEqual),
-        lod_min_clamp: 0.0,
-        lod_max_clamp: 100.0,
+    Ok(Texture2D {
+        texture,
+        view,
+        sampler,
+        width,
+        height,
+        format,
+        mip_level_count: 1,
+    })
+}
+
+fn create_texture_2d_sampler(context: &GpuContext, mip_level_count: u32) -> wgpu::Sampler {
+    SamplerBuilder::new()
+        .label("texture 2d sampler")
+        .mip_level_count(mip_level_count)
+        .build(context)
+}
+
+// Faces in layer order +x, -x, +y, -y, +z, -z
+pub const CUBEMAP_FACE_COUNT: u32 = 6;
+
+// Square faces in the layers of a D2 texture, sampled through a Cube view
+pub struct Cubemap {
+    pub texture: wgpu::Texture,
+    pub view: wgpu::TextureView,
+    pub sampler: wgpu::Sampler,
+    // width and height of each face
+    pub size: u32,
+    pub format: wgpu::TextureFormat,
+}
+
+// Face images in the order of CUBEMAP_FACE_COUNT, colors in srgb
+pub fn load_cubemap(context: &GpuContext, faces: [impl AsRef<Path>; 6]) -> Result<Cubemap, Error> {
+    let mut images = Vec::with_capacity(faces.len());
+    for path in &faces {
+        let path = path.as_ref();
+        let bytes = std::fs::read(path)?;
+        images.push(decode_rgba8(&bytes).map_err(|e| ImageError(format!("{}  file: {:?}", e, path)))?);
+    }
+    let label = faces[0].as_ref().to_string_lossy();
+    create_cubemap(context, &images, COLOR_TEXTURE_FORMAT, &label)
+}
+
+pub fn create_cubemap(
+    context: &GpuContext,
+    faces: &[image::RgbaImage],
+    format: wgpu::TextureFormat,
+    label: &str,
+) -> Result<Cubemap, Error> {
+    check_rgba8_format(format)?;
+    let dimensions: Vec<(u32, u32)> = faces.iter().map(|face| face.dimensions()).collect();
+    let size = get_cubemap_face_size(&dimensions)?;
+
+    let texture = context.device.create_texture(&wgpu::TextureDescriptor {
+        label: Some(label),
+        size: wgpu::Extent3d {
+            width: size,
+            height: size,
+            depth_or_array_layers: CUBEMAP_FACE_COUNT,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format,
+        usage: TEXTURE_2D_USAGE,
+        view_formats: &[],
+    });
+
+    for (layer, face) in faces.iter().enumerate() {
+        context.queue.write_texture(
+            wgpu::ImageCopyTexture {
+                texture: &texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d {
+                    x: 0,
+                    y: 0,
+                    z: layer as u32,
+                },
+                aspect: wgpu::TextureAspect::All,
+            },
+            face.as_raw(),
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(4 * size),
+                rows_per_image: Some(size),
+            },
+            wgpu::Extent3d {
+                width: size,
+                height: size,
+                depth_or_array_layers: 1,
+            },
+        );
+    }
+
+    let view = texture.create_view(&wgpu::TextureViewDescriptor {
+        label: Some(label),
+        dimension: Some(wgpu::TextureViewDimension::Cube),
         ..Default::default()
     });
 
-    Texture { texture, view, sampler }
+    Ok(Cubemap {
+        texture,
+        view,
+        sampler: create_texture_2d_sampler(context, 1),
+        size,
+        format,
+    })
+}
+
+// Faces have to be square and all of the same size
+pub fn get_cubemap_face_size(dimensions: &[(u32, u32)]) -> Result<u32, Error> {
+    if dimensions.len() != CUBEMAP_FACE_COUNT as usize {
+        return Err(TextureError(format!(
+            "a cubemap needs {} faces, got {}",
+            CUBEMAP_FACE_COUNT,
+            dimensions.len()
+        )));
+    }
+    let (width, height) = dimensions[0];
+    if width != height || width == 0 {
+        return Err(TextureError(format!(
+            "cubemap faces have to be square, the first is {}x{}",
+            width, height
+        )));
+    }
+    if let Some(face) = dimensions.iter().position(|size| *size != (width, height)) {
+        let (face_width, face_height) = dimensions[face];
+        return Err(TextureError(format!(
+            "cubemap face {} is {}x{}, the first is {}x{}",
+            face, face_width, face_height, width, height
+        )));
+    }
+    Ok(width)
+}
+
+pub const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
+
+// Depth target that can also be sampled. The texture is kept so passes that read it can rebind after a resize.
+#[derive(Debug)]
+pub struct DepthTexture {
+    pub texture: wgpu::Texture,
+    pub view: wgpu::TextureView,
+}
+
+// Sized to the surface, with the context's msaa sample count
+pub fn create_depth_texture(context: &GpuContext) -> DepthTexture {
+    create_depth_texture_with_samples(context, context.config.width, context.config.height, context.sample_count)
+}
+
+// For offscreen targets
+pub fn create_depth_texture_with_size(context: &GpuContext, width: u32, height: u32) -> DepthTexture {
+    create_depth_texture_with_samples(context, width, height, 1)
+}
+
+pub fn create_depth_texture_with_samples(context: &GpuContext, width: u32, height: u32, sample_count: u32) -> DepthTexture {
+    let texture = context.device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("depth_texture"),
+        size: wgpu::Extent3d {
+            width: width.max(1),
+            height: height.max(1),
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count,
+        dimension: wgpu::TextureDimension::D2,
+        format: DEPTH_FORMAT,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+        view_formats: &[],
+    });
+
+    let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+    DepthTexture { texture, view }
 }
 
 pub fn get_texture_bind_group(context: &GpuContext, texture: &Texture) -> (BindGroupLayout, BindGroup) {
@@ -157,3 +666,699 @@ pub fn get_texture_bind_group(context: &GpuContext, texture: &Texture) -> (BindG
 
     (texture_bind_group_layout, texture_bind_group)
 }
+
+pub const SURFACE_TARGET_USAGE: wgpu::TextureUsages = wgpu::TextureUsages::RENDER_ATTACHMENT.union(wgpu::TextureUsages::TEXTURE_BINDING);
+
+// Offscreen color target with the same format and size as the surface
+#[derive(Debug)]
+pub struct SurfaceTarget {
+    pub texture: wgpu::Texture,
+    pub view: wgpu::TextureView,
+    pub usage: wgpu::TextureUsages,
+}
+
+impl SurfaceTarget {
+    pub fn new(context: &GpuContext, extra_usage: wgpu::TextureUsages) -> Self {
+        let usage = SURFACE_TARGET_USAGE | extra_usage;
+        let texture = context.device.create_texture(&surface_target_descriptor(&context.config, usage));
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        SurfaceTarget { texture, view, usage }
+    }
+
+    pub fn recreate(&mut self, context: &GpuContext) {
+        *self = SurfaceTarget::new(context, self.usage);
+    }
+}
+
+pub fn surface_target_descriptor(config: &wgpu::SurfaceConfiguration, usage: wgpu::TextureUsages) -> wgpu::TextureDescriptor<'static> {
+    wgpu::TextureDescriptor {
+        label: Some("surface matched target"),
+        size: wgpu::Extent3d {
+            width: config.width,
+            height: config.height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: config.format,
+        usage,
+        view_formats: &[],
+    }
+}
+
+// The target is registered with the context and recreated at the new size by GpuContext::resize
+pub fn create_surface_matched_target(context: &mut GpuContext, extra_usage: wgpu::TextureUsages) -> Rc<RefCell<SurfaceTarget>> {
+    let target = Rc::new(RefCell::new(SurfaceTarget::new(context, extra_usage)));
+    context
+        .resize_registry
+        .register_resource(&target, |target, context, _, _| target.recreate(context));
+    target
+}
+
+// wgpu accepts anisotropy_clamp values from 1 to 16
+pub const MAX_ANISOTROPY: u16 = 16;
+
+// Returns the largest anisotropy the device will honor, 1 when anisotropic filtering isn't supported
+pub fn get_max_anisotropy(context: &GpuContext) -> u16 {
+    let downlevel = context.adapter.get_downlevel_capabilities();
+    if downlevel.flags.contains(wgpu::DownlevelFlags::ANISOTROPIC_FILTERING) {
+        MAX_ANISOTROPY
+    } else {
+        1
+    }
+}
+
+pub fn clamp_anisotropy(requested: u16, max_anisotropy: u16) -> u16 {
+    let clamped = requested.clamp(1, max_anisotropy.max(1));
+    if clamped != requested {
+        warn!("anisotropy {} not supported, clamped to {}", requested, clamped);
+    }
+    clamped
+}
+
+// ClampToBorder needs ADDRESS_MODE_CLAMP_TO_BORDER, which WebGL2 and many downlevel adapters lack
+pub fn get_supported_address_mode(address_mode: wgpu::AddressMode, clamp_to_border: bool) -> wgpu::AddressMode {
+    if address_mode == wgpu::AddressMode::ClampToBorder && !clamp_to_border {
+        warn!("ClampToBorder not supported, clamping to the edge");
+        return wgpu::AddressMode::ClampToEdge;
+    }
+    address_mode
+}
+
+// Linear, clamped to the edge and without anisotropy by default.
+//
+//   let color = SamplerBuilder::new().anisotropy(8).mip_level_count(texture.mip_level_count).build(context);
+//   let shadow = SamplerBuilder::new().comparison(wgpu::CompareFunction::LessEqual).build(context);
+#[derive(Debug, Clone)]
+pub struct SamplerBuilder {
+    pub label: Option<String>,
+    pub address_mode: wgpu::AddressMode,
+    pub mag_filter: wgpu::FilterMode,
+    pub min_filter: wgpu::FilterMode,
+    pub mipmap_filter: wgpu::FilterMode,
+    pub anisotropy: u16,
+    pub compare: Option<wgpu::CompareFunction>,
+    pub lod_min_clamp: f32,
+    pub lod_max_clamp: f32,
+}
+
+impl Default for SamplerBuilder {
+    fn default() -> Self {
+        SamplerBuilder::new()
+    }
+}
+
+impl SamplerBuilder {
+    pub fn new() -> Self {
+        SamplerBuilder {
+            label: None,
+            address_mode: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            anisotropy: 1,
+            compare: None,
+            lod_min_clamp: 0.0,
+            lod_max_clamp: 32.0,
+        }
+    }
+
+    pub fn label(mut self, label: impl Into<String>) -> Self {
+        self.label = Some(label.into());
+        self
+    }
+
+    pub fn address_mode(mut self, address_mode: wgpu::AddressMode) -> Self {
+        self.address_mode = address_mode;
+        self
+    }
+
+    pub fn filter(mut self, filter: wgpu::FilterMode) -> Self {
+        self.mag_filter = filter;
+        self.min_filter = filter;
+        self.mipmap_filter = filter;
+        self
+    }
+
+    pub fn linear(self) -> Self {
+        self.filter(wgpu::FilterMode::Linear)
+    }
+
+    pub fn nearest(self) -> Self {
+        self.filter(wgpu::FilterMode::Nearest)
+    }
+
+    // Clamped to what the device supports when the sampler is built
+    pub fn anisotropy(mut self, level: u16) -> Self {
+        self.anisotropy = level;
+        self
+    }
+
+    // A comparison sampler for depth textures, e.g. shadow maps sampled with textureSampleCompare
+    pub fn comparison(mut self, compare: wgpu::CompareFunction) -> Self {
+        self.compare = Some(compare);
+        self
+    }
+
+    pub fn lod_clamp(mut self, min: f32, max: f32) -> Self {
+        self.lod_min_clamp = min;
+        self.lod_max_clamp = max;
+        self
+    }
+
+    // Clamps the lod to the levels of a texture, e.g. Texture2D::mip_level_count after generate_mipmaps
+    pub fn mip_level_count(self, mip_level_count: u32) -> Self {
+        self.lod_clamp(0.0, mip_level_count.saturating_sub(1) as f32)
+    }
+
+    pub fn build(&self, context: &GpuContext) -> wgpu::Sampler {
+        let mut descriptor = self.get_descriptor(get_max_anisotropy(context));
+        let address_mode = get_supported_address_mode(self.address_mode, context.capabilities.clamp_to_border);
+        descriptor.address_mode_u = address_mode;
+        descriptor.address_mode_v = address_mode;
+        descriptor.address_mode_w = address_mode;
+        context.device.create_sampler(&descriptor)
+    }
+
+    pub fn get_descriptor(&self, max_anisotropy: u16) -> wgpu::SamplerDescriptor<'_> {
+        let mut anisotropy = clamp_anisotropy(self.anisotropy, max_anisotropy);
+
+        // wgpu requires all filters to be linear when anisotropy is enabled
+        let all_linear = self.mag_filter == wgpu::FilterMode::Linear
+            && self.min_filter == wgpu::FilterMode::Linear
+            && self.mipmap_filter == wgpu::FilterMode::Linear;
+
+        if anisotropy > 1 && !all_linear {
+            warn!("anisotropy requires linear filtering, disabling anisotropy");
+            anisotropy = 1;
+        }
+
+        wgpu::SamplerDescriptor {
+            label: self.label.as_deref(),
+            address_mode_u: self.address_mode,
+            address_mode_v: self.address_mode,
+            address_mode_w: self.address_mode,
+            mag_filter: self.mag_filter,
+            min_filter: self.min_filter,
+            mipmap_filter: self.mipmap_filter,
+            lod_min_clamp: self.lod_min_clamp,
+            lod_max_clamp: self.lod_max_clamp,
+            compare: self.compare,
+            anisotropy_clamp: anisotropy,
+            border_color: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct VolumeTexture {
+    pub texture: wgpu::Texture,
+    pub view: wgpu::TextureView,
+    pub size: wgpu::Extent3d,
+    pub format: wgpu::TextureFormat,
+}
+
+pub fn create_3d(context: &GpuContext, size_xyz: [u32; 3], format: wgpu::TextureFormat, usage: wgpu::TextureUsages) -> VolumeTexture {
+    let size = wgpu::Extent3d {
+        width: size_xyz[0],
+        height: size_xyz[1],
+        depth_or_array_layers: size_xyz[2],
+    };
+
+    let texture = context.device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("volume texture"),
+        size,
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D3,
+        format,
+        usage: usage | wgpu::TextureUsages::COPY_DST,
+        view_formats: &[],
+    });
+
+    let view = texture.create_view(&wgpu::TextureViewDescriptor {
+        label: Some("volume texture view"),
+        dimension: Some(wgpu::TextureViewDimension::D3),
+        ..Default::default()
+    });
+
+    VolumeTexture {
+        texture,
+        view,
+        size,
+        format,
+    }
+}
+
+// Data layout for tightly packed voxels, x fastest then rows then slices
+pub fn get_3d_data_layout(size: wgpu::Extent3d, format: wgpu::TextureFormat) -> Result<wgpu::ImageDataLayout, Error> {
+    let texel_size = format
+        .block_size(None)
+        .ok_or(TextureError(format!("format {:?} has no uniform texel size", format)))?;
+
+    Ok(wgpu::ImageDataLayout {
+        offset: 0,
+        bytes_per_row: Some(size.width * texel_size),
+        rows_per_image: Some(size.height),
+    })
+}
+
+// byte offset of a voxel inside data with the given layout
+pub fn get_voxel_offset(layout: &wgpu::ImageDataLayout, texel_size: u32, x: u32, y: u32, z: u32) -> usize {
+    let bytes_per_row = layout.bytes_per_row.unwrap_or(0) as usize;
+    let bytes_per_slice = bytes_per_row * layout.rows_per_image.unwrap_or(0) as usize;
+    layout.offset as usize + z as usize * bytes_per_slice + y as usize * bytes_per_row + (x * texel_size) as usize
+}
+
+pub fn upload_3d(context: &GpuContext, volume: &VolumeTexture, data: &[u8]) -> Result<(), Error> {
+    let layout = get_3d_data_layout(volume.size, volume.format)?;
+
+    let expected = layout.bytes_per_row.unwrap() as usize * volume.size.height as usize * volume.size.depth_or_array_layers as usize;
+    if data.len() != expected {
+        return Err(TextureError(format!(
+            "volume data is {} bytes, expected {} bytes",
+            data.len(),
+            expected
+        )));
+    }
+
+    context.queue.write_texture(volume.texture.as_image_copy(), data, layout, volume.size);
+
+    Ok(())
+}
+
+// Whether the adapter allows the format to be used for all of the usages, for picking fallback formats
+pub fn format_supports(context: &GpuContext, format: wgpu::TextureFormat, usage: wgpu::TextureUsages) -> bool {
+    features_support_usage(&context.adapter.get_texture_format_features(format), usage)
+}
+
+pub fn features_support_usage(features: &wgpu::TextureFormatFeatures, usage: wgpu::TextureUsages) -> bool {
+    features.allowed_usages.contains(usage)
+}
+
+// The first format in the list that supports the usage
+pub fn select_supported_format(
+    context: &GpuContext,
+    formats: &[wgpu::TextureFormat],
+    usage: wgpu::TextureUsages,
+) -> Option<wgpu::TextureFormat> {
+    formats.iter().copied().find(|format| format_supports(context, *format, usage))
+}
+
+// Filterable float format hdr images are uploaded as
+pub const HDR_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+// largest finite f16, brighter texels are clamped to it instead of becoming infinite
+const MAX_HDR_VALUE: f32 = 65504.0;
+
+// Linear rgba float pixels, rows from the top
+#[derive(Debug, Clone)]
+pub struct HdrImage {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<[f32; 4]>,
+}
+
+// Decodes a Radiance .hdr (RGBE) image, or an OpenEXR image with the exr feature
+pub fn decode_hdr(data: &[u8], format: image::ImageFormat) -> Result<HdrImage, Error> {
+    match format {
+        image::ImageFormat::Hdr => {}
+        image::ImageFormat::OpenExr if cfg!(feature = "exr") => {}
+        image::ImageFormat::OpenExr => return Err(ImageError("loading exr images needs the exr feature".to_string())),
+        _ => return Err(ImageError(format!("{:?} is not an hdr image format", format))),
+    }
+
+    let img = image::load_from_memory_with_format(data, format).map_err(|e| ImageError(format!("hdr decode error: {:?}", e)))?;
+    let rgba = img.to_rgba32f();
+
+    Ok(HdrImage {
+        width: rgba.width(),
+        height: rgba.height(),
+        pixels: rgba.pixels().map(|pixel| pixel.0).collect(),
+    })
+}
+
+// Loads a .hdr or .exr file into an HDR_FORMAT texture
+pub fn load_hdr(context: &GpuContext, file_path: impl Into<PathBuf>) -> Result<Texture, Error> {
+    let file_path = file_path.into();
+    let format =
+        image::ImageFormat::from_path(&file_path).map_err(|e| ImageError(format!("image error: {:?}  file: {:?}", e, &file_path)))?;
+    let data = std::fs::read(&file_path)?;
+    let hdr_image = decode_hdr(&data, format)?;
+
+    let texels: Vec<u16> = hdr_image
+        .pixels
+        .iter()
+        .flat_map(|pixel| pixel.map(|value| f32_to_f16(value.min(MAX_HDR_VALUE))))
+        .collect();
+
+    let texture_size = wgpu::Extent3d {
+        width: hdr_image.width,
+        height: hdr_image.height,
+        depth_or_array_layers: 1,
+    };
+
+    let texture = context.device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("hdr texture"),
+        size: texture_size,
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: HDR_FORMAT,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        view_formats: &[],
+    });
+
+    context.queue.write_texture(
+        texture.as_image_copy(),
+        bytemuck::cast_slice(&texels),
+        wgpu::ImageDataLayout {
+            offset: 0,
+            bytes_per_row: Some(8 * hdr_image.width),
+            rows_per_image: Some(hdr_image.height),
+        },
+        texture_size,
+    );
+
+    let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+    let sampler = context.device.create_sampler(&wgpu::SamplerDescriptor {
+        label: Some("hdr sampler"),
+        address_mode_u: wgpu::AddressMode::ClampToEdge,
+        address_mode_v: wgpu::AddressMode::ClampToEdge,
+        address_mode_w: wgpu::AddressMode::ClampToEdge,
+        mag_filter: wgpu::FilterMode::Linear,
+        min_filter: wgpu::FilterMode::Linear,
+        mipmap_filter: wgpu::FilterMode::Nearest,
+        ..Default::default()
+    });
+
+    Ok(Texture { texture, view, sampler })
+}
+
+// Round to nearest f16 bits, overflow becomes infinity and tiny values flush to zero
+pub fn f32_to_f16(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x7f_ffff;
+
+    // infinity and nan
+    if exponent == 0xff {
+        return sign | 0x7c00 | if mantissa != 0 { 0x200 } else { 0 };
+    }
+
+    let exponent = exponent - 127 + 15;
+    if exponent >= 0x1f {
+        return sign | 0x7c00;
+    }
+
+    if exponent <= 0 {
+        if exponent < -10 {
+            return sign;
+        }
+        // subnormal, the implicit leading bit becomes part of the mantissa
+        let mantissa = mantissa | 0x80_0000;
+        let shift = (14 - exponent) as u32;
+        let round = (mantissa >> (shift - 1)) & 1;
+        return sign | ((mantissa >> shift) + round) as u16;
+    }
+
+    // a rounding carry moves into the exponent, which is still the nearest value
+    let half = ((exponent as u32) << 10) | (mantissa >> 13);
+    let round = (mantissa >> 12) & 1;
+    sign | (half + round) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error::{ImageError, TextureError};
+    use crate::gpu_context::GpuContext;
+    use crate::snapshot::read_texture_slice_rgba;
+    use crate::texture::{
+        check_rgba8_format, clamp_anisotropy, create_3d, decode_hdr, decode_rgba8, f32_to_f16, features_support_usage, get_3d_data_layout,
+        get_checker_image, get_cubemap_face_size, get_mip_level_count, get_mip_size, get_supported_address_mode, get_uv_grid_image,
+        get_voxel_offset, surface_target_descriptor, upload_3d, SamplerBuilder, MAX_ANISOTROPY, SURFACE_TARGET_USAGE,
+    };
+
+    #[test]
+    fn test_clamp_anisotropy() {
+        assert_eq!(clamp_anisotropy(32, MAX_ANISOTROPY), 16);
+        assert_eq!(clamp_anisotropy(8, MAX_ANISOTROPY), 8);
+        assert_eq!(clamp_anisotropy(0, MAX_ANISOTROPY), 1);
+        assert_eq!(clamp_anisotropy(8, 1), 1);
+    }
+
+    #[test]
+    fn test_sampler_descriptor() {
+        let color = SamplerBuilder::new().anisotropy(32).mip_level_count(10);
+        let descriptor = color.get_descriptor(MAX_ANISOTROPY);
+        assert_eq!(descriptor.anisotropy_clamp, 16);
+        assert_eq!((descriptor.lod_min_clamp, descriptor.lod_max_clamp), (0.0, 9.0));
+        assert_eq!(descriptor.compare, None);
+
+        // without the capability or with nearest filtering there is no anisotropy
+        assert_eq!(color.get_descriptor(1).anisotropy_clamp, 1);
+        assert_eq!(color.clone().nearest().get_descriptor(MAX_ANISOTROPY).anisotropy_clamp, 1);
+
+        let shadow = SamplerBuilder::new()
+            .address_mode(wgpu::AddressMode::ClampToBorder)
+            .comparison(wgpu::CompareFunction::LessEqual);
+        let descriptor = shadow.get_descriptor(MAX_ANISOTROPY);
+        assert_eq!(descriptor.compare, Some(wgpu::CompareFunction::LessEqual));
+        assert_eq!(descriptor.address_mode_w, wgpu::AddressMode::ClampToBorder);
+        assert_eq!(descriptor.mag_filter, wgpu::FilterMode::Linear);
+
+        // devices without the feature clamp to the edge instead
+        assert_eq!(
+            get_supported_address_mode(wgpu::AddressMode::ClampToBorder, false),
+            wgpu::AddressMode::ClampToEdge
+        );
+        assert_eq!(
+            get_supported_address_mode(wgpu::AddressMode::ClampToBorder, true),
+            wgpu::AddressMode::ClampToBorder
+        );
+        assert_eq!(
+            get_supported_address_mode(wgpu::AddressMode::Repeat, false),
+            wgpu::AddressMode::Repeat
+        );
+
+        // textures without mipmaps only sample level 0
+        assert_eq!(SamplerBuilder::new().mip_level_count(1).lod_max_clamp, 0.0);
+    }
+
+    #[test]
+    fn test_surface_target_descriptor() {
+        let config = wgpu::SurfaceConfiguration {
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            format: wgpu::TextureFormat::Bgra8Unorm,
+            width: 640,
+            height: 480,
+            present_mode: wgpu::PresentMode::Fifo,
+            desired_maximum_frame_latency: 2,
+            alpha_mode: wgpu::CompositeAlphaMode::Auto,
+            view_formats: vec![],
+        };
+
+        let descriptor = surface_target_descriptor(&config, SURFACE_TARGET_USAGE | wgpu::TextureUsages::COPY_SRC);
+
+        assert_eq!(descriptor.format, config.format);
+        assert_eq!(descriptor.size.width, 640);
+        assert_eq!(descriptor.size.height, 480);
+        assert!(descriptor.usage.contains(wgpu::TextureUsages::COPY_SRC));
+    }
+
+    #[test]
+    fn test_volume_voxel_offset() {
+        let size = wgpu::Extent3d {
+            width: 4,
+            height: 3,
+            depth_or_array_layers: 2,
+        };
+        let layout = get_3d_data_layout(size, wgpu::TextureFormat::Rgba8Unorm).unwrap();
+        assert_eq!(layout.bytes_per_row, Some(16));
+        assert_eq!(layout.rows_per_image, Some(3));
+
+        // each voxel stores its own coordinates
+        let mut data = vec![0u8; 4 * 3 * 2 * 4];
+        for z in 0..2 {
+            for y in 0..3 {
+                for x in 0..4 {
+                    let offset = get_voxel_offset(&layout, 4, x, y, z);
+                    data[offset..offset + 4].copy_from_slice(&[x as u8, y as u8, z as u8, 255]);
+                }
+            }
+        }
+
+        let offset = get_voxel_offset(&layout, 4, 3, 1, 1);
+        assert_eq!(offset, 16 * 3 + 16 + 12);
+        assert_eq!(&data[offset..offset + 4], &[3, 1, 1, 255]);
+
+        assert!(get_3d_data_layout(size, wgpu::TextureFormat::Depth24Plus).is_err());
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "gpu_tests"), ignore = "needs a gpu adapter")]
+    fn test_volume_upload() {
+        let context = pollster::block_on(GpuContext::new_headless(4, 4)).unwrap();
+        let usage = wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_SRC;
+        let volume = create_3d(&context, [4, 3, 2], wgpu::TextureFormat::Rgba8Unorm, usage);
+        let layout = get_3d_data_layout(volume.size, volume.format).unwrap();
+
+        let mut data = vec![0u8; 4 * 3 * 2 * 4];
+        for z in 0..2 {
+            for y in 0..3 {
+                for x in 0..4 {
+                    let offset = get_voxel_offset(&layout, 4, x, y, z);
+                    data[offset..offset + 4].copy_from_slice(&[x as u8, y as u8, z as u8, 255]);
+                }
+            }
+        }
+        upload_3d(&context, &volume, &data).unwrap();
+
+        // the second slice comes back as written
+        let slice = read_texture_slice_rgba(&context, &volume.texture, 1, 4, 3).unwrap();
+        assert_eq!(slice, data[4 * 3 * 4..]);
+
+        assert!(upload_3d(&context, &volume, &data[4..]).is_err());
+        assert!(read_texture_slice_rgba(&context, &volume.texture, 2, 4, 3).is_err());
+    }
+
+    #[test]
+    fn test_format_features() {
+        let features = wgpu::TextureFormat::Rgba8Unorm.guaranteed_format_features(wgpu::Features::empty());
+        assert!(features_support_usage(
+            &features,
+            wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_SRC
+        ));
+        assert!(features.flags.contains(wgpu::TextureFormatFeatureFlags::FILTERABLE));
+
+        let features = wgpu::TextureFormat::Depth32Float.guaranteed_format_features(wgpu::Features::empty());
+        assert!(features_support_usage(&features, wgpu::TextureUsages::RENDER_ATTACHMENT));
+        assert!(!features_support_usage(&features, wgpu::TextureUsages::STORAGE_BINDING));
+    }
+
+    #[test]
+    fn test_load_hdr() {
+        // 2x1 flat RGBE image, a white texel at 1.0 and a black one
+        let mut data = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 1 +X 2\n".to_vec();
+        data.extend_from_slice(&[128, 128, 128, 129, 0, 0, 0, 0]);
+
+        let hdr_image = decode_hdr(&data, image::ImageFormat::Hdr).unwrap();
+        assert_eq!((hdr_image.width, hdr_image.height), (2, 1));
+
+        let [r, g, b, a] = hdr_image.pixels[0];
+        let luminance = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+        assert!((luminance - 1.0).abs() < 0.01);
+        assert_eq!(a, 1.0);
+        assert_eq!(hdr_image.pixels[1][..3], [0.0; 3]);
+
+        assert!(decode_hdr(&data, image::ImageFormat::Png).is_err());
+
+        assert_eq!(f32_to_f16(1.0), 0x3c00);
+        assert_eq!(f32_to_f16(-2.0), 0xc000);
+        assert_eq!(f32_to_f16(65504.0), 0x7bff);
+        assert_eq!(f32_to_f16(1e-8), 0);
+    }
+
+    #[test]
+    #[cfg(feature = "exr")]
+    fn test_decode_exr() {
+        // 2x1 float image encoded with image's own exr encoder
+        let pixels = vec![4.0, 2.0, 0.5, 1.0, 0.0, 0.25, 0.0, 1.0];
+        let image = image::Rgba32FImage::from_raw(2, 1, pixels).unwrap();
+        let mut data = std::io::Cursor::new(vec![]);
+        image::DynamicImage::ImageRgba32F(image)
+            .write_to(&mut data, image::ImageFormat::OpenExr)
+            .unwrap();
+
+        let hdr_image = decode_hdr(data.get_ref(), image::ImageFormat::OpenExr).unwrap();
+        assert_eq!((hdr_image.width, hdr_image.height), (2, 1));
+        assert_eq!(hdr_image.pixels, vec![[4.0, 2.0, 0.5, 1.0], [0.0, 0.25, 0.0, 1.0]]);
+    }
+
+    #[test]
+    fn test_decode_rgba8() {
+        // non power of two sizes decode as is
+        let image = image::RgbaImage::from_fn(3, 5, |x, y| image::Rgba([x as u8, y as u8, 7, 255]));
+        let mut png = std::io::Cursor::new(vec![]);
+        image::DynamicImage::ImageRgba8(image.clone())
+            .write_to(&mut png, image::ImageOutputFormat::Png)
+            .unwrap();
+
+        let decoded = decode_rgba8(png.get_ref()).unwrap();
+        assert_eq!(decoded.dimensions(), (3, 5));
+        assert_eq!(decoded, image);
+
+        assert!(matches!(decode_rgba8(b"not an image"), Err(ImageError(_))));
+
+        assert!(check_rgba8_format(wgpu::TextureFormat::Rgba8Unorm).is_ok());
+        assert!(matches!(check_rgba8_format(wgpu::TextureFormat::Rgba16Float), Err(TextureError(_))));
+    }
+
+    #[test]
+    fn test_mip_level_count() {
+        assert_eq!(get_mip_level_count(1, 1), 1);
+        assert_eq!(get_mip_level_count(256, 256), 9);
+        // non power of two sizes round down, the last level is 1x1
+        assert_eq!(get_mip_level_count(300, 17), 9);
+        assert_eq!(get_mip_level_count(3, 5), 3);
+        assert_eq!(get_mip_level_count(0, 0), 1);
+    }
+
+    #[test]
+    fn test_checker_image() {
+        let (a, b) = ([255, 0, 255, 255], [0, 0, 0, 255]);
+        let image = get_checker_image(8, a, b, 4);
+
+        // 2x2 pixel cells alternating along both axes
+        assert_eq!(image.get_pixel(0, 0).0, a);
+        assert_eq!(image.get_pixel(1, 1).0, a);
+        assert_eq!(image.get_pixel(2, 0).0, b);
+        assert_eq!(image.get_pixel(0, 2).0, b);
+        assert_eq!(image.get_pixel(2, 2).0, a);
+        assert_eq!(image.get_pixel(7, 6).0, a);
+        assert_eq!(image.get_pixel(7, 4).0, b);
+    }
+
+    #[test]
+    fn test_uv_grid_image() {
+        let image = get_uv_grid_image(64, 4, false);
+        let white = [255, 255, 255, 255];
+
+        assert_eq!(image.get_pixel(16, 5).0, white);
+        assert_eq!(image.get_pixel(63, 40).0, white);
+        // red follows u, green follows v
+        let top_right = image.get_pixel(56, 8).0;
+        let bottom_left = image.get_pixel(8, 56).0;
+        assert!(top_right[0] > 200 && top_right[1] < 50);
+        assert!(bottom_left[0] < 50 && bottom_left[1] > 200);
+
+        // the 1 of cell 1 draws its stem into the cell
+        let numbered = get_uv_grid_image(64, 4, true);
+        assert_eq!(numbered.get_pixel(16 + 3, 5).0, white);
+        assert_ne!(image.get_pixel(16 + 3, 5).0, white);
+    }
+
+    #[test]
+    fn test_mip_size() {
+        assert_eq!(get_mip_size(4, 4, 1), (2, 2));
+        assert_eq!(get_mip_size(300, 17, 3), (37, 2));
+        assert_eq!(get_mip_size(300, 17, 8), (1, 1));
+    }
+
+    #[test]
+    fn test_cubemap_face_size() {
+        assert_eq!(get_cubemap_face_size(&[(64, 64); 6]).unwrap(), 64);
+        assert!(matches!(get_cubemap_face_size(&[(64, 64); 5]), Err(TextureError(_))));
+        assert!(matches!(get_cubemap_face_size(&[(64, 32); 6]), Err(TextureError(_))));
+
+        let mut sizes = [(64, 64); 6];
+        sizes[4] = (32, 32);
+        let Err(TextureError(message)) = get_cubemap_face_size(&sizes) else {
+            panic!("mismatched faces are accepted");
+        };
+        assert!(message.contains("face 4"), "{}", message);
+    }
+}
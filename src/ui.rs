@@ -0,0 +1,173 @@
+use std::sync::Arc;
+
+use wgpu::TextureView;
+use winit::event::{ElementState, WindowEvent};
+use winit::window::Window;
+
+use crate::gpu_context::GpuContext;
+use crate::render::RenderPassBuilder;
+
+// The egui version the layer is built with, for building widgets
+pub use egui;
+
+// Debug and tuning ui drawn with egui over a finished frame. Window events go through
+// handle_window_event first, events it returns true for are egui's and shouldn't reach camera
+// controllers. Build the widgets once per frame with run, then draw them with record_overlay_pass.
+//
+//   layer.run(|ui_context| {
+//       egui::Window::new("settings").show(ui_context, |ui| ui.checkbox(&mut enabled, "enabled"));
+//   });
+//   layer.record_overlay_pass(context, &mut encoder, &frame_view);
+pub struct EguiLayer {
+    pub egui_context: egui::Context,
+    window: Arc<Window>,
+    state: egui_winit::State,
+    renderer: egui_wgpu::Renderer,
+    // from the last run, drawn by the next record_overlay_pass
+    paint_jobs: Vec<egui::ClippedPrimitive>,
+    textures_delta: egui::TexturesDelta,
+    pixels_per_point: f32,
+}
+
+impl EguiLayer {
+    // window is the window of the context's surface, the layer draws into views of its view format
+    pub fn new(context: &GpuContext, window: Arc<Window>) -> Self {
+        let egui_context = egui::Context::default();
+        let max_texture_side = context.device.limits().max_texture_dimension_2d as usize;
+        let state = egui_winit::State::new(
+            egui_context.clone(),
+            egui::ViewportId::ROOT,
+            &*window,
+            Some(window.scale_factor() as f32),
+            Some(max_texture_side),
+        );
+        let renderer = egui_wgpu::Renderer::new(&context.device, context.surface_view_format(), None, 1);
+
+        EguiLayer {
+            egui_context,
+            pixels_per_point: window.scale_factor() as f32,
+            window,
+            state,
+            renderer,
+            paint_jobs: vec![],
+            textures_delta: egui::TexturesDelta::default(),
+        }
+    }
+
+    // True when egui takes the event, the app should then leave it out of its own input
+    pub fn handle_window_event(&mut self, event: &WindowEvent) -> bool {
+        let _ = self.state.on_window_event(&self.window, event);
+        is_captured(event, self.wants_pointer_input(), self.wants_keyboard_input())
+    }
+
+    // The pointer is over an egui area or dragging one of its widgets
+    pub fn wants_pointer_input(&self) -> bool {
+        self.egui_context.wants_pointer_input()
+    }
+
+    // A text field has focus
+    pub fn wants_keyboard_input(&self) -> bool {
+        self.egui_context.wants_keyboard_input()
+    }
+
+    // Runs build_ui with the input gathered since the last run and tessellates the result
+    pub fn run(&mut self, build_ui: impl FnOnce(&egui::Context)) {
+        let raw_input = self.state.take_egui_input(&self.window);
+        let output = self.egui_context.run(raw_input, build_ui);
+        self.state.handle_platform_output(&self.window, output.platform_output);
+
+        self.paint_jobs = self.egui_context.tessellate(output.shapes, output.pixels_per_point);
+        self.textures_delta.append(output.textures_delta);
+        self.pixels_per_point = output.pixels_per_point;
+    }
+
+    // Draws the last run over target_view in its own pass, which loads the frame's contents.
+    // Record it after the scene and tonemap passes.
+    pub fn record_overlay_pass(&mut self, context: &GpuContext, encoder: &mut wgpu::CommandEncoder, target_view: &TextureView) {
+        let screen = egui_wgpu::ScreenDescriptor {
+            size_in_pixels: [context.config.width, context.config.height],
+            pixels_per_point: self.pixels_per_point,
+        };
+
+        let textures_delta = std::mem::take(&mut self.textures_delta);
+        for (id, image_delta) in &textures_delta.set {
+            self.renderer.update_texture(&context.device, &context.queue, *id, image_delta);
+        }
+
+        // only paint callbacks record their own command buffers, they go ahead of the frame's encoder
+        let callback_buffers = self
+            .renderer
+            .update_buffers(&context.device, &context.queue, encoder, &self.paint_jobs, &screen);
+        if !callback_buffers.is_empty() {
+            context.queue.submit(callback_buffers);
+        }
+
+        {
+            let mut pass = RenderPassBuilder::new()
+                .label("egui overlay")
+                .color(target_view, None)
+                .begin(encoder);
+            self.renderer.render(&mut pass, &self.paint_jobs, &screen);
+        }
+
+        for id in &textures_delta.free {
+            self.renderer.free_texture(id);
+        }
+    }
+}
+
+// Whether an event belongs to egui given what it wants. Releases always reach the app, so a drag
+// or key press that started outside the ui doesn't stick.
+pub fn is_captured(event: &WindowEvent, wants_pointer: bool, wants_keyboard: bool) -> bool {
+    match event {
+        WindowEvent::CursorMoved { .. } | WindowEvent::MouseWheel { .. } => wants_pointer,
+        WindowEvent::MouseInput { state, .. } => wants_pointer && *state == ElementState::Pressed,
+        WindowEvent::KeyboardInput { event, .. } => wants_keyboard && event.state == ElementState::Pressed,
+        WindowEvent::Ime(_) => wants_keyboard,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use winit::dpi::PhysicalPosition;
+    use winit::event::{DeviceId, ElementState, MouseButton, MouseScrollDelta, TouchPhase, WindowEvent};
+
+    use crate::ui::is_captured;
+
+    #[test]
+    fn test_captured_events() {
+        let device_id = unsafe { DeviceId::dummy() };
+        let press = WindowEvent::MouseInput {
+            device_id,
+            state: ElementState::Pressed,
+            button: MouseButton::Left,
+        };
+        let release = WindowEvent::MouseInput {
+            device_id,
+            state: ElementState::Released,
+            button: MouseButton::Left,
+        };
+        let wheel = WindowEvent::MouseWheel {
+            device_id,
+            delta: MouseScrollDelta::LineDelta(0.0, 1.0),
+            phase: TouchPhase::Moved,
+        };
+        let moved = WindowEvent::CursorMoved {
+            device_id,
+            position: PhysicalPosition::new(10.0, 20.0),
+        };
+
+        for event in [&press, &wheel, &moved] {
+            assert!(is_captured(event, true, false));
+            assert!(!is_captured(event, false, true));
+        }
+
+        // the camera controller sees the end of its drag
+        assert!(!is_captured(&release, true, false));
+
+        // window events are never egui's alone
+        assert!(!is_captured(&WindowEvent::Focused(false), true, true));
+        assert!(!is_captured(&WindowEvent::CloseRequested, true, true));
+    }
+}